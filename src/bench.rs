@@ -0,0 +1,149 @@
+//! Criterion benchmarks for the process-local hot paths: the baseline
+//! for lock and sharding work on `VTS_MANAGER`.
+//!
+//! Built only with the `bench` feature and ignored by default:
+//!
+//! ```sh
+//! cargo test --release --features bench bench:: -- --ignored --nocapture
+//! ```
+//!
+//! Criterion prints time per iteration and throughput for each case.
+//! The global manager is reset before every case so earlier cases do
+//! not grow the maps later ones walk.
+
+use std::hint::black_box;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::measurement::WallTime;
+use criterion::{BenchmarkGroup, Criterion, Throughput};
+
+use crate::prometheus::generate_vts_status_content;
+use crate::vts_node::VtsStatsManager;
+use crate::{
+    update_server_zone_stats, update_upstream_zone_stats, CACHE_MANAGER, GLOBAL_VTS_TEST_MUTEX,
+    VTS_MANAGER,
+};
+
+/// Zones the update benchmarks spread their writes over.
+const UPDATE_ZONES: usize = 64;
+
+/// Writer threads for the contended cases.
+const THREADS: u64 = 8;
+
+fn reset_state() {
+    *VTS_MANAGER.write().unwrap_or_else(crate::recover_poisoned) = VtsStatsManager::new();
+    CACHE_MANAGER.clear();
+}
+
+fn criterion() -> Criterion {
+    Criterion::default()
+        .sample_size(20)
+        .warm_up_time(Duration::from_millis(500))
+        .measurement_time(Duration::from_secs(3))
+}
+
+fn zone_names(count: usize, suffix: &str) -> Vec<String> {
+    (0..count).map(|i| format!("zone{i}.{suffix}")).collect()
+}
+
+/// Time `iters` calls of `update` spread over [`THREADS`] threads, each
+/// writing its own slice of `names`.
+fn contended<F>(iters: u64, names: &[String], update: F) -> Duration
+where
+    F: Fn(&str) + Sync,
+{
+    let per_thread = iters.div_ceil(THREADS);
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for t in 0..THREADS {
+            let update = &update;
+            scope.spawn(move || {
+                for i in 0..per_thread {
+                    let index = (t * per_thread + i) as usize % names.len();
+                    update(&names[index]);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn bench_updates(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    names: &[String],
+    update: impl Fn(&str) + Sync,
+) {
+    group.throughput(Throughput::Elements(1));
+    reset_state();
+    group.bench_function("1 thread", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            update(black_box(&names[i % names.len()]));
+            i += 1;
+        })
+    });
+    reset_state();
+    group.bench_function(format!("{THREADS} threads"), |b| {
+        b.iter_custom(|iters| contended(iters, names, &update))
+    });
+}
+
+#[test]
+#[ignore = "benchmark; run with --features bench -- --ignored"]
+fn bench_update_server_zone_stats() {
+    let _lock = GLOBAL_VTS_TEST_MUTEX
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let names = zone_names(UPDATE_ZONES, "server.bench");
+
+    let mut c = criterion();
+    let mut group = c.benchmark_group("update_server_zone_stats");
+    bench_updates(&mut group, &names, |zone| {
+        update_server_zone_stats(zone, 200, 512, 2048, 12)
+    });
+    group.finish();
+    reset_state();
+    c.final_summary();
+}
+
+#[test]
+#[ignore = "benchmark; run with --features bench -- --ignored"]
+fn bench_update_upstream_zone_stats() {
+    let _lock = GLOBAL_VTS_TEST_MUTEX
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let names = zone_names(UPDATE_ZONES, "upstream.bench");
+
+    let mut c = criterion();
+    let mut group = c.benchmark_group("update_upstream_zone_stats");
+    bench_updates(&mut group, &names, |upstream| {
+        update_upstream_zone_stats(upstream, "10.0.0.1:80", 12, 10, 512, 2048, 200)
+    });
+    group.finish();
+    reset_state();
+    c.final_summary();
+}
+
+#[test]
+#[ignore = "benchmark; run with --features bench -- --ignored"]
+fn bench_generate_status_content_1k_zones() {
+    let _lock = GLOBAL_VTS_TEST_MUTEX
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    reset_state();
+    for zone in zone_names(1000, "status.bench") {
+        update_server_zone_stats(&zone, 200, 512, 2048, 12);
+    }
+    let bytes = generate_vts_status_content().len() as u64;
+
+    let mut c = criterion();
+    let mut group = c.benchmark_group("generate_vts_status_content");
+    group.throughput(Throughput::Bytes(bytes));
+    group.bench_function("1k zones", |b| {
+        b.iter(|| black_box(generate_vts_status_content()))
+    });
+    group.finish();
+    reset_state();
+    c.final_summary();
+}
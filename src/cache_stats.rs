@@ -5,6 +5,7 @@
 //! and cache status information for both server zones and upstream servers.
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::RwLock;
 
 /// Cache status statistics
@@ -28,6 +29,16 @@ pub struct VtsCacheStats {
     pub hit: u64,
     /// Cache scarce count (cache storage low, content evicted)
     pub scarce: u64,
+    /// Response bytes sent for requests answered from cache (hit,
+    /// stale, updating, revalidated)
+    pub bytes_served: u64,
+    /// Requests that waited on `proxy_cache_lock` for another request
+    /// to populate the same key.  Not a cache status, so not part of
+    /// `total_requests` or the hit ratio
+    pub lock_waits: u64,
+    /// Lock waits that hit `proxy_cache_lock_timeout` and went to
+    /// upstream without caching (also counted in `lock_waits`)
+    pub lock_timeouts: u64,
 }
 
 /// Cache size statistics
@@ -79,16 +90,20 @@ impl VtsCacheStats {
         }
     }
 
-    /// Get total cache requests (all cache operations)
+    /// Get total cache requests (all cache operations), saturating at
+    /// `u64::MAX`
     pub fn total_requests(&self) -> u64 {
-        self.hit
-            + self.miss
-            + self.bypass
-            + self.expired
-            + self.stale
-            + self.updating
-            + self.revalidated
-            + self.scarce
+        [
+            self.miss,
+            self.bypass,
+            self.expired,
+            self.stale,
+            self.updating,
+            self.revalidated,
+            self.scarce,
+        ]
+        .into_iter()
+        .fold(self.hit, u64::saturating_add)
     }
 
     /// Get cache hit ratio as percentage
@@ -97,11 +112,32 @@ impl VtsCacheStats {
     ///
     /// Cache hit ratio as f64 (0.0 to 100.0), or 0.0 if no requests
     pub fn hit_ratio(&self) -> f64 {
-        let total = self.total_requests();
-        if total == 0 {
-            0.0
-        } else {
-            (self.hit as f64 / total as f64) * 100.0
+        self.hit_ratio_opt().unwrap_or(0.0)
+    }
+
+    /// Get cache hit ratio as percentage, telling "no requests yet"
+    /// apart from "no hits"
+    ///
+    /// # Returns
+    ///
+    /// Cache hit ratio (0.0 to 100.0), or `None` if no requests
+    pub fn hit_ratio_opt(&self) -> Option<f64> {
+        match self.total_requests() {
+            0 => None,
+            total => Some((self.hit as f64 / total as f64) * 100.0),
+        }
+    }
+
+    /// Get the fraction of cache-served responses (`HIT` + `STALE`)
+    /// that were stale
+    ///
+    /// # Returns
+    ///
+    /// `stale / (hit + stale)` (0.0 to 1.0), or `None` if neither
+    pub fn stale_ratio_opt(&self) -> Option<f64> {
+        match self.hit.saturating_add(self.stale) {
+            0 => None,
+            served => Some(self.stale as f64 / served as f64),
         }
     }
 }
@@ -135,10 +171,19 @@ impl VtsCacheSizeStats {
     ///
     /// Cache utilization as f64 (0.0 to 100.0), or 0.0 if max_size is 0
     pub fn utilization_percentage(&self) -> f64 {
-        if self.max_size == 0 {
-            0.0
-        } else {
-            (self.used_size as f64 / self.max_size as f64) * 100.0
+        self.utilization_percentage_opt().unwrap_or(0.0)
+    }
+
+    /// Get cache utilization percentage, telling "size unknown" apart
+    /// from "empty"
+    ///
+    /// # Returns
+    ///
+    /// Cache utilization (0.0 to 100.0), or `None` if max_size is 0
+    pub fn utilization_percentage_opt(&self) -> Option<f64> {
+        match self.max_size {
+            0 => None,
+            max_size => Some((self.used_size as f64 / max_size as f64) * 100.0),
         }
     }
 }
@@ -176,24 +221,172 @@ impl CacheZoneStats {
         self.size.max_size = max_size;
         self.size.used_size = used_size;
     }
+
+    /// Add response bytes served from this cache
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - Bytes sent for one request answered from cache
+    pub fn add_bytes_served(&mut self, bytes: u64) {
+        self.cache.bytes_served += bytes;
+    }
+
+    /// Record one request that waited on the cache lock
+    ///
+    /// # Arguments
+    ///
+    /// * `timed_out` - Whether the wait ended at `proxy_cache_lock_timeout`
+    pub fn record_lock_wait(&mut self, timed_out: bool) {
+        self.cache.lock_waits += 1;
+        if timed_out {
+            self.cache.lock_timeouts += 1;
+        }
+    }
+}
+
+/// Counters behind one zone of [`CacheStatsManager`], updated with
+/// relaxed atomic adds so requests hitting an existing zone only need
+/// the map's read lock.  Readers get a plain [`CacheZoneStats`] copy.
+#[derive(Debug, Default)]
+struct AtomicCacheZone {
+    miss: AtomicU64,
+    bypass: AtomicU64,
+    expired: AtomicU64,
+    stale: AtomicU64,
+    updating: AtomicU64,
+    revalidated: AtomicU64,
+    hit: AtomicU64,
+    scarce: AtomicU64,
+    bytes_served: AtomicU64,
+    lock_waits: AtomicU64,
+    lock_timeouts: AtomicU64,
+    max_size: AtomicU64,
+    used_size: AtomicU64,
+}
+
+impl AtomicCacheZone {
+    /// Same status names as [`VtsCacheStats::update_cache_status`].
+    fn update_cache_status(&self, cache_status: &str) {
+        let counter = match cache_status.to_uppercase().as_str() {
+            "HIT" => &self.hit,
+            "MISS" => &self.miss,
+            "BYPASS" => &self.bypass,
+            "EXPIRED" => &self.expired,
+            "STALE" => &self.stale,
+            "UPDATING" => &self.updating,
+            "REVALIDATED" => &self.revalidated,
+            "SCARCE" => &self.scarce,
+            _ => return, // Unknown cache status, ignore
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self, name: &str) -> CacheZoneStats {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        CacheZoneStats {
+            name: name.to_string(),
+            cache: VtsCacheStats {
+                miss: get(&self.miss),
+                bypass: get(&self.bypass),
+                expired: get(&self.expired),
+                stale: get(&self.stale),
+                updating: get(&self.updating),
+                revalidated: get(&self.revalidated),
+                hit: get(&self.hit),
+                scarce: get(&self.scarce),
+                bytes_served: get(&self.bytes_served),
+                lock_waits: get(&self.lock_waits),
+                lock_timeouts: get(&self.lock_timeouts),
+            },
+            size: VtsCacheSizeStats::new(get(&self.max_size), get(&self.used_size)),
+        }
+    }
+
+    /// Zero the request counters, keeping the size gauges.
+    fn reset_counters(&self) {
+        for counter in [
+            &self.miss,
+            &self.bypass,
+            &self.expired,
+            &self.stale,
+            &self.updating,
+            &self.revalidated,
+            &self.hit,
+            &self.scarce,
+            &self.bytes_served,
+            &self.lock_waits,
+            &self.lock_timeouts,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn store(&self, stats: &CacheZoneStats) {
+        let set = |counter: &AtomicU64, value| counter.store(value, Ordering::Relaxed);
+        set(&self.miss, stats.cache.miss);
+        set(&self.bypass, stats.cache.bypass);
+        set(&self.expired, stats.cache.expired);
+        set(&self.stale, stats.cache.stale);
+        set(&self.updating, stats.cache.updating);
+        set(&self.revalidated, stats.cache.revalidated);
+        set(&self.hit, stats.cache.hit);
+        set(&self.scarce, stats.cache.scarce);
+        set(&self.bytes_served, stats.cache.bytes_served);
+        set(&self.lock_waits, stats.cache.lock_waits);
+        set(&self.lock_timeouts, stats.cache.lock_timeouts);
+        set(&self.max_size, stats.size.max_size);
+        set(&self.used_size, stats.size.used_size);
+    }
 }
 
 /// Cache statistics manager
 ///
-/// Manages cache statistics for multiple cache zones
+/// Manages cache statistics for multiple cache zones.  Updates to a
+/// zone that already exists take the map's read lock and bump atomic
+/// counters, so concurrent requests do not serialise on it; only the
+/// first request for a new zone takes the write lock.
 pub struct CacheStatsManager {
     /// Map of cache zone name to its statistics
-    cache_zones: RwLock<HashMap<String, CacheZoneStats>>,
+    cache_zones: RwLock<HashMap<String, AtomicCacheZone>>,
+    /// Status counters by cache zone and upstream, for requests whose
+    /// upstream is known.  Only the status counters are used.
+    cache_upstreams: RwLock<HashMap<(String, String), AtomicCacheZone>>,
 }
 
 impl CacheStatsManager {
+    /// Whether a panic mid-update has poisoned the zone table.
+    pub fn is_poisoned(&self) -> bool {
+        self.cache_zones.is_poisoned()
+    }
+
     /// Create new cache statistics manager
     pub fn new() -> Self {
         Self {
             cache_zones: RwLock::new(HashMap::new()),
+            cache_upstreams: RwLock::new(HashMap::new()),
         }
     }
 
+    /// Apply `f` to the counters of `zone_name`, under the read lock if
+    /// the zone exists, otherwise inserting it under the write lock.
+    fn with_zone(&self, zone_name: &str, f: impl FnOnce(&AtomicCacheZone)) {
+        {
+            let zones = self
+                .cache_zones
+                .read()
+                .unwrap_or_else(crate::recover_poisoned);
+            if let Some(zone) = zones.get(zone_name) {
+                f(zone);
+                return;
+            }
+        }
+        let mut zones = self
+            .cache_zones
+            .write()
+            .unwrap_or_else(crate::recover_poisoned);
+        f(zones.entry(zone_name.to_string()).or_default());
+    }
+
     /// Update cache statistics for a specific zone
     ///
     /// # Arguments
@@ -201,14 +394,60 @@ impl CacheStatsManager {
     /// * `zone_name` - Cache zone name
     /// * `cache_status` - Cache status string (e.g., "HIT", "MISS", "BYPASS")
     pub fn update_cache_stats(&self, zone_name: &str, cache_status: &str) {
+        self.with_zone(zone_name, |zone| zone.update_cache_status(cache_status));
+    }
+
+    /// Count one cache status for the responses of `upstream` in
+    /// `zone_name`, on top of [`Self::update_cache_stats`] for the zone.
+    pub fn update_cache_upstream_stats(&self, zone_name: &str, upstream: &str, cache_status: &str) {
+        let key = (zone_name.to_string(), upstream.to_string());
+        {
+            let upstreams = self
+                .cache_upstreams
+                .read()
+                .unwrap_or_else(crate::recover_poisoned);
+            if let Some(counters) = upstreams.get(&key) {
+                counters.update_cache_status(cache_status);
+                return;
+            }
+        }
+        let mut upstreams = self
+            .cache_upstreams
+            .write()
+            .unwrap_or_else(crate::recover_poisoned);
+        upstreams
+            .entry(key)
+            .or_default()
+            .update_cache_status(cache_status);
+    }
+
+    /// Status counters by cache zone, then upstream.
+    pub fn get_all_cache_upstreams(&self) -> HashMap<String, HashMap<String, VtsCacheStats>> {
+        let upstreams = self
+            .cache_upstreams
+            .read()
+            .unwrap_or_else(crate::recover_poisoned);
+        let mut out: HashMap<String, HashMap<String, VtsCacheStats>> = HashMap::new();
+        for ((zone, upstream), counters) in upstreams.iter() {
+            out.entry(zone.clone())
+                .or_default()
+                .insert(upstream.clone(), counters.load(zone).cache);
+        }
+        out
+    }
+
+    /// Apply `f` to the statistics of `zone_name`, creating the zone
+    /// first if needed.  Holds the write lock throughout, so no atomic
+    /// update lands between reading and storing the zone.
+    pub fn update_zone(&self, zone_name: &str, f: impl FnOnce(&mut CacheZoneStats)) {
         let mut zones = self
             .cache_zones
             .write()
-            .unwrap_or_else(|poisoned| poisoned.into_inner());
-        let zone_stats = zones
-            .entry(zone_name.to_string())
-            .or_insert_with(|| CacheZoneStats::new(zone_name));
-        zone_stats.update_cache_status(cache_status);
+            .unwrap_or_else(crate::recover_poisoned);
+        let zone = zones.entry(zone_name.to_string()).or_default();
+        let mut stats = zone.load(zone_name);
+        f(&mut stats);
+        zone.store(&stats);
     }
 
     /// Update cache size information for a specific zone
@@ -219,14 +458,37 @@ impl CacheStatsManager {
     /// * `max_size` - Maximum cache size in bytes
     /// * `used_size` - Currently used cache size in bytes
     pub fn update_cache_size(&self, zone_name: &str, max_size: u64, used_size: u64) {
-        let mut zones = self
-            .cache_zones
-            .write()
-            .unwrap_or_else(|poisoned| poisoned.into_inner());
-        let zone_stats = zones
-            .entry(zone_name.to_string())
-            .or_insert_with(|| CacheZoneStats::new(zone_name));
-        zone_stats.update_cache_size(max_size, used_size);
+        self.with_zone(zone_name, |zone| {
+            zone.max_size.store(max_size, Ordering::Relaxed);
+            zone.used_size.store(used_size, Ordering::Relaxed);
+        });
+    }
+
+    /// Add response bytes served from cache for a specific zone
+    ///
+    /// # Arguments
+    ///
+    /// * `zone_name` - Cache zone name
+    /// * `bytes` - Bytes sent for one request answered from cache
+    pub fn record_cache_hit_bytes(&self, zone_name: &str, bytes: u64) {
+        self.with_zone(zone_name, |zone| {
+            zone.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+        });
+    }
+
+    /// Record one cache lock wait for a specific zone
+    ///
+    /// # Arguments
+    ///
+    /// * `zone_name` - Cache zone name
+    /// * `timed_out` - Whether the wait ended at `proxy_cache_lock_timeout`
+    pub fn record_cache_lock_wait(&self, zone_name: &str, timed_out: bool) {
+        self.with_zone(zone_name, |zone| {
+            zone.lock_waits.fetch_add(1, Ordering::Relaxed);
+            if timed_out {
+                zone.lock_timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+        });
     }
 
     /// Get cache statistics for a specific zone
@@ -243,8 +505,8 @@ impl CacheStatsManager {
         let zones = self
             .cache_zones
             .read()
-            .unwrap_or_else(|poisoned| poisoned.into_inner());
-        zones.get(zone_name).cloned()
+            .unwrap_or_else(crate::recover_poisoned);
+        zones.get(zone_name).map(|zone| zone.load(zone_name))
     }
 
     /// Get all cache zone statistics
@@ -256,8 +518,58 @@ impl CacheStatsManager {
         let zones = self
             .cache_zones
             .read()
-            .unwrap_or_else(|poisoned| poisoned.into_inner());
-        zones.clone()
+            .unwrap_or_else(crate::recover_poisoned);
+        zones
+            .iter()
+            .map(|(name, zone)| (name.clone(), zone.load(name)))
+            .collect()
+    }
+
+    /// Number of cache zones recorded so far.
+    pub fn zone_count(&self) -> usize {
+        self.cache_zones
+            .read()
+            .unwrap_or_else(crate::recover_poisoned)
+            .len()
+    }
+
+    /// Visit every cache zone, in name order, under a single read lock.
+    /// Each zone's counters are copied out one at a time rather than
+    /// cloning the whole table.  Used on the scrape path; `f` must not
+    /// call back into this manager.
+    pub fn for_each_cache_zone(&self, mut f: impl FnMut(&str, &CacheZoneStats)) {
+        let zones = self
+            .cache_zones
+            .read()
+            .unwrap_or_else(crate::recover_poisoned);
+        let mut sorted: Vec<_> = zones.iter().collect();
+        sorted.sort_unstable_by_key(|&(name, _)| name);
+        for (name, zone) in sorted {
+            f(name, &zone.load(name));
+        }
+    }
+
+    /// Zero every zone's request counters, and their per-upstream
+    /// breakdowns, in place.  The zones and their size gauges stay, as
+    /// the sizes describe the cache on disk rather than past requests.
+    /// Returns the number of zones reset.
+    pub fn reset_counters(&self) -> usize {
+        let zones = self
+            .cache_zones
+            .read()
+            .unwrap_or_else(crate::recover_poisoned);
+        for zone in zones.values() {
+            zone.reset_counters();
+        }
+        for counters in self
+            .cache_upstreams
+            .read()
+            .unwrap_or_else(crate::recover_poisoned)
+            .values()
+        {
+            counters.reset_counters();
+        }
+        zones.len()
     }
 
     /// Clear all cache statistics
@@ -266,8 +578,12 @@ impl CacheStatsManager {
         let mut zones = self
             .cache_zones
             .write()
-            .unwrap_or_else(|poisoned| poisoned.into_inner());
+            .unwrap_or_else(crate::recover_poisoned);
         zones.clear();
+        self.cache_upstreams
+            .write()
+            .unwrap_or_else(crate::recover_poisoned)
+            .clear();
     }
 }
 
@@ -306,6 +622,45 @@ mod tests {
         assert_eq!(stats.hit_ratio(), 50.0);
     }
 
+    #[test]
+    fn test_stale_ratio_opt_counts_stale_against_hits() {
+        let mut stats = VtsCacheStats::new();
+        stats.update_cache_status("MISS");
+        assert_eq!(stats.stale_ratio_opt(), None);
+
+        for status in ["HIT", "HIT", "HIT", "STALE", "EXPIRED"] {
+            stats.update_cache_status(status);
+        }
+        assert_eq!(stats.stale_ratio_opt(), Some(0.25));
+    }
+
+    #[test]
+    fn test_hit_ratio_opt_tells_no_requests_from_no_hits() {
+        let mut stats = VtsCacheStats::new();
+        assert_eq!(stats.hit_ratio_opt(), None);
+        assert_eq!(stats.hit_ratio(), 0.0);
+
+        stats.update_cache_status("MISS");
+        assert_eq!(stats.hit_ratio_opt(), Some(0.0));
+
+        stats.hit = u64::MAX;
+        assert_eq!(stats.total_requests(), u64::MAX);
+        assert_eq!(stats.hit_ratio_opt(), Some(100.0));
+    }
+
+    #[test]
+    fn test_utilization_opt_tells_unknown_size_from_empty() {
+        assert_eq!(
+            VtsCacheSizeStats::new(0, 0).utilization_percentage_opt(),
+            None
+        );
+        assert_eq!(VtsCacheSizeStats::new(0, 0).utilization_percentage(), 0.0);
+        assert_eq!(
+            VtsCacheSizeStats::new(1000, 0).utilization_percentage_opt(),
+            Some(0.0)
+        );
+    }
+
     #[test]
     fn test_cache_stats_unknown_status() {
         let mut stats = VtsCacheStats::new();
@@ -339,6 +694,21 @@ mod tests {
         assert_eq!(zone.size.utilization_percentage(), 50.0);
     }
 
+    #[test]
+    fn test_cache_lock_waits_are_not_requests() {
+        let manager = CacheStatsManager::new();
+
+        manager.update_cache_stats("zone1", "HIT");
+        manager.record_cache_lock_wait("zone1", false);
+        manager.record_cache_lock_wait("zone1", true);
+
+        let zone_stats = manager.get_cache_zone("zone1").unwrap();
+        assert_eq!(zone_stats.cache.lock_waits, 2);
+        assert_eq!(zone_stats.cache.lock_timeouts, 1);
+        assert_eq!(zone_stats.cache.total_requests(), 1);
+        assert_eq!(zone_stats.cache.hit_ratio(), 100.0);
+    }
+
     #[test]
     fn test_cache_stats_manager() {
         let manager = CacheStatsManager::new();
@@ -376,6 +746,29 @@ mod tests {
         assert_eq!(zone2.cache.miss, 1);
     }
 
+    #[test]
+    fn test_for_each_cache_zone_visits_each_zone_once() {
+        let manager = CacheStatsManager::new();
+        for zone in ["zone1", "zone2", "zone3"] {
+            manager.update_cache_stats(zone, "HIT");
+        }
+        manager.update_cache_stats("zone2", "MISS");
+
+        let mut seen = Vec::new();
+        manager.for_each_cache_zone(|name, stats| {
+            seen.push((name.to_string(), stats.cache.total_requests()));
+        });
+        seen.sort();
+        assert_eq!(
+            seen,
+            vec![
+                ("zone1".to_string(), 1),
+                ("zone2".to_string(), 2),
+                ("zone3".to_string(), 1),
+            ]
+        );
+    }
+
     #[test]
     fn test_cache_stats_clear() {
         let manager = CacheStatsManager::new();
@@ -386,4 +779,61 @@ mod tests {
         let all_zones = manager.get_all_cache_zones();
         assert_eq!(all_zones.len(), 0);
     }
+
+    #[test]
+    fn test_reset_counters_keeps_zones_and_sizes() {
+        let manager = CacheStatsManager::new();
+
+        manager.update_cache_stats("zone1", "HIT");
+        manager.update_cache_upstream_stats("zone1", "backend", "MISS");
+        manager.record_cache_hit_bytes("zone1", 4096);
+        manager.record_cache_lock_wait("zone1", true);
+        manager.update_cache_size("zone1", 1024 * 1024, 512 * 1024);
+
+        assert_eq!(manager.reset_counters(), 1);
+
+        let zone = manager.get_cache_zone("zone1").unwrap();
+        assert_eq!(zone.cache.total_requests(), 0);
+        assert_eq!(zone.cache.bytes_served, 0);
+        assert_eq!(zone.cache.lock_waits, 0);
+        assert_eq!(zone.cache.lock_timeouts, 0);
+        assert_eq!(zone.size.max_size, 1024 * 1024);
+        assert_eq!(zone.size.used_size, 512 * 1024);
+        assert_eq!(
+            manager.get_all_cache_upstreams()["zone1"]["backend"].total_requests(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_concurrent_updates_to_one_zone_are_not_lost() {
+        use std::sync::Arc;
+
+        const THREADS: u64 = 8;
+        const UPDATES: u64 = 10_000;
+
+        let manager = Arc::new(CacheStatsManager::new());
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let manager = Arc::clone(&manager);
+                std::thread::spawn(move || {
+                    for i in 0..UPDATES {
+                        manager.update_cache_stats("hot", if i % 2 == 0 { "HIT" } else { "MISS" });
+                        manager.record_cache_hit_bytes("hot", 1);
+                    }
+                    // Zones first seen mid-run are inserted, not lost.
+                    manager.update_cache_stats(&format!("zone{t}"), "HIT");
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let hot = manager.get_cache_zone("hot").unwrap();
+        assert_eq!(hot.cache.total_requests(), THREADS * UPDATES);
+        assert_eq!(hot.cache.hit, THREADS * UPDATES / 2);
+        assert_eq!(hot.cache.bytes_served, THREADS * UPDATES);
+        assert_eq!(manager.get_all_cache_zones().len(), 1 + THREADS as usize);
+    }
 }
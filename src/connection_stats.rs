@@ -17,6 +17,7 @@
 //! The lookup is cached in a `OnceLock` so the cost is paid exactly
 //! once per worker.
 
+use crate::stats::VtsConnectionStats;
 #[cfg(not(test))]
 use ngx::ffi::ngx_atomic_t;
 #[cfg(not(test))]
@@ -24,26 +25,9 @@ use std::ffi::CStr;
 #[cfg(not(test))]
 use std::sync::OnceLock;
 
-/// One snapshot of nginx's connection-state counters.  All values are
-/// taken from the same source as `stub_status`, but because each
-/// atomic is read independently the snapshot is not consistent across
-/// counters.  That's fine for monitoring — the drift between reads is
-/// sub-microsecond.
-#[derive(Clone, Copy, Debug, Default)]
-#[allow(dead_code)] // `requests` is exposed for symmetry with stub_status; not yet plumbed into a metric.
-pub struct ConnectionStats {
-    pub active: u64,
-    pub reading: u64,
-    pub writing: u64,
-    pub waiting: u64,
-    pub accepted: u64,
-    pub handled: u64,
-    pub requests: u64,
-}
-
-/// Resolved pointers to the seven `ngx_stat_*` atomics.  Populated
-/// once via `dlsym`; remains `None` on nginx builds without
-/// `stub_status`.
+/// Resolved pointers to the six `ngx_stat_*` atomics behind
+/// [`VtsConnectionStats`].  Populated once via `dlsym`; remains `None`
+/// on nginx builds without `stub_status`.
 #[cfg(not(test))]
 struct StatPointers {
     active: *const ngx_atomic_t,
@@ -52,7 +36,6 @@ struct StatPointers {
     waiting: *const ngx_atomic_t,
     accepted: *const ngx_atomic_t,
     handled: *const ngx_atomic_t,
-    requests: *const ngx_atomic_t,
 }
 
 // SAFETY: the pointers are read-only and stable for the lifetime of
@@ -90,7 +73,7 @@ fn lookup_symbol(name: &CStr) -> Option<*const ngx_atomic_t> {
 
 #[cfg(not(test))]
 fn resolve_pointers() -> Option<StatPointers> {
-    // All seven must resolve; if any is missing we treat the build as
+    // All six must resolve; if any is missing we treat the build as
     // not having stub_status compiled in (rather than silently zeroing
     // out a subset of the counters).
     Some(StatPointers {
@@ -100,15 +83,24 @@ fn resolve_pointers() -> Option<StatPointers> {
         waiting: lookup_symbol(c"ngx_stat_waiting")?,
         accepted: lookup_symbol(c"ngx_stat_accepted")?,
         handled: lookup_symbol(c"ngx_stat_handled")?,
-        requests: lookup_symbol(c"ngx_stat_requests")?,
     })
 }
 
-/// Sample all seven counters in one call.  Returns `None` when nginx
+/// Whether nginx exports the `stub_status` atomics, so the accepted
+/// and handled totals are real rather than the fallback's stand-ins.
+#[cfg(not(test))]
+pub fn available() -> bool {
+    STAT_POINTERS.get_or_init(resolve_pointers).is_some()
+}
+
+/// Sample the connection counters in one call.  Each atomic is read
+/// independently, so the snapshot is not consistent across counters;
+/// the drift between reads is sub-microsecond, which is fine for
+/// monitoring.  Returns `None` when nginx
 /// was built without `stub_status` and the symbols cannot be found.
 #[cfg(not(test))]
 #[allow(clippy::unnecessary_cast)] // `ngx_atomic_t` is `c_ulong`, which is 32-bit on some targets.
-pub fn read() -> Option<ConnectionStats> {
+pub fn read() -> Option<VtsConnectionStats> {
     let ptrs = STAT_POINTERS.get_or_init(resolve_pointers).as_ref()?;
     // SAFETY: `ptrs` are non-null pointers into nginx's global state,
     // populated once before workers fork and never freed.  The atomics
@@ -116,14 +108,13 @@ pub fn read() -> Option<ConnectionStats> {
     // plain read here observes the latest committed value (this is the
     // same access pattern `stub_status` itself uses).
     unsafe {
-        Some(ConnectionStats {
+        Some(VtsConnectionStats {
             active: *ptrs.active as u64,
             reading: *ptrs.reading as u64,
             writing: *ptrs.writing as u64,
             waiting: *ptrs.waiting as u64,
             accepted: *ptrs.accepted as u64,
             handled: *ptrs.handled as u64,
-            requests: *ptrs.requests as u64,
         })
     }
 }
@@ -132,10 +123,30 @@ pub fn read() -> Option<ConnectionStats> {
 /// that exports the atomics, so we pretend the build doesn't have
 /// `stub_status` and let callers exercise their fallback path.
 #[cfg(test)]
-pub fn read() -> Option<ConnectionStats> {
+pub fn read() -> Option<VtsConnectionStats> {
     None
 }
 
+#[cfg(test)]
+thread_local! {
+    /// Whether [`available`] pretends the atomics are exported, set by
+    /// [`set_test_available`].
+    static TEST_AVAILABLE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Test-only: make [`available`] on this thread report `available`.
+#[cfg(test)]
+pub fn set_test_available(available: bool) {
+    TEST_AVAILABLE.with(|t| t.set(available));
+}
+
+/// Test-only stub: `false`, like [`read`], unless a test said otherwise
+/// with [`set_test_available`].
+#[cfg(test)]
+pub fn available() -> bool {
+    TEST_AVAILABLE.with(|t| t.get())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,354 @@
+//! Control commands accepted in the query string of a `vts_status`
+//! location.
+//!
+//! `?control=reset&group=server|upstream|cache|connections` zeroes one
+//! metric group and leaves the others alone, e.g. to clear cache
+//! counters after fixing a cache configuration without losing the
+//! upstream history.  `?meta=1` returns the metric catalog (see
+//! [`crate::prometheus::metric_catalog`]) and `?format=influx` the
+//! counters in InfluxDB line protocol (see [`crate::influx`]).
+//! `?mode=delta` returns the increase of each counter since the previous
+//! delta scrape (see [`crate::delta`]), as exposition-format sample
+//! lines or, with `format=influx`, line protocol.  `?top=N&by=…` lists
+//! the busiest server zones or, with `group=upstream`, upstream servers
+//! (see [`crate::top`]).  `?control=selftest` checks the counters for
+//! broken invariants (see [`crate::selftest`]).  Requests with none of
+//! these arguments render the normal page.
+
+use crate::top::SortKey;
+
+/// Metric groups that can be reset on their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetGroup {
+    /// `nginx_vts_server_*`, `nginx_vts_location_*` and `nginx_vts_filter_*`
+    Server,
+    /// `nginx_vts_upstream_*`
+    Upstream,
+    /// `nginx_vts_cache_*`
+    Cache,
+    /// `nginx_vts_connections*`
+    Connections,
+}
+
+impl ResetGroup {
+    /// Parse the `group` argument.
+    pub fn parse(group: &str) -> Option<Self> {
+        match group {
+            "server" => Some(Self::Server),
+            "upstream" => Some(Self::Upstream),
+            "cache" => Some(Self::Cache),
+            "connections" => Some(Self::Connections),
+            _ => None,
+        }
+    }
+
+    /// The `group` argument naming this group.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Server => "server",
+            Self::Upstream => "upstream",
+            Self::Cache => "cache",
+            Self::Connections => "connections",
+        }
+    }
+}
+
+/// Plain-text reply to a control command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlResponse {
+    /// HTTP status: 200 on success, 400 for a malformed command, 500
+    /// for a failed self-test.
+    pub status: u16,
+    /// Response body, newline-terminated.
+    pub body: String,
+}
+
+impl ControlResponse {
+    fn ok(body: String) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn bad_request(body: String) -> Self {
+        Self { status: 400, body }
+    }
+}
+
+const GROUPS_HINT: &str = "expected one of server, upstream, cache, connections";
+
+/// The query arguments of a `vts_status` request that
+/// [`handle_query`] acts on.
+#[derive(Debug, Default)]
+struct Query<'a> {
+    control: Option<&'a str>,
+    group: Option<&'a str>,
+    top: Option<&'a str>,
+    by: Option<&'a str>,
+    meta: bool,
+    influx: bool,
+    delta: bool,
+}
+
+impl<'a> Query<'a> {
+    fn parse(args: &'a str) -> Self {
+        let mut query = Query::default();
+        for pair in args.split('&') {
+            match pair.split_once('=') {
+                Some(("control", value)) => query.control = Some(value),
+                Some(("group", value)) => query.group = Some(value),
+                Some(("top", value)) => query.top = Some(value),
+                Some(("by", value)) => query.by = Some(value),
+                Some(("meta", value)) => query.meta = value == "1",
+                Some(("format", value)) => query.influx = value == "influx",
+                Some(("mode", value)) => query.delta = value == "delta",
+                _ => {}
+            }
+        }
+        query
+    }
+
+    /// Whether the reply walks the counters: the page and every other
+    /// rendering.  Only `control=reset` (and malformed commands) and
+    /// `meta=1` don't.
+    fn renders(&self) -> bool {
+        match self.control {
+            Some(control) => control == "selftest",
+            None => self.top.is_some() || self.delta || self.influx || !self.meta,
+        }
+    }
+}
+
+/// Whether the query string of a `vts_status` request is answered by
+/// rendering counters, and so takes a `vts_status_rate` token.
+pub fn is_rate_limited(args: &str) -> bool {
+    Query::parse(args).renders()
+}
+
+/// Handle the query string of a `vts_status` request.  Returns `None`
+/// when it carries no `control`, `top`, `meta=1`, `format=influx` or
+/// `mode=delta` argument.
+pub fn handle_query(args: &str) -> Option<ControlResponse> {
+    let Query {
+        control,
+        group,
+        top,
+        by,
+        meta,
+        influx,
+        delta,
+    } = Query::parse(args);
+
+    let Some(control) = control else {
+        if let Some(top) = top {
+            return Some(handle_top(top, by, group));
+        }
+        if delta {
+            return Some(ControlResponse::ok(
+                crate::delta::generate_vts_status_delta(influx),
+            ));
+        }
+        if influx {
+            return Some(ControlResponse::ok(
+                crate::influx::generate_vts_status_influx(),
+            ));
+        }
+        return meta.then(|| ControlResponse::ok(crate::prometheus::metric_catalog()));
+    };
+    if control == "selftest" {
+        let report = crate::selftest::run_selftest();
+        let status = if report.passed() { 200 } else { 500 };
+        return Some(ControlResponse {
+            status,
+            body: report.render(),
+        });
+    }
+    if control != "reset" {
+        return Some(ControlResponse::bad_request(format!(
+            "unknown control command \"{control}\"\n"
+        )));
+    }
+    let Some(group) = group else {
+        return Some(ControlResponse::bad_request(format!(
+            "missing group; {GROUPS_HINT}\n"
+        )));
+    };
+    let Some(group) = ResetGroup::parse(group) else {
+        return Some(ControlResponse::bad_request(format!(
+            "invalid group \"{group}\"; {GROUPS_HINT}\n"
+        )));
+    };
+
+    let body = match reset_group(group) {
+        Some(count) => format!("reset: {} ({count} {})\n", group.name(), unit(group, count)),
+        None => format!("reset: {}\n", group.name()),
+    };
+    Some(ControlResponse::ok(body))
+}
+
+/// Handle `?top=N&by=…&group=server|upstream`.  `by` defaults to
+/// `requests` and `group` to `server`; `N` above [`MAX_TOP`] is clamped.
+///
+/// [`MAX_TOP`]: crate::top::MAX_TOP
+fn handle_top(top: &str, by: Option<&str>, group: Option<&str>) -> ControlResponse {
+    let Some(n) = top.parse::<usize>().ok().filter(|&n| n > 0) else {
+        return ControlResponse::bad_request(format!(
+            "invalid top \"{top}\"; expected a positive number\n"
+        ));
+    };
+    let by = by.unwrap_or("requests");
+    let Some(key) = SortKey::parse(by) else {
+        return ControlResponse::bad_request(format!(
+            "invalid by \"{by}\"; expected one of requests, bytes, errors, avg_time\n"
+        ));
+    };
+    let upstream = match group.unwrap_or("server") {
+        "server" => false,
+        "upstream" => true,
+        other => {
+            return ControlResponse::bad_request(format!(
+                "invalid group \"{other}\"; expected server or upstream\n"
+            ))
+        }
+    };
+    ControlResponse::ok(crate::top::generate_top(n, key, upstream))
+}
+
+/// What the count returned by [`reset_group`] is counting.
+fn unit(group: ResetGroup, count: usize) -> &'static str {
+    match (group, count) {
+        (ResetGroup::Upstream, 1) => "server",
+        (ResetGroup::Upstream, _) => "servers",
+        (_, 1) => "zone",
+        _ => "zones",
+    }
+}
+
+/// Zero one metric group, in the shared zone when configured and in the
+/// process-local managers either way.  Returns how many zones (upstream
+/// servers for [`ResetGroup::Upstream`]) were reset, or `None` for
+/// [`ResetGroup::Connections`], which has no zones.
+///
+/// Live gauges describing the present — in-flight requests, upstream
+/// queue lengths, cache sizes on disk — are kept.  Resetting
+/// connections only drops the cached snapshot: the accepted/handled
+/// totals come from nginx itself and reappear on the next scrape.
+pub fn reset_group(group: ResetGroup) -> Option<usize> {
+    let mut manager = crate::VTS_MANAGER
+        .write()
+        .unwrap_or_else(crate::recover_poisoned);
+    match group {
+        ResetGroup::Server => {
+            let local = manager.reset_server_zones();
+            Some(crate::shm::reset_servers().unwrap_or(local))
+        }
+        ResetGroup::Upstream => {
+            let local = manager.reset_upstream_zones();
+            Some(crate::shm::reset_upstreams().unwrap_or(local))
+        }
+        ResetGroup::Cache => {
+            let local = crate::CACHE_MANAGER.reset_counters();
+            Some(crate::shm::reset_caches().unwrap_or(local))
+        }
+        ResetGroup::Connections => {
+            manager.connections = Default::default();
+            *crate::LAST_CONNECTION_REFRESH
+                .lock()
+                .unwrap_or_else(crate::recover_poisoned) = None;
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_accepts_each_group_name() {
+        for group in [
+            ResetGroup::Server,
+            ResetGroup::Upstream,
+            ResetGroup::Cache,
+            ResetGroup::Connections,
+        ] {
+            assert_eq!(ResetGroup::parse(group.name()), Some(group));
+        }
+        assert_eq!(ResetGroup::parse("Cache"), None);
+        assert_eq!(ResetGroup::parse(""), None);
+    }
+
+    #[test]
+    fn queries_without_control_are_not_commands() {
+        assert_eq!(handle_query(""), None);
+        assert_eq!(handle_query("group=cache"), None);
+        assert_eq!(handle_query("format=prometheus&x"), None);
+        assert_eq!(handle_query("meta=0"), None);
+        assert_eq!(handle_query("mode=cumulative"), None);
+    }
+
+    #[test]
+    fn every_rendering_is_rate_limited_but_reset_and_meta() {
+        for args in [
+            "",
+            "x=1",
+            "format=influx",
+            "mode=delta",
+            "top=5",
+            "control=selftest",
+            "meta=1&format=influx",
+        ] {
+            assert!(is_rate_limited(args), "{args}");
+        }
+        for args in ["control=reset&group=cache", "control=delete", "meta=1"] {
+            assert!(!is_rate_limited(args), "{args}");
+        }
+    }
+
+    #[test]
+    fn meta_returns_the_catalog_without_samples() {
+        let response = handle_query("meta=1").unwrap();
+        assert_eq!(response.status, 200);
+        assert!(response.body.starts_with("# HELP nginx_vts_module_up "));
+        assert!(response.body.lines().all(|line| line.starts_with("# ")));
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected_without_resetting() {
+        let unknown = handle_query("control=delete&group=cache").unwrap();
+        assert_eq!(unknown.status, 400);
+        assert_eq!(unknown.body, "unknown control command \"delete\"\n");
+
+        let missing = handle_query("control=reset").unwrap();
+        assert_eq!(missing.status, 400);
+        assert!(missing.body.starts_with("missing group;"));
+
+        let invalid = handle_query("control=reset&group=everything").unwrap();
+        assert_eq!(invalid.status, 400);
+        assert_eq!(
+            invalid.body,
+            "invalid group \"everything\"; expected one of server, upstream, cache, connections\n"
+        );
+    }
+
+    #[test]
+    fn malformed_top_queries_are_rejected() {
+        for (query, reply) in [
+            ("top=0", "invalid top \"0\"; expected a positive number\n"),
+            (
+                "top=ten",
+                "invalid top \"ten\"; expected a positive number\n",
+            ),
+            (
+                "top=5&by=latency",
+                "invalid by \"latency\"; expected one of requests, bytes, errors, avg_time\n",
+            ),
+            (
+                "top=5&group=cache",
+                "invalid group \"cache\"; expected server or upstream\n",
+            ),
+        ] {
+            let response = handle_query(query).unwrap();
+            assert_eq!(response.status, 400, "{query}");
+            assert_eq!(response.body, reply, "{query}");
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! `?mode=delta` on a `vts_status` location: counters as the increase
+//! since the previous delta scrape instead of since startup.
+//!
+//! Built on [`crate::sample::collect_status_samples`].
+//! Each delta scrape subtracts the values recorded by the one before it
+//! and then records its own, so there is a single baseline per worker
+//! shared by every client: two collectors scraping `?mode=delta` split
+//! the increases between them.  Cumulative scrapes (no `mode`) neither
+//! read nor move the baseline, but mixing delta and cumulative readers
+//! of the same series is unsupported.
+//!
+//! The baseline is process-local even when the counters are not: with
+//! a `vts_zone` every worker diffs the same shared snapshot against its
+//! own previous delta scrape, so two scrapes answered by different
+//! workers report overlapping increases.  Without a zone each answer is
+//! the increase of the answering worker's own counters.  Either way the
+//! result is only a per-interval series with one worker answering
+//! (`worker_processes 1`); the passed-through `nginx_vts_worker_id`
+//! gauge tells a collector which worker it got.
+//!
+//! The first delta scrape has no baseline and returns the full values.
+//! A counter that went down (a `?control=reset`) reports its new value.
+//! Gauges are passed through unchanged.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::prometheus::escape_label_value;
+use crate::sample::{MetricKind, Sample};
+
+/// Name and labels identifying one series.
+type SeriesKey = (String, Vec<(String, String)>);
+
+/// Counter values seen by the previous delta scrape; `None` before the
+/// first one.
+static BASELINE: Mutex<Option<HashMap<SeriesKey, f64>>> = Mutex::new(None);
+
+/// Replace every counter and histogram value in `samples` by its
+/// increase since the previous call, and remember the current values
+/// for the next one.
+pub fn apply(samples: &mut [Sample]) {
+    let mut baseline = BASELINE.lock().unwrap_or_else(crate::recover_poisoned);
+    let previous = baseline.take();
+    let mut current = HashMap::with_capacity(samples.len());
+    for sample in samples.iter_mut() {
+        if sample.kind == MetricKind::Gauge {
+            continue;
+        }
+        let key = (sample.name.clone(), sample.labels.clone());
+        let value = sample.value;
+        if let Some(&before) = previous.as_ref().and_then(|p| p.get(&key)) {
+            if value >= before {
+                sample.value = value - before;
+            }
+        }
+        current.insert(key, value);
+    }
+    *baseline = Some(current);
+}
+
+/// Forget the baseline, so the next delta scrape returns full values.
+#[cfg(test)]
+pub fn clear_baseline() {
+    *BASELINE.lock().unwrap_or_else(crate::recover_poisoned) = None;
+}
+
+/// Render the status page as deltas, in line protocol when `influx` is
+/// set and otherwise as exposition-format sample lines.
+pub fn generate_vts_status_delta(influx: bool) -> String {
+    let mut samples = crate::sample::collect_status_samples();
+    apply(&mut samples);
+    if influx {
+        crate::influx::format_samples(&samples)
+    } else {
+        format_text(&samples)
+    }
+}
+
+/// One `name{labels} value` line per sample.  There are no `# TYPE`
+/// lines: a delta is neither a counter nor a gauge of the original
+/// series, so the output is not meant for a Prometheus server.
+fn format_text(samples: &[Sample]) -> String {
+    let mut output = String::new();
+    for sample in samples {
+        output.push_str(&sample.name);
+        for (i, (key, value)) in sample.labels.iter().enumerate() {
+            output.push(if i == 0 { '{' } else { ',' });
+            let _ = write!(output, "{key}=\"{}\"", escape_label_value(value));
+        }
+        if !sample.labels.is_empty() {
+            output.push('}');
+        }
+        let _ = writeln!(output, " {}", sample.value);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(kind: MetricKind, value: f64) -> Sample {
+        Sample {
+            name: "nginx_vts_server_requests_total".into(),
+            labels: vec![("zone".into(), "a \"b\"".into())],
+            value,
+            kind,
+        }
+    }
+
+    #[test]
+    fn counters_drop_back_to_full_value_after_a_reset() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        clear_baseline();
+
+        let mut first = [sample(MetricKind::Counter, 10.0)];
+        apply(&mut first);
+        assert_eq!(first[0].value, 10.0);
+
+        let mut reset = [sample(MetricKind::Counter, 3.0)];
+        apply(&mut reset);
+        assert_eq!(reset[0].value, 3.0);
+
+        let mut gauge = [sample(MetricKind::Gauge, 7.0)];
+        apply(&mut gauge);
+        assert_eq!(gauge[0].value, 7.0);
+        clear_baseline();
+
+        assert_eq!(
+            format_text(&first),
+            "nginx_vts_server_requests_total{zone=\"a \\\"b\\\"\"} 10\n"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Plain-text self-diagnostics served by `vts_status control=status`.
+//!
+//! Unlike the Prometheus output this report describes the module
+//! itself — how many zones it is tracking, whether the shared zone is
+//! in use, and whether any lock has been poisoned — so operators can
+//! tell a misconfigured module apart from a quiet one.
+
+use crate::vts_node::VtsStatsManager;
+
+/// Module-internal state summarised by the diagnostics report.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VtsDiagnostics {
+    /// Distinct server zones currently tracked.
+    pub server_zones: usize,
+    /// Distinct upstream groups currently tracked.
+    pub upstream_zones: usize,
+    /// Distinct cache zones currently tracked.
+    pub cache_zones: usize,
+    /// Whether a `vts_zone` shared-memory zone is in use.
+    pub shared_memory: bool,
+    /// Configured `vts_zone` size in bytes (`0` when not configured).
+    pub zone_size: usize,
+    /// Poisoned-lock recoveries since startup.
+    pub lock_poisoned: u64,
+    /// Request times clamped to 0 because the clock went backwards.
+    pub clock_skew: u64,
+}
+
+impl VtsDiagnostics {
+    /// Gather the current diagnostics.  Zone counts come from the
+    /// shared zone when configured, otherwise from the process-local
+    /// `manager`.
+    pub fn collect(manager: &VtsStatsManager) -> Self {
+        let server_zones = crate::shm::snapshot_servers()
+            .map(|m| m.len())
+            .unwrap_or_else(|| manager.stats.len());
+        let upstream_zones = crate::shm::snapshot_upstreams()
+            .map(|m| m.len())
+            .unwrap_or_else(|| manager.upstream_zones.len());
+        let cache_zones = crate::shm::snapshot_caches()
+            .map(|m| m.len())
+            .unwrap_or_else(|| crate::get_all_cache_zones().len());
+
+        Self {
+            server_zones,
+            upstream_zones,
+            cache_zones,
+            shared_memory: crate::shm::is_configured(),
+            zone_size: crate::shm::zone_size().unwrap_or(0),
+            lock_poisoned: crate::lock_poison_count(),
+            clock_skew: crate::clock_skew_count(),
+        }
+    }
+
+    /// Render as `key: value` lines.
+    pub fn render(&self) -> String {
+        format!(
+            "# nginx-vts-rust diagnostics\n\
+             # Version: {}\n\
+             server_zones: {}\n\
+             upstream_zones: {}\n\
+             cache_zones: {}\n\
+             shared_memory: {}\n\
+             zone_size: {}\n\
+             lock_poisoned: {}\n\
+             clock_skew: {}\n",
+            env!("CARGO_PKG_VERSION"),
+            self.server_zones,
+            self.upstream_zones,
+            self.cache_zones,
+            if self.shared_memory {
+                "initialized"
+            } else {
+                "not configured"
+            },
+            self.zone_size,
+            self.lock_poisoned,
+            self.clock_skew,
+        )
+    }
+}
+
+/// Generate the `control=status` diagnostics report.
+pub fn generate_vts_diagnostics_content() -> String {
+    let manager = crate::VTS_MANAGER
+        .read()
+        .unwrap_or_else(crate::recover_poisoned);
+    VtsDiagnostics::collect(&manager).render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_lists_every_field() {
+        let diag = VtsDiagnostics {
+            server_zones: 3,
+            upstream_zones: 2,
+            cache_zones: 1,
+            shared_memory: true,
+            zone_size: 1_048_576,
+            lock_poisoned: 0,
+            clock_skew: 4,
+        };
+        let out = diag.render();
+        assert!(out.starts_with("# nginx-vts-rust diagnostics\n"));
+        assert!(out.contains("server_zones: 3\n"));
+        assert!(out.contains("upstream_zones: 2\n"));
+        assert!(out.contains("cache_zones: 1\n"));
+        assert!(out.contains("shared_memory: initialized\n"));
+        assert!(out.contains("zone_size: 1048576\n"));
+        assert!(out.contains("lock_poisoned: 0\n"));
+        assert!(out.contains("clock_skew: 4\n"));
+    }
+
+    #[test]
+    fn collect_counts_process_local_zones() {
+        let mut manager = VtsStatsManager::new();
+        manager.update_server_stats("a.example.com", 200, 1, 1, 1);
+        manager.update_server_stats("b.example.com", 200, 1, 1, 1);
+        manager.update_upstream_stats("backend", "10.0.0.1:80", 1, 1, 1, 1, 200);
+        manager.update_upstream_stats("backend", "10.0.0.2:80", 1, 1, 1, 1, 200);
+        manager.update_upstream_stats("api", "10.0.1.1:80", 1, 1, 1, 1, 200);
+
+        let diag = VtsDiagnostics::collect(&manager);
+        assert_eq!(diag.server_zones, 2);
+        assert_eq!(diag.upstream_zones, 2);
+        // The unit-test binary never configures a `vts_zone`.
+        assert!(!diag.shared_memory);
+        assert_eq!(diag.zone_size, 0);
+        assert!(diag.render().contains("shared_memory: not configured\n"));
+    }
+}
@@ -0,0 +1,103 @@
+//! Errors from the Rust side of directive handling, rendered for the C
+//! handlers as static NUL-terminated strings they can log or return as
+//! `char *` to nginx.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+
+/// Why a directive's value was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VtsError {
+    /// `vts_zone_label` name isn't a valid exposition-format label name.
+    InvalidLabelName,
+    /// `vts_zone_label` name is reserved or used by the server families.
+    ReservedLabelName,
+    /// `vts_zone_label` name repeats one the zone already carries.
+    DuplicateLabelName,
+    /// A zone would carry more than [`crate::vts_node::MAX_ZONE_LABELS`].
+    TooManyLabels,
+    /// The directive was already given for this upstream or zone.
+    Duplicate,
+    /// `vts_unix_socket` in a build without the `unix-socket` feature.
+    UnixSocketUnsupported,
+    /// `vts_track_unique_clients on` in a build without the
+    /// `unique-clients` feature.
+    UniqueClientsUnsupported,
+}
+
+impl VtsError {
+    /// The message as nginx takes it: a directive handler may log it or
+    /// return it as is, in which case nginx prefixes the directive name.
+    pub fn as_c_str(self) -> &'static CStr {
+        match self {
+            VtsError::InvalidLabelName => c"invalid label name",
+            VtsError::ReservedLabelName => c"reserved label name",
+            VtsError::DuplicateLabelName => c"duplicate label name",
+            VtsError::TooManyLabels => c"too many labels for one zone",
+            VtsError::Duplicate => c"is duplicate",
+            VtsError::UnixSocketUnsupported => {
+                c"requires the module to be built with the unix-socket feature"
+            }
+            VtsError::UniqueClientsUnsupported => {
+                c"requires the module to be built with the unique-clients feature"
+            }
+        }
+    }
+
+    /// [`Self::as_c_str`] as a pointer for an FFI return value.
+    pub fn as_ptr(self) -> *const c_char {
+        self.as_c_str().as_ptr()
+    }
+}
+
+/// An FFI result: NULL for `Ok`, otherwise the static error message.
+pub fn result_ptr(result: Result<(), VtsError>) -> *const c_char {
+    match result {
+        Ok(()) => std::ptr::null(),
+        Err(error) => error.as_ptr(),
+    }
+}
+
+impl fmt::Display for VtsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.as_c_str().to_string_lossy())
+    }
+}
+
+impl std::error::Error for VtsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_variant_renders_its_message() {
+        for (error, message) in [
+            (VtsError::InvalidLabelName, "invalid label name"),
+            (VtsError::ReservedLabelName, "reserved label name"),
+            (VtsError::DuplicateLabelName, "duplicate label name"),
+            (VtsError::TooManyLabels, "too many labels for one zone"),
+            (VtsError::Duplicate, "is duplicate"),
+            (
+                VtsError::UnixSocketUnsupported,
+                "requires the module to be built with the unix-socket feature",
+            ),
+            (
+                VtsError::UniqueClientsUnsupported,
+                "requires the module to be built with the unique-clients feature",
+            ),
+        ] {
+            assert_eq!(error.as_c_str().to_str(), Ok(message));
+            assert_eq!(error.to_string(), message);
+            let rendered = unsafe { CStr::from_ptr(error.as_ptr()) };
+            assert_eq!(rendered, error.as_c_str());
+        }
+    }
+
+    #[test]
+    fn result_ptr_is_null_only_for_ok() {
+        assert!(result_ptr(Ok(())).is_null());
+        assert!(!result_ptr(Err(VtsError::Duplicate)).is_null());
+    }
+}
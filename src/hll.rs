@@ -0,0 +1,120 @@
+//! HyperLogLog distinct-count estimator for `vts_track_unique_clients`.
+//!
+//! Enabled with the `unique-clients` cargo feature.  Each server zone
+//! keeps 2^[`HLL_PRECISION`] one-byte registers (4 KiB); the estimate
+//! has a standard error of `1.04 / sqrt(4096)`, about 1.6%.  As with
+//! the latency histogram, the registers are a fixed-size array so the
+//! sketch can live as a `Copy` value in the shared-memory zone, where
+//! every worker inserts into the same sketch.
+//!
+//! Below `2.5 × 4096` the estimate switches to linear counting over the
+//! empty registers, which is close to exact for small zones.  With a
+//! 64-bit hash no large-range correction is needed.
+
+use std::hash::Hasher;
+
+/// `log2` of the register count.
+pub const HLL_PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << HLL_PRECISION;
+
+/// Fixed-size HyperLogLog sketch of byte strings (client addresses).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HyperLogLog {
+    registers: [u8; REGISTERS],
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HyperLogLog {
+    /// Create an empty sketch.
+    pub const fn new() -> Self {
+        Self {
+            registers: [0; REGISTERS],
+        }
+    }
+
+    /// Add one item.  Adding the same item again changes nothing.
+    pub fn insert(&mut self, item: &[u8]) {
+        // Same fixed-key SipHash as `vts_sample_rate`'s slots, so every
+        // worker hashes an address to the same register.
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write(item);
+        let hash = hasher.finish();
+
+        let index = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rest = hash << HLL_PRECISION;
+        let rank = (rest.leading_zeros().min(64 - HLL_PRECISION) + 1) as u8;
+        let register = &mut self.registers[index];
+        *register = (*register).max(rank);
+    }
+
+    /// Estimated number of distinct items added.
+    pub fn estimate(&self) -> f64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let mut sum = 0.0;
+        let mut zeros = 0;
+        for &r in &self.registers {
+            sum += 1.0 / (1u64 << r) as f64;
+            if r == 0 {
+                zeros += 1;
+            }
+        }
+        let raw = alpha * m * m / sum;
+        if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(n: u32) -> Vec<u8> {
+        std::net::Ipv4Addr::from(0x0a00_0000 + n)
+            .to_string()
+            .into_bytes()
+    }
+
+    /// Whether `estimate` is within three standard errors of `actual`.
+    fn within_error(estimate: f64, actual: u32) -> bool {
+        let error = 3.0 * 1.04 / (REGISTERS as f64).sqrt();
+        (estimate - actual as f64).abs() <= actual as f64 * error
+    }
+
+    #[test]
+    fn empty_sketch_estimates_zero() {
+        let hll = HyperLogLog::new();
+        assert_eq!(hll.estimate(), 0.0);
+    }
+
+    #[test]
+    fn repeats_are_not_counted_again() {
+        let mut hll = HyperLogLog::new();
+        for _ in 0..10 {
+            for n in 0..100 {
+                hll.insert(&ip(n));
+            }
+        }
+        assert!(within_error(hll.estimate(), 100), "{}", hll.estimate());
+    }
+
+    #[test]
+    fn estimate_stays_within_error_bounds() {
+        for actual in [1_000, 10_000, 100_000] {
+            let mut hll = HyperLogLog::new();
+            for n in 0..actual {
+                hll.insert(&ip(n));
+            }
+            let estimate = hll.estimate();
+            assert!(within_error(estimate, actual), "{actual}: {estimate}");
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! InfluxDB line protocol rendering of the status page, served for
+//! `?format=influx` on a `vts_status` location.
+//!
+//! Built on [`crate::sample::collect_status_samples`]: each sample's metric
+//! group (`server`, `upstream`, …) becomes the measurement, its identity
+//! labels (`zone`, `upstream`, `server`, …) become tags, and the rest of
+//! the name plus any breakdown labels (`direction`, `status`, `type`, …)
+//! becomes the field key.  Samples sharing a measurement and tag set are
+//! written as one line:
+//!
+//! ```text
+//! nginx_vts_upstream,upstream=backend,server=10.0.0.1:80 requests=500i,bytes_in=375000i 1700000000000000000
+//! ```
+//!
+//! Counters and histogram buckets/counts are integer fields (`i`
+//! suffix); gauges and histogram sums are floats, so a field keeps one
+//! type from scrape to scrape.
+
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::sample::{MetricKind, Sample};
+
+/// Measurement prefix, matching the Prometheus metric names.
+const PREFIX: &str = "nginx_vts_";
+
+/// Labels that break one series into several fields rather than
+/// identifying what is being measured.
+const FIELD_LABELS: &[&str] = &[
+    "direction",
+    "status",
+    "type",
+    "state",
+    "method",
+    "le",
+    "quantile",
+];
+
+/// Render the status page as line protocol, one line per measurement
+/// and tag set, all stamped with the current time.
+pub fn generate_vts_status_influx() -> String {
+    format_samples(&crate::sample::collect_status_samples())
+}
+
+/// Render `samples` as line protocol stamped with the current time.
+pub(crate) fn format_samples(samples: &[Sample]) -> String {
+    let timestamp_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    format_line_protocol(samples, timestamp_ns)
+}
+
+/// One output line being assembled.
+struct Line {
+    measurement: String,
+    tags: Vec<(String, String)>,
+    fields: Vec<(String, String)>,
+}
+
+/// Group `samples` into lines, keeping the order in which each
+/// measurement and tag set first appears.
+fn format_line_protocol(samples: &[Sample], timestamp_ns: u128) -> String {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut index: HashMap<(String, Vec<(String, String)>), usize> = HashMap::new();
+
+    for sample in samples {
+        let name = sample.name.strip_prefix(PREFIX).unwrap_or(&sample.name);
+        let name = name.strip_suffix("_total").unwrap_or(name);
+        let (group, rest) = name.split_once('_').unwrap_or((name, ""));
+
+        let mut tags = Vec::new();
+        let mut field = vec![rest];
+        for (key, value) in &sample.labels {
+            if FIELD_LABELS.contains(&key.as_str()) {
+                field.push(value);
+            } else if !value.is_empty() {
+                // Line protocol has no empty tag values; drop the tag.
+                tags.push((key.clone(), value.clone()));
+            }
+        }
+        let field: Vec<&str> = field.into_iter().filter(|s| !s.is_empty()).collect();
+        let field = if field.is_empty() {
+            "value".to_string()
+        } else {
+            field.join("_")
+        };
+
+        let integer = match sample.kind {
+            MetricKind::Counter => true,
+            MetricKind::Histogram => !sample.name.ends_with("_sum"),
+            MetricKind::Gauge => false,
+        };
+        let value = if integer {
+            format!("{}i", sample.value as u64)
+        } else {
+            format!("{:?}", sample.value)
+        };
+
+        let measurement = format!("{PREFIX}{group}");
+        let slot = *index
+            .entry((measurement.clone(), tags.clone()))
+            .or_insert_with(|| {
+                lines.push(Line {
+                    measurement,
+                    tags,
+                    fields: Vec::new(),
+                });
+                lines.len() - 1
+            });
+        lines[slot].fields.push((field, value));
+    }
+
+    let mut output = String::new();
+    for line in &lines {
+        output.push_str(&escape(&line.measurement, false));
+        for (key, value) in &line.tags {
+            output.push(',');
+            output.push_str(&escape(key, true));
+            output.push('=');
+            output.push_str(&escape(value, true));
+        }
+        for (i, (key, value)) in line.fields.iter().enumerate() {
+            output.push(if i == 0 { ' ' } else { ',' });
+            output.push_str(&escape(key, true));
+            output.push('=');
+            output.push_str(value);
+        }
+        output.push_str(&format!(" {timestamp_ns}\n"));
+    }
+    output
+}
+
+/// Backslash-escape commas and spaces, and equals signs too in tag
+/// keys, tag values and field keys (`with_equals`).
+fn escape(s: &str, with_equals: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == ',' || c == ' ' || (with_equals && c == '=') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vts_node::VtsStatsManager;
+
+    fn line<'a>(output: &'a str, prefix: &str) -> &'a str {
+        output
+            .lines()
+            .find(|l| l.starts_with(prefix))
+            .unwrap_or_else(|| panic!("no line starting with {prefix:?} in\n{output}"))
+    }
+
+    #[test]
+    fn upstream_server_renders_as_one_line() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut manager = VtsStatsManager::new();
+        manager.update_upstream_stats("backend", "10.0.0.1:80", 100, 50, 250, 750, 200);
+        manager.update_upstream_stats("backend", "10.0.0.1:80", 100, 50, 250, 750, 503);
+
+        let output = format_line_protocol(&manager.collect_samples(), 1_700_000_000_000_000_000);
+        let upstream = line(
+            &output,
+            "nginx_vts_upstream,upstream=backend,server=10.0.0.1:80 ",
+        );
+        assert!(upstream.starts_with(
+            "nginx_vts_upstream,upstream=backend,server=10.0.0.1:80 requests=2i,bytes_in=1500i,bytes_out=500i,"
+        ));
+        assert!(upstream.contains(",responses_5xx=1i,"));
+        assert!(upstream.contains(",server_up=1.0,"));
+        assert!(upstream.contains(",response_duration_seconds_bucket_+Inf=2i,"));
+        assert!(upstream.ends_with(" 1700000000000000000"));
+
+        // measurement,tags fields timestamp — exactly two unescaped spaces.
+        for l in output.lines() {
+            let unescaped = l.replace("\\ ", "");
+            assert_eq!(unescaped.matches(' ').count(), 2, "{l}");
+        }
+    }
+
+    #[test]
+    fn zone_tags_are_escaped() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut manager = VtsStatsManager::new();
+        manager
+            .set_zone_labels("a b,c=d", vec![("team".into(), "web ops".into())])
+            .unwrap();
+        manager.update_server_stats("a b,c=d", 200, 10, 20, 5);
+
+        let output = format_line_protocol(&manager.collect_samples(), 1);
+        let server = line(&output, "nginx_vts_server,");
+        assert!(server.starts_with(
+            "nginx_vts_server,zone=a\\ b\\,c\\=d,team=web\\ ops requests=1i,bytes_in=10i,bytes_out=20i,"
+        ));
+        assert!(server.contains(",request_seconds_avg=0.005,"));
+        assert!(line(&output, "nginx_vts_connections ").contains("active=0.0"));
+    }
+
+    #[test]
+    fn shared_memory_zones_are_included() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut shared = HashMap::new();
+        shared.insert(
+            "shared.example.com".to_string(),
+            crate::stats::VtsServerStats {
+                requests: 5,
+                bytes_in: 50,
+                bytes_out: 500,
+                ..Default::default()
+            },
+        );
+        crate::shm::set_test_servers(Some(shared));
+        let output = generate_vts_status_influx();
+        crate::shm::set_test_servers(None);
+
+        let server = line(&output, "nginx_vts_server,zone=shared.example.com ");
+        assert!(server.contains(" requests=5i,bytes_in=50i,bytes_out=500i,"));
+        // Process-wide families of the page come along too.
+        line(&output, "nginx_vts_ssl,result=ok ");
+    }
+}
@@ -0,0 +1,190 @@
+//! HdrHistogram-style latency histogram for upstream percentiles.
+//!
+//! Enabled with the `latency-percentiles` cargo feature.  Uses the
+//! HdrHistogram bucket layout with 2 significant decimal digits: every
+//! recorded value lands in a bucket no wider than 1/128 of the value
+//! itself, so any reported quantile is within ~0.8% of the true
+//! sample.  Storage is a fixed-size array (16 KiB) rather than a heap
+//! allocation so the histogram can live inside the `Copy` counters in
+//! the shared-memory zone.
+//!
+//! Values are milliseconds from 0 up to [`LATENCY_MAX_MS`]; larger
+//! values are clamped into the top bucket.
+
+/// Highest value tracked without clamping: one hour.
+pub const LATENCY_MAX_MS: u64 = 60 * 60 * 1000;
+
+/// `log2` of half the sub-bucket count (128 sub-buckets per half).
+const SUB_BUCKET_HALF_MAGNITUDE: u32 = 7;
+const SUB_BUCKET_HALF_COUNT: usize = 1 << SUB_BUCKET_HALF_MAGNITUDE;
+/// 256 sub-buckets: the smallest power of two ≥ 2 × 10² (2 digits).
+const SUB_BUCKET_COUNT: usize = SUB_BUCKET_HALF_COUNT * 2;
+const SUB_BUCKET_MASK: u64 = SUB_BUCKET_COUNT as u64 - 1;
+/// Buckets needed so `SUB_BUCKET_COUNT << (BUCKET_COUNT - 1)` exceeds
+/// [`LATENCY_MAX_MS`].
+const BUCKET_COUNT: usize = 15;
+const COUNTS_LEN: usize = (BUCKET_COUNT + 1) * SUB_BUCKET_HALF_COUNT;
+
+/// Quantiles rendered as `nginx_vts_upstream_response_quantile_seconds`.
+pub const LATENCY_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Fixed-size log-linear histogram of millisecond latencies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: [u64; COUNTS_LEN],
+    total: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    /// Create an empty histogram.
+    pub const fn new() -> Self {
+        Self {
+            counts: [0; COUNTS_LEN],
+            total: 0,
+        }
+    }
+
+    /// Record one observation of `ms` milliseconds.
+    pub fn record(&mut self, ms: u64) {
+        self.counts[counts_index(ms.min(LATENCY_MAX_MS))] += 1;
+        self.total += 1;
+    }
+
+    /// Fold `other` into `self`, e.g. to restore a saved histogram into
+    /// the shared zone.
+    pub fn add(&mut self, other: &LatencyHistogram) {
+        for (mine, theirs) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *mine += theirs;
+        }
+        self.total += other.total;
+    }
+
+    /// Value (in milliseconds) at quantile `q` in `0.0..=1.0`: the
+    /// highest value equivalent to the sample of that rank.  Returns
+    /// `0` for an empty histogram.
+    pub fn value_at_quantile(&self, q: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return highest_equivalent_value(value_from_index(index));
+            }
+        }
+        LATENCY_MAX_MS
+    }
+}
+
+/// Bucket (power-of-two range) that `value` falls in.
+fn bucket_index(value: u64) -> usize {
+    let leading_zero_count_base = 64 - SUB_BUCKET_HALF_MAGNITUDE - 1;
+    (leading_zero_count_base - (value | SUB_BUCKET_MASK).leading_zeros()) as usize
+}
+
+fn counts_index(value: u64) -> usize {
+    let bucket = bucket_index(value);
+    let sub_bucket = (value >> bucket) as usize;
+    ((bucket + 1) << SUB_BUCKET_HALF_MAGNITUDE) + sub_bucket - SUB_BUCKET_HALF_COUNT
+}
+
+/// Lowest value that maps to counts slot `index`.
+fn value_from_index(index: usize) -> u64 {
+    let mut bucket = (index >> SUB_BUCKET_HALF_MAGNITUDE) as isize - 1;
+    let mut sub_bucket = (index & (SUB_BUCKET_HALF_COUNT - 1)) + SUB_BUCKET_HALF_COUNT;
+    if bucket < 0 {
+        sub_bucket -= SUB_BUCKET_HALF_COUNT;
+        bucket = 0;
+    }
+    (sub_bucket as u64) << bucket
+}
+
+fn highest_equivalent_value(value: u64) -> u64 {
+    value + (1u64 << bucket_index(value)) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn within_precision(actual: u64, expected: u64) -> bool {
+        // One bucket is at most 1/128 of the value wide.
+        actual.abs_diff(expected) <= expected / 128 + 1
+    }
+
+    #[test]
+    fn layout_covers_the_tracked_range() {
+        assert!(counts_index(LATENCY_MAX_MS) < COUNTS_LEN);
+        assert_eq!(counts_index(0), 0);
+        assert_eq!(counts_index(255), 255);
+        // Exact below the first power-of-two boundary.
+        for v in 0..256 {
+            assert_eq!(value_from_index(counts_index(v)), v);
+        }
+    }
+
+    #[test]
+    fn quantiles_of_uniform_dataset() {
+        let mut h = LatencyHistogram::new();
+        for ms in 1..=10_000 {
+            h.record(ms);
+        }
+        assert_eq!(h.total, 10_000);
+
+        let p50 = h.value_at_quantile(0.5);
+        let p99 = h.value_at_quantile(0.99);
+        assert!(within_precision(p50, 5_000), "p50 = {p50}");
+        assert!(within_precision(p99, 9_900), "p99 = {p99}");
+        assert!(within_precision(h.value_at_quantile(1.0), 10_000));
+        assert_eq!(h.value_at_quantile(0.0), 1);
+    }
+
+    #[test]
+    fn quantiles_of_skewed_dataset() {
+        // 98 fast requests and 2 slow ones: p50 stays fast, p99 is slow.
+        let mut h = LatencyHistogram::new();
+        for _ in 0..98 {
+            h.record(12);
+        }
+        h.record(1_500);
+        h.record(1_500);
+        assert_eq!(h.value_at_quantile(0.5), 12);
+        assert!(within_precision(h.value_at_quantile(0.99), 1_500));
+    }
+
+    #[test]
+    fn add_merges_servers() {
+        let mut a = LatencyHistogram::new();
+        let mut b = LatencyHistogram::new();
+        for ms in 1..=500 {
+            a.record(ms);
+        }
+        for ms in 501..=1_000 {
+            b.record(ms);
+        }
+
+        let mut merged = LatencyHistogram::new();
+        merged.add(&a);
+        merged.add(&b);
+        assert_eq!(merged.total, 1_000);
+        assert!(within_precision(merged.value_at_quantile(0.5), 500));
+        assert!(within_precision(merged.value_at_quantile(0.99), 990));
+    }
+
+    #[test]
+    fn oversized_values_are_clamped_and_empty_is_zero() {
+        let mut h = LatencyHistogram::new();
+        assert_eq!(h.total, 0);
+        assert_eq!(h.value_at_quantile(0.99), 0);
+        h.record(u64::MAX);
+        assert!(within_precision(h.value_at_quantile(0.5), LATENCY_MAX_MS));
+    }
+}
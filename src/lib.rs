@@ -5,21 +5,55 @@
 //! with Prometheus metrics output.
 
 use ngx::ffi::*;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::os::raw::c_char;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use crate::cache_stats::CacheStatsManager;
+use crate::error::VtsError;
 use crate::prometheus::generate_vts_status_content;
+use crate::request::RequestRef;
+use crate::shm::ConnPhase;
+use crate::stats::{HttpMethod, QuicPhase};
+use crate::upstream_stats::UpstreamZone;
 use crate::vts_node::VtsStatsManager;
 
 #[cfg(test)]
 static GLOBAL_VTS_TEST_MUTEX: std::sync::Mutex<()> = std::sync::Mutex::new(());
 
+#[cfg(all(test, feature = "bench"))]
+mod bench;
 mod cache_stats;
 mod connection_stats;
+mod control;
+mod delta;
+mod diagnostics;
+mod error;
+#[cfg(feature = "unique-clients")]
+mod hll;
+mod influx;
+#[cfg(feature = "latency-percentiles")]
+mod latency;
 mod prometheus;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+#[cfg(feature = "remote-write")]
+mod remote_write;
+mod request;
+mod sample;
+mod selftest;
 mod shm;
+mod slow_log;
+mod snapshot;
+mod state_file;
 mod stats;
+mod status_rate;
+mod top;
+#[cfg(feature = "unix-socket")]
+mod unix_socket;
 mod upstream_stats;
 mod vts_node;
 
@@ -31,22 +65,18 @@ fn calculate_time_diff_ms(
     current_sec: u64,
     current_msec: u64,
 ) -> u64 {
-    // Calculate time difference in milliseconds
-    // Formula: (current_sec - start_sec) * 1000 + (current_msec - start_msec)
-    if current_msec >= start_msec {
-        let sec_diff = current_sec.saturating_sub(start_sec);
-        let msec_diff = current_msec - start_msec;
-        sec_diff * 1000 + msec_diff
-    } else {
-        // Only borrow if current_sec > start_sec, otherwise return 0 to avoid underflow
-        if current_sec > start_sec {
-            let sec_diff = current_sec - (start_sec + 1);
-            let msec_diff = (current_msec + 1000) - start_msec;
-            sec_diff * 1000 + msec_diff
-        } else {
-            0
-        }
+    // An end before the start means the clock stepped backwards:
+    // count it and report 0 rather than underflow.
+    if (current_sec, current_msec) < (start_sec, start_msec) {
+        CLOCK_SKEW_COUNT.fetch_add(1, Ordering::Relaxed);
+        return 0;
     }
+    // Formula: (current_sec - start_sec) * 1000 + current_msec - start_msec,
+    // saturating so extreme timestamps cannot overflow.
+    (current_sec - start_sec)
+        .saturating_mul(1000)
+        .saturating_add(current_msec)
+        .saturating_sub(start_msec)
 }
 
 /// Calculate elapsed milliseconds since the request started.
@@ -70,6 +100,521 @@ fn calculate_request_time(start_sec: u64, start_msec: u64) -> u64 {
     }
 }
 
+/// Longest `$vts_request_time` value: `u64::MAX` milliseconds is 17
+/// digits of seconds, a dot and three decimals.  Matches
+/// `NGX_HTTP_VTS_REQUEST_TIME_LEN` in src/ngx_http_vts_module.c.
+pub const REQUEST_TIME_VAR_LEN: usize = 21;
+
+/// Format milliseconds the way nginx prints `$request_time`: seconds
+/// with millisecond resolution, e.g. `1.234`.
+fn format_request_time(ms: u64) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+/// Get handler behind `$vts_request_time`: writes the request's
+/// elapsed time, as computed for the VTS counters, into `buf` and
+/// returns its length.  Returns 0 when `r` or `buf` is null or `len`
+/// is below [`REQUEST_TIME_VAR_LEN`].
+///
+/// # Safety
+///
+/// `r` must be null or point to a live request; a non-null `buf` must
+/// be writable for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vts_request_time_variable(
+    r: *const ngx_http_request_t,
+    buf: *mut u8,
+    len: usize,
+) -> usize {
+    let Some(req) = RequestRef::from_ptr(r) else {
+        return 0;
+    };
+    if buf.is_null() || len < REQUEST_TIME_VAR_LEN {
+        return 0;
+    }
+    let value = format_request_time(req.request_time_ms());
+    std::ptr::copy_nonoverlapping(value.as_ptr(), buf, value.len());
+    value.len()
+}
+
+/// Default ceiling for a single request/response time observation:
+/// 10 minutes.  Anything longer is treated as a garbage value from the
+/// caller (clock skew wrapping a negative difference, uninitialised
+/// fields) rather than a real measurement.
+pub const DEFAULT_MAX_REQUEST_TIME_MS: u64 = 10 * 60 * 1000;
+
+/// Active ceiling, set from the `vts_max_request_time` directive.
+static MAX_REQUEST_TIME_MS: AtomicU64 = AtomicU64::new(DEFAULT_MAX_REQUEST_TIME_MS);
+
+/// Process-local count of rejected observations, used when no
+/// `vts_zone` is configured (the shared zone keeps its own total).
+static DISCARDED_OBSERVATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Process-local count of requests whose server name was not valid
+/// UTF-8, used when no `vts_zone` is configured.
+static NON_UTF8_NAME_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// TLS handshake outcomes reported through [`vts_track_ssl`].  One
+/// instance lives in the shared zone; [`SSL_COUNTERS`] is the
+/// process-local fallback when no `vts_zone` is configured.
+#[derive(Default)]
+pub struct SslCounters {
+    handshakes_ok: AtomicU64,
+    handshakes_failed: AtomicU64,
+    session_reuses: AtomicU64,
+}
+
+/// Point-in-time copy of [`SslCounters`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SslStats {
+    pub handshakes_ok: u64,
+    pub handshakes_failed: u64,
+    pub session_reuses: u64,
+}
+
+impl SslCounters {
+    pub const fn new() -> Self {
+        Self {
+            handshakes_ok: AtomicU64::new(0),
+            handshakes_failed: AtomicU64::new(0),
+            session_reuses: AtomicU64::new(0),
+        }
+    }
+
+    /// Count one handshake.  A resumed session is only counted when
+    /// the handshake succeeded.
+    pub fn record(&self, ok: bool, reused: bool) {
+        if ok {
+            self.handshakes_ok.fetch_add(1, Ordering::Relaxed);
+            if reused {
+                self.session_reuses.fetch_add(1, Ordering::Relaxed);
+            }
+        } else {
+            self.handshakes_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn load(&self) -> SslStats {
+        SslStats {
+            handshakes_ok: self.handshakes_ok.load(Ordering::Relaxed),
+            handshakes_failed: self.handshakes_failed.load(Ordering::Relaxed),
+            session_reuses: self.session_reuses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Process-local TLS handshake counters, used when no `vts_zone` is
+/// configured.
+static SSL_COUNTERS: SslCounters = SslCounters::new();
+
+/// Whether a millisecond timing is small enough to be a real
+/// measurement.  Observations failing this check are dropped whole so
+/// one bad sample can't permanently skew the averages.
+fn is_plausible_time_ms(ms: u64) -> bool {
+    ms <= MAX_REQUEST_TIME_MS.load(Ordering::Relaxed)
+}
+
+/// Count one observation rejected by [`is_plausible_time_ms`].
+fn record_discarded_observation() {
+    if !crate::shm::record_discarded() {
+        DISCARDED_OBSERVATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Total observations discarded by the plausibility guard.
+pub fn discarded_observations() -> u64 {
+    crate::shm::discarded_observations()
+        .unwrap_or_else(|| DISCARDED_OBSERVATIONS.load(Ordering::Relaxed))
+}
+
+/// Decode a server name from nginx, replacing invalid UTF-8 with
+/// U+FFFD instead of dropping the request, and count the replacement
+/// in `nginx_vts_non_utf8_name_requests_total`.
+fn server_zone_name_lossy(bytes: &[u8]) -> Cow<'_, str> {
+    let name = String::from_utf8_lossy(bytes);
+    if let Cow::Owned(_) = name {
+        if !crate::shm::record_non_utf8_name_request() {
+            NON_UTF8_NAME_REQUESTS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    name
+}
+
+/// Total requests whose server name was recorded under a lossy UTF-8
+/// decoding.  Every request counts, not each distinct name.
+pub fn non_utf8_name_requests() -> u64 {
+    crate::shm::non_utf8_name_requests()
+        .unwrap_or_else(|| NON_UTF8_NAME_REQUESTS.load(Ordering::Relaxed))
+}
+
+/// Record the outcome of one TLS handshake.  `result` is `NGX_OK` for
+/// a completed handshake and anything else for a failed one; `reused`
+/// is non-zero when the client resumed an earlier session.  Called
+/// once per TLS connection: from the log handler for a completed
+/// handshake, from the OpenSSL info callback for a failed one.
+#[no_mangle]
+pub extern "C" fn vts_track_ssl(result: ngx_int_t, reused: u8) {
+    let ok = result == NGX_OK as ngx_int_t;
+    let reused = reused != 0;
+    if !crate::shm::record_ssl(ok, reused) {
+        SSL_COUNTERS.record(ok, reused);
+    }
+}
+
+/// TLS handshake totals for `nginx_vts_ssl_handshakes_total` and
+/// `nginx_vts_ssl_session_reuses_total`.
+pub fn ssl_stats() -> SslStats {
+    crate::shm::ssl_stats().unwrap_or_else(|| SSL_COUNTERS.load())
+}
+
+/// Set the ceiling applied by the plausibility guard.  Called from the
+/// `vts_max_request_time` directive; `0` restores
+/// [`DEFAULT_MAX_REQUEST_TIME_MS`] (the preconfiguration hook does this
+/// so a reload without the directive doesn't keep a stale value).
+#[no_mangle]
+pub extern "C" fn vts_set_max_request_time_ms(ms: u64) {
+    let ms = if ms == 0 {
+        DEFAULT_MAX_REQUEST_TIME_MS
+    } else {
+        ms
+    };
+    MAX_REQUEST_TIME_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Default Apdex threshold T: 500 ms.  Requests up to T are satisfied,
+/// up to 4T tolerating, slower ones frustrated.
+pub const DEFAULT_APDEX_THRESHOLD_MS: u64 = 500;
+
+/// Active threshold, set from the `vts_apdex_threshold` directive.
+static APDEX_THRESHOLD_MS: AtomicU64 = AtomicU64::new(DEFAULT_APDEX_THRESHOLD_MS);
+
+/// Current Apdex threshold T in milliseconds.
+pub fn apdex_threshold_ms() -> u64 {
+    APDEX_THRESHOLD_MS.load(Ordering::Relaxed)
+}
+
+/// Set the Apdex threshold T.  Called from the `vts_apdex_threshold`
+/// directive; `0` restores [`DEFAULT_APDEX_THRESHOLD_MS`] (done by the
+/// preconfiguration hook, as for `vts_max_request_time`).
+#[no_mangle]
+pub extern "C" fn vts_set_apdex_threshold_ms(ms: u64) {
+    let ms = if ms == 0 {
+        DEFAULT_APDEX_THRESHOLD_MS
+    } else {
+        ms
+    };
+    APDEX_THRESHOLD_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Active `vts_min_window`, in seconds: a server zone's minimum request
+/// time only covers the current window of this length.  0, the
+/// default, keeps the all-time minimum.
+static MIN_WINDOW_SECS: AtomicU64 = AtomicU64::new(0);
+
+/// Current `vts_min_window` in seconds; 0 when the minimum is all-time.
+pub fn min_window_secs() -> u64 {
+    MIN_WINDOW_SECS.load(Ordering::Relaxed)
+}
+
+/// Set the minimum request time window.  Called from the
+/// `vts_min_window` directive; `0` (also set by the preconfiguration
+/// hook) keeps the all-time minimum.
+#[no_mangle]
+pub extern "C" fn vts_set_min_window_secs(secs: u64) {
+    MIN_WINDOW_SECS.store(secs, Ordering::Relaxed);
+}
+
+/// Default `vts_upstream_degraded_threshold`: an upstream server whose
+/// error rate over the last minute exceeds 10% is `degraded`.
+pub const DEFAULT_UPSTREAM_DEGRADED_PERCENT: u64 = 10;
+
+/// Active threshold in percent, set from the
+/// `vts_upstream_degraded_threshold` directive.
+static UPSTREAM_DEGRADED_PERCENT: AtomicU64 = AtomicU64::new(DEFAULT_UPSTREAM_DEGRADED_PERCENT);
+
+/// Error rate (`0.0..=1.0`) above which an upstream server is reported
+/// as `degraded` by `nginx_vts_upstream_server_state`.
+pub fn upstream_degraded_error_rate() -> f64 {
+    UPSTREAM_DEGRADED_PERCENT.load(Ordering::Relaxed) as f64 / 100.0
+}
+
+/// Set the degraded threshold in percent.  Called from the
+/// `vts_upstream_degraded_threshold` directive; `0` restores
+/// [`DEFAULT_UPSTREAM_DEGRADED_PERCENT`] (done by the preconfiguration
+/// hook).
+#[no_mangle]
+pub extern "C" fn vts_set_upstream_degraded_threshold(percent: u64) {
+    let percent = if percent == 0 {
+        DEFAULT_UPSTREAM_DEGRADED_PERCENT
+    } else {
+        percent.min(100)
+    };
+    UPSTREAM_DEGRADED_PERCENT.store(percent, Ordering::Relaxed);
+}
+
+/// Active `vts_upstream_server_limit`; 0 means no limit.
+static UPSTREAM_SERVER_LIMIT: AtomicU64 = AtomicU64::new(0);
+
+/// Set the most servers an upstream reports individually before the
+/// rest are folded into `server="__aggregated__"`.  Called from the
+/// `vts_upstream_server_limit` directive; `0` lifts the limit (done by
+/// the preconfiguration hook).
+#[no_mangle]
+pub extern "C" fn vts_set_upstream_server_limit(limit: u64) {
+    UPSTREAM_SERVER_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Apply `vts_upstream_server_limit` to `zones` (see
+/// [`UpstreamZone::limit_servers`]).  `None` when no limit is set, so
+/// callers keep using `zones` without copying it.
+pub fn limit_upstream_zones(
+    zones: &HashMap<String, UpstreamZone>,
+) -> Option<HashMap<String, UpstreamZone>> {
+    match UPSTREAM_SERVER_LIMIT.load(Ordering::Relaxed) {
+        0 => None,
+        limit => Some(
+            zones
+                .iter()
+                .map(|(name, zone)| (name.clone(), zone.limit_servers(limit as usize)))
+                .collect(),
+        ),
+    }
+}
+
+/// Set the `vts_status_rate` limit in status pages per second; `0`
+/// lifts it (done by the preconfiguration hook).
+#[no_mangle]
+pub extern "C" fn vts_set_status_rate(rate: u64) {
+    status_rate::set_rate(rate);
+}
+
+/// Set the `vts_slow_log_threshold` in milliseconds; `0` disables the
+/// slow upstream log (done by the preconfiguration hook).
+#[no_mangle]
+pub extern "C" fn vts_set_slow_log_threshold_ms(ms: u64) {
+    slow_log::set_threshold_ms(ms);
+}
+
+/// Set the `vts_shm_warn_threshold` in percent; `0` restores
+/// [`crate::shm::DEFAULT_WARN_THRESHOLD_PERCENT`] (done by the
+/// preconfiguration hook).
+#[no_mangle]
+pub extern "C" fn vts_set_shm_warn_threshold(percent: u64) {
+    crate::shm::set_warn_threshold_percent(percent);
+}
+
+/// Check called on every logged request: returns 1 the first time this
+/// worker sees the `vts_zone` slab pool with less free space than
+/// `vts_shm_warn_threshold`, filling in its `free` and `total` bytes for
+/// the warning the caller logs; 0 otherwise.
+///
+/// # Safety
+///
+/// `free` and `total` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn vts_shm_near_full_warning(free: *mut u64, total: *mut u64) -> u8 {
+    let Some(usage) = crate::shm::near_full_warning() else {
+        return 0;
+    };
+    if let Some(free) = free.as_mut() {
+        *free = usage.free;
+    }
+    if let Some(total) = total.as_mut() {
+        *total = usage.total;
+    }
+    1
+}
+
+/// Take a `vts_status_rate` token for a status request with query
+/// string `args`, unless it is one that renders nothing (see
+/// [`control::is_rate_limited`]).  Returns 0 when the reply may be
+/// generated, otherwise the seconds the client should wait
+/// (`Retry-After`) before trying again.
+///
+/// # Safety
+///
+/// `args` must point to `len` readable bytes (or be null with `len` 0).
+#[no_mangle]
+pub unsafe extern "C" fn ngx_http_vts_status_retry_after(args: *const u8, len: usize) -> u64 {
+    let args = if args.is_null() || len == 0 {
+        Cow::Borrowed("")
+    } else {
+        String::from_utf8_lossy(std::slice::from_raw_parts(args, len))
+    };
+    if !control::is_rate_limited(&args) {
+        return 0;
+    }
+    status_rate::check().err().unwrap_or(0)
+}
+
+/// Default `vts_max_label_len`: label values longer than 128 bytes are
+/// truncated on the status page.
+pub const DEFAULT_MAX_LABEL_LEN: u64 = 128;
+
+/// Active limit, set from the `vts_max_label_len` directive.
+static MAX_LABEL_LEN: AtomicU64 = AtomicU64::new(DEFAULT_MAX_LABEL_LEN);
+
+/// Current label-value length limit in bytes.
+pub fn max_label_len() -> usize {
+    MAX_LABEL_LEN.load(Ordering::Relaxed) as usize
+}
+
+/// Set the label-value length limit.  Called from the
+/// `vts_max_label_len` directive; `0` restores [`DEFAULT_MAX_LABEL_LEN`]
+/// (done by the preconfiguration hook, as for `vts_max_request_time`).
+#[no_mangle]
+pub extern "C" fn vts_set_max_label_len(len: u64) {
+    let len = if len == 0 {
+        DEFAULT_MAX_LABEL_LEN
+    } else {
+        len.max(prometheus::MIN_LABEL_LEN as u64)
+    };
+    MAX_LABEL_LEN.store(len, Ordering::Relaxed);
+}
+
+/// `vts_sample_rate`: server-zone updates record one request in this
+/// many.  1 records every request.
+static SAMPLE_RATE: AtomicU64 = AtomicU64::new(1);
+
+/// Requests seen per server zone, for [`sample_weight`].  Each zone
+/// keeps its own count, so it records exactly one in N of its requests
+/// however they interleave with other zones'.
+static SAMPLE_TICKS: std::sync::LazyLock<RwLock<HashMap<String, AtomicU64>>> =
+    std::sync::LazyLock::new(Default::default);
+
+/// Set the server-zone sample rate.  Called from the `vts_sample_rate`
+/// directive; `0` restores 1, every request (done by the
+/// preconfiguration hook, as for `vts_max_request_time`).
+#[no_mangle]
+pub extern "C" fn vts_set_sample_rate(rate: u64) {
+    SAMPLE_RATE.store(rate.max(1), Ordering::Relaxed);
+}
+
+/// How many requests this one stands for under `vts_sample_rate`: the
+/// rate for every Nth request of the zone and `0` (skip it) for the
+/// rest, so scaled counters approximate the true totals.
+fn sample_weight(server_name: &str) -> u64 {
+    let rate = SAMPLE_RATE.load(Ordering::Relaxed);
+    if rate <= 1 {
+        return 1;
+    }
+    let tick = SAMPLE_TICKS
+        .read()
+        .unwrap_or_else(recover_poisoned)
+        .get(server_name)
+        .map(|ticks| ticks.fetch_add(1, Ordering::Relaxed));
+    // Only a zone's first request takes the write lock.
+    let tick = tick.unwrap_or_else(|| {
+        SAMPLE_TICKS
+            .write()
+            .unwrap_or_else(recover_poisoned)
+            .entry(server_name.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed)
+    });
+    match tick % rate {
+        0 => rate,
+        _ => 0,
+    }
+}
+
+/// `worker_processes` from the nginx core configuration, set in
+/// `init_process`.
+static WORKER_PROCESSES: AtomicU64 = AtomicU64::new(0);
+
+/// This worker's slot (`ngx_worker`, `0..worker_processes`), set in
+/// `init_process`.
+static WORKER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Record the configured worker count and this worker's slot.  Called
+/// from `init_process` in every worker.
+#[no_mangle]
+pub extern "C" fn vts_set_worker_info(worker_id: u64, worker_processes: u64) {
+    WORKER_ID.store(worker_id, Ordering::Relaxed);
+    WORKER_PROCESSES.store(worker_processes, Ordering::Relaxed);
+}
+
+/// `worker_connections` from the events configuration, set in
+/// `init_process`.
+static CONNECTIONS_LIMIT: AtomicU64 = AtomicU64::new(0);
+
+/// Record the configured `worker_connections`.  Called from
+/// `init_process` in every worker.
+#[no_mangle]
+pub extern "C" fn vts_set_connections_limit(limit: u64) {
+    CONNECTIONS_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Default minimum time between two connection collections: 1 second.
+pub const DEFAULT_CONNECTION_REFRESH_INTERVAL_MS: u64 = 1000;
+
+/// Active interval, set from the `vts_connection_refresh_interval`
+/// directive.
+static CONNECTION_REFRESH_INTERVAL_MS: AtomicU64 =
+    AtomicU64::new(DEFAULT_CONNECTION_REFRESH_INTERVAL_MS);
+
+/// When this worker last collected connection stats into
+/// `VTS_MANAGER`; `None` until the first scrape.
+static LAST_CONNECTION_REFRESH: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Set the minimum interval between connection collections.  Called
+/// from the `vts_connection_refresh_interval` directive; `0` restores
+/// [`DEFAULT_CONNECTION_REFRESH_INTERVAL_MS`] (done by the
+/// preconfiguration hook, as for `vts_max_request_time`).
+#[no_mangle]
+pub extern "C" fn vts_set_connection_refresh_interval_ms(ms: u64) {
+    let ms = if ms == 0 {
+        DEFAULT_CONNECTION_REFRESH_INTERVAL_MS
+    } else {
+        ms
+    };
+    CONNECTION_REFRESH_INTERVAL_MS.store(ms, Ordering::Relaxed);
+    *LAST_CONNECTION_REFRESH
+        .lock()
+        .unwrap_or_else(recover_poisoned) = None;
+}
+
+/// Whether the cached connection snapshot is older than the refresh
+/// interval.  Claims the refresh (stamps `now`) when it is, so
+/// concurrent scrapes don't both walk the table.
+fn connection_refresh_due(now: Instant) -> bool {
+    let interval = Duration::from_millis(CONNECTION_REFRESH_INTERVAL_MS.load(Ordering::Relaxed));
+    let mut last = LAST_CONNECTION_REFRESH
+        .lock()
+        .unwrap_or_else(recover_poisoned);
+    if matches!(*last, Some(at) if now.saturating_duration_since(at) < interval) {
+        return false;
+    }
+    *last = Some(now);
+    true
+}
+
+/// Number of times a poisoned process-local lock was recovered.  A
+/// poisoned lock means some thread panicked mid-update; we keep serving
+/// the (possibly partially updated) data but count the event so it's
+/// visible in the `control=status` diagnostics.
+static LOCK_POISON_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Recover the guard from a poisoned lock, counting the event.
+pub(crate) fn recover_poisoned<G>(poisoned: std::sync::PoisonError<G>) -> G {
+    LOCK_POISON_COUNT.fetch_add(1, Ordering::Relaxed);
+    poisoned.into_inner()
+}
+
+/// Number of poisoned-lock recoveries since startup.
+pub fn lock_poison_count() -> u64 {
+    LOCK_POISON_COUNT.load(Ordering::Relaxed)
+}
+
+/// Requests whose end time was before their start time.
+static CLOCK_SKEW_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Number of request times clamped to 0 because the clock went
+/// backwards, since startup.
+pub fn clock_skew_count() -> u64 {
+    CLOCK_SKEW_COUNT.load(Ordering::Relaxed)
+}
+
 /// Global VTS statistics manager
 static VTS_MANAGER: std::sync::LazyLock<Arc<RwLock<VtsStatsManager>>> =
     std::sync::LazyLock::new(|| Arc::new(RwLock::new(VtsStatsManager::new())));
@@ -78,6 +623,24 @@ static VTS_MANAGER: std::sync::LazyLock<Arc<RwLock<VtsStatsManager>>> =
 static CACHE_MANAGER: std::sync::LazyLock<Arc<CacheStatsManager>> =
     std::sync::LazyLock::new(|| Arc::new(CacheStatsManager::new()));
 
+/// Zone label for the default server (`server_name _;`, or a server
+/// block without any `server_name`).
+pub const DEFAULT_SERVER_ZONE: &str = "default";
+
+/// Zone label for requests that never had a server block selected, so
+/// they still add up in the aggregate totals instead of being dropped.
+pub const UNKNOWN_SERVER_ZONE: &str = "__unknown__";
+
+/// Map the placeholder names nginx uses for the default server onto
+/// [`DEFAULT_SERVER_ZONE`] so it is identifiable and never rendered as
+/// an empty `zone=""` label.
+fn normalize_server_zone(server_name: &str) -> &str {
+    match server_name {
+        "" | "_" => DEFAULT_SERVER_ZONE,
+        name => name,
+    }
+}
+
 /// Update server zone statistics
 pub fn update_server_zone_stats(
     server_name: &str,
@@ -86,953 +649,4691 @@ pub fn update_server_zone_stats(
     bytes_out: u64,
     request_time: u64,
 ) {
+    let server_name = normalize_server_zone(server_name);
     let mut manager = match VTS_MANAGER.write() {
         Ok(guard) => guard,
-        Err(poisoned) => poisoned.into_inner(),
+        Err(poisoned) => recover_poisoned(poisoned),
     };
     manager.update_server_stats(server_name, status, bytes_in, bytes_out, request_time);
 }
 
-/// Update upstream statistics
-pub fn update_upstream_zone_stats(
-    upstream_name: &str,
-    upstream_addr: &str,
+/// Record `weight` requests of `server_name` rejected by `limit_req` /
+/// `limit_conn`, preferring the shared zone when configured.
+pub fn update_server_zone_rate_limited(
+    server_name: &str,
+    bytes_in: u64,
+    bytes_out: u64,
     request_time: u64,
-    upstream_response_time: u64,
-    bytes_sent: u64,
-    bytes_received: u64,
-    status_code: u16,
+    weight: u64,
 ) {
+    let server_name = normalize_server_zone(server_name);
+    if crate::shm::record_server_rate_limited(
+        server_name,
+        bytes_in,
+        bytes_out,
+        request_time,
+        weight,
+    ) {
+        return;
+    }
     let mut manager = match VTS_MANAGER.write() {
         Ok(guard) => guard,
-        Err(poisoned) => poisoned.into_inner(),
+        Err(poisoned) => recover_poisoned(poisoned),
     };
-    manager.update_upstream_stats(
-        upstream_name,
-        upstream_addr,
-        request_time,
-        upstream_response_time,
-        bytes_sent,
-        bytes_received,
-        status_code,
-    );
+    manager.update_server_rate_limited(server_name, bytes_in, bytes_out, request_time, weight);
 }
 
-/// Update connection statistics for testing
-pub fn update_connection_stats(
-    active: u64,
-    reading: u64,
-    writing: u64,
-    waiting: u64,
-    accepted: u64,
-    handled: u64,
-) {
+/// Record one subrequest (SSI, `auth_request`, …) of `server_name`,
+/// preferring the shared zone when configured.  Counted apart from
+/// requests so a page with subrequests is one request, not several.
+pub fn update_server_zone_subrequest(server_name: &str) {
+    let server_name = normalize_server_zone(server_name);
+    if !is_server_zone_enabled(server_name) {
+        return;
+    }
+    if crate::shm::record_server_subrequest(server_name) {
+        return;
+    }
     let mut manager = match VTS_MANAGER.write() {
         Ok(guard) => guard,
-        Err(poisoned) => poisoned.into_inner(),
+        Err(poisoned) => recover_poisoned(poisoned),
     };
-    manager.update_connection_stats(active, reading, writing, waiting, accepted, handled);
+    manager.update_server_subrequest(server_name);
 }
 
-/// External API for tracking upstream requests dynamically
-/// This function can be called from external systems or nginx modules
-/// to track real-time upstream statistics
+/// Add response bytes of a still-streaming request of `server_name`,
+/// preferring the shared zone when configured.  The request is counted
+/// (and these bytes left out) when it is logged.
+pub fn track_server_zone_bytes_out(server_name: &str, bytes_out: u64) {
+    let server_name = normalize_server_zone(server_name);
+    if bytes_out == 0 || !is_server_zone_enabled(server_name) {
+        return;
+    }
+    if crate::shm::record_server_bytes_out(server_name, bytes_out) {
+        return;
+    }
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.update_server_bytes_out(server_name, bytes_out);
+}
+
+/// Mark `header_bytes` of the response bytes just counted for a request
+/// of `server_name` as headers, preferring the shared zone when
+/// configured.
+fn record_server_header_bytes(server_name: &str, header_bytes: u64) {
+    if header_bytes == 0 || crate::shm::record_server_header_bytes(server_name, header_bytes) {
+        return;
+    }
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.update_server_header_bytes(server_name, header_bytes);
+}
+
+/// Move one in-flight request of `server_name` between connection
+/// phases, preferring the shared zone when configured.
+pub fn transition_server_connection(server_name: &str, from: ConnPhase, to: ConnPhase) {
+    let server_name = normalize_server_zone(server_name);
+    if crate::shm::record_server_connection(server_name, from, to) {
+        return;
+    }
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.transition_server_connection(server_name, from, to);
+}
+
+/// Per-zone connection hook called by the C side as a request enters
+/// the zone (`0 -> 1`), sends its response header (`1 -> 2`), switches
+/// protocols (`n -> 3`) and is freed (`n -> 0`).  Unknown phase values
+/// are ignored.
 ///
 /// # Safety
 ///
-/// This function is unsafe because it dereferences raw C string pointers.
-/// The caller must ensure that:
-/// - `upstream_name` and `server_addr` are valid, non-null C string pointers
-/// - The strings pointed to by these pointers live for the duration of the call
-/// - The strings are properly null-terminated
+/// The `server_name` pointer must be a valid null-terminated C string.
 #[no_mangle]
-pub unsafe extern "C" fn vts_track_upstream_request(
-    upstream_name: *const c_char,
-    server_addr: *const c_char,
-    start_sec: u64,
-    start_msec: u64,
-    upstream_response_time: u64,
-    bytes_sent: u64,
-    bytes_received: u64,
-    status_code: u16,
+pub unsafe extern "C" fn vts_server_connection_transition_ffi(
+    server_name: *const c_char,
+    from: u8,
+    to: u8,
 ) {
-    if upstream_name.is_null() || server_addr.is_null() {
+    if server_name.is_null() {
         return;
     }
+    let Ok(name) = std::ffi::CStr::from_ptr(server_name).to_str() else {
+        return;
+    };
+    let (Some(from), Some(to)) = (ConnPhase::from_raw(from), ConnPhase::from_raw(to)) else {
+        return;
+    };
+    transition_server_connection(name, from, to);
+}
 
-    let upstream_name_str = std::ffi::CStr::from_ptr(upstream_name)
-        .to_str()
-        .unwrap_or("unknown");
-    let server_addr_str = std::ffi::CStr::from_ptr(server_addr)
-        .to_str()
-        .unwrap_or("unknown:0");
-
-    // Calculate request time using nginx-module-vts compatible method
-    let request_time = calculate_request_time(start_sec, start_msec);
-
-    // Prefer the cross-worker shared table when `vts_zone` is configured;
-    // fall back to the process-local manager otherwise (also the path
-    // exercised by unit tests).
-    if crate::shm::record_upstream(
-        upstream_name_str,
-        server_addr_str,
-        request_time,
-        upstream_response_time,
-        bytes_sent,
-        bytes_received,
-        status_code,
-    ) {
+/// QUIC connection hook: a connection moves `from` one phase `to`
+/// another (`0` idle, `1` handshaking, `2` established), with
+/// `zero_rtt` non-zero when its 0-RTT early data was accepted.  Meant
+/// for nginx's QUIC event hooks; unknown phase values are ignored.
+#[no_mangle]
+pub extern "C" fn vts_track_quic_connection(from: u8, to: u8, zero_rtt: u8) {
+    let (Some(from), Some(to)) = (QuicPhase::from_raw(from), QuicPhase::from_raw(to)) else {
         return;
-    }
-
-    update_upstream_zone_stats(
-        upstream_name_str,
-        server_addr_str,
-        request_time,
-        upstream_response_time,
-        bytes_sent,
-        bytes_received,
-        status_code,
-    );
+    };
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.transition_quic_connection(from, to, zero_rtt != 0);
 }
 
-/// Update cache statistics for a specific zone
-///
-/// # Arguments
-///
-/// * `zone_name` - Cache zone name
-/// * `cache_status` - Cache status string (e.g., "HIT", "MISS", "BYPASS")
-pub fn update_cache_stats(zone_name: &str, cache_status: &str) {
-    CACHE_MANAGER.update_cache_stats(zone_name, cache_status);
+/// Pause or resume accounting for a server zone.  Accumulated counters
+/// are kept either way.
+pub fn set_server_zone_enabled(server_name: &str, enabled: bool) {
+    let server_name = normalize_server_zone(server_name);
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.set_zone_enabled(server_name, enabled);
 }
 
-/// Map nginx's `r->upstream->cache_status` integer to the string the
-/// process-local `CacheStatsManager` expects.  Mirrors
-/// `ngx_http_cache_status[]` in nginx's `ngx_http_cache.h`.
-fn cache_status_str(status: u8) -> Option<&'static str> {
-    match status {
-        1 => Some("MISS"),
-        2 => Some("BYPASS"),
-        3 => Some("EXPIRED"),
-        4 => Some("STALE"),
-        5 => Some("UPDATING"),
-        6 => Some("REVALIDATED"),
-        7 => Some("HIT"),
-        8 => Some("SCARCE"),
-        _ => None,
-    }
+/// Append a user-defined label to a server zone's series.  Fails,
+/// leaving the zone's labels unchanged, under the same rules as
+/// [`VtsStatsManager::set_zone_labels`].
+pub fn add_server_zone_label(server_name: &str, name: &str, value: &str) -> Result<(), VtsError> {
+    let server_name = normalize_server_zone(server_name);
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    let mut labels = manager
+        .get_zone_labels()
+        .get(server_name)
+        .cloned()
+        .unwrap_or_default();
+    labels.push((name.to_string(), value.to_string()));
+    manager.set_zone_labels(server_name, labels)
 }
 
-/// LOG_PHASE entry point invoked by the C wrapper for each request that
-/// touched a cache.  `cache_status` is the raw `ngx_uint_t` from
-/// `r->upstream->cache_status`; 0 (no cache) is filtered on the C side.
-/// `max_size` / `used_size` are the current file cache settings (in
-/// bytes) and are overwritten on every call.
+/// Directive-time check for `vts_zone_label name=value`: whether
+/// `name` is valid and a server block that already has `configured`
+/// labels may take another.  Returns NULL when it may, otherwise a
+/// static error message.  Duplicates are caught later, by
+/// [`vts_add_zone_label_ffi`].
 ///
 /// # Safety
 ///
-/// The `zone_name` pointer must be a valid null-terminated C string.
-/// The caller must ensure the pointer remains valid for the duration of
-/// this call.
+/// `name` must point to `len` readable bytes.
 #[no_mangle]
-pub unsafe extern "C" fn vts_update_cache_stats_ffi(
-    zone_name: *const c_char,
-    cache_status: u8,
-    max_size: u64,
-    used_size: u64,
-) {
-    if zone_name.is_null() {
-        return;
+pub unsafe extern "C" fn vts_check_zone_label_ffi(
+    name: *const u8,
+    len: usize,
+    configured: usize,
+) -> *const c_char {
+    if name.is_null() {
+        return VtsError::InvalidLabelName.as_ptr();
     }
-    let Some(status_str) = cache_status_str(cache_status) else {
-        return;
-    };
-    let zone_str = match std::ffi::CStr::from_ptr(zone_name).to_str() {
-        Ok(s) => s,
-        Err(_) => return,
-    };
-
-    // Same dispatch pattern as `vts_update_server_stats_ffi`: shared
-    // memory wins when configured, otherwise fall back to the
-    // process-local manager (the path exercised by unit tests).
-    if crate::shm::record_cache(zone_str, cache_status, max_size, used_size) {
-        return;
+    let name = String::from_utf8_lossy(std::slice::from_raw_parts(name, len));
+    if let Err(error) = vts_node::check_zone_label_name(&name) {
+        return error.as_ptr();
     }
-    CACHE_MANAGER.update_cache_stats(zone_str, status_str);
-    CACHE_MANAGER.update_cache_size(zone_str, max_size, used_size);
-}
-
-/// Update cache size information for a specific zone
-///
-/// # Arguments
-///
-/// * `zone_name` - Cache zone name
-/// * `max_size` - Maximum cache size in bytes
-/// * `used_size` - Currently used cache size in bytes
-pub fn update_cache_size(zone_name: &str, max_size: u64, used_size: u64) {
-    CACHE_MANAGER.update_cache_size(zone_name, max_size, used_size);
+    if configured >= vts_node::MAX_ZONE_LABELS {
+        return VtsError::TooManyLabels.as_ptr();
+    }
+    std::ptr::null()
 }
 
-/// Get all cache zone statistics
+/// Attach `name="value"` to every series of server zone `zone` (keyed
+/// like `vts_disable_zone`, by first `server_name`).  Called from the
+/// postconfiguration hook for each `vts_zone_label`.  Returns NULL on
+/// success, otherwise a static error message.
 ///
-/// # Returns
+/// # Safety
 ///
-/// HashMap containing all cache zone statistics
-pub fn get_all_cache_zones() -> std::collections::HashMap<String, crate::cache_stats::CacheZoneStats>
-{
-    CACHE_MANAGER.get_all_cache_zones()
-}
-
-/// Check if upstream statistics collection is enabled
+/// Each pointer must point to its length in readable bytes.
 #[no_mangle]
-pub extern "C" fn vts_is_upstream_stats_enabled() -> bool {
-    // For now, always return true if VTS_MANAGER is available
-    // In a full implementation, this would check configuration
-    VTS_MANAGER.read().is_ok()
+pub unsafe extern "C" fn vts_add_zone_label_ffi(
+    zone: *const u8,
+    zone_len: usize,
+    name: *const u8,
+    name_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> *const c_char {
+    if zone.is_null() || name.is_null() || value.is_null() {
+        return VtsError::InvalidLabelName.as_ptr();
+    }
+    let zone = String::from_utf8_lossy(std::slice::from_raw_parts(zone, zone_len));
+    let name = String::from_utf8_lossy(std::slice::from_raw_parts(name, name_len));
+    let value = String::from_utf8_lossy(std::slice::from_raw_parts(value, value_len));
+    error::result_ptr(add_server_zone_label(&zone, &name, &value))
 }
 
-/// Collect current nginx connection statistics.  Prefer the global
-/// `ngx_stat_*` atomics (populated by nginx when stub_status is
-/// compiled in); otherwise fall back to a cycle-table walk.
+/// Drop every zone label.  Called from the preconfiguration hook so a
+/// reload re-applies `vts_zone_label` from scratch.
 #[no_mangle]
-pub extern "C" fn vts_collect_nginx_connections() {
-    #[cfg(not(test))]
-    unsafe {
-        use ngx::ffi::*;
-
-        // Preferred path: read the global atomics nginx itself uses to
-        // power stub_status (available only when nginx was built with
-        // `--with-http_stub_status_module`).  Pure-Rust dlsym lookup
-        // in `crate::connection_stats` returns None when the symbols
-        // aren't present, which gracefully falls back to the
-        // cycle-table walk below.
-        let (mut active, mut reading, mut writing, mut waiting, mut accepted, mut handled) =
-            if let Some(s) = crate::connection_stats::read() {
-                (
-                    s.active, s.reading, s.writing, s.waiting, s.accepted, s.handled,
-                )
-            } else {
-                let cycle = ngx_cycle;
-                if cycle.is_null() {
-                    return;
-                }
-                let connection_n = (*cycle).connection_n;
-                let connections = (*cycle).connections;
-                if connections.is_null() {
-                    return;
-                }
-                let mut active = 0u64;
-                for i in 0..connection_n {
-                    let conn = connections.add(i);
-                    if !conn.is_null() && (*conn).fd != -1 {
-                        active += 1;
-                    }
-                }
-                // No real per-state signal without the atomics; leave
-                // them at zero rather than fabricate values.  Treat
-                // accepted/handled as a coarse proxy for active.
-                (active, 0u64, 0u64, 0u64, active, active)
-            };
-        // Silence "value assigned but never read" if rustc gets clever
-        // about the inferred bindings above.
-        let _ = (&mut active, &mut reading, &mut writing, &mut waiting);
-        let _ = (&mut accepted, &mut handled);
-
-        // Update VTS connection statistics
-        {
-            let mut manager = match VTS_MANAGER.write() {
-                Ok(guard) => guard,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            manager.update_connection_stats(active, reading, writing, waiting, accepted, handled);
-        }
-    }
-
-    #[cfg(test)]
-    {
-        // For testing, use mock data
-        let mut manager = match VTS_MANAGER.write() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
-        };
-        manager.update_connection_stats(1, 0, 1, 0, 16, 16);
-    }
+pub extern "C" fn vts_clear_zone_labels() {
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.zone_labels.clear();
 }
 
-/// Update server zone statistics from nginx request processing
-/// This should be called from nginx log phase for each request
+/// Name the pool of upstream group `upstream` for `vts_upstream_zone`;
+/// the name becomes the `zone` label of the group's server series.
+/// Returns NULL on success, otherwise a static error message
+/// ([`VtsError::Duplicate`] if the group already has a name).
 ///
 /// # Safety
 ///
-/// The `server_name` pointer must be a valid null-terminated C string.
-/// The caller must ensure the pointer remains valid for the duration of this call.
+/// Each pointer must point to its length in readable bytes.
 #[no_mangle]
-pub unsafe extern "C" fn vts_update_server_stats_ffi(
-    server_name: *const c_char,
-    status: u16,
-    bytes_in: u64,
-    bytes_out: u64,
-    request_time: u64,
-) {
-    if server_name.is_null() {
-        return;
+pub unsafe extern "C" fn vts_set_upstream_zone_ffi(
+    upstream: *const u8,
+    upstream_len: usize,
+    zone: *const u8,
+    zone_len: usize,
+) -> *const c_char {
+    if upstream.is_null() || zone.is_null() {
+        return VtsError::Duplicate.as_ptr();
     }
-
-    let server_name_str = match std::ffi::CStr::from_ptr(server_name).to_str() {
-        Ok(s) => s,
-        Err(_) => return,
+    let upstream = String::from_utf8_lossy(std::slice::from_raw_parts(upstream, upstream_len));
+    let zone = String::from_utf8_lossy(std::slice::from_raw_parts(zone, zone_len));
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
     };
-
-    // Same dispatch as `vts_track_upstream_request`: shared memory wins
-    // when configured, otherwise the process-local manager is used.
-    if crate::shm::record_server(server_name_str, status, bytes_in, bytes_out, request_time) {
-        return;
-    }
-
-    update_server_zone_stats(server_name_str, status, bytes_in, bytes_out, request_time);
+    error::result_ptr(
+        manager
+            .set_upstream_zone_name(&upstream, &zone)
+            .then_some(())
+            .ok_or(VtsError::Duplicate),
+    )
 }
 
-/// Update VTS statistics from nginx (to be called periodically)
-/// This should be called from nginx worker process periodically to collect
-/// all types of statistics including connections, server zones, and upstream data
+/// Drop every upstream pool name.  Called from the preconfiguration
+/// hook so a reload re-applies `vts_upstream_zone` from scratch.
 #[no_mangle]
-pub extern "C" fn vts_update_statistics() {
-    // Collect nginx connection statistics
-    vts_collect_nginx_connections();
-
-    // Note: Server zone statistics are updated automatically when requests are processed
-    // via vts_update_server_stats_ffi() calls from nginx request processing
-
-    // Note: Upstream statistics are updated automatically when upstream requests complete
-    // via vts_update_upstream_stats_ffi() calls from nginx upstream processing
-
-    // Future: Could add periodic collection of other nginx internal statistics here
+pub extern "C" fn vts_clear_upstream_zone_names() {
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.upstream_zone_names.clear();
 }
 
-/// Get VTS status content for C integration
-/// Returns a pointer to a freshly generated status content string
+/// Report server zone `from` as `to` on the status page
+/// (`vts_zone_alias`); zones sharing an alias are summed.  Returns NULL
+/// on success, otherwise a static error message ([`VtsError::Duplicate`]
+/// if `from` already has an alias).
 ///
 /// # Safety
 ///
-/// The returned pointer is valid until the next call to this function.
-/// The caller must not free the returned pointer.
+/// Each pointer must point to its length in readable bytes.
 #[no_mangle]
-pub unsafe extern "C" fn ngx_http_vts_get_status() -> *const c_char {
-    use std::sync::Mutex;
+pub unsafe extern "C" fn vts_add_zone_alias_ffi(
+    from: *const u8,
+    from_len: usize,
+    to: *const u8,
+    to_len: usize,
+) -> *const c_char {
+    if from.is_null() || to.is_null() {
+        return VtsError::Duplicate.as_ptr();
+    }
+    let from = String::from_utf8_lossy(std::slice::from_raw_parts(from, from_len));
+    let to = String::from_utf8_lossy(std::slice::from_raw_parts(to, to_len));
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    error::result_ptr(
+        manager
+            .add_zone_alias(&from, &to)
+            .then_some(())
+            .ok_or(VtsError::Duplicate),
+    )
+}
 
-    static STATUS_CACHE: Mutex<Option<std::ffi::CString>> = Mutex::new(None);
+/// Drop every zone alias.  Called from the preconfiguration hook so a
+/// reload re-applies `vts_zone_alias` from scratch.
+#[no_mangle]
+pub extern "C" fn vts_clear_zone_aliases() {
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.zone_aliases.clear();
+}
 
-    // Update cache with fresh content
-    if let Ok(mut cache) = STATUS_CACHE.lock() {
-        let status_content = generate_vts_status_content();
-        let c_string = std::ffi::CString::new(status_content)
-            .unwrap_or_else(|_| std::ffi::CString::new("Failed to generate VTS status").unwrap());
-        *cache = Some(c_string);
-        cache.as_ref().unwrap().as_ptr()
-    } else {
-        // Fallback if mutex is poisoned
-        static FALLBACK: &[u8] = b"VTS Status: Error\0";
-        FALLBACK.as_ptr() as *const c_char
-    }
+/// Whether a server zone is currently being accounted.  Consulted on
+/// both the shared-memory and process-local paths.
+fn is_server_zone_enabled(server_name: &str) -> bool {
+    let manager = match VTS_MANAGER.read() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.is_zone_enabled(server_name)
 }
 
-/// External initialization function for nginx module integration
-/// This function is called from the C wrapper during module initialization
+/// Config-time entry point for `vts_disable_zone <name>`.  `enabled`
+/// is `0` to pause accounting and non-zero to resume it.
 ///
 /// # Safety
 ///
-/// This function is safe to call from C code as it handles the null pointer case
-/// and doesn't dereference the configuration pointer directly.
+/// `name` must point to `len` readable bytes for the duration of the
+/// call.
 #[no_mangle]
-pub unsafe extern "C" fn ngx_http_vts_init_rust_module(_cf: *mut ngx_conf_t) -> ngx_int_t {
-    // Initialize upstream zones
-    if initialize_upstream_zones_from_config(_cf).is_err() {
-        return NGX_ERROR as ngx_int_t;
+pub unsafe extern "C" fn vts_set_zone_enabled_ffi(name: *const u8, len: usize, enabled: u8) {
+    if name.is_null() {
+        return;
+    }
+    let bytes = std::slice::from_raw_parts(name, len);
+    if let Ok(zone) = std::str::from_utf8(bytes) {
+        set_server_zone_enabled(zone, enabled != 0);
     }
-
-    NGX_OK as ngx_int_t
 }
 
-#[cfg(test)]
+/// Resume accounting for every paused zone.  Called from the
+/// preconfiguration hook so a reload re-applies `vts_disable_zone`
+/// from scratch.
+#[no_mangle]
+pub extern "C" fn vts_clear_disabled_zones() {
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.disabled_zones.clear();
+}
+
+/// Update upstream statistics
+pub fn update_upstream_zone_stats(
+    upstream_name: &str,
+    upstream_addr: &str,
+    request_time: u64,
+    upstream_response_time: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    status_code: u16,
+) {
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.update_upstream_stats(
+        upstream_name,
+        upstream_addr,
+        request_time,
+        upstream_response_time,
+        bytes_sent,
+        bytes_received,
+        status_code,
+    );
+}
+
+/// Add one request's local time to the server of its final upstream
+/// attempt in the process-local manager.
+pub fn update_upstream_zone_local_time(
+    upstream_name: &str,
+    upstream_addr: &str,
+    request_time: u64,
+    upstream_response_time: u64,
+) {
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.update_upstream_local_time(
+        upstream_name,
+        upstream_addr,
+        request_time,
+        upstream_response_time,
+    );
+}
+
+/// Update connection statistics for testing
+pub fn update_connection_stats(
+    active: u64,
+    reading: u64,
+    writing: u64,
+    waiting: u64,
+    accepted: u64,
+    handled: u64,
+) {
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.update_connection_stats(active, reading, writing, waiting, accepted, handled);
+}
+
+/// `vts_upstream_key name`: key upstream servers by their configured
+/// name instead of the address they resolved to.
+static UPSTREAM_KEY_BY_NAME: AtomicBool = AtomicBool::new(false);
+
+/// Set from the `vts_upstream_key` directive; `0` (`addr`, the default)
+/// also on each configuration load.
+#[no_mangle]
+pub extern "C" fn vts_set_upstream_key_by_name(by_name: u8) {
+    UPSTREAM_KEY_BY_NAME.store(by_name != 0, Ordering::Relaxed);
+}
+
+/// Whether upstream servers are keyed by configured name.  The LOG_PHASE
+/// handler only looks the name up when they are.
+#[no_mangle]
+pub extern "C" fn vts_upstream_key_by_name() -> u8 {
+    UPSTREAM_KEY_BY_NAME.load(Ordering::Relaxed) as u8
+}
+
+/// The `server` label of one upstream attempt: the configured name
+/// under `vts_upstream_key name`, so a `resolve` server whose address
+/// changes stays one series, otherwise (or when the name is unknown)
+/// the address.
+fn upstream_server_key<'a>(server_addr: &'a str, server_name: &'a str) -> &'a str {
+    if server_name.is_empty() || !UPSTREAM_KEY_BY_NAME.load(Ordering::Relaxed) {
+        server_addr
+    } else {
+        server_name
+    }
+}
+
+/// External API for tracking upstream requests dynamically
+/// This function can be called from external systems or nginx modules
+/// to track real-time upstream statistics
+///
+/// `server_name` is the peer's configured `server` name (e.g.
+/// `backend.example.com:8080`), or null when unknown; see
+/// [`upstream_server_key`].
+///
+/// Returns the request time in milliseconds when the attempt is over
+/// `vts_slow_log_threshold` and its server is due a slow-log line, so
+/// the caller can write one; otherwise 0.
+///
+/// # Safety
+///
+/// This function is unsafe because it dereferences raw C string pointers.
+/// The caller must ensure that:
+/// - `upstream_name` and `server_addr` are valid, non-null C string pointers
+/// - `server_name` is null or a valid C string pointer
+/// - The strings pointed to by these pointers live for the duration of the call
+/// - The strings are properly null-terminated
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn vts_track_upstream_request(
+    upstream_name: *const c_char,
+    server_addr: *const c_char,
+    server_name: *const c_char,
+    start_sec: u64,
+    start_msec: u64,
+    upstream_response_time: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    status_code: u16,
+) -> u64 {
+    if upstream_name.is_null() || server_addr.is_null() {
+        return 0;
+    }
+
+    let upstream_name_str = std::ffi::CStr::from_ptr(upstream_name)
+        .to_str()
+        .unwrap_or("unknown");
+    let server_addr_str = std::ffi::CStr::from_ptr(server_addr)
+        .to_str()
+        .unwrap_or("unknown:0");
+    let server_name_str = if server_name.is_null() {
+        ""
+    } else {
+        std::ffi::CStr::from_ptr(server_name).to_str().unwrap_or("")
+    };
+    let server_addr_str = upstream_server_key(server_addr_str, server_name_str);
+
+    // Calculate request time using nginx-module-vts compatible method
+    let request_time = calculate_request_time(start_sec, start_msec);
+    if !is_plausible_time_ms(request_time) || !is_plausible_time_ms(upstream_response_time) {
+        record_discarded_observation();
+        return 0;
+    }
+    let slow = slow_log::check(upstream_name_str, server_addr_str, request_time);
+
+    // Prefer the cross-worker shared table when `vts_zone` is configured;
+    // fall back to the process-local manager otherwise (also the path
+    // exercised by unit tests).
+    if !crate::shm::record_upstream(
+        upstream_name_str,
+        server_addr_str,
+        request_time,
+        upstream_response_time,
+        bytes_sent,
+        bytes_received,
+        status_code,
+    ) {
+        update_upstream_zone_stats(
+            upstream_name_str,
+            server_addr_str,
+            request_time,
+            upstream_response_time,
+            bytes_sent,
+            bytes_received,
+            status_code,
+        );
+    }
+
+    if slow {
+        request_time
+    } else {
+        0
+    }
+}
+
+/// Record a request's local time (`nginx_vts_request_local_seconds`):
+/// its request time minus `upstream_response_time`, the response times
+/// of all its attempts summed by the caller.  Called once per request,
+/// after [`vts_track_upstream_request`] has recorded every attempt,
+/// with the final attempt's server.
+///
+/// # Safety
+///
+/// Same pointer requirements as [`vts_track_upstream_request`].
+#[no_mangle]
+pub unsafe extern "C" fn vts_track_upstream_local_time(
+    upstream_name: *const c_char,
+    server_addr: *const c_char,
+    server_name: *const c_char,
+    start_sec: u64,
+    start_msec: u64,
+    upstream_response_time: u64,
+) {
+    if upstream_name.is_null() || server_addr.is_null() {
+        return;
+    }
+
+    let upstream_name_str = std::ffi::CStr::from_ptr(upstream_name)
+        .to_str()
+        .unwrap_or("unknown");
+    let server_addr_str = std::ffi::CStr::from_ptr(server_addr)
+        .to_str()
+        .unwrap_or("unknown:0");
+    let server_name_str = if server_name.is_null() {
+        ""
+    } else {
+        std::ffi::CStr::from_ptr(server_name).to_str().unwrap_or("")
+    };
+    let server_addr_str = upstream_server_key(server_addr_str, server_name_str);
+
+    // An implausible time was already counted as discarded by the
+    // attempt that carried it.
+    let request_time = calculate_request_time(start_sec, start_msec);
+    if !is_plausible_time_ms(request_time) || !is_plausible_time_ms(upstream_response_time) {
+        return;
+    }
+
+    if !crate::shm::record_upstream_local_time(
+        upstream_name_str,
+        server_addr_str,
+        request_time,
+        upstream_response_time,
+    ) {
+        update_upstream_zone_local_time(
+            upstream_name_str,
+            server_addr_str,
+            request_time,
+            upstream_response_time,
+        );
+    }
+}
+
+/// Record a request joining (`waited`) or leaving an upstream's
+/// connection-slot queue, in shared memory when `vts_zone` is
+/// configured and in the process-local manager otherwise.
+pub fn track_upstream_queue(upstream_name: &str, waited: bool) {
+    if crate::shm::record_upstream_queue(upstream_name, waited) {
+        return;
+    }
+
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.update_upstream_queue(upstream_name, waited);
+}
+
+/// External API for upstream connection-slot queueing.  Stock nginx
+/// keeps no such queue, so glue that implements one (or reads it from
+/// a patched `ngx_http_upstream`) calls this with `waited = true` when
+/// a request starts waiting and `waited = false` when it stops.
+///
+/// # Safety
+///
+/// `upstream_name` must be a valid null-terminated C string (or null,
+/// which is ignored) that stays valid for the duration of the call.
+#[no_mangle]
+pub unsafe extern "C" fn vts_track_upstream_queue(upstream_name: *const c_char, waited: bool) {
+    if upstream_name.is_null() {
+        return;
+    }
+    let upstream_name_str = std::ffi::CStr::from_ptr(upstream_name)
+        .to_str()
+        .unwrap_or("unknown");
+    track_upstream_queue(upstream_name_str, waited);
+}
+
+/// Update cache statistics for a specific zone
+///
+/// # Arguments
+///
+/// * `zone_name` - Cache zone name
+/// * `cache_status` - Cache status string (e.g., "HIT", "MISS", "BYPASS")
+/// * `upstream` - Upstream whose response this was, when known; also
+///   counted under `(zone_name, upstream)`
+pub fn update_cache_stats(zone_name: &str, cache_status: &str, upstream: Option<&str>) {
+    CACHE_MANAGER.update_cache_stats(zone_name, cache_status);
+    if let Some(upstream) = upstream.filter(|u| !u.is_empty()) {
+        CACHE_MANAGER.update_cache_upstream_stats(zone_name, upstream, cache_status);
+    }
+}
+
+/// Add response bytes served from cache for a specific zone
+///
+/// # Arguments
+///
+/// * `zone_name` - Cache zone name
+/// * `bytes` - Bytes sent for one request answered from cache
+pub fn record_cache_hit_bytes(zone_name: &str, bytes: u64) {
+    CACHE_MANAGER.record_cache_hit_bytes(zone_name, bytes);
+}
+
+/// Record one request that waited on `proxy_cache_lock` in a cache
+/// zone, in shared memory when `vts_zone` is configured and in the
+/// process-local manager otherwise.
+pub fn record_cache_lock_wait(zone_name: &str, timed_out: bool) {
+    if crate::shm::record_cache_lock(zone_name, timed_out) {
+        return;
+    }
+    CACHE_MANAGER.record_cache_lock_wait(zone_name, timed_out);
+}
+
+/// LOG_PHASE entry point for a request that waited on the cache lock
+/// (`r->cache->wait_time` set).  `timed_out` is non-zero when the wait
+/// ended at `proxy_cache_lock_timeout`.
+///
+/// # Safety
+///
+/// The `zone_name` pointer must be a valid null-terminated C string.
+/// The caller must ensure the pointer remains valid for the duration of
+/// this call.
+#[no_mangle]
+pub unsafe extern "C" fn vts_track_cache_lock_ffi(zone_name: *const c_char, timed_out: u8) {
+    if zone_name.is_null() {
+        return;
+    }
+    let Ok(zone_str) = std::ffi::CStr::from_ptr(zone_name).to_str() else {
+        return;
+    };
+    record_cache_lock_wait(zone_str, timed_out != 0);
+}
+
+/// Map nginx's `r->upstream->cache_status` integer to the string the
+/// process-local `CacheStatsManager` expects.  Mirrors
+/// `ngx_http_cache_status[]` in nginx's `ngx_http_cache.h`.
+fn cache_status_str(status: u8) -> Option<&'static str> {
+    match status {
+        1 => Some("MISS"),
+        2 => Some("BYPASS"),
+        3 => Some("EXPIRED"),
+        4 => Some("STALE"),
+        5 => Some("UPDATING"),
+        6 => Some("REVALIDATED"),
+        7 => Some("HIT"),
+        8 => Some("SCARCE"),
+        _ => None,
+    }
+}
+
+/// LOG_PHASE entry point invoked by the C wrapper for each request that
+/// touched a cache.  `cache_status` is the raw `ngx_uint_t` from
+/// `r->upstream->cache_status`; 0 (no cache) is filtered on the C side.
+/// `bytes_sent` is what the request sent to the client and counts
+/// toward `cache_bytes_served_total` when the body came from cache.
+/// `max_size` / `used_size` are the current file cache settings (in
+/// bytes) and are overwritten on every call.  `upstream_name` is the
+/// request's upstream block, or null or empty when unknown; when given,
+/// the status is also counted per `(zone, upstream)`.
+///
+/// # Safety
+///
+/// `zone_name` must be a valid null-terminated C string, and
+/// `upstream_name` null or one.  The caller must ensure the pointers
+/// remain valid for the duration of this call.
+#[no_mangle]
+pub unsafe extern "C" fn vts_update_cache_stats_ffi(
+    zone_name: *const c_char,
+    upstream_name: *const c_char,
+    cache_status: u8,
+    bytes_sent: u64,
+    max_size: u64,
+    used_size: u64,
+) {
+    if zone_name.is_null() {
+        return;
+    }
+    let Some(status_str) = cache_status_str(cache_status) else {
+        return;
+    };
+    let zone_str = match std::ffi::CStr::from_ptr(zone_name).to_str() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let upstream = if upstream_name.is_null() {
+        None
+    } else {
+        std::ffi::CStr::from_ptr(upstream_name)
+            .to_str()
+            .ok()
+            .filter(|u| !u.is_empty())
+    };
+
+    // Same dispatch pattern as `vts_update_server_stats_ffi`: shared
+    // memory wins when configured, otherwise fall back to the
+    // process-local manager (the path exercised by unit tests).
+    if crate::shm::record_cache(zone_str, cache_status, bytes_sent, max_size, used_size) {
+        if let Some(upstream) = upstream {
+            crate::shm::record_cache_upstream(zone_str, upstream, cache_status);
+        }
+        return;
+    }
+    update_cache_stats(zone_str, status_str, upstream);
+    if crate::shm::is_served_from_cache(cache_status) {
+        CACHE_MANAGER.record_cache_hit_bytes(zone_str, bytes_sent);
+    }
+    CACHE_MANAGER.update_cache_size(zone_str, max_size, used_size);
+}
+
+/// Update cache size information for a specific zone
+///
+/// # Arguments
+///
+/// * `zone_name` - Cache zone name
+/// * `max_size` - Maximum cache size in bytes
+/// * `used_size` - Currently used cache size in bytes
+pub fn update_cache_size(zone_name: &str, max_size: u64, used_size: u64) {
+    CACHE_MANAGER.update_cache_size(zone_name, max_size, used_size);
+}
+
+/// Get all cache zone statistics
+///
+/// # Returns
+///
+/// HashMap containing all cache zone statistics
+pub fn get_all_cache_zones() -> std::collections::HashMap<String, crate::cache_stats::CacheZoneStats>
+{
+    CACHE_MANAGER.get_all_cache_zones()
+}
+
+/// Visit every process-local cache zone without cloning the table.
+pub fn for_each_cache_zone(f: impl FnMut(&str, &crate::cache_stats::CacheZoneStats)) {
+    CACHE_MANAGER.for_each_cache_zone(f);
+}
+
+/// Check if upstream statistics collection is enabled
+#[no_mangle]
+pub extern "C" fn vts_is_upstream_stats_enabled() -> bool {
+    // For now, always return true if VTS_MANAGER is available
+    // In a full implementation, this would check configuration
+    VTS_MANAGER.read().is_ok()
+}
+
+/// Collect current nginx connection statistics.  Prefer the global
+/// `ngx_stat_*` atomics (populated by nginx when stub_status is
+/// compiled in); otherwise fall back to a cycle-table walk.
+///
+/// The walk is O(`worker_connections`), so within the
+/// `vts_connection_refresh_interval` of the last collection the
+/// snapshot already in `VTS_MANAGER` is reused.
+#[no_mangle]
+pub extern "C" fn vts_collect_nginx_connections() {
+    if !connection_refresh_due(Instant::now()) {
+        return;
+    }
+
+    #[cfg(not(test))]
+    unsafe {
+        use ngx::ffi::*;
+
+        // Preferred path: read the global atomics nginx itself uses to
+        // power stub_status (available only when nginx was built with
+        // `--with-http_stub_status_module`).  Pure-Rust dlsym lookup
+        // in `crate::connection_stats` returns None when the symbols
+        // aren't present, which gracefully falls back to the
+        // cycle-table walk below.
+        let (mut active, mut reading, mut writing, mut waiting, mut accepted, mut handled) =
+            if let Some(s) = crate::connection_stats::read() {
+                (
+                    s.active, s.reading, s.writing, s.waiting, s.accepted, s.handled,
+                )
+            } else {
+                let cycle = ngx_cycle;
+                if cycle.is_null() {
+                    return;
+                }
+                let connection_n = (*cycle).connection_n;
+                let connections = (*cycle).connections;
+                if connections.is_null() {
+                    return;
+                }
+                let mut active = 0u64;
+                for i in 0..connection_n {
+                    let conn = connections.add(i);
+                    if !conn.is_null() && (*conn).fd != -1 {
+                        active += 1;
+                    }
+                }
+                // No real per-state signal without the atomics; leave
+                // them at zero rather than fabricate values.  Treat
+                // accepted/handled as a coarse proxy for active.
+                (active, 0u64, 0u64, 0u64, active, active)
+            };
+        // Silence "value assigned but never read" if rustc gets clever
+        // about the inferred bindings above.
+        let _ = (&mut active, &mut reading, &mut writing, &mut waiting);
+        let _ = (&mut accepted, &mut handled);
+
+        // Update VTS connection statistics
+        {
+            let mut manager = match VTS_MANAGER.write() {
+                Ok(guard) => guard,
+                Err(poisoned) => recover_poisoned(poisoned),
+            };
+            manager.update_connection_stats(active, reading, writing, waiting, accepted, handled);
+        }
+    }
+
+    #[cfg(test)]
+    {
+        // For testing, use mock data
+        CONNECTION_COLLECTIONS.fetch_add(1, Ordering::Relaxed);
+        let mut manager = match VTS_MANAGER.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => recover_poisoned(poisoned),
+        };
+        manager.update_connection_stats(1, 0, 1, 0, 16, 16);
+    }
+}
+
+/// Test-only count of collections that actually ran.
+#[cfg(test)]
+static CONNECTION_COLLECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Update server zone statistics from nginx request processing
+/// This should be called from nginx log phase for each request
+///
+/// # Safety
+///
+/// The `server_name` pointer must be null or a valid null-terminated C
+/// string.  The caller must ensure the pointer remains valid for the
+/// duration of this call.
+///
+/// A null `server_name` (no server block was selected) is counted under
+/// [`UNKNOWN_SERVER_ZONE`].  `rate_limited` is non-zero when `limit_req` / `limit_conn` rejected
+/// the request; it is then counted as rate-limited instead of under its
+/// status class.  `is_main` is zero for a subrequest (`r != r->main`),
+/// which only bumps `nginx_vts_server_subrequests_total`.
+/// `header_bytes_out` is the part of `bytes_out` that was response
+/// headers (`$bytes_sent - $body_bytes_sent`).
+#[no_mangle]
+pub unsafe extern "C" fn vts_update_server_stats_ffi(
+    server_name: *const c_char,
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    header_bytes_out: u64,
+    request_time: u64,
+    rate_limited: u8,
+    is_main: u8,
+) {
+    let server_name_str = if server_name.is_null() {
+        Cow::Borrowed(UNKNOWN_SERVER_ZONE)
+    } else {
+        server_zone_name_lossy(std::ffi::CStr::from_ptr(server_name).to_bytes())
+    };
+    if is_main == 0 {
+        update_server_zone_subrequest(&server_name_str);
+        return;
+    }
+    record_server_request(
+        &server_name_str,
+        status,
+        bytes_in,
+        bytes_out,
+        header_bytes_out,
+        0,
+        request_time,
+        rate_limited != 0,
+    );
+}
+
+/// LOG_PHASE entry point for the server-zone update: reads the zone,
+/// status, byte counts and elapsed time straight off the request.
+/// Subrequests are only counted as such; null pointers are ignored.
+/// A request without a server block goes to [`UNKNOWN_SERVER_ZONE`].
+/// A main request that went through an internal redirect is still one
+/// client request and is counted once; the C handler makes sure this
+/// runs only once per main request.
+///
+/// `bytes_streamed` is what the body filter already reported through
+/// [`vts_track_body_bytes`] for this request; it is not counted again.
+///
+/// # Safety
+///
+/// `r` must be null or point to the live request being logged.
+#[no_mangle]
+pub unsafe extern "C" fn vts_log_server_request(
+    r: *const ngx_http_request_t,
+    rate_limited: u8,
+    bytes_streamed: u64,
+) {
+    let Some(req) = RequestRef::from_ptr(r) else {
+        return;
+    };
+    let server_name = request_zone_name(&req, server_zone_name_lossy);
+    if !req.is_main() {
+        update_server_zone_subrequest(&server_name);
+        return;
+    }
+    record_server_request(
+        &server_name,
+        req.status(),
+        req.bytes_received(),
+        req.bytes_sent(),
+        req.header_bytes_sent(),
+        bytes_streamed,
+        req.request_time_ms(),
+        rate_limited != 0,
+    );
+}
+
+/// Body-filter entry point (`vts_stream_bytes on`): adds `sent` bytes
+/// that just went out for a still-streaming response to its server
+/// zone, so long-lived responses (SSE, large downloads) show progress
+/// before LOG_PHASE.  Subrequests and null pointers are ignored.
+///
+/// # Safety
+///
+/// `r` must be null or point to a live request.
+#[no_mangle]
+pub unsafe extern "C" fn vts_track_body_bytes(r: *const ngx_http_request_t, sent: u64) {
+    let Some(req) = RequestRef::from_ptr(r) else {
+        return;
+    };
+    if !req.is_main() {
+        return;
+    }
+    // Counted as non-UTF-8 once, at LOG_PHASE, not per body chunk.
+    let server_name = request_zone_name(&req, String::from_utf8_lossy);
+    track_server_zone_bytes_out(&server_name, sent);
+}
+
+/// Zone name of `req` before [`normalize_server_zone`]: the first
+/// `server_name` of its server block (decoded by `decode`), `"_"` for a
+/// block without one, or [`UNKNOWN_SERVER_ZONE`] when the request never
+/// named a host, so no server block was chosen for it.
+fn request_zone_name<'a>(
+    req: &RequestRef<'a>,
+    decode: impl FnOnce(&'a [u8]) -> Cow<'a, str>,
+) -> Cow<'a, str> {
+    if !req.has_host() {
+        return Cow::Borrowed(UNKNOWN_SERVER_ZONE);
+    }
+    match req.server_name() {
+        Some(name) => decode(name),
+        None => Cow::Borrowed("_"),
+    }
+}
+
+/// Shared tail of the server-stats FFI entry points: plausibility
+/// guard, zone pause check, then shm / process-local dispatch.
+///
+/// `bytes_streamed` of `bytes_out` were already added while the body
+/// was in flight and are left out here.
+#[allow(clippy::too_many_arguments)]
+fn record_server_request(
+    server_name: &str,
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    header_bytes_out: u64,
+    bytes_streamed: u64,
+    request_time: u64,
+    rate_limited: bool,
+) {
+    let server_name = normalize_server_zone(server_name);
+    let header_bytes_out = header_bytes_out.min(bytes_out);
+    let bytes_out = bytes_out.saturating_sub(bytes_streamed);
+    if !is_plausible_time_ms(request_time) {
+        record_discarded_observation();
+        return;
+    }
+
+    if !is_server_zone_enabled(server_name) {
+        return;
+    }
+
+    let weight = sample_weight(server_name);
+    if weight == 0 {
+        return;
+    }
+
+    if rate_limited {
+        update_server_zone_rate_limited(server_name, bytes_in, bytes_out, request_time, weight);
+    } else if !crate::shm::record_server(
+        server_name,
+        status,
+        bytes_in,
+        bytes_out,
+        request_time,
+        weight,
+    ) {
+        // Same dispatch as `vts_track_upstream_request`: shared memory
+        // wins when configured, otherwise the process-local manager.
+        VTS_MANAGER
+            .write()
+            .unwrap_or_else(recover_poisoned)
+            .update_server_stats_weighted(
+                server_name,
+                status,
+                bytes_in,
+                bytes_out,
+                request_time,
+                weight,
+            );
+    }
+    // After the bytes themselves, so headers never exceed `bytes_out`.
+    record_server_header_bytes(server_name, header_bytes_out.saturating_mul(weight));
+}
+
+/// Count one response in the method × status cross-tab of its server
+/// zone (`vts_detail_method_status on`), in shared memory when
+/// `vts_zone` is configured and in the process-local manager otherwise.
+pub fn record_method_status(server_name: &str, method: HttpMethod, status: u16) {
+    let server_name = normalize_server_zone(server_name);
+    if !is_server_zone_enabled(server_name) {
+        return;
+    }
+    if crate::shm::record_method_status(server_name, method, status) {
+        return;
+    }
+    VTS_MANAGER
+        .write()
+        .unwrap_or_else(recover_poisoned)
+        .update_method_status(server_name, method, status);
+}
+
+/// LOG_PHASE entry point for `vts_detail_method_status on`.  `method`
+/// is nginx's `r->method` bit and `status` the final response status.
+///
+/// # Safety
+///
+/// The `zone_name` pointer must be a valid null-terminated C string.
+/// The caller must ensure the pointer remains valid for the duration of
+/// this call.
+#[no_mangle]
+pub unsafe extern "C" fn vts_track_method_status_ffi(
+    zone_name: *const c_char,
+    method: u64,
+    status: u16,
+) {
+    if zone_name.is_null() {
+        return;
+    }
+    // Already counted by `vts_log_server_request` if not UTF-8.
+    let zone = String::from_utf8_lossy(std::ffi::CStr::from_ptr(zone_name).to_bytes());
+    record_method_status(&zone, HttpMethod::from_ngx(method), status);
+}
+
+/// Count one response in the Content-Type breakdown of its server zone
+/// (`vts_track_content_type on`), in shared memory when `vts_zone` is
+/// configured and in the process-local manager otherwise.
+pub fn record_content_type(server_name: &str, content_type: &[u8]) {
+    let server_name = normalize_server_zone(server_name);
+    if !is_server_zone_enabled(server_name) {
+        return;
+    }
+    if crate::shm::record_content_type(server_name, content_type) {
+        return;
+    }
+    VTS_MANAGER
+        .write()
+        .unwrap_or_else(recover_poisoned)
+        .update_content_type(server_name, content_type);
+}
+
+/// LOG_PHASE entry point for `vts_track_content_type on`.
+/// `content_type` is `r->headers_out.content_type` (may be NULL/empty).
+///
+/// # Safety
+///
+/// The `zone_name` pointer must be a valid null-terminated C string,
+/// and `content_type` valid for `len` bytes unless NULL.  The caller
+/// must ensure both remain valid for the duration of this call.
+#[no_mangle]
+pub unsafe extern "C" fn vts_track_content_type_ffi(
+    zone_name: *const c_char,
+    content_type: *const u8,
+    len: usize,
+) {
+    if zone_name.is_null() {
+        return;
+    }
+    let content_type = if content_type.is_null() {
+        &[][..]
+    } else {
+        std::slice::from_raw_parts(content_type, len)
+    };
+    let zone = String::from_utf8_lossy(std::ffi::CStr::from_ptr(zone_name).to_bytes());
+    record_content_type(&zone, content_type);
+}
+
+/// Count one response in the `grpc-status` breakdown of its server
+/// zone (`vts_track_grpc on`), in shared memory when `vts_zone` is
+/// configured and in the process-local manager otherwise.  Responses
+/// without a `grpc-status` are not gRPC and are ignored.
+pub fn record_grpc_status(server_name: &str, grpc_status: &[u8]) {
+    let server_name = normalize_server_zone(server_name);
+    if !is_server_zone_enabled(server_name) {
+        return;
+    }
+    if crate::shm::record_grpc_status(server_name, grpc_status) {
+        return;
+    }
+    VTS_MANAGER
+        .write()
+        .unwrap_or_else(recover_poisoned)
+        .update_grpc_status(server_name, grpc_status);
+}
+
+/// LOG_PHASE entry point for `vts_track_grpc on`.  `grpc_status` is the
+/// response's `grpc-status` (the upstream trailer, or the header of a
+/// trailers-only response), NULL/empty when there is none.
+///
+/// # Safety
+///
+/// The `zone_name` pointer must be a valid null-terminated C string,
+/// and `grpc_status` valid for `len` bytes unless NULL.  The caller
+/// must ensure both remain valid for the duration of this call.
+#[no_mangle]
+pub unsafe extern "C" fn vts_track_grpc_status_ffi(
+    zone_name: *const c_char,
+    grpc_status: *const u8,
+    len: usize,
+) {
+    if zone_name.is_null() || grpc_status.is_null() {
+        return;
+    }
+    let grpc_status = std::slice::from_raw_parts(grpc_status, len);
+    let zone = String::from_utf8_lossy(std::ffi::CStr::from_ptr(zone_name).to_bytes());
+    record_grpc_status(&zone, grpc_status);
+}
+
+/// Add a client address to the distinct-client sketch of its server
+/// zone (`vts_track_unique_clients on`), in shared memory when
+/// `vts_zone` is configured and in the process-local manager otherwise.
+#[cfg(feature = "unique-clients")]
+pub fn record_unique_client(server_name: &str, addr: &[u8]) {
+    let server_name = normalize_server_zone(server_name);
+    if !is_server_zone_enabled(server_name) {
+        return;
+    }
+    if crate::shm::record_unique_client(server_name, addr) {
+        return;
+    }
+    VTS_MANAGER
+        .write()
+        .unwrap_or_else(recover_poisoned)
+        .update_unique_client(server_name, addr);
+}
+
+/// Whether this build can serve `vts_track_unique_clients on`: NULL
+/// when it can, otherwise the error for the directive to return.
+#[no_mangle]
+pub extern "C" fn vts_unique_clients_supported_ffi() -> *const c_char {
+    #[cfg(feature = "unique-clients")]
+    {
+        std::ptr::null()
+    }
+    #[cfg(not(feature = "unique-clients"))]
+    VtsError::UniqueClientsUnsupported.as_ptr()
+}
+
+/// LOG_PHASE entry point for `vts_track_unique_clients on`.  `addr` is
+/// the client address as text (`$remote_addr`).  A no-op without the
+/// `unique-clients` feature, where the directive is rejected anyway.
+///
+/// # Safety
+///
+/// The `zone_name` pointer must be a valid null-terminated C string,
+/// and `addr` valid for `len` bytes unless NULL.  The caller must
+/// ensure both remain valid for the duration of this call.
+#[no_mangle]
+pub unsafe extern "C" fn vts_track_unique_client_ffi(
+    zone_name: *const c_char,
+    addr: *const u8,
+    len: usize,
+) {
+    if zone_name.is_null() || addr.is_null() {
+        return;
+    }
+    #[cfg(feature = "unique-clients")]
+    {
+        let addr = std::slice::from_raw_parts(addr, len);
+        let zone = String::from_utf8_lossy(std::ffi::CStr::from_ptr(zone_name).to_bytes());
+        record_unique_client(&zone, addr);
+    }
+    #[cfg(not(feature = "unique-clients"))]
+    let _ = len;
+}
+
+/// Count a request of `server_name` whose client body could not be
+/// read, by its final status (408 timeout, 400 malformed), in shared
+/// memory when `vts_zone` is configured and in the process-local
+/// manager otherwise.
+pub fn record_client_body_error(server_name: &str, status: u16) {
+    let server_name = normalize_server_zone(server_name);
+    if !is_server_zone_enabled(server_name) {
+        return;
+    }
+    if crate::shm::record_server_client_body_error(server_name, status) {
+        return;
+    }
+    VTS_MANAGER
+        .write()
+        .unwrap_or_else(recover_poisoned)
+        .update_server_client_body_error(server_name, status);
+}
+
+/// LOG_PHASE entry point for requests that ended while their body was
+/// being read, with `status` 408 or 400.  The request itself is
+/// counted by `vts_log_server_request`.
+///
+/// # Safety
+///
+/// The `zone_name` pointer must be a valid null-terminated C string.
+/// The caller must ensure the pointer remains valid for the duration of
+/// this call.
+#[no_mangle]
+pub unsafe extern "C" fn vts_track_client_body_error_ffi(zone_name: *const c_char, status: u16) {
+    if zone_name.is_null() {
+        return;
+    }
+    let zone = String::from_utf8_lossy(std::ffi::CStr::from_ptr(zone_name).to_bytes());
+    record_client_body_error(&zone, status);
+}
+
+/// Count the header count and size of one request in its server
+/// zone's request-header histograms, in shared memory when `vts_zone`
+/// is configured and in the process-local manager otherwise.
+pub fn record_request_headers(server_name: &str, count: u64, bytes: u64) {
+    let server_name = normalize_server_zone(server_name);
+    if !is_server_zone_enabled(server_name) {
+        return;
+    }
+    if crate::shm::record_request_headers(server_name, count, bytes) {
+        return;
+    }
+    VTS_MANAGER
+        .write()
+        .unwrap_or_else(recover_poisoned)
+        .update_request_headers(server_name, count, bytes);
+}
+
+/// LOG_PHASE entry point for the request-header histograms.  `count`
+/// is the number of request headers and `bytes` their size on the
+/// wire, request line and CRLFs included.
+///
+/// # Safety
+///
+/// The `zone_name` pointer must be a valid null-terminated C string.
+/// The caller must ensure the pointer remains valid for the duration of
+/// this call.
+#[no_mangle]
+pub unsafe extern "C" fn vts_track_request_headers_ffi(
+    zone_name: *const c_char,
+    count: u64,
+    bytes: u64,
+) {
+    if zone_name.is_null() {
+        return;
+    }
+    // Already counted by `vts_log_server_request` if not UTF-8.
+    let zone = String::from_utf8_lossy(std::ffi::CStr::from_ptr(zone_name).to_bytes());
+    record_request_headers(&zone, count, bytes);
+}
+
+/// Count one request in location zone `location` (`vts_location_zone`),
+/// in shared memory when `vts_zone` is configured and in the
+/// process-local manager otherwise.  Implausible times were already
+/// counted as discarded by the server-zone update and are dropped.
+pub fn record_location_request(
+    location: &str,
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    request_time: u64,
+) {
+    if location.is_empty() || !is_plausible_time_ms(request_time) {
+        return;
+    }
+    if crate::shm::record_location(location, status, bytes_in, bytes_out, request_time) {
+        return;
+    }
+    VTS_MANAGER
+        .write()
+        .unwrap_or_else(recover_poisoned)
+        .update_location_stats(location, status, bytes_in, bytes_out, request_time);
+}
+
+/// LOG_PHASE entry point for `vts_location_zone`: counts the main
+/// request `r` under the location zone `location[..len]`, reading the
+/// status, byte counts and elapsed time off the request.
+///
+/// # Safety
+///
+/// `r` must be null or point to the live request being logged, and
+/// `location` must be null or point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vts_log_location_request(
+    r: *const ngx_http_request_t,
+    location: *const u8,
+    len: usize,
+) {
+    let Some(req) = RequestRef::from_ptr(r) else {
+        return;
+    };
+    if location.is_null() || !req.is_main() {
+        return;
+    }
+    let location = String::from_utf8_lossy(std::slice::from_raw_parts(location, len));
+    record_location_request(
+        &location,
+        req.status(),
+        req.bytes_received(),
+        req.bytes_sent(),
+        req.request_time_ms(),
+    );
+}
+
+/// Count one request in filter group `group` under `key`, the value of
+/// the group's `vts_filter_by_variable` variable, in shared memory when
+/// `vts_zone` is configured and in the process-local manager otherwise.
+/// Empty keys (variable unset) and implausible times are dropped.
+pub fn record_filter_request(
+    group: &str,
+    key: &str,
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    request_time: u64,
+) {
+    if group.is_empty() || key.is_empty() || !is_plausible_time_ms(request_time) {
+        return;
+    }
+    if crate::shm::record_filter(group, key, status, bytes_in, bytes_out, request_time) {
+        return;
+    }
+    VTS_MANAGER
+        .write()
+        .unwrap_or_else(recover_poisoned)
+        .update_filter_stats(group, key, status, bytes_in, bytes_out, request_time);
+}
+
+/// LOG_PHASE entry point for `vts_filter_by_variable`: counts the main
+/// request `r` under filter group `group[..group_len]` with the
+/// variable's value `key[..key_len]`, reading the status, byte counts
+/// and elapsed time off the request.
+///
+/// # Safety
+///
+/// `r` must be null or point to the live request being logged, and
+/// `group` / `key` must each be null or point to that many readable
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vts_log_filter_request(
+    r: *const ngx_http_request_t,
+    group: *const u8,
+    group_len: usize,
+    key: *const u8,
+    key_len: usize,
+) {
+    let Some(req) = RequestRef::from_ptr(r) else {
+        return;
+    };
+    if group.is_null() || key.is_null() || !req.is_main() {
+        return;
+    }
+    let group = String::from_utf8_lossy(std::slice::from_raw_parts(group, group_len));
+    let key = String::from_utf8_lossy(std::slice::from_raw_parts(key, key_len));
+    record_filter_request(
+        &group,
+        &key,
+        req.status(),
+        req.bytes_received(),
+        req.bytes_sent(),
+        req.request_time_ms(),
+    );
+}
+
+/// Update VTS statistics from nginx (to be called periodically)
+/// This should be called from nginx worker process periodically to collect
+/// all types of statistics including connections, server zones, and upstream data
+#[no_mangle]
+pub extern "C" fn vts_update_statistics() {
+    // Collect nginx connection statistics
+    vts_collect_nginx_connections();
+
+    // Note: Server zone statistics are updated automatically when requests are processed
+    // via vts_update_server_stats_ffi() calls from nginx request processing
+
+    // Note: Upstream statistics are updated automatically when upstream requests complete
+    // via vts_update_upstream_stats_ffi() calls from nginx upstream processing
+
+    // Future: Could add periodic collection of other nginx internal statistics here
+}
+
+/// Get VTS status content for C integration
+/// Returns a pointer to a freshly generated status content string
+///
+/// # Safety
+///
+/// The returned pointer is valid until the next call to this function.
+/// The caller must not free the returned pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_http_vts_get_status() -> *const c_char {
+    use std::sync::Mutex;
+
+    static STATUS_PAGE: Mutex<String> = Mutex::new(String::new());
+
+    // Render straight into the buffer handed out last time and pass it
+    // on as-is: a page that still fits costs no allocation or copy.
+    if let Ok(mut page) = STATUS_PAGE.lock() {
+        crate::prometheus::write_vts_status_content(&mut page);
+        if page.contains('\0') {
+            page.clear();
+            page.push_str("Failed to generate VTS status");
+        }
+        page.push('\0');
+        page.as_ptr() as *const c_char
+    } else {
+        // Fallback if mutex is poisoned
+        static FALLBACK: &[u8] = b"VTS Status: Error\0";
+        FALLBACK.as_ptr() as *const c_char
+    }
+}
+
+/// Get the status page in the Prometheus protobuf exposition format
+/// (delimited `MetricFamily` messages), storing its length in `*len`.
+/// Returns NULL when the module was built without the `protobuf`
+/// feature, in which case the caller serves the text page.  Same
+/// pointer-lifetime contract as [`ngx_http_vts_get_status`].
+///
+/// # Safety
+///
+/// `len` must be a valid pointer to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_http_vts_get_status_protobuf(len: *mut usize) -> *const u8 {
+    #[cfg(feature = "protobuf")]
+    {
+        use std::sync::Mutex;
+
+        static PROTOBUF_CACHE: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+        if let Ok(mut cache) = PROTOBUF_CACHE.lock() {
+            *cache = protobuf::generate_vts_status_protobuf();
+            *len = cache.len();
+            return cache.as_ptr();
+        }
+    }
+    *len = 0;
+    std::ptr::null()
+}
+
+/// Whether `Accept` header value `accept` prefers the protobuf
+/// exposition to the text page (see [`protobuf::accepts_protobuf`]).
+/// Always 0 when the module was built without the `protobuf` feature.
+///
+/// # Safety
+///
+/// `accept` must point to `len` readable bytes (or be null with `len` 0).
+#[no_mangle]
+pub unsafe extern "C" fn ngx_http_vts_prefers_protobuf(accept: *const u8, len: usize) -> i32 {
+    #[cfg(feature = "protobuf")]
+    if !accept.is_null() && len > 0 {
+        let accept = String::from_utf8_lossy(std::slice::from_raw_parts(accept, len));
+        return protobuf::accepts_protobuf(&accept) as i32;
+    }
+    #[cfg(not(feature = "protobuf"))]
+    let _ = (accept, len);
+    0
+}
+
+/// Get the minimal status page (info and connection series only) that
+/// the status handler falls back to when it cannot allocate a buffer
+/// for the full page.  Same pointer-lifetime contract as
+/// [`ngx_http_vts_get_status`].
+///
+/// # Safety
+///
+/// The returned pointer is valid until the next call to this function.
+/// The caller must not free the returned pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_http_vts_get_minimal_status() -> *const c_char {
+    use std::sync::Mutex;
+
+    static MINIMAL_CACHE: Mutex<Option<std::ffi::CString>> = Mutex::new(None);
+
+    if let Ok(mut cache) = MINIMAL_CACHE.lock() {
+        let content = crate::prometheus::generate_minimal_status_content();
+        let c_string = std::ffi::CString::new(content)
+            .unwrap_or_else(|_| std::ffi::CString::new("VTS Status: Error").unwrap());
+        *cache = Some(c_string);
+        cache.as_ref().unwrap().as_ptr()
+    } else {
+        static FALLBACK: &[u8] = b"VTS Status: Error\0";
+        FALLBACK.as_ptr() as *const c_char
+    }
+}
+
+/// Get the `vts_status control=status` diagnostics report for C
+/// integration.  Same pointer-lifetime contract as
+/// [`ngx_http_vts_get_status`].
+///
+/// # Safety
+///
+/// The returned pointer is valid until the next call to this function.
+/// The caller must not free the returned pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_http_vts_get_diagnostics() -> *const c_char {
+    use std::sync::Mutex;
+
+    static DIAGNOSTICS_CACHE: Mutex<Option<std::ffi::CString>> = Mutex::new(None);
+
+    if let Ok(mut cache) = DIAGNOSTICS_CACHE.lock() {
+        let content = crate::diagnostics::generate_vts_diagnostics_content();
+        let c_string = std::ffi::CString::new(content).unwrap_or_else(|_| {
+            std::ffi::CString::new("Failed to generate VTS diagnostics").unwrap()
+        });
+        *cache = Some(c_string);
+        cache.as_ref().unwrap().as_ptr()
+    } else {
+        static FALLBACK: &[u8] = b"VTS Diagnostics: Error\0";
+        FALLBACK.as_ptr() as *const c_char
+    }
+}
+
+/// HTTP status for a `vts_health` probe: `200` while `manager` can be
+/// read, `503` once a panic mid-update has poisoned it.  Unlike the
+/// scrape path this does not recover the lock, so a poisoned module
+/// keeps failing its health check until the worker is replaced.
+fn health_status(manager: &RwLock<VtsStatsManager>) -> u16 {
+    match manager.read() {
+        Ok(_) => 200,
+        Err(_) => 503,
+    }
+}
+
+/// Whether the stats behind the status page are intact: neither
+/// `manager` nor the cache table has been poisoned by a panic
+/// mid-update.  Reported as `nginx_vts_module_up`; like
+/// [`health_status`] this reads the poison flag, which recovering the
+/// lock leaves set.
+fn module_up(manager: &RwLock<VtsStatsManager>) -> bool {
+    !manager.is_poisoned() && !CACHE_MANAGER.is_poisoned()
+}
+
+/// [`module_up`] for the process-local manager.
+pub fn vts_module_up() -> bool {
+    module_up(&VTS_MANAGER)
+}
+
+/// `vts_health` handler entry point: see [`health_status`].  Touches no
+/// counters and renders nothing, so probes stay cheap.
+#[no_mangle]
+pub extern "C" fn ngx_http_vts_health_status() -> u16 {
+    health_status(&VTS_MANAGER)
+}
+
+/// Run a `?control=...` command from the query string of a
+/// `vts_status` request (see [`control::handle_query`]).  Returns null
+/// when `args` carries no command, so the caller renders the normal
+/// page; otherwise the reply body, with its HTTP status stored in
+/// `*status`.  Same pointer-lifetime contract as
+/// [`ngx_http_vts_get_status`].
+///
+/// # Safety
+///
+/// `args` must point to `len` readable bytes (or be null with `len` 0)
+/// and `status` must be valid for writes.  The returned pointer is
+/// valid until the next call to this function.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_http_vts_control(
+    args: *const u8,
+    len: usize,
+    status: *mut u16,
+) -> *const c_char {
+    use std::sync::Mutex;
+
+    static CONTROL_CACHE: Mutex<Option<std::ffi::CString>> = Mutex::new(None);
+
+    if args.is_null() || len == 0 || status.is_null() {
+        return std::ptr::null();
+    }
+    let args = String::from_utf8_lossy(std::slice::from_raw_parts(args, len));
+    let Some(response) = control::handle_query(&args) else {
+        return std::ptr::null();
+    };
+
+    let mut cache = CONTROL_CACHE.lock().unwrap_or_else(recover_poisoned);
+    // Only the echoed argument could carry a NUL; drop it rather than
+    // the whole reply.
+    let body =
+        std::ffi::CString::new(response.body.replace('\0', "")).expect("NUL bytes were removed");
+    *status = response.status;
+    cache.insert(body).as_ptr()
+}
+
+thread_local! {
+    /// `vts_expose_zones` of the status location being served; empty
+    /// exposes every server zone.  The status handler sets it before
+    /// anything is rendered (page, control replies, protobuf) and clears
+    /// it before returning, so other locations and the `vts_unix_socket`
+    /// listener see every zone.
+    static EXPOSED_ZONES: std::cell::RefCell<Vec<String>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Whether server zone `zone` (as named on the page, after any
+/// `vts_zone_alias`) appears on the page being rendered.
+pub fn is_zone_exposed(zone: &str) -> bool {
+    EXPOSED_ZONES.with_borrow(|zones| zones.is_empty() || zones.iter().any(|z| z == zone))
+}
+
+/// `zones` less the server zones `vts_expose_zones` leaves off the page
+/// being rendered; borrowed when every zone is exposed.
+pub fn exposed_zones<V: Clone>(zones: &HashMap<String, V>) -> Cow<'_, HashMap<String, V>> {
+    if EXPOSED_ZONES.with_borrow(Vec::is_empty) {
+        return Cow::Borrowed(zones);
+    }
+    Cow::Owned(
+        zones
+            .iter()
+            .filter(|(zone, _)| is_zone_exposed(zone))
+            .map(|(zone, v)| (zone.clone(), v.clone()))
+            .collect(),
+    )
+}
+
+/// Add one `vts_expose_zones` name for the page about to be rendered.
+///
+/// # Safety
+///
+/// `name` must point to `len` readable bytes (or be null with `len` 0).
+#[no_mangle]
+pub unsafe extern "C" fn vts_expose_zone_ffi(name: *const u8, len: usize) {
+    if name.is_null() || len == 0 {
+        return;
+    }
+    let name = String::from_utf8_lossy(std::slice::from_raw_parts(name, len)).into_owned();
+    EXPOSED_ZONES.with_borrow_mut(|zones| zones.push(name));
+}
+
+/// Expose every server zone again.  Called by the status handler once
+/// the page is rendered.
+#[no_mangle]
+pub extern "C" fn vts_clear_exposed_zones() {
+    EXPOSED_ZONES.with_borrow_mut(Vec::clear);
+}
+
+/// Set the `vts_unix_socket` path the first worker binds in
+/// `init_process`; `len` 0 clears it (preconfiguration).  Returns NULL
+/// on success, otherwise a static error message.
+///
+/// # Safety
+///
+/// `path` must point to `len` readable bytes (or be null with `len` 0).
+#[no_mangle]
+pub unsafe extern "C" fn vts_set_unix_socket_path_ffi(
+    path: *const u8,
+    len: usize,
+) -> *const c_char {
+    if path.is_null() || len == 0 {
+        #[cfg(feature = "unix-socket")]
+        unix_socket::set_path(None);
+        return std::ptr::null();
+    }
+    #[cfg(feature = "unix-socket")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = std::ffi::OsStr::from_bytes(std::slice::from_raw_parts(path, len));
+        unix_socket::set_path(Some(path.into()));
+        std::ptr::null()
+    }
+    #[cfg(not(feature = "unix-socket"))]
+    VtsError::UnixSocketUnsupported.as_ptr()
+}
+
+/// Start serving the status page on the `vts_unix_socket` path, if one
+/// is configured.  Called from `init_process` of the first worker.
+/// Returns 0 on success, otherwise the OS error (-1 when there is
+/// none) for the caller to log.
+#[no_mangle]
+pub extern "C" fn vts_unix_socket_start_ffi() -> i32 {
+    #[cfg(feature = "unix-socket")]
+    if let Err(e) = unix_socket::start() {
+        return e.raw_os_error().unwrap_or(-1);
+    }
+    0
+}
+
+/// Re-render the page the `vts_unix_socket` listener serves.  Called
+/// from a timer on the first worker's event thread; returns 1 while a
+/// listener is running (keep the timer armed), 0 otherwise.
+#[no_mangle]
+pub extern "C" fn vts_unix_socket_refresh_ffi() -> i32 {
+    #[cfg(feature = "unix-socket")]
+    {
+        i32::from(unix_socket::refresh())
+    }
+    #[cfg(not(feature = "unix-socket"))]
+    0
+}
+
+/// Stop the `vts_unix_socket` listener and remove its socket file.
+/// Called from `exit_process`.
+#[no_mangle]
+pub extern "C" fn vts_unix_socket_stop_ffi() {
+    #[cfg(feature = "unix-socket")]
+    unix_socket::stop();
+}
+
+/// Set the `vts_state_file` path; `len` 0 clears it
+/// (preconfiguration).
+///
+/// # Safety
+///
+/// `path` must point to `len` readable bytes (or be null with `len` 0).
+#[no_mangle]
+pub unsafe extern "C" fn vts_set_state_file_ffi(path: *const u8, len: usize) {
+    if path.is_null() || len == 0 {
+        state_file::set_path(None);
+        return;
+    }
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::OsStr::from_bytes(std::slice::from_raw_parts(path, len));
+    state_file::set_path(Some(path.into()));
+}
+
+/// Hand a `vts_state_file` error to C: null for `Ok`, otherwise the
+/// message, valid until the next state-file call.
+fn state_file_result(result: Result<(), String>) -> *const c_char {
+    use std::sync::Mutex;
+
+    static STATE_FILE_ERROR: Mutex<Option<std::ffi::CString>> = Mutex::new(None);
+
+    let Err(message) = result else {
+        return std::ptr::null();
+    };
+    let message =
+        std::ffi::CString::new(message.replace('\0', "")).expect("NUL bytes were removed");
+    STATE_FILE_ERROR
+        .lock()
+        .unwrap_or_else(recover_poisoned)
+        .insert(message)
+        .as_ptr()
+}
+
+/// Merge the `vts_state_file` into the live counters (first worker's
+/// `init_process`).  Returns NULL on success or when nothing was
+/// configured, otherwise why the file was ignored.
+#[no_mangle]
+pub extern "C" fn vts_state_file_load_ffi() -> *const c_char {
+    state_file_result(state_file::load_configured())
+}
+
+/// Write the live counters to the `vts_state_file` (first worker's
+/// `exit_process`).  Returns NULL on success or when nothing was
+/// configured, otherwise why the write failed.
+#[no_mangle]
+pub extern "C" fn vts_state_file_save_ffi() -> *const c_char {
+    state_file_result(state_file::save_configured())
+}
+
+/// External initialization function for nginx module integration
+/// This function is called from the C wrapper during module initialization,
+/// after `ngx_http_vts_register_log_handler` has installed the phase
+/// handlers; it only sets up Rust-side state.
+///
+/// # Safety
+///
+/// This function is safe to call from C code as it handles the null pointer case
+/// and doesn't dereference the configuration pointer directly.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_http_vts_init_rust_module(_cf: *mut ngx_conf_t) -> ngx_int_t {
+    // Initialize upstream zones
+    if initialize_upstream_zones_from_config(_cf).is_err() {
+        return NGX_ERROR as ngx_int_t;
+    }
+
+    NGX_OK as ngx_int_t
+}
+
+/// Seed address `server` of upstream block `upstream` with zeroed
+/// counters.  Called from the C wrapper for every address of every
+/// parsed `upstream {}` block after [`ngx_http_vts_init_rust_module`];
+/// returns 1 when the block already lists the address, so the caller
+/// can warn through the nginx error log.
+///
+/// # Safety
+///
+/// Each pointer must point to its length in readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vts_seed_upstream_server_ffi(
+    upstream: *const u8,
+    upstream_len: usize,
+    server: *const u8,
+    server_len: usize,
+) -> u8 {
+    if upstream.is_null() || server.is_null() {
+        return 0;
+    }
+    let upstream = String::from_utf8_lossy(std::slice::from_raw_parts(upstream, upstream_len));
+    let server = String::from_utf8_lossy(std::slice::from_raw_parts(server, server_len));
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.seed_upstream_server(&upstream, &server) as u8
+}
+
+/// Mark address `server` of upstream block `upstream` as down, or up
+/// again, from its peer's `down` flag.  Called from the C side for
+/// every peer before a page is rendered, so
+/// `nginx_vts_upstream_server_up` and the derived families follow the
+/// running configuration.
+///
+/// # Safety
+///
+/// Each pointer must point to its length in readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vts_set_upstream_down_ffi(
+    upstream: *const u8,
+    upstream_len: usize,
+    server: *const u8,
+    server_len: usize,
+    down: u8,
+) {
+    if upstream.is_null() || server.is_null() {
+        return;
+    }
+    let upstream = String::from_utf8_lossy(std::slice::from_raw_parts(upstream, upstream_len));
+    let server = String::from_utf8_lossy(std::slice::from_raw_parts(server, server_len));
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    manager.set_upstream_server_down(&upstream, &server, down != 0);
+}
+
+#[cfg(test)]
 mod integration_tests {
     use super::*;
 
     #[test]
-    fn test_integrated_vts_status_functionality() {
+    fn test_integrated_vts_status_functionality() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Test the integrated VTS status with upstream stats
+
+        // Create completely fresh manager state for this test to avoid race conditions
+        {
+            let mut manager = match VTS_MANAGER.write() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+
+            // Complete reset to ensure deterministic test state
+            *manager = VtsStatsManager::new();
+        }
+
+        // Set up connection statistics for the test
+        update_connection_stats(1, 0, 1, 0, 16, 16);
+
+        // Add some sample server zone data with unique identifiers for this test
+        update_server_zone_stats("test1-example.com", 200, 1024, 2048, 150);
+        update_server_zone_stats("test1-example.com", 404, 512, 256, 80);
+        update_server_zone_stats("test1-api.example.com", 200, 2048, 4096, 200);
+
+        // Add some upstream stats with unique identifiers for this test
+        update_upstream_zone_stats(
+            "test1-backend_pool",
+            "192.168.1.10:80",
+            100,
+            50,
+            1500,
+            800,
+            200,
+        );
+        update_upstream_zone_stats(
+            "test1-backend_pool",
+            "192.168.1.11:80",
+            150,
+            75,
+            2000,
+            1000,
+            200,
+        );
+        update_upstream_zone_stats(
+            "test1-backend_pool",
+            "192.168.1.10:80",
+            120,
+            60,
+            1200,
+            600,
+            404,
+        );
+
+        update_upstream_zone_stats("test1-api_pool", "192.168.2.10:8080", 80, 40, 800, 400, 200);
+        update_upstream_zone_stats(
+            "test1-api_pool",
+            "192.168.2.11:8080",
+            300,
+            200,
+            3000,
+            1500,
+            500,
+        );
+
+        // Generate VTS status content
+        let status_content = validated_status_content();
+
+        // Verify basic structure
+        assert!(status_content.contains("# nginx-vts-rust"));
+        assert!(status_content.contains("# VTS Status: Active"));
+
+        // Server zones information is now only in Prometheus metrics
+        // (removed duplicate summary section)
+
+        // Verify Prometheus metrics section exists
+        assert!(status_content.contains("# Prometheus Metrics:"));
+        assert!(status_content.contains("nginx_vts_upstream_requests_total"));
+        assert!(status_content.contains("nginx_vts_upstream_responses_total"));
+
+        // Verify specific upstream metrics with test-unique identifiers
+        assert!(status_content.contains("test1-backend_pool"));
+        assert!(status_content.contains("192.168.1.10:80"));
+        assert!(status_content.contains("192.168.1.11:80"));
+        assert!(status_content.contains("test1-api_pool"));
+
+        println!("=== Generated VTS Status Content ===");
+        println!("{}", status_content);
+        println!("=== End VTS Status Content ===");
+    }
+
+    #[test]
+    fn test_issue6_complete_metrics_output() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Create completely fresh manager state for this test
+        {
+            let mut manager = match VTS_MANAGER.write() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            *manager = VtsStatsManager::new();
+        }
+
+        // Set up test data similar to ISSUE6.md requirements with unique identifiers
+        update_connection_stats(1, 0, 1, 0, 16, 16);
+        update_server_zone_stats("test2-example.com", 200, 50000, 2000000, 125);
+        update_server_zone_stats("test2-example.com", 404, 5000, 100000, 50);
+        update_upstream_zone_stats(
+            "test2-backend",
+            "10.0.0.1:8080",
+            50,
+            25,
+            750000,
+            250000,
+            200,
+        );
+        update_upstream_zone_stats(
+            "test2-backend",
+            "10.0.0.2:8080",
+            60,
+            30,
+            680000,
+            230000,
+            200,
+        );
+        update_upstream_zone_stats(
+            "test2-api_backend",
+            "192.168.1.10:9090",
+            80,
+            40,
+            400000,
+            200000,
+            200,
+        );
+
+        let content = validated_status_content();
+
+        println!("=== ISSUE6 Complete Metrics Output ===");
+        println!("{}", content);
+        println!("=== End ISSUE6 Output ===");
+
+        // Verify nginx_vts_info metric
+        assert!(content.contains("# HELP nginx_vts_info Nginx VTS module information"));
+        assert!(content.contains("# TYPE nginx_vts_info gauge"));
+        assert!(content.contains("nginx_vts_info{hostname="));
+
+        // Verify connection metrics
+        assert!(content.contains("# HELP nginx_vts_connections Current nginx connections"));
+        assert!(content.contains("nginx_vts_connections{state=\"active\"} 1"));
+        assert!(content.contains("nginx_vts_connections{state=\"writing\"} 1"));
+        assert!(content.contains("nginx_vts_connections_total{state=\"accepted\"} 16"));
+        assert!(content.contains("nginx_vts_connections_total{state=\"handled\"} 16"));
+
+        // Verify server zone metrics with test-unique identifiers
+        assert!(content.contains("# HELP nginx_vts_server_requests_total Total number of requests"));
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"test2-example.com\"}"));
+        assert!(content.contains("# HELP nginx_vts_server_bytes_total Total bytes transferred"));
+        assert!(content
+            .contains("nginx_vts_server_bytes_total{zone=\"test2-example.com\",direction=\"in\"}"));
+        assert!(content.contains(
+            "nginx_vts_server_bytes_total{zone=\"test2-example.com\",direction=\"out\"}"
+        ));
+
+        // Verify upstream metrics are still present with test-unique identifiers
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"test2-backend\",server=\"10.0.0.1:8080\"}"
+        ));
+        assert!(content.contains("nginx_vts_upstream_requests_total{upstream=\"test2-api_backend\",server=\"192.168.1.10:9090\"}"));
+    }
+
+    #[test]
+    fn test_vts_stats_persistence() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Test that stats persist across multiple updates
+
+        // Create completely fresh manager state for this test
+        {
+            let mut manager = match VTS_MANAGER.write() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            *manager = VtsStatsManager::new();
+        }
+
+        let initial_content = validated_status_content();
+        let _initial_backend_requests = if initial_content.contains("test3-persistence_backend") {
+            1
+        } else {
+            0
+        };
+
+        // Add stats - two requests to same server, one request to different server with unique identifiers
+        update_upstream_zone_stats(
+            "test3-persistence_backend",
+            "10.0.0.1:80",
+            100,
+            50,
+            1000,
+            500,
+            200,
+        );
+        update_upstream_zone_stats(
+            "test3-persistence_backend",
+            "10.0.0.1:80",
+            120,
+            60,
+            1200,
+            600,
+            200,
+        );
+        update_upstream_zone_stats(
+            "test3-persistence_backend",
+            "10.0.0.2:80",
+            80,
+            40,
+            800,
+            400,
+            200,
+        );
+
+        let content1 = validated_status_content();
+        assert!(content1.contains("test3-persistence_backend"));
+
+        let content2 = validated_status_content();
+        // Verify metrics are present (no longer check summary format)
+        assert!(content2.contains("nginx_vts_upstream_requests_total"));
+
+        // Verify final state (deterministic since we reset the manager)
+        let manager = VTS_MANAGER
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Check that the upstream zone exists and has servers with test-unique identifiers
+        let backend_zone = manager.get_upstream_zone("test3-persistence_backend");
+        assert!(backend_zone.is_some(), "Backend zone should exist");
+
+        let zone = backend_zone.unwrap();
+        assert!(
+            zone.servers.contains_key("10.0.0.1:80"),
+            "Server 10.0.0.1:80 should exist"
+        );
+        assert!(
+            zone.servers.contains_key("10.0.0.2:80"),
+            "Server 10.0.0.2:80 should exist"
+        );
+
+        // Verify total requests across both servers (should be 3: 2 + 1)
+        let total_requests: u64 = zone.servers.values().map(|s| s.request_counter).sum();
+        assert_eq!(total_requests, 3, "Total requests should be 3 (2 + 1)");
+    }
+
+    #[test]
+    fn test_empty_vts_stats() {
+        // Test VTS status generation with empty stats
+        // Note: This may not be truly empty if other tests have run first
+        let content = validated_status_content();
+
+        // Should still have basic structure
+        assert!(content.contains("# nginx-vts-rust"));
+        assert!(content.contains("# VTS Status: Active"));
+        assert!(content.contains("# Prometheus Metrics:"));
+
+        // Should always output server metrics headers, even if no data
+        assert!(content.contains("# HELP nginx_vts_server_requests_total Total number of requests"));
+        assert!(content.contains("# TYPE nginx_vts_server_requests_total counter"));
+        assert!(content.contains("# HELP nginx_vts_server_bytes_total Total bytes transferred"));
+        assert!(content.contains("# TYPE nginx_vts_server_bytes_total counter"));
+    }
+
+    #[test]
+    fn test_state_file_restores_counters_after_restart() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        let path =
+            std::env::temp_dir().join(format!("vts-test-{}-restart.bin", std::process::id()));
+        state_file::set_path(Some(path.clone()));
+
+        update_server_zone_stats("state.example.com", 200, 10, 20, 5);
+        update_upstream_zone_stats("state_backend", "10.0.0.9:80", 5, 3, 100, 50, 502);
+        assert!(vts_state_file_save_ffi().is_null());
+
+        // A restart: empty counters, one new request, then the file.
+        reset_manager();
+        update_server_zone_stats("state.example.com", 200, 1, 2, 7);
+        assert!(vts_state_file_load_ffi().is_null());
+
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"state.example.com\"} 2\n"));
+        assert!(content.contains(
+            "nginx_vts_upstream_responses_total{upstream=\"state_backend\",server=\"10.0.0.9:80\",status=\"5xx\"} 1\n"
+        ));
+
+        // A corrupt file is reported and leaves the counters alone.
+        std::fs::write(&path, b"garbage").unwrap();
+        let err = unsafe { std::ffi::CStr::from_ptr(vts_state_file_load_ffi()) };
+        assert!(err.to_str().unwrap().contains("bad magic"));
+        assert!(validated_status_content()
+            .contains("nginx_vts_server_requests_total{zone=\"state.example.com\"} 2\n"));
+
+        std::fs::remove_file(&path).unwrap();
+        state_file::set_path(None);
+        reset_manager();
+    }
+
+    // ---------- helpers ----------
+
+    fn reset_manager() {
+        let mut manager = match VTS_MANAGER.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *manager = VtsStatsManager::new();
+        // The fresh manager holds no connection snapshot to reuse.
+        *LAST_CONNECTION_REFRESH
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+    }
+
+    /// Render `/status` and fail the test if the exposition is malformed.
+    fn validated_status_content() -> String {
+        let content = generate_vts_status_content();
+        if let Err(errors) = crate::prometheus::validate_prometheus(&content) {
+            panic!(
+                "invalid Prometheus output:\n{}\n\n{content}",
+                errors.join("\n")
+            );
+        }
+        content
+    }
+
+    // ---------- upstream + Prometheus rendering ----------
+
+    #[test]
+    fn test_bulk_upstream_requests_render_correctly() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        for i in 0..500 {
+            let status_code = if i % 50 == 0 {
+                500
+            } else if i % 20 == 0 {
+                404
+            } else {
+                200
+            };
+            let request_time = 40 + (i % 30);
+            let upstream_time = request_time / 2;
+            update_upstream_zone_stats(
+                "backend",
+                "127.0.0.1:8080",
+                request_time,
+                upstream_time,
+                1500,
+                750,
+                status_code,
+            );
+        }
+
+        let s = validated_status_content();
+        assert!(s.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 500"
+        ));
+        assert!(s.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 375000"));
+        assert!(s.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 750000"));
+        assert!(s.contains(
+            "nginx_vts_upstream_server_up{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
+        ));
+    }
+
+    #[test]
+    fn test_no_upstream_metrics_until_first_request() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let content = validated_status_content();
+        // Pre-request render must NOT emit per-(upstream,server) request counters.
+        assert!(!content.contains("nginx_vts_upstream_requests_total{"));
+        // But the basic headers and module info are always present.
+        assert!(content.contains("# nginx-vts-rust"));
+        assert!(content.contains("# VTS Status: Active"));
+        assert!(content.contains("# HELP nginx_vts_info"));
+    }
+
+    #[test]
+    fn test_upstream_counters_accumulate_across_requests() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        update_upstream_zone_stats("backend", "127.0.0.1:8080", 85, 42, 1024, 512, 200);
+        let after_one = validated_status_content();
+        assert!(after_one.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
+        ));
+        assert!(after_one.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 512"));
+
+        update_upstream_zone_stats("backend", "127.0.0.1:8080", 92, 48, 1536, 768, 200);
+        let after_two = validated_status_content();
+        assert!(after_two.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 2"
+        ));
+        assert!(after_two.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 1280"));
+        assert!(after_two.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 2560"));
+        assert!(after_two.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"2xx\"} 2"));
+    }
+
+    #[test]
+    fn test_request_local_time_counts_a_retried_request_once() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        // A 300 ms request: 100 ms on a peer that failed, then 150 ms on
+        // the one that answered.  The 50 ms left over is local time, on
+        // the final attempt's server only.
+        update_upstream_zone_stats("backend", "10.0.0.1:80", 300, 100, 100, 0, 502);
+        update_upstream_zone_stats("backend", "10.0.0.2:80", 300, 150, 100, 100, 200);
+        update_upstream_zone_local_time("backend", "10.0.0.2:80", 300, 100 + 150);
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_request_local_seconds{upstream=\"backend\",server=\"10.0.0.1:80\"} 0.000000\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_request_local_seconds{upstream=\"backend\",server=\"10.0.0.2:80\"} 0.050000\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_request_local_clamped_total{upstream=\"backend\",server=\"10.0.0.2:80\"} 0\n"
+        ));
+    }
+
+    #[test]
+    fn test_request_local_time_clamps_when_upstream_time_is_longer() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        update_upstream_zone_stats("backend", "127.0.0.1:8080", 200, 50, 100, 100, 200);
+        update_upstream_zone_local_time("backend", "127.0.0.1:8080", 200, 50);
+        // Upstream time above request time: local time clamps to 0.
+        update_upstream_zone_stats("backend", "127.0.0.1:8080", 100, 150, 100, 100, 200);
+        update_upstream_zone_local_time("backend", "127.0.0.1:8080", 100, 150);
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_request_local_seconds{upstream=\"backend\",server=\"127.0.0.1:8080\"} 0.150000\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_request_local_clamped_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn test_upstream_zone_label_separates_shared_servers() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let set = |upstream: &str, zone: &str| unsafe {
+            vts_set_upstream_zone_ffi(upstream.as_ptr(), upstream.len(), zone.as_ptr(), zone.len())
+        };
+        assert!(set("api", "pool_api").is_null());
+        assert!(set("web", "pool_web").is_null());
+        assert_eq!(
+            unsafe { std::ffi::CStr::from_ptr(set("web", "again")) },
+            VtsError::Duplicate.as_c_str()
+        );
+
+        update_upstream_zone_stats("api", "10.0.0.1:80", 85, 42, 1024, 512, 200);
+        update_upstream_zone_stats("web", "10.0.0.1:80", 85, 42, 1024, 512, 502);
+        update_upstream_zone_stats("web", "10.0.0.1:80", 85, 42, 1024, 512, 200);
+        update_upstream_zone_stats("legacy", "10.0.0.1:80", 85, 42, 1024, 512, 200);
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"api\",zone=\"pool_api\",server=\"10.0.0.1:80\"} 1"
+        ));
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"web\",zone=\"pool_web\",server=\"10.0.0.1:80\"} 2"
+        ));
+        assert!(content.contains(
+            "nginx_vts_upstream_responses_total{upstream=\"web\",zone=\"pool_web\",server=\"10.0.0.1:80\",status=\"5xx\"} 1"
+        ));
+        // Groups without a pool name keep the label, empty.
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"legacy\",zone=\"\",server=\"10.0.0.1:80\"} 1"
+        ));
+
+        vts_clear_upstream_zone_names();
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"web\",server=\"10.0.0.1:80\"} 2"
+        ));
+        assert!(!content.contains("zone=\"pool_"));
+    }
+
+    #[test]
+    fn test_samples_cover_every_series_of_the_page() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        CACHE_MANAGER.clear();
+        seed_every_group();
+
+        let content = validated_status_content();
+        let samples = crate::sample::collect_status_samples();
+        let series = content
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .count();
+        // Every series but nginx_vts_label_truncations, which describes
+        // the page text rather than the stats.
+        assert_eq!(samples.len(), series - 1);
+        assert!(samples
+            .iter()
+            .all(|s| s.name != "nginx_vts_label_truncations"));
+
+        let find = |name: &str, labels: &[(&str, &str)]| {
+            samples
+                .iter()
+                .find(|s| {
+                    s.name == name
+                        && labels
+                            .iter()
+                            .all(|&(k, v)| s.labels.iter().any(|(sk, sv)| sk == k && sv == v))
+                })
+                .unwrap_or_else(|| panic!("no sample {name} {labels:?}"))
+        };
+        use crate::sample::MetricKind;
+        let hits = find(
+            "nginx_vts_cache_requests_total",
+            &[("zone", "reset_cache"), ("status", "hit")],
+        );
+        assert_eq!((hits.value, hits.kind), (1.0, MetricKind::Counter));
+        find("nginx_vts_ssl_handshakes_total", &[("result", "ok")]);
+        assert_eq!(find("nginx_vts_shm_zones", &[]).kind, MetricKind::Gauge);
+        find("nginx_vts_worker_processes", &[]);
+        let bucket = find(
+            "nginx_vts_upstream_response_duration_seconds_bucket",
+            &[("upstream", "reset_backend"), ("le", "+Inf")],
+        );
+        assert_eq!((bucket.value, bucket.kind), (1.0, MetricKind::Histogram));
+    }
+
+    #[test]
+    fn test_delta_mode_returns_increase_since_previous_delta_scrape() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        crate::delta::clear_baseline();
+
+        let delta_scrape = || crate::control::handle_query("mode=delta").unwrap().body;
+        update_server_zone_stats("example.com", 200, 100, 1000, 5);
+        update_server_zone_stats("example.com", 200, 100, 1000, 5);
+
+        // No baseline yet: the full values.
+        let first = delta_scrape();
+        assert!(first.contains("nginx_vts_server_requests_total{zone=\"example.com\"} 2\n"));
+        assert!(first.contains(
+            "nginx_vts_server_bytes_total{zone=\"example.com\",direction=\"out\"} 2000\n"
+        ));
+
+        update_server_zone_stats("example.com", 500, 100, 1000, 5);
+        // A cumulative scrape in between does not move the baseline.
+        assert!(validated_status_content()
+            .contains("nginx_vts_server_requests_total{zone=\"example.com\"} 3"));
+
+        let second = delta_scrape();
+        assert!(second.contains("nginx_vts_server_requests_total{zone=\"example.com\"} 1\n"));
+        assert!(second
+            .contains("nginx_vts_server_responses_total{zone=\"example.com\",status=\"2xx\"} 0\n"));
+        assert!(second
+            .contains("nginx_vts_server_responses_total{zone=\"example.com\",status=\"5xx\"} 1\n"));
+
+        let influx = crate::control::handle_query("mode=delta&format=influx")
+            .unwrap()
+            .body;
+        assert!(influx.contains("nginx_vts_server,zone=example.com requests=0i,"));
+        crate::delta::clear_baseline();
+    }
+
+    #[test]
+    fn test_upstream_key_name_merges_resolved_addresses() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let track = |addr: &std::ffi::CStr| unsafe {
+            vts_track_upstream_request(
+                c"backend".as_ptr(),
+                addr.as_ptr(),
+                c"backend.example.com:8080".as_ptr(),
+                1000,
+                0,
+                10,
+                100,
+                50,
+                200,
+            );
+        };
+
+        // Default `addr`: one series per resolved address.
+        track(c"10.0.0.1:8080");
+        track(c"10.0.0.2:8080");
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"10.0.0.1:8080\"} 1"
+        ));
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"10.0.0.2:8080\"} 1"
+        ));
+
+        // `name`: the address change is invisible.
+        reset_manager();
+        vts_set_upstream_key_by_name(1);
+        assert_eq!(vts_upstream_key_by_name(), 1);
+        track(c"10.0.0.1:8080");
+        track(c"10.0.0.2:8080");
+        unsafe {
+            // No configured name known: still keyed by address.
+            vts_track_upstream_request(
+                c"backend".as_ptr(),
+                c"10.0.0.3:8080".as_ptr(),
+                std::ptr::null(),
+                1000,
+                0,
+                10,
+                100,
+                50,
+                200,
+            );
+        }
+        vts_set_upstream_key_by_name(0);
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"backend.example.com:8080\"} 2"
+        ));
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"10.0.0.3:8080\"} 1"
+        ));
+        assert!(!content.contains("server=\"10.0.0.1:8080\""));
+    }
+
+    #[test]
+    fn test_server_request_seconds_sum_and_count_match_totals() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        for request_time in [5, 15, 250] {
+            update_server_zone_stats("little.example.com", 200, 10, 20, request_time);
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_server_request_seconds_sum counter\n"));
+        assert!(content
+            .contains("nginx_vts_server_request_seconds_sum{zone=\"little.example.com\"} 0.270"));
+        assert!(content
+            .contains("nginx_vts_server_request_seconds_count{zone=\"little.example.com\"} 3\n"));
+    }
+
+    #[test]
+    fn test_quic_connection_transitions_reach_the_status_page() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        // Three connections start; two complete the handshake, one of
+        // them with 0-RTT; one of those then closes.
+        for _ in 0..3 {
+            vts_track_quic_connection(0, 1, 0);
+        }
+        vts_track_quic_connection(1, 2, 1);
+        vts_track_quic_connection(1, 2, 0);
+        vts_track_quic_connection(2, 0, 0);
+        // Unknown phases are ignored; closing an untracked one saturates.
+        vts_track_quic_connection(7, 2, 1);
+        vts_track_quic_connection(2, 0, 0);
+        vts_track_quic_connection(2, 0, 0);
+
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_quic_connections{state=\"handshaking\"} 1\n"));
+        assert!(content.contains("nginx_vts_quic_connections{state=\"established\"} 0\n"));
+        assert!(content.contains("nginx_vts_quic_0rtt_total 1\n"));
+    }
+
+    #[test]
+    fn test_sample_rate_scales_counters_to_approximate_totals() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let server_name = std::ffi::CString::new("busy.example.com").unwrap();
+        vts_set_sample_rate(10);
+        for _ in 0..1005 {
+            unsafe {
+                vts_update_server_stats_ffi(server_name.as_ptr(), 200, 10, 100, 40, 20, 0, 1);
+            }
+        }
+        vts_set_sample_rate(0);
+
+        let stats = {
+            let manager = VTS_MANAGER
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            manager.get_all_server_stats()["busy.example.com"].clone()
+        };
+        // Every sampled request stands for ten, so the scaled count is
+        // within one sample of the truth whatever the tick offset.
+        assert!(stats.requests.abs_diff(1005) < 10, "{}", stats.requests);
+        assert_eq!(stats.requests % 10, 0);
+        assert_eq!(stats.responses.status_2xx, stats.requests);
+        assert_eq!(stats.bytes_in, stats.requests * 10);
+        assert_eq!(stats.bytes_out, stats.requests * 100);
+        assert_eq!(stats.header_bytes_out, stats.requests * 40);
+        assert_eq!(stats.request_times.avg, 0.02);
+        assert_eq!(stats.request_times.max, 0.02);
+
+        // Back at rate 1, every request counts once.
+        unsafe {
+            vts_update_server_stats_ffi(server_name.as_ptr(), 200, 10, 100, 40, 20, 0, 1);
+        }
+        let manager = VTS_MANAGER
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        assert_eq!(
+            manager.get_all_server_stats()["busy.example.com"].requests,
+            stats.requests + 1
+        );
+    }
+
+    #[test]
+    fn test_sample_rate_counts_interleaved_zones_separately() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        // Alternating requests: a shared tick would hand every sampled
+        // request to one of the two zones.
+        let zones = [
+            std::ffi::CString::new("left.interleaved.example.com").unwrap(),
+            std::ffi::CString::new("right.interleaved.example.com").unwrap(),
+        ];
+        vts_set_sample_rate(4);
+        for _ in 0..400 {
+            for zone in &zones {
+                unsafe {
+                    vts_update_server_stats_ffi(zone.as_ptr(), 200, 10, 100, 40, 20, 0, 1);
+                }
+            }
+        }
+        vts_set_sample_rate(0);
+
+        let manager = VTS_MANAGER
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let stats = manager.get_all_server_stats();
+        assert_eq!(stats["left.interleaved.example.com"].requests, 400);
+        assert_eq!(stats["right.interleaved.example.com"].requests, 400);
+    }
+
+    #[test]
+    fn test_zone_alias_combines_zones_in_output() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let alias = |from: &str, to: &str| unsafe {
+            vts_add_zone_alias_ffi(from.as_ptr(), from.len(), to.as_ptr(), to.len())
+        };
+        assert!(alias("legacy.example.com", "example.com").is_null());
+        assert!(alias("www.example.com", "example.com").is_null());
+        assert!(!alias("www.example.com", "other.example.com").is_null());
+
+        let legacy = std::ffi::CString::new("legacy.example.com").unwrap();
+        let www = std::ffi::CString::new("www.example.com").unwrap();
+        unsafe {
+            vts_update_server_stats_ffi(legacy.as_ptr(), 200, 10, 100, 0, 5, 0, 1);
+            vts_update_server_stats_ffi(www.as_ptr(), 200, 20, 200, 0, 5, 0, 1);
+            vts_update_server_stats_ffi(www.as_ptr(), 404, 30, 300, 0, 5, 0, 1);
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"example.com\"} 3\n"));
+        assert!(content
+            .contains("nginx_vts_server_bytes_total{zone=\"example.com\",direction=\"in\"} 60\n"));
+        assert!(content
+            .contains("nginx_vts_server_responses_total{zone=\"example.com\",status=\"2xx\"} 2\n"));
+        assert!(content
+            .contains("nginx_vts_server_responses_total{zone=\"example.com\",status=\"4xx\"} 1\n"));
+        assert!(!content.contains("zone=\"legacy.example.com\""));
+        assert!(!content.contains("zone=\"www.example.com\""));
+
+        vts_clear_zone_aliases();
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"www.example.com\"} 2\n"));
+    }
+
+    #[test]
+    fn test_upstream_status_zero_counts_as_no_response() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        update_upstream_zone_stats("backend", "127.0.0.1:8080", 85, 42, 1024, 512, 200);
+        update_upstream_zone_stats("backend", "127.0.0.1:8080", 3000, 3000, 1024, 0, 0);
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 2"
+        ));
+        assert!(content.contains(
+            "nginx_vts_upstream_no_response_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
+        ));
+        // Only the 200 landed in a status class; the status-0 attempt
+        // is accounted for by no_response alone.
+        for (class, count) in [("1xx", 0), ("2xx", 1), ("3xx", 0), ("4xx", 0), ("5xx", 0)] {
+            assert!(content.contains(&format!(
+                "nginx_vts_upstream_responses_total{{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"{class}\"}} {count}\n"
+            )));
+        }
+    }
+
+    #[test]
+    fn test_vts_track_upstream_request_ffi_records_into_state() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let upstream_name = std::ffi::CString::new("backend").unwrap();
+        let server_addr = std::ffi::CString::new("127.0.0.1:8080").unwrap();
+
+        unsafe {
+            vts_track_upstream_request(
+                upstream_name.as_ptr(),
+                server_addr.as_ptr(),
+                std::ptr::null(),
+                1000,
+                500,
+                38,
+                2048,
+                1024,
+                200,
+            );
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
+        ));
+        assert!(content.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 1024"));
+        assert!(content.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 2048"));
+    }
+
+    #[test]
+    fn test_upstream_request_and_response_bytes_follow_the_ffi_arguments() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let upstream_name = std::ffi::CString::new("backend").unwrap();
+        let server_addr = std::ffi::CString::new("127.0.0.1:8080").unwrap();
+        // A small request drawing a large response: 300 bytes sent to
+        // the upstream, 7000 received from it.
+        unsafe {
+            vts_track_upstream_request(
+                upstream_name.as_ptr(),
+                server_addr.as_ptr(),
+                std::ptr::null(),
+                1000,
+                500,
+                38,
+                300,
+                7000,
+                200,
+            );
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_request_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 300\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_upstream_response_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 7000\n"
+        ));
+    }
+
+    #[test]
+    fn test_vts_track_upstream_queue_ffi_tracks_length_and_waits() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let content = validated_status_content();
+        assert!(!content.contains("nginx_vts_upstream_queue_length"));
+
+        let upstream_name = std::ffi::CString::new("backend").unwrap();
+        unsafe {
+            vts_track_upstream_queue(upstream_name.as_ptr(), true);
+            vts_track_upstream_queue(upstream_name.as_ptr(), true);
+            vts_track_upstream_queue(upstream_name.as_ptr(), true);
+            vts_track_upstream_queue(upstream_name.as_ptr(), false);
+            vts_track_upstream_queue(std::ptr::null(), true);
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_upstream_queue_length gauge"));
+        assert!(content.contains("nginx_vts_upstream_queue_length{upstream=\"backend\"} 2\n"));
+        assert!(content.contains("# TYPE nginx_vts_upstream_queue_waits_total counter"));
+        assert!(content.contains("nginx_vts_upstream_queue_waits_total{upstream=\"backend\"} 3\n"));
+
+        // Draining the queue drops the gauge but keeps the counter.
+        track_upstream_queue("backend", false);
+        track_upstream_queue("backend", false);
+        track_upstream_queue("backend", false);
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_upstream_queue_length{upstream=\"backend\"} 0\n"));
+        assert!(content.contains("nginx_vts_upstream_queue_waits_total{upstream=\"backend\"} 3\n"));
+    }
+
+    #[test]
+    fn test_initialize_upstream_zones_seeds_zero_metrics() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        // Before init: no per-server series.
+        let before = validated_status_content();
+        assert!(before.contains("nginx_vts_upstream_zones_total 0"));
+
+        initialize_upstream_zones_for_testing();
+        let after = validated_status_content();
+
+        // Should now render the configured backend with zero counters.
+        assert!(after.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 0"
+        ));
+        assert!(after.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 0"));
+        assert!(after.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 0"));
+        assert!(after.contains(
+            "nginx_vts_upstream_server_up{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
+        ));
+        // All status-class buckets exist with 0.
+        for class in ["1xx", "2xx", "3xx", "4xx", "5xx"] {
+            let expected = format!(
+                "nginx_vts_upstream_responses_total{{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"{}\"}} 0",
+                class
+            );
+            assert!(after.contains(&expected), "missing {class}");
+        }
+    }
+
+    #[test]
+    fn test_duplicate_upstream_servers_are_counted() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let before = validated_status_content();
+        assert!(!before.contains("nginx_vts_upstream_duplicate_servers_total"));
+
+        let upstream = "dup_backend";
+        let duplicates: Vec<u8> = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.1:80"]
+            .iter()
+            .map(|addr| unsafe {
+                vts_seed_upstream_server_ffi(
+                    upstream.as_ptr(),
+                    upstream.len(),
+                    addr.as_ptr(),
+                    addr.len(),
+                )
+            })
+            .collect();
+        // Only the repeat is reported back to the C side for its warning.
+        assert_eq!(duplicates, [0, 0, 1]);
+
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_upstream_duplicate_servers_total counter"));
+        assert!(content
+            .contains("nginx_vts_upstream_duplicate_servers_total{upstream=\"dup_backend\"} 1"));
+        // Merged, not listed twice.
+        assert_eq!(
+            content
+                .matches("nginx_vts_upstream_requests_total{upstream=\"dup_backend\",server=\"10.0.0.1:80\"}")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_peer_down_flags_drive_server_up_and_fully_down() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let upstream = "down_backend";
+        let set_down = |addr: &str, down: u8| unsafe {
+            vts_set_upstream_down_ffi(
+                upstream.as_ptr(),
+                upstream.len(),
+                addr.as_ptr(),
+                addr.len(),
+                down,
+            )
+        };
+        set_down("10.0.0.1:80", 1);
+        set_down("10.0.0.2:80", 1);
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_server_up{upstream=\"down_backend\",server=\"10.0.0.1:80\"} 0"
+        ));
+        assert!(content.contains("nginx_vts_upstream_fully_down{upstream=\"down_backend\"} 1"));
+
+        // The status handler copies the flags again before every page.
+        set_down("10.0.0.2:80", 0);
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_server_up{upstream=\"down_backend\",server=\"10.0.0.2:80\"} 1"
+        ));
+        assert!(content.contains("nginx_vts_upstream_fully_down{upstream=\"down_backend\"} 0"));
+    }
+
+    #[test]
+    fn test_upstream_server_state_follows_the_peer_down_flag() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let (upstream, server) = ("state_backend", "10.0.0.1:80");
+        update_upstream_zone_stats(upstream, server, 50, 40, 100, 200, 200);
+        let state = |content: &str, state: &str| {
+            content.contains(&format!(
+                "nginx_vts_upstream_server_state{{upstream=\"{upstream}\",server=\"{server}\",state=\"{state}\"}} 1"
+            ))
+        };
+        assert!(state(&validated_status_content(), "healthy"));
+
+        let set_down = |down: u8| unsafe {
+            vts_set_upstream_down_ffi(
+                upstream.as_ptr(),
+                upstream.len(),
+                server.as_ptr(),
+                server.len(),
+                down,
+            )
+        };
+        set_down(1);
+        let content = validated_status_content();
+        assert!(state(&content, "down"));
+        assert!(!state(&content, "healthy"));
+
+        set_down(0);
+        assert!(state(&validated_status_content(), "healthy"));
+    }
+
+    #[test]
+    fn test_status_response_includes_help_and_type_headers() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        initialize_upstream_zones_for_testing();
+
+        let content = validated_status_content();
+        for header in [
+            "# HELP nginx_vts_upstream_requests_total Total upstream requests",
+            "# TYPE nginx_vts_upstream_requests_total counter",
+            "# HELP nginx_vts_upstream_bytes_total Total bytes transferred to/from upstream",
+            "# HELP nginx_vts_upstream_response_seconds Upstream response time statistics",
+            "# HELP nginx_vts_upstream_server_up Upstream server status (1=up, 0=down)",
+            "# HELP nginx_vts_upstream_responses_total Upstream responses by status code",
+        ] {
+            assert!(content.contains(header), "missing header: {header}");
+        }
+    }
+
+    #[test]
+    fn test_complete_status_flow_init_request_then_recorded() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        initialize_upstream_zones_for_testing();
+
+        // Step 1: fresh status → zero counters.
+        let first = validated_status_content();
+        assert!(first.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 0"
+        ));
+
+        // Step 2: simulate a 94ms request through the upstream.
+        update_upstream_zone_stats("backend", "127.0.0.1:8080", 94, 30, 1370, 615, 200);
+
+        // Step 3: counters reflect the request.
+        let third = validated_status_content();
+        assert!(third.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
+        ));
+        assert!(third.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 615"));
+        assert!(third.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 1370"));
+        assert!(third.contains("nginx_vts_upstream_response_seconds{upstream=\"backend\",server=\"127.0.0.1:8080\",type=\"request_avg\"} 0.094000"));
+        assert!(third.contains("nginx_vts_upstream_response_seconds{upstream=\"backend\",server=\"127.0.0.1:8080\",type=\"upstream_avg\"} 0.030000"));
+    }
+
+    #[test]
+    fn test_log_phase_ffi_accumulates_across_calls() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        initialize_upstream_zones_for_testing();
+
+        let upstream_name = std::ffi::CString::new("backend").unwrap();
+        let server_addr = std::ffi::CString::new("127.0.0.1:8080").unwrap();
+
+        // Three sequential LOG_PHASE-style calls with mixed statuses.
+        let calls: &[(u64, u64, u64, u64, u16)] = &[
+            (500, 42, 1024, 512, 200),
+            (600, 55, 2048, 1024, 200),
+            (700, 48, 1536, 768, 404),
+        ];
+        for &(start_msec, response_ms, bytes_sent, bytes_received, status) in calls {
+            unsafe {
+                vts_track_upstream_request(
+                    upstream_name.as_ptr(),
+                    server_addr.as_ptr(),
+                    std::ptr::null(),
+                    1000,
+                    start_msec,
+                    response_ms,
+                    bytes_sent,
+                    bytes_received,
+                    status,
+                );
+            }
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 3"
+        ));
+        // 512 + 1024 + 768 = 2304 in / 1024 + 2048 + 1536 = 4608 out.
+        assert!(content.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 2304"));
+        assert!(content.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 4608"));
+        // 2 × 2xx + 1 × 4xx.
+        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"2xx\"} 2"));
+        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"4xx\"} 1"));
+    }
+
+    #[test]
+    fn test_log_phase_ffi_categorises_diverse_status_codes() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        initialize_upstream_zones_for_testing();
+
+        let upstream_name = std::ffi::CString::new("backend").unwrap();
+        let server_addr = std::ffi::CString::new("127.0.0.1:8080").unwrap();
+
+        // 2 baseline 200s with extreme timings, then 8 mixed statuses.
+        let scenarios: &[(u64, u64, u64, u64, u16)] = &[
+            (0, 100, 50, 1, 200),                 // sub-ms upstream
+            (1800, 1_048_576, 2_097_152, 1, 200), // ~1.8s upstream, MBs
+            (25, 200, 100, 1, 301),
+            (25, 200, 100, 1, 302),
+            (25, 200, 100, 1, 400),
+            (25, 200, 100, 1, 401),
+            (25, 200, 100, 1, 403),
+            (25, 200, 100, 1, 500),
+            (25, 200, 100, 1, 502),
+            (25, 200, 100, 1, 503),
+        ];
+        for &(response_ms, bytes_sent, bytes_received, start_msec, status) in scenarios {
+            unsafe {
+                vts_track_upstream_request(
+                    upstream_name.as_ptr(),
+                    server_addr.as_ptr(),
+                    std::ptr::null(),
+                    1000,
+                    start_msec,
+                    response_ms,
+                    bytes_sent,
+                    bytes_received,
+                    status,
+                );
+            }
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 10"
+        ));
+        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"2xx\"} 2"));
+        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"3xx\"} 2"));
+        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"4xx\"} 3"));
+        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"5xx\"} 3"));
+    }
+
+    #[test]
+    fn test_absurd_upstream_time_is_discarded() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let upstream_name = std::ffi::CString::new("backend").unwrap();
+        let server_addr = std::ffi::CString::new("127.0.0.1:8080").unwrap();
+        let discarded_before = discarded_observations();
+
+        for response_ms in [40, 3_000_000_000, 60] {
+            unsafe {
+                vts_track_upstream_request(
+                    upstream_name.as_ptr(),
+                    server_addr.as_ptr(),
+                    std::ptr::null(),
+                    1000,
+                    0,
+                    response_ms,
+                    100,
+                    50,
+                    200,
+                );
+            }
+        }
+
+        assert_eq!(discarded_observations(), discarded_before + 1);
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 2"
+        ));
+        // (40 + 60) / 2 = 50ms: the absurd sample never reached the sum.
+        assert!(content.contains("nginx_vts_upstream_response_seconds{upstream=\"backend\",server=\"127.0.0.1:8080\",type=\"upstream_avg\"} 0.050000"));
+        assert!(content.contains(&format!(
+            "nginx_vts_discarded_observations_total {}",
+            discarded_before + 1
+        )));
+    }
+
+    #[test]
+    fn test_absurd_server_request_time_is_discarded() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let server_name = std::ffi::CString::new("absurd.example.com").unwrap();
+        let discarded_before = discarded_observations();
+        unsafe {
+            vts_update_server_stats_ffi(server_name.as_ptr(), 200, 10, 20, 0, 100, 0, 1);
+            // A negative C-side difference wrapped to u64.
+            vts_update_server_stats_ffi(server_name.as_ptr(), 200, 10, 20, 0, u64::MAX - 5, 0, 1);
+        }
+
+        assert_eq!(discarded_observations(), discarded_before + 1);
+        let manager = VTS_MANAGER
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let zone = manager.stats.get("absurd.example.com").unwrap();
+        assert_eq!(zone.requests, 1);
+        assert_eq!(zone.request_time_max, 100);
+    }
+
+    #[test]
+    fn test_non_utf8_server_name_is_recorded_lossily() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let server_name = std::ffi::CString::new(b"caf\xe9.example.com".to_vec()).unwrap();
+        let non_utf8_before = non_utf8_name_requests();
+        unsafe {
+            vts_update_server_stats_ffi(server_name.as_ptr(), 200, 10, 20, 0, 5, 0, 1);
+        }
+
+        assert_eq!(non_utf8_name_requests(), non_utf8_before + 1);
+        {
+            let manager = VTS_MANAGER
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let zone = manager.stats.get("caf\u{FFFD}.example.com").unwrap();
+            assert_eq!(zone.requests, 1);
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains("zone=\"caf\u{FFFD}.example.com\""));
+        assert!(content.contains(&format!(
+            "nginx_vts_non_utf8_name_requests_total {}",
+            non_utf8_before + 1
+        )));
+
+        // Valid names are not counted.
+        let valid = std::ffi::CString::new("ok.example.com").unwrap();
+        unsafe {
+            vts_update_server_stats_ffi(valid.as_ptr(), 200, 10, 20, 0, 5, 0, 1);
+        }
+        assert_eq!(non_utf8_name_requests(), non_utf8_before + 1);
+
+        // Every request under the lossy name counts, not just the first.
+        unsafe {
+            vts_update_server_stats_ffi(server_name.as_ptr(), 200, 10, 20, 0, 5, 0, 1);
+        }
+        assert_eq!(non_utf8_name_requests(), non_utf8_before + 2);
+    }
+
+    #[test]
+    fn test_ssl_handshakes_are_counted_by_result() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let before = ssl_stats();
+        vts_track_ssl(NGX_OK as ngx_int_t, 0);
+        vts_track_ssl(NGX_OK as ngx_int_t, 1);
+        vts_track_ssl(NGX_ERROR as ngx_int_t, 0);
+        // A failed handshake never counts as a resumption.
+        vts_track_ssl(NGX_ERROR as ngx_int_t, 1);
+
+        let after = ssl_stats();
+        assert_eq!(after.handshakes_ok, before.handshakes_ok + 2);
+        assert_eq!(after.handshakes_failed, before.handshakes_failed + 2);
+        assert_eq!(after.session_reuses, before.session_reuses + 1);
+
+        let content = validated_status_content();
+        assert!(content.contains(&format!(
+            "nginx_vts_ssl_handshakes_total{{result=\"ok\"}} {}\n",
+            after.handshakes_ok
+        )));
+        assert!(content.contains(&format!(
+            "nginx_vts_ssl_handshakes_total{{result=\"failed\"}} {}\n",
+            after.handshakes_failed
+        )));
+        assert!(content.contains(&format!(
+            "nginx_vts_ssl_session_reuses_total {}\n",
+            after.session_reuses
+        )));
+    }
+
+    #[test]
+    fn test_connection_snapshot_is_reused_within_refresh_interval() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        vts_set_connection_refresh_interval_ms(60 * 60 * 1000);
+
+        let before = CONNECTION_COLLECTIONS.load(Ordering::Relaxed);
+        vts_collect_nginx_connections();
+        assert_eq!(CONNECTION_COLLECTIONS.load(Ordering::Relaxed), before + 1);
+
+        // Collections within the interval reuse the stored snapshot
+        // instead of overwriting it.
+        update_connection_stats(7, 0, 7, 0, 99, 99);
+        vts_collect_nginx_connections();
+        vts_collect_nginx_connections();
+        assert_eq!(CONNECTION_COLLECTIONS.load(Ordering::Relaxed), before + 1);
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_connections{state=\"active\"} 7"));
+        assert!(content.contains("nginx_vts_connections_total{state=\"accepted\"} 99"));
+
+        // Changing the interval drops the cached stamp; after it has
+        // elapsed the next collection runs again.
+        vts_set_connection_refresh_interval_ms(1);
+        vts_collect_nginx_connections();
+        std::thread::sleep(Duration::from_millis(5));
+        vts_collect_nginx_connections();
+        assert_eq!(CONNECTION_COLLECTIONS.load(Ordering::Relaxed), before + 3);
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_connections{state=\"active\"} 1"));
+
+        vts_set_connection_refresh_interval_ms(0);
+        assert_eq!(
+            CONNECTION_REFRESH_INTERVAL_MS.load(Ordering::Relaxed),
+            DEFAULT_CONNECTION_REFRESH_INTERVAL_MS
+        );
+    }
+
+    #[test]
+    fn test_max_request_time_is_configurable() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        vts_set_max_request_time_ms(1000);
+        assert!(is_plausible_time_ms(1000));
+        assert!(!is_plausible_time_ms(1001));
+
+        // 0 restores the default.
+        vts_set_max_request_time_ms(0);
+        assert!(is_plausible_time_ms(DEFAULT_MAX_REQUEST_TIME_MS));
+        assert!(!is_plausible_time_ms(DEFAULT_MAX_REQUEST_TIME_MS + 1));
+    }
+
+    #[test]
+    fn test_diagnostics_report_counts_zones() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        CACHE_MANAGER.clear();
+
+        update_server_zone_stats("diag1.example.com", 200, 1, 1, 1);
+        update_server_zone_stats("diag2.example.com", 200, 1, 1, 1);
+        update_server_zone_stats("diag3.example.com", 200, 1, 1, 1);
+        update_upstream_zone_stats("diag_backend", "10.0.0.1:80", 1, 1, 1, 1, 200);
+        update_cache_stats("diag_cache", "HIT", None);
+
+        let raw = unsafe { std::ffi::CStr::from_ptr(ngx_http_vts_get_diagnostics()) };
+        let report = raw.to_str().unwrap();
+        assert!(report.contains("server_zones: 3\n"));
+        assert!(report.contains("upstream_zones: 1\n"));
+        assert!(report.contains("cache_zones: 1\n"));
+        assert!(report.contains("shared_memory: not configured\n"));
+        assert!(report.contains("zone_size: 0\n"));
+        assert!(report.contains(&format!("lock_poisoned: {}\n", lock_poison_count())));
+        assert!(report.contains("\nclock_skew: "));
+        // Diagnostics are not the Prometheus exposition.
+        assert!(!report.contains("nginx_vts_"));
+    }
+
+    /// What `ngx_http_vts_status_handler` copies into its response
+    /// buffer: it sizes the buffer and `Content-Length` with `strlen`
+    /// of this pointer, so the body must be the full exposition with
+    /// no interior NUL, and the pointer must stay valid until the next
+    /// call.  The C handler itself needs a linked nginx to run and is
+    /// not driven here.
+    #[test]
+    fn test_status_handler_body_is_full_exposition() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        update_server_zone_stats("handler.example.com", 200, 100, 2048, 5);
+        update_upstream_zone_stats("backend", "10.0.0.1:80", 5, 3, 100, 2048, 200);
+
+        let ptr = unsafe { ngx_http_vts_get_status() };
+        let body = unsafe { std::ffi::CStr::from_ptr(ptr) }.to_bytes().to_vec();
+        let expected = validated_status_content();
+        assert_eq!(body.len(), expected.len());
+        assert_eq!(body, expected.as_bytes());
+        assert!(body.ends_with(b"\n"));
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("nginx_vts_server_requests_total{zone=\"handler.example.com\"} 1"));
+
+        // A second scrape replaces the cached body with fresh content.
+        update_server_zone_stats("handler.example.com", 200, 100, 2048, 5);
+        let again = unsafe { std::ffi::CStr::from_ptr(ngx_http_vts_get_status()) };
+        assert!(again
+            .to_str()
+            .unwrap()
+            .contains("nginx_vts_server_requests_total{zone=\"handler.example.com\"} 2"));
+    }
+
+    /// The status handler's page buffer is kept across scrapes: a
+    /// repeated scrape of the same zones is rendered into the same
+    /// allocation instead of a new page.
+    #[test]
+    fn test_repeated_scrape_reuses_status_page() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        update_server_zone_stats("reuse.example.com", 200, 100, 2048, 5);
+        let first = unsafe { ngx_http_vts_get_status() };
+        let first_len = unsafe { std::ffi::CStr::from_ptr(first) }.to_bytes().len();
+
+        update_server_zone_stats("reuse.example.com", 200, 100, 2048, 5);
+        let again = unsafe { ngx_http_vts_get_status() };
+        assert_eq!(again, first);
+        let body = unsafe { std::ffi::CStr::from_ptr(again) }.to_str().unwrap();
+        assert_eq!(body.len(), first_len);
+        assert!(body.contains("nginx_vts_server_requests_total{zone=\"reuse.example.com\"} 2"));
+    }
+
+    #[test]
+    fn test_disabled_zone_is_frozen_and_marked() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let name = std::ffi::CString::new("paused.example.com").unwrap();
+        unsafe {
+            vts_update_server_stats_ffi(name.as_ptr(), 200, 10, 20, 0, 5, 0, 1);
+            vts_set_zone_enabled_ffi(name.as_ptr() as *const u8, name.as_bytes().len(), 0);
+            vts_update_server_stats_ffi(name.as_ptr(), 200, 10, 20, 0, 5, 0, 1);
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"paused.example.com\"} 1"));
+        assert!(content.contains("nginx_vts_server_zone_disabled{zone=\"paused.example.com\"} 1"));
+
+        vts_clear_disabled_zones();
+        unsafe {
+            vts_update_server_stats_ffi(name.as_ptr(), 200, 10, 20, 0, 5, 0, 1);
+        }
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"paused.example.com\"} 2"));
+        assert!(!content.contains("nginx_vts_server_zone_disabled{"));
+    }
+
+    #[test]
+    fn test_zone_label_appears_only_on_its_zone() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let add = |zone: &str, name: &str, value: &str| unsafe {
+            let error = vts_add_zone_label_ffi(
+                zone.as_ptr(),
+                zone.len(),
+                name.as_ptr(),
+                name.len(),
+                value.as_ptr(),
+                value.len(),
+            );
+            (!error.is_null()).then(|| std::ffi::CStr::from_ptr(error).to_str().unwrap())
+        };
+        assert_eq!(add("acme.example.com", "tenant", "acme \"inc\""), None);
+        assert_eq!(
+            add("acme.example.com", "tenant", "again"),
+            Some("duplicate label name")
+        );
+        assert_eq!(
+            add("acme.example.com", "status", "x"),
+            Some("reserved label name")
+        );
+
+        update_server_zone_stats("acme.example.com", 200, 10, 20, 5);
+        update_server_zone_stats("other.example.com", 200, 10, 20, 5);
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_server_requests_total{zone=\"acme.example.com\",tenant=\"acme \\\"inc\\\"\"} 1"
+        ));
+        assert!(content.contains(
+            "nginx_vts_server_responses_total{zone=\"acme.example.com\",tenant=\"acme \\\"inc\\\"\",status=\"2xx\"} 1"
+        ));
+        assert!(content
+            .contains("nginx_vts_server_requests_total{zone=\"other.example.com\",tenant=\"\"} 1"));
+        for direction in ["in", "out"] {
+            assert!(content.contains(&format!(
+                "nginx_vts_server_bytes_total{{zone=\"acme.example.com\",tenant=\"acme \\\"inc\\\"\",direction=\"{direction}\"}}"
+            )));
+        }
+        // Whatever the family, a series of the zone carries its label.
+        for line in content
+            .lines()
+            .filter(|l| l.contains("zone=\"acme.example.com\""))
+        {
+            assert!(
+                line.contains("zone=\"acme.example.com\",tenant=\"acme \\\"inc\\\"\""),
+                "{line}"
+            );
+        }
+
+        vts_clear_zone_labels();
+        let content = validated_status_content();
+        assert!(!content.contains("tenant="));
+    }
+
+    #[test]
+    fn test_check_zone_label_ffi_rejects_bad_names_and_overflow() {
+        let check = |name: &str, configured: usize| unsafe {
+            let error = vts_check_zone_label_ffi(name.as_ptr(), name.len(), configured);
+            (!error.is_null()).then(|| std::ffi::CStr::from_ptr(error).to_str().unwrap())
+        };
+        assert_eq!(check("tenant", 0), None);
+        assert_eq!(check("team-x", 0), Some("invalid label name"));
+        assert_eq!(check("zone", 0), Some("reserved label name"));
+        assert_eq!(
+            check("tenant", vts_node::MAX_ZONE_LABELS),
+            Some("too many labels for one zone")
+        );
+    }
+
+    // ---------- cache stats ----------
+
+    #[test]
+    fn test_default_server_name_maps_to_default_zone() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        unsafe {
+            vts_update_server_stats_ffi(c"".as_ptr(), 200, 10, 20, 0, 5, 0, 1);
+            vts_update_server_stats_ffi(c"_".as_ptr(), 404, 10, 20, 0, 5, 0, 1);
+        }
+        update_server_zone_stats("", 200, 1, 1, 1);
+
+        let status = validated_status_content();
+        assert!(status.contains("nginx_vts_server_requests_total{zone=\"default\"} 3"));
+        assert!(!status.contains("zone=\"\""));
+        assert!(!status.contains("zone=\"_\""));
+    }
+
+    #[test]
+    fn test_request_without_server_block_counts_in_unknown_zone() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        // A main request that never named a host, e.g. a malformed
+        // request line answered with 400 by the default server.
+        let mut r: ngx_http_request_t = unsafe { std::mem::zeroed() };
+        r.main = std::ptr::addr_of_mut!(r);
+        r.headers_out.status = 400;
+        // The same request with a `Host` lands in its server block.
+        let mut host = *b"example.org";
+        let mut named: ngx_http_request_t = unsafe { std::mem::zeroed() };
+        named.main = std::ptr::addr_of_mut!(named);
+        named.headers_in.server = ngx_str_t {
+            len: host.len(),
+            data: host.as_mut_ptr(),
+        };
+        unsafe {
+            vts_log_server_request(&r, 0, 0);
+            vts_log_server_request(&named, 0, 0);
+            vts_update_server_stats_ffi(std::ptr::null(), 400, 10, 20, 0, 5, 0, 1);
+        }
+        update_server_zone_stats("example.com", 200, 10, 20, 5);
+
+        let status = validated_status_content();
+        assert!(status.contains("nginx_vts_server_requests_total{zone=\"__unknown__\"} 2"));
+        assert!(status.contains("nginx_vts_server_requests_total{zone=\"example.com\"} 1"));
+        // The unit-test build has no server blocks: `server_name` is
+        // empty, so the named request is the default server's.
+        assert!(status.contains("nginx_vts_server_requests_total{zone=\"default\"} 1"));
+    }
+
+    #[test]
+    fn test_self_monitored_scrape_adds_its_body_to_bytes_out() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        update_server_zone_stats("default", 200, 100, 1_000, 5);
+
+        // A scrape as the status handler renders it, then as the
+        // LOG_PHASE handler hands it over under `vts_self_monitor on`.
+        let body_len = unsafe { std::ffi::CStr::from_ptr(ngx_http_vts_get_status()) }
+            .to_bytes()
+            .len() as u64;
+        let header_len = 150;
+        let mut c: ngx_connection_t = unsafe { std::mem::zeroed() };
+        c.sent = (header_len + body_len) as _;
+        let mut host = *b"status.example.com";
+        let mut r: ngx_http_request_t = unsafe { std::mem::zeroed() };
+        r.main = std::ptr::addr_of_mut!(r);
+        r.connection = std::ptr::addr_of_mut!(c);
+        r.headers_in.server = ngx_str_t {
+            len: host.len(),
+            data: host.as_mut_ptr(),
+        };
+        r.headers_out.status = 200;
+        r.header_size = header_len as _;
+        r.request_length = 80;
+        unsafe { vts_log_server_request(&r, 0, 0) };
+
+        // The unit-test build has no server blocks, so the scrape is the
+        // default server's.
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"default\"} 2"));
+        assert!(content.contains(&format!(
+            "nginx_vts_server_bytes_total{{zone=\"default\",direction=\"out\"}} {}",
+            1_000 + header_len + body_len
+        )));
+        assert!(content.contains(&format!(
+            "nginx_vts_server_response_header_bytes_total{{zone=\"default\"}} {header_len}"
+        )));
+        assert!(content.contains(&format!(
+            "nginx_vts_server_response_body_bytes_total{{zone=\"default\"}} {}",
+            1_000 + body_len
+        )));
+    }
+
+    #[test]
+    fn test_server_apdex_buckets_request_times() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        vts_set_apdex_threshold_ms(500);
+
+        // T = 500ms: two satisfied (<= T), two tolerating (<= 4T), one
+        // frustrated.
+        for ms in [100, 500, 1_500, 2_000, 2_500] {
+            update_server_zone_stats("apdex.example.com", 200, 10, 10, ms);
+        }
+        // A zone seeded with no traffic gets no score.
+        VTS_MANAGER
+            .write()
+            .unwrap_or_else(recover_poisoned)
+            .stats
+            .insert(
+                "idle.example.com".to_string(),
+                crate::shm::ServerCounters::new(),
+            );
+
+        let content = validated_status_content();
+        vts_set_apdex_threshold_ms(0);
+        assert!(content.contains("# TYPE nginx_vts_server_apdex gauge"));
+        // (2 + 2 / 2) / 5
+        assert!(content.contains("nginx_vts_server_apdex{zone=\"apdex.example.com\"} 0.600000"));
+        assert!(!content.contains("nginx_vts_server_apdex{zone=\"idle.example.com\"}"));
+    }
+
+    /// Run `?{args}` through the control FFI as the status handler does.
+    fn control(args: &str) -> (u16, String) {
+        let mut status = 0;
+        let body = unsafe { ngx_http_vts_control(args.as_ptr(), args.len(), &mut status) };
+        assert!(!body.is_null(), "{args} is not a control command");
+        let body = unsafe { std::ffi::CStr::from_ptr(body) };
+        (status, body.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_requests_per_connection_is_zone_requests_over_handled() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        for _ in 0..6 {
+            update_server_zone_stats("a.example.com", 200, 10, 20, 5);
+        }
+        for _ in 0..3 {
+            update_server_zone_stats("b.example.com", 404, 10, 20, 5);
+        }
+        update_connection_stats(2, 0, 1, 1, 4, 4);
+        // Without the stub_status atomics, `handled` is a stand-in for
+        // the active connections: no sample.
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_requests_per_connection gauge\n"));
+        assert!(!content.contains("\nnginx_vts_requests_per_connection "));
+
+        connection_stats::set_test_available(true);
+        let content = validated_status_content();
+        // 9 requests over 4 handled connections.
+        assert!(content.contains("nginx_vts_requests_per_connection 2.250000\n"));
+
+        update_connection_stats(0, 0, 0, 0, 0, 0);
+        let content = validated_status_content();
+        connection_stats::set_test_available(false);
+        assert!(content.contains("# TYPE nginx_vts_requests_per_connection gauge\n"));
+        assert!(!content.contains("\nnginx_vts_requests_per_connection "));
+    }
+
+    /// Seed one series in every resettable group.
+    fn seed_every_group() {
+        update_server_zone_stats("reset.example.com", 200, 10, 20, 5);
+        update_upstream_zone_stats("reset_backend", "10.0.0.1:80", 5, 3, 10, 20, 200);
+        update_cache_stats("reset_cache", "HIT", None);
+        update_connection_stats(7, 1, 2, 4, 100, 100);
+    }
+
+    #[test]
+    fn test_control_reset_server_keeps_other_groups() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        CACHE_MANAGER.clear();
+        seed_every_group();
+
+        assert_eq!(
+            control("control=reset&group=server"),
+            (200, "reset: server (1 zone)\n".to_string())
+        );
+
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"reset.example.com\"} 0"));
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"reset_backend\",server=\"10.0.0.1:80\"} 1"
+        ));
+        assert!(content
+            .contains("nginx_vts_cache_requests_total{zone=\"reset_cache\",status=\"hit\"} 1"));
+        assert!(content.contains("nginx_vts_connections{state=\"active\"} 7"));
+    }
+
+    #[test]
+    fn test_control_reset_upstream_keeps_other_groups() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        CACHE_MANAGER.clear();
+        seed_every_group();
+
+        assert_eq!(
+            control("control=reset&group=upstream"),
+            (200, "reset: upstream (1 server)\n".to_string())
+        );
+
+        let content = validated_status_content();
+        // The configured server stays, with zeroed counters.
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"reset_backend\",server=\"10.0.0.1:80\"} 0"
+        ));
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"reset.example.com\"} 1"));
+        assert!(content
+            .contains("nginx_vts_cache_requests_total{zone=\"reset_cache\",status=\"hit\"} 1"));
+    }
+
+    #[test]
+    fn test_control_reset_cache_keeps_other_groups() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        CACHE_MANAGER.clear();
+        seed_every_group();
+        update_cache_size("reset_cache", 1048576, 524288);
 
-        // Test the integrated VTS status with upstream stats
+        assert_eq!(
+            control("control=reset&group=cache"),
+            (200, "reset: cache (1 zone)\n".to_string())
+        );
 
-        // Create completely fresh manager state for this test to avoid race conditions
-        {
-            let mut manager = match VTS_MANAGER.write() {
-                Ok(guard) => guard,
-                Err(poisoned) => poisoned.into_inner(),
-            };
+        let content = validated_status_content();
+        // Counters are zeroed in place; the size gauges stay.
+        assert!(content
+            .contains("nginx_vts_cache_requests_total{zone=\"reset_cache\",status=\"hit\"} 0\n"));
+        assert!(content
+            .contains("nginx_vts_cache_size_bytes{zone=\"reset_cache\",type=\"max\"} 1048576\n"));
+        assert!(content
+            .contains("nginx_vts_cache_size_bytes{zone=\"reset_cache\",type=\"used\"} 524288\n"));
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"reset.example.com\"} 1"));
+        assert!(content.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"reset_backend\",server=\"10.0.0.1:80\"} 1"
+        ));
+    }
 
-            // Complete reset to ensure deterministic test state
-            *manager = VtsStatsManager::new();
-        }
+    #[test]
+    fn test_control_reset_connections_keeps_other_groups() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        CACHE_MANAGER.clear();
+        seed_every_group();
 
-        // Set up connection statistics for the test
-        update_connection_stats(1, 0, 1, 0, 16, 16);
+        assert_eq!(
+            control("control=reset&group=connections"),
+            (200, "reset: connections\n".to_string())
+        );
 
-        // Add some sample server zone data with unique identifiers for this test
-        update_server_zone_stats("test1-example.com", 200, 1024, 2048, 150);
-        update_server_zone_stats("test1-example.com", 404, 512, 256, 80);
-        update_server_zone_stats("test1-api.example.com", 200, 2048, 4096, 200);
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_connections{state=\"active\"} 0"));
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"reset.example.com\"} 1"));
+        assert!(content
+            .contains("nginx_vts_cache_requests_total{zone=\"reset_cache\",status=\"hit\"} 1"));
+    }
 
-        // Add some upstream stats with unique identifiers for this test
-        update_upstream_zone_stats(
-            "test1-backend_pool",
-            "192.168.1.10:80",
-            100,
-            50,
-            1500,
-            800,
-            200,
-        );
-        update_upstream_zone_stats(
-            "test1-backend_pool",
-            "192.168.1.11:80",
-            150,
-            75,
-            2000,
-            1000,
-            200,
-        );
-        update_upstream_zone_stats(
-            "test1-backend_pool",
-            "192.168.1.10:80",
-            120,
-            60,
-            1200,
-            600,
-            404,
-        );
+    #[test]
+    fn test_control_invalid_group_is_a_bad_request() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        CACHE_MANAGER.clear();
+        seed_every_group();
+
+        let (status, body) = control("control=reset&group=bogus");
+        assert_eq!(status, 400);
+        assert!(body.starts_with("invalid group \"bogus\""));
+
+        // Nothing was reset, and plain scrapes are not commands.
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"reset.example.com\"} 1"));
+        let mut status = 0;
+        let none = unsafe { ngx_http_vts_control(b"x=1".as_ptr(), 3, &mut status) };
+        assert!(none.is_null());
+    }
 
-        update_upstream_zone_stats("test1-api_pool", "192.168.2.10:8080", 80, 40, 800, 400, 200);
-        update_upstream_zone_stats(
-            "test1-api_pool",
-            "192.168.2.11:8080",
-            300,
-            200,
-            3000,
-            1500,
-            500,
+    #[test]
+    fn test_control_selftest_flags_inconsistent_counters() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+        seed_every_group();
+
+        let (status, body) = control("control=selftest");
+        assert_eq!(status, 200, "{body}");
+        assert_eq!(body, "selftest: pass (2 checked)\n");
+
+        // More 5xx than the server ever handled.
+        VTS_MANAGER
+            .write()
+            .unwrap()
+            .get_upstream_zone_mut("reset_backend")
+            .unwrap()
+            .get_or_create_server("10.0.0.1:80")
+            .responses
+            .status_5xx = 5;
+        let (status, body) = control("control=selftest");
+        assert_eq!(status, 500);
+        assert_eq!(
+            body,
+            "selftest: fail (2 checked, 1 violation)\n\
+             upstream server \"reset_backend/10.0.0.1:80\": status classes sum to 6, above 1 requests\n"
         );
+    }
 
-        // Generate VTS status content
-        let status_content = generate_vts_status_content();
+    #[test]
+    fn test_expose_zones_leaves_unlisted_zones_off_the_page() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
 
-        // Verify basic structure
-        assert!(status_content.contains("# nginx-vts-rust"));
-        assert!(status_content.contains("# VTS Status: Active"));
+        for host in ["a.example.com", "b.example.com", "c.example.com"] {
+            update_server_zone_stats(host, 200, 10, 20, 5);
+            record_content_type(host, b"text/html");
+        }
+        for host in ["a.example.com", "b.example.com"] {
+            unsafe { vts_expose_zone_ffi(host.as_ptr(), host.len()) };
+        }
+        let content = validated_status_content();
+        vts_clear_exposed_zones();
 
-        // Server zones information is now only in Prometheus metrics
-        // (removed duplicate summary section)
+        for host in ["a.example.com", "b.example.com"] {
+            assert!(content.contains(&format!(
+                "nginx_vts_server_requests_total{{zone=\"{host}\"}} 1\n"
+            )));
+        }
+        assert!(!content.contains("c.example.com"));
 
-        // Verify Prometheus metrics section exists
-        assert!(status_content.contains("# Prometheus Metrics:"));
-        assert!(status_content.contains("nginx_vts_upstream_requests_total"));
-        assert!(status_content.contains("nginx_vts_upstream_responses_total"));
+        // An empty list exposes every zone again.
+        assert!(validated_status_content()
+            .contains("nginx_vts_server_requests_total{zone=\"c.example.com\"} 1\n"));
+    }
 
-        // Verify specific upstream metrics with test-unique identifiers
-        assert!(status_content.contains("test1-backend_pool"));
-        assert!(status_content.contains("192.168.1.10:80"));
-        assert!(status_content.contains("192.168.1.11:80"));
-        assert!(status_content.contains("test1-api_pool"));
+    #[test]
+    fn test_expose_zones_applies_to_every_rendering() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
 
-        println!("=== Generated VTS Status Content ===");
-        println!("{}", status_content);
-        println!("=== End VTS Status Content ===");
+        for host in ["a.example.com", "b.example.com", "c.example.com"] {
+            update_server_zone_stats(host, 200, 10, 20, 5);
+        }
+        // The status handler fills the list before any control dispatch.
+        for host in ["a.example.com", "b.example.com"] {
+            unsafe { vts_expose_zone_ffi(host.as_ptr(), host.len()) };
+        }
+        let (_, top) = control("top=10");
+        let (_, influx) = control("format=influx");
+        let (_, selftest) = control("control=selftest");
+        vts_clear_exposed_zones();
+
+        for body in [&top, &influx] {
+            assert!(body.contains("a.example.com"), "{body}");
+            assert!(body.contains("b.example.com"), "{body}");
+            assert!(!body.contains("c.example.com"), "{body}");
+        }
+        assert_eq!(selftest, "selftest: pass (2 checked)\n");
+
+        let (_, top) = control("top=10");
+        assert!(top.contains("c.example.com"), "{top}");
     }
 
     #[test]
-    fn test_issue6_complete_metrics_output() {
+    fn test_minimal_status_fallback_keeps_info_and_connections() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
 
-        // Create completely fresh manager state for this test
-        {
-            let mut manager = match VTS_MANAGER.write() {
-                Ok(guard) => guard,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            *manager = VtsStatsManager::new();
+        update_connection_stats(3, 1, 1, 1, 50, 50);
+        for i in 0..64 {
+            update_server_zone_stats(&format!("big{i}.example.com"), 200, 10, 20, 5);
         }
+        let full = validated_status_content();
+
+        // What the handler sends when allocating `full` fails.
+        let minimal = unsafe { std::ffi::CStr::from_ptr(ngx_http_vts_get_minimal_status()) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        crate::prometheus::validate_prometheus(&minimal).unwrap();
+        assert!(minimal.contains("# VTS Status: Truncated"));
+        assert!(minimal.contains("nginx_vts_info{hostname=\"test-hostname\""));
+        assert!(minimal.contains("nginx_vts_connections{state=\"active\"} 3"));
+        assert!(minimal.contains("nginx_vts_connections_total{state=\"accepted\"} 50"));
+        assert!(!minimal.contains("nginx_vts_server_"));
+        assert!(minimal.len() * 10 < full.len());
+    }
 
-        // Set up test data similar to ISSUE6.md requirements with unique identifiers
-        update_connection_stats(1, 0, 1, 0, 16, 16);
-        update_server_zone_stats("test2-example.com", 200, 50000, 2000000, 125);
-        update_server_zone_stats("test2-example.com", 404, 5000, 100000, 50);
-        update_upstream_zone_stats(
-            "test2-backend",
-            "10.0.0.1:8080",
-            50,
-            25,
-            750000,
-            250000,
-            200,
-        );
-        update_upstream_zone_stats(
-            "test2-backend",
-            "10.0.0.2:8080",
-            60,
-            30,
-            680000,
-            230000,
-            200,
-        );
-        update_upstream_zone_stats(
-            "test2-api_backend",
-            "192.168.1.10:9090",
-            80,
-            40,
-            400000,
-            200000,
-            200,
-        );
+    #[test]
+    fn test_method_status_cross_tab_only_when_recorded() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
 
-        let content = generate_vts_status_content();
+        update_server_zone_stats("api.example.com", 200, 10, 20, 5);
+        assert!(!validated_status_content().contains("server_method_status_total"));
 
-        println!("=== ISSUE6 Complete Metrics Output ===");
-        println!("{}", content);
-        println!("=== End ISSUE6 Output ===");
+        let zone = std::ffi::CString::new("api.example.com").unwrap();
+        unsafe {
+            vts_track_method_status_ffi(zone.as_ptr(), 0x2, 200);
+            vts_track_method_status_ffi(zone.as_ptr(), 0x2, 204);
+            vts_track_method_status_ffi(zone.as_ptr(), 0x8, 502);
+            vts_track_method_status_ffi(zone.as_ptr(), 0x100, 404);
+        }
 
-        // Verify nginx_vts_info metric
-        assert!(content.contains("# HELP nginx_vts_info Nginx VTS module information"));
-        assert!(content.contains("# TYPE nginx_vts_info gauge"));
-        assert!(content.contains("nginx_vts_info{hostname="));
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_server_method_status_total counter"));
+        assert!(content.contains(
+            "nginx_vts_server_method_status_total{zone=\"api.example.com\",method=\"GET\",status=\"2xx\"} 2"
+        ));
+        assert!(content.contains(
+            "nginx_vts_server_method_status_total{zone=\"api.example.com\",method=\"POST\",status=\"5xx\"} 1"
+        ));
+        assert!(content.contains(
+            "nginx_vts_server_method_status_total{zone=\"api.example.com\",method=\"OTHER\",status=\"4xx\"} 1"
+        ));
+        // Empty cells are left out.
+        assert!(!content.contains("method=\"GET\",status=\"5xx\""));
+    }
 
-        // Verify connection metrics
-        assert!(content.contains("# HELP nginx_vts_connections Current nginx connections"));
-        assert!(content.contains("nginx_vts_connections{state=\"active\"} 1"));
-        assert!(content.contains("nginx_vts_connections{state=\"writing\"} 1"));
-        assert!(content.contains("nginx_vts_connections_total{state=\"accepted\"} 16"));
-        assert!(content.contains("nginx_vts_connections_total{state=\"handled\"} 16"));
+    #[test]
+    fn test_upstream_server_limit_aggregates_the_last_servers() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
 
-        // Verify server zone metrics with test-unique identifiers
-        assert!(content.contains("# HELP nginx_vts_server_requests_total Total number of requests"));
-        assert!(content.contains("nginx_vts_server_requests_total{zone=\"test2-example.com\"}"));
-        assert!(content.contains("# HELP nginx_vts_server_bytes_total Total bytes transferred"));
-        assert!(content
-            .contains("nginx_vts_server_bytes_total{zone=\"test2-example.com\",direction=\"in\"}"));
+        for (server, requests) in [("10.0.0.1:80", 3), ("10.0.0.2:80", 1), ("10.0.0.3:80", 2)] {
+            for _ in 0..requests {
+                update_upstream_zone_stats("limited", server, 10, 5, 100, 50, 200);
+            }
+        }
+        vts_set_upstream_server_limit(2);
+        let content = validated_status_content();
+        vts_set_upstream_server_limit(0);
+
+        for (server, requests) in [
+            ("10.0.0.1:80", 3),
+            ("10.0.0.2:80", 1),
+            (upstream_stats::AGGREGATED_SERVER, 2),
+        ] {
+            assert!(
+                content.contains(&format!(
+                    "nginx_vts_upstream_requests_total{{upstream=\"limited\",server=\"{server}\"}} {requests}\n"
+                )),
+                "{server}"
+            );
+        }
+        assert!(!content.contains("server=\"10.0.0.3:80\""));
+    }
+
+    #[test]
+    fn test_upgrades_are_counted_apart_from_1xx() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let zone = std::ffi::CString::new("ws.example.com").unwrap();
+        unsafe {
+            vts_server_connection_transition_ffi(zone.as_ptr(), 0, 1);
+            vts_server_connection_transition_ffi(zone.as_ptr(), 1, 2);
+            vts_server_connection_transition_ffi(zone.as_ptr(), 2, 3);
+        }
+        update_server_zone_stats("ws.example.com", 101, 300, 150, 5);
+        update_server_zone_stats("ws.example.com", 100, 10, 20, 5);
+
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_server_upgrades_total{zone=\"ws.example.com\"} 1\n"));
         assert!(content.contains(
-            "nginx_vts_server_bytes_total{zone=\"test2-example.com\",direction=\"out\"}"
+            "nginx_vts_server_responses_total{zone=\"ws.example.com\",status=\"1xx\"} 1\n"
+        ));
+        assert!(
+            content.contains("nginx_vts_server_websocket_connections{zone=\"ws.example.com\"} 1\n")
+        );
+        assert!(content.contains(
+            "nginx_vts_server_connections{zone=\"ws.example.com\",state=\"active\"} 1\n"
+        ));
+
+        unsafe { vts_server_connection_transition_ffi(zone.as_ptr(), 3, 0) };
+        let content = validated_status_content();
+        assert!(
+            content.contains("nginx_vts_server_websocket_connections{zone=\"ws.example.com\"} 0\n")
+        );
+    }
+
+    #[test]
+    fn test_goodput_excludes_error_response_bytes() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        for (status, bytes_out) in [(200, 1000), (302, 200), (404, 50), (503, 4000)] {
+            update_server_zone_stats("shop.example.com", status, 10, bytes_out, 5);
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_server_bytes_total{zone=\"shop.example.com\",direction=\"out\"} 5250\n"
+        ));
+        assert!(content
+            .contains("nginx_vts_server_goodput_bytes_total{zone=\"shop.example.com\"} 1200\n"));
+    }
+
+    #[test]
+    fn test_206_and_304_are_counted_alongside_their_class() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        for status in [200, 206, 206, 301, 304] {
+            update_server_zone_stats("media.example.com", status, 10, 20, 5);
+            update_upstream_zone_stats("media", "10.0.0.9:80", 5, 3, 20, 10, status);
+        }
+
+        let content = validated_status_content();
+        for (family, status, count) in [
+            ("responses_total", "2xx", 3),
+            ("responses_detail_total", "206", 2),
+            ("responses_total", "3xx", 2),
+            ("responses_detail_total", "304", 1),
+        ] {
+            assert!(
+                content.contains(&format!(
+                    "nginx_vts_server_{family}{{zone=\"media.example.com\",status=\"{status}\"}} {count}\n"
+                )),
+                "server {status}"
+            );
+            assert!(
+                content.contains(&format!(
+                    "nginx_vts_upstream_{family}{{upstream=\"media\",server=\"10.0.0.9:80\",status=\"{status}\"}} {count}\n"
+                )),
+                "upstream {status}"
+            );
+        }
+        // The class families sum to the requests again.
+        assert!(!content.contains(
+            "nginx_vts_server_responses_total{zone=\"media.example.com\",status=\"206\"}"
         ));
+    }
 
-        // Verify upstream metrics are still present with test-unique identifiers
+    #[test]
+    fn test_grpc_status_is_counted_apart_from_http_status() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        // A plain HTTP response: no grpc-status, no breakdown.
+        update_server_zone_stats("grpc.example.com", 200, 10, 20, 5);
+        let zone = c"grpc.example.com";
+        unsafe { vts_track_grpc_status_ffi(zone.as_ptr(), std::ptr::null(), 0) };
+        record_grpc_status("grpc.example.com", b"");
+        assert!(!validated_status_content().contains("server_grpc_responses_total"));
+
+        // UNAVAILABLE, wrapped in HTTP 200 like nearly every gRPC error.
+        update_server_zone_stats("grpc.example.com", 200, 10, 20, 5);
+        unsafe { vts_track_grpc_status_ffi(zone.as_ptr(), b"14".as_ptr(), 2) };
+        update_server_zone_stats("grpc.example.com", 200, 10, 20, 5);
+        record_grpc_status("grpc.example.com", b"0");
+        // Not a canonical code.
+        record_grpc_status("grpc.example.com", b"99");
+
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_server_grpc_responses_total counter"));
+        for (name, count) in [("UNAVAILABLE", 1), ("OK", 1), ("UNKNOWN", 1)] {
+            assert!(
+                content.contains(&format!(
+                    "nginx_vts_server_grpc_responses_total{{zone=\"grpc.example.com\",grpc_status=\"{name}\"}} {count}\n"
+                )),
+                "{name}"
+            );
+        }
+        assert!(!content.contains("grpc_status=\"CANCELLED\""));
         assert!(content.contains(
-            "nginx_vts_upstream_requests_total{upstream=\"test2-backend\",server=\"10.0.0.1:8080\"}"
+            "nginx_vts_server_responses_total{zone=\"grpc.example.com\",status=\"2xx\"} 3\n"
         ));
-        assert!(content.contains("nginx_vts_upstream_requests_total{upstream=\"test2-api_backend\",server=\"192.168.1.10:9090\"}"));
     }
 
+    #[cfg(feature = "unique-clients")]
     #[test]
-    fn test_vts_stats_persistence() {
+    fn test_unique_clients_estimate_is_within_error_bounds() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
 
-        // Test that stats persist across multiple updates
+        assert!(!validated_status_content().contains("server_unique_clients_estimate"));
 
-        // Create completely fresh manager state for this test
-        {
-            let mut manager = match VTS_MANAGER.write() {
-                Ok(guard) => guard,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            *manager = VtsStatsManager::new();
+        // 5000 distinct addresses, each seen three times.
+        let zone = c"example.com";
+        for _ in 0..3 {
+            for n in 0..5_000u32 {
+                let addr = std::net::Ipv4Addr::from(0xc0a8_0000 + n).to_string();
+                unsafe { vts_track_unique_client_ffi(zone.as_ptr(), addr.as_ptr(), addr.len()) };
+            }
         }
+        unsafe { vts_track_unique_client_ffi(zone.as_ptr(), std::ptr::null(), 0) };
+
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_server_unique_clients_estimate gauge"));
+        let estimate: f64 = content
+            .lines()
+            .find_map(|line| {
+                line.strip_prefix("nginx_vts_server_unique_clients_estimate{zone=\"example.com\"} ")
+            })
+            .expect("estimate for example.com")
+            .parse()
+            .unwrap();
+        // Three standard errors of a 4096-register sketch.
+        let bound = 5_000.0 * 3.0 * 1.04 / 64.0;
+        assert!((estimate - 5_000.0).abs() <= bound, "estimate = {estimate}");
+    }
 
-        let initial_content = generate_vts_status_content();
-        let _initial_backend_requests = if initial_content.contains("test3-persistence_backend") {
-            1
-        } else {
-            0
-        };
-
-        // Add stats - two requests to same server, one request to different server with unique identifiers
-        update_upstream_zone_stats(
-            "test3-persistence_backend",
-            "10.0.0.1:80",
-            100,
-            50,
-            1000,
-            500,
-            200,
-        );
-        update_upstream_zone_stats(
-            "test3-persistence_backend",
-            "10.0.0.1:80",
-            120,
-            60,
-            1200,
-            600,
-            200,
-        );
-        update_upstream_zone_stats(
-            "test3-persistence_backend",
-            "10.0.0.2:80",
-            80,
-            40,
-            800,
-            400,
-            200,
-        );
+    #[test]
+    fn test_content_type_breakdown_by_primary_type() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
 
-        let content1 = generate_vts_status_content();
-        assert!(content1.contains("test3-persistence_backend"));
+        update_server_zone_stats("cdn.example.com", 200, 10, 20, 5);
+        assert!(!validated_status_content().contains("server_responses_by_type_total"));
 
-        let content2 = generate_vts_status_content();
-        // Verify metrics are present (no longer check summary format)
-        assert!(content2.contains("nginx_vts_upstream_requests_total"));
+        let zone = std::ffi::CString::new("cdn.example.com").unwrap();
+        for content_type in [
+            &b"application/json"[..],
+            b"Application/JSON; charset=utf-8",
+            b"image/png",
+            b"font/woff2",
+        ] {
+            unsafe {
+                vts_track_content_type_ffi(
+                    zone.as_ptr(),
+                    content_type.as_ptr(),
+                    content_type.len(),
+                );
+            }
+        }
+        unsafe { vts_track_content_type_ffi(zone.as_ptr(), std::ptr::null(), 0) };
+
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_server_responses_by_type_total counter"));
+        for (kind, count) in [("application", 2), ("image", 1), ("text", 0), ("other", 2)] {
+            assert!(
+                content.contains(&format!(
+                    "nginx_vts_server_responses_by_type_total{{zone=\"cdn.example.com\",type=\"{kind}\"}} {count}\n"
+                )),
+                "{kind}"
+            );
+        }
+    }
 
-        // Verify final state (deterministic since we reset the manager)
-        let manager = VTS_MANAGER
-            .read()
+    #[test]
+    fn test_client_body_errors_are_split_by_reason() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
 
-        // Check that the upstream zone exists and has servers with test-unique identifiers
-        let backend_zone = manager.get_upstream_zone("test3-persistence_backend");
-        assert!(backend_zone.is_some(), "Backend zone should exist");
-
-        let zone = backend_zone.unwrap();
-        assert!(
-            zone.servers.contains_key("10.0.0.1:80"),
-            "Server 10.0.0.1:80 should exist"
-        );
-        assert!(
-            zone.servers.contains_key("10.0.0.2:80"),
-            "Server 10.0.0.2:80 should exist"
-        );
+        let zone = std::ffi::CString::new("upload.example.com").unwrap();
+        unsafe {
+            vts_track_client_body_error_ffi(zone.as_ptr(), 408);
+            vts_track_client_body_error_ffi(zone.as_ptr(), 408);
+            vts_track_client_body_error_ffi(zone.as_ptr(), 400);
+            // Not a body read failure.
+            vts_track_client_body_error_ffi(zone.as_ptr(), 413);
+        }
 
-        // Verify total requests across both servers (should be 3: 2 + 1)
-        let total_requests: u64 = zone.servers.values().map(|s| s.request_counter).sum();
-        assert_eq!(total_requests, 3, "Total requests should be 3 (2 + 1)");
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_server_client_body_errors_total counter"));
+        assert!(content.contains(
+            "nginx_vts_server_client_body_errors_total{zone=\"upload.example.com\",reason=\"timeout\"} 2\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_server_client_body_errors_total{zone=\"upload.example.com\",reason=\"malformed\"} 1\n"
+        ));
     }
 
     #[test]
-    fn test_empty_vts_stats() {
-        // Test VTS status generation with empty stats
-        // Note: This may not be truly empty if other tests have run first
-        let content = generate_vts_status_content();
-
-        // Should still have basic structure
-        assert!(content.contains("# nginx-vts-rust"));
-        assert!(content.contains("# VTS Status: Active"));
-        assert!(content.contains("# Prometheus Metrics:"));
+    fn test_request_header_size_lands_in_its_bucket() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
 
-        // Should always output server metrics headers, even if no data
-        assert!(content.contains("# HELP nginx_vts_server_requests_total Total number of requests"));
-        assert!(content.contains("# TYPE nginx_vts_server_requests_total counter"));
-        assert!(content.contains("# HELP nginx_vts_server_bytes_total Total bytes transferred"));
-        assert!(content.contains("# TYPE nginx_vts_server_bytes_total counter"));
-    }
+        update_server_zone_stats("api.example.com", 200, 10, 20, 5);
+        assert!(!validated_status_content().contains("server_request_header_bytes"));
 
-    // ---------- helpers ----------
+        // "GET / HTTP/1.1" + 20 headers of ~150 bytes: 3000 bytes.
+        let zone = std::ffi::CString::new("api.example.com").unwrap();
+        unsafe {
+            vts_track_request_headers_ffi(zone.as_ptr(), 20, 3000);
+            vts_track_request_headers_ffi(zone.as_ptr(), 3, 200);
+        }
 
-    fn reset_manager() {
-        let mut manager = match VTS_MANAGER.write() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_server_request_header_bytes histogram"));
+        let bucket = |le: &str| {
+            format!("nginx_vts_server_request_header_bytes_bucket{{zone=\"api.example.com\",le=\"{le}\"}} ")
         };
-        *manager = VtsStatsManager::new();
+        assert!(content.contains(&format!("{}1\n", bucket("512"))));
+        assert!(content.contains(&format!("{}1\n", bucket("2048"))));
+        assert!(content.contains(&format!("{}2\n", bucket("4096"))));
+        assert!(content.contains(&format!("{}2\n", bucket("+Inf"))));
+        assert!(content.contains(
+            "nginx_vts_server_request_header_bytes_sum{zone=\"api.example.com\"} 3200\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_server_request_headers_bucket{zone=\"api.example.com\",le=\"16\"} 1\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_server_request_headers_bucket{zone=\"api.example.com\",le=\"32\"} 2\n"
+        ));
+        assert!(content
+            .contains("nginx_vts_server_request_headers_count{zone=\"api.example.com\"} 2\n"));
     }
 
-    // ---------- upstream + Prometheus rendering ----------
-
     #[test]
-    fn test_bulk_upstream_requests_render_correctly() {
+    fn test_filter_by_variable_keys_by_variable_value() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         reset_manager();
 
-        for i in 0..500 {
-            let status_code = if i % 50 == 0 {
-                500
-            } else if i % 20 == 0 {
-                404
-            } else {
-                200
-            };
-            let request_time = 40 + (i % 30);
-            let upstream_time = request_time / 2;
-            update_upstream_zone_stats(
-                "backend",
-                "127.0.0.1:8080",
-                request_time,
-                upstream_time,
-                1500,
-                750,
-                status_code,
-            );
+        // What `ngx_http_vts_log_filters` hands over for each group of
+        // the location: the request and its variable's value (e.g.
+        // `$geoip2_data_country_code`).  Unset or empty values never
+        // get here.
+        let group = "country";
+        let log = |r: &ngx_http_request_t, value: &str| unsafe {
+            vts_log_filter_request(r, group.as_ptr(), group.len(), value.as_ptr(), value.len());
+        };
+        let mut ok: ngx_http_request_t = unsafe { std::mem::zeroed() };
+        ok.main = std::ptr::addr_of_mut!(ok);
+        ok.request_length = 10;
+        let mut failed: ngx_http_request_t = unsafe { std::mem::zeroed() };
+        failed.main = std::ptr::addr_of_mut!(failed);
+        failed.headers_out.status = 502;
+        // A subrequest of `ok`, which only its main request counts.
+        let mut sub: ngx_http_request_t = unsafe { std::mem::zeroed() };
+        sub.main = std::ptr::addr_of_mut!(ok);
+
+        log(&ok, "US");
+        log(&failed, "US");
+        log(&ok, "JP");
+        log(&sub, "JP");
+
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_filter_requests_total{filter=\"country\",filter_name=\"US\"} 2\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_filter_requests_total{filter=\"country\",filter_name=\"JP\"} 1\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_filter_responses_total{filter=\"country\",filter_name=\"US\",status=\"5xx\"} 1\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_filter_bytes_total{filter=\"country\",filter_name=\"JP\",direction=\"in\"} 10\n"
+        ));
+
+        // Once the group is full, further values share `__other__`
+        // while known ones keep their own series.
+        for n in 2..crate::stats::MAX_FILTER_KEYS {
+            log(&ok, &format!("C{n}"));
         }
+        log(&ok, "XX");
+        log(&ok, "YY");
+        log(&ok, "US");
 
-        let s = generate_vts_status_content();
-        assert!(s.contains(
-            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 500"
+        let content = validated_status_content();
+        assert!(content.contains(
+            "nginx_vts_filter_requests_total{filter=\"country\",filter_name=\"__other__\"} 2\n"
         ));
-        assert!(s.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 375000"));
-        assert!(s.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 750000"));
-        assert!(s.contains(
-            "nginx_vts_upstream_server_up{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
+        assert!(content.contains(
+            "nginx_vts_filter_requests_total{filter=\"country\",filter_name=\"US\"} 3\n"
         ));
+        assert!(!content.contains("filter_name=\"XX\""));
     }
 
     #[test]
-    fn test_no_upstream_metrics_until_first_request() {
+    fn test_location_zones_accumulate_independently() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         reset_manager();
 
-        let content = generate_vts_status_content();
-        // Pre-request render must NOT emit per-(upstream,server) request counters.
-        assert!(!content.contains("nginx_vts_upstream_requests_total{"));
-        // But the basic headers and module info are always present.
-        assert!(content.contains("# nginx-vts-rust"));
-        assert!(content.contains("# VTS Status: Active"));
-        assert!(content.contains("# HELP nginx_vts_info"));
+        update_server_zone_stats("example.com", 200, 10, 20, 5);
+        assert!(!validated_status_content().contains("nginx_vts_location_"));
+
+        record_location_request("api", 200, 100, 1000, 30);
+        record_location_request("api", 502, 100, 200, 50);
+        record_location_request("static", 200, 50, 8000, 2);
+        record_location_request("static", 304, 50, 0, 1);
+        record_location_request("static", 200, 50, 8000, 3);
+
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_location_requests_total counter"));
+        assert!(content.contains("nginx_vts_location_requests_total{location=\"api\"} 2\n"));
+        assert!(content.contains("nginx_vts_location_requests_total{location=\"static\"} 3\n"));
+        assert!(content
+            .contains("nginx_vts_location_bytes_total{location=\"api\",direction=\"out\"} 1200\n"));
+        assert!(content.contains(
+            "nginx_vts_location_bytes_total{location=\"static\",direction=\"out\"} 16000\n"
+        ));
+        assert!(content
+            .contains("nginx_vts_location_responses_total{location=\"api\",status=\"5xx\"} 1\n"));
+        assert!(content.contains(
+            "nginx_vts_location_responses_total{location=\"static\",status=\"5xx\"} 0\n"
+        ));
+        assert!(content.contains(
+            "nginx_vts_location_responses_total{location=\"static\",status=\"3xx\"} 1\n"
+        ));
+        // Location accounting does not touch the server zones.
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"example.com\"} 1\n"));
+        assert!(!content.contains("zone=\"api\""));
     }
 
     #[test]
-    fn test_upstream_counters_accumulate_across_requests() {
+    fn test_metric_catalog_matches_emitted_families() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         reset_manager();
+        CACHE_MANAGER.clear();
 
-        update_upstream_zone_stats("backend", "127.0.0.1:8080", 85, 42, 1024, 512, 200);
-        let after_one = generate_vts_status_content();
-        assert!(after_one.contains(
-            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
-        ));
-        assert!(after_one.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 512"));
+        fn help_lines(content: &str) -> std::collections::BTreeSet<String> {
+            content
+                .lines()
+                .filter(|line| line.starts_with("# HELP "))
+                .map(str::to_string)
+                .collect()
+        }
 
-        update_upstream_zone_stats("backend", "127.0.0.1:8080", 92, 48, 1536, 768, 200);
-        let after_two = generate_vts_status_content();
-        assert!(after_two.contains(
-            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 2"
+        // `upstream_zones_total` only appears while no upstream exists.
+        let mut emitted = help_lines(&validated_status_content());
+
+        update_server_zone_stats("example.com", 200, 10, 20, 5);
+        record_method_status("example.com", HttpMethod::Get, 200);
+        record_request_headers("example.com", 12, 900);
+        record_content_type("example.com", b"text/html");
+        record_grpc_status("example.com", b"0");
+        record_location_request("api", 200, 10, 20, 5);
+        record_filter_request("country", "US", 200, 10, 20, 5);
+        set_server_zone_enabled("paused.example.com", false);
+        update_upstream_zone_stats("backend", "10.0.0.1:80", 50, 40, 100, 200, 200);
+        track_upstream_queue("backend", true);
+        {
+            let mut manager = VTS_MANAGER
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            manager.seed_upstream_server("backend", "10.0.0.1:80");
+            manager.seed_upstream_server("backend", "10.0.0.1:80");
+        }
+        update_cache_stats("cache_zone", "HIT", None);
+        update_cache_size("cache_zone", 1024, 512);
+        emitted.extend(help_lines(&validated_status_content()));
+        CACHE_MANAGER.clear();
+        // Slab usage is only read from a configured `vts_zone`.
+        emitted.extend(help_lines(
+            &crate::prometheus::PrometheusFormatter::new()
+                .format_shm_stats(&[("vts".to_string(), crate::shm::SlabUsage::default())]),
         ));
-        assert!(after_two.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 1280"));
-        assert!(after_two.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 2560"));
-        assert!(after_two.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"2xx\"} 2"));
+
+        let catalog = crate::prometheus::metric_catalog();
+        crate::prometheus::validate_prometheus(&catalog).unwrap();
+        assert_eq!(help_lines(&catalog), emitted);
     }
 
     #[test]
-    fn test_vts_track_upstream_request_ffi_records_into_state() {
+    fn test_subrequests_do_not_inflate_server_requests() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         reset_manager();
 
-        let upstream_name = std::ffi::CString::new("backend").unwrap();
-        let server_addr = std::ffi::CString::new("127.0.0.1:8080").unwrap();
-
+        let zone = c"ssi.example.com";
         unsafe {
-            vts_track_upstream_request(
-                upstream_name.as_ptr(),
-                server_addr.as_ptr(),
-                1000,
-                500,
-                38,
-                2048,
-                1024,
-                200,
-            );
+            vts_update_server_stats_ffi(zone.as_ptr(), 200, 100, 2000, 0, 15, 0, 1);
+            // Two SSI includes of that page.
+            vts_update_server_stats_ffi(zone.as_ptr(), 200, 0, 300, 0, 5, 0, 0);
+            vts_update_server_stats_ffi(zone.as_ptr(), 200, 0, 300, 0, 5, 0, 0);
         }
 
-        let content = generate_vts_status_content();
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"ssi.example.com\"} 1"));
+        assert!(content.contains("nginx_vts_server_subrequests_total{zone=\"ssi.example.com\"} 2"));
         assert!(content.contains(
-            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
+            "nginx_vts_server_responses_total{zone=\"ssi.example.com\",status=\"2xx\"} 1"
         ));
-        assert!(content.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 1024"));
-        assert!(content.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 2048"));
+        assert!(content.contains(
+            "nginx_vts_server_bytes_total{zone=\"ssi.example.com\",direction=\"out\"} 2000"
+        ));
+    }
+
+    #[test]
+    fn test_long_zone_names_are_truncated_and_counted() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        let content = validated_status_content();
+        assert!(content.contains("nginx_vts_label_truncations 0"));
+
+        // 2-byte characters so a plain byte cut would split one.
+        let long = format!("{}.example.com", "é".repeat(100));
+        update_server_zone_stats(&long, 200, 10, 20, 5);
+        update_server_zone_stats("short.example.com", 200, 10, 20, 5);
+
+        let content = validated_status_content();
+        assert!(!content.contains(&long));
+        assert!(content.contains("nginx_vts_label_truncations 1"));
+        let line = content
+            .lines()
+            .find(|line| line.starts_with("nginx_vts_server_requests_total{zone=\"é"))
+            .unwrap();
+        let zone = line
+            .strip_prefix("nginx_vts_server_requests_total{zone=\"")
+            .and_then(|rest| rest.split_once('"'))
+            .map(|(zone, _)| zone)
+            .unwrap();
+        assert!(zone.len() <= DEFAULT_MAX_LABEL_LEN as usize);
+        assert!(zone.contains('…'));
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"short.example.com\"} 1"));
+
+        // A gauge of this page: nothing is cut any more, so it drops back.
+        vts_set_max_label_len(4096);
+        let content = validated_status_content();
+        assert!(content.contains(&long));
+        assert!(content.contains("# TYPE nginx_vts_label_truncations gauge\n"));
+        assert!(content.contains("nginx_vts_label_truncations 0"));
+        vts_set_max_label_len(0);
+    }
+
+    #[test]
+    fn test_log_server_request_ignores_null_request() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_manager();
+
+        unsafe { vts_log_server_request(std::ptr::null(), 0, 0) };
+
+        let manager = VTS_MANAGER
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        assert!(manager.get_all_server_stats().is_empty());
     }
 
     #[test]
-    fn test_initialize_upstream_zones_seeds_zero_metrics() {
+    fn test_consecutive_scrapes_are_byte_identical() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         reset_manager();
+        CACHE_MANAGER.clear();
 
-        // Before init: no per-server series.
-        let before = generate_vts_status_content();
-        assert!(before.contains("nginx_vts_upstream_zones_total 0"));
+        for i in 0..16 {
+            update_server_zone_stats(&format!("host{i}.example.com"), 200, 10, 20, 5);
+            update_cache_stats(&format!("cache{i}"), "HIT", None);
+        }
 
-        initialize_upstream_zones_for_testing();
-        let after = generate_vts_status_content();
+        let first = validated_status_content();
+        let second = validated_status_content();
+        assert_eq!(first, second);
 
-        // Should now render the configured backend with zero counters.
-        assert!(after.contains(
-            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 0"
-        ));
-        assert!(after.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 0"));
-        assert!(after.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 0"));
-        assert!(after.contains(
-            "nginx_vts_upstream_server_up{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
-        ));
-        // All status-class buckets exist with 0.
-        for class in ["1xx", "2xx", "3xx", "4xx", "5xx"] {
-            let expected = format!(
-                "nginx_vts_upstream_responses_total{{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"{}\"}} 0",
-                class
-            );
-            assert!(after.contains(&expected), "missing {class}");
-        }
+        CACHE_MANAGER.clear();
     }
 
     #[test]
-    fn test_status_response_includes_help_and_type_headers() {
+    fn test_streamed_body_bytes_are_not_counted_twice() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         reset_manager();
-        initialize_upstream_zones_for_testing();
 
-        let content = generate_vts_status_content();
-        for header in [
-            "# HELP nginx_vts_upstream_requests_total Total upstream requests",
-            "# TYPE nginx_vts_upstream_requests_total counter",
-            "# HELP nginx_vts_upstream_bytes_total Total bytes transferred to/from upstream",
-            "# HELP nginx_vts_upstream_response_seconds Upstream response time statistics",
-            "# HELP nginx_vts_upstream_server_up Upstream server status (1=up, 0=down)",
-            "# HELP nginx_vts_upstream_responses_total Upstream responses by status code",
-        ] {
-            assert!(content.contains(header), "missing header: {header}");
+        // Body filter reports three chunks of a 6000-byte response.
+        let mut streamed = 0;
+        for chunk in [1000, 2000, 3000] {
+            track_server_zone_bytes_out("stream.example.com", chunk);
+            streamed += chunk;
+
+            let status = validated_status_content();
+            assert!(status.contains(&format!(
+                "nginx_vts_server_bytes_total{{zone=\"stream.example.com\",direction=\"out\"}} {streamed}"
+            )));
+            assert!(
+                status.contains("nginx_vts_server_requests_total{zone=\"stream.example.com\"} 0")
+            );
         }
+
+        // LOG_PHASE sees the connection total and what was streamed.
+        record_server_request(
+            "stream.example.com",
+            200,
+            300,
+            6000,
+            250,
+            streamed,
+            40,
+            false,
+        );
+
+        let status = validated_status_content();
+        assert!(status.contains(
+            "nginx_vts_server_bytes_total{zone=\"stream.example.com\",direction=\"out\"} 6000"
+        ));
+        assert!(status.contains(
+            "nginx_vts_server_bytes_total{zone=\"stream.example.com\",direction=\"in\"} 300"
+        ));
+        // The header split is applied once, at LOG_PHASE.
+        assert!(status.contains(
+            "nginx_vts_server_response_header_bytes_total{zone=\"stream.example.com\"} 250"
+        ));
+        assert!(status.contains(
+            "nginx_vts_server_response_body_bytes_total{zone=\"stream.example.com\"} 5750"
+        ));
+        assert!(status.contains("nginx_vts_server_requests_total{zone=\"stream.example.com\"} 1"));
+
+        unsafe { vts_track_body_bytes(std::ptr::null(), 10) };
     }
 
     #[test]
-    fn test_complete_status_flow_init_request_then_recorded() {
+    fn test_header_and_body_bytes_add_up_to_bytes_out() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         reset_manager();
-        initialize_upstream_zones_for_testing();
-
-        // Step 1: fresh status → zero counters.
-        let first = generate_vts_status_content();
-        assert!(first.contains(
-            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 0"
-        ));
 
-        // Step 2: simulate a 94ms request through the upstream.
-        update_upstream_zone_stats("backend", "127.0.0.1:8080", 94, 30, 1370, 615, 200);
+        let zone = c"split.example.com";
+        unsafe {
+            // A small page with a large Set-Cookie header.
+            vts_update_server_stats_ffi(zone.as_ptr(), 200, 100, 1200, 900, 5, 0, 1);
+            // A download whose header is noise.
+            vts_update_server_stats_ffi(zone.as_ptr(), 200, 100, 50_200, 200, 5, 0, 1);
+            // A header larger than what was sent is capped.
+            vts_update_server_stats_ffi(zone.as_ptr(), 499, 100, 40, 400, 5, 0, 1);
+        }
 
-        // Step 3: counters reflect the request.
-        let third = generate_vts_status_content();
-        assert!(third.contains(
-            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 1"
-        ));
-        assert!(third.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 615"));
-        assert!(third.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 1370"));
-        assert!(third.contains("nginx_vts_upstream_response_seconds{upstream=\"backend\",server=\"127.0.0.1:8080\",type=\"request_avg\"} 0.094000"));
-        assert!(third.contains("nginx_vts_upstream_response_seconds{upstream=\"backend\",server=\"127.0.0.1:8080\",type=\"upstream_avg\"} 0.030000"));
+        let status = validated_status_content();
+        let bytes = |series: &str| {
+            let prefix = format!("nginx_vts_{series}}} ");
+            status
+                .lines()
+                .find_map(|l| l.strip_prefix(prefix.as_str()))
+                .unwrap_or_else(|| panic!("{prefix}"))
+                .parse::<u64>()
+                .unwrap()
+        };
+        let header = bytes("server_response_header_bytes_total{zone=\"split.example.com\"");
+        let body = bytes("server_response_body_bytes_total{zone=\"split.example.com\"");
+        let out = bytes("server_bytes_total{zone=\"split.example.com\",direction=\"out\"");
+        assert_eq!(header, 900 + 200 + 40);
+        assert_eq!(body, 300 + 50_000);
+        assert_eq!(out, 51_440);
+        assert_eq!(header + body, out);
     }
 
     #[test]
-    fn test_log_phase_ffi_accumulates_across_calls() {
+    fn test_rate_limited_503_is_kept_apart_from_backend_503() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         reset_manager();
-        initialize_upstream_zones_for_testing();
-
-        let upstream_name = std::ffi::CString::new("backend").unwrap();
-        let server_addr = std::ffi::CString::new("127.0.0.1:8080").unwrap();
 
-        // Three sequential LOG_PHASE-style calls with mixed statuses.
-        let calls: &[(u64, u64, u64, u64, u16)] = &[
-            (500, 42, 1024, 512, 200),
-            (600, 55, 2048, 1024, 200),
-            (700, 48, 1536, 768, 404),
-        ];
-        for &(start_msec, response_ms, bytes_sent, bytes_received, status) in calls {
-            unsafe {
-                vts_track_upstream_request(
-                    upstream_name.as_ptr(),
-                    server_addr.as_ptr(),
-                    1000,
-                    start_msec,
-                    response_ms,
-                    bytes_sent,
-                    bytes_received,
-                    status,
-                );
-            }
+        let zone = c"limited.example.com";
+        unsafe {
+            // Backend 503 proxied through.
+            vts_update_server_stats_ffi(zone.as_ptr(), 503, 100, 200, 0, 10, 0, 1);
+            // `limit_req` rejection, also surfaced as 503.
+            vts_update_server_stats_ffi(zone.as_ptr(), 503, 100, 50, 0, 0, 1, 1);
+            vts_update_server_stats_ffi(zone.as_ptr(), 503, 100, 50, 0, 0, 1, 1);
         }
 
-        let content = generate_vts_status_content();
-        assert!(content.contains(
-            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 3"
+        let status = validated_status_content();
+        assert!(status.contains("nginx_vts_server_requests_total{zone=\"limited.example.com\"} 3"));
+        assert!(status.contains(
+            "nginx_vts_server_responses_total{zone=\"limited.example.com\",status=\"5xx\"} 1"
         ));
-        // 512 + 1024 + 768 = 2304 in / 1024 + 2048 + 1536 = 4608 out.
-        assert!(content.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"in\"} 2304"));
-        assert!(content.contains("nginx_vts_upstream_bytes_total{upstream=\"backend\",server=\"127.0.0.1:8080\",direction=\"out\"} 4608"));
-        // 2 × 2xx + 1 × 4xx.
-        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"2xx\"} 2"));
-        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"4xx\"} 1"));
+        assert!(
+            status.contains("nginx_vts_server_rate_limited_total{zone=\"limited.example.com\"} 2")
+        );
     }
 
     #[test]
-    fn test_log_phase_ffi_categorises_diverse_status_codes() {
+    fn test_server_connections_balance_per_zone() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         reset_manager();
-        initialize_upstream_zones_for_testing();
 
-        let upstream_name = std::ffi::CString::new("backend").unwrap();
-        let server_addr = std::ffi::CString::new("127.0.0.1:8080").unwrap();
+        let begin = |zone: &std::ffi::CStr| unsafe {
+            vts_server_connection_transition_ffi(zone.as_ptr(), 0, 1)
+        };
+        let writing = |zone: &std::ffi::CStr| unsafe {
+            vts_server_connection_transition_ffi(zone.as_ptr(), 1, 2)
+        };
+        let end = |zone: &std::ffi::CStr, from: u8| unsafe {
+            vts_server_connection_transition_ffi(zone.as_ptr(), from, 0)
+        };
 
-        // 2 baseline 200s with extreme timings, then 8 mixed statuses.
-        let scenarios: &[(u64, u64, u64, u64, u16)] = &[
-            (0, 100, 50, 1, 200),                 // sub-ms upstream
-            (1800, 1_048_576, 2_097_152, 1, 200), // ~1.8s upstream, MBs
-            (25, 200, 100, 1, 301),
-            (25, 200, 100, 1, 302),
-            (25, 200, 100, 1, 400),
-            (25, 200, 100, 1, 401),
-            (25, 200, 100, 1, 403),
-            (25, 200, 100, 1, 500),
-            (25, 200, 100, 1, 502),
-            (25, 200, 100, 1, 503),
-        ];
-        for &(response_ms, bytes_sent, bytes_received, start_msec, status) in scenarios {
-            unsafe {
-                vts_track_upstream_request(
-                    upstream_name.as_ptr(),
-                    server_addr.as_ptr(),
-                    1000,
-                    start_msec,
-                    response_ms,
-                    bytes_sent,
-                    bytes_received,
-                    status,
-                );
+        begin(c"a.example.com");
+        begin(c"a.example.com");
+        begin(c"b.example.com");
+        writing(c"a.example.com");
+
+        let status = validated_status_content();
+        assert!(status
+            .contains("nginx_vts_server_connections{zone=\"a.example.com\",state=\"active\"} 2"));
+        assert!(status
+            .contains("nginx_vts_server_connections{zone=\"a.example.com\",state=\"reading\"} 1"));
+        assert!(status
+            .contains("nginx_vts_server_connections{zone=\"a.example.com\",state=\"writing\"} 1"));
+        assert!(status
+            .contains("nginx_vts_server_connections{zone=\"b.example.com\",state=\"active\"} 1"));
+        // Connection hooks alone never count as served requests.
+        assert!(status.contains("nginx_vts_server_requests_total{zone=\"a.example.com\"} 0"));
+
+        end(c"a.example.com", 2);
+        end(c"a.example.com", 1);
+        end(c"b.example.com", 1);
+
+        let status = validated_status_content();
+        for zone in ["a.example.com", "b.example.com"] {
+            for state in ["active", "reading", "writing"] {
+                assert!(status.contains(&format!(
+                    "nginx_vts_server_connections{{zone=\"{zone}\",state=\"{state}\"}} 0"
+                )));
             }
         }
-
-        let content = generate_vts_status_content();
-        assert!(content.contains(
-            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"127.0.0.1:8080\"} 10"
-        ));
-        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"2xx\"} 2"));
-        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"3xx\"} 2"));
-        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"4xx\"} 3"));
-        assert!(content.contains("nginx_vts_upstream_responses_total{upstream=\"backend\",server=\"127.0.0.1:8080\",status=\"5xx\"} 3"));
     }
 
-    // ---------- cache stats ----------
-
     #[test]
     fn test_cache_stats_basic_functionality() {
         let _lock = GLOBAL_VTS_TEST_MUTEX
@@ -1040,10 +5341,10 @@ mod integration_tests {
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         CACHE_MANAGER.clear();
 
-        update_cache_stats("zone1", "HIT");
-        update_cache_stats("zone1", "HIT");
-        update_cache_stats("zone1", "MISS");
-        update_cache_stats("zone1", "BYPASS");
+        update_cache_stats("zone1", "HIT", None);
+        update_cache_stats("zone1", "HIT", None);
+        update_cache_stats("zone1", "MISS", None);
+        update_cache_stats("zone1", "BYPASS", None);
         update_cache_size("zone1", 1_048_576, 524_288);
 
         let cache_zones = get_all_cache_zones();
@@ -1067,11 +5368,11 @@ mod integration_tests {
             .unwrap_or_else(|poisoned| poisoned.into_inner());
         CACHE_MANAGER.clear();
 
-        update_cache_stats("zone1", "HIT");
-        update_cache_stats("zone1", "MISS");
-        update_cache_stats("zone2", "HIT");
-        update_cache_stats("zone2", "HIT");
-        update_cache_stats("zone2", "HIT");
+        update_cache_stats("zone1", "HIT", None);
+        update_cache_stats("zone1", "MISS", None);
+        update_cache_stats("zone2", "HIT", None);
+        update_cache_stats("zone2", "HIT", None);
+        update_cache_stats("zone2", "HIT", None);
         update_cache_size("zone1", 1_048_576, 262_144);
         update_cache_size("zone2", 2_097_152, 1_572_864);
 
@@ -1107,7 +5408,7 @@ mod integration_tests {
             "REVALIDATED",
             "SCARCE",
         ] {
-            update_cache_stats("comprehensive_zone", status);
+            update_cache_stats("comprehensive_zone", status, None);
         }
 
         let cache_zones = get_all_cache_zones();
@@ -1132,12 +5433,12 @@ mod integration_tests {
         CACHE_MANAGER.clear();
         reset_manager();
 
-        update_cache_stats("test_cache", "HIT");
-        update_cache_stats("test_cache", "HIT");
-        update_cache_stats("test_cache", "MISS");
+        update_cache_stats("test_cache", "HIT", None);
+        update_cache_stats("test_cache", "HIT", None);
+        update_cache_stats("test_cache", "MISS", None);
         update_cache_size("test_cache", 1_048_576, 524_288);
 
-        let content = generate_vts_status_content();
+        let content = validated_status_content();
         assert!(content.contains("# HELP nginx_vts_cache_requests_total"));
         assert!(content.contains("# TYPE nginx_vts_cache_requests_total counter"));
         assert!(content
@@ -1152,7 +5453,97 @@ mod integration_tests {
             .contains("nginx_vts_cache_size_bytes{zone=\"test_cache\",type=\"used\"} 524288"));
         assert!(content.contains("# HELP nginx_vts_cache_hit_ratio"));
         assert!(content.contains("# TYPE nginx_vts_cache_hit_ratio gauge"));
-        assert!(content.contains("nginx_vts_cache_hit_ratio{zone=\"test_cache\"} 66.67"));
+        assert!(content.contains("nginx_vts_cache_hit_ratio{zone=\"test_cache\"} 66.666667"));
+    }
+
+    #[test]
+    fn test_cache_bytes_served_totals_cache_answered_requests() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        CACHE_MANAGER.clear();
+        reset_manager();
+
+        let zone = c"static_cache";
+        unsafe {
+            // HIT, HIT, STALE and REVALIDATED are answered from cache...
+            vts_update_cache_stats_ffi(zone.as_ptr(), std::ptr::null(), 7, 1_000, 0, 0);
+            vts_update_cache_stats_ffi(zone.as_ptr(), std::ptr::null(), 7, 2_500, 0, 0);
+            vts_update_cache_stats_ffi(zone.as_ptr(), std::ptr::null(), 4, 400, 0, 0);
+            vts_update_cache_stats_ffi(zone.as_ptr(), std::ptr::null(), 6, 100, 0, 0);
+            // ...MISS and BYPASS are not.
+            vts_update_cache_stats_ffi(zone.as_ptr(), std::ptr::null(), 1, 9_000, 0, 0);
+            vts_update_cache_stats_ffi(zone.as_ptr(), std::ptr::null(), 2, 7_000, 0, 0);
+        }
+        record_cache_hit_bytes("static_cache", 500);
+
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_cache_bytes_served_total counter"));
+        assert!(content.contains("nginx_vts_cache_bytes_served_total{zone=\"static_cache\"} 4500"));
+    }
+
+    #[test]
+    fn test_cache_requests_split_by_upstream_in_a_shared_zone() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        CACHE_MANAGER.clear();
+        reset_manager();
+
+        let zone = c"shared_cache";
+        unsafe {
+            vts_update_cache_stats_ffi(zone.as_ptr(), c"api".as_ptr(), 7, 100, 0, 0);
+            vts_update_cache_stats_ffi(zone.as_ptr(), c"api".as_ptr(), 7, 100, 0, 0);
+            vts_update_cache_stats_ffi(zone.as_ptr(), c"web".as_ptr(), 7, 100, 0, 0);
+            vts_update_cache_stats_ffi(zone.as_ptr(), c"web".as_ptr(), 1, 100, 0, 0);
+        }
+        update_cache_stats("shared_cache", "HIT", None);
+
+        let content = validated_status_content();
+        for (labels, value) in [
+            ("upstream=\"api\",status=\"hit\"", 2),
+            ("upstream=\"web\",status=\"hit\"", 1),
+            ("upstream=\"web\",status=\"miss\"", 1),
+            ("upstream=\"\",status=\"hit\"", 1),
+        ] {
+            assert!(
+                content.contains(&format!(
+                    "nginx_vts_cache_requests_total{{zone=\"shared_cache\",{labels}}} {value}\n"
+                )),
+                "{labels}"
+            );
+        }
+        // The zone-wide families are unchanged.
+        assert!(content.contains("nginx_vts_cache_hit_ratio{zone=\"shared_cache\"} 80.000000"));
+        // Other tests expect the plain per-zone series.
+        CACHE_MANAGER.clear();
+    }
+
+    #[test]
+    fn test_cache_lock_counters_leave_hit_ratio_alone() {
+        let _lock = GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        CACHE_MANAGER.clear();
+        reset_manager();
+
+        let zone = c"locked_cache";
+        unsafe {
+            // One MISS fills the key while two requests wait on the lock;
+            // one of them times out and goes upstream, the other HITs.
+            vts_update_cache_stats_ffi(zone.as_ptr(), std::ptr::null(), 1, 100, 0, 0);
+            vts_track_cache_lock_ffi(zone.as_ptr(), 0);
+            vts_update_cache_stats_ffi(zone.as_ptr(), std::ptr::null(), 7, 100, 0, 0);
+            vts_track_cache_lock_ffi(zone.as_ptr(), 1);
+            vts_update_cache_stats_ffi(zone.as_ptr(), std::ptr::null(), 2, 100, 0, 0);
+        }
+
+        let content = validated_status_content();
+        assert!(content.contains("# TYPE nginx_vts_cache_lock_waits_total counter"));
+        assert!(content.contains("# TYPE nginx_vts_cache_lock_timeouts_total counter"));
+        assert!(content.contains("nginx_vts_cache_lock_waits_total{zone=\"locked_cache\"} 2"));
+        assert!(content.contains("nginx_vts_cache_lock_timeouts_total{zone=\"locked_cache\"} 1"));
+        assert!(content.contains("nginx_vts_cache_hit_ratio{zone=\"locked_cache\"} 33.333333"));
     }
 
     #[test]
@@ -1163,7 +5554,7 @@ mod integration_tests {
         CACHE_MANAGER.clear();
         reset_manager();
 
-        let content = generate_vts_status_content();
+        let content = validated_status_content();
         // Headers always emitted, even with no recorded cache zones.
         assert!(content.contains("# HELP nginx_vts_cache_requests_total"));
         assert!(content.contains("# TYPE nginx_vts_cache_requests_total counter"));
@@ -1179,52 +5570,28 @@ pub fn initialize_upstream_zones_for_testing() {
         if let Err(e) = initialize_upstream_zones_from_config(std::ptr::null_mut()) {
             eprintln!("Failed to initialize upstream zones: {}", e);
         }
+        let (upstream, server) = ("backend", "127.0.0.1:8080");
+        vts_seed_upstream_server_ffi(
+            upstream.as_ptr(),
+            upstream.len(),
+            server.as_ptr(),
+            server.len(),
+        );
     }
 }
 
-/// Initialize upstream zones from nginx configuration  
-/// Parses nginx.conf upstream blocks and creates zero-value statistics
-unsafe fn initialize_upstream_zones_from_config(_cf: *mut ngx_conf_t) -> Result<(), &'static str> {
-    {
-        let mut manager = match VTS_MANAGER.write() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
-        };
-        // Clear any existing data to start fresh
-        manager.stats.clear();
-        manager.upstream_zones.clear();
-
-        // For now, hard-code the upstream from ISSUE3.md nginx.conf
-        // TODO: Parse actual nginx configuration
-        manager.update_upstream_stats(
-            "backend",
-            "127.0.0.1:8080",
-            0, // request_time
-            0, // upstream_response_time
-            0, // bytes_sent
-            0, // bytes_received
-            0, // status_code (no actual request yet)
-        );
-
-        // Mark server as up (available)
-        if let Some(zone) = manager.get_upstream_zone_mut("backend") {
-            if let Some(server) = zone.servers.get_mut("127.0.0.1:8080") {
-                server.down = false;
-                // Reset all counters to 0 for initialization. The seed
-                // call above only exists to register the zone in the
-                // map; it must not leave any non-zero residue (avg
-                // computation divides by `*_counter`).
-                server.request_counter = 0;
-                server.in_bytes = 0;
-                server.out_bytes = 0;
-                server.request_time_total = 0;
-                server.request_time_counter = 0;
-                server.response_time_total = 0;
-                server.response_time_counter = 0;
-                server.response_buckets = [0; crate::upstream_stats::RESPONSE_TIME_BUCKET_COUNT];
-            }
-        }
-    }
+/// Initialize upstream zones from nginx configuration
+/// Drops the previous cycle's upstream state; the C wrapper then seeds
+/// the parsed `upstream {}` blocks through [`vts_seed_upstream_server_ffi`].
+unsafe fn initialize_upstream_zones_from_config(_cf: *mut ngx_conf_t) -> Result<(), VtsError> {
+    let mut manager = match VTS_MANAGER.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => recover_poisoned(poisoned),
+    };
+    // Clear any existing data to start fresh
+    manager.stats.clear();
+    manager.upstream_zones.clear();
+    manager.upstream_duplicate_servers.clear();
 
     Ok(())
 }
@@ -1248,6 +5615,61 @@ mod tests {
         assert!(content.contains("# VTS Status: Active"));
         assert!(content.contains("test-hostname"));
         assert!(content.contains("# Prometheus Metrics:"));
+        assert!(content.contains(
+            r#"nginx_vts_nginx_build_info{nginx_version="1.25.3",configure_args=" --with-cc-opt='-DNGX_HAVE_X=\"1\"' --prefix=/usr/local/nginx"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_info_metric_carries_numeric_pid() {
+        use crate::prometheus::generate_vts_status_content;
+        let content = generate_vts_status_content();
+        let info = content
+            .lines()
+            .find(|l| l.starts_with("nginx_vts_info{"))
+            .expect("nginx_vts_info sample");
+        let pid = info
+            .split("pid=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .expect("pid label");
+        assert!(pid.parse::<u32>().is_ok(), "pid = {pid:?}");
+    }
+
+    #[test]
+    fn test_worker_metrics_carry_numeric_values() {
+        use crate::prometheus::generate_vts_status_content;
+        let content = generate_vts_status_content();
+        let value = |name: &str| {
+            content
+                .lines()
+                .find_map(|l| l.strip_prefix(name)?.strip_prefix(' '))
+                .unwrap_or_else(|| panic!("{name} sample"))
+                .parse::<u64>()
+                .unwrap()
+        };
+        assert!(content.contains("# TYPE nginx_vts_worker_processes gauge"));
+        assert!(content.contains("# TYPE nginx_vts_worker_id gauge"));
+        let (processes, id) = (
+            value("nginx_vts_worker_processes"),
+            value("nginx_vts_worker_id"),
+        );
+        assert_eq!((processes, id), (4, 1));
+        assert!(id < processes);
+    }
+
+    #[test]
+    fn test_connections_limit_is_a_positive_number() {
+        use crate::prometheus::generate_vts_status_content;
+        let content = generate_vts_status_content();
+        assert!(content.contains("# TYPE nginx_vts_connections_limit gauge"));
+        let limit = content
+            .lines()
+            .find_map(|l| l.strip_prefix("nginx_vts_connections_limit "))
+            .expect("nginx_vts_connections_limit sample")
+            .parse::<u64>()
+            .unwrap();
+        assert!(limit > 0);
     }
 
     #[test]
@@ -1258,6 +5680,49 @@ mod tests {
         assert_eq!(time_str, "1234567890");
     }
 
+    #[test]
+    fn health_status_is_ok_while_manager_is_readable() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        assert_eq!(crate::ngx_http_vts_health_status(), 200);
+    }
+
+    #[test]
+    fn health_status_is_unavailable_once_poisoned() {
+        use crate::vts_node::VtsStatsManager;
+        use std::sync::{Arc, RwLock};
+
+        let manager = Arc::new(RwLock::new(VtsStatsManager::new()));
+        assert_eq!(crate::health_status(&manager), 200);
+
+        let writer = Arc::clone(&manager);
+        let _ = std::thread::spawn(move || {
+            let _guard = writer.write().unwrap();
+            panic!("simulated panic mid-update");
+        })
+        .join();
+        assert!(manager.is_poisoned());
+        assert_eq!(crate::health_status(&manager), 503);
+        assert!(!crate::module_up(&manager));
+    }
+
+    #[test]
+    fn module_up_is_the_first_sample_of_a_healthy_page() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let content = crate::prometheus::generate_vts_status_content();
+        let first = content
+            .lines()
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .unwrap();
+        assert_eq!(first, "nginx_vts_module_up 1");
+
+        let down = crate::prometheus::PrometheusFormatter::new().format_module_up(false);
+        assert!(down.contains("nginx_vts_module_up 0\n"));
+    }
+
     #[test]
     fn calculate_time_diff_ms_handles_same_second() {
         // 50ms apart within one second.
@@ -1273,7 +5738,55 @@ mod tests {
     #[test]
     fn calculate_time_diff_ms_clamps_zero_on_clock_skew() {
         // Same second but msec went backwards (clock skew): return 0
-        // rather than panic / underflow.
+        // rather than panic / underflow, and count it.
+        let before = crate::clock_skew_count();
         assert_eq!(crate::calculate_time_diff_ms(100, 500, 100, 100), 0);
+        // An earlier second with a later msec is skew too.
+        assert_eq!(crate::calculate_time_diff_ms(101, 100, 100, 500), 0);
+        assert!(crate::clock_skew_count() >= before + 2);
+    }
+
+    #[test]
+    fn request_time_variable_formats_seconds_with_milliseconds() {
+        use crate::{calculate_time_diff_ms, format_request_time};
+        assert_eq!(
+            format_request_time(calculate_time_diff_ms(100, 900, 101, 134)),
+            "0.234"
+        );
+        assert_eq!(
+            format_request_time(calculate_time_diff_ms(100, 5, 161, 10)),
+            "61.005"
+        );
+        assert_eq!(format_request_time(0), "0.000");
+        assert_eq!(
+            format_request_time(u64::MAX).len(),
+            crate::REQUEST_TIME_VAR_LEN
+        );
+    }
+
+    #[test]
+    fn request_time_variable_rejects_null_and_short_buffers() {
+        let mut buf = [0u8; crate::REQUEST_TIME_VAR_LEN];
+        unsafe {
+            assert_eq!(
+                crate::vts_request_time_variable(std::ptr::null(), buf.as_mut_ptr(), buf.len()),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn calculate_time_diff_ms_saturates_at_max_values() {
+        use crate::calculate_time_diff_ms;
+        assert_eq!(calculate_time_diff_ms(u64::MAX, 999, u64::MAX, 999), 0);
+        assert_eq!(
+            calculate_time_diff_ms(u64::MAX - 1, 900, u64::MAX, 100),
+            200
+        );
+        assert_eq!(calculate_time_diff_ms(0, 0, u64::MAX, 999), u64::MAX);
+        assert_eq!(
+            calculate_time_diff_ms(u64::MAX, 0, u64::MAX, u64::MAX),
+            u64::MAX
+        );
     }
 }
@@ -7,6 +7,7 @@
 
 #include <ngx_config.h>
 #include <ngx_core.h>
+#include <ngx_event.h>
 #include <ngx_http.h>
 
 // Forward declarations from wrapper
@@ -16,23 +17,204 @@ extern ngx_int_t ngx_http_vts_init_wrapper(ngx_conf_t *cf);
 // every worker observes the same fixed-layout `VtsSharedTable`.
 extern ngx_int_t vts_init_shm_zone(ngx_shm_zone_t *shm_zone, void *data);
 
+// Rust-side ceiling for plausible request/response times (ms); 0 resets
+// to the built-in default.
+extern void vts_set_max_request_time_ms(uint64_t ms);
+
+// Rust-side minimum interval between connection-table walks.  0 resets
+// to the built-in default.
+extern void vts_set_connection_refresh_interval_ms(uint64_t ms);
+
+// Rust-side Apdex threshold T for `nginx_vts_server_apdex`.  0 resets
+// to the built-in default.
+extern void vts_set_apdex_threshold_ms(uint64_t ms);
+
+// Rust-side `vts_min_window` (seconds) for the server zones' minimum
+// request time.  0 keeps the all-time minimum.
+extern void vts_set_min_window_secs(uint64_t secs);
+
+// Rust-side `nginx_vts_worker_processes` / `nginx_vts_worker_id`.
+extern void vts_set_worker_info(uint64_t worker_id, uint64_t worker_processes);
+
+// Rust-side `nginx_vts_connections_limit` (`worker_connections`).
+extern void vts_set_connections_limit(uint64_t limit);
+
+// Rust-side `vts_sample_rate`: server zones record one request in
+// this many.  0 resets to 1 (every request).
+extern void vts_set_sample_rate(uint64_t rate);
+
+// Rust-side `vts_upstream_degraded_threshold`, in percent.  0 resets
+// to the default (10).
+extern void vts_set_upstream_degraded_threshold(uint64_t percent);
+
+// Rust-side `vts_upstream_server_limit`: servers reported per upstream
+// before the rest are aggregated.  0 lifts the limit.
+extern void vts_set_upstream_server_limit(uint64_t limit);
+
+// Rust-side `vts_status_rate`, in status pages per second.  0 lifts
+// the limit.
+extern void vts_set_status_rate(uint64_t rate);
+
+// Rust-side `vts_slow_log_threshold` (ms) for the slow upstream log.
+// 0 disables it.
+extern void vts_set_slow_log_threshold_ms(uint64_t ms);
+
+// Rust-side `vts_shm_warn_threshold`, in percent of the `vts_zone`
+// left free.  0 resets to the default (10).
+extern void vts_set_shm_warn_threshold(uint64_t percent);
+
+// Rust-side label-value length limit (bytes).  0 resets to the
+// built-in default.
+extern void vts_set_max_label_len(uint64_t len);
+
+// Smallest `vts_max_label_len`; matches `MIN_LABEL_LEN` in
+// src/prometheus/truncate.rs.
+#define NGX_HTTP_VTS_MIN_LABEL_LEN  16
+
+// Rust-side per-zone accounting switch used by `vts_disable_zone`.
+extern void vts_set_zone_enabled_ffi(const u_char *name, size_t len, uint8_t enabled);
+extern void vts_clear_disabled_zones(void);
+
+// Rust-side user-defined labels for server zones (`vts_zone_label`).
+// Both return NULL on success, otherwise a static error message.
+extern const char *vts_check_zone_label_ffi(const u_char *name, size_t len, size_t configured);
+extern const char *vts_add_zone_label_ffi(const u_char *zone, size_t zone_len,
+                                          const u_char *name, size_t name_len,
+                                          const u_char *value, size_t value_len);
+extern void vts_clear_zone_labels(void);
+
+// Rust-side pool names for upstream groups (`vts_upstream_zone`).  The
+// setter returns NULL, or "is duplicate" if the group already has one.
+extern const char *vts_set_upstream_zone_ffi(const u_char *upstream, size_t upstream_len,
+                                             const u_char *zone, size_t zone_len);
+extern void vts_clear_upstream_zone_names(void);
+
+// Rust-side output names for server zones (`vts_zone_alias`).  The
+// setter returns NULL, or "is duplicate" if the zone already has one.
+extern const char *vts_add_zone_alias_ffi(const u_char *from, size_t from_len,
+                                          const u_char *to, size_t to_len);
+extern void vts_clear_zone_aliases(void);
+
+// Rust-side `vts_upstream_key`: 1 keys upstream servers by configured
+// name, 0 (the default) by resolved address.
+extern void vts_set_upstream_key_by_name(uint8_t by_name);
+
+// Rust-side `vts_unix_socket` listener (needs the `unix-socket` cargo
+// feature).  The setter returns NULL on success, otherwise a static
+// error message; start returns 0 or an errno.
+extern const char *vts_set_unix_socket_path_ffi(const u_char *path, size_t len);
+extern int vts_unix_socket_start_ffi(void);
+extern void vts_unix_socket_stop_ffi(void);
+extern int vts_unix_socket_refresh_ffi(void);
+
+// Copy the upstream peers' `down` flags into the statistics before a
+// render (ngx_vts_wrapper.c).
+extern void ngx_http_vts_sync_upstream_peers(void);
+
+// How often the first worker re-renders the `vts_unix_socket` page.
+#define NGX_HTTP_VTS_UNIX_SOCKET_REFRESH  1000
+
+static ngx_event_t ngx_http_vts_unix_socket_refresh_ev;
+
+// Whether `vts_track_unique_clients` is available (needs the
+// `unique-clients` cargo feature): NULL if so, otherwise the error.
+extern const char *vts_unique_clients_supported_ffi(void);
+
+// Whether an Accept header value prefers the protobuf exposition
+// (always 0 without the `protobuf` cargo feature).
+extern int ngx_http_vts_prefers_protobuf(const u_char *accept, size_t len);
+
+// Rust-side `vts_state_file` persistence.  Load and save return NULL on
+// success (or when no file is configured), otherwise an error message.
+extern void vts_set_state_file_ffi(const u_char *path, size_t len);
+extern const char *vts_state_file_load_ffi(void);
+extern const char *vts_state_file_save_ffi(void);
+
+// Rust-side `$vts_request_time`: writes the request time as `s.mmm`
+// into `buf` and returns its length, 0 on failure.
+extern size_t vts_request_time_variable(ngx_http_request_t *r, u_char *buf, size_t len);
+
+// Rust-side `vts_filter_by_variable` accounting for one request.
+extern void vts_log_filter_request(ngx_http_request_t *r,
+    const u_char *group, size_t group_len, const u_char *key, size_t key_len);
+
+// Longest `$vts_request_time` value; matches `REQUEST_TIME_VAR_LEN`
+// in src/lib.rs.
+#define NGX_HTTP_VTS_REQUEST_TIME_LEN  21
+
+// Longest zone name the shared table stores; matches
+// `VTS_MAX_KEY_BYTES` in src/shm.rs.
+#define NGX_HTTP_VTS_MAX_KEY_BYTES  256
+
+// What a `vts_status` location renders.
+#define NGX_HTTP_VTS_STATUS_METRICS      0
+#define NGX_HTTP_VTS_STATUS_DIAGNOSTICS  1
+
 // Configuration structure
+// One `vts_filter_by_variable` group: requests are counted under the
+// value of the variable at `index`.
+typedef struct {
+    ngx_str_t group;
+    ngx_int_t index;
+} ngx_http_vts_filter_t;
+
 typedef struct {
     ngx_flag_t enable;
     size_t zone_size;
     ngx_str_t zone_name;
+    ngx_uint_t status_mode;
+    ngx_flag_t stream_bytes;
+    ngx_flag_t self_monitor;
+    ngx_flag_t detail_method_status;
+    ngx_flag_t track_content_type;
+    ngx_flag_t track_grpc;
+    ngx_flag_t track_unique_clients;
+    ngx_array_t *zone_labels;   /* of ngx_keyval_t; server level only */
+    ngx_str_t location_zone;    /* vts_location_zone; empty when unset */
+    ngx_array_t *filters;       /* of ngx_http_vts_filter_t */
+    ngx_flag_t health;          /* vts_health in this very location */
+    ngx_array_t *expose_zones;  /* of ngx_str_t; vts_expose_zones */
 } ngx_http_vts_loc_conf_t;
 
 // Forward declarations
+static ngx_int_t ngx_http_vts_preconfiguration(ngx_conf_t *cf);
 static ngx_int_t ngx_http_vts_postconfiguration(ngx_conf_t *cf);
 static void *ngx_http_vts_create_loc_conf(ngx_conf_t *cf);
 static char *ngx_http_vts_merge_loc_conf(ngx_conf_t *cf, void *parent, void *child);
 static char *ngx_http_vts_zone_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
 static char *ngx_http_vts_status_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
 static char *ngx_http_vts_upstream_stats_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_max_request_time_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_connection_refresh_interval_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_apdex_threshold_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_min_window_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_max_label_len_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_sample_rate_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_upstream_degraded_threshold_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_upstream_server_limit_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_status_rate_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_slow_log_threshold_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_shm_warn_threshold_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_disable_zone_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_zone_label_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_upstream_zone_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_zone_alias_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static ngx_int_t ngx_http_vts_apply_zone_labels(ngx_conf_t *cf);
+static char *ngx_http_vts_unix_socket_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_state_file_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_upstream_key_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_location_zone_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_filter_by_variable_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_health_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_expose_zones_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static char *ngx_http_vts_track_unique_clients_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf);
+static ngx_int_t ngx_http_vts_init_process(ngx_cycle_t *cycle);
+static void ngx_http_vts_unix_socket_refresh(ngx_event_t *ev);
+static void ngx_http_vts_exit_process(ngx_cycle_t *cycle);
 
-// Handler declaration
+// Handler declarations
 static ngx_int_t ngx_http_vts_status_handler(ngx_http_request_t *r);
+static ngx_int_t ngx_http_vts_health_handler(ngx_http_request_t *r);
 
 // Module commands
 static ngx_command_t ngx_http_vts_commands[] = {
@@ -46,12 +228,20 @@ static ngx_command_t ngx_http_vts_commands[] = {
     },
     {
         ngx_string("vts_status"),
-        NGX_HTTP_LOC_CONF | NGX_CONF_NOARGS,
+        NGX_HTTP_LOC_CONF | NGX_CONF_NOARGS | NGX_CONF_TAKE1,
         ngx_http_vts_status_directive,
         NGX_HTTP_LOC_CONF_OFFSET,
         0,
         NULL
     },
+    {
+        ngx_string("vts_health"),
+        NGX_HTTP_LOC_CONF | NGX_CONF_NOARGS,
+        ngx_http_vts_health_directive,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        0,
+        NULL
+    },
     {
         ngx_string("vts_upstream_stats"),
         NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG,
@@ -60,12 +250,232 @@ static ngx_command_t ngx_http_vts_commands[] = {
         offsetof(ngx_http_vts_loc_conf_t, enable),
         NULL
     },
+    {
+        ngx_string("vts_stream_bytes"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG,
+        ngx_conf_set_flag_slot,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_vts_loc_conf_t, stream_bytes),
+        NULL
+    },
+    {
+        ngx_string("vts_self_monitor"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG,
+        ngx_conf_set_flag_slot,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_vts_loc_conf_t, self_monitor),
+        NULL
+    },
+    {
+        ngx_string("vts_detail_method_status"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG,
+        ngx_conf_set_flag_slot,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_vts_loc_conf_t, detail_method_status),
+        NULL
+    },
+    {
+        ngx_string("vts_track_content_type"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG,
+        ngx_conf_set_flag_slot,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_vts_loc_conf_t, track_content_type),
+        NULL
+    },
+    {
+        ngx_string("vts_track_grpc"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG,
+        ngx_conf_set_flag_slot,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_vts_loc_conf_t, track_grpc),
+        NULL
+    },
+    {
+        ngx_string("vts_track_unique_clients"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG,
+        ngx_http_vts_track_unique_clients_directive,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_vts_loc_conf_t, track_unique_clients),
+        NULL
+    },
+    {
+        ngx_string("vts_location_zone"),
+        NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_location_zone_directive,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_expose_zones"),
+        NGX_HTTP_LOC_CONF | NGX_CONF_1MORE,
+        ngx_http_vts_expose_zones_directive,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_filter_by_variable"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE2,
+        ngx_http_vts_filter_by_variable_directive,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_max_request_time"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_max_request_time_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_connection_refresh_interval"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_connection_refresh_interval_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_apdex_threshold"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_apdex_threshold_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_min_window"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_min_window_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_max_label_len"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_max_label_len_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_sample_rate"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_sample_rate_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_upstream_degraded_threshold"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_upstream_degraded_threshold_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_upstream_server_limit"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_upstream_server_limit_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_status_rate"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_status_rate_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_slow_log_threshold"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_slow_log_threshold_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_shm_warn_threshold"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_shm_warn_threshold_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_disable_zone"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_disable_zone_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_zone_alias"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE2,
+        ngx_http_vts_zone_alias_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_unix_socket"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_unix_socket_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_state_file"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_state_file_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_upstream_key"),
+        NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_upstream_key_directive,
+        NGX_HTTP_MAIN_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_zone_label"),
+        NGX_HTTP_SRV_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_zone_label_directive,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        0,
+        NULL
+    },
+    {
+        ngx_string("vts_upstream_zone"),
+        NGX_HTTP_UPS_CONF | NGX_CONF_TAKE1,
+        ngx_http_vts_upstream_zone_directive,
+        NGX_HTTP_LOC_CONF_OFFSET,
+        0,
+        NULL
+    },
     ngx_null_command
 };
 
-// Module context
+// Module context.  There is no server configuration: every directive
+// allowed at `server` level stores into the loc conf, which nginx
+// creates for http, server and location blocks alike and merges
+// downward through ngx_http_vts_merge_loc_conf, so a server-level
+// value reaches each of its locations unless a location sets its own.
 static ngx_http_module_t ngx_http_vts_module_ctx = {
-    NULL,                              /* preconfiguration */
+    ngx_http_vts_preconfiguration,     /* preconfiguration */
     ngx_http_vts_postconfiguration,    /* postconfiguration */
     NULL,                              /* create main configuration */
     NULL,                              /* init main configuration */
@@ -83,14 +493,77 @@ ngx_module_t ngx_http_vts_module = {
     NGX_HTTP_MODULE,                   /* module type */
     NULL,                              /* init master */
     NULL,                              /* init module */
-    NULL,                              /* init process */
+    ngx_http_vts_init_process,         /* init process */
     NULL,                              /* init thread */
     NULL,                              /* exit thread */
-    NULL,                              /* exit process */
+    ngx_http_vts_exit_process,         /* exit process */
     NULL,                              /* exit master */
     NGX_MODULE_V1_PADDING
 };
 
+// Whether the request's Accept header prefers the Prometheus protobuf
+// exposition format to the text page.  The media ranges and their
+// q-values are weighed on the Rust side; only the first Accept header
+// is considered.
+static ngx_flag_t
+ngx_http_vts_accepts_protobuf(ngx_http_request_t *r)
+{
+    ngx_list_part_t *part;
+    ngx_table_elt_t *h;
+    ngx_uint_t       i;
+
+    part = &r->headers_in.headers.part;
+    h = part->elts;
+
+    for (i = 0; /* void */; i++) {
+        if (i >= part->nelts) {
+            if (part->next == NULL) {
+                break;
+            }
+            part = part->next;
+            h = part->elts;
+            i = 0;
+        }
+
+        if (h[i].key.len == sizeof("Accept") - 1
+            && ngx_strncasecmp(h[i].key.data, (u_char *) "Accept",
+                               sizeof("Accept") - 1) == 0)
+        {
+            return ngx_http_vts_prefers_protobuf(h[i].value.data,
+                                                 h[i].value.len) != 0;
+        }
+    }
+
+    return 0;
+}
+
+// Add `Retry-After: <seconds>` to the response headers.
+static ngx_int_t
+ngx_http_vts_set_retry_after(ngx_http_request_t *r, uint64_t seconds)
+{
+    ngx_table_elt_t *h;
+
+    h = ngx_list_push(&r->headers_out.headers);
+    if (h == NULL) {
+        return NGX_ERROR;
+    }
+
+    h->value.data = ngx_pnalloc(r->pool, NGX_INT64_LEN);
+    if (h->value.data == NULL) {
+        h->hash = 0;
+        return NGX_ERROR;
+    }
+    h->value.len = ngx_sprintf(h->value.data, "%uL", seconds) - h->value.data;
+
+    h->hash = 1;
+#if (nginx_version >= 1023000)
+    h->next = NULL;
+#endif
+    ngx_str_set(&h->key, "Retry-After");
+
+    return NGX_OK;
+}
+
 // Status handler implementation
 static ngx_int_t
 ngx_http_vts_status_handler(ngx_http_request_t *r)
@@ -98,9 +571,29 @@ ngx_http_vts_status_handler(ngx_http_request_t *r)
     ngx_int_t rc;
     ngx_buf_t *b;
     ngx_chain_t out;
+    ngx_http_vts_loc_conf_t *vlcf;
+    const char *status_output;
+    size_t status_len = 0;
+    uint16_t control_status;
+    uint64_t retry_after;
+    u_char *limited;
+    // Prometheus text exposition format identifier; Prometheus 3.x
+    // rejects scrapes that arrive without a recognised Content-Type.
+    ngx_str_t content_type =
+        ngx_string("text/plain; version=0.0.4; charset=utf-8");
+    ngx_str_t *zone;
+    ngx_uint_t i;
 
-    // Rust function to get status output
+    // Rust functions to get status output
     extern const char* ngx_http_vts_get_status();
+    extern const u_char* ngx_http_vts_get_status_protobuf(size_t *len);
+    extern const char* ngx_http_vts_get_diagnostics();
+    extern const char* ngx_http_vts_get_minimal_status();
+    extern uint64_t ngx_http_vts_status_retry_after(const u_char *args, size_t len);
+    extern const char* ngx_http_vts_control(const u_char *args, size_t len,
+                                            uint16_t *status);
+    extern void vts_expose_zone_ffi(const u_char *name, size_t len);
+    extern void vts_clear_exposed_zones(void);
 
     if (!(r->method & (NGX_HTTP_GET|NGX_HTTP_HEAD))) {
         return NGX_HTTP_NOT_ALLOWED;
@@ -109,6 +602,7 @@ ngx_http_vts_status_handler(ngx_http_request_t *r)
     // Mark the request so the LOG_PHASE handler can recognise its
     // own scrape and skip the server-zone update — otherwise
     // Prometheus scrapes would inflate `nginx_vts_server_requests_total`.
+    // (`vts_self_monitor on` opts back in to counting them.)
     // Any non-NULL value works; we use the static handler address
     // because it's a unique, readily-available sentinel.
     ngx_http_set_ctx(r, (void *) ngx_http_vts_status_handler, ngx_http_vts_module);
@@ -117,17 +611,93 @@ ngx_http_vts_status_handler(ngx_http_request_t *r)
     if (rc != NGX_OK) {
         return rc;
     }
+
+    // `vts_expose_zones` limits the server zones this location shows: on
+    // the page and in every other rendering (`?format=influx`,
+    // `?mode=delta`, `?top=`, protobuf, `?control=selftest`).  The list
+    // only applies while this request renders, so every return below
+    // clears it.
+    vlcf = ngx_http_get_module_loc_conf(r, ngx_http_vts_module);
+    if (vlcf->expose_zones != NULL) {
+        zone = vlcf->expose_zones->elts;
+        for (i = 0; i < vlcf->expose_zones->nelts; i++) {
+            vts_expose_zone_ffi(zone[i].data, zone[i].len);
+        }
+    }
     
-    // Get status from Rust implementation
-    const char *status_output = ngx_http_vts_get_status();
-    size_t status_len = ngx_strlen(status_output);
-    
-    // Set response headers.  The Content-Type is the Prometheus text
-    // exposition format identifier; Prometheus 3.x rejects scrapes that
-    // arrive without a recognised Content-Type.
-    r->headers_out.status = NGX_HTTP_OK;
+    // Peer `down` flags are read here, on the event thread, rather
+    // than from the counters, which carry no peer state.
+    ngx_http_vts_sync_upstream_peers();
+
+    // Past `vts_status_rate`, answer 429 instead of generating a reply.
+    // Every rendering takes a token (the page, `?format=influx`,
+    // `?mode=delta`, `?top=`, protobuf, `?control=selftest`); only
+    // `?control=reset` and `?meta=1`, which render no counters, don't.
+    status_output = NULL;
+    control_status = NGX_HTTP_OK;
+    retry_after = ngx_http_vts_status_retry_after(r->args.data, r->args.len);
+    if (retry_after > 0) {
+        if (ngx_http_vts_set_retry_after(r, retry_after) != NGX_OK) {
+            vts_clear_exposed_zones();
+            return NGX_HTTP_INTERNAL_SERVER_ERROR;
+        }
+
+        limited = ngx_pnalloc(r->pool, sizeof("vts: status rate limit "
+                                              "exceeded, retry after  s\n")
+                                       + NGX_INT64_LEN);
+        if (limited == NULL) {
+            vts_clear_exposed_zones();
+            return NGX_HTTP_INTERNAL_SERVER_ERROR;
+        }
+        status_len = ngx_sprintf(limited, "vts: status rate limit exceeded, "
+                                 "retry after %uL s\n", retry_after) - limited;
+        status_output = (const char *) limited;
+        control_status = 429;
+    }
+
+    // A `?control=reset&group=...` query runs that command and replies
+    // with its confirmation (or a 400 explaining what was wrong)
+    // instead of the page; `?meta=1`, `?format=influx` and
+    // `?mode=delta` reply with the metric catalog, the line-protocol
+    // rendering or the increases since the last delta scrape.
+    if (status_output == NULL) {
+        status_output = ngx_http_vts_control(r->args.data, r->args.len,
+                                             &control_status);
+        if (status_output != NULL) {
+            status_len = ngx_strlen(status_output);
+        }
+    }
+
+    // Otherwise get status from Rust implementation: either the
+    // Prometheus exposition or, for `vts_status control=status`, the
+    // module's own diagnostics report.  Scrapes negotiating the
+    // protobuf format get it when the module was built with the
+    // `protobuf` feature (the call returns NULL otherwise).
+    if (status_output == NULL
+        && vlcf->status_mode != NGX_HTTP_VTS_STATUS_DIAGNOSTICS
+        && ngx_http_vts_accepts_protobuf(r))
+    {
+        status_output = (const char *) ngx_http_vts_get_status_protobuf(&status_len);
+        if (status_output != NULL) {
+            ngx_str_set(&content_type,
+                        "application/vnd.google.protobuf; "
+                        "proto=io.prometheus.client.MetricFamily; "
+                        "encoding=delimited");
+        }
+    }
+    if (status_output == NULL) {
+        if (vlcf->status_mode == NGX_HTTP_VTS_STATUS_DIAGNOSTICS) {
+            status_output = ngx_http_vts_get_diagnostics();
+        } else {
+            status_output = ngx_http_vts_get_status();
+        }
+        status_len = ngx_strlen(status_output);
+    }
+    vts_clear_exposed_zones();
+
+    r->headers_out.status = control_status;
     r->headers_out.content_length_n = status_len;
-    ngx_str_set(&r->headers_out.content_type, "text/plain; version=0.0.4; charset=utf-8");
+    r->headers_out.content_type = content_type;
     r->headers_out.content_type_len = r->headers_out.content_type.len;
     r->headers_out.content_type_lowcase = NULL;
     
@@ -138,10 +708,27 @@ ngx_http_vts_status_handler(ngx_http_request_t *r)
         }
     }
     
-    // Create response buffer
+    // Create response buffer.  A large status page is the allocation
+    // most likely to fail under memory pressure; rather than a bare 500,
+    // fall back to the minimal page (info + connections) so scrapes
+    // still show the server is alive.
     b = ngx_create_temp_buf(r->pool, status_len);
     if (b == NULL) {
-        return NGX_HTTP_INTERNAL_SERVER_ERROR;
+        ngx_log_error(NGX_LOG_ERR, r->connection->log, 0,
+                      "vts: failed to allocate %uz bytes for the status "
+                      "response, sending the minimal status", status_len);
+
+        status_output = ngx_http_vts_get_minimal_status();
+        status_len = ngx_strlen(status_output);
+        r->headers_out.content_length_n = status_len;
+        ngx_str_set(&r->headers_out.content_type,
+                    "text/plain; version=0.0.4; charset=utf-8");
+        r->headers_out.content_type_len = r->headers_out.content_type.len;
+
+        b = ngx_create_temp_buf(r->pool, status_len);
+        if (b == NULL) {
+            return NGX_HTTP_INTERNAL_SERVER_ERROR;
+        }
     }
     
     ngx_memcpy(b->pos, status_output, status_len);
@@ -163,42 +750,423 @@ ngx_http_vts_status_handler(ngx_http_request_t *r)
     return ngx_http_output_filter(r, &out);
 }
 
-// Postconfiguration - called after all configuration is parsed
+// Health handler: `200 ok` while the module's stats are usable, `503`
+// once a panic has poisoned them.  Renders nothing, so load balancers
+// can probe it far more often than Prometheus scrapes the status page.
 static ngx_int_t
-ngx_http_vts_postconfiguration(ngx_conf_t *cf)
+ngx_http_vts_health_handler(ngx_http_request_t *r)
 {
-    // Initialize the wrapper (includes LOG_PHASE handler registration)
-    return ngx_http_vts_init_wrapper(cf);
-}
+    ngx_int_t rc;
+    ngx_buf_t *b;
+    ngx_chain_t out;
+    ngx_str_t body;
 
-// Create location configuration
-static void *
-ngx_http_vts_create_loc_conf(ngx_conf_t *cf)
-{
-    ngx_http_vts_loc_conf_t *conf;
-    
-    conf = ngx_pcalloc(cf->pool, sizeof(ngx_http_vts_loc_conf_t));
-    if (conf == NULL) {
-        return NULL;
+    extern uint16_t ngx_http_vts_health_status(void);
+
+    if (!(r->method & (NGX_HTTP_GET|NGX_HTTP_HEAD))) {
+        return NGX_HTTP_NOT_ALLOWED;
     }
-    
-    conf->enable = NGX_CONF_UNSET;
-    conf->zone_size = NGX_CONF_UNSET_SIZE;
-    
-    return conf;
-}
 
-// Merge location configurations
-static char *
-ngx_http_vts_merge_loc_conf(ngx_conf_t *cf, void *parent, void *child)
-{
-    ngx_http_vts_loc_conf_t *prev = parent;
-    ngx_http_vts_loc_conf_t *conf = child;
-    
-    ngx_conf_merge_value(conf->enable, prev->enable, 0);
-    ngx_conf_merge_size_value(conf->zone_size, prev->zone_size, 1024*1024);
-    
-    return NGX_CONF_OK;
+    // Probes are not traffic: skip the server-zone update like a
+    // status scrape (again unless `vts_self_monitor on`).
+    ngx_http_set_ctx(r, (void *) ngx_http_vts_health_handler, ngx_http_vts_module);
+
+    rc = ngx_http_discard_request_body(r);
+    if (rc != NGX_OK) {
+        return rc;
+    }
+
+    r->headers_out.status = ngx_http_vts_health_status();
+    if (r->headers_out.status == NGX_HTTP_OK) {
+        ngx_str_set(&body, "ok\n");
+    } else {
+        ngx_str_set(&body, "unavailable\n");
+    }
+
+    r->headers_out.content_length_n = body.len;
+    ngx_str_set(&r->headers_out.content_type, "text/plain");
+    r->headers_out.content_type_len = r->headers_out.content_type.len;
+    r->headers_out.content_type_lowcase = NULL;
+
+    rc = ngx_http_send_header(r);
+    if (rc == NGX_ERROR || rc > NGX_OK || r->header_only) {
+        return rc;
+    }
+
+    b = ngx_calloc_buf(r->pool);
+    if (b == NULL) {
+        return NGX_HTTP_INTERNAL_SERVER_ERROR;
+    }
+    b->pos = body.data;
+    b->last = body.data + body.len;
+    b->memory = 1;
+    b->last_buf = 1;
+    b->last_in_chain = 1;
+
+    out.buf = b;
+    out.next = NULL;
+
+    return ngx_http_output_filter(r, &out);
+}
+
+// `$vts_request_time`: the request time the VTS counters use, for
+// `log_format`.  Not cacheable, so every evaluation reads the clock.
+static ngx_int_t
+ngx_http_vts_request_time_variable(ngx_http_request_t *r,
+    ngx_http_variable_value_t *v, uintptr_t data)
+{
+    u_char *p;
+    size_t len;
+
+    (void)data;
+
+    p = ngx_pnalloc(r->pool, NGX_HTTP_VTS_REQUEST_TIME_LEN);
+    if (p == NULL) {
+        return NGX_ERROR;
+    }
+
+    len = vts_request_time_variable(r, p, NGX_HTTP_VTS_REQUEST_TIME_LEN);
+    if (len == 0) {
+        v->not_found = 1;
+        return NGX_OK;
+    }
+
+    v->len = len;
+    v->valid = 1;
+    v->no_cacheable = 1;
+    v->not_found = 0;
+    v->data = p;
+
+    return NGX_OK;
+}
+
+// Preconfiguration - called before the http block is parsed.  Adds
+// `$vts_request_time` and resets process-global Rust settings so a
+// reload that drops a directive falls back to the default instead of
+// keeping the previous cycle's value.
+static ngx_int_t
+ngx_http_vts_preconfiguration(ngx_conf_t *cf)
+{
+    ngx_http_variable_t *var;
+    static ngx_str_t request_time_name = ngx_string("vts_request_time");
+
+    var = ngx_http_add_variable(cf, &request_time_name, NGX_HTTP_VAR_NOCACHEABLE);
+    if (var == NULL) {
+        return NGX_ERROR;
+    }
+    var->get_handler = ngx_http_vts_request_time_variable;
+
+    vts_set_max_request_time_ms(0);
+    vts_set_connection_refresh_interval_ms(0);
+    vts_set_apdex_threshold_ms(0);
+    vts_set_min_window_secs(0);
+    vts_set_max_label_len(0);
+    vts_set_sample_rate(0);
+    vts_set_upstream_degraded_threshold(0);
+    vts_set_upstream_server_limit(0);
+    vts_set_status_rate(0);
+    vts_set_slow_log_threshold_ms(0);
+    vts_set_shm_warn_threshold(0);
+    vts_clear_disabled_zones();
+    vts_clear_zone_labels();
+    vts_clear_upstream_zone_names();
+    vts_clear_zone_aliases();
+    vts_set_upstream_key_by_name(0);
+    vts_set_unix_socket_path_ffi(NULL, 0);
+    vts_set_state_file_ffi(NULL, 0);
+
+    return NGX_OK;
+}
+
+// Init process - every worker records its slot, the configured worker
+// count and `worker_connections`; the first one also merges the `vts_state_file` and
+// starts the `vts_unix_socket` listener, if configured.  An unreadable
+// state file or a bind failure is logged but does not stop the worker:
+// counters start fresh and the HTTP `vts_status` locations keep working.
+static ngx_int_t
+ngx_http_vts_init_process(ngx_cycle_t *cycle)
+{
+    int err;
+    const char *msg;
+    ngx_core_conf_t *ccf;
+    ngx_event_conf_t *ecf;
+
+    if (ngx_process != NGX_PROCESS_WORKER && ngx_process != NGX_PROCESS_SINGLE) {
+        return NGX_OK;
+    }
+
+    ccf = (ngx_core_conf_t *) ngx_get_conf(cycle->conf_ctx, ngx_core_module);
+    vts_set_worker_info((uint64_t) ngx_worker,
+                        ccf != NULL ? (uint64_t) ccf->worker_processes : 0);
+
+    ecf = ngx_event_get_conf(cycle->conf_ctx, ngx_event_core_module);
+    vts_set_connections_limit(ecf != NULL ? (uint64_t) ecf->connections : 0);
+
+    if (ngx_worker != 0) {
+        return NGX_OK;
+    }
+
+    // On a reload the old first worker is still running and only
+    // saves in its exit_process, after this load: without a vts_zone
+    // the file read here is the one from the previous stop.
+    msg = vts_state_file_load_ffi();
+    if (msg != NULL) {
+        ngx_log_error(NGX_LOG_WARN, cycle->log, 0,
+                      "vts_state_file: %s, starting fresh", msg);
+    }
+
+    err = vts_unix_socket_start_ffi();
+    if (err != 0) {
+        ngx_log_error(NGX_LOG_ALERT, cycle->log, err > 0 ? err : 0,
+                      "vts_unix_socket: failed to start the listener");
+
+    } else if (vts_unix_socket_refresh_ffi()) {
+        ngx_http_vts_unix_socket_refresh_ev.handler =
+            ngx_http_vts_unix_socket_refresh;
+        ngx_http_vts_unix_socket_refresh_ev.log = cycle->log;
+        ngx_http_vts_unix_socket_refresh_ev.cancelable = 1;
+        ngx_add_timer(&ngx_http_vts_unix_socket_refresh_ev,
+                      NGX_HTTP_VTS_UNIX_SOCKET_REFRESH);
+    }
+
+    return NGX_OK;
+}
+
+// Timer handler: render the `vts_unix_socket` page here, on the event
+// thread, since the listener thread must not walk nginx's structures.
+static void
+ngx_http_vts_unix_socket_refresh(ngx_event_t *ev)
+{
+    if (ngx_exiting) {
+        return;
+    }
+
+    ngx_http_vts_sync_upstream_peers();
+
+    if (!vts_unix_socket_refresh_ffi()) {
+        return;
+    }
+
+    ngx_add_timer(ev, NGX_HTTP_VTS_UNIX_SOCKET_REFRESH);
+}
+
+// Exit process - stop the `vts_unix_socket` listener (a no-op in
+// workers that never started one); the first worker also writes the
+// `vts_state_file`, if configured.
+static void
+ngx_http_vts_exit_process(ngx_cycle_t *cycle)
+{
+    const char *msg;
+
+    vts_unix_socket_stop_ffi();
+
+    if ((ngx_process != NGX_PROCESS_WORKER && ngx_process != NGX_PROCESS_SINGLE)
+        || ngx_worker != 0)
+    {
+        return;
+    }
+
+    msg = vts_state_file_save_ffi();
+    if (msg != NULL) {
+        ngx_log_error(NGX_LOG_WARN, cycle->log, 0, "vts_state_file: %s", msg);
+    }
+}
+
+// Postconfiguration - called after all configuration is parsed
+static ngx_int_t
+ngx_http_vts_postconfiguration(ngx_conf_t *cf)
+{
+    // Server names are only complete once the whole config is parsed.
+    if (ngx_http_vts_apply_zone_labels(cf) != NGX_OK) {
+        return NGX_ERROR;
+    }
+
+    // Initialize the wrapper (includes LOG_PHASE handler registration)
+    return ngx_http_vts_init_wrapper(cf);
+}
+
+// Whether `vts_stream_bytes` is on for the request's location.  Used by
+// the body filter in the wrapper, which can't see the conf struct.
+ngx_flag_t
+ngx_http_vts_stream_bytes_enabled(ngx_http_request_t *r)
+{
+    ngx_http_vts_loc_conf_t *vlcf;
+
+    vlcf = ngx_http_get_module_loc_conf(r, ngx_http_vts_module);
+    return vlcf != NULL && vlcf->stream_bytes;
+}
+
+// Whether `vts_self_monitor` is on for the request's location, i.e.
+// whether a scrape served there is counted in its server zone like any
+// other request.  Used by the LOG_PHASE handler in the wrapper.
+ngx_flag_t
+ngx_http_vts_self_monitor_enabled(ngx_http_request_t *r)
+{
+    ngx_http_vts_loc_conf_t *vlcf;
+
+    vlcf = ngx_http_get_module_loc_conf(r, ngx_http_vts_module);
+    return vlcf != NULL && vlcf->self_monitor;
+}
+
+// Whether `vts_detail_method_status` is on for the request's location.
+// Used by the LOG_PHASE handler in the wrapper.
+ngx_flag_t
+ngx_http_vts_detail_method_status_enabled(ngx_http_request_t *r)
+{
+    ngx_http_vts_loc_conf_t *vlcf;
+
+    vlcf = ngx_http_get_module_loc_conf(r, ngx_http_vts_module);
+    return vlcf != NULL && vlcf->detail_method_status;
+}
+
+// Whether `vts_track_grpc` is on for the request's location.  Used by
+// the LOG_PHASE handler in the wrapper.
+ngx_flag_t
+ngx_http_vts_track_grpc_enabled(ngx_http_request_t *r)
+{
+    ngx_http_vts_loc_conf_t *vlcf;
+
+    vlcf = ngx_http_get_module_loc_conf(r, ngx_http_vts_module);
+    return vlcf != NULL && vlcf->track_grpc;
+}
+
+// Whether `vts_track_unique_clients` is on for the request's location.
+// Used by the LOG_PHASE handler in the wrapper.
+ngx_flag_t
+ngx_http_vts_track_unique_clients_enabled(ngx_http_request_t *r)
+{
+    ngx_http_vts_loc_conf_t *vlcf;
+
+    vlcf = ngx_http_get_module_loc_conf(r, ngx_http_vts_module);
+    return vlcf != NULL && vlcf->track_unique_clients;
+}
+
+// Whether `vts_track_content_type` is on for the request's location.
+// Used by the LOG_PHASE handler in the wrapper.
+ngx_flag_t
+ngx_http_vts_track_content_type_enabled(ngx_http_request_t *r)
+{
+    ngx_http_vts_loc_conf_t *vlcf;
+
+    vlcf = ngx_http_get_module_loc_conf(r, ngx_http_vts_module);
+    return vlcf != NULL && vlcf->track_content_type;
+}
+
+// The `vts_location_zone` name for the request's location, or NULL when
+// none is configured.  Used by the LOG_PHASE handler in the wrapper.
+ngx_str_t *
+ngx_http_vts_location_zone(ngx_http_request_t *r)
+{
+    ngx_http_vts_loc_conf_t *vlcf;
+
+    vlcf = ngx_http_get_module_loc_conf(r, ngx_http_vts_module);
+    if (vlcf == NULL || vlcf->location_zone.len == 0) {
+        return NULL;
+    }
+    return &vlcf->location_zone;
+}
+
+// Count the request under each `vts_filter_by_variable` group of its
+// location, keyed by the variable's value; unset or empty values are
+// skipped.  Called from the LOG_PHASE handler in the wrapper.
+void
+ngx_http_vts_log_filters(ngx_http_request_t *r)
+{
+    ngx_http_vts_loc_conf_t    *vlcf;
+    ngx_http_vts_filter_t      *filter;
+    ngx_http_variable_value_t  *vv;
+    ngx_uint_t                  i;
+
+    vlcf = ngx_http_get_module_loc_conf(r, ngx_http_vts_module);
+    if (vlcf == NULL || vlcf->filters == NULL) {
+        return;
+    }
+
+    filter = vlcf->filters->elts;
+    for (i = 0; i < vlcf->filters->nelts; i++) {
+        vv = ngx_http_get_indexed_variable(r, filter[i].index);
+        if (vv == NULL || vv->not_found || vv->len == 0) {
+            continue;
+        }
+        vts_log_filter_request(r, filter[i].group.data, filter[i].group.len,
+                               vv->data, vv->len);
+    }
+}
+
+// Create location configuration
+static void *
+ngx_http_vts_create_loc_conf(ngx_conf_t *cf)
+{
+    ngx_http_vts_loc_conf_t *conf;
+    
+    conf = ngx_pcalloc(cf->pool, sizeof(ngx_http_vts_loc_conf_t));
+    if (conf == NULL) {
+        return NULL;
+    }
+    
+    conf->enable = NGX_CONF_UNSET;
+    conf->zone_size = NGX_CONF_UNSET_SIZE;
+    conf->status_mode = NGX_CONF_UNSET_UINT;
+    conf->stream_bytes = NGX_CONF_UNSET;
+    conf->self_monitor = NGX_CONF_UNSET;
+    conf->detail_method_status = NGX_CONF_UNSET;
+    conf->track_content_type = NGX_CONF_UNSET;
+    conf->track_grpc = NGX_CONF_UNSET;
+    conf->track_unique_clients = NGX_CONF_UNSET;
+    conf->health = NGX_CONF_UNSET;
+    conf->filters = NGX_CONF_UNSET_PTR;
+    conf->expose_zones = NGX_CONF_UNSET_PTR;
+    
+    return conf;
+}
+
+// Merge location configurations
+static char *
+ngx_http_vts_merge_loc_conf(ngx_conf_t *cf, void *parent, void *child)
+{
+    ngx_http_vts_loc_conf_t *prev = parent;
+    ngx_http_vts_loc_conf_t *conf = child;
+    ngx_http_core_loc_conf_t *clcf;
+
+    // `status_mode` is only set by `vts_status` in this very location.
+    // If a later directive replaced our content handler, fail instead
+    // of serving something else at the status URL.
+    if (conf->status_mode != NGX_CONF_UNSET_UINT) {
+        clcf = ngx_http_conf_get_module_loc_conf(cf, ngx_http_core_module);
+        if (clcf->handler != ngx_http_vts_status_handler) {
+            ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                               "vts_status conflicts with existing handler in this location");
+            return NGX_CONF_ERROR;
+        }
+    }
+    
+    // Same for `vts_health`; `health` is never merged, so it is only
+    // set in the location that has the directive.
+    if (conf->health == 1) {
+        clcf = ngx_http_conf_get_module_loc_conf(cf, ngx_http_core_module);
+        if (clcf->handler != ngx_http_vts_health_handler) {
+            ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                               "vts_health conflicts with existing handler in this location");
+            return NGX_CONF_ERROR;
+        }
+    }
+    
+    // `vts_upstream_stats`: accepted and merged, but nothing reads it.
+    ngx_conf_merge_value(conf->enable, prev->enable, 0);
+    ngx_conf_merge_size_value(conf->zone_size, prev->zone_size, 1024*1024);
+    ngx_conf_merge_uint_value(conf->status_mode, prev->status_mode,
+                              NGX_HTTP_VTS_STATUS_METRICS);
+    ngx_conf_merge_value(conf->stream_bytes, prev->stream_bytes, 0);
+    ngx_conf_merge_value(conf->self_monitor, prev->self_monitor, 0);
+    ngx_conf_merge_value(conf->detail_method_status, prev->detail_method_status, 0);
+    ngx_conf_merge_value(conf->track_content_type, prev->track_content_type, 0);
+    ngx_conf_merge_value(conf->track_grpc, prev->track_grpc, 0);
+    ngx_conf_merge_value(conf->track_unique_clients, prev->track_unique_clients, 0);
+    ngx_conf_merge_str_value(conf->location_zone, prev->location_zone, "");
+    ngx_conf_merge_ptr_value(conf->filters, prev->filters, NULL);
+    ngx_conf_merge_ptr_value(conf->expose_zones, prev->expose_zones, NULL);
+    
+    return NGX_CONF_OK;
 }
 
 // Handle vts_zone directive: declare a shared-memory zone and hand it
@@ -245,24 +1213,775 @@ ngx_http_vts_zone_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
     return NGX_CONF_OK;
 }
 
-// Handle vts_status directive
+// Handle vts_status directive.  `vts_status;` serves the Prometheus
+// metrics; `vts_status control=status;` serves the diagnostics report.
 static char *
 ngx_http_vts_status_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
 {
     ngx_http_core_loc_conf_t *clcf;
-    
+    ngx_http_vts_loc_conf_t *vlcf = conf;
+    ngx_str_t *value;
+
     (void)cmd;  // Mark as intentionally unused
-    (void)conf; // Mark as intentionally unused
-    
+
+    vlcf->status_mode = NGX_HTTP_VTS_STATUS_METRICS;
+
+    if (cf->args->nelts == 2) {
+        value = cf->args->elts;
+        if (value[1].len == sizeof("control=status") - 1
+            && ngx_strncmp(value[1].data, "control=status", value[1].len) == 0)
+        {
+            vlcf->status_mode = NGX_HTTP_VTS_STATUS_DIAGNOSTICS;
+        } else {
+            ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                               "invalid vts_status parameter \"%V\"", &value[1]);
+            return NGX_CONF_ERROR;
+        }
+    }
+
+    // Refuse to silently replace another content handler (proxy_pass,
+    // fastcgi_pass, stub_status, ...) set earlier in this location.
+    // The reverse order is caught in merge_loc_conf.
     clcf = ngx_http_conf_get_module_loc_conf(cf, ngx_http_core_module);
+    if (clcf->handler != NULL && clcf->handler != ngx_http_vts_status_handler) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "vts_status conflicts with existing handler in this location");
+        return NGX_CONF_ERROR;
+    }
     clcf->handler = ngx_http_vts_status_handler;
     
     return NGX_CONF_OK;
 }
 
-// Handle vts_upstream_stats directive
+// Handle vts_health directive: serve the health check at this location.
+static char *
+ngx_http_vts_health_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_http_core_loc_conf_t *clcf;
+    ngx_http_vts_loc_conf_t *vlcf = conf;
+
+    (void)cmd;
+
+    // As for vts_status: never silently replace another content handler.
+    clcf = ngx_http_conf_get_module_loc_conf(cf, ngx_http_core_module);
+    if (clcf->handler != NULL && clcf->handler != ngx_http_vts_health_handler) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "vts_health conflicts with existing handler in this location");
+        return NGX_CONF_ERROR;
+    }
+    clcf->handler = ngx_http_vts_health_handler;
+    vlcf->health = 1;
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_expose_zones directive: only the listed server zones
+// (exact names) appear on this location's status page.  Without it
+// every zone does.
+static char *
+ngx_http_vts_expose_zones_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_http_vts_loc_conf_t  *vlcf = conf;
+    ngx_str_t                *value, *zone;
+    ngx_uint_t                i;
+
+    (void)cmd;
+
+    if (vlcf->expose_zones != NGX_CONF_UNSET_PTR) {
+        return "is duplicate";
+    }
+
+    vlcf->expose_zones = ngx_array_create(cf->pool, cf->args->nelts - 1,
+                                          sizeof(ngx_str_t));
+    if (vlcf->expose_zones == NULL) {
+        return NGX_CONF_ERROR;
+    }
+
+    value = cf->args->elts;
+    for (i = 1; i < cf->args->nelts; i++) {
+        if (value[i].len == 0 || value[i].len > NGX_HTTP_VTS_MAX_KEY_BYTES) {
+            ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                               "vts_expose_zones zone \"%V\" must be 1 to %d bytes long",
+                               &value[i], NGX_HTTP_VTS_MAX_KEY_BYTES);
+            return NGX_CONF_ERROR;
+        }
+        zone = ngx_array_push(vlcf->expose_zones);
+        if (zone == NULL) {
+            return NGX_CONF_ERROR;
+        }
+        *zone = value[i];
+    }
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_upstream_stats directive, kept for backward compatibility.
+// ngx_conf_set_flag_slot compares the argument bytewise against "on" and
+// "off" and rejects anything else with "invalid value ... it must be
+// \"on\" or \"off\"", so no other bytes ever reach Rust.
 static char *
 ngx_http_vts_upstream_stats_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
 {
     return ngx_conf_set_flag_slot(cf, cmd, conf);
 }
+
+// Handle vts_max_request_time directive: request/response times above
+// this ceiling are discarded as implausible and counted in
+// `nginx_vts_discarded_observations_total`.
+static char *
+ngx_http_vts_max_request_time_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    ms;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    ms = ngx_parse_time(&value[1], 0);
+    if (ms == NGX_ERROR || ms == 0) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_max_request_time \"%V\"", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_max_request_time_ms((uint64_t) ms);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_connection_refresh_interval directive: scrapes within this
+// interval of the last connection collection reuse its snapshot instead
+// of walking the connection table again.
+static char *
+ngx_http_vts_connection_refresh_interval_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    ms;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    ms = ngx_parse_time(&value[1], 0);
+    if (ms == NGX_ERROR || ms == 0) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_connection_refresh_interval \"%V\"", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_connection_refresh_interval_ms((uint64_t) ms);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_apdex_threshold directive: the satisfied threshold T for
+// `nginx_vts_server_apdex` (tolerating up to 4T).
+static char *
+ngx_http_vts_apdex_threshold_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    ms;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    ms = ngx_parse_time(&value[1], 0);
+    if (ms == NGX_ERROR || ms == 0) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_apdex_threshold \"%V\"", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_apdex_threshold_ms((uint64_t) ms);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_min_window directive: each server zone's minimum request
+// time only covers the current window of this many seconds, so one
+// outlier doesn't pin it for good.  0 keeps the all-time minimum.
+static char *
+ngx_http_vts_min_window_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    secs;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    secs = ngx_parse_time(&value[1], 1);
+    if (secs == NGX_ERROR) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_min_window \"%V\"", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_min_window_secs((uint64_t) secs);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_sample_rate directive: server zones record one request in
+// N, with counters scaled by N.
+static char *
+ngx_http_vts_sample_rate_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    rate;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    rate = ngx_atoi(value[1].data, value[1].len);
+    if (rate == NGX_ERROR || rate < 1) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_sample_rate \"%V\", must be a "
+                           "number of at least 1", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_sample_rate((uint64_t) rate);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_upstream_degraded_threshold directive: upstream servers
+// whose last-minute error rate exceeds this percentage are reported as
+// `degraded` by `nginx_vts_upstream_server_state`.
+static char *
+ngx_http_vts_upstream_degraded_threshold_directive(ngx_conf_t *cf, ngx_command_t *cmd,
+    void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    percent;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    percent = ngx_atoi(value[1].data, value[1].len);
+    if (percent == NGX_ERROR || percent < 1 || percent > 100) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_upstream_degraded_threshold \"%V\", "
+                           "must be a percentage from 1 to 100", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_upstream_degraded_threshold((uint64_t) percent);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_upstream_server_limit directive: each upstream reports at
+// most this many servers, the first by address, and sums the rest into
+// server="__aggregated__".
+static char *
+ngx_http_vts_upstream_server_limit_directive(ngx_conf_t *cf, ngx_command_t *cmd,
+    void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    limit;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    limit = ngx_atoi(value[1].data, value[1].len);
+    if (limit == NGX_ERROR || limit < 1) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_upstream_server_limit \"%V\", "
+                           "must be a positive number of servers", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_upstream_server_limit((uint64_t) limit);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_status_rate directive: each worker generates at most this
+// many status pages per second and answers the rest with a 429.
+static char *
+ngx_http_vts_status_rate_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    rate;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    rate = ngx_atoi(value[1].data, value[1].len);
+    if (rate == NGX_ERROR || rate < 1) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_status_rate \"%V\", "
+                           "must be a positive number of pages per second",
+                           &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_status_rate((uint64_t) rate);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_slow_log_threshold directive: upstream attempts whose
+// request took longer are logged at warn level, at most once per second
+// per server.  0 disables the log.
+static char *
+ngx_http_vts_slow_log_threshold_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    ms;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    ms = ngx_parse_time(&value[1], 0);
+    if (ms == NGX_ERROR) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_slow_log_threshold \"%V\"", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_slow_log_threshold_ms((uint64_t) ms);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_shm_warn_threshold directive: once less than this
+// percentage of the `vts_zone` is free, each worker logs one warning
+// and `nginx_vts_shm_near_full` turns 1.
+static char *
+ngx_http_vts_shm_warn_threshold_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    percent;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    percent = ngx_atoi(value[1].data, value[1].len);
+    if (percent == NGX_ERROR || percent < 1 || percent > 100) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_shm_warn_threshold \"%V\", "
+                           "must be a percentage from 1 to 100", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_shm_warn_threshold((uint64_t) percent);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_max_label_len directive: label values longer than this many
+// bytes are truncated on the status page.
+static char *
+ngx_http_vts_max_label_len_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t   *value;
+    ngx_int_t    len;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    len = ngx_atoi(value[1].data, value[1].len);
+    if (len == NGX_ERROR || len < NGX_HTTP_VTS_MIN_LABEL_LEN) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_max_label_len \"%V\", must be a "
+                           "number of at least %d", &value[1],
+                           NGX_HTTP_VTS_MIN_LABEL_LEN);
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_max_label_len((uint64_t) len);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_disable_zone directive: pause accounting for a server zone
+// (keyed by its first `server_name`) while keeping its counters.
+static char *
+ngx_http_vts_disable_zone_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t  *value;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    if (value[1].len == 0) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "vts_disable_zone requires a zone name");
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_zone_enabled_ffi(value[1].data, value[1].len, 0);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_unix_socket directive: the path the first worker serves
+// the status page on, for scrapers that should not go through HTTP.
+static char *
+ngx_http_vts_unix_socket_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t   *value;
+    const char  *err;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    if (value[1].len == 0) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "vts_unix_socket requires a path");
+        return NGX_CONF_ERROR;
+    }
+
+    // Relative paths are relative to the nginx prefix, as for pid/logs.
+    if (ngx_conf_full_name(cf->cycle, &value[1], 0) != NGX_OK) {
+        return NGX_CONF_ERROR;
+    }
+
+    err = vts_set_unix_socket_path_ffi(value[1].data, value[1].len);
+    if (err != NULL) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "vts_unix_socket \"%V\": %s", &value[1], err);
+        return NGX_CONF_ERROR;
+    }
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_track_unique_clients directive: a plain flag, but `on` is
+// refused in builds without the `unique-clients` feature rather than
+// silently exposing nothing.
+static char *
+ngx_http_vts_track_unique_clients_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_http_vts_loc_conf_t *vlcf = conf;
+    char                    *rv;
+    const char              *err;
+
+    rv = ngx_conf_set_flag_slot(cf, cmd, conf);
+    if (rv != NGX_CONF_OK || !vlcf->track_unique_clients) {
+        return rv;
+    }
+
+    err = vts_unique_clients_supported_ffi();
+    if (err != NULL) {
+        return (char *) err;
+    }
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_state_file directive: where the first worker writes the
+// counters on exit and reads them back on start.
+static char *
+ngx_http_vts_state_file_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t  *value;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    if (value[1].len == 0) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "vts_state_file requires a path");
+        return NGX_CONF_ERROR;
+    }
+
+    // Relative paths are relative to the nginx prefix, as for pid/logs.
+    if (ngx_conf_full_name(cf->cycle, &value[1], 0) != NGX_OK) {
+        return NGX_CONF_ERROR;
+    }
+
+    vts_set_state_file_ffi(value[1].data, value[1].len);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_upstream_key directive: `name` keys upstream servers by
+// their configured name, so a `resolve` server whose address changes
+// stays one series; `addr` (the default) keys them by address.
+static char *
+ngx_http_vts_upstream_key_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t  *value;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    if (ngx_strcmp(value[1].data, "name") == 0) {
+        vts_set_upstream_key_by_name(1);
+
+    } else if (ngx_strcmp(value[1].data, "addr") == 0) {
+        vts_set_upstream_key_by_name(0);
+
+    } else {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_upstream_key \"%V\", "
+                           "expected \"name\" or \"addr\"", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_location_zone directive: count this location's requests
+// under the given name as well as under its server zone.  Nested
+// locations inherit the name unless they set their own.
+static char *
+ngx_http_vts_location_zone_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_http_vts_loc_conf_t  *vlcf = conf;
+    ngx_str_t                *value;
+
+    (void)cmd;
+
+    if (vlcf->location_zone.data != NULL) {
+        return "is duplicate";
+    }
+
+    value = cf->args->elts;
+
+    if (value[1].len == 0 || value[1].len > NGX_HTTP_VTS_MAX_KEY_BYTES) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "vts_location_zone \"%V\" must be 1 to %d bytes long",
+                           &value[1], NGX_HTTP_VTS_MAX_KEY_BYTES);
+        return NGX_CONF_ERROR;
+    }
+
+    vlcf->location_zone = value[1];
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_filter_by_variable directive: count requests under filter
+// group `group`, keyed by the value of `$variable` at log time (e.g. a
+// GeoIP country code).  Repeat it for several groups; as with other
+// array directives, a level that sets any replaces the inherited ones.
+static char *
+ngx_http_vts_filter_by_variable_directive(ngx_conf_t *cf, ngx_command_t *cmd,
+    void *conf)
+{
+    ngx_http_vts_loc_conf_t  *vlcf = conf;
+    ngx_http_vts_filter_t    *filter;
+    ngx_str_t                *value;
+
+    (void)cmd;
+
+    value = cf->args->elts;
+
+    if (value[1].len == 0 || value[1].len > NGX_HTTP_VTS_MAX_KEY_BYTES) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "vts_filter_by_variable group \"%V\" must be 1 to %d bytes long",
+                           &value[1], NGX_HTTP_VTS_MAX_KEY_BYTES);
+        return NGX_CONF_ERROR;
+    }
+
+    if (value[2].len < 2 || value[2].data[0] != '$') {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid variable name \"%V\"", &value[2]);
+        return NGX_CONF_ERROR;
+    }
+    value[2].len--;
+    value[2].data++;
+
+    if (vlcf->filters == NGX_CONF_UNSET_PTR) {
+        vlcf->filters = ngx_array_create(cf->pool, 2, sizeof(ngx_http_vts_filter_t));
+        if (vlcf->filters == NULL) {
+            return NGX_CONF_ERROR;
+        }
+    }
+
+    filter = ngx_array_push(vlcf->filters);
+    if (filter == NULL) {
+        return NGX_CONF_ERROR;
+    }
+
+    filter->group = value[1];
+    filter->index = ngx_http_get_variable_index(cf, &value[2]);
+    if (filter->index == NGX_ERROR) {
+        return NGX_CONF_ERROR;
+    }
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_zone_label directive: `name=value` is added to every series
+// of this server block's zone.  Names are checked here so errors point
+// at the directive; the labels are handed to Rust in postconfiguration,
+// once the block's server_name is known.
+static char *
+ngx_http_vts_zone_label_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_http_vts_loc_conf_t  *vlcf = conf;
+    ngx_str_t                *value;
+    ngx_keyval_t             *kv;
+    u_char                   *eq;
+    const char               *err;
+    ngx_uint_t                i;
+
+    (void)cmd;
+
+    value = cf->args->elts;
+
+    eq = ngx_strlchr(value[1].data, value[1].data + value[1].len, '=');
+    if (eq == NULL) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "vts_zone_label \"%V\" is not name=value", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    if (vlcf->zone_labels == NULL) {
+        vlcf->zone_labels = ngx_array_create(cf->pool, 4, sizeof(ngx_keyval_t));
+        if (vlcf->zone_labels == NULL) {
+            return NGX_CONF_ERROR;
+        }
+    }
+
+    err = vts_check_zone_label_ffi(value[1].data, eq - value[1].data,
+                                   vlcf->zone_labels->nelts);
+    if (err != NULL) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "vts_zone_label \"%V\": %s", &value[1], err);
+        return NGX_CONF_ERROR;
+    }
+
+    kv = vlcf->zone_labels->elts;
+    for (i = 0; i < vlcf->zone_labels->nelts; i++) {
+        if ((size_t) (eq - value[1].data) == kv[i].key.len
+            && ngx_strncmp(kv[i].key.data, value[1].data, kv[i].key.len) == 0)
+        {
+            ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                               "vts_zone_label \"%V\": duplicate label name",
+                               &value[1]);
+            return NGX_CONF_ERROR;
+        }
+    }
+
+    kv = ngx_array_push(vlcf->zone_labels);
+    if (kv == NULL) {
+        return NGX_CONF_ERROR;
+    }
+    kv->key.data = value[1].data;
+    kv->key.len = eq - value[1].data;
+    kv->value.data = eq + 1;
+    kv->value.len = value[1].data + value[1].len - (eq + 1);
+
+    return NGX_CONF_OK;
+}
+
+// Handle vts_upstream_zone directive: names the pool of the enclosing
+// upstream block, added as `zone="..."` to its server series.
+static char *
+ngx_http_vts_upstream_zone_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t                     *value;
+    ngx_http_upstream_srv_conf_t  *uscf;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+    uscf = ngx_http_conf_get_module_srv_conf(cf, ngx_http_upstream_module);
+
+    if (value[1].len == 0) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_upstream_zone \"%V\"", &value[1]);
+        return NGX_CONF_ERROR;
+    }
+
+    return (char *) vts_set_upstream_zone_ffi(uscf->host.data, uscf->host.len,
+                                              value[1].data, value[1].len);
+}
+
+// Handle vts_zone_alias directive: report the server zone named by the
+// first argument under the second, summed with any zone that already
+// has that name.  Counters stay stored under the original name.
+static char *
+ngx_http_vts_zone_alias_directive(ngx_conf_t *cf, ngx_command_t *cmd, void *conf)
+{
+    ngx_str_t  *value;
+
+    (void)cmd;
+    (void)conf;
+
+    value = cf->args->elts;
+
+    if (value[1].len == 0 || value[2].len == 0) {
+        ngx_conf_log_error(NGX_LOG_EMERG, cf, 0,
+                           "invalid vts_zone_alias \"%V\" \"%V\"",
+                           &value[1], &value[2]);
+        return NGX_CONF_ERROR;
+    }
+
+    return (char *) vts_add_zone_alias_ffi(value[1].data, value[1].len,
+                                           value[2].data, value[2].len);
+}
+
+// Hand each server block's `vts_zone_label`s to Rust, keyed by the
+// block's first server_name like every other server-zone lookup.
+static ngx_int_t
+ngx_http_vts_apply_zone_labels(ngx_conf_t *cf)
+{
+    ngx_http_core_main_conf_t   *cmcf;
+    ngx_http_core_srv_conf_t   **cscfp;
+    ngx_http_vts_loc_conf_t     *vlcf;
+    ngx_keyval_t                *kv;
+    const char                  *err;
+    ngx_uint_t                   s, i;
+
+    cmcf = ngx_http_conf_get_module_main_conf(cf, ngx_http_core_module);
+    cscfp = cmcf->servers.elts;
+
+    for (s = 0; s < cmcf->servers.nelts; s++) {
+        vlcf = cscfp[s]->ctx->loc_conf[ngx_http_vts_module.ctx_index];
+        if (vlcf->zone_labels == NULL) {
+            continue;
+        }
+
+        kv = vlcf->zone_labels->elts;
+        for (i = 0; i < vlcf->zone_labels->nelts; i++) {
+            err = vts_add_zone_label_ffi(cscfp[s]->server_name.data,
+                                         cscfp[s]->server_name.len,
+                                         kv[i].key.data, kv[i].key.len,
+                                         kv[i].value.data, kv[i].value.len);
+            if (err != NULL) {
+                ngx_log_error(NGX_LOG_EMERG, cf->log, 0,
+                              "vts_zone_label \"%V\" in server \"%V\": %s",
+                              &kv[i].key, &cscfp[s]->server_name, err);
+                return NGX_ERROR;
+            }
+        }
+    }
+
+    return NGX_OK;
+}
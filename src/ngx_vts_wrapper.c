@@ -9,10 +9,12 @@
 #include <ngx_core.h>
 #include <ngx_http.h>
 
-// External Rust functions
-extern void vts_track_upstream_request(
+// External Rust functions.  Returns the request time in ms when the
+// attempt should get a `vts_slow_log_threshold` line, otherwise 0.
+extern uint64_t vts_track_upstream_request(
     const char* upstream_name,
     const char* server_addr,
+    const char* server_name,
     uint64_t start_sec,
     uint64_t start_msec,
     uint64_t upstream_response_time,
@@ -21,107 +23,824 @@ extern void vts_track_upstream_request(
     uint16_t status_code
 );
 
-// External Rust functions
-extern void vts_update_server_stats_ffi(
+// Record a request's time outside the upstream once, on the server of
+// its final attempt; `upstream_response_time` is summed over attempts.
+extern void vts_track_upstream_local_time(
+    const char* upstream_name,
+    const char* server_addr,
     const char* server_name,
-    uint16_t status,
-    uint64_t bytes_in,
-    uint64_t bytes_out,
-    uint64_t request_time
+    uint64_t start_sec,
+    uint64_t start_msec,
+    uint64_t upstream_response_time
+);
+
+// Whether `vts_upstream_key name` is in effect.
+extern uint8_t vts_upstream_key_by_name(void);
+
+// Returns 1 the first time this worker finds the `vts_zone` below
+// `vts_shm_warn_threshold`, with the pool's free and total bytes.
+extern uint8_t vts_shm_near_full_warning(uint64_t *free, uint64_t *total);
+
+// External Rust functions
+extern void vts_log_server_request(
+    ngx_http_request_t *r,
+    uint8_t rate_limited,
+    uint64_t bytes_streamed
+);
+
+extern void vts_track_body_bytes(
+    ngx_http_request_t *r,
+    uint64_t sent
 );
 
 extern void vts_update_cache_stats_ffi(
     const char* zone_name,
+    const char* upstream_name,
     uint8_t cache_status,
+    uint64_t bytes_sent,
     uint64_t max_size,
     uint64_t used_size
 );
 
+extern void vts_track_cache_lock_ffi(
+    const char* zone_name,
+    uint8_t timed_out
+);
+
+extern void vts_server_connection_transition_ffi(
+    const char* server_name,
+    uint8_t from,
+    uint8_t to
+);
+
+// TLS handshake outcome (NGX_OK or not) and whether the session was
+// resumed.
+extern void vts_track_ssl(ngx_int_t result, uint8_t reused);
+
 // External Rust initialization function
 extern ngx_int_t ngx_http_vts_init_rust_module(ngx_conf_t *cf);
 
+// Seed one configured upstream server address with zeroed counters.
+// Returns 1 when the upstream block already lists that address.
+extern uint8_t vts_seed_upstream_server_ffi(
+    const u_char *upstream,
+    size_t upstream_len,
+    const u_char *server,
+    size_t server_len
+);
+
+// Mark one upstream server down (1) or up (0), from its peer's `down`.
+extern void vts_set_upstream_down_ffi(
+    const u_char *upstream,
+    size_t upstream_len,
+    const u_char *server,
+    size_t server_len,
+    uint8_t down
+);
+
 // Module struct defined in ngx_http_vts_module.c.  We consult its
 // per-request ctx slot to detect requests served by the vts_status
 // content handler (so Prometheus scrapes don't inflate server_zone
 // counters).
 extern ngx_module_t ngx_http_vts_module;
 
+// `vts_stream_bytes` for the request's location (ngx_http_vts_module.c).
+extern ngx_flag_t ngx_http_vts_stream_bytes_enabled(ngx_http_request_t *r);
+
+// `vts_self_monitor` for the request's location (ngx_http_vts_module.c).
+extern ngx_flag_t ngx_http_vts_self_monitor_enabled(ngx_http_request_t *r);
+
+// `vts_detail_method_status` for the request's location (ngx_http_vts_module.c).
+extern ngx_flag_t ngx_http_vts_detail_method_status_enabled(ngx_http_request_t *r);
+
+// `vts_track_content_type` for the request's location (ngx_http_vts_module.c).
+extern ngx_flag_t ngx_http_vts_track_content_type_enabled(ngx_http_request_t *r);
+
+// `vts_track_grpc` for the request's location (ngx_http_vts_module.c).
+extern ngx_flag_t ngx_http_vts_track_grpc_enabled(ngx_http_request_t *r);
+
+// `vts_track_unique_clients` for the request's location (ngx_http_vts_module.c).
+extern ngx_flag_t ngx_http_vts_track_unique_clients_enabled(ngx_http_request_t *r);
+
+// `vts_location_zone` for the request's location (ngx_http_vts_module.c).
+extern ngx_str_t *ngx_http_vts_location_zone(ngx_http_request_t *r);
+
+// `vts_filter_by_variable` accounting (ngx_http_vts_module.c).
+extern void ngx_http_vts_log_filters(ngx_http_request_t *r);
+
+extern void vts_log_location_request(
+    ngx_http_request_t *r,
+    const u_char *location,
+    size_t len
+);
+
+extern void vts_track_method_status_ffi(
+    const char* zone_name,
+    uint64_t method,
+    uint16_t status
+);
+
+extern void vts_track_grpc_status_ffi(
+    const char* zone_name,
+    const u_char *grpc_status,
+    size_t len
+);
+
+extern void vts_track_unique_client_ffi(
+    const char* zone_name,
+    const u_char *addr,
+    size_t len
+);
+
+extern void vts_track_content_type_ffi(
+    const char* zone_name,
+    const u_char *content_type,
+    size_t len
+);
+
+extern void vts_track_client_body_error_ffi(
+    const char* zone_name,
+    uint16_t status
+);
+
+extern void vts_track_request_headers_ffi(
+    const char* zone_name,
+    uint64_t count,
+    uint64_t bytes
+);
+
+static ngx_http_output_header_filter_pt ngx_http_vts_next_header_filter;
+static ngx_http_output_body_filter_pt ngx_http_vts_next_body_filter;
+
+// Values of `r->limit_req_status` / `r->limit_conn_status` (nginx
+// 1.17.6+).  The limiter modules keep these private to their .c files.
+#define NGX_HTTP_VTS_LIMIT_REQ_REJECTED   3
+#define NGX_HTTP_VTS_LIMIT_CONN_REJECTED  2
+
+// Connection phases passed to `vts_server_connection_transition_ffi`;
+// must match `ConnPhase::from_raw` on the Rust side.
+#define NGX_HTTP_VTS_CONN_IDLE     0
+#define NGX_HTTP_VTS_CONN_READING  1
+#define NGX_HTTP_VTS_CONN_WRITING  2
+#define NGX_HTTP_VTS_CONN_UPGRADED 3
+
+// Per-request connection-gauge state.  Hung off the request pool as
+// cleanup data rather than the module ctx: nginx zeroes module ctxs on
+// internal redirect (try_files, error_page, ...), but pool cleanups
+// survive until the request is freed, so the gauge always gets its
+// matching decrement.  `streamed` is the part of `c->sent` the body
+// filter has already reported, for the same reason.
+typedef struct {
+    ngx_uint_t  state;
+    off_t       streamed;
+    u_char      zone[256];
+} ngx_http_vts_conn_t;
+
+static void ngx_http_vts_conn_cleanup(void *data);
+
+/*
+ * Resolve the server zone name for a request into a NUL-terminated
+ * buffer.
+ *
+ * Key on the matched server block's first `server_name` rather than
+ * the raw `Host` header (`r->headers_in.server`): that header is
+ * attacker-controlled and has unbounded cardinality, which would let
+ * any client trivially blow up the shared table by sending varying
+ * Host values.
+ */
+static void
+ngx_http_vts_server_zone_name(ngx_http_request_t *r, u_char *buf, size_t size)
+{
+    ngx_http_core_srv_conf_t *cscf;
+    ngx_str_t server_zone;
+
+    // Same names as the Rust side's `request_zone_name`: a request
+    // that never named a host is "__unknown__", whichever server
+    // block nginx defaulted to; a block without a name is "_".
+    if (r->headers_in.server.len == 0) {
+        ngx_cpystrn(buf, (u_char*)"__unknown__", size);
+        return;
+    }
+
+    cscf = ngx_http_get_module_srv_conf(r, ngx_http_core_module);
+    if (cscf != NULL && cscf->server_name.len > 0) {
+        server_zone = cscf->server_name;
+    } else {
+        ngx_str_set(&server_zone, "_");
+    }
+
+    if (server_zone.len > 0 && server_zone.len < size - 1) {
+        ngx_memcpy(buf, server_zone.data, server_zone.len);
+        buf[server_zone.len] = '\0';
+    } else {
+        ngx_cpystrn(buf, (u_char*)"_", size);
+    }
+}
+
+/*
+ * Find this request's connection-gauge state, if it is being tracked.
+ */
+static ngx_http_vts_conn_t *
+ngx_http_vts_get_conn(ngx_http_request_t *r)
+{
+    ngx_pool_cleanup_t *cln;
+
+    for (cln = r->pool->cleanup; cln; cln = cln->next) {
+        if (cln->handler == ngx_http_vts_conn_cleanup) {
+            return cln->data;
+        }
+    }
+
+    return NULL;
+}
+
+static void
+ngx_http_vts_conn_cleanup(void *data)
+{
+    ngx_http_vts_conn_t *conn = data;
+
+    if (conn->state != NGX_HTTP_VTS_CONN_IDLE) {
+        vts_server_connection_transition_ffi(
+            (const char *)conn->zone,
+            (uint8_t)conn->state,
+            NGX_HTTP_VTS_CONN_IDLE
+        );
+        conn->state = NGX_HTTP_VTS_CONN_IDLE;
+    }
+}
+
+/*
+ * POST_READ_PHASE handler: the request has been matched to a server
+ * block, so start counting it against that zone as "reading".
+ */
+static ngx_int_t
+ngx_http_vts_conn_begin_handler(ngx_http_request_t *r)
+{
+    ngx_pool_cleanup_t *cln;
+    ngx_http_vts_conn_t *conn;
+
+    if (r != r->main || ngx_http_vts_get_conn(r) != NULL) {
+        return NGX_DECLINED;
+    }
+
+    cln = ngx_pool_cleanup_add(r->pool, sizeof(ngx_http_vts_conn_t));
+    if (cln == NULL) {
+        return NGX_DECLINED;
+    }
+
+    conn = cln->data;
+    ngx_http_vts_server_zone_name(r, conn->zone, sizeof(conn->zone));
+    conn->state = NGX_HTTP_VTS_CONN_READING;
+    conn->streamed = 0;
+    cln->handler = ngx_http_vts_conn_cleanup;
+
+    vts_server_connection_transition_ffi(
+        (const char *)conn->zone,
+        NGX_HTTP_VTS_CONN_IDLE,
+        NGX_HTTP_VTS_CONN_READING
+    );
+
+    return NGX_DECLINED;
+}
+
+/*
+ * PRECONTENT_PHASE handler: the request is about to produce its
+ * response, so move it to "writing".  Requests finalized earlier
+ * (rewrite `return`, access denial) go straight from "reading" to
+ * idle.
+ */
+static ngx_int_t
+ngx_http_vts_conn_writing_handler(ngx_http_request_t *r)
+{
+    ngx_http_vts_conn_t *conn;
+
+    if (r != r->main) {
+        return NGX_DECLINED;
+    }
+
+    conn = ngx_http_vts_get_conn(r);
+    if (conn != NULL && conn->state == NGX_HTTP_VTS_CONN_READING) {
+        vts_server_connection_transition_ffi(
+            (const char *)conn->zone,
+            NGX_HTTP_VTS_CONN_READING,
+            NGX_HTTP_VTS_CONN_WRITING
+        );
+        conn->state = NGX_HTTP_VTS_CONN_WRITING;
+    }
+
+    return NGX_DECLINED;
+}
+
+/*
+ * Header filter: a 101 Switching Protocols response turns the request
+ * into a long-lived tunnel (WebSocket), so move it to "upgraded" until
+ * the request is freed, when the pool cleanup takes it back to idle.
+ */
+static ngx_int_t
+ngx_http_vts_header_filter(ngx_http_request_t *r)
+{
+    ngx_http_vts_conn_t *conn;
+
+    if (r == r->main
+        && r->headers_out.status == NGX_HTTP_SWITCHING_PROTOCOLS)
+    {
+        conn = ngx_http_vts_get_conn(r);
+        if (conn != NULL
+            && conn->state != NGX_HTTP_VTS_CONN_IDLE
+            && conn->state != NGX_HTTP_VTS_CONN_UPGRADED)
+        {
+            vts_server_connection_transition_ffi(
+                (const char *)conn->zone,
+                (uint8_t)conn->state,
+                NGX_HTTP_VTS_CONN_UPGRADED
+            );
+            conn->state = NGX_HTTP_VTS_CONN_UPGRADED;
+        }
+    }
+
+    return ngx_http_vts_next_header_filter(r);
+}
+
+/*
+ * Body filter for `vts_stream_bytes on`: after each chunk is passed
+ * down the chain, report the growth of `c->sent` so long-lived
+ * responses (SSE, large downloads) update `bytes_out` as they go.  The
+ * reported total is handed to the LOG_PHASE update, which leaves it
+ * out.  Subrequest output lands in the same `c->sent` and is picked up
+ * on the main request's next chunk or at log time.
+ */
+static ngx_int_t
+ngx_http_vts_body_filter(ngx_http_request_t *r, ngx_chain_t *in)
+{
+    ngx_int_t rc;
+    ngx_http_vts_conn_t *conn;
+
+    rc = ngx_http_vts_next_body_filter(r, in);
+
+    if (r != r->main
+        || ngx_http_get_module_ctx(r, ngx_http_vts_module) != NULL
+        || !ngx_http_vts_stream_bytes_enabled(r))
+    {
+        return rc;
+    }
+
+    conn = ngx_http_vts_get_conn(r);
+    if (conn != NULL && r->connection->sent > conn->streamed) {
+        vts_track_body_bytes(r, (uint64_t)(r->connection->sent - conn->streamed));
+        conn->streamed = r->connection->sent;
+    }
+
+    return rc;
+}
+
 /*
  * LOG_PHASE handler implementation
  * 
  * This handler is called by nginx during the LOG_PHASE for each request.
  * It extracts upstream information and forwards it to the Rust implementation.
  */
+// Configured `server` name (e.g. `backend.example.com:8080`) of the
+// peer an upstream attempt went to, for `vts_upstream_key name`.  The
+// stock balancers (round robin, hash, ip_hash, least_conn, random, with
+// or without keepalive) build on the round-robin peer list and report
+// the attempt's peer as a pointer to the `name` of one of its peers,
+// whose `server` is the configured name.  The peer is looked up in that
+// list, primary then backup, rather than derived from the pointer, as
+// a third-party balancer may supply a name of its own; NULL when
+// nothing matches, including when no peer was live.
+static ngx_str_t *
+ngx_http_vts_upstream_server_name(ngx_http_upstream_srv_conf_t *uscf, ngx_str_t *peer)
+{
+    ngx_str_t                     *server;
+    ngx_http_upstream_rr_peer_t   *rr;
+    ngx_http_upstream_rr_peers_t  *peers, *list;
+
+    peers = uscf->peer.data;
+    if (peers == NULL) {
+        return NULL;
+    }
+
+    server = NULL;
+
+    ngx_http_upstream_rr_peers_rlock(peers);
+
+    for (list = peers; list != NULL && server == NULL; list = list->next) {
+        for (rr = list->peer; rr != NULL; rr = rr->next) {
+            if (&rr->name == peer) {
+                server = rr->server.len > 0 ? &rr->server : NULL;
+                break;
+            }
+        }
+    }
+
+    ngx_http_upstream_rr_peers_unlock(peers);
+
+    return server;
+}
+
+// Copy every upstream peer's `down` flag into the statistics, so the
+// page rendered next reports `nginx_vts_upstream_server_up`,
+// `nginx_vts_upstream_fully_down` and `nginx_vts_upstream_server_state`
+// from the running configuration.  Called on the event thread before
+// the status handler or the `vts_unix_socket` refresh renders; walks
+// the same round-robin peer lists as ngx_http_vts_upstream_server_name,
+// backups included.
+void
+ngx_http_vts_sync_upstream_peers(void)
+{
+    ngx_uint_t                      i;
+    ngx_http_upstream_rr_peer_t    *rr;
+    ngx_http_upstream_rr_peers_t   *peers, *list;
+    ngx_http_upstream_srv_conf_t  **uscfp;
+    ngx_http_upstream_main_conf_t  *umcf;
+
+    umcf = ngx_http_cycle_get_module_main_conf(ngx_cycle,
+                                               ngx_http_upstream_module);
+    if (umcf == NULL) {
+        return;
+    }
+
+    uscfp = umcf->upstreams.elts;
+
+    for (i = 0; i < umcf->upstreams.nelts; i++) {
+        peers = uscfp[i]->peer.data;
+        if (uscfp[i]->servers == NULL || peers == NULL) {
+            continue;
+        }
+
+        ngx_http_upstream_rr_peers_rlock(peers);
+
+        for (list = peers; list != NULL; list = list->next) {
+            for (rr = list->peer; rr != NULL; rr = rr->next) {
+                vts_set_upstream_down_ffi(uscfp[i]->host.data,
+                                          uscfp[i]->host.len,
+                                          rr->name.data, rr->name.len,
+                                          rr->down ? 1 : 0);
+            }
+        }
+
+        ngx_http_upstream_rr_peers_unlock(peers);
+    }
+}
+
+#if (NGX_HTTP_SSL)
+// Marker cleanup on a client connection's pool, added once its TLS
+// handshake has been counted.
+static void
+ngx_http_vts_ssl_counted(void *data)
+{
+}
+
+// Whether the handshake of `c` still has to be counted; marks it
+// counted.  The mark lives on the connection's own pool, so it lasts
+// exactly as long as the connection, whichever stream or request gets
+// here first.
+static ngx_uint_t
+ngx_http_vts_ssl_count_once(ngx_connection_t *c)
+{
+    ngx_pool_cleanup_t  *cln;
+
+    if (c->pool == NULL) {
+        return 0;
+    }
+
+    for (cln = c->pool->cleanup; cln; cln = cln->next) {
+        if (cln->handler == ngx_http_vts_ssl_counted) {
+            return 0;
+        }
+    }
+
+    cln = ngx_pool_cleanup_add(c->pool, 0);
+    if (cln == NULL) {
+        return 0;
+    }
+
+    cln->handler = ngx_http_vts_ssl_counted;
+
+    return 1;
+}
+
+// Report the completed TLS handshake of the client connection carrying
+// `r`, once per connection, so keepalive and multiplexed requests
+// don't count the same handshake twice.  HTTP/2 streams run on a fake
+// connection; the handshake belongs to the real one underneath.
+static void
+ngx_http_vts_track_ssl(ngx_http_request_t *r)
+{
+    ngx_connection_t *c = r->connection;
+
+#if (NGX_HTTP_V2)
+    if (r->stream) {
+        c = r->stream->connection->connection;
+    }
+#endif
+
+#if (NGX_HTTP_V3)
+    // Every QUIC stream is its own connection.
+    if (c->quic) {
+        return;
+    }
+#endif
+
+    if (c->ssl == NULL || !ngx_http_vts_ssl_count_once(c)) {
+        return;
+    }
+
+    vts_track_ssl(NGX_OK,
+                  SSL_session_reused(c->ssl->connection) ? 1 : 0);
+}
+
+// nginx's own info callback, which ours chains to.
+static void (*ngx_http_vts_ssl_info_next)(const ngx_ssl_conn_t *ssl_conn,
+    int where, int ret);
+
+// Failed handshakes never reach an HTTP module, so they are counted
+// from OpenSSL's info callback instead: a fatal alert, sent or
+// received, before the handshake finished.  Failures without an
+// alert, such as the client closing mid-handshake, are not seen.
+static void
+ngx_http_vts_ssl_info_callback(const ngx_ssl_conn_t *ssl_conn, int where,
+    int ret)
+{
+    ngx_connection_t  *c;
+
+    if (ngx_http_vts_ssl_info_next != NULL) {
+        ngx_http_vts_ssl_info_next(ssl_conn, where, ret);
+    }
+
+    if (!(where & SSL_CB_ALERT)
+        || (ret >> 8) != SSL3_AL_FATAL
+        || SSL_is_init_finished(ssl_conn))
+    {
+        return;
+    }
+
+    c = ngx_ssl_get_connection((ngx_ssl_conn_t *) ssl_conn);
+    if (c == NULL) {
+        return;
+    }
+
+#if (NGX_HTTP_V3)
+    if (c->quic) {
+        return;
+    }
+#endif
+
+    if (ngx_http_vts_ssl_count_once(c)) {
+        vts_track_ssl(NGX_ERROR, 0);
+    }
+}
+
+// Chain the info callback into the TLS context of every server block
+// that terminates TLS.
+static void
+ngx_http_vts_ssl_hook(ngx_conf_t *cf)
+{
+    ngx_uint_t                  i;
+    ngx_http_ssl_srv_conf_t    *sscf;
+    ngx_http_core_srv_conf_t  **cscfp;
+    ngx_http_core_main_conf_t  *cmcf;
+
+    cmcf = ngx_http_conf_get_module_main_conf(cf, ngx_http_core_module);
+    cscfp = cmcf->servers.elts;
+
+    for (i = 0; i < cmcf->servers.nelts; i++) {
+        sscf = cscfp[i]->ctx->srv_conf[ngx_http_ssl_module.ctx_index];
+        if (sscf == NULL
+            || sscf->ssl.ctx == NULL
+            || SSL_CTX_get_info_callback(sscf->ssl.ctx)
+               == ngx_http_vts_ssl_info_callback)
+        {
+            continue;
+        }
+
+        if (ngx_http_vts_ssl_info_next == NULL) {
+            ngx_http_vts_ssl_info_next =
+                SSL_CTX_get_info_callback(sscf->ssl.ctx);
+        }
+
+        SSL_CTX_set_info_callback(sscf->ssl.ctx,
+                                  ngx_http_vts_ssl_info_callback);
+    }
+}
+#endif
+
+/*
+ * Feed the request-header histograms of the request's server zone.
+ *
+ * Size is what the client sent: request line, every `name: value`
+ * line and the CRLFs, as the headers were parsed (for HTTP/2 and
+ * HTTP/3, the decoded header list).  Done at LOG_PHASE rather than
+ * right after header parsing so requests rejected there (400, 494
+ * "Request Header Or Cookie Too Large") are counted too; those are
+ * the ones abuse detection cares about.  Headers parsed before a 494
+ * are in the list, the oversized one is not.
+ */
+static void
+ngx_http_vts_track_request_headers(ngx_http_request_t *r)
+{
+    u_char zone_buf[256];
+    ngx_list_part_t *part;
+    ngx_table_elt_t *h;
+    ngx_uint_t i;
+    uint64_t count = 0;
+    uint64_t bytes = r->request_line.len + 2 + 2;
+
+    part = &r->headers_in.headers.part;
+    h = part->elts;
+
+    for (i = 0; /* void */; i++) {
+        if (i >= part->nelts) {
+            if (part->next == NULL) {
+                break;
+            }
+            part = part->next;
+            h = part->elts;
+            i = 0;
+        }
+
+        count++;
+        bytes += h[i].key.len + sizeof(": ") - 1 + h[i].value.len + 2;
+    }
+
+    ngx_http_vts_server_zone_name(r, zone_buf, sizeof(zone_buf));
+    vts_track_request_headers_ffi((const char *)zone_buf, count, bytes);
+}
+
+/*
+ * The response's `grpc-status` for `vts_track_grpc`: the upstream's
+ * trailer, or its header for a trailers-only response.  NULL when there
+ * is neither, i.e. the response is not gRPC.
+ */
+static ngx_http_variable_value_t *
+ngx_http_vts_grpc_status(ngx_http_request_t *r)
+{
+    static ngx_str_t names[] = {
+        ngx_string("upstream_trailer_grpc_status"),
+        ngx_string("upstream_http_grpc_status"),
+    };
+    ngx_http_variable_value_t *vv;
+    ngx_uint_t i;
+
+    for (i = 0; i < sizeof(names) / sizeof(names[0]); i++) {
+        vv = ngx_http_get_variable(r, &names[i],
+                                   ngx_hash_key(names[i].data, names[i].len));
+        if (vv != NULL && !vv->not_found && vv->len > 0) {
+            return vv;
+        }
+    }
+
+    return NULL;
+}
+
 static ngx_int_t
 ngx_http_vts_log_handler(ngx_http_request_t *r)
 {
     ngx_http_upstream_t *u;
+    ngx_http_vts_conn_t *conn;
+    ngx_str_t *location_zone;
     ngx_str_t upstream_name = ngx_null_string;
     u_char upstream_name_buf[256];
     u_char server_addr_buf[256];
     u_char server_name_buf[256];
-    ngx_http_core_srv_conf_t *cscf;
-    ngx_str_t server_zone;
+    ngx_str_t *server_name;
+    ngx_flag_t key_by_name;
+    uint64_t slow_ms;
+    uint64_t shm_free, shm_total;
+    uint64_t upstream_time;
+    ngx_uint_t attempts;
 
-    // Count each user-facing request exactly once.  nginx fires the
-    // LOG_PHASE handler for every subrequest as well as the main
-    // request (auth_request, addition, SSI, X-Accel-Redirect, …);
-    // letting those through would double-count both server-zone and
-    // upstream counters.  `r->main` always points at the top-level
-    // request, so `r == r->main` selects exactly the main one.
+    // Count each user-facing request exactly once.  With
+    // `log_subrequest on` nginx fires the LOG_PHASE handler for every
+    // subrequest as well as the main request (auth_request, addition,
+    // SSI, …); counting those as requests would double-count both
+    // server-zone and upstream counters.  `r->main` always points at
+    // the top-level request, so `r == r->main` selects exactly the main
+    // one; the others only go to `nginx_vts_server_subrequests_total`.
+    // Under the default `log_subrequest off` subrequests never get
+    // here, and that family stays at 0.
     if (r != r->main) {
+        vts_log_server_request(r, 0, 0);
         return NGX_DECLINED;
     }
 
-    // Skip Prometheus scrapes: the vts_status content handler sets
-    // a non-NULL ctx on the request before rendering, which lets us
-    // exclude /status from server_zone counters here.  Otherwise
-    // every scrape would inflate `nginx_vts_server_requests_total`
-    // for whichever vhost hosts /status.
-    if (ngx_http_get_module_ctx(r, ngx_http_vts_module) != NULL) {
+#if (NGX_HTTP_SSL)
+    // Counted before the scrape skip below: the handshake happened
+    // whatever the first request turns out to be.
+    ngx_http_vts_track_ssl(r);
+#endif
+
+    // Skip Prometheus scrapes and health probes: the vts_status and
+    // vts_health content handlers set a non-NULL ctx on the request,
+    // which lets us exclude them from server_zone counters here.
+    // Otherwise every scrape would inflate
+    // `nginx_vts_server_requests_total` for whichever vhost hosts
+    // /status.  `vts_self_monitor on` keeps them, so the scrape's own
+    // requests and bytes show up under the status location's server
+    // zone.
+    if (ngx_http_get_module_ctx(r, ngx_http_vts_module) != NULL
+        && !ngx_http_vts_self_monitor_enabled(r))
+    {
         return NGX_DECLINED;
     }
 
     // ----- server zone update (always for main requests) -----
 
-    // Key on the matched server block's first `server_name` rather
-    // than the raw `Host` header (`r->headers_in.server`): that
-    // header is attacker-controlled and has unbounded cardinality,
-    // which would let any client trivially blow up the shared table
-    // by sending varying Host values.
-    cscf = ngx_http_get_module_srv_conf(r, ngx_http_core_module);
-    if (cscf != NULL && cscf->server_name.len > 0) {
-        server_zone = cscf->server_name;
-    } else {
-        ngx_str_set(&server_zone, "_");
+    // Requests rejected by limit_req / limit_conn are counted apart
+    // from real 5xx so a limiter 503 can't be mistaken for a backend
+    // failure.  Dry-run rejections are not actual rejections.
+    uint8_t rate_limited =
+        r->main->limit_req_status == NGX_HTTP_VTS_LIMIT_REQ_REJECTED
+        || r->main->limit_conn_status == NGX_HTTP_VTS_LIMIT_CONN_REJECTED;
+
+    // Zone, status, byte counts and elapsed time are read off the
+    // request on the Rust side (see `RequestRef`).  Bytes the body
+    // filter already reported are passed along so they aren't counted
+    // twice.
+    conn = ngx_http_vts_get_conn(r);
+    vts_log_server_request(r, rate_limited,
+                           conn != NULL ? (uint64_t)conn->streamed : 0);
+
+    // `vts_shm_warn_threshold`: one line per worker before inserts into
+    // the shared zone start failing.
+    if (vts_shm_near_full_warning(&shm_free, &shm_total)) {
+        ngx_log_error(NGX_LOG_WARN, r->connection->log, 0,
+                      "vts: shared memory zone is near full: "
+                      "%uL of %uL bytes free", shm_free, shm_total);
     }
 
-    if (server_zone.len > 0 && server_zone.len < sizeof(server_name_buf) - 1) {
-        ngx_memcpy(server_name_buf, server_zone.data, server_zone.len);
-        server_name_buf[server_zone.len] = '\0';
-    } else {
-        ngx_cpystrn(server_name_buf, (u_char*)"_", sizeof(server_name_buf));
+    // A 408 or 400 after body reading started (`r->request_body` is
+    // only set by ngx_http_read_client_request_body) is a body read
+    // failure: client_body_timeout, or a malformed/truncated body.
+    if (r->request_body != NULL
+        && (r->headers_out.status == NGX_HTTP_REQUEST_TIME_OUT
+            || r->headers_out.status == NGX_HTTP_BAD_REQUEST))
+    {
+        u_char zone_buf[256];
+
+        ngx_http_vts_server_zone_name(r, zone_buf, sizeof(zone_buf));
+        vts_track_client_body_error_ffi((const char *)zone_buf,
+                                        (uint16_t)r->headers_out.status);
     }
 
-    // Calculate total request time in milliseconds using nginx's builtin calculation
-    ngx_msec_t request_time;
-    ngx_time_t *tp = ngx_timeofday();
-    request_time = (ngx_msec_t) ((tp->sec - r->start_sec) * 1000 + (tp->msec - r->start_msec));
+    // Opt-in method x status-class cross-tab for the same zone.
+    if (ngx_http_vts_detail_method_status_enabled(r)) {
+        u_char zone_buf[256];
 
-    // Get response status (use r->headers_out.status if available, otherwise default)
-    ngx_uint_t response_status = r->headers_out.status ? r->headers_out.status : 200;
+        ngx_http_vts_server_zone_name(r, zone_buf, sizeof(zone_buf));
+        vts_track_method_status_ffi(
+            (const char *)zone_buf,
+            (uint64_t)r->method,
+            (uint16_t)(r->headers_out.status ? r->headers_out.status : NGX_HTTP_OK)
+        );
+    }
 
-    // Calculate bytes sent and received for this request
-    off_t bytes_in = r->request_length;
-    off_t bytes_out = r->connection->sent;
+    // Opt-in breakdown by the response Content-Type's top-level type.
+    if (ngx_http_vts_track_content_type_enabled(r)) {
+        u_char zone_buf[256];
 
-    vts_update_server_stats_ffi(
-        (const char*)server_name_buf,
-        (uint16_t)response_status,
-        (uint64_t)bytes_in,
-        (uint64_t)bytes_out,
-        (uint64_t)request_time
-    );
+        ngx_http_vts_server_zone_name(r, zone_buf, sizeof(zone_buf));
+        vts_track_content_type_ffi((const char *)zone_buf,
+                                   r->headers_out.content_type.data,
+                                   r->headers_out.content_type.len);
+    }
+
+    // Opt-in breakdown by gRPC status, which the HTTP 200 around most
+    // gRPC errors hides.
+    if (ngx_http_vts_track_grpc_enabled(r)) {
+        ngx_http_variable_value_t *grpc_status;
+
+        grpc_status = ngx_http_vts_grpc_status(r);
+        if (grpc_status != NULL) {
+            u_char zone_buf[256];
+
+            ngx_http_vts_server_zone_name(r, zone_buf, sizeof(zone_buf));
+            vts_track_grpc_status_ffi((const char *)zone_buf,
+                                      grpc_status->data, grpc_status->len);
+        }
+    }
+
+    // Opt-in distinct-client estimate, by `$remote_addr` (after
+    // realip, when configured).
+    if (ngx_http_vts_track_unique_clients_enabled(r)) {
+        u_char zone_buf[256];
+
+        ngx_http_vts_server_zone_name(r, zone_buf, sizeof(zone_buf));
+        vts_track_unique_client_ffi((const char *)zone_buf,
+                                    r->connection->addr_text.data,
+                                    r->connection->addr_text.len);
+    }
+
+    ngx_http_vts_track_request_headers(r);
+
+    // The same request again under its location zone, if configured.
+    location_zone = ngx_http_vts_location_zone(r);
+    if (location_zone != NULL) {
+        vts_log_location_request(r, location_zone->data, location_zone->len);
+    }
+
+    // And under each filter group, keyed by its variable's value.
+    ngx_http_vts_log_filters(r);
 
     // ----- upstream + cache updates (only when upstream framework was used) -----
 
@@ -163,6 +882,10 @@ ngx_http_vts_log_handler(ngx_http_request_t *r)
         ngx_http_upstream_state_t *states = r->upstream_states->elts;
         ngx_uint_t i;
 
+        key_by_name = vts_upstream_key_by_name();
+        upstream_time = 0;
+        attempts = 0;
+
         for (i = 0; i < r->upstream_states->nelts; i++) {
             ngx_http_upstream_state_t *st = &states[i];
             if (st->peer == NULL
@@ -174,9 +897,21 @@ ngx_http_vts_log_handler(ngx_http_request_t *r)
             ngx_memcpy(server_addr_buf, st->peer->data, st->peer->len);
             server_addr_buf[st->peer->len] = '\0';
 
-            vts_track_upstream_request(
+            // `vts_upstream_key name`: also pass the configured name,
+            // which Rust then uses as the `server` key.
+            server_name_buf[0] = '\0';
+            server_name = key_by_name
+                ? ngx_http_vts_upstream_server_name(u->conf->upstream, st->peer)
+                : NULL;
+            if (server_name != NULL && server_name->len < sizeof(server_name_buf)) {
+                ngx_memcpy(server_name_buf, server_name->data, server_name->len);
+                server_name_buf[server_name->len] = '\0';
+            }
+
+            slow_ms = vts_track_upstream_request(
                 (const char *)upstream_name_buf,
                 (const char *)server_addr_buf,
+                (const char *)server_name_buf,
                 (uint64_t)r->start_sec,
                 (uint64_t)r->start_msec,
                 (uint64_t)st->response_time,
@@ -184,11 +919,43 @@ ngx_http_vts_log_handler(ngx_http_request_t *r)
                 (uint64_t)st->bytes_received,
                 (uint16_t)st->status
             );
+            upstream_time += (uint64_t)st->response_time;
+            attempts++;
+
+            // `vts_slow_log_threshold`: one line per slow attempt,
+            // rate-limited per server on the Rust side.
+            if (slow_ms != 0) {
+                ngx_log_error(NGX_LOG_WARN, r->connection->log, 0,
+                              "vts slow upstream request: upstream=\"%s\" "
+                              "server=\"%s\" request_time=%uL.%03uL "
+                              "upstream_response_time=%M.%03M status=%ui",
+                              upstream_name_buf, server_addr_buf,
+                              slow_ms / 1000, slow_ms % 1000,
+                              st->response_time / 1000,
+                              st->response_time % 1000,
+                              st->status);
+            }
+        }
+
+        // Local time once per request: the request time minus every
+        // attempt's upstream time, on the final attempt's server (the
+        // buffers still hold it).  Per attempt, a retried request would
+        // count the time spent on earlier attempts as local.
+        if (attempts > 0) {
+            vts_track_upstream_local_time(
+                (const char *)upstream_name_buf,
+                (const char *)server_addr_buf,
+                (const char *)server_name_buf,
+                (uint64_t)r->start_sec,
+                (uint64_t)r->start_msec,
+                upstream_time
+            );
         }
     }
 
 #if (NGX_HTTP_CACHE)
-    // Record `$upstream_cache_status` observations.  `cache_status == 0`
+    // Record `$upstream_cache_status` observations, with the bytes sent
+    // to the client so cache-served traffic can be totalled.  `cache_status == 0`
     // means the request did not consult any cache (no `proxy_cache`
     // configured, or the request bypassed cache lookup before nginx
     // assigned a status), so skip it.  Cache zone name is the shared
@@ -223,10 +990,22 @@ ngx_http_vts_log_handler(ngx_http_request_t *r)
 
             vts_update_cache_stats_ffi(
                 (const char *)cache_zone_buf,
+                (const char *)upstream_name_buf,
                 (uint8_t)u->cache_status,
+                (uint64_t)r->connection->sent,
                 max_size,
                 used_size
             );
+
+            // `wait_time` is only set once the request has parked on
+            // `proxy_cache_lock`; nginx clears `lock_timeout` when that
+            // wait expires and the request goes upstream uncached.
+            if (r->cache->wait_time != 0) {
+                vts_track_cache_lock_ffi(
+                    (const char *)cache_zone_buf,
+                    (uint8_t)(r->cache->lock_timeout == 0)
+                );
+            }
         }
     }
 #endif
@@ -236,9 +1015,12 @@ ngx_http_vts_log_handler(ngx_http_request_t *r)
 
 /*
  * Register LOG_PHASE handler
- * 
- * This function registers the LOG_PHASE handler with nginx.
- * It should be called during module initialization.
+ *
+ * Installs every request hook the module has: the LOG_PHASE handler,
+ * the POST_READ / PRECONTENT connection-gauge handlers and the body
+ * filter.  Called once per configuration from postconfiguration; this
+ * is the only place phase handlers are pushed, so the Rust side must
+ * not register its own or each request would be counted twice.
  */
 ngx_int_t
 ngx_http_vts_register_log_handler(ngx_conf_t *cf)
@@ -260,9 +1042,78 @@ ngx_http_vts_register_log_handler(ngx_conf_t *cf)
 
     *h = ngx_http_vts_log_handler;
 
+    // Per-zone connection gauges: begin at POST_READ, switch to
+    // writing at PRECONTENT, end in the pool cleanup.
+    h = ngx_array_push(&cmcf->phases[NGX_HTTP_POST_READ_PHASE].handlers);
+    if (h == NULL) {
+        return NGX_ERROR;
+    }
+
+    *h = ngx_http_vts_conn_begin_handler;
+
+    h = ngx_array_push(&cmcf->phases[NGX_HTTP_PRECONTENT_PHASE].handlers);
+    if (h == NULL) {
+        return NGX_ERROR;
+    }
+
+    *h = ngx_http_vts_conn_writing_handler;
+
+    // Upgraded-connection gauge, and incremental `bytes_out` for
+    // `vts_stream_bytes on`.  The module is built as an HTTP_AUX_FILTER
+    // (see `config`) so these hooks land in the final filter chain.
+    ngx_http_vts_next_header_filter = ngx_http_top_header_filter;
+    ngx_http_top_header_filter = ngx_http_vts_header_filter;
+    ngx_http_vts_next_body_filter = ngx_http_top_body_filter;
+    ngx_http_top_body_filter = ngx_http_vts_body_filter;
+
     return NGX_OK;
 }
 
+/*
+ * Seed every address of every parsed upstream block so configured
+ * servers show up with zero counters before their first request, and
+ * warn about addresses an upstream block lists more than once.
+ */
+static void
+ngx_http_vts_seed_upstream_servers(ngx_conf_t *cf)
+{
+    ngx_uint_t                      i, j, k;
+    ngx_http_upstream_server_t     *server;
+    ngx_http_upstream_srv_conf_t  **uscfp;
+    ngx_http_upstream_main_conf_t  *umcf;
+
+    umcf = ngx_http_conf_get_module_main_conf(cf, ngx_http_upstream_module);
+    if (umcf == NULL) {
+        return;
+    }
+
+    uscfp = umcf->upstreams.elts;
+
+    for (i = 0; i < umcf->upstreams.nelts; i++) {
+        if (uscfp[i]->servers == NULL) {
+            continue;
+        }
+
+        server = uscfp[i]->servers->elts;
+
+        for (j = 0; j < uscfp[i]->servers->nelts; j++) {
+            for (k = 0; k < server[j].naddrs; k++) {
+                if (vts_seed_upstream_server_ffi(uscfp[i]->host.data,
+                                                 uscfp[i]->host.len,
+                                                 server[j].addrs[k].name.data,
+                                                 server[j].addrs[k].name.len))
+                {
+                    ngx_log_error(NGX_LOG_WARN, cf->log, 0,
+                                  "vts: upstream \"%V\" lists server %V "
+                                  "more than once; merging",
+                                  &uscfp[i]->host,
+                                  &server[j].addrs[k].name);
+                }
+            }
+        }
+    }
+}
+
 /*
  * Module initialization wrapper
  *
@@ -286,5 +1137,11 @@ ngx_http_vts_init_wrapper(ngx_conf_t *cf)
         return rc;
     }
 
+    ngx_http_vts_seed_upstream_servers(cf);
+
+#if (NGX_HTTP_SSL)
+    ngx_http_vts_ssl_hook(cf);
+#endif
+
     return NGX_OK;
 }
@@ -1,81 +1,213 @@
-//! `nginx_vts_cache_*` series: request counters, size gauges, hit ratio.
+//! `nginx_vts_cache_*` series: request counters, size gauges, lock
+//! waits, hit and stale ratios.
 
 use std::collections::HashMap;
 
 use super::PrometheusFormatter;
-use crate::cache_stats::CacheZoneStats;
+use crate::cache_stats::{CacheZoneStats, VtsCacheStats};
+use crate::sample::{label_set, Family, MetricKind};
 
 impl PrometheusFormatter {
-    /// Format cache statistics to Prometheus metrics.
-    pub fn format_cache_stats(&self, cache_zones: &HashMap<String, CacheZoneStats>) -> String {
-        let mut output = String::new();
-
-        if cache_zones.is_empty() {
-            // Always emit the HELP/TYPE headers so scrapers can see
-            // the metric exists even before any cache traffic.
-            output
-                .push_str("# HELP nginx_vts_cache_requests_total Total number of cache requests\n");
-            output.push_str("# TYPE nginx_vts_cache_requests_total counter\n");
-            output.push_str("# HELP nginx_vts_cache_size_bytes Cache size statistics in bytes\n");
-            output.push_str("# TYPE nginx_vts_cache_size_bytes gauge\n\n");
-            return output;
+    /// Format cache statistics to Prometheus metrics, with
+    /// `cache_requests_total` split by the `upstreams` counters when
+    /// any are known (see [`CacheStatsWriter::with_upstreams`]).
+    pub fn format_cache_stats(
+        &self,
+        cache_zones: &HashMap<String, CacheZoneStats>,
+        upstreams: &HashMap<String, HashMap<String, VtsCacheStats>>,
+    ) -> String {
+        self.render(&self.cache_families(cache_zones, upstreams))
+    }
+
+    pub(crate) fn cache_families(
+        &self,
+        cache_zones: &HashMap<String, CacheZoneStats>,
+        upstreams: &HashMap<String, HashMap<String, VtsCacheStats>>,
+    ) -> Vec<Family> {
+        let mut zones: Vec<_> = cache_zones.values().collect();
+        zones.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        let mut writer = self.cache_stats_writer().with_upstreams(upstreams);
+        for zone_stats in zones {
+            writer.add(zone_stats);
+        }
+        writer.into_families()
+    }
+
+    /// Start a [`CacheStatsWriter`] for rendering zones one at a time.
+    pub fn cache_stats_writer(&self) -> CacheStatsWriter<'_> {
+        use MetricKind::{Counter, Gauge};
+
+        CacheStatsWriter {
+            formatter: self,
+            upstreams: None,
+            requests: self.family(
+                "cache_requests_total",
+                Counter,
+                "Total number of cache requests by status",
+            ),
+            size: self.family("cache_size_bytes", Gauge, "Cache size statistics in bytes"),
+            bytes_served: self.family(
+                "cache_bytes_served_total",
+                Counter,
+                "Response bytes served from cache",
+            ),
+            lock_waits: self.family(
+                "cache_lock_waits_total",
+                Counter,
+                "Requests that waited on the cache lock",
+            ),
+            lock_timeouts: self.family(
+                "cache_lock_timeouts_total",
+                Counter,
+                "Cache lock waits that timed out",
+            ),
+            hit_ratio: self.family("cache_hit_ratio", Gauge, "Cache hit ratio percentage"),
+            stale_ratio: self.family(
+                "cache_stale_ratio",
+                Gauge,
+                "Fraction of cache hits served stale",
+            ),
+        }
+    }
+}
+
+/// Single-pass builder for the cache families; see
+/// `ServerStatsWriter` for why zones are fed one at a time.
+pub struct CacheStatsWriter<'a> {
+    formatter: &'a PrometheusFormatter,
+    upstreams: Option<&'a HashMap<String, HashMap<String, VtsCacheStats>>>,
+    requests: Family,
+    size: Family,
+    bytes_served: Family,
+    lock_waits: Family,
+    lock_timeouts: Family,
+    hit_ratio: Family,
+    stale_ratio: Family,
+}
+
+/// A cache's request counters by `status` label.
+fn status_counts(cache: &VtsCacheStats) -> [(&'static str, u64); 8] {
+    [
+        ("hit", cache.hit),
+        ("miss", cache.miss),
+        ("bypass", cache.bypass),
+        ("expired", cache.expired),
+        ("stale", cache.stale),
+        ("updating", cache.updating),
+        ("revalidated", cache.revalidated),
+        ("scarce", cache.scarce),
+    ]
+}
+
+impl<'a> CacheStatsWriter<'a> {
+    /// Split `cache_requests_total` by the upstream whose responses
+    /// were cached, from counters by cache zone and then upstream.
+    /// Once any upstream is known every series carries the `upstream`
+    /// label, with the zone's requests of no known upstream under
+    /// `upstream=""`, so the series of a zone still sum to its total.
+    pub fn with_upstreams(
+        mut self,
+        upstreams: &'a HashMap<String, HashMap<String, VtsCacheStats>>,
+    ) -> Self {
+        if !upstreams.is_empty() {
+            self.upstreams = Some(upstreams);
         }
+        self
+    }
 
-        let prefix = &self.metric_prefix;
+    /// Add the series of one cache zone.
+    pub fn add(&mut self, zone_stats: &CacheZoneStats) {
+        let zone = label_set(&[], &[("zone", &zone_stats.name)]);
 
         // Cache request counters.
-        output.push_str(
-            "# HELP nginx_vts_cache_requests_total Total number of cache requests by status\n",
-        );
-        output.push_str("# TYPE nginx_vts_cache_requests_total counter\n");
-        for zone_stats in cache_zones.values() {
-            let zone = &zone_stats.name;
-            for (status, value) in [
-                ("hit", zone_stats.cache.hit),
-                ("miss", zone_stats.cache.miss),
-                ("bypass", zone_stats.cache.bypass),
-                ("expired", zone_stats.cache.expired),
-                ("stale", zone_stats.cache.stale),
-                ("updating", zone_stats.cache.updating),
-                ("revalidated", zone_stats.cache.revalidated),
-                ("scarce", zone_stats.cache.scarce),
-            ] {
-                output.push_str(&format!(
-                    "{prefix}cache_requests_total{{zone=\"{zone}\",status=\"{status}\"}} {value}\n"
-                ));
+        match self.upstreams {
+            None => {
+                for (status, value) in status_counts(&zone_stats.cache) {
+                    self.requests
+                        .push(label_set(&zone, &[("status", status)]), value);
+                }
+            }
+            Some(upstreams) => {
+                let mut unattributed = status_counts(&zone_stats.cache);
+                let mut by_upstream: Vec<_> = upstreams
+                    .get(zone_stats.name.as_str())
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                by_upstream.sort_unstable_by_key(|&(upstream, _)| upstream);
+                for (upstream, cache) in by_upstream {
+                    for (i, (status, value)) in status_counts(cache).into_iter().enumerate() {
+                        unattributed[i].1 = unattributed[i].1.saturating_sub(value);
+                        self.requests.push(
+                            label_set(&zone, &[("upstream", upstream), ("status", status)]),
+                            value,
+                        );
+                    }
+                }
+                for (status, value) in unattributed {
+                    self.requests.push(
+                        label_set(&zone, &[("upstream", ""), ("status", status)]),
+                        value,
+                    );
+                }
             }
         }
-        output.push('\n');
 
         // Cache size gauges.
-        output.push_str("# HELP nginx_vts_cache_size_bytes Cache size statistics in bytes\n");
-        output.push_str("# TYPE nginx_vts_cache_size_bytes gauge\n");
-        for zone_stats in cache_zones.values() {
-            let zone = &zone_stats.name;
-            output.push_str(&format!(
-                "{prefix}cache_size_bytes{{zone=\"{zone}\",type=\"max\"}} {}\n",
-                zone_stats.size.max_size
-            ));
-            output.push_str(&format!(
-                "{prefix}cache_size_bytes{{zone=\"{zone}\",type=\"used\"}} {}\n",
-                zone_stats.size.used_size
-            ));
+        self.size.push(
+            label_set(&zone, &[("type", "max")]),
+            zone_stats.size.max_size,
+        );
+        self.size.push(
+            label_set(&zone, &[("type", "used")]),
+            zone_stats.size.used_size,
+        );
+
+        // Bytes answered from cache instead of upstream.
+        self.bytes_served
+            .push(zone.clone(), zone_stats.cache.bytes_served);
+
+        // proxy_cache_lock waits; not a cache status, so outside the
+        // hit ratio.
+        self.lock_waits
+            .push(zone.clone(), zone_stats.cache.lock_waits);
+        self.lock_timeouts
+            .push(zone.clone(), zone_stats.cache.lock_timeouts);
+
+        // Cache hit ratio (derived from counters above).  A zone with
+        // no requests has no ratio rather than a misleading 0%.
+        if let Some(hit_ratio) = zone_stats.cache.hit_ratio_opt() {
+            self.hit_ratio.push(zone.clone(), hit_ratio);
+        }
+
+        // Share of cache-served responses that were stale, i.e. how
+        // much `proxy_cache_use_stale` is covering for the upstream.
+        if let Some(stale_ratio) = zone_stats.cache.stale_ratio_opt() {
+            self.stale_ratio.push(zone, stale_ratio);
         }
-        output.push('\n');
-
-        // Cache hit ratio (derived from counters above).
-        output.push_str("# HELP nginx_vts_cache_hit_ratio Cache hit ratio percentage\n");
-        output.push_str("# TYPE nginx_vts_cache_hit_ratio gauge\n");
-        for zone_stats in cache_zones.values() {
-            let zone = &zone_stats.name;
-            let hit_ratio = zone_stats.cache.hit_ratio();
-            output.push_str(&format!(
-                "{prefix}cache_hit_ratio{{zone=\"{zone}\"}} {hit_ratio:.2}\n"
-            ));
+    }
+
+    pub(crate) fn into_families(self) -> Vec<Family> {
+        if self.requests.series.is_empty() {
+            // Always emit the requests and size headers so scrapers can
+            // see the metric exists even before any cache traffic.
+            return vec![self.requests, self.size];
         }
-        output.push('\n');
+        vec![
+            self.requests,
+            self.size,
+            self.bytes_served,
+            self.lock_waits,
+            self.lock_timeouts,
+            self.hit_ratio,
+            self.stale_ratio,
+        ]
+    }
 
-        output
+    /// Emit every family, headers first.
+    pub fn finish(self) -> String {
+        let formatter = self.formatter;
+        formatter.render(&self.into_families())
     }
 }
 
@@ -86,7 +218,7 @@ mod tests {
     #[test]
     fn empty_cache_zones_emit_only_headers() {
         let empty: HashMap<String, CacheZoneStats> = HashMap::new();
-        let out = PrometheusFormatter::new().format_cache_stats(&empty);
+        let out = PrometheusFormatter::new().format_cache_stats(&empty, &HashMap::new());
         assert!(out.contains("# HELP nginx_vts_cache_requests_total"));
         assert!(out.contains("# TYPE nginx_vts_cache_requests_total counter"));
         assert!(out.contains("# HELP nginx_vts_cache_size_bytes"));
@@ -105,7 +237,7 @@ mod tests {
         zone.size.used_size = 524_288;
         zones.insert("test_cache".into(), zone);
 
-        let out = PrometheusFormatter::new().format_cache_stats(&zones);
+        let out = PrometheusFormatter::new().format_cache_stats(&zones, &HashMap::new());
         assert!(
             out.contains("nginx_vts_cache_requests_total{zone=\"test_cache\",status=\"hit\"} 7")
         );
@@ -119,6 +251,111 @@ mod tests {
             out.contains("nginx_vts_cache_size_bytes{zone=\"test_cache\",type=\"used\"} 524288")
         );
         // 7 / (7 + 3) = 70.00
-        assert!(out.contains("nginx_vts_cache_hit_ratio{zone=\"test_cache\"} 70.00"));
+        assert!(out.contains("nginx_vts_cache_hit_ratio{zone=\"test_cache\"} 70.000000"));
+    }
+
+    #[test]
+    fn stale_ratio_is_stale_over_hit_plus_stale() {
+        let mut zones = HashMap::new();
+        let mut zone = CacheZoneStats::new("edge");
+        zone.cache.hit = 6;
+        zone.cache.stale = 2;
+        zone.cache.miss = 10;
+        zones.insert("edge".to_string(), zone);
+        let mut cold = CacheZoneStats::new("cold");
+        cold.cache.miss = 4;
+        zones.insert("cold".to_string(), cold);
+
+        let out = PrometheusFormatter::new().format_cache_stats(&zones, &HashMap::new());
+        // 2 / (6 + 2); misses don't count.
+        assert!(out.contains("nginx_vts_cache_stale_ratio{zone=\"edge\"} 0.250000\n"));
+        assert!(!out.contains("nginx_vts_cache_stale_ratio{zone=\"cold\"}"));
+    }
+
+    #[test]
+    fn untouched_zone_has_no_hit_ratio_but_all_misses_is_zero() {
+        let mut zones = HashMap::new();
+        zones.insert("idle".to_string(), CacheZoneStats::new("idle"));
+        let mut cold = CacheZoneStats::new("cold");
+        cold.cache.miss = 4;
+        zones.insert("cold".to_string(), cold);
+
+        let out = PrometheusFormatter::new().format_cache_stats(&zones, &HashMap::new());
+        assert!(out.contains("nginx_vts_cache_requests_total{zone=\"idle\",status=\"hit\"} 0"));
+        assert!(!out.contains("nginx_vts_cache_hit_ratio{zone=\"idle\"}"));
+        assert!(out.contains("nginx_vts_cache_hit_ratio{zone=\"cold\"} 0.000000"));
+    }
+
+    #[test]
+    fn ratios_on_rounding_boundaries_round_half_up() {
+        let mut zones = HashMap::new();
+        let mut thirds = CacheZoneStats::new("thirds");
+        thirds.cache.hit = 2;
+        thirds.cache.miss = 1;
+        zones.insert("thirds".to_string(), thirds);
+        let mut sixths = CacheZoneStats::new("sixths");
+        sixths.cache.hit = 1;
+        sixths.cache.miss = 5;
+        zones.insert("sixths".to_string(), sixths);
+        let mut eighths = CacheZoneStats::new("eighths");
+        eighths.cache.hit = 7;
+        eighths.cache.stale = 1;
+        zones.insert("eighths".to_string(), eighths);
+
+        let out = PrometheusFormatter::new()
+            .float_precision(2)
+            .format_cache_stats(&zones, &HashMap::new());
+        assert!(out.contains("nginx_vts_cache_hit_ratio{zone=\"thirds\"} 66.67\n"));
+        assert!(out.contains("nginx_vts_cache_hit_ratio{zone=\"sixths\"} 16.67\n"));
+        // 1/8 = 0.125 exactly: half-to-even formatting alone gives 0.12.
+        assert!(out.contains("nginx_vts_cache_stale_ratio{zone=\"eighths\"} 0.13\n"));
+    }
+
+    #[test]
+    fn cache_requests_split_by_upstream_within_a_zone() {
+        let mut zone = CacheZoneStats::new("shared");
+        zone.cache.hit = 5;
+        zone.cache.miss = 2;
+        let mut api = VtsCacheStats::new();
+        api.hit = 3;
+        let mut web = VtsCacheStats::new();
+        web.hit = 1;
+        web.miss = 2;
+        let upstreams = HashMap::from([(
+            "shared".to_string(),
+            HashMap::from([("api".to_string(), api), ("web".to_string(), web)]),
+        )]);
+        let other = CacheZoneStats::new("other");
+
+        let f = PrometheusFormatter::new();
+        let mut writer = f.cache_stats_writer().with_upstreams(&upstreams);
+        writer.add(&other);
+        writer.add(&zone);
+        let out = writer.finish();
+        for (labels, value) in [
+            ("zone=\"shared\",upstream=\"api\",status=\"hit\"", 3),
+            ("zone=\"shared\",upstream=\"web\",status=\"hit\"", 1),
+            ("zone=\"shared\",upstream=\"web\",status=\"miss\"", 2),
+            // One hit from no known upstream.
+            ("zone=\"shared\",upstream=\"\",status=\"hit\"", 1),
+            ("zone=\"shared\",upstream=\"\",status=\"miss\"", 0),
+            ("zone=\"other\",upstream=\"\",status=\"hit\"", 0),
+        ] {
+            assert!(
+                out.contains(&format!(
+                    "nginx_vts_cache_requests_total{{{labels}}} {value}\n"
+                )),
+                "{labels}"
+            );
+        }
+        assert!(!out.contains("nginx_vts_cache_requests_total{zone=\"shared\",status="));
+        crate::prometheus::validate_prometheus(&out).unwrap();
+
+        // No upstream known anywhere: the plain per-zone series.
+        let none = HashMap::new();
+        let mut writer = f.cache_stats_writer().with_upstreams(&none);
+        writer.add(&zone);
+        let out = writer.finish();
+        assert!(out.contains("nginx_vts_cache_requests_total{zone=\"shared\",status=\"hit\"} 5\n"));
     }
 }
@@ -0,0 +1,396 @@
+//! Metric catalog: the `# HELP` / `# TYPE` header of every family the
+//! module can emit, without samples, for `vts_status?meta=1`.
+//!
+//! Families that only appear under some configuration (zone labels,
+//! `vts_detail_method_status`, duplicate upstream servers, …) are
+//! listed all the same, so tooling sees the full set up front.
+
+/// `(name without prefix, type, help)` in status-page order.
+const FAMILIES: &[(&str, &str, &str)] = &[
+    (
+        "module_up",
+        "gauge",
+        "Whether the module's stats are intact",
+    ),
+    ("info", "gauge", "Nginx VTS module information"),
+    (
+        "nginx_build_info",
+        "gauge",
+        "nginx version and configure arguments",
+    ),
+    (
+        "worker_processes",
+        "gauge",
+        "Configured number of worker processes",
+    ),
+    (
+        "worker_id",
+        "gauge",
+        "Slot of the worker that rendered this page",
+    ),
+    (
+        "discarded_observations_total",
+        "counter",
+        "Observations discarded for implausible timing",
+    ),
+    (
+        "non_utf8_name_requests_total",
+        "counter",
+        "Requests whose server name was recorded with invalid UTF-8 replaced",
+    ),
+    (
+        "ssl_handshakes_total",
+        "counter",
+        "TLS handshakes by result",
+    ),
+    (
+        "ssl_session_reuses_total",
+        "counter",
+        "TLS handshakes that resumed a session",
+    ),
+    (
+        "shm_zones",
+        "gauge",
+        "Shared memory zones used by the module",
+    ),
+    (
+        "shm_slab_bytes",
+        "gauge",
+        "Slab allocator usage per shared memory zone",
+    ),
+    (
+        "shm_node_count",
+        "gauge",
+        "Entries stored in the shared memory zone's tables",
+    ),
+    (
+        "shm_near_full",
+        "gauge",
+        "Whether the shared memory zone's free space is below vts_shm_warn_threshold",
+    ),
+    ("connections", "gauge", "Current nginx connections"),
+    ("connections_total", "counter", "Total nginx connections"),
+    (
+        "connections_limit",
+        "gauge",
+        "Configured worker_connections per worker",
+    ),
+    (
+        "requests_per_connection",
+        "gauge",
+        "Requests per handled connection",
+    ),
+    ("quic_connections", "gauge", "Current QUIC connections"),
+    (
+        "quic_0rtt_total",
+        "counter",
+        "QUIC connections with 0-RTT data accepted",
+    ),
+    (
+        "server_requests_total",
+        "counter",
+        "Total number of requests",
+    ),
+    ("server_bytes_total", "counter", "Total bytes transferred"),
+    (
+        "server_response_header_bytes_total",
+        "counter",
+        "Response header bytes sent",
+    ),
+    (
+        "server_response_body_bytes_total",
+        "counter",
+        "Response body bytes sent",
+    ),
+    (
+        "server_goodput_bytes_total",
+        "counter",
+        "Bytes sent with successful responses",
+    ),
+    (
+        "server_responses_total",
+        "counter",
+        "Total responses by status code",
+    ),
+    (
+        "server_responses_detail_total",
+        "counter",
+        "Responses with selected status codes",
+    ),
+    (
+        "server_rate_limited_total",
+        "counter",
+        "Requests rejected by limit_req or limit_conn",
+    ),
+    (
+        "server_subrequests_total",
+        "counter",
+        "Subrequests, counted apart from requests",
+    ),
+    (
+        "server_client_body_errors_total",
+        "counter",
+        "Requests that failed reading the client body",
+    ),
+    (
+        "server_upgrades_total",
+        "counter",
+        "Requests upgraded to another protocol (101)",
+    ),
+    ("server_request_seconds", "gauge", "Request processing time"),
+    (
+        "server_request_seconds_sum",
+        "counter",
+        "Total request processing time",
+    ),
+    (
+        "server_request_seconds_count",
+        "counter",
+        "Requests timed in server_request_seconds_sum",
+    ),
+    ("server_apdex", "gauge", "Apdex score from request time"),
+    (
+        "server_connections",
+        "gauge",
+        "Requests in flight per server zone",
+    ),
+    (
+        "server_websocket_connections",
+        "gauge",
+        "Open upgraded connections per server zone",
+    ),
+    (
+        "server_method_status_total",
+        "counter",
+        "Requests by method and status class",
+    ),
+    (
+        "server_responses_by_type_total",
+        "counter",
+        "Responses by Content-Type top-level type",
+    ),
+    (
+        "server_grpc_responses_total",
+        "counter",
+        "Responses by gRPC status",
+    ),
+    #[cfg(feature = "unique-clients")]
+    (
+        "server_unique_clients_estimate",
+        "gauge",
+        "Approximate distinct client addresses",
+    ),
+    (
+        "server_request_header_bytes",
+        "histogram",
+        "Request line and header size distribution in bytes",
+    ),
+    (
+        "server_request_headers",
+        "histogram",
+        "Request header count distribution",
+    ),
+    (
+        "server_zone_disabled",
+        "gauge",
+        "Server zones with accounting paused",
+    ),
+    (
+        "location_requests_total",
+        "counter",
+        "Total requests per location zone",
+    ),
+    (
+        "location_bytes_total",
+        "counter",
+        "Bytes transferred per location zone",
+    ),
+    (
+        "location_responses_total",
+        "counter",
+        "Responses per location zone by status code",
+    ),
+    (
+        "location_request_seconds",
+        "gauge",
+        "Request processing time per location zone",
+    ),
+    (
+        "filter_requests_total",
+        "counter",
+        "Total requests per filter zone",
+    ),
+    (
+        "filter_bytes_total",
+        "counter",
+        "Bytes transferred per filter zone",
+    ),
+    (
+        "filter_responses_total",
+        "counter",
+        "Responses per filter zone by status code",
+    ),
+    (
+        "filter_request_seconds",
+        "gauge",
+        "Request processing time per filter zone",
+    ),
+    (
+        "upstream_zones_total",
+        "gauge",
+        "Total number of upstream zones",
+    ),
+    (
+        "upstream_requests_total",
+        "counter",
+        "Total upstream requests",
+    ),
+    (
+        "upstream_bytes_total",
+        "counter",
+        "Total bytes transferred to/from upstream",
+    ),
+    (
+        "upstream_request_bytes_total",
+        "counter",
+        "Request bytes sent to upstream",
+    ),
+    (
+        "upstream_response_bytes_total",
+        "counter",
+        "Response bytes received from upstream",
+    ),
+    (
+        "upstream_response_seconds",
+        "gauge",
+        "Upstream response time statistics",
+    ),
+    (
+        "request_local_seconds",
+        "counter",
+        "Time spent outside the upstream, total",
+    ),
+    (
+        "request_local_clamped_total",
+        "counter",
+        "Requests whose upstream time exceeded the request time",
+    ),
+    #[cfg(feature = "latency-percentiles")]
+    (
+        "upstream_response_quantile_seconds",
+        "gauge",
+        "Upstream response time percentiles",
+    ),
+    (
+        "upstream_server_up",
+        "gauge",
+        "Upstream server status (1=up, 0=down)",
+    ),
+    (
+        "upstream_fully_down",
+        "gauge",
+        "Upstreams with every server down (1=all down)",
+    ),
+    (
+        "upstream_error_rate",
+        "gauge",
+        "Share of upstream requests in the last 60s that got a 5xx or no response",
+    ),
+    (
+        "upstream_server_state",
+        "gauge",
+        "Upstream server state derived from down flag and error rate (always 1)",
+    ),
+    (
+        "upstream_responses_total",
+        "counter",
+        "Upstream responses by status code",
+    ),
+    (
+        "upstream_responses_detail_total",
+        "counter",
+        "Upstream responses with selected status codes",
+    ),
+    (
+        "upstream_no_response_total",
+        "counter",
+        "Upstream attempts that got no response",
+    ),
+    (
+        "upstream_response_duration_seconds",
+        "histogram",
+        "Upstream response time distribution",
+    ),
+    (
+        "upstream_queue_length",
+        "gauge",
+        "Requests waiting for an upstream connection slot",
+    ),
+    (
+        "upstream_queue_waits_total",
+        "counter",
+        "Requests that waited for an upstream connection slot",
+    ),
+    (
+        "upstream_duplicate_servers_total",
+        "counter",
+        "Server addresses listed more than once in an upstream",
+    ),
+    (
+        "cache_requests_total",
+        "counter",
+        "Total number of cache requests by status",
+    ),
+    (
+        "cache_size_bytes",
+        "gauge",
+        "Cache size statistics in bytes",
+    ),
+    (
+        "cache_bytes_served_total",
+        "counter",
+        "Response bytes served from cache",
+    ),
+    (
+        "cache_lock_waits_total",
+        "counter",
+        "Requests that waited on the cache lock",
+    ),
+    (
+        "cache_lock_timeouts_total",
+        "counter",
+        "Cache lock waits that timed out",
+    ),
+    ("cache_hit_ratio", "gauge", "Cache hit ratio percentage"),
+    (
+        "cache_stale_ratio",
+        "gauge",
+        "Fraction of cache hits served stale",
+    ),
+    (
+        "label_truncations",
+        "gauge",
+        "Label values on this page truncated to vts_max_label_len",
+    ),
+];
+
+/// Every metric family the status page can contain, as `# HELP` and
+/// `# TYPE` lines with no samples.  Independent of recorded data.
+pub fn metric_catalog() -> String {
+    let prefix = super::PrometheusFormatter::new().metric_prefix;
+    let mut output = String::new();
+    for (name, kind, help) in FAMILIES {
+        output.push_str(&format!("# HELP {prefix}{name} {help}\n"));
+        output.push_str(&format!("# TYPE {prefix}{name} {kind}\n"));
+    }
+    output
+}
+
+/// `# HELP` text of family `name` (without prefix), if catalogued.
+#[cfg(feature = "protobuf")]
+pub fn family_help(name: &str) -> Option<&'static str> {
+    FAMILIES
+        .iter()
+        .find(|(family, _, _)| *family == name)
+        .map(|(_, _, help)| *help)
+}
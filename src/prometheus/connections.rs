@@ -1,47 +1,115 @@
-//! `nginx_vts_connections` and `nginx_vts_connections_total` series.
+//! `nginx_vts_connections`, `nginx_vts_connections_total` and the QUIC
+//! connection series.
 
 use super::PrometheusFormatter;
-use crate::stats::VtsConnectionStats;
+use crate::sample::{label_set, Family, MetricKind};
+use crate::stats::{VtsConnectionStats, VtsQuicStats};
 
 impl PrometheusFormatter {
     /// Format connection statistics into Prometheus metrics.
     pub fn format_connection_stats(&self, connections: &VtsConnectionStats) -> String {
-        let mut output = String::new();
-        let prefix = &self.metric_prefix;
+        self.render(&self.connection_families(connections))
+    }
 
+    pub(crate) fn connection_families(&self, connections: &VtsConnectionStats) -> Vec<Family> {
         // Current connection states (gauge).
-        output.push_str(&format!(
-            "# HELP {prefix}connections Current nginx connections\n"
-        ));
-        output.push_str(&format!("# TYPE {prefix}connections gauge\n"));
+        let mut current = self.family(
+            "connections",
+            MetricKind::Gauge,
+            "Current nginx connections",
+        );
         for (state, value) in [
             ("active", connections.active),
             ("reading", connections.reading),
             ("writing", connections.writing),
             ("waiting", connections.waiting),
         ] {
-            output.push_str(&format!(
-                "{prefix}connections{{state=\"{state}\"}} {value}\n"
-            ));
+            current.push(label_set(&[], &[("state", state)]), value);
         }
-        output.push('\n');
 
         // Lifetime totals (counter).
-        output.push_str(&format!(
-            "# HELP {prefix}connections_total Total nginx connections\n"
-        ));
-        output.push_str(&format!("# TYPE {prefix}connections_total counter\n"));
+        let mut totals = self.family(
+            "connections_total",
+            MetricKind::Counter,
+            "Total nginx connections",
+        );
         for (state, value) in [
             ("accepted", connections.accepted),
             ("handled", connections.handled),
         ] {
-            output.push_str(&format!(
-                "{prefix}connections_total{{state=\"{state}\"}} {value}\n"
-            ));
+            totals.push(label_set(&[], &[("state", state)]), value);
         }
-        output.push('\n');
 
-        output
+        vec![current, totals]
+    }
+
+    /// Format QUIC connections by phase and the count of accepted
+    /// 0-RTT handshakes.
+    pub fn format_quic_stats(&self, quic: &VtsQuicStats) -> String {
+        self.render(&self.quic_families(quic))
+    }
+
+    pub(crate) fn quic_families(&self, quic: &VtsQuicStats) -> Vec<Family> {
+        let mut connections = self.family(
+            "quic_connections",
+            MetricKind::Gauge,
+            "Current QUIC connections",
+        );
+        for (state, value) in [
+            ("handshaking", quic.handshaking),
+            ("established", quic.established),
+        ] {
+            connections.push(label_set(&[], &[("state", state)]), value);
+        }
+        vec![
+            connections,
+            self.scalar(
+                "quic_0rtt_total",
+                MetricKind::Counter,
+                "QUIC connections with 0-RTT data accepted",
+                quic.zero_rtt,
+            ),
+        ]
+    }
+
+    /// Format `nginx_vts_requests_per_connection`: `requests` (summed
+    /// over server zones) per handled connection, how much keepalive is
+    /// saving.  No sample until a connection has been handled; callers
+    /// pass 0 when nginx doesn't export the handled total.
+    pub fn format_requests_per_connection(&self, requests: u64, handled: u64) -> String {
+        self.render(&self.requests_per_connection_families(requests, handled))
+    }
+
+    pub(crate) fn requests_per_connection_families(
+        &self,
+        requests: u64,
+        handled: u64,
+    ) -> Vec<Family> {
+        let mut family = self.family(
+            "requests_per_connection",
+            MetricKind::Gauge,
+            "Requests per handled connection",
+        );
+        if handled > 0 {
+            family.push(Vec::new(), requests as f64 / handled as f64);
+        }
+        vec![family]
+    }
+
+    /// Format `nginx_vts_connections_limit`, the configured
+    /// `worker_connections`.  Per worker, so the capacity of the whole
+    /// instance is this times `nginx_vts_worker_processes`.
+    pub fn format_connections_limit(&self, limit: u64) -> String {
+        self.render(&self.connections_limit_families(limit))
+    }
+
+    pub(crate) fn connections_limit_families(&self, limit: u64) -> Vec<Family> {
+        vec![self.scalar(
+            "connections_limit",
+            MetricKind::Gauge,
+            "Configured worker_connections per worker",
+            limit,
+        )]
     }
 }
 
@@ -67,4 +135,35 @@ mod tests {
         assert!(out.contains("nginx_vts_connections_total{state=\"accepted\"} 1000"));
         assert!(out.contains("nginx_vts_connections_total{state=\"handled\"} 999"));
     }
+
+    #[test]
+    fn format_quic_stats_emits_phases_and_0rtt() {
+        let stats = VtsQuicStats {
+            handshaking: 2,
+            established: 5,
+            zero_rtt: 3,
+        };
+        let out = PrometheusFormatter::new().format_quic_stats(&stats);
+        assert!(out.contains("# TYPE nginx_vts_quic_connections gauge"));
+        assert!(out.contains("nginx_vts_quic_connections{state=\"handshaking\"} 2\n"));
+        assert!(out.contains("nginx_vts_quic_connections{state=\"established\"} 5\n"));
+        assert!(out.contains("# TYPE nginx_vts_quic_0rtt_total counter"));
+        assert!(out.contains("nginx_vts_quic_0rtt_total 3\n"));
+    }
+
+    #[test]
+    fn requests_per_connection_divides_requests_by_handled() {
+        let f = PrometheusFormatter::new();
+        let out = f.format_requests_per_connection(250, 100);
+        assert!(out.contains("# TYPE nginx_vts_requests_per_connection gauge\n"));
+        assert!(out.contains("nginx_vts_requests_per_connection 2.500000\n"));
+        assert!(f
+            .format_requests_per_connection(10, 3)
+            .contains("nginx_vts_requests_per_connection 3.333333\n"));
+
+        // Nothing handled yet: the family, but no sample.
+        let out = f.format_requests_per_connection(5, 0);
+        assert!(!out.contains("\nnginx_vts_requests_per_connection "));
+        crate::prometheus::validate_prometheus(&out).unwrap();
+    }
 }
@@ -0,0 +1,143 @@
+//! `nginx_vts_filter_*` series for `vts_filter_by_variable`.
+
+use std::collections::HashMap;
+
+use super::PrometheusFormatter;
+use crate::sample::{label_set, Family, MetricKind};
+use crate::stats::VtsServerStats;
+
+impl PrometheusFormatter {
+    /// Format filter-zone statistics: requests, bytes, responses by
+    /// status class and request time, keyed by the `filter` (group) and
+    /// `filter_name` (variable value) labels.  Emits nothing when no
+    /// `vts_filter_by_variable` group has seen a request.
+    pub fn format_filter_stats(
+        &self,
+        filters: &HashMap<String, HashMap<String, VtsServerStats>>,
+    ) -> String {
+        self.render(&self.filter_families(filters))
+    }
+
+    pub(crate) fn filter_families(
+        &self,
+        filters: &HashMap<String, HashMap<String, VtsServerStats>>,
+    ) -> Vec<Family> {
+        if filters.is_empty() {
+            return Vec::new();
+        }
+        let mut sorted: Vec<_> = filters
+            .iter()
+            .flat_map(|(group, keys)| keys.iter().map(move |(key, stats)| (group, key, stats)))
+            .collect();
+        sorted.sort_unstable_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        let sorted: Vec<_> = sorted
+            .into_iter()
+            .map(|(group, key, stats)| {
+                (
+                    label_set(&[], &[("filter", group), ("filter_name", key)]),
+                    stats,
+                )
+            })
+            .collect();
+
+        let mut requests = self.family(
+            "filter_requests_total",
+            MetricKind::Counter,
+            "Total requests per filter zone",
+        );
+        let mut bytes = self.family(
+            "filter_bytes_total",
+            MetricKind::Counter,
+            "Bytes transferred per filter zone",
+        );
+        let mut responses = self.family(
+            "filter_responses_total",
+            MetricKind::Counter,
+            "Responses per filter zone by status code",
+        );
+        let mut request_seconds = self.family(
+            "filter_request_seconds",
+            MetricKind::Gauge,
+            "Request processing time per filter zone",
+        );
+        for (labels, stats) in &sorted {
+            requests.push(labels.clone(), stats.requests);
+            for (direction, value) in [("in", stats.bytes_in), ("out", stats.bytes_out)] {
+                bytes.push(label_set(labels, &[("direction", direction)]), value);
+            }
+            for (class, value) in [
+                ("1xx", stats.responses.status_1xx),
+                ("2xx", stats.responses.status_2xx),
+                ("3xx", stats.responses.status_3xx),
+                ("4xx", stats.responses.status_4xx),
+                ("5xx", stats.responses.status_5xx),
+            ] {
+                responses.push(label_set(labels, &[("status", class)]), value);
+            }
+            for (kind, value) in [
+                ("avg", stats.request_times.avg),
+                ("min", stats.request_times.min),
+                ("max", stats.request_times.max),
+            ] {
+                request_seconds.push(label_set(labels, &[("type", kind)]), value);
+            }
+        }
+        vec![requests, bytes, responses, request_seconds]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{FILTER_OTHER_KEY, MAX_FILTER_KEYS};
+    use crate::vts_node::VtsStatsManager;
+
+    #[test]
+    fn format_filter_stats_keys_by_group_and_value() {
+        let mut manager = VtsStatsManager::new();
+        manager.update_filter_stats("country", "US", 200, 100, 2000, 40);
+        manager.update_filter_stats("country", "US", 503, 100, 200, 60);
+        manager.update_filter_stats("country", "JP", 200, 50, 8000, 2);
+
+        let output =
+            PrometheusFormatter::new().format_filter_stats(&manager.get_all_filter_stats());
+        assert!(output.contains(
+            "nginx_vts_filter_requests_total{filter=\"country\",filter_name=\"US\"} 2\n"
+        ));
+        assert!(output.contains(
+            "nginx_vts_filter_requests_total{filter=\"country\",filter_name=\"JP\"} 1\n"
+        ));
+        assert!(output.contains(
+            "nginx_vts_filter_bytes_total{filter=\"country\",filter_name=\"US\",direction=\"out\"} 2200\n"
+        ));
+        assert!(output.contains(
+            "nginx_vts_filter_responses_total{filter=\"country\",filter_name=\"US\",status=\"5xx\"} 1\n"
+        ));
+        assert!(output.contains(
+            "nginx_vts_filter_request_seconds{filter=\"country\",filter_name=\"JP\",type=\"max\"} 0.002000\n"
+        ));
+        crate::prometheus::validate_prometheus(&output).unwrap();
+    }
+
+    #[test]
+    fn filter_keys_past_the_limit_share_one_series() {
+        let mut manager = VtsStatsManager::new();
+        for i in 0..MAX_FILTER_KEYS + 5 {
+            manager.update_filter_stats("asn", &format!("AS{i}"), 200, 1, 1, 1);
+        }
+        // Keys already tracked keep counting under their own name.
+        manager.update_filter_stats("asn", "AS0", 200, 1, 1, 1);
+
+        let filters = manager.get_all_filter_stats();
+        assert_eq!(filters["asn"].len(), MAX_FILTER_KEYS + 1);
+        assert_eq!(filters["asn"][FILTER_OTHER_KEY].requests, 5);
+        assert_eq!(filters["asn"]["AS0"].requests, 2);
+    }
+
+    #[test]
+    fn format_filter_stats_empty_is_empty() {
+        assert!(PrometheusFormatter::new()
+            .format_filter_stats(&HashMap::new())
+            .is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+//! `nginx_vts_location_*` series for `vts_location_zone`.
+
+use std::collections::HashMap;
+
+use super::PrometheusFormatter;
+use crate::sample::{label_set, Family, MetricKind};
+use crate::stats::VtsServerStats;
+
+impl PrometheusFormatter {
+    /// Format location-zone statistics: requests, bytes, responses by
+    /// status class and request time, keyed by the `location` label.
+    /// Emits nothing when no `vts_location_zone` has seen a request.
+    pub fn format_location_stats(&self, locations: &HashMap<String, VtsServerStats>) -> String {
+        self.render(&self.location_families(locations))
+    }
+
+    pub(crate) fn location_families(
+        &self,
+        locations: &HashMap<String, VtsServerStats>,
+    ) -> Vec<Family> {
+        if locations.is_empty() {
+            return Vec::new();
+        }
+        let mut sorted: Vec<_> = locations.iter().collect();
+        sorted.sort_unstable_by_key(|&(name, _)| name);
+        let sorted: Vec<_> = sorted
+            .into_iter()
+            .map(|(name, stats)| (label_set(&[], &[("location", name)]), stats))
+            .collect();
+
+        let mut requests = self.family(
+            "location_requests_total",
+            MetricKind::Counter,
+            "Total requests per location zone",
+        );
+        let mut bytes = self.family(
+            "location_bytes_total",
+            MetricKind::Counter,
+            "Bytes transferred per location zone",
+        );
+        let mut responses = self.family(
+            "location_responses_total",
+            MetricKind::Counter,
+            "Responses per location zone by status code",
+        );
+        let mut request_seconds = self.family(
+            "location_request_seconds",
+            MetricKind::Gauge,
+            "Request processing time per location zone",
+        );
+        for (labels, stats) in &sorted {
+            requests.push(labels.clone(), stats.requests);
+            for (direction, value) in [("in", stats.bytes_in), ("out", stats.bytes_out)] {
+                bytes.push(label_set(labels, &[("direction", direction)]), value);
+            }
+            for (class, value) in [
+                ("1xx", stats.responses.status_1xx),
+                ("2xx", stats.responses.status_2xx),
+                ("3xx", stats.responses.status_3xx),
+                ("4xx", stats.responses.status_4xx),
+                ("5xx", stats.responses.status_5xx),
+            ] {
+                responses.push(label_set(labels, &[("status", class)]), value);
+            }
+            for (kind, value) in [
+                ("avg", stats.request_times.avg),
+                ("min", stats.request_times.min),
+                ("max", stats.request_times.max),
+            ] {
+                request_seconds.push(label_set(labels, &[("type", kind)]), value);
+            }
+        }
+        vec![requests, bytes, responses, request_seconds]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vts_node::VtsStatsManager;
+
+    #[test]
+    fn format_location_stats_keys_by_location() {
+        let mut manager = VtsStatsManager::new();
+        manager.update_location_stats("api", 200, 100, 2000, 40);
+        manager.update_location_stats("api", 503, 100, 200, 60);
+        manager.update_location_stats("static", 200, 50, 8000, 2);
+
+        let output =
+            PrometheusFormatter::new().format_location_stats(&manager.get_all_location_stats());
+        assert!(output.contains("nginx_vts_location_requests_total{location=\"api\"} 2\n"));
+        assert!(output.contains("nginx_vts_location_requests_total{location=\"static\"} 1\n"));
+        assert!(output
+            .contains("nginx_vts_location_bytes_total{location=\"api\",direction=\"out\"} 2200\n"));
+        assert!(output
+            .contains("nginx_vts_location_responses_total{location=\"api\",status=\"5xx\"} 1\n"));
+        assert!(output.contains(
+            "nginx_vts_location_request_seconds{location=\"static\",type=\"max\"} 0.002000\n"
+        ));
+        // `api` sorts before `static`.
+        assert!(output.find("location=\"api\"") < output.find("location=\"static\""));
+        crate::prometheus::validate_prometheus(&output).unwrap();
+    }
+
+    #[test]
+    fn format_location_stats_empty_is_empty() {
+        assert!(PrometheusFormatter::new()
+            .format_location_stats(&HashMap::new())
+            .is_empty());
+    }
+}
@@ -4,35 +4,97 @@
 //!
 //!   - [`connections`] — `nginx_vts_connections` and `_connections_total`
 //!   - [`server`]      — `nginx_vts_server_*`
-//!   - [`upstream`]    — `nginx_vts_upstream_*` (counters + histogram)
+//!   - [`upstream`]    — `nginx_vts_upstream_*` (counters + histogram,
+//!     connection-slot queues)
 //!   - [`cache`]       — `nginx_vts_cache_*`
+//!   - [`catalog`]     — headers of all of the above, for `?meta=1`
+//!   - [`truncate`]    — `vts_max_label_len`, applied to the whole page
 //!
-//! [`PrometheusFormatter::format_nginx_info`] and the top-level
-//! [`generate_vts_status_content`] entry point live in this module
-//! because they orchestrate the others.
+//! Each builds [`Family`] values (see `crate::sample`) from the stats
+//! it is given; [`PrometheusFormatter::write_families`] turns them into
+//! exposition text.  [`status_families`] assembles the whole page from
+//! the snapshots, and [`generate_vts_status_content`] renders it, so
+//! the page and `collect_samples` never disagree.
 
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt::Write;
 
-use crate::upstream_stats::UpstreamZone;
+use crate::sample::{label_set, Family, MetricKind, Value};
+use crate::shm::SlabUsage;
+use crate::upstream_stats::{UpstreamQueueStats, UpstreamZone};
+use crate::vts_node::VtsStatsManager;
 
 #[cfg(not(test))]
 use ngx::ffi::ngx_time;
 
 mod cache;
+mod catalog;
 mod connections;
+mod filter;
+mod location;
 mod server;
+mod truncate;
 mod upstream;
+#[cfg(test)]
+mod validate;
+
+#[cfg(feature = "protobuf")]
+pub use catalog::family_help;
+pub use catalog::metric_catalog;
+pub use truncate::MIN_LABEL_LEN;
+#[cfg(test)]
+pub(crate) use validate::validate_prometheus;
+
+/// Default decimal places for float sample values.
+const DEFAULT_FLOAT_PRECISION: usize = 6;
+
+/// Rough page size outside the per-zone families (banner, info,
+/// connections, shm, …), for [`estimated_page_capacity`].
+const PAGE_BASE_BYTES: usize = 8 * 1024;
+
+/// Rough page bytes per server zone, upstream server and cache zone.
+/// Erring low only costs a few reallocations on the first scrape; the
+/// status handler's buffer keeps whatever the page grew to after that.
+const PAGE_BYTES_PER_SERVER_ZONE: usize = 2 * 1024;
+const PAGE_BYTES_PER_UPSTREAM_SERVER: usize = 2 * 1024;
+const PAGE_BYTES_PER_CACHE_ZONE: usize = 1024;
+
+/// Largest page buffer kept between scrapes.  A one-off huge page
+/// (say, before a reload dropped most zones) is not held forever.
+const PAGE_RETAIN_BYTES: usize = 64 * 1024 * 1024;
+
+/// Capacity to reserve up front for a status page covering
+/// `server_zones` server zones, `upstream_servers` upstream servers and
+/// `cache_zones` cache zones.
+fn estimated_page_capacity(
+    server_zones: usize,
+    upstream_servers: usize,
+    cache_zones: usize,
+) -> usize {
+    PAGE_BASE_BYTES
+        .saturating_add(server_zones.saturating_mul(PAGE_BYTES_PER_SERVER_ZONE))
+        .saturating_add(upstream_servers.saturating_mul(PAGE_BYTES_PER_UPSTREAM_SERVER))
+        .saturating_add(cache_zones.saturating_mul(PAGE_BYTES_PER_CACHE_ZONE))
+}
 
 /// Prometheus metrics formatter for VTS statistics.
 ///
-/// Carries the metric-name prefix and provides a `format_*` method
-/// per metric family.  The method bodies live in the submodules
-/// listed above; this file holds the type and the `nginx_info`
-/// metric (which is the only one that doesn't take a per-zone map).
+/// Carries the metric-name prefix (and optional sample timestamp and
+/// float precision).  Each metric family has a `*_families` method
+/// building it and a `format_*` method rendering just that; the bodies
+/// live in the submodules listed above, and this file holds the type,
+/// the renderer and the process-wide families.
 #[allow(dead_code)] // All fields used in formatting
 pub struct PrometheusFormatter {
     /// Optional metric prefix (default: "nginx_vts_")
     pub metric_prefix: String,
+    /// Epoch-milliseconds timestamp appended to every sample line, for
+    /// exporting a snapshot taken at a known time (default: none, i.e.
+    /// scrape time)
+    pub timestamp_ms: Option<u64>,
+    /// Decimal places for every float sample value (default: 6)
+    pub float_precision: usize,
 }
 
 impl PrometheusFormatter {
@@ -40,6 +102,8 @@ impl PrometheusFormatter {
     pub fn new() -> Self {
         Self {
             metric_prefix: "nginx_vts_".to_string(),
+            timestamp_ms: None,
+            float_precision: DEFAULT_FLOAT_PRECISION,
         }
     }
 
@@ -48,23 +112,371 @@ impl PrometheusFormatter {
     pub fn with_prefix(prefix: &str) -> Self {
         Self {
             metric_prefix: prefix.to_string(),
+            timestamp_ms: None,
+            float_precision: DEFAULT_FLOAT_PRECISION,
         }
     }
 
-    /// Format nginx basic info metrics into Prometheus format
-    pub fn format_nginx_info(&self, hostname: &str, version: &str) -> String {
+    /// Append `timestamp_ms` (milliseconds since the Unix epoch) to every
+    /// sample this formatter emits.
+    #[allow(dead_code)] // For backfill exports; /status is stamped at scrape time.
+    pub fn with_timestamps(mut self, timestamp_ms: u64) -> Self {
+        self.timestamp_ms = Some(timestamp_ms);
+        self
+    }
+
+    /// Render float samples (times, ratios, Apdex) with `precision`
+    /// decimal places instead of the default six.
+    #[allow(dead_code)] // /status always renders with the default precision.
+    pub fn float_precision(mut self, precision: usize) -> Self {
+        self.float_precision = precision;
+        self
+    }
+
+    /// An empty family named with this formatter's prefix.
+    fn family(&self, name: &str, kind: MetricKind, help: &'static str) -> Family {
+        Family::new(&self.metric_prefix, name, kind, help)
+    }
+
+    /// A family of one unlabelled series.
+    fn scalar(
+        &self,
+        name: &str,
+        kind: MetricKind,
+        help: &'static str,
+        value: impl Into<Value>,
+    ) -> Family {
+        let mut family = self.family(name, kind, help);
+        family.push(Vec::new(), value);
+        family
+    }
+
+    /// Render `families` as exposition text.
+    pub(crate) fn render(&self, families: &[Family]) -> String {
         let mut output = String::new();
-        output.push_str(&format!(
-            "# HELP {}info Nginx VTS module information\n",
-            self.metric_prefix
-        ));
-        output.push_str(&format!("# TYPE {}info gauge\n", self.metric_prefix));
-        output.push_str(&format!(
-            "{}info{{hostname=\"{}\",version=\"{}\"}} 1\n\n",
-            self.metric_prefix, hostname, version
-        ));
+        self.write_families(&mut output, families);
         output
     }
+
+    /// Append `families` to `output`: each family's `# HELP` and
+    /// `# TYPE`, one line per series and a blank line.  Integers print
+    /// as they are; floats with `float_precision` places, rounded half
+    /// away from zero.  Every series gets `timestamp_ms` when set.
+    pub(crate) fn write_families(&self, output: &mut String, families: &[Family]) {
+        let precision = self.float_precision;
+        for family in families {
+            let name = &family.name;
+            let _ = write!(
+                output,
+                "# HELP {name} {}\n# TYPE {name} {}\n",
+                family.help,
+                family.kind.as_str()
+            );
+            for series in &family.series {
+                output.push_str(name);
+                output.push_str(series.suffix);
+                for (i, (label, value)) in series.labels.iter().enumerate() {
+                    output.push(if i == 0 { '{' } else { ',' });
+                    output.push_str(label);
+                    output.push_str("=\"");
+                    push_escaped(output, value);
+                    output.push('"');
+                }
+                if !series.labels.is_empty() {
+                    output.push('}');
+                }
+                let _ = match series.value {
+                    Value::Int(value) => write!(output, " {value}"),
+                    Value::Float(value) => {
+                        write!(output, " {:.precision$}", round_half_up(value, precision))
+                    }
+                };
+                if let Some(ts) = self.timestamp_ms {
+                    let _ = write!(output, " {ts}");
+                }
+                output.push('\n');
+            }
+            output.push('\n');
+        }
+    }
+
+    /// Format `nginx_vts_module_up`: 1 while the module's stats are
+    /// intact, 0 once a poisoned lock means they may be partial.  Comes
+    /// first on the page so a parser can tell module output from an
+    /// error page a proxy served in its place.
+    pub fn format_module_up(&self, up: bool) -> String {
+        self.render(&self.module_up_families(up))
+    }
+
+    pub(crate) fn module_up_families(&self, up: bool) -> Vec<Family> {
+        vec![self.scalar(
+            "module_up",
+            MetricKind::Gauge,
+            "Whether the module's stats are intact",
+            u64::from(up),
+        )]
+    }
+
+    /// Format nginx basic info metrics into Prometheus format
+    pub fn format_nginx_info(&self, hostname: &str, version: &str, pid: u32) -> String {
+        self.render(&self.nginx_info_families(hostname, version, pid))
+    }
+
+    pub(crate) fn nginx_info_families(
+        &self,
+        hostname: &str,
+        version: &str,
+        pid: u32,
+    ) -> Vec<Family> {
+        let mut info = self.family("info", MetricKind::Gauge, "Nginx VTS module information");
+        info.push(
+            label_set(
+                &[],
+                &[
+                    ("hostname", hostname),
+                    ("version", version),
+                    ("pid", &pid.to_string()),
+                ],
+            ),
+            1,
+        );
+        vec![info]
+    }
+
+    /// Format `nginx_vts_nginx_build_info` for the nginx binary the
+    /// module is loaded into.  `configure_args` routinely contains
+    /// quotes and backslashes, which the renderer escapes.
+    pub fn format_nginx_build_info(&self, nginx_version: &str, configure_args: &str) -> String {
+        self.render(&self.nginx_build_info_families(nginx_version, configure_args))
+    }
+
+    pub(crate) fn nginx_build_info_families(
+        &self,
+        nginx_version: &str,
+        configure_args: &str,
+    ) -> Vec<Family> {
+        let mut info = self.family(
+            "nginx_build_info",
+            MetricKind::Gauge,
+            "nginx version and configure arguments",
+        );
+        info.push(
+            label_set(
+                &[],
+                &[
+                    ("nginx_version", nginx_version),
+                    ("configure_args", configure_args),
+                ],
+            ),
+            1,
+        );
+        vec![info]
+    }
+
+    /// Format `nginx_vts_worker_processes` (the configured count) and
+    /// `nginx_vts_worker_id` (the slot of the worker answering).
+    pub fn format_worker_info(&self, worker_processes: u64, worker_id: u64) -> String {
+        self.render(&self.worker_info_families(worker_processes, worker_id))
+    }
+
+    pub(crate) fn worker_info_families(
+        &self,
+        worker_processes: u64,
+        worker_id: u64,
+    ) -> Vec<Family> {
+        vec![
+            self.scalar(
+                "worker_processes",
+                MetricKind::Gauge,
+                "Configured number of worker processes",
+                worker_processes,
+            ),
+            self.scalar(
+                "worker_id",
+                MetricKind::Gauge,
+                "Slot of the worker that rendered this page",
+                worker_id,
+            ),
+        ]
+    }
+
+    /// Format the count of observations rejected by the FFI
+    /// plausibility guard (absurd request / response times).
+    pub fn format_discarded_observations(&self, discarded: u64) -> String {
+        self.render(&self.discarded_observations_families(discarded))
+    }
+
+    pub(crate) fn discarded_observations_families(&self, discarded: u64) -> Vec<Family> {
+        vec![self.scalar(
+            "discarded_observations_total",
+            MetricKind::Counter,
+            "Observations discarded for implausible timing",
+            discarded,
+        )]
+    }
+
+    /// Format the count of distinct label values shortened by
+    /// `vts_max_label_len` on this page.  A gauge: it describes the page
+    /// it is on, so it differs between locations that expose different
+    /// zones and drops when a long-named zone goes away.  Not part of
+    /// the samples, whose labels are never shortened.
+    pub fn format_label_truncations(&self, count: u64) -> String {
+        self.render(&[self.scalar(
+            "label_truncations",
+            MetricKind::Gauge,
+            "Label values on this page truncated to vts_max_label_len",
+            count,
+        )])
+    }
+
+    /// Format TLS handshake outcomes and session resumptions.
+    pub fn format_ssl_stats(&self, stats: &crate::SslStats) -> String {
+        self.render(&self.ssl_families(stats))
+    }
+
+    pub(crate) fn ssl_families(&self, stats: &crate::SslStats) -> Vec<Family> {
+        let mut handshakes = self.family(
+            "ssl_handshakes_total",
+            MetricKind::Counter,
+            "TLS handshakes by result",
+        );
+        for (result, value) in [
+            ("ok", stats.handshakes_ok),
+            ("failed", stats.handshakes_failed),
+        ] {
+            handshakes.push(label_set(&[], &[("result", result)]), value);
+        }
+        vec![
+            handshakes,
+            self.scalar(
+                "ssl_session_reuses_total",
+                MetricKind::Counter,
+                "TLS handshakes that resumed a session",
+                stats.session_reuses,
+            ),
+        ]
+    }
+
+    /// Format the count of requests whose server name was not valid
+    /// UTF-8 and was recorded under a lossy decoding.
+    pub fn format_non_utf8_name_requests(&self, count: u64) -> String {
+        self.render(&self.non_utf8_name_requests_families(count))
+    }
+
+    pub(crate) fn non_utf8_name_requests_families(&self, count: u64) -> Vec<Family> {
+        vec![self.scalar(
+            "non_utf8_name_requests_total",
+            MetricKind::Counter,
+            "Requests whose server name was recorded with invalid UTF-8 replaced",
+            count,
+        )]
+    }
+
+    /// Format `nginx_vts_shm_zones` and, per zone, the slab allocator's
+    /// page usage and allocation counters (`reqs` and `fails` count
+    /// allocations, not bytes), the number of table entries and whether
+    /// free space is below `vts_shm_warn_threshold`.
+    pub fn format_shm_stats(&self, zones: &[(String, SlabUsage)]) -> String {
+        self.render(&self.shm_families(zones))
+    }
+
+    pub(crate) fn shm_families(&self, zones: &[(String, SlabUsage)]) -> Vec<Family> {
+        let mut families = vec![self.scalar(
+            "shm_zones",
+            MetricKind::Gauge,
+            "Shared memory zones used by the module",
+            zones.len() as u64,
+        )];
+        if zones.is_empty() {
+            return families;
+        }
+
+        let mut slab = self.family(
+            "shm_slab_bytes",
+            MetricKind::Gauge,
+            "Slab allocator usage per shared memory zone",
+        );
+        let mut nodes = self.family(
+            "shm_node_count",
+            MetricKind::Gauge,
+            "Entries stored in the shared memory zone's tables",
+        );
+        let mut near_full = self.family(
+            "shm_near_full",
+            MetricKind::Gauge,
+            "Whether the shared memory zone's free space is below vts_shm_warn_threshold",
+        );
+        let threshold = crate::shm::warn_threshold_percent();
+        for (zone, usage) in zones {
+            for (kind, value) in [
+                ("total", usage.total),
+                ("used", usage.used),
+                ("free", usage.free),
+                ("reqs", usage.reqs),
+                ("fails", usage.fails),
+            ] {
+                slab.push(label_set(&[], &[("zone", zone), ("type", kind)]), value);
+            }
+            nodes.push(label_set(&[], &[("zone", zone)]), usage.nodes);
+            near_full.push(
+                label_set(&[], &[("zone", zone)]),
+                u64::from(usage.is_near_full(threshold)),
+            );
+        }
+        families.extend([slab, nodes, near_full]);
+        families
+    }
+}
+
+/// Append `value` to `output` escaped per the text exposition format.
+fn push_escaped(output: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => output.push_str("\\\\"),
+            '"' => output.push_str("\\\""),
+            '\n' => output.push_str("\\n"),
+            c => output.push(c),
+        }
+    }
+}
+
+/// Escape a label value per the text exposition format: backslash,
+/// double quote and newline.
+pub fn escape_label_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    push_escaped(&mut out, value);
+    out
+}
+
+/// Format a histogram `le` bound as Prometheus expects: the shortest
+/// decimal that reads back as the same `f64` (`0.025`, `1`, `2.5`,
+/// never `0.10000000000000001`), and `+Inf` / `-Inf` for infinities.
+/// The rendering must be stable across scrapes so the time series
+/// doesn't fragment.
+pub fn format_le(bound: f64) -> String {
+    if bound.is_infinite() {
+        return if bound > 0.0 { "+Inf" } else { "-Inf" }.to_string();
+    }
+    // `Display` for `f64` is already the shortest round-trip form and
+    // never uses an exponent; `-0` is folded into `0`.
+    if bound == 0.0 {
+        return "0".to_string();
+    }
+    bound.to_string()
+}
+
+/// Round `value` to `decimals` places, halves away from zero, for
+/// ratio samples.  `{:.N}` alone rounds the binary value half-to-even,
+/// so 12.5 would print as `12` at 0 places; rounding first makes every
+/// ratio land on the same side of a boundary.  Values too large to
+/// scale are returned unchanged.
+pub fn round_half_up(value: f64, decimals: usize) -> f64 {
+    let factor = 10f64.powi(decimals.min(i32::MAX as usize) as i32);
+    let scaled = value * factor;
+    if !scaled.is_finite() {
+        return value;
+    }
+    scaled.round() / factor
 }
 
 impl Default for PrometheusFormatter {
@@ -73,36 +485,99 @@ impl Default for PrometheusFormatter {
     }
 }
 
-/// Generate VTS status content.
-///
-/// Creates a comprehensive status report including server
-/// information, connection statistics, and request metrics.
-pub fn generate_vts_status_content() -> String {
-    // Collect current nginx connection statistics only in production
+/// Generate the minimal status page sent when the full one cannot be
+/// allocated: the banner, `nginx_vts_info` and the connection series,
+/// all bounded in size regardless of how many zones exist.
+pub fn generate_minimal_status_content() -> String {
     #[cfg(not(test))]
     crate::vts_collect_nginx_connections();
 
     let manager = crate::VTS_MANAGER
         .read()
-        .unwrap_or_else(|poisoned| poisoned.into_inner());
+        .unwrap_or_else(crate::recover_poisoned);
     let formatter = PrometheusFormatter::new();
 
-    // When `vts_zone` is configured the cross-worker shared table is the
-    // authoritative source for server and upstream stats. Otherwise we
-    // fall back to the process-local manager (used by unit tests and by
-    // single-worker development setups that haven't declared a zone).
-    let server_zone_stats =
-        crate::shm::snapshot_servers().unwrap_or_else(|| manager.get_all_server_stats());
-    let upstream_owned = crate::shm::snapshot_upstreams();
-    let upstream_zones: &HashMap<String, UpstreamZone> = match upstream_owned.as_ref() {
-        Some(m) => m,
-        None => manager.get_all_upstream_zones(),
-    };
-
     let mut content = String::new();
+    content.push_str(&format!(
+        "# nginx-vts-rust\n\
+         # Version: {}\n\
+         # VTS Status: Truncated (status page allocation failed)\n\
+         \n",
+        env!("CARGO_PKG_VERSION")
+    ));
+    content.push_str(&formatter.format_module_up(crate::vts_module_up()));
+    content.push_str(&formatter.format_nginx_info(
+        &get_hostname(),
+        env!("CARGO_PKG_VERSION"),
+        get_worker_pid(),
+    ));
+    content.push_str(&formatter.format_connection_stats(manager.get_connection_stats()));
+    content
+}
+
+/// The process-local state the status page is built from: a copy of
+/// `VTS_MANAGER`, taken after refreshing the connection counters, and
+/// whether the module is up.
+pub(crate) fn status_state() -> (VtsStatsManager, bool) {
+    // Collect current nginx connection statistics only in production
+    #[cfg(not(test))]
+    crate::vts_collect_nginx_connections();
+
+    // Copy the process-local state out and release the lock straight
+    // away: every request's LOG_PHASE takes the write lock, so it must
+    // only wait for the clone, not for formatting thousands of zones.
+    let up = crate::vts_module_up();
+    let manager = crate::VTS_MANAGER
+        .read()
+        .unwrap_or_else(crate::recover_poisoned)
+        .clone();
+    (manager, up)
+}
+
+/// Generate VTS status content.
+///
+/// Creates a comprehensive status report including server
+/// information, connection statistics, and request metrics.
+pub fn generate_vts_status_content() -> String {
+    let mut page = String::new();
+    write_vts_status_content(&mut page);
+    page
+}
+
+/// [`generate_vts_status_content`] into `page`, reusing its
+/// allocation: the status handler keeps one buffer across scrapes.
+pub(crate) fn write_vts_status_content(page: &mut String) {
+    let (manager, up) = status_state();
+    render_status_into(page, &manager, up);
+}
+
+/// Format the full status page from `manager`, a copy of the
+/// process-local state, and the shared-memory snapshots.  `up` is
+/// reported as `nginx_vts_module_up`.  Takes no lock on `VTS_MANAGER`.
+pub(crate) fn render_status_content(manager: &VtsStatsManager, up: bool) -> String {
+    let mut page = String::new();
+    render_status_into(&mut page, manager, up);
+    page
+}
+
+/// [`render_status_content`] into `page`, replacing what it held.
+/// The buffer is only reallocated when the page outgrows it or a label
+/// value has to be truncated.
+pub(crate) fn render_status_into(page: &mut String, manager: &VtsStatsManager, up: bool) {
+    let formatter = PrometheusFormatter::new();
+    let (families, capacity) = build_status_families(&formatter, manager, up);
+
+    // Size the buffer for the zones at hand.  One a past page grew
+    // beyond `PAGE_RETAIN_BYTES` is let go rather than kept forever.
+    if page.capacity() > PAGE_RETAIN_BYTES {
+        *page = String::new();
+    }
+    page.clear();
+    page.reserve(capacity);
 
     // Header information
-    content.push_str(&format!(
+    let _ = write!(
+        page,
         "# nginx-vts-rust\n\
          # Version: {}\n\
          # Hostname: {}\n\
@@ -114,31 +589,221 @@ pub fn generate_vts_status_content() -> String {
         env!("CARGO_PKG_VERSION"),
         get_hostname(),
         get_current_time()
-    ));
+    );
 
-    content.push_str("# Prometheus Metrics:\n");
+    page.push_str("# Prometheus Metrics:\n");
+    formatter.write_families(page, &families);
 
-    content.push_str(&formatter.format_nginx_info(&get_hostname(), env!("CARGO_PKG_VERSION")));
-    content.push_str(&formatter.format_connection_stats(manager.get_connection_stats()));
-    content.push_str(&formatter.format_server_stats(&server_zone_stats));
+    // Last, so the count covers every family above.
+    let (truncated_page, truncated) = truncate::truncate_label_values(page, crate::max_label_len());
+    if let Cow::Owned(truncated_page) = truncated_page {
+        *page = truncated_page;
+    }
+    page.push_str(&formatter.format_label_truncations(truncated));
+}
+
+/// Every family of the status page, in page order, from `manager` and
+/// the shared-memory snapshots.  `up` is reported as
+/// `nginx_vts_module_up`.  The page and `collect_samples` are both
+/// built from this.
+pub(crate) fn status_families(manager: &VtsStatsManager, up: bool) -> Vec<Family> {
+    build_status_families(&PrometheusFormatter::new(), manager, up).0
+}
+
+/// [`status_families`] and the capacity to reserve for their page.
+fn build_status_families(
+    formatter: &PrometheusFormatter,
+    manager: &VtsStatsManager,
+    up: bool,
+) -> (Vec<Family>, usize) {
+    // When `vts_zone` is configured the cross-worker shared table is the
+    // authoritative source for server and upstream stats. Otherwise we
+    // fall back to the process-local manager (used by unit tests and by
+    // single-worker development setups that haven't declared a zone).
+    let server_zone_stats = crate::shm::snapshot_servers();
+    let mut upstream_owned = crate::shm::snapshot_upstreams();
+    if let Some(zones) = upstream_owned.as_mut() {
+        manager.apply_upstream_peer_state(zones);
+    }
+    let upstream_zones: &HashMap<String, UpstreamZone> = match upstream_owned.as_ref() {
+        Some(m) => m,
+        None => manager.get_all_upstream_zones(),
+    };
+    let upstream_limited = crate::limit_upstream_zones(upstream_zones);
+    let upstream_zones = upstream_limited.as_ref().unwrap_or(upstream_zones);
+    let queues_owned = crate::shm::snapshot_upstream_queues();
+    let upstream_queues: &HashMap<String, UpstreamQueueStats> = match queues_owned.as_ref() {
+        Some(m) => m,
+        None => manager.get_all_upstream_queues(),
+    };
+
+    let cache_zones_owned = crate::shm::snapshot_caches();
+
+    let capacity = estimated_page_capacity(
+        server_zone_stats
+            .as_ref()
+            .map_or_else(|| manager.server_zone_count(), HashMap::len),
+        upstream_zones.values().map(|zone| zone.servers.len()).sum(),
+        cache_zones_owned
+            .as_ref()
+            .map_or_else(|| crate::CACHE_MANAGER.zone_count(), HashMap::len),
+    );
+
+    let mut families = formatter.module_up_families(up);
+    families.extend(formatter.nginx_info_families(
+        &get_hostname(),
+        env!("CARGO_PKG_VERSION"),
+        get_worker_pid(),
+    ));
+    let (nginx_version, configure_args) = get_nginx_build_info();
+    families.extend(formatter.nginx_build_info_families(&nginx_version, &configure_args));
+    let (worker_processes, worker_id) = get_worker_info();
+    families.extend(formatter.worker_info_families(worker_processes, worker_id));
+    families.extend(formatter.discarded_observations_families(crate::discarded_observations()));
+    families.extend(formatter.non_utf8_name_requests_families(crate::non_utf8_name_requests()));
+    families.extend(formatter.ssl_families(&crate::ssl_stats()));
+    families.extend(formatter.shm_families(crate::shm::slab_usage().as_slice()));
+    families.extend(formatter.connection_families(manager.get_connection_stats()));
+    families.extend(formatter.connections_limit_families(get_connections_limit()));
+    let requests = match &server_zone_stats {
+        Some(stats) => stats
+            .values()
+            .map(|s| s.requests)
+            .fold(0, u64::saturating_add),
+        None => manager.total_server_requests(),
+    };
+    // Without the stub_status atomics `handled` only stands in for the
+    // active connections, and the ratio would mean nothing.
+    let handled = match crate::connection_stats::available() {
+        true => manager.get_connection_stats().handled,
+        false => 0,
+    };
+    families.extend(formatter.requests_per_connection_families(requests, handled));
+    families.extend(formatter.quic_families(manager.get_quic_stats()));
+    let zone_labels = manager.get_zone_labels();
+    let zone_aliases = manager.get_zone_aliases();
+    // Zones left off this location's page by `vts_expose_zones` are
+    // dropped from every per-zone family below.
+    match server_zone_stats {
+        Some(stats) if !zone_aliases.is_empty() => {
+            let stats = crate::stats::alias_server_zones(stats, zone_aliases);
+            families.extend(formatter.server_families(&crate::exposed_zones(&stats), zone_labels));
+        }
+        Some(stats) => {
+            families.extend(formatter.server_families(&crate::exposed_zones(&stats), zone_labels))
+        }
+        None => {
+            let mut writer = formatter
+                .server_stats_writer()
+                .with_zone_labels(zone_labels);
+            manager.for_each_server_zone(|zone, stats| {
+                if crate::is_zone_exposed(zone) {
+                    writer.add(zone, stats);
+                }
+            });
+            families.extend(writer.into_families());
+        }
+    }
+    let method_status_owned = crate::shm::snapshot_method_status();
+    families.extend(
+        formatter.method_status_families(
+            &crate::exposed_zones(
+                method_status_owned
+                    .as_ref()
+                    .unwrap_or_else(|| manager.get_all_method_status()),
+            ),
+            zone_labels,
+        ),
+    );
+    let content_types_owned = crate::shm::snapshot_content_types();
+    families.extend(
+        formatter.content_type_families(
+            &crate::exposed_zones(
+                content_types_owned
+                    .as_ref()
+                    .unwrap_or_else(|| manager.get_all_content_types()),
+            ),
+            zone_labels,
+        ),
+    );
+    let grpc_statuses_owned = crate::shm::snapshot_grpc_statuses();
+    families.extend(
+        formatter.grpc_status_families(
+            &crate::exposed_zones(
+                grpc_statuses_owned
+                    .as_ref()
+                    .unwrap_or_else(|| manager.get_all_grpc_statuses()),
+            ),
+            zone_labels,
+        ),
+    );
+    #[cfg(feature = "unique-clients")]
+    {
+        let unique_clients_owned = crate::shm::snapshot_unique_clients();
+        families.extend(
+            formatter.unique_client_families(
+                &crate::exposed_zones(
+                    unique_clients_owned
+                        .as_ref()
+                        .unwrap_or_else(|| manager.get_all_unique_clients()),
+                ),
+                zone_labels,
+            ),
+        );
+    }
+    let request_headers_owned = crate::shm::snapshot_request_headers();
+    families.extend(
+        formatter.request_header_families(
+            &crate::exposed_zones(
+                request_headers_owned
+                    .as_ref()
+                    .unwrap_or_else(|| manager.get_all_request_headers()),
+            ),
+            zone_labels,
+        ),
+    );
+    families.extend(formatter.disabled_zone_families(&manager.get_disabled_zones()));
+    let locations =
+        crate::shm::snapshot_locations().unwrap_or_else(|| manager.get_all_location_stats());
+    families.extend(formatter.location_families(&locations));
+    let filters = crate::shm::snapshot_filters().unwrap_or_else(|| manager.get_all_filter_stats());
+    families.extend(formatter.filter_families(&filters));
 
     if !upstream_zones.is_empty() {
-        content.push_str(&formatter.format_upstream_stats(upstream_zones));
+        families
+            .extend(formatter.upstream_families(upstream_zones, manager.get_upstream_zone_names()));
     } else {
         // Placeholder for when no upstream zones exist.
-        content.push_str(
-            "# HELP nginx_vts_upstream_zones_total Total number of upstream zones\n\
-             # TYPE nginx_vts_upstream_zones_total gauge\n\
-             nginx_vts_upstream_zones_total 0\n\n",
-        );
+        families.push(formatter.scalar(
+            "upstream_zones_total",
+            MetricKind::Gauge,
+            "Total number of upstream zones",
+            0,
+        ));
     }
+    families.extend(formatter.upstream_queue_families(upstream_queues));
+    families.extend(
+        formatter.upstream_duplicate_server_families(manager.get_upstream_duplicate_servers()),
+    );
 
     // Generate cache metrics — prefer the cross-worker shared table
     // when configured, otherwise fall back to the process-local manager.
-    let cache_zones = crate::shm::snapshot_caches().unwrap_or_else(crate::get_all_cache_zones);
-    content.push_str(&formatter.format_cache_stats(&cache_zones));
+    let cache_upstreams = crate::shm::snapshot_cache_upstreams()
+        .unwrap_or_else(|| crate::CACHE_MANAGER.get_all_cache_upstreams());
+    match cache_zones_owned {
+        Some(cache_zones) => {
+            families.extend(formatter.cache_families(&cache_zones, &cache_upstreams))
+        }
+        None => {
+            let mut writer = formatter
+                .cache_stats_writer()
+                .with_upstreams(&cache_upstreams);
+            crate::for_each_cache_zone(|_, zone_stats| writer.add(zone_stats));
+            families.extend(writer.into_families());
+        }
+    }
 
-    content
+    (families, capacity)
 }
 
 /// Get system hostname (nginx-independent version for testing).
@@ -163,6 +828,77 @@ pub fn get_hostname() -> String {
     }
 }
 
+/// PID of the worker rendering the response.  Without `vts_zone` every
+/// worker keeps its own counters, so this tells scrapes apart.
+pub fn get_worker_pid() -> u32 {
+    #[cfg(not(test))]
+    {
+        std::process::id()
+    }
+
+    #[cfg(test)]
+    {
+        4242
+    }
+}
+
+/// Configured `worker_connections`: the connection limit of each
+/// worker, not of the whole instance.
+pub fn get_connections_limit() -> u64 {
+    #[cfg(not(test))]
+    {
+        crate::CONNECTIONS_LIMIT.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    {
+        1024
+    }
+}
+
+/// Configured `worker_processes` and the slot of the worker rendering
+/// the response.  Unlike the pid, the slot is stable across worker
+/// restarts, so per-worker series can be told apart over time.
+pub fn get_worker_info() -> (u64, u64) {
+    #[cfg(not(test))]
+    {
+        use std::sync::atomic::Ordering;
+        (
+            crate::WORKER_PROCESSES.load(Ordering::Relaxed),
+            crate::WORKER_ID.load(Ordering::Relaxed),
+        )
+    }
+
+    #[cfg(test)]
+    {
+        (4, 1)
+    }
+}
+
+/// Version and `./configure` arguments of the nginx binary this module
+/// was built against (`NGINX_VERSION` / `NGX_CONFIGURE`).
+pub fn get_nginx_build_info() -> (String, String) {
+    #[cfg(not(test))]
+    {
+        fn c_bytes_to_string(bytes: &[u8]) -> String {
+            let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+            String::from_utf8_lossy(bytes).into_owned()
+        }
+        (
+            c_bytes_to_string(ngx::ffi::NGINX_VERSION),
+            c_bytes_to_string(ngx::ffi::NGX_CONFIGURE),
+        )
+    }
+
+    #[cfg(test)]
+    {
+        (
+            "1.25.3".to_string(),
+            " --with-cc-opt='-DNGX_HAVE_X=\"1\"' --prefix=/usr/local/nginx".to_string(),
+        )
+    }
+}
+
 /// Get current time as string (nginx-independent version for testing).
 pub fn get_current_time() -> String {
     #[cfg(not(test))]
@@ -194,10 +930,287 @@ mod tests {
     }
 
     #[test]
-    fn format_nginx_info_includes_hostname_and_version() {
-        let out = PrometheusFormatter::new().format_nginx_info("h.example.test", "1.2.3");
+    fn zone_order_does_not_depend_on_insertion_order() {
+        use crate::cache_stats::CacheZoneStats;
+        use crate::stats::VtsServerStats;
+
+        let names: Vec<String> = (0..32).map(|i| format!("zone{i:02}")).collect();
+        let render = |order: &[String]| {
+            let f = PrometheusFormatter::new();
+            let mut servers = HashMap::new();
+            let mut upstreams = HashMap::new();
+            let mut caches = HashMap::new();
+            for name in order {
+                servers.insert(name.clone(), VtsServerStats::default());
+                let zone = upstreams
+                    .entry(name.clone())
+                    .or_insert_with(|| UpstreamZone::new(name));
+                for addr in ["10.0.0.2:80", "10.0.0.1:80"] {
+                    zone.get_or_create_server(addr);
+                }
+                caches.insert(name.clone(), CacheZoneStats::new(name));
+            }
+            f.format_server_stats(&servers)
+                + &f.format_upstream_stats(&upstreams)
+                + &f.format_cache_stats(&caches, &HashMap::new())
+        };
+
+        let forward = render(&names);
+        let reversed: Vec<String> = names.iter().rev().cloned().collect();
+        assert_eq!(forward, render(&reversed));
+
+        let first = forward.find("zone=\"zone00\"").unwrap();
+        let last = forward.find("zone=\"zone31\"").unwrap();
+        assert!(first < last);
+        assert!(
+            forward.find("server=\"10.0.0.1:80\"").unwrap()
+                < forward.find("server=\"10.0.0.2:80\"").unwrap()
+        );
+    }
+
+    #[test]
+    fn shm_stats_count_zones_and_break_out_slab_usage() {
+        let f = PrometheusFormatter::new();
+        let none = f.format_shm_stats(&[]);
+        assert!(none.contains("nginx_vts_shm_zones 0\n"));
+        assert!(!none.contains("shm_slab_bytes"));
+
+        let usage = SlabUsage {
+            total: 1_048_576,
+            used: 65_536,
+            free: 983_040,
+            reqs: 120,
+            fails: 2,
+            nodes: 42,
+        };
+        let out = f.format_shm_stats(&[("vts".to_string(), usage)]);
+        assert!(out.contains("nginx_vts_shm_zones 1\n"));
+        assert!(out.contains("nginx_vts_shm_slab_bytes{zone=\"vts\",type=\"used\"} 65536\n"));
+        assert!(out.contains("nginx_vts_shm_slab_bytes{zone=\"vts\",type=\"fails\"} 2\n"));
+        assert!(out.contains("nginx_vts_shm_node_count{zone=\"vts\"} 42\n"));
+        assert!(out.contains("nginx_vts_shm_near_full{zone=\"vts\"} 0\n"));
+        validate_prometheus(&out).unwrap();
+
+        let full = SlabUsage {
+            used: 1_000_000,
+            free: 48_576,
+            ..usage
+        };
+        let out = f.format_shm_stats(&[("vts".to_string(), full)]);
+        assert!(out.contains("nginx_vts_shm_near_full{zone=\"vts\"} 1\n"));
+    }
+
+    #[test]
+    fn status_page_formats_without_the_manager_lock() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut manager = VtsStatsManager::new();
+        manager.update_server_stats("a.test", 200, 10, 20, 7);
+
+        // Would deadlock if formatting went back to `VTS_MANAGER`.
+        let _writer = crate::VTS_MANAGER
+            .write()
+            .unwrap_or_else(crate::recover_poisoned);
+        let content = render_status_content(&manager, true);
+        assert!(content.contains("nginx_vts_server_requests_total{zone=\"a.test\"} 1\n"));
+    }
+
+    #[test]
+    fn page_capacity_grows_with_zone_counts() {
+        assert_eq!(estimated_page_capacity(0, 0, 0), PAGE_BASE_BYTES);
+        assert_eq!(
+            estimated_page_capacity(10, 4, 2),
+            PAGE_BASE_BYTES
+                + 10 * PAGE_BYTES_PER_SERVER_ZONE
+                + 4 * PAGE_BYTES_PER_UPSTREAM_SERVER
+                + 2 * PAGE_BYTES_PER_CACHE_ZONE
+        );
+        assert_eq!(estimated_page_capacity(usize::MAX, 1, 1), usize::MAX);
+    }
+
+    #[test]
+    fn reused_page_buffer_leaves_pages_unchanged() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut small = VtsStatsManager::new();
+        small.update_server_stats("a.test", 200, 10, 20, 7);
+        let mut large = VtsStatsManager::new();
+        for i in 0..200 {
+            large.update_server_stats(&format!("zone{i}.test"), 200, 10, 20, 7);
+            large.update_upstream_stats("backend", &format!("10.0.{i}.1:80"), 7, 3, 10, 20, 200);
+        }
+
+        let first = render_status_content(&small, true);
+        let mut page = String::new();
+        render_status_into(&mut page, &large, true);
+        assert!(page.contains("nginx_vts_server_requests_total{zone=\"zone199.test\"} 1\n"));
+        assert!(page.capacity() >= estimated_page_capacity(200, 200, 0));
+
+        // Building the small page in the same buffer neither reallocates
+        // it nor leaves anything of the large page behind.
+        let buffer = page.as_ptr();
+        render_status_into(&mut page, &small, true);
+        assert_eq!(page.as_ptr(), buffer);
+        assert_eq!(page, first);
+        assert!(!page.contains("zone0.test"));
+        validate_prometheus(&page).unwrap();
+    }
+
+    #[test]
+    fn float_precision_applies_to_every_float_sample() {
+        use crate::cache_stats::CacheZoneStats;
+
+        let mut manager = VtsStatsManager::new();
+        manager.update_server_stats("a.test", 200, 10, 20, 7);
+        manager.update_location_stats("api", 200, 10, 20, 7);
+        manager.update_upstream_stats("backend", "10.0.0.1:80", 7, 3, 10, 20, 200);
+        let mut cache = CacheZoneStats::new("c");
+        for status in ["HIT", "MISS", "MISS"] {
+            cache.update_cache_status(status);
+        }
+        let caches = HashMap::from([("c".to_string(), cache)]);
+
+        let f = PrometheusFormatter::new().float_precision(3);
+        let output = f.format_server_stats(&manager.get_all_server_stats())
+            + &f.format_location_stats(&manager.get_all_location_stats())
+            + &f.format_upstream_stats(manager.get_all_upstream_zones())
+            + &f.format_cache_stats(&caches, &HashMap::new());
+        validate_prometheus(&output).unwrap();
+
+        let floats: Vec<&str> = output
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.rsplit(' ').next().unwrap())
+            .filter(|value| value.contains('.'))
+            .collect();
+        assert!(floats.len() > 10);
+        for value in floats {
+            let decimals = value.split('.').nth(1).unwrap();
+            assert_eq!(decimals.len(), 3, "{value}");
+        }
+        assert!(output
+            .contains("nginx_vts_server_request_seconds{zone=\"a.test\",type=\"max\"} 0.007\n"));
+        assert!(output.contains("nginx_vts_cache_hit_ratio{zone=\"c\"} 33.333\n"));
+    }
+
+    #[test]
+    fn with_timestamps_stamps_every_sample_line() {
+        use crate::cache_stats::CacheZoneStats;
+        use crate::stats::{VtsConnectionStats, VtsServerStats};
+
+        let render = |f: &PrometheusFormatter| {
+            let servers = HashMap::from([("a.test".to_string(), VtsServerStats::default())]);
+            let mut upstreams = HashMap::new();
+            upstreams
+                .entry("backend".to_string())
+                .or_insert_with(|| UpstreamZone::new("backend"))
+                .get_or_create_server("10.0.0.1:80");
+            let caches = HashMap::from([("c".to_string(), CacheZoneStats::new("c"))]);
+            let queues = HashMap::from([("backend".to_string(), UpstreamQueueStats::default())]);
+            f.format_nginx_info("h", "1.0", 1)
+                + &f.format_nginx_build_info("1.25.3", "")
+                + &f.format_discarded_observations(0)
+                + &f.format_non_utf8_name_requests(0)
+                + &f.format_ssl_stats(&crate::SslStats::default())
+                + &f.format_shm_stats(&[("vts".to_string(), SlabUsage::default())])
+                + &f.format_connection_stats(&VtsConnectionStats::default())
+                + &f.format_connections_limit(1024)
+                + &f.format_quic_stats(&crate::stats::VtsQuicStats::default())
+                + &f.format_server_stats(&servers)
+                + &f.format_disabled_zones(&["a.test".to_string()])
+                + &f.format_upstream_stats(&upstreams)
+                + &f.format_upstream_queue_stats(&queues)
+                + &f.format_cache_stats(&caches, &HashMap::new())
+        };
+        let is_sample = |line: &&str| !line.is_empty() && !line.starts_with('#');
+
+        let plain = render(&PrometheusFormatter::new());
+        validate_prometheus(&plain).unwrap();
+        for line in plain.lines().filter(is_sample) {
+            assert_eq!(line.split(' ').count(), 2, "unexpected timestamp: {line}");
+        }
+
+        let stamped = render(&PrometheusFormatter::new().with_timestamps(1_700_000_000_123));
+        validate_prometheus(&stamped).unwrap();
+        assert_eq!(stamped.lines().count(), plain.lines().count());
+        for line in stamped.lines().filter(is_sample) {
+            assert!(
+                line.ends_with(" 1700000000123"),
+                "missing timestamp: {line}"
+            );
+        }
+        for line in stamped.lines().filter(|l| !is_sample(l)) {
+            assert!(!line.contains("1700000000123"), "stamped comment: {line}");
+        }
+    }
+
+    #[test]
+    fn format_nginx_info_includes_hostname_version_and_pid() {
+        let out = PrometheusFormatter::new().format_nginx_info("h.example.test", "1.2.3", 4242);
         assert!(out.contains("# HELP nginx_vts_info Nginx VTS module information"));
         assert!(out.contains("# TYPE nginx_vts_info gauge"));
-        assert!(out.contains("nginx_vts_info{hostname=\"h.example.test\",version=\"1.2.3\"} 1"));
+        assert!(out.contains(
+            "nginx_vts_info{hostname=\"h.example.test\",version=\"1.2.3\",pid=\"4242\"} 1"
+        ));
+    }
+
+    #[test]
+    fn build_info_escapes_configure_args() {
+        let args = r#"--with-cc-opt="-I /opt/inc" --add-module=C:\src\vts"#;
+        let out = PrometheusFormatter::new().format_nginx_build_info("1.25.3", args);
+        assert!(out.contains("# TYPE nginx_vts_nginx_build_info gauge"));
+        assert!(out.contains(
+            r#"nginx_vts_nginx_build_info{nginx_version="1.25.3",configure_args="--with-cc-opt=\"-I /opt/inc\" --add-module=C:\\src\\vts"} 1"#
+        ));
+        assert_eq!(validate::validate_prometheus(&out), Ok(()));
+    }
+
+    #[test]
+    fn escape_label_value_handles_quotes_backslashes_and_newlines() {
+        assert_eq!(escape_label_value("plain"), "plain");
+        assert_eq!(escape_label_value("a\"b"), "a\\\"b");
+        assert_eq!(escape_label_value("a\\b"), "a\\\\b");
+        assert_eq!(escape_label_value("a\nb"), "a\\nb");
+    }
+
+    #[test]
+    fn format_le_renders_shortest_round_trip_bounds() {
+        assert_eq!(format_le(0.025), "0.025");
+        assert_eq!(format_le(1.0), "1");
+        assert_eq!(format_le(2.5), "2.5");
+        assert_eq!(format_le(f64::INFINITY), "+Inf");
+        assert_eq!(format_le(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(format_le(0.1), "0.1");
+        assert_eq!(format_le(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(format_le(5.0 / 1000.0), "0.005");
+        assert_eq!(format_le(0.0005), "0.0005");
+        assert_eq!(format_le(-0.0), "0");
+        assert_eq!(format_le(32768.0), "32768");
+        for bound in [0.025, 0.1, 2.5, 1e-7, 123_456.789] {
+            assert_eq!(format_le(bound).parse::<f64>().unwrap(), bound);
+        }
+    }
+
+    #[test]
+    fn round_half_up_rounds_halves_away_from_zero() {
+        assert_eq!(round_half_up(2.0 / 3.0 * 100.0, 2), 66.67);
+        assert_eq!(round_half_up(1.0 / 6.0 * 100.0, 2), 16.67);
+        assert_eq!(round_half_up(12.5, 0), 13.0);
+        assert_eq!(round_half_up(0.125, 2), 0.13);
+        assert_eq!(round_half_up(-0.125, 2), -0.13);
+        assert_eq!(format!("{:.2}", round_half_up(0.125, 2)), "0.13");
+        // Too large to scale, or not a number: left alone.
+        assert_eq!(round_half_up(f64::MAX, 6), f64::MAX);
+        assert_eq!(round_half_up(1.5, 400), 1.5);
+        assert!(round_half_up(f64::NAN, 2).is_nan());
+    }
+
+    #[test]
+    fn format_discarded_observations_emits_counter() {
+        let out = PrometheusFormatter::new().format_discarded_observations(3);
+        assert!(out.contains("# TYPE nginx_vts_discarded_observations_total counter"));
+        assert!(out.contains("nginx_vts_discarded_observations_total 3\n"));
     }
 }
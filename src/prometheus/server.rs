@@ -1,92 +1,568 @@
-//! `nginx_vts_server_*` series (requests / bytes / responses / request_seconds).
+//! `nginx_vts_server_*` series (requests / bytes / responses / rate_limited
+//! / request_seconds / connections).
 
 use std::collections::HashMap;
 
-use super::PrometheusFormatter;
-use crate::stats::VtsServerStats;
+use super::{format_le, PrometheusFormatter};
+#[cfg(feature = "unique-clients")]
+use crate::hll::HyperLogLog;
+use crate::sample::{label_set, Family, MetricKind};
+use crate::stats::{
+    ContentTypeCounters, GrpcStatusCounters, HttpMethod, MethodStatusCounters, RequestHeaderStats,
+    VtsServerStats, CONTENT_TYPES, GRPC_STATUS_NAMES, STATUS_CLASSES,
+};
 
 impl PrometheusFormatter {
     /// Format server zone statistics into Prometheus metrics.
+    #[allow(dead_code)] // Used in tests and VTS integration
     pub fn format_server_stats(&self, server_stats: &HashMap<String, VtsServerStats>) -> String {
-        let mut output = String::new();
-        let prefix = &self.metric_prefix;
+        self.format_labeled_server_stats(server_stats, &HashMap::new())
+    }
 
-        // Server requests total.
-        output.push_str(&format!(
-            "# HELP {prefix}server_requests_total Total number of requests\n"
-        ));
-        output.push_str(&format!("# TYPE {prefix}server_requests_total counter\n"));
-        for (zone, stats) in server_stats {
-            output.push_str(&format!(
-                "{prefix}server_requests_total{{zone=\"{zone}\"}} {}\n",
-                stats.requests
-            ));
-        }
-        output.push('\n');
-
-        // Server bytes total.
-        output.push_str(&format!(
-            "# HELP {prefix}server_bytes_total Total bytes transferred\n"
-        ));
-        output.push_str(&format!("# TYPE {prefix}server_bytes_total counter\n"));
-        for (zone, stats) in server_stats {
-            output.push_str(&format!(
-                "{prefix}server_bytes_total{{zone=\"{zone}\",direction=\"in\"}} {}\n",
-                stats.bytes_in
-            ));
-            output.push_str(&format!(
-                "{prefix}server_bytes_total{{zone=\"{zone}\",direction=\"out\"}} {}\n",
-                stats.bytes_out
-            ));
-        }
-        output.push('\n');
-
-        // Server responses total.
-        output.push_str(&format!(
-            "# HELP {prefix}server_responses_total Total responses by status code\n"
-        ));
-        output.push_str(&format!("# TYPE {prefix}server_responses_total counter\n"));
-        for (zone, stats) in server_stats {
-            for (class, value) in [
-                ("1xx", stats.responses.status_1xx),
-                ("2xx", stats.responses.status_2xx),
-                ("3xx", stats.responses.status_3xx),
-                ("4xx", stats.responses.status_4xx),
-                ("5xx", stats.responses.status_5xx),
-            ] {
-                output.push_str(&format!(
-                    "{prefix}server_responses_total{{zone=\"{zone}\",status=\"{class}\"}} {value}\n"
-                ));
+    /// [`format_server_stats`](Self::format_server_stats) with the
+    /// user-defined `vts_zone_label` labels added to each zone's series.
+    pub fn format_labeled_server_stats(
+        &self,
+        server_stats: &HashMap<String, VtsServerStats>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> String {
+        self.render(&self.server_families(server_stats, zone_labels))
+    }
+
+    pub(crate) fn server_families(
+        &self,
+        server_stats: &HashMap<String, VtsServerStats>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> Vec<Family> {
+        let mut zones: Vec<_> = server_stats.iter().collect();
+        zones.sort_unstable_by_key(|&(zone, _)| zone);
+        let mut writer = self.server_stats_writer().with_zone_labels(zone_labels);
+        for (zone, stats) in zones {
+            writer.add(zone, stats);
+        }
+        writer.into_families()
+    }
+
+    /// Start a [`ServerStatsWriter`] for rendering zones one at a time.
+    pub fn server_stats_writer(&self) -> ServerStatsWriter<'_> {
+        use MetricKind::{Counter, Gauge};
+
+        ServerStatsWriter {
+            formatter: self,
+            zone_labels: None,
+            label_names: Vec::new(),
+            requests: self.family("server_requests_total", Counter, "Total number of requests"),
+            bytes: self.family("server_bytes_total", Counter, "Total bytes transferred"),
+            // direction="out" split into headers and body.
+            response_header_bytes: self.family(
+                "server_response_header_bytes_total",
+                Counter,
+                "Response header bytes sent",
+            ),
+            response_body_bytes: self.family(
+                "server_response_body_bytes_total",
+                Counter,
+                "Response body bytes sent",
+            ),
+            // The part of direction="out" sent with 2xx and 3xx responses.
+            goodput_bytes: self.family(
+                "server_goodput_bytes_total",
+                Counter,
+                "Bytes sent with successful responses",
+            ),
+            responses: self.family(
+                "server_responses_total",
+                Counter,
+                "Total responses by status code",
+            ),
+            // 206 and 304, also counted in their class above.
+            responses_detail: self.family(
+                "server_responses_detail_total",
+                Counter,
+                "Responses with selected status codes",
+            ),
+            // Requests rejected by limit_req / limit_conn.
+            rate_limited: self.family(
+                "server_rate_limited_total",
+                Counter,
+                "Requests rejected by limit_req or limit_conn",
+            ),
+            // SSI, auth_request, …; not part of server_requests_total.
+            // Only logged with `log_subrequest on`.
+            subrequests: self.family(
+                "server_subrequests_total",
+                Counter,
+                "Subrequests, counted apart from requests",
+            ),
+            // 408 (client_body_timeout) vs 400 while reading the body.
+            client_body_errors: self.family(
+                "server_client_body_errors_total",
+                Counter,
+                "Requests that failed reading the client body",
+            ),
+            // 101 Switching Protocols; not part of server_responses_total.
+            upgrades: self.family(
+                "server_upgrades_total",
+                Counter,
+                "Requests upgraded to another protocol (101)",
+            ),
+            // Avg/min/max gauges.
+            request_seconds: self.family(
+                "server_request_seconds",
+                Gauge,
+                "Request processing time",
+            ),
+            request_seconds_sum: self.family(
+                "server_request_seconds_sum",
+                Counter,
+                "Total request processing time",
+            ),
+            request_seconds_count: self.family(
+                "server_request_seconds_count",
+                Counter,
+                "Requests timed in server_request_seconds_sum",
+            ),
+            // (satisfied + tolerating / 2) / requests, per vts_apdex_threshold.
+            apdex: self.family("server_apdex", Gauge, "Apdex score from request time"),
+            // In-flight requests per zone, by phase.
+            connections: self.family(
+                "server_connections",
+                Gauge,
+                "Requests in flight per server zone",
+            ),
+            // Upgraded (WebSocket) connections still open, also in `active`.
+            websocket_connections: self.family(
+                "server_websocket_connections",
+                Gauge,
+                "Open upgraded connections per server zone",
+            ),
+        }
+    }
+
+    /// Format the `vts_detail_method_status` cross-tab as
+    /// `nginx_vts_server_method_status_total{zone,method,status}`, with
+    /// the zones' `vts_zone_label` labels.  Only non-zero cells are
+    /// emitted; nothing at all when no zone has the detail on.
+    pub fn format_method_status(
+        &self,
+        method_status: &HashMap<String, MethodStatusCounters>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> String {
+        self.render(&self.method_status_families(method_status, zone_labels))
+    }
+
+    pub(crate) fn method_status_families(
+        &self,
+        method_status: &HashMap<String, MethodStatusCounters>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> Vec<Family> {
+        if method_status.is_empty() {
+            return Vec::new();
+        }
+        let selectors = self.server_stats_writer().with_zone_labels(zone_labels);
+        let mut zones: Vec<_> = method_status.iter().collect();
+        zones.sort_unstable_by_key(|&(zone, _)| zone);
+
+        let mut family = self.family(
+            "server_method_status_total",
+            MetricKind::Counter,
+            "Requests by method and status class",
+        );
+        for (zone, counters) in zones {
+            let labels = selectors.zone_label_set(zone);
+            for method in HttpMethod::ALL {
+                for class in STATUS_CLASSES {
+                    let value = counters.get(method, class);
+                    if value > 0 {
+                        family.push(
+                            label_set(&labels, &[("method", method.as_str()), ("status", class)]),
+                            value,
+                        );
+                    }
+                }
             }
         }
-        output.push('\n');
+        vec![family]
+    }
 
-        // Server request seconds (avg/min/max gauges).
-        output.push_str(&format!(
-            "# HELP {prefix}server_request_seconds Request processing time\n"
-        ));
-        output.push_str(&format!("# TYPE {prefix}server_request_seconds gauge\n"));
-        for (zone, stats) in server_stats {
-            for (kind, value) in [
-                ("avg", stats.request_times.avg),
-                ("min", stats.request_times.min),
-                ("max", stats.request_times.max),
-            ] {
-                output.push_str(&format!(
-                    "{prefix}server_request_seconds{{zone=\"{zone}\",type=\"{kind}\"}} {value:.6}\n"
-                ));
+    /// Format the `vts_track_content_type` breakdown as
+    /// `nginx_vts_server_responses_by_type_total{zone,type}`, with the
+    /// zones' `vts_zone_label` labels.  Nothing at all when no zone has
+    /// it on.
+    pub fn format_content_types(
+        &self,
+        content_types: &HashMap<String, ContentTypeCounters>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> String {
+        self.render(&self.content_type_families(content_types, zone_labels))
+    }
+
+    pub(crate) fn content_type_families(
+        &self,
+        content_types: &HashMap<String, ContentTypeCounters>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> Vec<Family> {
+        if content_types.is_empty() {
+            return Vec::new();
+        }
+        let selectors = self.server_stats_writer().with_zone_labels(zone_labels);
+        let mut zones: Vec<_> = content_types.iter().collect();
+        zones.sort_unstable_by_key(|&(zone, _)| zone);
+
+        let mut family = self.family(
+            "server_responses_by_type_total",
+            MetricKind::Counter,
+            "Responses by Content-Type top-level type",
+        );
+        for (zone, counters) in zones {
+            let labels = selectors.zone_label_set(zone);
+            for (kind, value) in CONTENT_TYPES.iter().zip(counters.counts) {
+                family.push(label_set(&labels, &[("type", *kind)]), value);
             }
         }
-        output.push('\n');
+        vec![family]
+    }
+
+    /// Format the `vts_track_grpc` breakdown as
+    /// `nginx_vts_server_grpc_responses_total{zone,grpc_status}`, with
+    /// the zones' `vts_zone_label` labels.  Only codes seen so far are
+    /// emitted, and nothing at all before the first gRPC response.
+    pub fn format_grpc_statuses(
+        &self,
+        grpc_statuses: &HashMap<String, GrpcStatusCounters>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> String {
+        self.render(&self.grpc_status_families(grpc_statuses, zone_labels))
+    }
+
+    pub(crate) fn grpc_status_families(
+        &self,
+        grpc_statuses: &HashMap<String, GrpcStatusCounters>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> Vec<Family> {
+        if grpc_statuses.is_empty() {
+            return Vec::new();
+        }
+        let selectors = self.server_stats_writer().with_zone_labels(zone_labels);
+        let mut zones: Vec<_> = grpc_statuses.iter().collect();
+        zones.sort_unstable_by_key(|&(zone, _)| zone);
+
+        let mut family = self.family(
+            "server_grpc_responses_total",
+            MetricKind::Counter,
+            "Responses by gRPC status",
+        );
+        for (zone, counters) in zones {
+            let labels = selectors.zone_label_set(zone);
+            for (name, value) in GRPC_STATUS_NAMES.iter().zip(counters.counts) {
+                if value > 0 {
+                    family.push(label_set(&labels, &[("grpc_status", *name)]), value);
+                }
+            }
+        }
+        vec![family]
+    }
+
+    /// Format the `vts_track_unique_clients` sketches as
+    /// `nginx_vts_server_unique_clients_estimate{zone}`, rounded to a
+    /// whole number of clients, with the zones' `vts_zone_label`
+    /// labels.  Emits nothing before the first tracked request.
+    #[cfg(feature = "unique-clients")]
+    pub fn format_unique_clients(
+        &self,
+        unique_clients: &HashMap<String, HyperLogLog>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> String {
+        self.render(&self.unique_client_families(unique_clients, zone_labels))
+    }
+
+    #[cfg(feature = "unique-clients")]
+    pub(crate) fn unique_client_families(
+        &self,
+        unique_clients: &HashMap<String, HyperLogLog>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> Vec<Family> {
+        if unique_clients.is_empty() {
+            return Vec::new();
+        }
+        let selectors = self.server_stats_writer().with_zone_labels(zone_labels);
+        let mut zones: Vec<_> = unique_clients.iter().collect();
+        zones.sort_unstable_by_key(|&(zone, _)| zone);
+
+        let mut family = self.family(
+            "server_unique_clients_estimate",
+            MetricKind::Gauge,
+            "Approximate distinct client addresses",
+        );
+        for (zone, sketch) in zones {
+            family.push(
+                selectors.zone_label_set(zone),
+                sketch.estimate().round() as u64,
+            );
+        }
+        vec![family]
+    }
+
+    /// Format the request-header histograms as
+    /// `nginx_vts_server_request_header_bytes` and
+    /// `nginx_vts_server_request_headers` (`_bucket{le}`, `_sum`,
+    /// `_count`), with the zones' `vts_zone_label` labels.  Emits
+    /// nothing before the first observed request.
+    pub fn format_request_headers(
+        &self,
+        request_headers: &HashMap<String, RequestHeaderStats>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> String {
+        self.render(&self.request_header_families(request_headers, zone_labels))
+    }
+
+    pub(crate) fn request_header_families(
+        &self,
+        request_headers: &HashMap<String, RequestHeaderStats>,
+        zone_labels: &HashMap<String, Vec<(String, String)>>,
+    ) -> Vec<Family> {
+        if request_headers.is_empty() {
+            return Vec::new();
+        }
+        let selectors = self.server_stats_writer().with_zone_labels(zone_labels);
+        let mut zones: Vec<_> = request_headers.iter().collect();
+        zones.sort_unstable_by_key(|&(zone, _)| zone);
+
+        // Same order as `RequestHeaderStats::histograms`.
+        let help = [
+            "Request line and header size distribution in bytes",
+            "Request header count distribution",
+        ];
+        let mut families = Vec::with_capacity(help.len());
+        for (i, help) in help.into_iter().enumerate() {
+            let name = RequestHeaderStats::default().histograms()[i].0;
+            let mut family = self.family(name, MetricKind::Histogram, help);
+            for &(zone, stats) in &zones {
+                let (_, bounds, buckets, sum) = stats.histograms()[i];
+                let labels = selectors.zone_label_set(zone);
+                for (&bound, &value) in bounds.iter().zip(buckets) {
+                    let le = format_le(bound as f64);
+                    family.push_part("_bucket", label_set(&labels, &[("le", &le)]), value);
+                }
+                // +Inf bucket holds every sample, equal to _count.
+                let le = format_le(f64::INFINITY);
+                family.push_part(
+                    "_bucket",
+                    label_set(&labels, &[("le", &le)]),
+                    stats.requests,
+                );
+                family.push_part("_sum", labels.clone(), sum);
+                family.push_part("_count", labels, stats.requests);
+            }
+            families.push(family);
+        }
+        families
+    }
 
-        output
+    /// Mark server zones whose accounting is paused (see
+    /// `VtsStatsManager::set_zone_enabled`).  Their counters above are
+    /// frozen at the moment they were disabled.  Emits nothing when no
+    /// zone is disabled.
+    pub fn format_disabled_zones(&self, disabled_zones: &[String]) -> String {
+        self.render(&self.disabled_zone_families(disabled_zones))
+    }
+
+    pub(crate) fn disabled_zone_families(&self, disabled_zones: &[String]) -> Vec<Family> {
+        if disabled_zones.is_empty() {
+            return Vec::new();
+        }
+        let mut family = self.family(
+            "server_zone_disabled",
+            MetricKind::Gauge,
+            "Server zones with accounting paused",
+        );
+        for zone in disabled_zones {
+            family.push(label_set(&[], &[("zone", zone)]), 1);
+        }
+        vec![family]
+    }
+}
+
+/// Single-pass builder for the server families.  Zones are fed one at
+/// a time (e.g. straight from `VtsStatsManager::for_each_server_zone`,
+/// without cloning the table) and each family collects its own series
+/// so the output stays grouped by family.  Series come out in the order
+/// zones are added; callers feed them sorted by name.
+pub struct ServerStatsWriter<'a> {
+    formatter: &'a PrometheusFormatter,
+    zone_labels: Option<&'a HashMap<String, Vec<(String, String)>>>,
+    /// Every user-defined label name across all zones, sorted.
+    label_names: Vec<&'a str>,
+    requests: Family,
+    bytes: Family,
+    response_header_bytes: Family,
+    response_body_bytes: Family,
+    goodput_bytes: Family,
+    responses: Family,
+    responses_detail: Family,
+    rate_limited: Family,
+    subrequests: Family,
+    client_body_errors: Family,
+    upgrades: Family,
+    request_seconds: Family,
+    request_seconds_sum: Family,
+    request_seconds_count: Family,
+    apdex: Family,
+    connections: Family,
+    websocket_connections: Family,
+}
+
+impl<'a> ServerStatsWriter<'a> {
+    /// Add each zone's `vts_zone_label` labels to its series.  Zones
+    /// without a given label get it empty (which Prometheus treats as
+    /// absent), so every series of a family has the same label names.
+    pub fn with_zone_labels(
+        mut self,
+        zone_labels: &'a HashMap<String, Vec<(String, String)>>,
+    ) -> Self {
+        let mut names: Vec<&str> = zone_labels
+            .values()
+            .flatten()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        self.zone_labels = Some(zone_labels);
+        self.label_names = names;
+        self
+    }
+
+    /// `zone` followed by the zone's user-defined labels.
+    fn zone_label_set(&self, zone: &str) -> Vec<(String, String)> {
+        let own = self.zone_labels.and_then(|labels| labels.get(zone));
+        let mut labels = Vec::with_capacity(1 + self.label_names.len());
+        labels.push(("zone".to_string(), zone.to_string()));
+        for name in &self.label_names {
+            let value = own
+                .and_then(|labels| labels.iter().find(|(n, _)| n == name))
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default();
+            labels.push((name.to_string(), value));
+        }
+        labels
+    }
+
+    /// Add the series of one server zone.
+    pub fn add(&mut self, zone: &str, stats: &VtsServerStats) {
+        let labels = self.zone_label_set(zone);
+        let with = |extra: &[(&str, &str)]| label_set(&labels, extra);
+
+        self.requests.push(labels.clone(), stats.requests);
+
+        for (direction, value) in [("in", stats.bytes_in), ("out", stats.bytes_out)] {
+            self.bytes.push(with(&[("direction", direction)]), value);
+        }
+
+        self.response_header_bytes
+            .push(labels.clone(), stats.header_bytes_out);
+        self.response_body_bytes
+            .push(labels.clone(), stats.body_bytes_out());
+        self.goodput_bytes
+            .push(labels.clone(), stats.goodput_bytes_out);
+
+        for (class, value) in [
+            ("1xx", stats.responses.status_1xx),
+            ("2xx", stats.responses.status_2xx),
+            ("3xx", stats.responses.status_3xx),
+            ("4xx", stats.responses.status_4xx),
+            ("5xx", stats.responses.status_5xx),
+        ] {
+            self.responses.push(with(&[("status", class)]), value);
+        }
+
+        for (status, value) in [
+            ("206", stats.responses.status_206),
+            ("304", stats.responses.status_304),
+        ] {
+            self.responses_detail
+                .push(with(&[("status", status)]), value);
+        }
+
+        self.rate_limited.push(labels.clone(), stats.rate_limited);
+        self.subrequests.push(labels.clone(), stats.subrequests);
+
+        for (reason, value) in [
+            ("timeout", stats.client_body_timeouts),
+            ("malformed", stats.client_body_malformed),
+        ] {
+            self.client_body_errors
+                .push(with(&[("reason", reason)]), value);
+        }
+
+        self.upgrades.push(labels.clone(), stats.upgrades);
+
+        for (kind, value) in [
+            ("avg", stats.request_times.avg),
+            ("min", stats.request_times.min),
+            ("max", stats.request_times.max),
+        ] {
+            self.request_seconds.push(with(&[("type", kind)]), value);
+        }
+
+        // Summary-style components: unlike the gauges above these add
+        // up across zones and instances, and rate(sum) is the average
+        // number of requests in flight (Little's Law).
+        self.request_seconds_sum
+            .push(labels.clone(), stats.request_times.total);
+        self.request_seconds_count
+            .push(labels.clone(), stats.requests);
+
+        // No score until the zone has seen a request.
+        if let Some(score) = stats.apdex.score() {
+            self.apdex.push(labels.clone(), score);
+        }
+
+        for (state, value) in [
+            ("active", stats.connections.active),
+            ("reading", stats.connections.reading),
+            ("writing", stats.connections.writing),
+        ] {
+            self.connections.push(with(&[("state", state)]), value);
+        }
+
+        self.websocket_connections
+            .push(labels, stats.connections.upgraded);
+    }
+
+    /// Every family, in a fixed order.
+    pub(crate) fn into_families(self) -> Vec<Family> {
+        vec![
+            self.requests,
+            self.bytes,
+            self.response_header_bytes,
+            self.response_body_bytes,
+            self.goodput_bytes,
+            self.responses,
+            self.responses_detail,
+            self.rate_limited,
+            self.subrequests,
+            self.client_body_errors,
+            self.upgrades,
+            self.request_seconds,
+            self.request_seconds_sum,
+            self.request_seconds_count,
+            self.apdex,
+            self.connections,
+            self.websocket_connections,
+        ]
+    }
+
+    /// Render every family, headers first, in a fixed order.
+    pub fn finish(self) -> String {
+        let formatter = self.formatter;
+        formatter.render(&self.into_families())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::stats::{VtsRequestTimes, VtsResponseStats};
+    use crate::stats::{VtsApdexStats, VtsRequestTimes, VtsResponseStats, VtsServerConnections};
 
     #[test]
     fn format_server_stats_emits_all_families() {
@@ -97,19 +573,39 @@ mod tests {
                 requests: 42,
                 bytes_in: 1024,
                 bytes_out: 2048,
+                header_bytes_out: 512,
+                goodput_bytes_out: 1800,
                 responses: VtsResponseStats {
                     status_1xx: 0,
                     status_2xx: 40,
                     status_3xx: 0,
                     status_4xx: 1,
                     status_5xx: 1,
+                    status_206: 0,
+                    status_304: 0,
                 },
+                rate_limited: 4,
                 request_times: VtsRequestTimes {
                     total: 4.2,
                     min: 0.005,
                     max: 0.250,
                     avg: 0.100,
                 },
+                apdex: VtsApdexStats {
+                    satisfied: 30,
+                    tolerating: 10,
+                    frustrated: 2,
+                },
+                subrequests: 6,
+                client_body_timeouts: 2,
+                client_body_malformed: 1,
+                upgrades: 0,
+                connections: VtsServerConnections {
+                    active: 3,
+                    reading: 1,
+                    writing: 2,
+                    upgraded: 0,
+                },
             },
         );
 
@@ -120,6 +616,12 @@ mod tests {
         assert!(out.contains(
             "nginx_vts_server_bytes_total{zone=\"example.test\",direction=\"out\"} 2048"
         ));
+        assert!(
+            out.contains("nginx_vts_server_response_header_bytes_total{zone=\"example.test\"} 512")
+        );
+        assert!(
+            out.contains("nginx_vts_server_response_body_bytes_total{zone=\"example.test\"} 1536")
+        );
         assert!(out
             .contains("nginx_vts_server_responses_total{zone=\"example.test\",status=\"2xx\"} 40"));
         assert!(out
@@ -130,5 +632,28 @@ mod tests {
         assert!(out.contains(
             "nginx_vts_server_request_seconds{zone=\"example.test\",type=\"min\"} 0.005000"
         ));
+        assert!(out.contains("nginx_vts_server_rate_limited_total{zone=\"example.test\"} 4"));
+        assert!(out.contains("nginx_vts_server_subrequests_total{zone=\"example.test\"} 6"));
+        assert!(out.contains(
+            "nginx_vts_server_client_body_errors_total{zone=\"example.test\",reason=\"timeout\"} 2"
+        ));
+        // (30 + 10 / 2) / 42
+        assert!(out.contains("nginx_vts_server_apdex{zone=\"example.test\"} 0.833333"));
+        assert!(out.contains("# TYPE nginx_vts_server_connections gauge"));
+        assert!(
+            out.contains("nginx_vts_server_connections{zone=\"example.test\",state=\"active\"} 3")
+        );
+        assert!(
+            out.contains("nginx_vts_server_connections{zone=\"example.test\",state=\"writing\"} 2")
+        );
+    }
+
+    #[test]
+    fn format_disabled_zones_marks_each_zone() {
+        let f = PrometheusFormatter::new();
+        assert!(f.format_disabled_zones(&[]).is_empty());
+        let out = f.format_disabled_zones(&["noisy.test".to_string()]);
+        assert!(out.contains("# TYPE nginx_vts_server_zone_disabled gauge"));
+        assert!(out.contains("nginx_vts_server_zone_disabled{zone=\"noisy.test\"} 1"));
     }
 }
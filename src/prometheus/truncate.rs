@@ -0,0 +1,194 @@
+//! `vts_max_label_len`: cap label values on the rendered page.
+//!
+//! Applied once to the finished page, so every family (zone, upstream,
+//! server and cache names, `vts_zone_label` values, build info) is
+//! covered without each formatter knowing the limit.  A value longer
+//! than the limit keeps as much of its start as fits, cut on a UTF-8
+//! boundary, followed by `…` and a hash of the full value, so two long
+//! names sharing a prefix stay distinct series.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use super::escape_label_value;
+
+/// Marker between the kept prefix and the hash.
+const ELLIPSIS: &str = "…";
+
+/// Hex digits of the full value's hash after [`ELLIPSIS`].
+const HASH_DIGITS: usize = 8;
+
+/// Smallest accepted `vts_max_label_len`: room for the marker, the
+/// hash and a few bytes of the value.
+pub const MIN_LABEL_LEN: usize = 16;
+
+/// `value` shortened to at most `max_len` bytes, or `None` when it
+/// already fits.
+pub fn truncate_label_value(value: &str, max_len: usize) -> Option<String> {
+    if value.len() <= max_len {
+        return None;
+    }
+    let mut cut = max_len.saturating_sub(ELLIPSIS.len() + HASH_DIGITS);
+    while !value.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    Some(format!("{}{ELLIPSIS}{:08x}", &value[..cut], fnv1a(value)))
+}
+
+/// Rewrite every over-long label value in `output`.  Returns the page,
+/// borrowed as-is when nothing was too long, and how many distinct
+/// values were truncated.
+pub fn truncate_label_values(output: &str, max_len: usize) -> (Cow<'_, str>, u64) {
+    let mut truncated = HashSet::new();
+    let mut rewritten: Option<String> = None;
+    let mut offset = 0;
+    for line in output.split_inclusive('\n') {
+        let changed = match line.starts_with('#') {
+            true => None,
+            false => rewrite_labels(line, max_len, &mut truncated),
+        };
+        match (rewritten.as_mut(), changed) {
+            (Some(page), Some(line)) => page.push_str(&line),
+            (Some(page), None) => page.push_str(line),
+            (None, Some(line)) => {
+                let mut page = String::with_capacity(output.len());
+                page.push_str(&output[..offset]);
+                page.push_str(&line);
+                rewritten = Some(page);
+            }
+            (None, None) => {}
+        }
+        offset += line.len();
+    }
+    let page = rewritten.map_or(Cow::Borrowed(output), Cow::Owned);
+    (page, truncated.len() as u64)
+}
+
+/// `line` with its over-long label values replaced, or `None` when
+/// nothing changed (or the line has no well-formed label set).  Only
+/// allocates once a value actually needs shortening.
+fn rewrite_labels(line: &str, max_len: usize, truncated: &mut HashSet<String>) -> Option<String> {
+    let mut pos = line.find('{')? + 1;
+    let mut out: Option<String> = None;
+    let mut copied = 0;
+    loop {
+        let start = pos + line[pos..].find("=\"")? + 2;
+        let end = start + closing_quote(&line[start..])?;
+        let escaped = &line[start..end];
+        // Unescaping never lengthens a value, so one that fits escaped
+        // fits unescaped too.
+        if escaped.len() > max_len {
+            let value = unescape(escaped);
+            if let Some(short) = truncate_label_value(&value, max_len) {
+                let out = out.get_or_insert_with(|| String::with_capacity(line.len()));
+                out.push_str(&line[copied..start]);
+                out.push_str(&escape_label_value(&short));
+                copied = end;
+                truncated.insert(value);
+            }
+        }
+
+        let rest = &line[end..];
+        if rest.starts_with("\",") {
+            pos = end + 2;
+        } else if rest.starts_with("\"}") {
+            break;
+        } else {
+            return None;
+        }
+    }
+    let mut out = out?;
+    out.push_str(&line[copied..]);
+    Some(out)
+}
+
+/// Byte offset of the first unescaped `"` in `s`.
+fn closing_quote(s: &str) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Inverse of [`escape_label_value`].
+fn unescape(escaped: &str) -> String {
+    let mut out = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// 32-bit FNV-1a: stable across builds, unlike `DefaultHasher`.
+fn fnv1a(value: &str) -> u32 {
+    value.bytes().fold(0x811c_9dc5, |hash, b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_values_are_left_alone() {
+        assert_eq!(truncate_label_value("example.com", 16), None);
+        let page = "# HELP m M\nm{zone=\"example.com\"} 1\n";
+        let (out, count) = truncate_label_values(page, 16);
+        assert!(matches!(out, Cow::Borrowed(borrowed) if std::ptr::eq(borrowed, page)));
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn truncation_respects_utf8_boundaries_and_limit() {
+        // 'é' is two bytes; a byte cut at 5 would split the third one.
+        let value = "ééééééééééééééé";
+        let short = truncate_label_value(value, 16).unwrap();
+        assert!(short.len() <= 16);
+        assert!(short.starts_with("éé…"));
+        assert_eq!(short.len(), "éé…".len() + HASH_DIGITS);
+    }
+
+    #[test]
+    fn shared_prefixes_stay_distinct_and_escapes_survive() {
+        let a = format!("{}a\"", "x".repeat(40));
+        let b = format!("{}b\"", "x".repeat(40));
+        let page = format!(
+            "m{{zone=\"{}\",status=\"2xx\"}} 1\n\
+             m{{zone=\"{}\",status=\"2xx\"}} 2\n\
+             m{{zone=\"{}\",status=\"5xx\"}} 3\n",
+            escape_label_value(&a),
+            escape_label_value(&b),
+            escape_label_value(&a),
+        );
+        let (out, count) = truncate_label_values(&page, 32);
+        assert_eq!(count, 2);
+
+        let zones: Vec<String> = out
+            .lines()
+            .map(|line| {
+                let rest = line.strip_prefix("m{zone=\"").unwrap();
+                unescape(&rest[..closing_quote(rest).unwrap()])
+            })
+            .collect();
+        assert!(zones.iter().all(|zone| zone.len() <= 32));
+        assert_ne!(zones[0], zones[1]);
+        assert_eq!(zones[0], zones[2]);
+        assert!(out.contains(",status=\"5xx\"} 3\n"));
+    }
+}
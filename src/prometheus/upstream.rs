@@ -1,14 +1,139 @@
 //! `nginx_vts_upstream_*` series: requests, bytes, response_seconds
-//! summary, server_up gauge, status counters, and the
+//! summary, server_up and rolling error_rate gauges, status counters,
+//! and the
 //! `response_duration_seconds` classic histogram (compatible with
 //! `histogram_quantile()` for p50/p90/p99 panels).
 
 use std::collections::HashMap;
 
-use super::PrometheusFormatter;
-use crate::upstream_stats::{UpstreamZone, RESPONSE_TIME_BUCKET_BOUNDS_MS};
+use super::{format_le, PrometheusFormatter};
+use crate::sample::{label_set, Family, MetricKind};
+use crate::upstream_stats::{
+    now_secs, UpstreamQueueStats, UpstreamServerStats, UpstreamZone, RESPONSE_TIME_BUCKET_BOUNDS_MS,
+};
+
+/// An upstream group's labels and its servers, sorted by name.
+type SortedUpstream<'a> = (
+    Vec<(String, String)>,
+    Vec<(&'a str, &'a UpstreamServerStats)>,
+);
+
+/// Order upstream groups and servers by name so every scrape renders
+/// its lines in the same order.  Each group comes with its `upstream`
+/// label, plus `zone` once any group has a `vts_upstream_zone` (empty
+/// for groups without one, so every series of a family has the same
+/// label names).
+fn sorted_upstreams<'a>(
+    upstream_zones: &'a HashMap<String, UpstreamZone>,
+    zone_names: &HashMap<String, String>,
+) -> Vec<SortedUpstream<'a>> {
+    let mut upstreams: Vec<_> = upstream_zones
+        .iter()
+        .map(|(name, zone)| {
+            let mut servers: Vec<_> = zone
+                .servers
+                .iter()
+                .map(|(addr, stats)| (addr.as_str(), stats))
+                .collect();
+            servers.sort_unstable_by_key(|&(addr, _)| addr);
+            (name.as_str(), servers)
+        })
+        .collect();
+    upstreams.sort_unstable_by_key(|&(name, _)| name);
+    upstreams
+        .into_iter()
+        .map(|(name, servers)| {
+            let mut labels = vec![("upstream".to_string(), name.to_string())];
+            if !zone_names.is_empty() {
+                let zone = zone_names.get(name).cloned().unwrap_or_default();
+                labels.push(("zone".to_string(), zone));
+            }
+            (labels, servers)
+        })
+        .collect()
+}
+
+/// Every upstream server in page order, with its group's labels and
+/// `server`.
+fn server_label_sets<'a>(
+    upstreams: &[SortedUpstream<'a>],
+) -> Vec<(Vec<(String, String)>, &'a UpstreamServerStats)> {
+    upstreams
+        .iter()
+        .flat_map(|(labels, servers)| {
+            servers
+                .iter()
+                .map(move |&(addr, stats)| (label_set(labels, &[("server", addr)]), stats))
+        })
+        .collect()
+}
 
 impl PrometheusFormatter {
+    /// Format upstream connection-slot queues: the current length gauge
+    /// and the count of requests that have had to wait.
+    pub fn format_upstream_queue_stats(
+        &self,
+        queues: &HashMap<String, UpstreamQueueStats>,
+    ) -> String {
+        self.render(&self.upstream_queue_families(queues))
+    }
+
+    pub(crate) fn upstream_queue_families(
+        &self,
+        queues: &HashMap<String, UpstreamQueueStats>,
+    ) -> Vec<Family> {
+        if queues.is_empty() {
+            return Vec::new();
+        }
+        let mut sorted: Vec<_> = queues.iter().collect();
+        sorted.sort_unstable_by_key(|&(name, _)| name);
+
+        let mut length = self.family(
+            "upstream_queue_length",
+            MetricKind::Gauge,
+            "Requests waiting for an upstream connection slot",
+        );
+        let mut waits = self.family(
+            "upstream_queue_waits_total",
+            MetricKind::Counter,
+            "Requests that waited for an upstream connection slot",
+        );
+        for (upstream_name, queue) in sorted {
+            let labels = label_set(&[], &[("upstream", upstream_name)]);
+            length.push(labels.clone(), queue.length);
+            waits.push(labels, queue.waits_total);
+        }
+        vec![length, waits]
+    }
+
+    /// Format the per-upstream count of server addresses listed more
+    /// than once in the configuration.  Emits nothing when there are
+    /// none.
+    pub fn format_upstream_duplicate_servers(&self, duplicates: &HashMap<String, u64>) -> String {
+        self.render(&self.upstream_duplicate_server_families(duplicates))
+    }
+
+    pub(crate) fn upstream_duplicate_server_families(
+        &self,
+        duplicates: &HashMap<String, u64>,
+    ) -> Vec<Family> {
+        if duplicates.is_empty() {
+            return Vec::new();
+        }
+        let mut sorted: Vec<_> = duplicates.iter().collect();
+        sorted.sort_unstable_by_key(|&(name, _)| name);
+
+        let mut family = self.family(
+            "upstream_duplicate_servers_total",
+            MetricKind::Counter,
+            "Server addresses listed more than once in an upstream",
+        );
+        for (upstream_name, &count) in sorted {
+            family.push(label_set(&[], &[("upstream", upstream_name)]), count);
+        }
+        vec![family]
+    }
+
     /// Format upstream statistics into Prometheus metrics.
     ///
     /// Generates metrics for upstream servers including request counts,
@@ -16,182 +141,253 @@ impl PrometheusFormatter {
     /// the response-duration histogram.
     #[allow(dead_code)] // Used in tests and VTS integration
     pub fn format_upstream_stats(&self, upstream_zones: &HashMap<String, UpstreamZone>) -> String {
-        let mut output = String::new();
+        self.format_labeled_upstream_stats(upstream_zones, &HashMap::new())
+    }
+
+    /// [`format_upstream_stats`](Self::format_upstream_stats) with a
+    /// `zone` label from each group's `vts_upstream_zone`, so servers
+    /// shared between pools stay apart by pool as well as by upstream.
+    pub fn format_labeled_upstream_stats(
+        &self,
+        upstream_zones: &HashMap<String, UpstreamZone>,
+        zone_names: &HashMap<String, String>,
+    ) -> String {
+        self.render(&self.upstream_families(upstream_zones, zone_names))
+    }
+
+    pub(crate) fn upstream_families(
+        &self,
+        upstream_zones: &HashMap<String, UpstreamZone>,
+        zone_names: &HashMap<String, String>,
+    ) -> Vec<Family> {
+        use MetricKind::{Counter, Gauge};
+
         if upstream_zones.is_empty() {
-            return output;
+            return Vec::new();
         }
-        let prefix = &self.metric_prefix;
+        let upstreams = sorted_upstreams(upstream_zones, zone_names);
+        let servers = server_label_sets(&upstreams);
+        let mut families = Vec::new();
 
-        // nginx_vts_upstream_requests_total
-        output.push_str(&format!(
-            "# HELP {prefix}upstream_requests_total Total upstream requests\n"
-        ));
-        output.push_str(&format!("# TYPE {prefix}upstream_requests_total counter\n"));
-        for (upstream_name, zone) in upstream_zones {
-            for (server_addr, stats) in &zone.servers {
-                output.push_str(&format!(
-                    "{prefix}upstream_requests_total{{upstream=\"{upstream_name}\",server=\"{server_addr}\"}} {}\n",
-                    stats.request_counter
-                ));
+        let mut requests = self.family(
+            "upstream_requests_total",
+            Counter,
+            "Total upstream requests",
+        );
+        let mut bytes = self.family(
+            "upstream_bytes_total",
+            Counter,
+            "Total bytes transferred to/from upstream",
+        );
+        // The same bytes as `direction="out"` / `"in"` above, named for
+        // what they carry.
+        let mut request_bytes = self.family(
+            "upstream_request_bytes_total",
+            Counter,
+            "Request bytes sent to upstream",
+        );
+        let mut response_bytes = self.family(
+            "upstream_response_bytes_total",
+            Counter,
+            "Response bytes received from upstream",
+        );
+        // avg/total summary.
+        let mut response_seconds = self.family(
+            "upstream_response_seconds",
+            Gauge,
+            "Upstream response time statistics",
+        );
+        // Request time not spent waiting on the upstream, so local
+        // processing shows apart.
+        let mut local_seconds = self.family(
+            "request_local_seconds",
+            Counter,
+            "Time spent outside the upstream, total",
+        );
+        let mut local_clamped = self.family(
+            "request_local_clamped_total",
+            Counter,
+            "Requests whose upstream time exceeded the request time",
+        );
+        for (labels, stats) in &servers {
+            requests.push(labels.clone(), stats.request_counter);
+            for (direction, value) in [("in", stats.in_bytes), ("out", stats.out_bytes)] {
+                bytes.push(label_set(labels, &[("direction", direction)]), value);
             }
-        }
-        output.push('\n');
-
-        // nginx_vts_upstream_bytes_total
-        output.push_str(&format!(
-            "# HELP {prefix}upstream_bytes_total Total bytes transferred to/from upstream\n"
-        ));
-        output.push_str(&format!("# TYPE {prefix}upstream_bytes_total counter\n"));
-        for (upstream_name, zone) in upstream_zones {
-            for (server_addr, stats) in &zone.servers {
-                output.push_str(&format!(
-                    "{prefix}upstream_bytes_total{{upstream=\"{upstream_name}\",server=\"{server_addr}\",direction=\"in\"}} {}\n",
-                    stats.in_bytes
-                ));
-                output.push_str(&format!(
-                    "{prefix}upstream_bytes_total{{upstream=\"{upstream_name}\",server=\"{server_addr}\",direction=\"out\"}} {}\n",
-                    stats.out_bytes
-                ));
+            request_bytes.push(labels.clone(), stats.out_bytes);
+            response_bytes.push(labels.clone(), stats.in_bytes);
+            for (kind, value) in [
+                ("request_avg", stats.avg_request_time() / 1000.0),
+                ("upstream_avg", stats.avg_response_time() / 1000.0),
+                ("request_total", stats.request_time_total as f64 / 1000.0),
+                ("upstream_total", stats.response_time_total as f64 / 1000.0),
+            ] {
+                response_seconds.push(label_set(labels, &[("type", kind)]), value);
             }
+            local_seconds.push(labels.clone(), stats.local_time_total as f64 / 1000.0);
+            local_clamped.push(labels.clone(), stats.local_time_clamped);
         }
-        output.push('\n');
+        families.extend([
+            requests,
+            bytes,
+            request_bytes,
+            response_bytes,
+            response_seconds,
+            local_seconds,
+            local_clamped,
+        ]);
 
-        // nginx_vts_upstream_response_seconds (avg/total summary).
-        output.push_str(&format!(
-            "# HELP {prefix}upstream_response_seconds Upstream response time statistics\n"
-        ));
-        output.push_str(&format!("# TYPE {prefix}upstream_response_seconds gauge\n"));
-        for (upstream_name, zone) in upstream_zones {
-            for (server_addr, stats) in &zone.servers {
-                let avg_request_time = stats.avg_request_time() / 1000.0;
-                let avg_response_time = stats.avg_response_time() / 1000.0;
-                let total_request_time = stats.request_time_total as f64 / 1000.0;
-                let total_upstream_time = stats.response_time_total as f64 / 1000.0;
-                for (kind, value) in [
-                    ("request_avg", avg_request_time),
-                    ("upstream_avg", avg_response_time),
-                    ("request_total", total_request_time),
-                    ("upstream_total", total_upstream_time),
-                ] {
-                    output.push_str(&format!(
-                        "{prefix}upstream_response_seconds{{upstream=\"{upstream_name}\",server=\"{server_addr}\",type=\"{kind}\"}} {value:.6}\n"
-                    ));
+        // A family of its own so `upstream_response_seconds` keeps one
+        // label set.
+        #[cfg(feature = "latency-percentiles")]
+        {
+            let mut quantiles = self.family(
+                "upstream_response_quantile_seconds",
+                Gauge,
+                "Upstream response time percentiles",
+            );
+            for (labels, stats) in &servers {
+                for q in crate::latency::LATENCY_QUANTILES {
+                    let value = stats.latency.value_at_quantile(q) as f64 / 1000.0;
+                    quantiles.push(label_set(labels, &[("quantile", &q.to_string())]), value);
                 }
             }
+            families.push(quantiles);
         }
-        output.push('\n');
 
-        // nginx_vts_upstream_server_up
-        output.push_str(&format!(
-            "# HELP {prefix}upstream_server_up Upstream server status (1=up, 0=down)\n"
-        ));
-        output.push_str(&format!("# TYPE {prefix}upstream_server_up gauge\n"));
-        for (upstream_name, zone) in upstream_zones {
-            for (server_addr, stats) in &zone.servers {
-                let server_up = if stats.down { 0 } else { 1 };
-                output.push_str(&format!(
-                    "{prefix}upstream_server_up{{upstream=\"{upstream_name}\",server=\"{server_addr}\"}} {server_up}\n"
-                ));
-            }
+        let mut server_up = self.family(
+            "upstream_server_up",
+            Gauge,
+            "Upstream server status (1=up, 0=down)",
+        );
+        for (labels, stats) in &servers {
+            server_up.push(labels.clone(), u64::from(!stats.down));
         }
-        output.push('\n');
+        families.push(server_up);
 
-        // HTTP status code metrics and response-time histogram.
-        self.format_upstream_status_metrics(&mut output, upstream_zones);
-        self.format_upstream_response_histogram(&mut output, upstream_zones);
+        // One series per upstream, so an outage alert needn't compare
+        // sums of server_up with counts.
+        let mut fully_down = self.family(
+            "upstream_fully_down",
+            Gauge,
+            "Upstreams with every server down (1=all down)",
+        );
+        for (labels, servers) in &upstreams {
+            // An upstream with no servers recorded is not an outage.
+            let down = !servers.is_empty() && servers.iter().all(|(_, stats)| stats.down);
+            fully_down.push(labels.clone(), u64::from(down));
+        }
+        families.push(fully_down);
 
-        output
+        // Share of the last minute's requests that failed, readable
+        // without rate().
+        let mut error_rate = self.family(
+            "upstream_error_rate",
+            Gauge,
+            "Share of upstream requests in the last 60s that got a 5xx or no response",
+        );
+        // The one series per server that says whether to worry, from
+        // server_up and error_rate.
+        let mut server_state = self.family(
+            "upstream_server_state",
+            Gauge,
+            "Upstream server state derived from down flag and error rate (always 1)",
+        );
+        let now = now_secs();
+        let degraded_rate = crate::upstream_degraded_error_rate();
+        for (labels, stats) in &servers {
+            error_rate.push(labels.clone(), stats.error_window.rate(now));
+            let state = stats.health_state(now, degraded_rate);
+            server_state.push(label_set(labels, &[("state", state)]), 1);
+        }
+        families.extend([error_rate, server_state]);
+
+        // HTTP status code metrics and response-time histogram.
+        families.extend(self.upstream_status_families(&servers));
+        families.push(self.upstream_response_histogram(&servers));
+        families
     }
 
-    /// `nginx_vts_upstream_responses_total{status="1xx"…"5xx"}` (class buckets).
-    #[allow(dead_code)] // Used in format_upstream_stats method
-    fn format_upstream_status_metrics(
+    /// `nginx_vts_upstream_responses_total{status="1xx"…"5xx"}` (class
+    /// buckets), the 206 / 304 detail and
+    /// `nginx_vts_upstream_no_response_total`.
+    fn upstream_status_families(
         &self,
-        output: &mut String,
-        upstream_zones: &HashMap<String, UpstreamZone>,
-    ) {
-        let prefix = &self.metric_prefix;
-        output.push_str(&format!(
-            "# HELP {prefix}upstream_responses_total Upstream responses by status code\n"
-        ));
-        output.push_str(&format!(
-            "# TYPE {prefix}upstream_responses_total counter\n"
-        ));
-        for (upstream_name, zone) in upstream_zones {
-            for (server_addr, stats) in &zone.servers {
-                for (class, value) in [
-                    ("1xx", stats.responses.status_1xx),
-                    ("2xx", stats.responses.status_2xx),
-                    ("3xx", stats.responses.status_3xx),
-                    ("4xx", stats.responses.status_4xx),
-                    ("5xx", stats.responses.status_5xx),
-                ] {
-                    output.push_str(&format!(
-                        "{prefix}upstream_responses_total{{upstream=\"{upstream_name}\",server=\"{server_addr}\",status=\"{class}\"}} {value}\n"
-                    ));
-                }
+        servers: &[(Vec<(String, String)>, &UpstreamServerStats)],
+    ) -> [Family; 3] {
+        let mut responses = self.family(
+            "upstream_responses_total",
+            MetricKind::Counter,
+            "Upstream responses by status code",
+        );
+        // 206 and 304 in a family of their own: they are also counted
+        // in their class, so sum(upstream_responses_total) stays exact.
+        let mut detail = self.family(
+            "upstream_responses_detail_total",
+            MetricKind::Counter,
+            "Upstream responses with selected status codes",
+        );
+        // Status 0: no response at all, so no class to count it under.
+        let mut no_response = self.family(
+            "upstream_no_response_total",
+            MetricKind::Counter,
+            "Upstream attempts that got no response",
+        );
+        for (labels, stats) in servers {
+            for (class, value) in [
+                ("1xx", stats.responses.status_1xx),
+                ("2xx", stats.responses.status_2xx),
+                ("3xx", stats.responses.status_3xx),
+                ("4xx", stats.responses.status_4xx),
+                ("5xx", stats.responses.status_5xx),
+            ] {
+                responses.push(label_set(labels, &[("status", class)]), value);
+            }
+            for (status, value) in [
+                ("206", stats.responses.status_206),
+                ("304", stats.responses.status_304),
+            ] {
+                detail.push(label_set(labels, &[("status", status)]), value);
             }
+            no_response.push(labels.clone(), stats.no_response);
         }
-        output.push('\n');
+        [responses, detail, no_response]
     }
 
     /// `nginx_vts_upstream_response_duration_seconds` classic
     /// histogram (`_bucket{le="..."}`, `_sum`, `_count`).  Compatible
     /// with `histogram_quantile()` for p50 / p90 / p99 panels.
-    #[allow(dead_code)] // Used in format_upstream_stats method
-    fn format_upstream_response_histogram(
+    fn upstream_response_histogram(
         &self,
-        output: &mut String,
-        upstream_zones: &HashMap<String, UpstreamZone>,
-    ) {
-        let prefix = &self.metric_prefix;
-        output.push_str(&format!(
-            "# HELP {prefix}upstream_response_duration_seconds Upstream response time distribution\n"
-        ));
-        output.push_str(&format!(
-            "# TYPE {prefix}upstream_response_duration_seconds histogram\n"
-        ));
-
-        for (upstream_name, zone) in upstream_zones {
-            for (server_addr, stats) in &zone.servers {
-                for (i, &bound_ms) in RESPONSE_TIME_BUCKET_BOUNDS_MS.iter().enumerate() {
-                    let bound_s = bound_ms as f64 / 1000.0;
-                    output.push_str(&format!(
-                        "{prefix}upstream_response_duration_seconds_bucket{{upstream=\"{upstream_name}\",server=\"{server_addr}\",le=\"{}\"}} {}\n",
-                        format_le_bound(bound_s),
-                        stats.response_buckets[i]
-                    ));
-                }
-                // +Inf bucket holds every sample, equal to _count.
-                output.push_str(&format!(
-                    "{prefix}upstream_response_duration_seconds_bucket{{upstream=\"{upstream_name}\",server=\"{server_addr}\",le=\"+Inf\"}} {}\n",
-                    stats.response_time_counter
-                ));
-                output.push_str(&format!(
-                    "{prefix}upstream_response_duration_seconds_sum{{upstream=\"{upstream_name}\",server=\"{server_addr}\"}} {:.6}\n",
-                    stats.response_time_total as f64 / 1000.0
-                ));
-                output.push_str(&format!(
-                    "{prefix}upstream_response_duration_seconds_count{{upstream=\"{upstream_name}\",server=\"{server_addr}\"}} {}\n",
-                    stats.response_time_counter
-                ));
+        servers: &[(Vec<(String, String)>, &UpstreamServerStats)],
+    ) -> Family {
+        let mut histogram = self.family(
+            "upstream_response_duration_seconds",
+            MetricKind::Histogram,
+            "Upstream response time distribution",
+        );
+        let bounds: Vec<String> = RESPONSE_TIME_BUCKET_BOUNDS_MS
+            .iter()
+            .map(|&bound_ms| format_le(bound_ms as f64 / 1000.0))
+            .chain([format_le(f64::INFINITY)])
+            .collect();
+        for (labels, stats) in servers {
+            // The last bound is +Inf, holding every sample (= _count).
+            let counts = stats.response_buckets[..RESPONSE_TIME_BUCKET_BOUNDS_MS.len()]
+                .iter()
+                .copied()
+                .chain([stats.response_time_counter]);
+            for (le, count) in bounds.iter().zip(counts) {
+                histogram.push_part("_bucket", label_set(labels, &[("le", le)]), count);
             }
+            histogram.push_part(
+                "_sum",
+                labels.clone(),
+                stats.response_time_total as f64 / 1000.0,
+            );
+            histogram.push_part("_count", labels.clone(), stats.response_time_counter);
         }
-        output.push('\n');
-    }
-}
-
-/// Format a histogram `le` bound (in seconds) as Prometheus expects
-/// — fixed-point with trailing zeros trimmed: `0.005`, `0.01`,
-/// `0.1`, `1`, `2.5`, `10`.  The rendering must be stable across
-/// scrapes so the time series doesn't fragment.
-fn format_le_bound(seconds: f64) -> String {
-    let formatted = format!("{seconds:.3}");
-    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
-    if trimmed.is_empty() {
-        "0".to_string()
-    } else {
-        trimmed.to_string()
+        histogram
     }
 }
 
@@ -229,13 +425,22 @@ mod tests {
     }
 
     #[test]
-    fn format_le_bound_trims_trailing_zeros() {
-        assert_eq!(format_le_bound(0.005), "0.005");
-        assert_eq!(format_le_bound(0.01), "0.01");
-        assert_eq!(format_le_bound(0.1), "0.1");
-        assert_eq!(format_le_bound(1.0), "1");
-        assert_eq!(format_le_bound(2.5), "2.5");
-        assert_eq!(format_le_bound(10.0), "10");
+    fn upstream_fully_down_only_when_every_server_is_down() {
+        let mut all_down = UpstreamZone::new("all_down");
+        for addr in ["10.0.1.1:80", "10.0.1.2:80"] {
+            all_down.get_or_create_server(addr).down = true;
+        }
+        let mut zones = HashMap::from([
+            ("mixed".to_string(), create_test_upstream_zone()),
+            ("all_down".to_string(), all_down),
+        ]);
+        zones.insert("empty".to_string(), UpstreamZone::new("empty"));
+
+        let out = PrometheusFormatter::new().format_upstream_stats(&zones);
+        assert!(out.contains("# TYPE nginx_vts_upstream_fully_down gauge"));
+        assert!(out.contains("nginx_vts_upstream_fully_down{upstream=\"all_down\"} 1\n"));
+        assert!(out.contains("nginx_vts_upstream_fully_down{upstream=\"mixed\"} 0\n"));
+        assert!(out.contains("nginx_vts_upstream_fully_down{upstream=\"empty\"} 0\n"));
     }
 
     #[test]
@@ -277,6 +482,108 @@ mod tests {
         assert!(out.contains("nginx_vts_upstream_response_duration_seconds_count{upstream=\"test_backend\",server=\"10.0.0.1:80\"} 100"));
     }
 
+    #[cfg(feature = "latency-percentiles")]
+    #[test]
+    fn upstream_stats_render_latency_quantiles() {
+        let mut zone = UpstreamZone::new("test_backend");
+        let server = zone.get_or_create_server("10.0.0.1:80");
+        for _ in 0..98 {
+            server.update_timing(0, 20);
+        }
+        server.update_timing(0, 800);
+        server.update_timing(0, 800);
+        let mut zones = HashMap::new();
+        zones.insert("test_backend".to_string(), zone);
+        let out = PrometheusFormatter::new().format_upstream_stats(&zones);
+
+        assert!(out.contains("nginx_vts_upstream_response_quantile_seconds{upstream=\"test_backend\",server=\"10.0.0.1:80\",quantile=\"0.5\"} 0.020000"));
+        assert!(out.contains("nginx_vts_upstream_response_quantile_seconds{upstream=\"test_backend\",server=\"10.0.0.1:80\",quantile=\"0.9\"} 0.020000"));
+        // 800 ms sits in a 4 ms-wide bucket; report its upper edge.
+        assert!(out.contains("nginx_vts_upstream_response_quantile_seconds{upstream=\"test_backend\",server=\"10.0.0.1:80\",quantile=\"0.99\"} 0.803000"));
+    }
+
+    #[test]
+    fn upstream_error_rate_covers_the_window_not_all_time() {
+        let mut zone = UpstreamZone::new("backend");
+        let server = zone.get_or_create_server("10.0.0.1:80");
+        // Old successes outside the window, then a recent burst of
+        // errors with a single success.
+        let now = now_secs();
+        for _ in 0..100 {
+            server.error_window.record(now - 600, false);
+        }
+        for status in [500, 502, 503, 200] {
+            server.update_response_status(status);
+        }
+        zone.get_or_create_server("10.0.0.2:80");
+        let mut zones = HashMap::new();
+        zones.insert("backend".to_string(), zone);
+        let out = PrometheusFormatter::new().format_upstream_stats(&zones);
+
+        assert!(out.contains("# TYPE nginx_vts_upstream_error_rate gauge"));
+        assert!(out.contains(
+            "nginx_vts_upstream_error_rate{upstream=\"backend\",server=\"10.0.0.1:80\"} 0.750000"
+        ));
+        // No traffic reads as 0, not NaN.
+        assert!(out.contains(
+            "nginx_vts_upstream_error_rate{upstream=\"backend\",server=\"10.0.0.2:80\"} 0.000000"
+        ));
+    }
+
+    #[test]
+    fn upstream_server_state_flips_to_degraded_on_errors() {
+        let state = |zone: &UpstreamZone| {
+            let mut zones = HashMap::new();
+            zones.insert("backend".to_string(), zone.clone());
+            let out = PrometheusFormatter::new().format_upstream_stats(&zones);
+            out.lines()
+                .find(|l| l.starts_with("nginx_vts_upstream_server_state{"))
+                .unwrap()
+                .to_string()
+        };
+        let mut zone = UpstreamZone::new("backend");
+        let server = zone.get_or_create_server("10.0.0.1:80");
+        for _ in 0..10 {
+            server.update_response_status(200);
+        }
+        assert_eq!(
+            state(&zone),
+            "nginx_vts_upstream_server_state{upstream=\"backend\",server=\"10.0.0.1:80\",state=\"healthy\"} 1"
+        );
+
+        // 1 error in 11 is 9%, still under the default 10%.
+        let addr = "10.0.0.1:80";
+        zone.get_or_create_server(addr).update_response_status(502);
+        assert!(state(&zone).contains("state=\"healthy\""));
+        zone.get_or_create_server(addr).update_response_status(0);
+        assert!(state(&zone).contains("state=\"degraded\""));
+
+        // Marked down wins over the error rate.
+        zone.get_or_create_server(addr).down = true;
+        assert!(state(&zone).contains("state=\"down\""));
+    }
+
+    #[test]
+    fn upstream_queue_stats_render_gauge_and_counter() {
+        let f = PrometheusFormatter::new();
+        assert!(f.format_upstream_queue_stats(&HashMap::new()).is_empty());
+
+        let mut queues = HashMap::new();
+        queues.insert(
+            "backend".to_string(),
+            UpstreamQueueStats {
+                length: 4,
+                waits_total: 17,
+            },
+        );
+        let out = f.format_upstream_queue_stats(&queues);
+        assert!(out.contains("# TYPE nginx_vts_upstream_queue_length gauge"));
+        assert!(out.contains("nginx_vts_upstream_queue_length{upstream=\"backend\"} 4\n"));
+        assert!(out.contains("# TYPE nginx_vts_upstream_queue_waits_total counter"));
+        assert!(out.contains("nginx_vts_upstream_queue_waits_total{upstream=\"backend\"} 17\n"));
+        assert_eq!(crate::prometheus::validate_prometheus(&out), Ok(()));
+    }
+
     #[test]
     fn custom_prefix_replaces_default_throughout() {
         let f = PrometheusFormatter::with_prefix("custom_vts_");
@@ -0,0 +1,332 @@
+//! Structural checker for the Prometheus text exposition we emit.
+//!
+//! Test-only.  [`validate_prometheus`] is run over the rendered
+//! `/status` body by the integration tests so a new metric family that
+//! breaks the format (bad label escaping, a second `# TYPE`, samples
+//! with drifting label sets, negative counters) fails CI instead of a
+//! scrape.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Check `text` against the subset of the exposition format we rely
+/// on.  Returns every problem found, each prefixed with its 1-based
+/// line number.
+///
+/// Rules:
+/// - each sample line is `name{label="value",...} value` (labels
+///   optional), with a valid metric name and properly escaped values;
+/// - every family has exactly one `# HELP` and one `# TYPE`, and the
+///   `TYPE` precedes its samples;
+/// - all samples of one metric name carry the same label names, and no
+///   series (name + labels) appears twice;
+/// - counter samples (and histogram/summary `_bucket` / `_sum` /
+///   `_count`) are non-negative.
+pub fn validate_prometheus(text: &str) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    let mut help: HashMap<String, usize> = HashMap::new();
+    let mut types: HashMap<String, String> = HashMap::new();
+    let mut type_counts: HashMap<String, usize> = HashMap::new();
+    let mut sampled: BTreeSet<String> = BTreeSet::new();
+    let mut label_names: HashMap<String, Vec<String>> = HashMap::new();
+    let mut series: HashSet<String> = HashSet::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let lineno = index + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            match rest.split_once(' ') {
+                Some((name, _)) if is_metric_name(name) => {
+                    *help.entry(name.to_string()).or_default() += 1;
+                }
+                _ => errors.push(format!("line {lineno}: malformed HELP: {line}")),
+            }
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("# TYPE ") {
+            match rest.split_once(' ') {
+                Some((name, kind))
+                    if is_metric_name(name)
+                        && matches!(
+                            kind,
+                            "counter" | "gauge" | "histogram" | "summary" | "untyped"
+                        ) =>
+                {
+                    *type_counts.entry(name.to_string()).or_default() += 1;
+                    types.insert(name.to_string(), kind.to_string());
+                }
+                _ => errors.push(format!("line {lineno}: malformed TYPE: {line}")),
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            // Free-form comment (e.g. the `# nginx-vts-rust` banner).
+            continue;
+        }
+
+        let sample = match parse_sample(line) {
+            Ok(sample) => sample,
+            Err(e) => {
+                errors.push(format!("line {lineno}: {e}: {line}"));
+                continue;
+            }
+        };
+
+        let Some((family, kind)) = family_of(&sample.name, &types) else {
+            errors.push(format!(
+                "line {lineno}: sample `{}` has no preceding TYPE",
+                sample.name
+            ));
+            continue;
+        };
+        sampled.insert(family.clone());
+
+        let names: Vec<String> = sample.labels.iter().map(|(k, _)| k.clone()).collect();
+        let mut unique = names.clone();
+        unique.sort();
+        unique.dedup();
+        if unique.len() != names.len() {
+            errors.push(format!("line {lineno}: duplicate label name"));
+        }
+        match label_names.get(&sample.name) {
+            Some(expected) if *expected != unique => errors.push(format!(
+                "line {lineno}: `{}` labels {unique:?} differ from earlier {expected:?}",
+                sample.name
+            )),
+            Some(_) => {}
+            None => {
+                label_names.insert(sample.name.clone(), unique);
+            }
+        }
+
+        let mut key_labels = sample.labels.clone();
+        key_labels.sort();
+        if !series.insert(format!("{}{key_labels:?}", sample.name)) {
+            errors.push(format!("line {lineno}: duplicate series `{}`", sample.name));
+        }
+
+        let must_be_non_negative = kind == "counter" || sample.name != family;
+        if must_be_non_negative && (sample.value.is_nan() || sample.value < 0.0) {
+            errors.push(format!(
+                "line {lineno}: `{}` must be non-negative, got {}",
+                sample.name, sample.value
+            ));
+        }
+    }
+
+    for family in &sampled {
+        match help.get(family).copied().unwrap_or(0) {
+            1 => {}
+            0 => errors.push(format!("family `{family}` has no HELP")),
+            n => errors.push(format!("family `{family}` has {n} HELP lines")),
+        }
+    }
+    for (family, &count) in &type_counts {
+        if count > 1 {
+            errors.push(format!("family `{family}` has {count} TYPE lines"));
+        }
+    }
+    for (family, &count) in &help {
+        if count > 1 && !sampled.contains(family) {
+            errors.push(format!("family `{family}` has {count} HELP lines"));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+struct Sample {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+/// Resolve the family a sample belongs to, accounting for the
+/// `_bucket` / `_sum` / `_count` series of histograms and summaries.
+fn family_of(name: &str, types: &HashMap<String, String>) -> Option<(String, String)> {
+    if let Some(kind) = types.get(name) {
+        return Some((name.to_string(), kind.clone()));
+    }
+    for suffix in ["_bucket", "_sum", "_count"] {
+        if let Some(base) = name.strip_suffix(suffix) {
+            if let Some(kind) = types.get(base) {
+                if kind == "histogram" || kind == "summary" {
+                    return Some((base.to_string(), kind.clone()));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_sample(line: &str) -> Result<Sample, String> {
+    let name_end = line.find(['{', ' ']).ok_or("missing value")?;
+    let name = &line[..name_end];
+    if !is_metric_name(name) {
+        return Err(format!("invalid metric name `{name}`"));
+    }
+
+    let mut rest = &line[name_end..];
+    let mut labels = Vec::new();
+    if let Some(after_brace) = rest.strip_prefix('{') {
+        rest = after_brace;
+        loop {
+            if let Some(after) = rest.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let eq = rest.find('=').ok_or("unterminated label set")?;
+            let label = &rest[..eq];
+            if !is_label_name(label) {
+                return Err(format!("invalid label name `{label}`"));
+            }
+            let (value, after) = parse_label_value(&rest[eq + 1..])?;
+            labels.push((label.to_string(), value));
+            rest = after;
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with('}') {
+                return Err("expected `,` or `}` after label value".to_string());
+            }
+        }
+    }
+
+    let mut fields = rest
+        .strip_prefix(' ')
+        .ok_or("expected a space before the value")?
+        .split(' ');
+    let value = match fields.next().unwrap_or("") {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        "NaN" => f64::NAN,
+        v => v
+            .parse::<f64>()
+            .map_err(|_| format!("invalid sample value `{v}`"))?,
+    };
+    if let Some(ts) = fields.next() {
+        ts.parse::<i64>()
+            .map_err(|_| format!("invalid timestamp `{ts}`"))?;
+    }
+    if fields.next().is_some() {
+        return Err("trailing fields after the value".to_string());
+    }
+
+    Ok(Sample {
+        name: name.to_string(),
+        labels,
+        value,
+    })
+}
+
+/// Parse a double-quoted label value with `\\`, `\"` and `\n` escapes,
+/// returning the unescaped value and the remaining input.
+fn parse_label_value(input: &str) -> Result<(String, &str), String> {
+    let body = input
+        .strip_prefix('"')
+        .ok_or("label value must be quoted")?;
+    let mut value = String::new();
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &body[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, '"')) => value.push('"'),
+                Some((_, 'n')) => value.push('\n'),
+                _ => return Err("invalid escape in label value".to_string()),
+            },
+            '\n' => return Err("raw newline in label value".to_string()),
+            c => value.push(c),
+        }
+    }
+    Err("unterminated label value".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = "\
+# nginx-vts-rust banner comment
+# HELP demo_requests_total Requests
+# TYPE demo_requests_total counter
+demo_requests_total{zone=\"a\"} 3
+demo_requests_total{zone=\"b \\\"quoted\\\"\"} 0
+
+# HELP demo_latency Latency
+# TYPE demo_latency histogram
+demo_latency_bucket{le=\"0.1\"} 1
+demo_latency_bucket{le=\"+Inf\"} 2
+demo_latency_sum 0.3
+demo_latency_count 2
+";
+
+    #[test]
+    fn accepts_well_formed_output() {
+        assert_eq!(validate_prometheus(VALID), Ok(()));
+    }
+
+    #[test]
+    fn rejects_each_class_of_problem() {
+        let cases = [
+            (
+                "duplicate TYPE",
+                format!("{VALID}# TYPE demo_requests_total counter\n"),
+            ),
+            ("missing HELP", "# TYPE x gauge\nx 1\n".to_string()),
+            ("no TYPE", "# HELP y Y\ny 1\n".to_string()),
+            (
+                "label drift",
+                "# HELP z Z\n# TYPE z gauge\nz{a=\"1\"} 1\nz{b=\"1\"} 1\n".to_string(),
+            ),
+            (
+                "negative counter",
+                "# HELP c C\n# TYPE c counter\nc -1\n".to_string(),
+            ),
+            (
+                "bad escape",
+                "# HELP e E\n# TYPE e gauge\ne{a=\"\\x\"} 1\n".to_string(),
+            ),
+            (
+                "unquoted value",
+                "# HELP u U\n# TYPE u gauge\nu{a=1} 1\n".to_string(),
+            ),
+            (
+                "duplicate series",
+                "# HELP d D\n# TYPE d gauge\nd{a=\"1\"} 1\nd{a=\"1\"} 2\n".to_string(),
+            ),
+            (
+                "non-numeric value",
+                "# HELP n N\n# TYPE n gauge\nn{a=\"1\"} lots\n".to_string(),
+            ),
+        ];
+        for (what, text) in cases {
+            assert!(validate_prometheus(&text).is_err(), "{what} not caught");
+        }
+    }
+
+    #[test]
+    fn errors_carry_line_numbers() {
+        let errors = validate_prometheus("# HELP c C\n# TYPE c counter\nc -1\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("line 3:"), "{errors:?}");
+    }
+}
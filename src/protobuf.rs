@@ -0,0 +1,456 @@
+//! Prometheus protobuf exposition of the status page, served when a
+//! scrape negotiates it with
+//! `Accept: application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited`.
+//!
+//! Enabled with the `protobuf` cargo feature.  Built on
+//! [`crate::sample::collect_status_samples`] like `?format=influx`:
+//! samples are grouped into one `MetricFamily` per name, histogram `_bucket` / `_sum` / `_count` samples are folded
+//! back into one `Histogram` per label set, and each family is written
+//! with a varint length prefix (the "delimited" encoding).
+//!
+//! Hand-rolled for the same reason as `remote_write.rs`: the subset of
+//! `metrics.proto` needed here is small and fixed.
+//!
+//! ```text
+//! MetricFamily { string name = 1; string help = 2; MetricType type = 3; repeated Metric metric = 4; }
+//! Metric       { repeated LabelPair label = 1; Gauge gauge = 2; Counter counter = 3; Histogram histogram = 7; }
+//! LabelPair    { string name = 1; string value = 2; }
+//! Gauge / Counter { double value = 1; }
+//! Histogram    { uint64 sample_count = 1; double sample_sum = 2; repeated Bucket bucket = 3; }
+//! Bucket       { uint64 cumulative_count = 1; double upper_bound = 2; }
+//! ```
+
+use std::collections::HashMap;
+
+use crate::sample::{MetricKind, Sample};
+
+/// Metric-name prefix, stripped to look up `# HELP` texts.
+const PREFIX: &str = "nginx_vts_";
+
+/// `MetricType` enum values.
+const TYPE_COUNTER: u64 = 0;
+const TYPE_GAUGE: u64 = 1;
+const TYPE_HISTOGRAM: u64 = 4;
+
+/// Render the status page as delimited `MetricFamily` messages.
+pub fn generate_vts_status_protobuf() -> Vec<u8> {
+    encode_metric_families(&crate::sample::collect_status_samples())
+}
+
+/// Whether an `Accept` header value prefers this format to the text
+/// page: a media range names `application/vnd.google.protobuf` (with
+/// `proto=io.prometheus.client.MetricFamily` and `encoding=delimited`
+/// when those parameters are given) at a non-zero `q` no lower than the
+/// best range covering `text/plain`.  `q=0` means "not acceptable".
+pub fn accepts_protobuf(accept: &str) -> bool {
+    let mut protobuf = 0.0_f64;
+    let mut text = 0.0_f64;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media = params.next().unwrap_or("").trim();
+        let mut q = 1.0_f64;
+        let mut compatible = true;
+        for param in params {
+            let Some((key, value)) = param.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"');
+            match key.trim().to_ascii_lowercase().as_str() {
+                // A malformed weight is treated as "not acceptable".
+                "q" => q = value.parse().unwrap_or(0.0),
+                "proto" => compatible &= value == "io.prometheus.client.MetricFamily",
+                "encoding" => compatible &= value == "delimited",
+                _ => {}
+            }
+        }
+        let q = q.clamp(0.0, 1.0);
+        if media.eq_ignore_ascii_case("application/vnd.google.protobuf") {
+            if compatible {
+                protobuf = protobuf.max(q);
+            }
+        } else if media.eq_ignore_ascii_case("text/plain")
+            || media.eq_ignore_ascii_case("text/*")
+            || media == "*/*"
+        {
+            text = text.max(q);
+        }
+    }
+    protobuf > 0.0 && protobuf >= text
+}
+
+/// One metric's value: a plain sample or a reassembled histogram.
+enum Value {
+    Scalar(f64),
+    Histogram {
+        count: u64,
+        sum: f64,
+        buckets: Vec<(f64, u64)>,
+    },
+}
+
+struct Metric {
+    labels: Vec<(String, String)>,
+    value: Value,
+}
+
+struct Family {
+    name: String,
+    kind: MetricKind,
+    metrics: Vec<Metric>,
+}
+
+/// Group `samples` into families, keeping the order in which each
+/// family, and each label set within it, first appears.
+fn group(samples: &[Sample]) -> Vec<Family> {
+    let mut families: Vec<Family> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+
+    for sample in samples {
+        let (name, part) = match sample.kind {
+            MetricKind::Histogram => ["_bucket", "_sum", "_count"]
+                .into_iter()
+                .find_map(|suffix| sample.name.strip_suffix(suffix).map(|n| (n, suffix)))
+                .unwrap_or((sample.name.as_str(), "")),
+            _ => (sample.name.as_str(), ""),
+        };
+        let i = *index.entry(name.to_string()).or_insert_with(|| {
+            families.push(Family {
+                name: name.to_string(),
+                kind: sample.kind,
+                metrics: Vec::new(),
+            });
+            families.len() - 1
+        });
+        let family = &mut families[i];
+
+        if sample.kind != MetricKind::Histogram {
+            family.metrics.push(Metric {
+                labels: sample.labels.clone(),
+                value: Value::Scalar(sample.value),
+            });
+            continue;
+        }
+
+        let mut labels = sample.labels.clone();
+        let le = labels
+            .iter()
+            .position(|(k, _)| k == "le")
+            .map(|i| labels.remove(i).1);
+        let metric = match family.metrics.iter().position(|m| m.labels == labels) {
+            Some(i) => &mut family.metrics[i],
+            None => {
+                family.metrics.push(Metric {
+                    labels,
+                    value: Value::Histogram {
+                        count: 0,
+                        sum: 0.0,
+                        buckets: Vec::new(),
+                    },
+                });
+                family.metrics.last_mut().unwrap()
+            }
+        };
+        let Value::Histogram {
+            count,
+            sum,
+            buckets,
+        } = &mut metric.value
+        else {
+            continue;
+        };
+        match part {
+            "_sum" => *sum = sample.value,
+            "_count" => *count = sample.value as u64,
+            // `+Inf` is implied by `sample_count`.
+            _ => {
+                if let Some(bound) = le.and_then(|le| le.parse::<f64>().ok()) {
+                    if bound.is_finite() {
+                        buckets.push((bound, sample.value as u64));
+                    }
+                }
+            }
+        }
+    }
+    families
+}
+
+/// Encode `samples` as length-delimited `MetricFamily` messages.
+pub fn encode_metric_families(samples: &[Sample]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for family in group(samples) {
+        let mut msg = Vec::new();
+        put_bytes_field(&mut msg, 1, family.name.as_bytes());
+        let help = family
+            .name
+            .strip_prefix(PREFIX)
+            .and_then(crate::prometheus::family_help);
+        if let Some(help) = help {
+            put_bytes_field(&mut msg, 2, help.as_bytes());
+        }
+        let (kind, value_field) = match family.kind {
+            MetricKind::Counter => (TYPE_COUNTER, 3),
+            MetricKind::Gauge => (TYPE_GAUGE, 2),
+            MetricKind::Histogram => (TYPE_HISTOGRAM, 7),
+        };
+        put_key(&mut msg, 3, WIRE_VARINT);
+        put_varint(&mut msg, kind);
+
+        for metric in &family.metrics {
+            let mut m = Vec::new();
+            for (name, value) in &metric.labels {
+                let mut pair = Vec::new();
+                put_bytes_field(&mut pair, 1, name.as_bytes());
+                put_bytes_field(&mut pair, 2, value.as_bytes());
+                put_bytes_field(&mut m, 1, &pair);
+            }
+            let mut value = Vec::new();
+            match &metric.value {
+                Value::Scalar(v) => put_double(&mut value, 1, *v),
+                Value::Histogram {
+                    count,
+                    sum,
+                    buckets,
+                } => {
+                    put_key(&mut value, 1, WIRE_VARINT);
+                    put_varint(&mut value, *count);
+                    put_double(&mut value, 2, *sum);
+                    for &(bound, cumulative) in buckets {
+                        let mut bucket = Vec::new();
+                        put_key(&mut bucket, 1, WIRE_VARINT);
+                        put_varint(&mut bucket, cumulative);
+                        put_double(&mut bucket, 2, bound);
+                        put_bytes_field(&mut value, 3, &bucket);
+                    }
+                }
+            }
+            put_bytes_field(&mut m, value_field, &value);
+            put_bytes_field(&mut msg, 4, &m);
+        }
+
+        put_varint(&mut out, msg.len() as u64);
+        out.extend_from_slice(&msg);
+    }
+    out
+}
+
+// --- protobuf ---
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn put_key(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(out, ((field as u64) << 3) | wire_type as u64);
+}
+
+fn put_bytes_field(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_key(out, field, WIRE_LEN);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn put_double(out: &mut Vec<u8>, field: u32, v: f64) {
+    put_key(out, field, WIRE_FIXED64);
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vts_node::VtsStatsManager;
+
+    fn get_varint(buf: &[u8], pos: &mut usize) -> u64 {
+        let mut v = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = buf[*pos];
+            *pos += 1;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return v;
+            }
+            shift += 7;
+        }
+    }
+
+    /// `(field, payload)` pairs of a message; varint payloads are the
+    /// value's little-endian bytes.
+    fn fields(buf: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut pos = 0;
+        let mut out = Vec::new();
+        while pos < buf.len() {
+            let key = get_varint(buf, &mut pos);
+            let payload = match (key & 0b111) as u8 {
+                WIRE_VARINT => get_varint(buf, &mut pos).to_le_bytes().to_vec(),
+                WIRE_FIXED64 => {
+                    pos += 8;
+                    buf[pos - 8..pos].to_vec()
+                }
+                WIRE_LEN => {
+                    let n = get_varint(buf, &mut pos) as usize;
+                    pos += n;
+                    buf[pos - n..pos].to_vec()
+                }
+                other => panic!("unexpected wire type {other}"),
+            };
+            out.push(((key >> 3) as u32, payload));
+        }
+        out
+    }
+
+    fn string(payload: &[u8]) -> String {
+        String::from_utf8(payload.to_vec()).unwrap()
+    }
+
+    fn double(payload: &[u8]) -> f64 {
+        f64::from_le_bytes(payload.try_into().unwrap())
+    }
+
+    fn varint(payload: &[u8]) -> u64 {
+        u64::from_le_bytes(payload.try_into().unwrap())
+    }
+
+    /// A decoded `MetricFamily`: name, help, type and, per metric,
+    /// its labels and `(value field, value message)`.
+    type Decoded = (
+        String,
+        String,
+        u64,
+        Vec<(Vec<(String, String)>, u32, Vec<u8>)>,
+    );
+
+    /// Split a delimited stream and decode each `MetricFamily`.
+    fn decode(buf: &[u8]) -> Vec<Decoded> {
+        let mut pos = 0;
+        let mut out = Vec::new();
+        while pos < buf.len() {
+            let n = get_varint(buf, &mut pos) as usize;
+            let msg = &buf[pos..pos + n];
+            pos += n;
+            let (mut name, mut help, mut kind, mut metrics) =
+                (String::new(), String::new(), 0, Vec::new());
+            for (field, payload) in fields(msg) {
+                match field {
+                    1 => name = string(&payload),
+                    2 => help = string(&payload),
+                    3 => kind = varint(&payload),
+                    4 => {
+                        let mut labels = Vec::new();
+                        let mut value = None;
+                        for (field, body) in fields(&payload) {
+                            if field == 1 {
+                                let pair = fields(&body);
+                                labels.push((string(&pair[0].1), string(&pair[1].1)));
+                            } else {
+                                value = Some((field, body));
+                            }
+                        }
+                        let (field, body) = value.expect("every metric carries a value");
+                        metrics.push((labels, field, body));
+                    }
+                    other => panic!("unexpected MetricFamily field {other}"),
+                }
+            }
+            out.push((name, help, kind, metrics));
+        }
+        out
+    }
+
+    #[test]
+    fn encodes_delimited_metric_families() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut manager = VtsStatsManager::new();
+        manager.update_server_stats("example.com", 200, 10, 20, 5);
+        manager.update_server_stats("example.com", 503, 1, 2, 5);
+        manager.update_upstream_stats("backend", "10.0.0.1:80", 50, 40, 100, 200, 200);
+
+        let families = decode(&encode_metric_families(&manager.collect_samples()));
+        let family = |name: &str| {
+            families
+                .iter()
+                .find(|f| f.0 == format!("nginx_vts_{name}"))
+                .unwrap_or_else(|| panic!("missing family {name}"))
+        };
+
+        // One family per name, never repeated.
+        let mut names: Vec<_> = families.iter().map(|f| &f.0).collect();
+        let total = names.len();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), total);
+
+        let requests = family("server_requests_total");
+        assert_eq!(requests.1, "Total number of requests");
+        assert_eq!(requests.2, TYPE_COUNTER);
+        let (labels, field, body) = &requests.3[0];
+        assert_eq!(labels, &[("zone".to_string(), "example.com".to_string())]);
+        assert_eq!(*field, 3);
+        assert_eq!(double(&fields(body)[0].1), 2.0);
+
+        let responses = family("server_responses_total");
+        assert_eq!(responses.3.len(), 5);
+
+        let up = family("upstream_server_up");
+        assert_eq!((up.2, up.3[0].1), (TYPE_GAUGE, 2));
+
+        // The histogram comes back as one metric without `le`.
+        let histogram = family("upstream_response_duration_seconds");
+        assert_eq!(histogram.2, TYPE_HISTOGRAM);
+        assert_eq!(histogram.3.len(), 1);
+        let (labels, field, body) = &histogram.3[0];
+        assert!(labels.iter().all(|(k, _)| k != "le"));
+        assert_eq!(*field, 7);
+        let h = fields(body);
+        assert_eq!(varint(&h[0].1), 1);
+        assert_eq!(double(&h[1].1), 0.04);
+        let buckets: Vec<_> = h[2..]
+            .iter()
+            .map(|(_, b)| {
+                let b = fields(b);
+                (double(&b[1].1), varint(&b[0].1))
+            })
+            .collect();
+        assert_eq!(buckets.len(), 11);
+        assert_eq!(buckets[2], (0.025, 0));
+        assert_eq!(buckets[3], (0.05, 1));
+    }
+
+    #[test]
+    fn empty_input_encodes_nothing() {
+        assert!(encode_metric_families(&[]).is_empty());
+    }
+
+    #[test]
+    fn accept_header_is_negotiated_by_q_value() {
+        let prometheus = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3,*/*;q=0.1";
+        assert!(accepts_protobuf(prometheus));
+        assert!(accepts_protobuf("Application/Vnd.Google.Protobuf"));
+
+        // Listed but refused, or ranked below the text page.
+        assert!(!accepts_protobuf("application/vnd.google.protobuf;q=0"));
+        assert!(!accepts_protobuf(
+            "text/plain;version=0.0.4,application/vnd.google.protobuf;q=0.5"
+        ));
+        assert!(!accepts_protobuf("application/vnd.google.protobuf;q=oops"));
+        // Another message type or encoding.
+        assert!(!accepts_protobuf(
+            "application/vnd.google.protobuf;proto=io.prometheus.client.Other"
+        ));
+        assert!(!accepts_protobuf(
+            "application/vnd.google.protobuf;encoding=text"
+        ));
+        // A wildcard alone keeps the text page.
+        assert!(!accepts_protobuf("*/*"));
+        assert!(!accepts_protobuf(""));
+    }
+}
@@ -0,0 +1,652 @@
+//! Prometheus remote_write export of a [`VtsSnapshot`].
+//!
+//! Enabled with the `remote-write` cargo feature.  The payload is the
+//! snappy-compressed (block format) protobuf `prometheus.WriteRequest`
+//! that Prometheus, Mimir, Cortex and friends accept on their
+//! `/api/v1/write` endpoint, so counters can be pushed without running
+//! an exporter.
+//!
+//! Like the snapshot wire format, both encodings are hand-rolled: the
+//! message shape is tiny and fixed, and pulling in `prost` + `snap`
+//! for it would dwarf the module.
+//!
+//! ```text
+//! WriteRequest { repeated TimeSeries timeseries = 1; }
+//! TimeSeries   { repeated Label labels = 1; repeated Sample samples = 2; }
+//! Label        { string name = 1; string value = 2; }
+//! Sample       { double value = 1; int64 timestamp = 2; }
+//! ```
+//!
+//! Series use the same names and labels as the `/status` text output.
+
+use crate::snapshot::VtsSnapshot;
+
+/// Metric-name prefix, matching [`crate::prometheus::PrometheusFormatter::new`].
+const PREFIX: &str = "nginx_vts_";
+
+/// One remote_write series: sorted labels (including `__name__`) and a
+/// single sample value.
+#[derive(Debug, Clone, PartialEq)]
+struct Series {
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+impl Series {
+    fn new(name: &str, labels: &[(&str, &str)], value: u64) -> Self {
+        let mut all: Vec<(String, String)> = Vec::with_capacity(labels.len() + 1);
+        all.push(("__name__".to_string(), format!("{PREFIX}{name}")));
+        all.extend(labels.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+        // Remote-write receivers require labels sorted by name.
+        all.sort();
+        Self {
+            labels: all,
+            value: value as f64,
+        }
+    }
+}
+
+impl VtsSnapshot {
+    /// Encode the snapshot as a snappy-compressed remote_write
+    /// `WriteRequest`, stamping every sample with `timestamp_ms`
+    /// (milliseconds since the Unix epoch).
+    #[allow(dead_code)] // The feature's API: the module itself never pushes.
+    pub fn to_remote_write(&self, timestamp_ms: i64) -> Vec<u8> {
+        let mut request = Vec::new();
+        for series in self.remote_write_series() {
+            let mut ts = Vec::new();
+            for (name, value) in &series.labels {
+                let mut label = Vec::new();
+                put_bytes_field(&mut label, 1, name.as_bytes());
+                put_bytes_field(&mut label, 2, value.as_bytes());
+                put_bytes_field(&mut ts, 1, &label);
+            }
+            let mut sample = Vec::new();
+            put_key(&mut sample, 1, WIRE_FIXED64);
+            sample.extend_from_slice(&series.value.to_le_bytes());
+            put_key(&mut sample, 2, WIRE_VARINT);
+            put_varint(&mut sample, timestamp_ms as u64);
+            put_bytes_field(&mut ts, 2, &sample);
+
+            put_bytes_field(&mut request, 1, &ts);
+        }
+        snappy_compress(&request)
+    }
+
+    /// Flatten the snapshot into one series per exported sample.
+    fn remote_write_series(&self) -> Vec<Series> {
+        let mut out = Vec::new();
+
+        let c = &self.connections;
+        for (state, value) in [
+            ("active", c.active),
+            ("reading", c.reading),
+            ("writing", c.writing),
+            ("waiting", c.waiting),
+        ] {
+            out.push(Series::new("connections", &[("state", state)], value));
+        }
+        for (state, value) in [("accepted", c.accepted), ("handled", c.handled)] {
+            out.push(Series::new("connections_total", &[("state", state)], value));
+        }
+
+        for (zone, s) in &self.servers {
+            let zone = zone.as_str();
+            out.push(Series::new(
+                "server_requests_total",
+                &[("zone", zone)],
+                s.requests,
+            ));
+            for (direction, value) in [("in", s.bytes_in), ("out", s.bytes_out)] {
+                out.push(Series::new(
+                    "server_bytes_total",
+                    &[("zone", zone), ("direction", direction)],
+                    value,
+                ));
+            }
+            out.push(Series::new(
+                "server_response_header_bytes_total",
+                &[("zone", zone)],
+                s.header_bytes_out,
+            ));
+            out.push(Series::new(
+                "server_response_body_bytes_total",
+                &[("zone", zone)],
+                s.bytes_out.saturating_sub(s.header_bytes_out),
+            ));
+            out.push(Series::new(
+                "server_goodput_bytes_total",
+                &[("zone", zone)],
+                s.goodput_bytes_out,
+            ));
+            for (class, value) in [
+                ("1xx", s.status_1xx),
+                ("2xx", s.status_2xx),
+                ("3xx", s.status_3xx),
+                ("4xx", s.status_4xx),
+                ("5xx", s.status_5xx),
+            ] {
+                out.push(Series::new(
+                    "server_responses_total",
+                    &[("zone", zone), ("status", class)],
+                    value,
+                ));
+            }
+            for (status, value) in [("206", s.status_206), ("304", s.status_304)] {
+                out.push(Series::new(
+                    "server_responses_detail_total",
+                    &[("zone", zone), ("status", status)],
+                    value,
+                ));
+            }
+            out.push(Series::new(
+                "server_rate_limited_total",
+                &[("zone", zone)],
+                s.rate_limited,
+            ));
+            out.push(Series::new(
+                "server_subrequests_total",
+                &[("zone", zone)],
+                s.subrequests,
+            ));
+            for (reason, value) in [
+                ("timeout", s.client_body_timeouts),
+                ("malformed", s.client_body_malformed),
+            ] {
+                out.push(Series::new(
+                    "server_client_body_errors_total",
+                    &[("zone", zone), ("reason", reason)],
+                    value,
+                ));
+            }
+            out.push(Series::new(
+                "server_upgrades_total",
+                &[("zone", zone)],
+                s.upgrades,
+            ));
+        }
+
+        for ((upstream, server), u) in &self.upstreams {
+            let base = [("upstream", upstream.as_str()), ("server", server.as_str())];
+            out.push(Series::new(
+                "upstream_requests_total",
+                &base,
+                u.request_counter,
+            ));
+            for (direction, value) in [("in", u.in_bytes), ("out", u.out_bytes)] {
+                out.push(Series::new(
+                    "upstream_bytes_total",
+                    &[base[0], base[1], ("direction", direction)],
+                    value,
+                ));
+            }
+            for (class, value) in [
+                ("1xx", u.status_1xx),
+                ("2xx", u.status_2xx),
+                ("3xx", u.status_3xx),
+                ("4xx", u.status_4xx),
+                ("5xx", u.status_5xx),
+            ] {
+                out.push(Series::new(
+                    "upstream_responses_total",
+                    &[base[0], base[1], ("status", class)],
+                    value,
+                ));
+            }
+            for (status, value) in [("206", u.status_206), ("304", u.status_304)] {
+                out.push(Series::new(
+                    "upstream_responses_detail_total",
+                    &[base[0], base[1], ("status", status)],
+                    value,
+                ));
+            }
+            out.push(Series::new(
+                "upstream_no_response_total",
+                &base,
+                u.no_response,
+            ));
+        }
+
+        for (zone, cache) in &self.caches {
+            let zone = zone.as_str();
+            for (status, value) in [
+                ("hit", cache.hit),
+                ("miss", cache.miss),
+                ("bypass", cache.bypass),
+                ("expired", cache.expired),
+                ("stale", cache.stale),
+                ("updating", cache.updating),
+                ("revalidated", cache.revalidated),
+                ("scarce", cache.scarce),
+            ] {
+                out.push(Series::new(
+                    "cache_requests_total",
+                    &[("zone", zone), ("status", status)],
+                    value,
+                ));
+            }
+            for (kind, value) in [("max", cache.max_size), ("used", cache.used_size)] {
+                out.push(Series::new(
+                    "cache_size_bytes",
+                    &[("zone", zone), ("type", kind)],
+                    value,
+                ));
+            }
+            out.push(Series::new(
+                "cache_bytes_served_total",
+                &[("zone", zone)],
+                cache.bytes_served,
+            ));
+            out.push(Series::new(
+                "cache_lock_waits_total",
+                &[("zone", zone)],
+                cache.lock_waits,
+            ));
+            out.push(Series::new(
+                "cache_lock_timeouts_total",
+                &[("zone", zone)],
+                cache.lock_timeouts,
+            ));
+        }
+
+        out
+    }
+}
+
+// --- protobuf ---
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn put_key(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(out, ((field as u64) << 3) | wire_type as u64);
+}
+
+fn put_bytes_field(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_key(out, field, WIRE_LEN);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+// --- snappy (block format) ---
+
+/// Largest back-reference a 2-byte-offset copy element can express.
+const SNAPPY_MAX_OFFSET: usize = u16::MAX as usize;
+const SNAPPY_HASH_BITS: u32 = 14;
+
+/// Compress `input` as a single snappy block: greedy 4-byte matching
+/// against a hash table of recent positions.  Output is always a valid
+/// block, even when nothing matches (it degrades to literals).
+fn snappy_compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    put_varint(&mut out, input.len() as u64);
+
+    // `table[h]` holds `position + 1` of the last 4-byte window hashing
+    // to `h` (0 = empty).
+    let mut table = vec![0usize; 1 << SNAPPY_HASH_BITS];
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i + 4 <= input.len() {
+        let window = u32::from_le_bytes([input[i], input[i + 1], input[i + 2], input[i + 3]]);
+        let h = (window.wrapping_mul(0x1e35_a7bd) >> (32 - SNAPPY_HASH_BITS)) as usize;
+        let candidate = table[h];
+        table[h] = i + 1;
+
+        if candidate != 0 {
+            let c = candidate - 1;
+            if i - c <= SNAPPY_MAX_OFFSET && input[c..c + 4] == input[i..i + 4] {
+                snappy_literal(&mut out, &input[literal_start..i]);
+                let mut len = 4;
+                while i + len < input.len() && input[c + len] == input[i + len] {
+                    len += 1;
+                }
+                snappy_copy(&mut out, i - c, len);
+                i += len;
+                literal_start = i;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    snappy_literal(&mut out, &input[literal_start..]);
+    out
+}
+
+fn snappy_literal(out: &mut Vec<u8>, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    let n = bytes.len() - 1;
+    if n < 60 {
+        out.push((n as u8) << 2);
+    } else {
+        let len_bytes = (n as u32).to_le_bytes();
+        let width = match n {
+            0..=0xff => 1,
+            0x100..=0xffff => 2,
+            0x1_0000..=0xff_ffff => 3,
+            _ => 4,
+        };
+        out.push(((59 + width) as u8) << 2);
+        out.extend_from_slice(&len_bytes[..width]);
+    }
+    out.extend_from_slice(bytes);
+}
+
+fn snappy_copy(out: &mut Vec<u8>, offset: usize, mut len: usize) {
+    // Copy elements carry at most 64 bytes; split so the tail is
+    // always at least 4 bytes long.
+    while len >= 68 {
+        snappy_copy_element(out, offset, 64);
+        len -= 64;
+    }
+    if len > 64 {
+        snappy_copy_element(out, offset, 60);
+        len -= 60;
+    }
+    snappy_copy_element(out, offset, len);
+}
+
+fn snappy_copy_element(out: &mut Vec<u8>, offset: usize, len: usize) {
+    if (4..12).contains(&len) && offset < 2048 {
+        out.push(0b01 | (((len - 4) as u8) << 2) | (((offset >> 8) as u8) << 5));
+        out.push(offset as u8);
+    } else {
+        out.push(0b10 | (((len - 1) as u8) << 2));
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shm::{CacheCounters, ServerCounters, UpstreamCounters};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    /// Minimal snappy block decoder covering every element type.
+    fn snappy_decompress(input: &[u8]) -> Vec<u8> {
+        let mut pos = 0;
+        let expected = get_varint(input, &mut pos) as usize;
+        let mut out = Vec::with_capacity(expected);
+        while pos < input.len() {
+            let tag = input[pos];
+            pos += 1;
+            match tag & 0b11 {
+                0 => {
+                    let mut n = (tag >> 2) as usize;
+                    if n >= 60 {
+                        let width = n - 59;
+                        let mut buf = [0u8; 4];
+                        buf[..width].copy_from_slice(&input[pos..pos + width]);
+                        n = u32::from_le_bytes(buf) as usize;
+                        pos += width;
+                    }
+                    out.extend_from_slice(&input[pos..pos + n + 1]);
+                    pos += n + 1;
+                }
+                kind => {
+                    let (len, offset) = if kind == 1 {
+                        let len = ((tag >> 2) & 0b111) as usize + 4;
+                        let offset = (((tag >> 5) as usize) << 8) | input[pos] as usize;
+                        pos += 1;
+                        (len, offset)
+                    } else {
+                        assert_eq!(kind, 2, "4-byte offsets are never emitted");
+                        let len = (tag >> 2) as usize + 1;
+                        let offset = u16::from_le_bytes([input[pos], input[pos + 1]]) as usize;
+                        pos += 2;
+                        (len, offset)
+                    };
+                    let start = out.len() - offset;
+                    for k in 0..len {
+                        out.push(out[start + k]);
+                    }
+                }
+            }
+        }
+        assert_eq!(out.len(), expected);
+        out
+    }
+
+    fn get_varint(buf: &[u8], pos: &mut usize) -> u64 {
+        let mut v = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = buf[*pos];
+            *pos += 1;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return v;
+            }
+            shift += 7;
+        }
+    }
+
+    /// Iterate `(field, payload)` over a message, where the payload is
+    /// the raw bytes for length-delimited/fixed64 fields and the
+    /// little-endian varint value for varint fields.
+    fn fields(buf: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut pos = 0;
+        let mut out = Vec::new();
+        while pos < buf.len() {
+            let key = get_varint(buf, &mut pos);
+            let field = (key >> 3) as u32;
+            let payload = match (key & 0b111) as u8 {
+                WIRE_VARINT => get_varint(buf, &mut pos).to_le_bytes().to_vec(),
+                WIRE_FIXED64 => {
+                    pos += 8;
+                    buf[pos - 8..pos].to_vec()
+                }
+                WIRE_LEN => {
+                    let n = get_varint(buf, &mut pos) as usize;
+                    pos += n;
+                    buf[pos - n..pos].to_vec()
+                }
+                other => panic!("unexpected wire type {other}"),
+            };
+            out.push((field, payload));
+        }
+        out
+    }
+
+    /// Decode a remote_write payload into `(labels, value, timestamp)`.
+    fn decode(payload: &[u8]) -> Vec<(BTreeMap<String, String>, f64, i64)> {
+        let request = snappy_decompress(payload);
+        let mut out = Vec::new();
+        for (field, ts) in fields(&request) {
+            assert_eq!(field, 1);
+            let mut labels = BTreeMap::new();
+            let mut label_order = Vec::new();
+            let mut sample = None;
+            for (field, body) in fields(&ts) {
+                let inner = fields(&body);
+                match field {
+                    1 => {
+                        let name = String::from_utf8(inner[0].1.clone()).unwrap();
+                        let value = String::from_utf8(inner[1].1.clone()).unwrap();
+                        label_order.push(name.clone());
+                        labels.insert(name, value);
+                    }
+                    2 => {
+                        let value = f64::from_le_bytes(inner[0].1[..].try_into().unwrap());
+                        let ts = i64::from_le_bytes(inner[1].1[..].try_into().unwrap());
+                        sample = Some((value, ts));
+                    }
+                    other => panic!("unexpected TimeSeries field {other}"),
+                }
+            }
+            let mut sorted = label_order.clone();
+            sorted.sort();
+            assert_eq!(label_order, sorted, "labels must be sorted by name");
+            let (value, ts) = sample.expect("every series carries one sample");
+            out.push((labels, value, ts));
+        }
+        out
+    }
+
+    fn key(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn snappy_round_trips_repetitive_and_random_input() {
+        let repetitive = b"nginx_vts_server_requests_total".repeat(40);
+        let compressed = snappy_compress(&repetitive);
+        assert!(compressed.len() < repetitive.len() / 4);
+        assert_eq!(snappy_decompress(&compressed), repetitive);
+
+        // Pseudo-random bytes: mostly literals, including the long
+        // literal encodings.
+        let mut x: u32 = 0x1234_5678;
+        let noisy: Vec<u8> = (0..70_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        assert_eq!(snappy_decompress(&snappy_compress(&noisy)), noisy);
+
+        assert_eq!(snappy_decompress(&snappy_compress(b"")), b"");
+        assert_eq!(snappy_decompress(&snappy_compress(b"abc")), b"abc");
+    }
+
+    #[test]
+    fn to_remote_write_decodes_to_expected_series() {
+        let mut snap = VtsSnapshot::new();
+        snap.connections.active = 5;
+        snap.connections.accepted = 100;
+
+        let mut server = ServerCounters::new();
+        server.update(200, 10, 20, 5);
+        server.update(503, 1, 2, 5);
+        snap.servers.insert("example.com".into(), server);
+
+        let mut upstream = UpstreamCounters::new();
+        upstream.update(10, 5, 300, 400, 200);
+        snap.upstreams
+            .insert(("backend".into(), "10.0.0.1:80".into()), upstream);
+
+        let mut cache = CacheCounters::new();
+        cache.update(7, 1024, 512);
+        cache.add_bytes_served(7, 2048);
+        snap.caches.insert("static".into(), cache);
+
+        let decoded = decode(&snap.to_remote_write(1_700_000_000_123));
+        assert!(decoded.iter().all(|(_, _, ts)| *ts == 1_700_000_000_123));
+
+        let series: BTreeMap<_, _> = decoded.into_iter().map(|(l, v, _)| (l, v)).collect();
+        // 6 connection + 18 server + 11 upstream + 13 cache series.
+        assert_eq!(series.len(), 48);
+
+        let expected = [
+            (
+                key(&[("__name__", "nginx_vts_connections"), ("state", "active")]),
+                5.0,
+            ),
+            (
+                key(&[
+                    ("__name__", "nginx_vts_connections_total"),
+                    ("state", "accepted"),
+                ]),
+                100.0,
+            ),
+            (
+                key(&[
+                    ("__name__", "nginx_vts_server_requests_total"),
+                    ("zone", "example.com"),
+                ]),
+                2.0,
+            ),
+            (
+                key(&[
+                    ("__name__", "nginx_vts_server_bytes_total"),
+                    ("direction", "out"),
+                    ("zone", "example.com"),
+                ]),
+                22.0,
+            ),
+            (
+                key(&[
+                    ("__name__", "nginx_vts_server_goodput_bytes_total"),
+                    ("zone", "example.com"),
+                ]),
+                20.0,
+            ),
+            (
+                key(&[
+                    ("__name__", "nginx_vts_server_responses_total"),
+                    ("status", "5xx"),
+                    ("zone", "example.com"),
+                ]),
+                1.0,
+            ),
+            (
+                key(&[
+                    ("__name__", "nginx_vts_upstream_requests_total"),
+                    ("server", "10.0.0.1:80"),
+                    ("upstream", "backend"),
+                ]),
+                1.0,
+            ),
+            (
+                key(&[
+                    ("__name__", "nginx_vts_upstream_bytes_total"),
+                    ("direction", "in"),
+                    ("server", "10.0.0.1:80"),
+                    ("upstream", "backend"),
+                ]),
+                400.0,
+            ),
+            (
+                key(&[
+                    ("__name__", "nginx_vts_cache_requests_total"),
+                    ("status", "hit"),
+                    ("zone", "static"),
+                ]),
+                1.0,
+            ),
+            (
+                key(&[
+                    ("__name__", "nginx_vts_cache_size_bytes"),
+                    ("type", "used"),
+                    ("zone", "static"),
+                ]),
+                512.0,
+            ),
+            (
+                key(&[
+                    ("__name__", "nginx_vts_cache_bytes_served_total"),
+                    ("zone", "static"),
+                ]),
+                2048.0,
+            ),
+        ];
+        for (labels, value) in expected {
+            assert_eq!(series.get(&labels), Some(&value), "series {labels:?}");
+        }
+
+        let names: BTreeSet<_> = series.keys().map(|l| l["__name__"].clone()).collect();
+        assert_eq!(names.len(), 19);
+    }
+
+    #[test]
+    fn empty_snapshot_still_exports_connection_series() {
+        let decoded = decode(&VtsSnapshot::new().to_remote_write(0));
+        assert_eq!(decoded.len(), 6);
+        assert!(decoded.iter().all(|(_, v, _)| *v == 0.0));
+    }
+}
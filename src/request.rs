@@ -0,0 +1,151 @@
+//! Borrowed, null-checked view of an nginx request for the FFI entry
+//! points.
+//!
+//! C hands us `*const ngx_http_request_t`; [`RequestRef::from_ptr`] is
+//! the one place that checks it for null, after which the accessors
+//! read fields without further `unsafe` at the call sites.
+//!
+//! As in `shm.rs`, accessors that reach into nginx-linked symbols are
+//! stubbed out under `cfg(test)`.
+
+use ngx::ffi::*;
+
+/// Shared reference to an `ngx_http_request_t` that outlives `'a`.
+#[derive(Clone, Copy)]
+pub struct RequestRef<'a>(&'a ngx_http_request_t);
+
+#[allow(dead_code)] // Used in tests and future integrations
+impl<'a> RequestRef<'a> {
+    /// Wrap a raw request pointer, returning `None` when it is null.
+    ///
+    /// # Safety
+    ///
+    /// A non-null `r` must point to a live request that stays valid
+    /// (and is not mutated through another path) for `'a`.
+    pub unsafe fn from_ptr(r: *const ngx_http_request_t) -> Option<Self> {
+        r.as_ref().map(RequestRef)
+    }
+
+    /// Whether this is the client-facing request rather than a
+    /// subrequest.
+    pub fn is_main(&self) -> bool {
+        std::ptr::eq(self.0.main, self.0)
+    }
+
+    /// Response status, defaulting to `200` before one is set (same
+    /// fallback the LOG_PHASE handler has always used).
+    pub fn status(&self) -> u16 {
+        match self.0.headers_out.status {
+            0 => 200,
+            s => u16::try_from(s).unwrap_or(u16::MAX),
+        }
+    }
+
+    /// Bytes sent to the client on this connection.
+    pub fn bytes_sent(&self) -> u64 {
+        // SAFETY: `connection` is either null or owned by the request
+        // for its whole lifetime.
+        match unsafe { self.0.connection.as_ref() } {
+            Some(c) => c.sent.max(0) as u64,
+            None => 0,
+        }
+    }
+
+    /// Part of [`bytes_sent`](Self::bytes_sent) that was the response
+    /// header, as in `$bytes_sent - $body_bytes_sent`.
+    pub fn header_bytes_sent(&self) -> u64 {
+        (self.0.header_size as u64).min(self.bytes_sent())
+    }
+
+    /// Bytes received from the client (request line, headers, body).
+    pub fn bytes_received(&self) -> u64 {
+        self.0.request_length.max(0) as u64
+    }
+
+    /// Milliseconds elapsed since the request started.
+    pub fn request_time_ms(&self) -> u64 {
+        crate::calculate_request_time(self.0.start_sec as u64, self.0.start_msec as u64)
+    }
+
+    /// Whether the request named a host (`Host` header, absolute URI or
+    /// HTTP/2 and HTTP/3 `:authority`).  Requests that failed before one
+    /// was parsed (malformed request line or `Host`, connection closed
+    /// early) and HTTP/1.0 requests without one did not; nginx serves
+    /// those from the default server without ever choosing it by name.
+    pub fn has_host(&self) -> bool {
+        self.0.headers_in.server.len > 0
+    }
+
+    /// Raw bytes of the first `server_name` of the matched server
+    /// block, or `None` when the block has none.  Never the raw `Host`
+    /// header, which is client-controlled.  Not necessarily UTF-8.
+    #[cfg(not(test))]
+    pub fn server_name(&self) -> Option<&'a [u8]> {
+        // SAFETY: `srv_conf` is the per-module array nginx attaches to
+        // every request once a server block is selected; the core
+        // module's slot always holds an `ngx_http_core_srv_conf_t`.
+        unsafe {
+            if self.0.srv_conf.is_null() {
+                return None;
+            }
+            let index = (*std::ptr::addr_of!(ngx_http_core_module)).ctx_index;
+            let cscf = *self.0.srv_conf.add(index) as *const ngx_http_core_srv_conf_t;
+            let name = &cscf.as_ref()?.server_name;
+            if name.len == 0 || name.data.is_null() {
+                return None;
+            }
+            Some(std::slice::from_raw_parts(name.data, name.len))
+        }
+    }
+
+    /// Value of the nginx variable `name` (without the `$`), or `None`
+    /// when it is unknown or not set for this request.
+    #[cfg(not(test))]
+    pub fn variable(&self, name: &str) -> Option<&'a [u8]> {
+        let mut lowered = name.to_ascii_lowercase().into_bytes();
+        let mut key = ngx_str_t {
+            len: lowered.len(),
+            data: lowered.as_mut_ptr(),
+        };
+        // SAFETY: `ngx_http_get_variable` only reads the request for
+        // lookup purposes (it may fill the variable cache); the value
+        // it returns lives in the request pool.
+        unsafe {
+            let hash = ngx_hash_key(key.data, key.len);
+            let vv = ngx_http_get_variable(
+                self.0 as *const ngx_http_request_t as *mut ngx_http_request_t,
+                &mut key,
+                hash,
+            );
+            let vv = vv.as_ref()?;
+            if vv.not_found() != 0 || vv.data.is_null() {
+                return None;
+            }
+            Some(std::slice::from_raw_parts(vv.data, vv.len() as usize))
+        }
+    }
+
+    /// Test-only stub: the unit-test binary doesn't link nginx's
+    /// `ngx_http_core_module`.
+    #[cfg(test)]
+    pub fn server_name(&self) -> Option<&'a [u8]> {
+        None
+    }
+
+    /// Test-only stub: the unit-test binary doesn't link nginx's
+    /// variable machinery.
+    #[cfg(test)]
+    pub fn variable(&self, _name: &str) -> Option<&'a [u8]> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_request_is_none() {
+        assert!(unsafe { RequestRef::from_ptr(std::ptr::null()) }.is_none());
+    }
+}
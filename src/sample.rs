@@ -0,0 +1,317 @@
+//! Flat, format-neutral view of the status page.
+//!
+//! [`collect_status_samples`] returns one [`Sample`] per series of the
+//! `/status` page, named and labelled exactly as there, so an embedder
+//! feeding StatsD, CloudWatch or its own exporter does not have to parse
+//! the exposition text.  Both come from the same [`Family`] list, built
+//! from the shared-memory snapshots (or the manager without a
+//! `vts_zone`), the cache zones and the process-wide gauges (`ssl_*`,
+//! `shm_*`, `worker_*`), honouring `vts_expose_zones` and aliases.  The
+//! page is rendered from that list; the samples are the list flattened,
+//! so they keep full label values (`vts_max_label_len` only applies to
+//! the text) and values read straight from the counters.  Histograms are
+//! flattened the same way Prometheus does: `_bucket{le="…"}`, `_sum` and
+//! `_count` samples, all of kind [`MetricKind::Histogram`].
+
+use crate::vts_node::VtsStatsManager;
+
+/// How a sample's value behaves over time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// Only ever increases (until a reset).
+    Counter,
+    /// May go up and down.
+    Gauge,
+    /// Part of a histogram: a bucket, its sum or its count.
+    Histogram,
+}
+
+impl MetricKind {
+    /// The `# TYPE` keyword.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+/// One series and its current value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Full metric name, e.g. `nginx_vts_upstream_requests_total`.
+    pub name: String,
+    /// Label pairs in page order; values are unescaped.
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub kind: MetricKind,
+}
+
+/// A series value as recorded: counts stay exact integers and times and
+/// ratios floats, so the page prints each the way it always has.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Value {
+    Int(u64),
+    Float(f64),
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<Value> for f64 {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Int(value) => value as f64,
+            Value::Float(value) => value,
+        }
+    }
+}
+
+/// One series of a [`Family`].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Series {
+    /// `_bucket`, `_sum` or `_count` for a histogram's parts, empty
+    /// otherwise.
+    pub suffix: &'static str,
+    pub labels: Vec<(String, String)>,
+    pub value: Value,
+}
+
+/// A metric family: the `# HELP` and `# TYPE` of one block of the page
+/// and its series, in page order.  A family with no series still shows
+/// its header.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Family {
+    /// Full name, metric prefix included.
+    pub name: String,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    pub series: Vec<Series>,
+}
+
+impl Family {
+    pub(crate) fn new(prefix: &str, name: &str, kind: MetricKind, help: &'static str) -> Self {
+        Self {
+            name: format!("{prefix}{name}"),
+            help,
+            kind,
+            series: Vec::new(),
+        }
+    }
+
+    /// Add a series.
+    pub(crate) fn push(&mut self, labels: Vec<(String, String)>, value: impl Into<Value>) {
+        self.push_part("", labels, value);
+    }
+
+    /// Add one part (`_bucket`, `_sum`, `_count`) of a histogram.
+    pub(crate) fn push_part(
+        &mut self,
+        suffix: &'static str,
+        labels: Vec<(String, String)>,
+        value: impl Into<Value>,
+    ) {
+        self.series.push(Series {
+            suffix,
+            labels,
+            value: value.into(),
+        });
+    }
+}
+
+/// `base` followed by `extra`, as owned label pairs.
+pub(crate) fn label_set(
+    base: &[(String, String)],
+    extra: &[(&str, &str)],
+) -> Vec<(String, String)> {
+    let mut labels = Vec::with_capacity(base.len() + extra.len());
+    labels.extend_from_slice(base);
+    labels.extend(extra.iter().map(|&(k, v)| (k.to_string(), v.to_string())));
+    labels
+}
+
+/// Flatten `families` into samples, in page order.
+pub(crate) fn flatten(families: Vec<Family>) -> Vec<Sample> {
+    let mut samples = Vec::with_capacity(families.iter().map(|f| f.series.len()).sum());
+    for family in families {
+        for series in family.series {
+            samples.push(Sample {
+                name: format!("{}{}", family.name, series.suffix),
+                labels: series.labels,
+                value: series.value.into(),
+                kind: family.kind,
+            });
+        }
+    }
+    samples
+}
+
+/// Every series of the status page this request would be served, as a
+/// flat list in page order.
+pub fn collect_status_samples() -> Vec<Sample> {
+    let (manager, up) = crate::prometheus::status_state();
+    flatten(crate::prometheus::status_families(&manager, up))
+}
+
+impl VtsStatsManager {
+    /// Every series of the status page rendered from this manager, in
+    /// page order, so two calls over the same counters return the same
+    /// list.  As on the page, the shared-memory tables take precedence
+    /// over the manager's own maps when a `vts_zone` is configured.
+    pub fn collect_samples(&self) -> Vec<Sample> {
+        flatten(crate::prometheus::status_families(
+            self,
+            crate::vts_module_up(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find<'a>(samples: &'a [Sample], name: &str, labels: &[(&str, &str)]) -> Option<&'a Sample> {
+        samples.iter().find(|s| {
+            s.name == name
+                && s.labels.len() == labels.len()
+                && s.labels
+                    .iter()
+                    .zip(labels)
+                    .all(|((k, v), (ek, ev))| k == ek && v == ev)
+        })
+    }
+
+    #[test]
+    fn collect_samples_includes_upstream_request_count() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut manager = VtsStatsManager::new();
+        manager.update_upstream_stats("backend", "10.0.0.1:80", 100, 50, 1000, 500, 200);
+        manager.update_upstream_stats("backend", "10.0.0.1:80", 120, 60, 1000, 500, 502);
+        manager.update_upstream_stats("backend", "10.0.0.2:80", 80, 40, 1000, 500, 200);
+
+        let samples = manager.collect_samples();
+        let sample = find(
+            &samples,
+            "nginx_vts_upstream_requests_total",
+            &[("upstream", "backend"), ("server", "10.0.0.1:80")],
+        )
+        .expect("upstream request-count sample");
+        assert_eq!(sample.value, 2.0);
+        assert_eq!(sample.kind, MetricKind::Counter);
+
+        let five_xx = find(
+            &samples,
+            "nginx_vts_upstream_responses_total",
+            &[
+                ("upstream", "backend"),
+                ("server", "10.0.0.1:80"),
+                ("status", "5xx"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(five_xx.value, 1.0);
+
+        let inf = find(
+            &samples,
+            "nginx_vts_upstream_response_duration_seconds_bucket",
+            &[
+                ("upstream", "backend"),
+                ("server", "10.0.0.2:80"),
+                ("le", "+Inf"),
+            ],
+        )
+        .unwrap();
+        assert_eq!((inf.value, inf.kind), (1.0, MetricKind::Histogram));
+    }
+
+    #[test]
+    fn collect_samples_carries_zone_labels_and_is_stable() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut manager = VtsStatsManager::new();
+        manager
+            .set_zone_labels("b.example.com", vec![("team".into(), "web".into())])
+            .unwrap();
+        manager.update_server_stats("b.example.com", 200, 10, 20, 5);
+        manager.update_server_stats("a.example.com", 404, 10, 20, 5);
+
+        let samples = manager.collect_samples();
+        assert_eq!(samples, manager.collect_samples());
+
+        let requests: Vec<_> = samples
+            .iter()
+            .filter(|s| s.name == "nginx_vts_server_requests_total")
+            .collect();
+        assert_eq!(requests.len(), 2);
+        // As on the page, a zone without the label has it empty.
+        assert_eq!(
+            requests[0].labels,
+            [
+                ("zone".into(), "a.example.com".into()),
+                ("team".into(), String::new())
+            ]
+        );
+        assert_eq!(
+            requests[1].labels,
+            [
+                ("zone".into(), "b.example.com".into()),
+                ("team".into(), "web".into())
+            ]
+        );
+
+        let active = find(&samples, "nginx_vts_connections", &[("state", "active")]).unwrap();
+        assert_eq!(active.kind, MetricKind::Gauge);
+    }
+
+    #[test]
+    fn samples_keep_full_labels_and_unrounded_values() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let long = format!("{}.example.com", "x".repeat(200));
+        let mut manager = VtsStatsManager::new();
+        manager.update_server_stats(&long, 200, 10, 20, 5);
+        for request_time in [1, 1, 2] {
+            manager.update_upstream_stats("backend", "10.0.0.1:80", request_time, 1, 10, 20, 200);
+        }
+
+        let page = crate::prometheus::render_status_content(&manager, true);
+        assert!(!page.contains(&long));
+        let samples = manager.collect_samples();
+        assert!(find(
+            &samples,
+            "nginx_vts_server_requests_total",
+            &[("zone", long.as_str())]
+        )
+        .is_some());
+
+        // 4 ms over 3 requests: the page prints 0.001333, the sample
+        // keeps every digit.
+        let avg = find(
+            &samples,
+            "nginx_vts_upstream_response_seconds",
+            &[
+                ("upstream", "backend"),
+                ("server", "10.0.0.1:80"),
+                ("type", "request_avg"),
+            ],
+        )
+        .unwrap();
+        let server = &manager.get_all_upstream_zones()["backend"].servers["10.0.0.1:80"];
+        assert_eq!(avg.value, server.avg_request_time() / 1000.0);
+        assert!(page.contains("type=\"request_avg\"} 0.001333\n"));
+    }
+}
@@ -0,0 +1,243 @@
+//! `?control=selftest` on a `vts_status` location: cheap consistency
+//! checks over the current counters, so corrupted state in the field
+//! shows up as a list of broken invariants rather than as odd graphs.
+//!
+//! The checks only assert what the update paths guarantee: status
+//! classes never outnumber requests, `206`/`304` are part of their
+//! class, histogram buckets are cumulative, byte totals haven't wrapped
+//! below zero and averages are finite.
+
+use std::collections::HashMap;
+
+use crate::stats::VtsServerStats;
+use crate::upstream_stats::{UpstreamServerStats, UpstreamZone};
+use crate::vts_node::VtsStatsManager;
+
+/// Outcome of one self-test run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelftestReport {
+    /// Server zones and upstream servers checked.
+    pub checked: usize,
+    /// One line per broken invariant, naming the zone and the values.
+    pub violations: Vec<String>,
+}
+
+impl SelftestReport {
+    /// Whether every invariant held.
+    pub fn passed(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// Render as a `selftest: pass|fail` line, then the violations.
+    pub fn render(&self) -> String {
+        let mut out = if self.passed() {
+            format!("selftest: pass ({} checked)\n", self.checked)
+        } else {
+            let n = self.violations.len();
+            let noun = if n == 1 { "violation" } else { "violations" };
+            format!("selftest: fail ({} checked, {n} {noun})\n", self.checked)
+        };
+        for violation in &self.violations {
+            out.push_str(violation);
+            out.push('\n');
+        }
+        out
+    }
+
+    fn check(&mut self, holds: bool, violation: impl FnOnce() -> String) {
+        if !holds {
+            self.violations.push(violation());
+        }
+    }
+
+    /// A `u64` byte total past `i64::MAX` has wrapped below zero.
+    fn check_bytes(&mut self, subject: &str, name: &str, bytes: u64) {
+        self.check(i64::try_from(bytes).is_ok(), || {
+            format!("{subject}: {name} {bytes} wrapped below zero")
+        });
+    }
+
+    fn check_finite(&mut self, subject: &str, name: &str, value: f64) {
+        self.check(value.is_finite() && value >= 0.0, || {
+            format!("{subject}: {name} is {value}")
+        });
+    }
+
+    fn check_server(&mut self, name: &str, stats: &VtsServerStats) {
+        let subject = format!("server zone \"{name}\"");
+        let r = &stats.responses;
+        let classes = r.status_1xx + r.status_2xx + r.status_3xx + r.status_4xx + r.status_5xx;
+        self.check(classes <= stats.requests, || {
+            format!(
+                "{subject}: status classes sum to {classes}, above {} requests",
+                stats.requests
+            )
+        });
+        self.check(r.status_206 <= r.status_2xx, || {
+            format!(
+                "{subject}: {} 206s above {} 2xx",
+                r.status_206, r.status_2xx
+            )
+        });
+        self.check(r.status_304 <= r.status_3xx, || {
+            format!(
+                "{subject}: {} 304s above {} 3xx",
+                r.status_304, r.status_3xx
+            )
+        });
+        self.check_bytes(&subject, "bytes_in", stats.bytes_in);
+        self.check_bytes(&subject, "bytes_out", stats.bytes_out);
+        self.check_finite(&subject, "request time total", stats.request_times.total);
+        self.check_finite(&subject, "request time avg", stats.request_times.avg);
+        self.checked += 1;
+    }
+
+    fn check_upstream(&mut self, upstream: &str, addr: &str, stats: &UpstreamServerStats) {
+        let subject = format!("upstream server \"{upstream}/{addr}\"");
+        let r = &stats.responses;
+        let classes = r.status_1xx
+            + r.status_2xx
+            + r.status_3xx
+            + r.status_4xx
+            + r.status_5xx
+            + stats.no_response;
+        self.check(classes <= stats.request_counter, || {
+            format!(
+                "{subject}: status classes sum to {classes}, above {} requests",
+                stats.request_counter
+            )
+        });
+        self.check(r.status_206 <= r.status_2xx, || {
+            format!(
+                "{subject}: {} 206s above {} 2xx",
+                r.status_206, r.status_2xx
+            )
+        });
+        self.check(r.status_304 <= r.status_3xx, || {
+            format!(
+                "{subject}: {} 304s above {} 3xx",
+                r.status_304, r.status_3xx
+            )
+        });
+        let cumulative = stats.response_buckets.windows(2).all(|w| w[0] <= w[1]);
+        self.check(cumulative, || {
+            format!("{subject}: response-time buckets are not cumulative")
+        });
+        let widest = stats.response_buckets.last().copied().unwrap_or(0);
+        self.check(widest <= stats.response_time_counter, || {
+            format!(
+                "{subject}: {widest} samples in the widest bucket, above {} in +Inf",
+                stats.response_time_counter
+            )
+        });
+        self.check_bytes(&subject, "in_bytes", stats.in_bytes);
+        self.check_bytes(&subject, "out_bytes", stats.out_bytes);
+        self.check_finite(&subject, "request time avg", stats.avg_request_time());
+        self.check_finite(&subject, "response time avg", stats.avg_response_time());
+        self.checked += 1;
+    }
+}
+
+/// Check every server zone and upstream server, in name order so the
+/// report is stable.  Server zones `vts_expose_zones` leaves off the
+/// page are not checked, so their names stay off the report.
+pub fn check_all(
+    servers: &HashMap<String, VtsServerStats>,
+    upstreams: &HashMap<String, UpstreamZone>,
+) -> SelftestReport {
+    let mut report = SelftestReport::default();
+    let mut names: Vec<_> = servers
+        .keys()
+        .filter(|zone| crate::is_zone_exposed(zone))
+        .collect();
+    names.sort_unstable();
+    for name in names {
+        report.check_server(name, &servers[name]);
+    }
+    let mut groups: Vec<_> = upstreams.keys().collect();
+    groups.sort_unstable();
+    for group in groups {
+        let zone = &upstreams[group];
+        let mut addrs: Vec<_> = zone.servers.keys().collect();
+        addrs.sort_unstable();
+        for addr in addrs {
+            report.check_upstream(group, addr, &zone.servers[addr]);
+        }
+    }
+    report
+}
+
+impl VtsStatsManager {
+    /// Run the self-test over the process-local counters.
+    pub fn selftest(&self) -> SelftestReport {
+        check_all(&self.get_all_server_stats(), self.get_all_upstream_zones())
+    }
+}
+
+/// The `?control=selftest` report, over the shared zone when configured
+/// and the process-local manager otherwise.
+pub fn run_selftest() -> SelftestReport {
+    let manager = crate::VTS_MANAGER
+        .read()
+        .unwrap_or_else(crate::recover_poisoned);
+    let servers = crate::shm::snapshot_servers().unwrap_or_else(|| manager.get_all_server_stats());
+    match crate::shm::snapshot_upstreams() {
+        Some(upstreams) => check_all(&servers, &upstreams),
+        None => check_all(&servers, manager.get_all_upstream_zones()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consistent_counters_pass() {
+        let mut manager = VtsStatsManager::new();
+        manager.update_server_stats("example.com", 200, 100, 1000, 5);
+        manager.update_server_stats("example.com", 206, 100, 1000, 5);
+        manager.update_upstream_stats("backend", "10.0.0.1:80", 50, 20, 100, 200, 304);
+        manager.update_upstream_stats("backend", "10.0.0.1:80", 50, 20, 100, 0, 0);
+
+        let report = manager.selftest();
+        assert!(report.passed(), "{}", report.render());
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.render(), "selftest: pass (2 checked)\n");
+    }
+
+    #[test]
+    fn inconsistent_counters_are_flagged() {
+        let mut server = VtsServerStats {
+            requests: 1,
+            bytes_out: u64::MAX,
+            ..Default::default()
+        };
+        server.responses.status_2xx = 2;
+        server.request_times.avg = f64::NAN;
+        let servers = HashMap::from([("example.com".to_string(), server)]);
+
+        let mut zone = UpstreamZone::new("backend");
+        let upstream = zone.get_or_create_server("10.0.0.1:80");
+        upstream.update_timing(50, 20);
+        upstream.response_buckets[0] = 5;
+        upstream.responses.status_304 = 1;
+        let upstreams = HashMap::from([("backend".to_string(), zone)]);
+
+        let report = check_all(&servers, &upstreams);
+        assert!(!report.passed());
+        let body = report.render();
+        assert!(
+            body.starts_with("selftest: fail (2 checked, 5 violations)\n"),
+            "{body}"
+        );
+        for violation in [
+            "server zone \"example.com\": status classes sum to 2, above 1 requests",
+            "server zone \"example.com\": bytes_out 18446744073709551615 wrapped below zero",
+            "server zone \"example.com\": request time avg is NaN",
+            "upstream server \"backend/10.0.0.1:80\": 1 304s above 0 3xx",
+            "upstream server \"backend/10.0.0.1:80\": response-time buckets are not cumulative",
+        ] {
+            assert!(report.violations.iter().any(|v| v == violation), "{body}");
+        }
+    }
+}
@@ -23,13 +23,21 @@ use ngx::ffi::*;
 use ngx::sync::RwLock;
 use std::collections::HashMap;
 use std::os::raw::c_void;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 
 use crate::cache_stats::{CacheZoneStats, VtsCacheStats};
-use crate::stats::{VtsRequestTimes, VtsResponseStats, VtsServerStats};
+#[cfg(feature = "unique-clients")]
+use crate::hll::HyperLogLog;
+#[cfg(feature = "latency-percentiles")]
+use crate::latency::LatencyHistogram;
+use crate::snapshot::VtsSnapshot;
+use crate::stats::{
+    ContentTypeCounters, GrpcStatusCounters, HttpMethod, MethodStatusCounters, RequestHeaderStats,
+    VtsApdexStats, VtsRequestTimes, VtsResponseStats, VtsServerConnections, VtsServerStats,
+};
 use crate::upstream_stats::{
-    UpstreamServerStats, UpstreamZone, VtsResponseStats as UpstreamResp,
-    RESPONSE_TIME_BUCKET_BOUNDS_MS, RESPONSE_TIME_BUCKET_COUNT,
+    is_upstream_error, local_time_ms, now_secs, ErrorWindow, UpstreamQueueStats,
+    UpstreamServerStats, UpstreamZone, RESPONSE_TIME_BUCKET_BOUNDS_MS, RESPONSE_TIME_BUCKET_COUNT,
 };
 
 /// Sanity upper bound on the byte length of a single key.  The matched
@@ -43,20 +51,85 @@ pub const VTS_MAX_KEY_BYTES: usize = 256;
 /// recorded a request.  Any real measurement compares less than this.
 const TIME_MIN_UNSET: u64 = u64::MAX;
 
+/// Phase of an in-flight request as seen by the per-zone connection
+/// gauges.  The raw values match the `NGX_HTTP_VTS_CONN_*` constants in
+/// `ngx_vts_wrapper.c`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnPhase {
+    /// Not (or no longer) counted against the zone.
+    Idle,
+    /// Request accepted by the zone; response header not yet sent.
+    Reading,
+    /// Response header sent; body still being written.
+    Writing,
+    /// Switched protocols (101, e.g. WebSocket); the tunnel is open.
+    Upgraded,
+}
+
+impl ConnPhase {
+    /// Decode the raw value passed across the FFI boundary.
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        match raw {
+            0 => Some(ConnPhase::Idle),
+            1 => Some(ConnPhase::Reading),
+            2 => Some(ConnPhase::Writing),
+            3 => Some(ConnPhase::Upgraded),
+            _ => None,
+        }
+    }
+}
+
 /// Per server-zone counters stored as the value in the `servers` map.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct ServerCounters {
     pub requests: u64,
     pub bytes_in: u64,
     pub bytes_out: u64,
+    /// Part of `bytes_out` that was response headers.
+    pub header_bytes_out: u64,
+    /// Part of `bytes_out` sent with 2xx and 3xx responses.
+    pub goodput_bytes_out: u64,
     pub status_1xx: u64,
     pub status_2xx: u64,
     pub status_3xx: u64,
     pub status_4xx: u64,
     pub status_5xx: u64,
+    /// 206 responses, also counted in `status_2xx`.
+    pub status_206: u64,
+    /// 304 responses, also counted in `status_3xx`.
+    pub status_304: u64,
     pub request_time_total: u64,
     pub request_time_max: u64,
     pub request_time_min: u64,
+    /// Under `vts_min_window`, the window `request_time_min` was taken
+    /// in: seconds since the epoch divided by the window length.
+    pub min_window: u64,
+    /// Requests rejected by `limit_req` / `limit_conn`.  Counted in
+    /// `requests` but not in any `status_*` class, so limiter 503s
+    /// don't inflate the backend 5xx count.
+    pub rate_limited: u64,
+    /// Requests within the Apdex threshold T.
+    pub apdex_satisfied: u64,
+    /// Requests within 4T but over T.
+    pub apdex_tolerating: u64,
+    /// Requests over 4T.
+    pub apdex_frustrated: u64,
+    /// Subrequests (SSI, `auth_request`, …) logged in this zone.  Kept
+    /// out of `requests` so they don't double-count their parent.
+    pub subrequests: u64,
+    /// Client body reads that ended in a 408 (timeout).
+    pub client_body_timeouts: u64,
+    /// Client body reads that ended in a 400 (malformed body).
+    pub client_body_malformed: u64,
+    /// 101 Switching Protocols responses.  Counted in `requests` but
+    /// not in `status_1xx`.
+    pub upgrades: u64,
+    /// In-flight requests currently in [`ConnPhase::Reading`].
+    pub conn_reading: u64,
+    /// In-flight requests currently in [`ConnPhase::Writing`].
+    pub conn_writing: u64,
+    /// Open connections currently in [`ConnPhase::Upgraded`].
+    pub conn_upgraded: u64,
 }
 
 impl ServerCounters {
@@ -65,15 +138,43 @@ impl ServerCounters {
             requests: 0,
             bytes_in: 0,
             bytes_out: 0,
+            header_bytes_out: 0,
+            goodput_bytes_out: 0,
             status_1xx: 0,
             status_2xx: 0,
             status_3xx: 0,
             status_4xx: 0,
             status_5xx: 0,
+            status_206: 0,
+            status_304: 0,
             request_time_total: 0,
             request_time_max: 0,
             request_time_min: TIME_MIN_UNSET,
+            min_window: 0,
+            rate_limited: 0,
+            apdex_satisfied: 0,
+            apdex_tolerating: 0,
+            apdex_frustrated: 0,
+            subrequests: 0,
+            client_body_timeouts: 0,
+            client_body_malformed: 0,
+            upgrades: 0,
+            conn_reading: 0,
+            conn_writing: 0,
+            conn_upgraded: 0,
+        }
+    }
+
+    /// Restore the invariants `update` keeps for counters that were set
+    /// wholesale (see `VtsStatsManager::load_server_zone`): no minimum
+    /// before the first request, and no more header or goodput bytes
+    /// than bytes.
+    pub(crate) fn normalize(&mut self) {
+        if self.requests == 0 {
+            self.request_time_min = TIME_MIN_UNSET;
         }
+        self.header_bytes_out = self.header_bytes_out.min(self.bytes_out);
+        self.goodput_bytes_out = self.goodput_bytes_out.min(self.bytes_out);
     }
 
     /// Convert into the output-side struct that the Prometheus formatter
@@ -94,47 +195,228 @@ impl ServerCounters {
             requests: self.requests,
             bytes_in: self.bytes_in,
             bytes_out: self.bytes_out,
+            header_bytes_out: self.header_bytes_out,
+            goodput_bytes_out: self.goodput_bytes_out,
             responses: VtsResponseStats {
                 status_1xx: self.status_1xx,
                 status_2xx: self.status_2xx,
                 status_3xx: self.status_3xx,
                 status_4xx: self.status_4xx,
                 status_5xx: self.status_5xx,
+                status_206: self.status_206,
+                status_304: self.status_304,
             },
+            rate_limited: self.rate_limited,
             request_times: VtsRequestTimes {
                 total,
                 min,
                 max: self.request_time_max as f64 / 1000.0,
                 avg,
             },
+            apdex: VtsApdexStats {
+                satisfied: self.apdex_satisfied,
+                tolerating: self.apdex_tolerating,
+                frustrated: self.apdex_frustrated,
+            },
+            subrequests: self.subrequests,
+            client_body_timeouts: self.client_body_timeouts,
+            client_body_malformed: self.client_body_malformed,
+            upgrades: self.upgrades,
+            connections: VtsServerConnections {
+                active: self.conn_reading + self.conn_writing + self.conn_upgraded,
+                reading: self.conn_reading,
+                writing: self.conn_writing,
+                upgraded: self.conn_upgraded,
+            },
         }
     }
 
     pub(crate) fn update(&mut self, status: u16, bytes_in: u64, bytes_out: u64, request_time: u64) {
-        self.requests += 1;
-        self.bytes_in += bytes_in;
+        self.update_weighted(status, bytes_in, bytes_out, request_time, 1);
+    }
+
+    /// Like [`update`], standing for `weight` requests like this one
+    /// (`vts_sample_rate`): counts and sums scale, the request-time
+    /// extremes don't.
+    ///
+    /// [`update`]: ServerCounters::update
+    pub(crate) fn update_weighted(
+        &mut self,
+        status: u16,
+        bytes_in: u64,
+        bytes_out: u64,
+        request_time: u64,
+        weight: u64,
+    ) {
+        self.account(bytes_in, bytes_out, request_time, weight);
+        match status {
+            101 => self.upgrades += weight,
+            100..=199 => self.status_1xx += weight,
+            200..=299 => self.status_2xx += weight,
+            300..=399 => self.status_3xx += weight,
+            400..=499 => self.status_4xx += weight,
+            500..=599 => self.status_5xx += weight,
+            _ => {}
+        }
+        match status {
+            206 => self.status_206 += weight,
+            304 => self.status_304 += weight,
+            _ => {}
+        }
+        if (200..=399).contains(&status) {
+            self.goodput_bytes_out += bytes_out.saturating_mul(weight);
+        }
+    }
+
+    /// Add the history in `other` (e.g. restored from a state file)
+    /// into these counters: totals add up and the request-time extremes
+    /// widen.  The in-flight gauges are left alone — `other`'s requests
+    /// are no longer open.
+    pub(crate) fn merge(&mut self, other: &Self) {
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.header_bytes_out += other.header_bytes_out;
+        self.goodput_bytes_out += other.goodput_bytes_out;
+        self.status_1xx += other.status_1xx;
+        self.status_2xx += other.status_2xx;
+        self.status_3xx += other.status_3xx;
+        self.status_4xx += other.status_4xx;
+        self.status_5xx += other.status_5xx;
+        self.status_206 += other.status_206;
+        self.status_304 += other.status_304;
+        self.request_time_total += other.request_time_total;
+        self.request_time_max = self.request_time_max.max(other.request_time_max);
+        self.request_time_min = self.request_time_min.min(other.request_time_min);
+        self.rate_limited += other.rate_limited;
+        self.apdex_satisfied += other.apdex_satisfied;
+        self.apdex_tolerating += other.apdex_tolerating;
+        self.apdex_frustrated += other.apdex_frustrated;
+        self.subrequests += other.subrequests;
+        self.client_body_timeouts += other.client_body_timeouts;
+        self.client_body_malformed += other.client_body_malformed;
+        self.upgrades += other.upgrades;
+    }
+
+    /// Record requests rejected by a limiter.  Like [`update_weighted`]
+    /// except the responses go to `rate_limited` instead of a status
+    /// class.
+    ///
+    /// [`update_weighted`]: ServerCounters::update_weighted
+    pub(crate) fn update_rate_limited(
+        &mut self,
+        bytes_in: u64,
+        bytes_out: u64,
+        request_time: u64,
+        weight: u64,
+    ) {
+        self.account(bytes_in, bytes_out, request_time, weight);
+        self.rate_limited += weight;
+    }
+
+    /// Record one subrequest.  Only the count is kept: its bytes and
+    /// time are part of the main request's.
+    pub(crate) fn add_subrequest(&mut self) {
+        self.subrequests += 1;
+    }
+
+    /// Record a request whose client body could not be read, by its
+    /// final status: 408 is a timeout, 400 a malformed body.  Other
+    /// statuses are ignored.
+    pub(crate) fn add_client_body_error(&mut self, status: u16) {
+        match status {
+            408 => self.client_body_timeouts += 1,
+            400 => self.client_body_malformed += 1,
+            _ => {}
+        }
+    }
+
+    /// Add response bytes already on the wire for a request that is
+    /// still streaming.  The request itself is counted by its final
+    /// [`update`], which must then leave these bytes out.
+    ///
+    /// [`update`]: ServerCounters::update
+    pub(crate) fn add_bytes_out(&mut self, bytes_out: u64) {
         self.bytes_out += bytes_out;
-        self.request_time_total += request_time;
+    }
+
+    /// Mark `header_bytes` of the response bytes already counted in
+    /// `bytes_out` as headers; the rest are body.
+    pub(crate) fn add_header_bytes_out(&mut self, header_bytes: u64) {
+        self.header_bytes_out += header_bytes;
+    }
+
+    /// Zero the accumulated counters, keeping the in-flight gauges
+    /// (those requests are still open and will transition out later).
+    pub(crate) fn reset(&mut self) {
+        *self = Self {
+            conn_reading: self.conn_reading,
+            conn_writing: self.conn_writing,
+            conn_upgraded: self.conn_upgraded,
+            ..Self::new()
+        };
+    }
+
+    /// Request, byte and timing accounting shared by every update, for
+    /// `weight` requests alike.
+    fn account(&mut self, bytes_in: u64, bytes_out: u64, request_time: u64, weight: u64) {
+        self.requests += weight;
+        self.bytes_in += bytes_in.saturating_mul(weight);
+        self.bytes_out += bytes_out.saturating_mul(weight);
+        self.request_time_total += request_time.saturating_mul(weight);
         if request_time > self.request_time_max {
             self.request_time_max = request_time;
         }
+        let window = crate::min_window_secs();
+        if window > 0 {
+            self.roll_min_window(now_secs(), window);
+        }
         if request_time < self.request_time_min {
             self.request_time_min = request_time;
         }
-        match status {
-            100..=199 => self.status_1xx += 1,
-            200..=299 => self.status_2xx += 1,
-            300..=399 => self.status_3xx += 1,
-            400..=499 => self.status_4xx += 1,
-            500..=599 => self.status_5xx += 1,
-            _ => {}
+        let threshold = crate::apdex_threshold_ms();
+        if request_time <= threshold {
+            self.apdex_satisfied += weight;
+        } else if request_time <= threshold.saturating_mul(4) {
+            self.apdex_tolerating += weight;
+        } else {
+            self.apdex_frustrated += weight;
+        }
+    }
+
+    /// Under a `vts_min_window` of `window` seconds, forget the minimum
+    /// request time once `now` (seconds since the epoch) is past the
+    /// window it was taken in, so one outlier can't pin it for good.
+    pub(crate) fn roll_min_window(&mut self, now: u64, window: u64) {
+        let slot = now / window;
+        if slot != self.min_window {
+            self.min_window = slot;
+            self.request_time_min = TIME_MIN_UNSET;
+        }
+    }
+
+    /// Move one in-flight request of this zone from phase `from` to
+    /// phase `to`.  Decrements saturate so an unbalanced hook can't
+    /// wrap a gauge around.
+    pub(crate) fn transition_connection(&mut self, from: ConnPhase, to: ConnPhase) {
+        match from {
+            ConnPhase::Idle => {}
+            ConnPhase::Reading => self.conn_reading = self.conn_reading.saturating_sub(1),
+            ConnPhase::Writing => self.conn_writing = self.conn_writing.saturating_sub(1),
+            ConnPhase::Upgraded => self.conn_upgraded = self.conn_upgraded.saturating_sub(1),
+        }
+        match to {
+            ConnPhase::Idle => {}
+            ConnPhase::Reading => self.conn_reading += 1,
+            ConnPhase::Writing => self.conn_writing += 1,
+            ConnPhase::Upgraded => self.conn_upgraded += 1,
         }
     }
 }
 
 /// Per (upstream, server) counters stored as the value in the
 /// `upstreams` map.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct UpstreamCounters {
     pub request_counter: u64,
     pub in_bytes: u64,
@@ -144,16 +426,32 @@ pub struct UpstreamCounters {
     pub status_3xx: u64,
     pub status_4xx: u64,
     pub status_5xx: u64,
+    /// 206 responses, also counted in `status_2xx`.
+    pub status_206: u64,
+    /// 304 responses, also counted in `status_3xx`.
+    pub status_304: u64,
+    /// See [`UpstreamServerStats::no_response`].
+    pub no_response: u64,
     pub request_time_total: u64,
     pub request_time_counter: u64,
     pub response_time_total: u64,
     pub response_time_counter: u64,
+    /// See [`UpstreamServerStats::local_time_total`].
+    pub local_time_total: u64,
+    /// See [`UpstreamServerStats::local_time_clamped`].
+    pub local_time_clamped: u64,
     /// See [`UpstreamServerStats::response_buckets`].
     pub response_buckets: [u64; RESPONSE_TIME_BUCKET_COUNT],
+    /// See [`UpstreamServerStats::latency`].  Adds 16 KiB per
+    /// upstream server to the zone.
+    #[cfg(feature = "latency-percentiles")]
+    pub latency: LatencyHistogram,
+    /// See [`UpstreamServerStats::error_window`].
+    pub error_window: ErrorWindow,
 }
 
 impl UpstreamCounters {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             request_counter: 0,
             in_bytes: 0,
@@ -163,37 +461,111 @@ impl UpstreamCounters {
             status_3xx: 0,
             status_4xx: 0,
             status_5xx: 0,
+            status_206: 0,
+            status_304: 0,
+            no_response: 0,
             request_time_total: 0,
             request_time_counter: 0,
             response_time_total: 0,
             response_time_counter: 0,
+            local_time_total: 0,
+            local_time_clamped: 0,
             response_buckets: [0; RESPONSE_TIME_BUCKET_COUNT],
+            #[cfg(feature = "latency-percentiles")]
+            latency: LatencyHistogram::new(),
+            error_window: ErrorWindow::default(),
+        }
+    }
+
+    /// Inverse of [`into_stats`](Self::into_stats), for snapshotting
+    /// the process-local manager.  The server's configuration
+    /// (`weight`, `down`, …) is not part of the counters.
+    pub(crate) fn from_stats(stats: &UpstreamServerStats) -> Self {
+        Self {
+            request_counter: stats.request_counter,
+            in_bytes: stats.in_bytes,
+            out_bytes: stats.out_bytes,
+            status_1xx: stats.responses.status_1xx,
+            status_2xx: stats.responses.status_2xx,
+            status_3xx: stats.responses.status_3xx,
+            status_4xx: stats.responses.status_4xx,
+            status_5xx: stats.responses.status_5xx,
+            status_206: stats.responses.status_206,
+            status_304: stats.responses.status_304,
+            no_response: stats.no_response,
+            request_time_total: stats.request_time_total,
+            request_time_counter: stats.request_time_counter,
+            response_time_total: stats.response_time_total,
+            response_time_counter: stats.response_time_counter,
+            local_time_total: stats.local_time_total,
+            local_time_clamped: stats.local_time_clamped,
+            response_buckets: stats.response_buckets,
+            #[cfg(feature = "latency-percentiles")]
+            latency: stats.latency,
+            error_window: stats.error_window,
+        }
+    }
+
+    /// Add the history in `other` into these counters.  The rolling
+    /// error window only describes the last minute and is not merged.
+    pub(crate) fn merge(&mut self, other: &Self) {
+        self.request_counter += other.request_counter;
+        self.in_bytes += other.in_bytes;
+        self.out_bytes += other.out_bytes;
+        self.status_1xx += other.status_1xx;
+        self.status_2xx += other.status_2xx;
+        self.status_3xx += other.status_3xx;
+        self.status_4xx += other.status_4xx;
+        self.status_5xx += other.status_5xx;
+        self.status_206 += other.status_206;
+        self.status_304 += other.status_304;
+        self.no_response += other.no_response;
+        self.request_time_total += other.request_time_total;
+        self.request_time_counter += other.request_time_counter;
+        self.response_time_total += other.response_time_total;
+        self.response_time_counter += other.response_time_counter;
+        self.local_time_total += other.local_time_total;
+        self.local_time_clamped += other.local_time_clamped;
+        for (bucket, saved) in self.response_buckets.iter_mut().zip(other.response_buckets) {
+            *bucket += saved;
         }
+        #[cfg(feature = "latency-percentiles")]
+        self.latency.add(&other.latency);
     }
 
     /// Populate the output-side `UpstreamServerStats` consumed by the
     /// Prometheus formatter.
-    fn into_stats(self, server: &str) -> UpstreamServerStats {
+    pub(crate) fn into_stats(self, server: &str) -> UpstreamServerStats {
         let mut stats = UpstreamServerStats::new(server);
         stats.request_counter = self.request_counter;
         stats.in_bytes = self.in_bytes;
         stats.out_bytes = self.out_bytes;
-        stats.responses = UpstreamResp {
+        stats.responses = VtsResponseStats {
             status_1xx: self.status_1xx,
             status_2xx: self.status_2xx,
             status_3xx: self.status_3xx,
             status_4xx: self.status_4xx,
             status_5xx: self.status_5xx,
+            status_206: self.status_206,
+            status_304: self.status_304,
         };
+        stats.no_response = self.no_response;
         stats.request_time_total = self.request_time_total;
         stats.request_time_counter = self.request_time_counter;
         stats.response_time_total = self.response_time_total;
         stats.response_time_counter = self.response_time_counter;
+        stats.local_time_total = self.local_time_total;
+        stats.local_time_clamped = self.local_time_clamped;
         stats.response_buckets = self.response_buckets;
+        #[cfg(feature = "latency-percentiles")]
+        {
+            stats.latency = self.latency;
+        }
+        stats.error_window = self.error_window;
         stats
     }
 
-    fn update(
+    pub(crate) fn update(
         &mut self,
         request_time: u64,
         upstream_response_time: u64,
@@ -220,7 +592,10 @@ impl UpstreamCounters {
                 self.response_buckets[i] += 1;
             }
         }
+        #[cfg(feature = "latency-percentiles")]
+        self.latency.record(upstream_response_time);
         match status {
+            0..=99 => self.no_response += 1,
             100..=199 => self.status_1xx += 1,
             200..=299 => self.status_2xx += 1,
             300..=399 => self.status_3xx += 1,
@@ -228,6 +603,22 @@ impl UpstreamCounters {
             500..=599 => self.status_5xx += 1,
             _ => {}
         }
+        match status {
+            206 => self.status_206 += 1,
+            304 => self.status_304 += 1,
+            _ => {}
+        }
+        self.error_window
+            .record(now_secs(), is_upstream_error(status));
+    }
+
+    /// See [`UpstreamServerStats::add_local_time`].
+    pub(crate) fn add_local_time(&mut self, request_time: u64, upstream_response_time: u64) {
+        if request_time > 0 {
+            let (local, clamped) = local_time_ms(request_time, upstream_response_time);
+            self.local_time_total += local;
+            self.local_time_clamped += u64::from(clamped);
+        }
     }
 }
 
@@ -237,7 +628,7 @@ impl UpstreamCounters {
 /// `$upstream_cache_status` (1=MISS .. 8=SCARCE); `max_size` and
 /// `used_size` are snapshots of the file cache state and are
 /// overwritten (not accumulated) on every observation.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct CacheCounters {
     pub miss: u64,
     pub bypass: u64,
@@ -252,10 +643,16 @@ pub struct CacheCounters {
     /// Current on-disk usage of this cache, in bytes (approximated as
     /// `sh->size * bsize` from the file cache shared header).
     pub used_size: u64,
+    /// Response bytes sent for requests answered from this cache.
+    pub bytes_served: u64,
+    /// See [`VtsCacheStats::lock_waits`].
+    pub lock_waits: u64,
+    /// See [`VtsCacheStats::lock_timeouts`].
+    pub lock_timeouts: u64,
 }
 
 impl CacheCounters {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             miss: 0,
             bypass: 0,
@@ -267,6 +664,9 @@ impl CacheCounters {
             scarce: 0,
             max_size: 0,
             used_size: 0,
+            bytes_served: 0,
+            lock_waits: 0,
+            lock_timeouts: 0,
         }
     }
 
@@ -275,7 +675,7 @@ impl CacheCounters {
     /// scheme `$upstream_cache_status` is derived from).  Unknown
     /// status values are ignored, but size fields are always updated
     /// (they reflect the current cache state, not request history).
-    fn update(&mut self, status: u8, max_size: u64, used_size: u64) {
+    pub(crate) fn update(&mut self, status: u8, max_size: u64, used_size: u64) {
         match status {
             1 => self.miss += 1,
             2 => self.bypass += 1,
@@ -291,9 +691,76 @@ impl CacheCounters {
         self.used_size = used_size;
     }
 
+    /// Add the `bytes` sent for one request if `status` means the body
+    /// came from this cache rather than upstream.
+    pub(crate) fn add_bytes_served(&mut self, status: u8, bytes: u64) {
+        if is_served_from_cache(status) {
+            self.bytes_served += bytes;
+        }
+    }
+
+    /// Zero the request counters, keeping the size gauges (they
+    /// describe the cache on disk, not request history).
+    pub(crate) fn reset(&mut self) {
+        *self = Self {
+            max_size: self.max_size,
+            used_size: self.used_size,
+            ..Self::new()
+        };
+    }
+
+    /// Count one request that waited on the cache lock.
+    pub(crate) fn add_lock_wait(&mut self, timed_out: bool) {
+        self.lock_waits += 1;
+        if timed_out {
+            self.lock_timeouts += 1;
+        }
+    }
+
+    /// Add the history in `other` into these counters.  The size
+    /// gauges describe the cache on disk now, so `other`'s are only
+    /// taken while this zone has not reported any.
+    pub(crate) fn merge(&mut self, other: &Self) {
+        self.miss += other.miss;
+        self.bypass += other.bypass;
+        self.expired += other.expired;
+        self.stale += other.stale;
+        self.updating += other.updating;
+        self.revalidated += other.revalidated;
+        self.hit += other.hit;
+        self.scarce += other.scarce;
+        self.bytes_served += other.bytes_served;
+        self.lock_waits += other.lock_waits;
+        self.lock_timeouts += other.lock_timeouts;
+        if self.max_size == 0 && self.used_size == 0 {
+            self.max_size = other.max_size;
+            self.used_size = other.used_size;
+        }
+    }
+
+    /// Inverse of [`into_stats`](Self::into_stats), for snapshotting
+    /// the process-local cache manager.
+    pub(crate) fn from_stats(stats: &CacheZoneStats) -> Self {
+        Self {
+            miss: stats.cache.miss,
+            bypass: stats.cache.bypass,
+            expired: stats.cache.expired,
+            stale: stats.cache.stale,
+            updating: stats.cache.updating,
+            revalidated: stats.cache.revalidated,
+            hit: stats.cache.hit,
+            scarce: stats.cache.scarce,
+            max_size: stats.size.max_size,
+            used_size: stats.size.used_size,
+            bytes_served: stats.cache.bytes_served,
+            lock_waits: stats.cache.lock_waits,
+            lock_timeouts: stats.cache.lock_timeouts,
+        }
+    }
+
     /// Convert into the output-side struct that the Prometheus formatter
     /// consumes.
-    fn into_stats(self, zone: &str) -> CacheZoneStats {
+    pub(crate) fn into_stats(self, zone: &str) -> CacheZoneStats {
         let mut out = CacheZoneStats::new(zone);
         out.cache = VtsCacheStats {
             miss: self.miss,
@@ -304,6 +771,9 @@ impl CacheCounters {
             revalidated: self.revalidated,
             hit: self.hit,
             scarce: self.scarce,
+            bytes_served: self.bytes_served,
+            lock_waits: self.lock_waits,
+            lock_timeouts: self.lock_timeouts,
         };
         out.size.max_size = self.max_size;
         out.size.used_size = self.used_size;
@@ -311,6 +781,13 @@ impl CacheCounters {
     }
 }
 
+/// Whether the raw cache `status` means the response body was served
+/// from the cache: HIT, STALE, UPDATING (stale while a refresh runs)
+/// and REVALIDATED (upstream answered 304, body from cache).
+pub(crate) fn is_served_from_cache(status: u8) -> bool {
+    matches!(status, 4..=7)
+}
+
 /// Format the upstream map key as `"upstream\0server"`.  Keys come from
 /// nginx configuration and never contain a NUL byte, so the separator is
 /// unambiguous.
@@ -338,12 +815,94 @@ pub type UpstreamMap<A> = RbTreeMap<NgxString<A>, UpstreamCounters, A>;
 /// `RbTreeMap` keyed by cache-zone name, stored in the slab pool.
 pub type CacheMap<A> = RbTreeMap<NgxString<A>, CacheCounters, A>;
 
+/// `RbTreeMap` keyed by upstream name, stored in the slab pool.
+pub type QueueMap<A> = RbTreeMap<NgxString<A>, UpstreamQueueStats, A>;
+
+/// `RbTreeMap` keyed by server-zone name, stored in the slab pool.
+/// Only zones with `vts_detail_method_status` on get an entry.
+pub type MethodStatusMap<A> = RbTreeMap<NgxString<A>, MethodStatusCounters, A>;
+
+/// `RbTreeMap` keyed by server-zone name, stored in the slab pool.
+pub type RequestHeaderMap<A> = RbTreeMap<NgxString<A>, RequestHeaderStats, A>;
+
+/// `RbTreeMap` keyed by server-zone name, stored in the slab pool.
+/// Only zones with `vts_track_content_type` on get an entry.
+pub type ContentTypeMap<A> = RbTreeMap<NgxString<A>, ContentTypeCounters, A>;
+
+/// `RbTreeMap` keyed by server-zone name, stored in the slab pool.
+/// Only zones with `vts_track_grpc` on and gRPC responses get an entry.
+pub type GrpcStatusMap<A> = RbTreeMap<NgxString<A>, GrpcStatusCounters, A>;
+
+/// `RbTreeMap` keyed by filter group, stored in the slab pool: how
+/// many keys the group has in [`VtsShared::filters`].
+pub type KeyCountMap<A> = RbTreeMap<NgxString<A>, u64, A>;
+
+/// `RbTreeMap` keyed by server-zone name, stored in the slab pool.
+/// Only zones with `vts_track_unique_clients` on get an entry.
+#[cfg(feature = "unique-clients")]
+pub type UniqueClientMap<A> = RbTreeMap<NgxString<A>, HyperLogLog, A>;
+
 /// Root of the shared-memory state, allocated once from the slab pool.
 #[cfg_attr(test, allow(dead_code))]
 pub struct VtsShared {
     pub servers: RwLock<ServerMap<SlabPool>>,
     pub upstreams: RwLock<UpstreamMap<SlabPool>>,
     pub caches: RwLock<CacheMap<SlabPool>>,
+    pub queues: RwLock<QueueMap<SlabPool>>,
+    pub method_status: RwLock<MethodStatusMap<SlabPool>>,
+    pub request_headers: RwLock<RequestHeaderMap<SlabPool>>,
+    pub content_types: RwLock<ContentTypeMap<SlabPool>>,
+    pub grpc_statuses: RwLock<GrpcStatusMap<SlabPool>>,
+    #[cfg(feature = "unique-clients")]
+    pub unique_clients: RwLock<UniqueClientMap<SlabPool>>,
+    /// Location-zone counters keyed by `vts_location_zone` name.
+    pub locations: RwLock<ServerMap<SlabPool>>,
+    /// Filter-zone counters keyed by the `vts_filter_by_variable`
+    /// group and value, composed like the upstream keys.
+    pub filters: RwLock<ServerMap<SlabPool>>,
+    /// Keys per filter group in `filters`, so admitting a new key
+    /// doesn't scan the table.  Only written with `filters`
+    /// write-locked, which keeps the two in step.
+    pub filter_key_counts: RwLock<KeyCountMap<SlabPool>>,
+    /// Cache status counters by cache zone and upstream, composed like
+    /// the upstream keys.  Only requests whose upstream is known get an
+    /// entry.
+    pub cache_upstreams: RwLock<CacheMap<SlabPool>>,
+    /// Observations rejected by the FFI plausibility guard (see
+    /// `lib.rs::is_plausible_time_ms`), summed across workers.
+    pub discarded: AtomicU64,
+    /// Requests whose server name was recorded under a lossy UTF-8
+    /// decoding, summed across workers.
+    pub non_utf8_name_requests: AtomicU64,
+    /// TLS handshake outcomes, summed across workers.
+    pub ssl: crate::SslCounters,
+    /// Set when this cycle created the zone; cleared by the first
+    /// [`claim_state_restore`] so a `vts_state_file` is merged once.
+    pub state_restore_pending: AtomicBool,
+}
+
+#[cfg(not(test))]
+impl VtsShared {
+    /// Entries across all tables, for `nginx_vts_shm_node_count`.
+    fn node_count(&self) -> u64 {
+        let counts = [
+            self.servers.read().iter().count(),
+            self.upstreams.read().iter().count(),
+            self.caches.read().iter().count(),
+            self.queues.read().iter().count(),
+            self.method_status.read().iter().count(),
+            self.request_headers.read().iter().count(),
+            self.content_types.read().iter().count(),
+            self.grpc_statuses.read().iter().count(),
+            #[cfg(feature = "unique-clients")]
+            self.unique_clients.read().iter().count(),
+            self.locations.read().iter().count(),
+            self.filters.read().iter().count(),
+            self.filter_key_counts.read().iter().count(),
+            self.cache_upstreams.read().iter().count(),
+        ];
+        counts.iter().sum::<usize>() as u64
+    }
 }
 
 /// Pointer published once by `vts_init_shm_zone` (in the master, before
@@ -352,13 +911,33 @@ pub struct VtsShared {
 /// back to the process-local manager.
 static VTS_SHARED: AtomicPtr<VtsShared> = AtomicPtr::new(std::ptr::null_mut());
 
+/// Size in bytes of the configured `vts_zone`, recorded alongside
+/// `VTS_SHARED` by `vts_init_shm_zone`.
+static ZONE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Slab pool of the configured `vts_zone` and the zone's name, recorded
+/// alongside `VTS_SHARED` for `nginx_vts_shm_slab_bytes`.
+#[cfg(not(test))]
+static SLAB_POOL: AtomicPtr<ngx_slab_pool_t> = AtomicPtr::new(std::ptr::null_mut());
+#[cfg(not(test))]
+static ZONE_NAME: std::sync::Mutex<String> = std::sync::Mutex::new(String::new());
+
 /// True when a shared zone has been configured and recording will write
 /// into it.
-#[allow(dead_code)]
 pub fn is_configured() -> bool {
     !VTS_SHARED.load(Ordering::Acquire).is_null()
 }
 
+/// Configured `vts_zone` size in bytes, or `None` when no zone is
+/// configured.
+pub fn zone_size() -> Option<usize> {
+    if is_configured() {
+        Some(ZONE_SIZE.load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
 #[cfg(not(test))]
 fn shared() -> Option<&'static VtsShared> {
     let ptr = VTS_SHARED.load(Ordering::Acquire);
@@ -384,30 +963,53 @@ pub fn record_server(
     bytes_in: u64,
     bytes_out: u64,
     request_time: u64,
+    weight: u64,
 ) -> bool {
+    update_server_entry(name, |c| {
+        c.update_weighted(status, bytes_in, bytes_out, request_time, weight)
+    })
+}
+
+/// Apply `f` to the shared counters for server zone `name`, inserting a
+/// fresh entry first if needed.  Same return-value contract as
+/// [`record_server`].
+#[cfg(not(test))]
+fn update_server_entry(name: &str, f: impl FnOnce(&mut ServerCounters)) -> bool {
     let Some(shared) = shared() else {
         return false;
     };
+    update_counters_entry(&shared.servers, name, f);
+    true
+}
+
+/// Apply `f` to the counters for `name` in `map` (server or location
+/// zones), inserting a fresh entry first if needed.  Oversized keys and
+/// out-of-memory inserts are dropped.
+#[cfg(not(test))]
+fn update_counters_entry(
+    map: &RwLock<ServerMap<SlabPool>>,
+    name: &str,
+    f: impl FnOnce(&mut ServerCounters),
+) {
     if name.is_empty() || name.len() > VTS_MAX_KEY_BYTES {
-        return true;
+        return;
     }
 
     let key_bytes = name.as_bytes();
-    let mut guard = shared.servers.write();
+    let mut guard = map.write();
 
     if let Some(entry) = guard.get_mut(key_bytes) {
-        entry.update(status, bytes_in, bytes_out, request_time);
-        return true;
+        f(entry);
+        return;
     }
 
     let alloc = guard.allocator().clone();
     let Ok(key) = NgxString::try_from_bytes_in(key_bytes, alloc) else {
-        return true;
+        return;
     };
     let mut counters = ServerCounters::new();
-    counters.update(status, bytes_in, bytes_out, request_time);
+    f(&mut counters);
     let _ = guard.try_insert(key, counters);
-    true
 }
 
 /// Test-only stub: pretends no `vts_zone` is configured so callers fall
@@ -420,10 +1022,103 @@ pub fn record_server(
     _bytes_in: u64,
     _bytes_out: u64,
     _request_time: u64,
+    _weight: u64,
+) -> bool {
+    false
+}
+
+/// Record one limiter-rejected request of server zone `name` into
+/// shared memory.  See [`record_server`] for the return-value contract.
+#[cfg(not(test))]
+pub fn record_server_rate_limited(
+    name: &str,
+    bytes_in: u64,
+    bytes_out: u64,
+    request_time: u64,
+    weight: u64,
+) -> bool {
+    update_server_entry(name, |c| {
+        c.update_rate_limited(bytes_in, bytes_out, request_time, weight)
+    })
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_server_rate_limited(
+    _name: &str,
+    _bytes_in: u64,
+    _bytes_out: u64,
+    _request_time: u64,
+    _weight: u64,
 ) -> bool {
     false
 }
 
+/// Record one subrequest of server zone `name` into shared memory.
+/// See [`record_server`] for the return-value contract.
+#[cfg(not(test))]
+pub fn record_server_subrequest(name: &str) -> bool {
+    update_server_entry(name, ServerCounters::add_subrequest)
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_server_subrequest(_name: &str) -> bool {
+    false
+}
+
+/// Add streamed response bytes to server zone `name` in shared memory.
+/// See [`record_server`] for the return-value contract.
+#[cfg(not(test))]
+pub fn record_server_bytes_out(name: &str, bytes_out: u64) -> bool {
+    update_server_entry(name, |c| c.add_bytes_out(bytes_out))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_server_bytes_out(_name: &str, _bytes_out: u64) -> bool {
+    false
+}
+
+/// Mark response bytes of server zone `name` in shared memory as
+/// headers.  See [`record_server`] for the return-value contract.
+#[cfg(not(test))]
+pub fn record_server_header_bytes(name: &str, header_bytes: u64) -> bool {
+    update_server_entry(name, |c| c.add_header_bytes_out(header_bytes))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_server_header_bytes(_name: &str, _header_bytes: u64) -> bool {
+    false
+}
+
+/// Record a client body read failure of server zone `name` in shared
+/// memory.  See [`record_server`] for the return-value contract.
+#[cfg(not(test))]
+pub fn record_server_client_body_error(name: &str, status: u16) -> bool {
+    update_server_entry(name, |c| c.add_client_body_error(status))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_server_client_body_error(_name: &str, _status: u16) -> bool {
+    false
+}
+
+/// Move one in-flight request of server zone `name` between connection
+/// phases.  See [`record_server`] for the return-value contract.
+#[cfg(not(test))]
+pub fn record_server_connection(name: &str, from: ConnPhase, to: ConnPhase) -> bool {
+    update_server_entry(name, |c| c.transition_connection(from, to))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_server_connection(_name: &str, _from: ConnPhase, _to: ConnPhase) -> bool {
+    false
+}
+
 /// Record one upstream-server request into shared memory.  See
 /// [`record_server`] for the return-value contract.
 #[cfg(not(test))]
@@ -436,6 +1131,26 @@ pub fn record_upstream(
     bytes_sent: u64,
     bytes_received: u64,
     status: u16,
+) -> bool {
+    update_upstream_entry(upstream, server, |c| {
+        c.update(
+            request_time,
+            upstream_response_time,
+            bytes_sent,
+            bytes_received,
+            status,
+        )
+    })
+}
+
+/// Apply `f` to the shared counters for `server` in `upstream`,
+/// inserting a fresh entry first if needed.  Same return-value contract
+/// as [`record_upstream`].
+#[cfg(not(test))]
+fn update_upstream_entry(
+    upstream: &str,
+    server: &str,
+    f: impl FnOnce(&mut UpstreamCounters),
 ) -> bool {
     let Some(shared) = shared() else {
         return false;
@@ -452,13 +1167,7 @@ pub fn record_upstream(
     let mut guard = shared.upstreams.write();
 
     if let Some(entry) = guard.get_mut(composite.as_slice()) {
-        entry.update(
-            request_time,
-            upstream_response_time,
-            bytes_sent,
-            bytes_received,
-            status,
-        );
+        f(entry);
         return true;
     }
 
@@ -467,193 +1176,1357 @@ pub fn record_upstream(
         return true;
     };
     let mut counters = UpstreamCounters::new();
-    counters.update(
-        request_time,
-        upstream_response_time,
-        bytes_sent,
-        bytes_received,
-        status,
-    );
+    f(&mut counters);
     let _ = guard.try_insert(key, counters);
     true
 }
 
 /// Test-only stub.  See [`record_server`].
 #[cfg(test)]
-#[allow(clippy::too_many_arguments)]
-pub fn record_upstream(
-    _upstream: &str,
-    _server: &str,
-    _request_time: u64,
-    _upstream_response_time: u64,
-    _bytes_sent: u64,
-    _bytes_received: u64,
-    _status: u16,
-) -> bool {
-    false
+#[allow(clippy::too_many_arguments)]
+pub fn record_upstream(
+    _upstream: &str,
+    _server: &str,
+    _request_time: u64,
+    _upstream_response_time: u64,
+    _bytes_sent: u64,
+    _bytes_received: u64,
+    _status: u16,
+) -> bool {
+    false
+}
+
+/// Record one request's local time on the server of its final upstream
+/// attempt into shared memory.  See [`record_server`] for the
+/// return-value contract.
+#[cfg(not(test))]
+pub fn record_upstream_local_time(
+    upstream: &str,
+    server: &str,
+    request_time: u64,
+    upstream_response_time: u64,
+) -> bool {
+    update_upstream_entry(upstream, server, |c| {
+        c.add_local_time(request_time, upstream_response_time)
+    })
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_upstream_local_time(
+    _upstream: &str,
+    _server: &str,
+    _request_time: u64,
+    _upstream_response_time: u64,
+) -> bool {
+    false
+}
+
+/// Record one cache-status observation into shared memory together
+/// with the `bytes` sent for the request and the current `max_size` /
+/// `used_size` of the file cache.
+/// Returns `false` when no `vts_zone` is configured so the caller
+/// can fall back to the process-local `CACHE_MANAGER`.  Oversized
+/// zone names and out-of-memory inserts are silently dropped while
+/// reporting `true`.
+#[cfg(not(test))]
+pub fn record_cache(zone: &str, status: u8, bytes: u64, max_size: u64, used_size: u64) -> bool {
+    update_cache_entry(zone, |c| {
+        c.update(status, max_size, used_size);
+        c.add_bytes_served(status, bytes);
+    })
+}
+
+/// Apply `f` to the shared counters for cache zone `zone`, inserting a
+/// fresh entry first if needed.  Same return-value contract as
+/// [`record_cache`].
+#[cfg(not(test))]
+fn update_cache_entry(zone: &str, f: impl FnOnce(&mut CacheCounters)) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    if zone.is_empty() || zone.len() > VTS_MAX_KEY_BYTES {
+        return true;
+    }
+
+    let key_bytes = zone.as_bytes();
+    let mut guard = shared.caches.write();
+
+    if let Some(entry) = guard.get_mut(key_bytes) {
+        f(entry);
+        return true;
+    }
+
+    let alloc = guard.allocator().clone();
+    let Ok(key) = NgxString::try_from_bytes_in(key_bytes, alloc) else {
+        return true;
+    };
+    let mut counters = CacheCounters::new();
+    f(&mut counters);
+    let _ = guard.try_insert(key, counters);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_cache(
+    _zone: &str,
+    _status: u8,
+    _bytes: u64,
+    _max_size: u64,
+    _used_size: u64,
+) -> bool {
+    false
+}
+
+/// Record one cache-status observation for the responses of
+/// `upstream` in cache zone `zone`, alongside [`record_cache`] for the
+/// zone itself.  Same return-value contract.
+#[cfg(not(test))]
+pub fn record_cache_upstream(zone: &str, upstream: &str, status: u8) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    if zone.is_empty()
+        || upstream.is_empty()
+        || zone.len() > VTS_MAX_KEY_BYTES
+        || upstream.len() > VTS_MAX_KEY_BYTES
+    {
+        return true;
+    }
+
+    let composite = upstream_key_bytes(zone, upstream);
+    let mut guard = shared.cache_upstreams.write();
+
+    if let Some(entry) = guard.get_mut(composite.as_slice()) {
+        entry.update(status, 0, 0);
+        return true;
+    }
+
+    let alloc = guard.allocator().clone();
+    let Ok(key) = NgxString::try_from_bytes_in(&composite, alloc) else {
+        return true;
+    };
+    let mut counters = CacheCounters::new();
+    counters.update(status, 0, 0);
+    let _ = guard.try_insert(key, counters);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_cache_upstream(_zone: &str, _upstream: &str, _status: u8) -> bool {
+    false
+}
+
+/// Record one request that waited on the cache lock of `zone`.  Same
+/// return-value contract as [`record_cache`].
+#[cfg(not(test))]
+pub fn record_cache_lock(zone: &str, timed_out: bool) -> bool {
+    update_cache_entry(zone, |c| c.add_lock_wait(timed_out))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_cache_lock(_zone: &str, _timed_out: bool) -> bool {
+    false
+}
+
+/// Record one upstream queue event (see
+/// [`UpstreamQueueStats::update`]).  Same return-value contract as
+/// [`record_server`].
+#[cfg(not(test))]
+pub fn record_upstream_queue(upstream: &str, waited: bool) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    if upstream.is_empty() || upstream.len() > VTS_MAX_KEY_BYTES {
+        return true;
+    }
+
+    let key_bytes = upstream.as_bytes();
+    let mut guard = shared.queues.write();
+
+    if let Some(entry) = guard.get_mut(key_bytes) {
+        entry.update(waited);
+        return true;
+    }
+
+    let alloc = guard.allocator().clone();
+    let Ok(key) = NgxString::try_from_bytes_in(key_bytes, alloc) else {
+        return true;
+    };
+    let mut queue = UpstreamQueueStats::default();
+    queue.update(waited);
+    let _ = guard.try_insert(key, queue);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_upstream_queue(_upstream: &str, _waited: bool) -> bool {
+    false
+}
+
+/// Record one response in the method × status cross-tab of server zone
+/// `zone`.  Same return-value contract as [`record_server`].
+#[cfg(not(test))]
+pub fn record_method_status(zone: &str, method: HttpMethod, status: u16) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    if zone.is_empty() || zone.len() > VTS_MAX_KEY_BYTES {
+        return true;
+    }
+
+    let key_bytes = zone.as_bytes();
+    let mut guard = shared.method_status.write();
+
+    if let Some(entry) = guard.get_mut(key_bytes) {
+        entry.record(method, status);
+        return true;
+    }
+
+    let alloc = guard.allocator().clone();
+    let Ok(key) = NgxString::try_from_bytes_in(key_bytes, alloc) else {
+        return true;
+    };
+    let mut counters = MethodStatusCounters::default();
+    counters.record(method, status);
+    let _ = guard.try_insert(key, counters);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_method_status(_zone: &str, _method: HttpMethod, _status: u16) -> bool {
+    false
+}
+
+/// Record one response in the Content-Type breakdown of server zone
+/// `zone`.  Same return-value contract as [`record_server`].
+#[cfg(not(test))]
+pub fn record_content_type(zone: &str, content_type: &[u8]) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    if zone.is_empty() || zone.len() > VTS_MAX_KEY_BYTES {
+        return true;
+    }
+
+    let key_bytes = zone.as_bytes();
+    let mut guard = shared.content_types.write();
+
+    if let Some(entry) = guard.get_mut(key_bytes) {
+        entry.record(content_type);
+        return true;
+    }
+
+    let alloc = guard.allocator().clone();
+    let Ok(key) = NgxString::try_from_bytes_in(key_bytes, alloc) else {
+        return true;
+    };
+    let mut counters = ContentTypeCounters::default();
+    counters.record(content_type);
+    let _ = guard.try_insert(key, counters);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_content_type(_zone: &str, _content_type: &[u8]) -> bool {
+    false
+}
+
+/// Record one response in the `grpc-status` breakdown of server zone
+/// `zone`.  Same return-value contract as [`record_server`].
+#[cfg(not(test))]
+pub fn record_grpc_status(zone: &str, grpc_status: &[u8]) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    if zone.is_empty()
+        || zone.len() > VTS_MAX_KEY_BYTES
+        || GrpcStatusCounters::classify(grpc_status).is_none()
+    {
+        return true;
+    }
+
+    let key_bytes = zone.as_bytes();
+    let mut guard = shared.grpc_statuses.write();
+
+    if let Some(entry) = guard.get_mut(key_bytes) {
+        entry.record(grpc_status);
+        return true;
+    }
+
+    let alloc = guard.allocator().clone();
+    let Ok(key) = NgxString::try_from_bytes_in(key_bytes, alloc) else {
+        return true;
+    };
+    let mut counters = GrpcStatusCounters::default();
+    counters.record(grpc_status);
+    let _ = guard.try_insert(key, counters);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_grpc_status(_zone: &str, _grpc_status: &[u8]) -> bool {
+    false
+}
+
+/// Add client address `addr` to the distinct-client sketch of server
+/// zone `zone`.  Same return-value contract as [`record_server`].
+#[cfg(all(feature = "unique-clients", not(test)))]
+pub fn record_unique_client(zone: &str, addr: &[u8]) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    if zone.is_empty() || zone.len() > VTS_MAX_KEY_BYTES || addr.is_empty() {
+        return true;
+    }
+
+    let key_bytes = zone.as_bytes();
+    let mut guard = shared.unique_clients.write();
+
+    if let Some(entry) = guard.get_mut(key_bytes) {
+        entry.insert(addr);
+        return true;
+    }
+
+    let alloc = guard.allocator().clone();
+    let Ok(key) = NgxString::try_from_bytes_in(key_bytes, alloc) else {
+        return true;
+    };
+    let mut sketch = HyperLogLog::new();
+    sketch.insert(addr);
+    let _ = guard.try_insert(key, sketch);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(all(feature = "unique-clients", test))]
+pub fn record_unique_client(_zone: &str, _addr: &[u8]) -> bool {
+    false
+}
+
+/// Record the header count and size of one request of server zone
+/// `zone`.  Same return-value contract as [`record_server`].
+#[cfg(not(test))]
+pub fn record_request_headers(zone: &str, count: u64, bytes: u64) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    if zone.is_empty() || zone.len() > VTS_MAX_KEY_BYTES {
+        return true;
+    }
+
+    let key_bytes = zone.as_bytes();
+    let mut guard = shared.request_headers.write();
+
+    if let Some(entry) = guard.get_mut(key_bytes) {
+        entry.record(count, bytes);
+        return true;
+    }
+
+    let alloc = guard.allocator().clone();
+    let Ok(key) = NgxString::try_from_bytes_in(key_bytes, alloc) else {
+        return true;
+    };
+    let mut stats = RequestHeaderStats::default();
+    stats.record(count, bytes);
+    let _ = guard.try_insert(key, stats);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_request_headers(_zone: &str, _count: u64, _bytes: u64) -> bool {
+    false
+}
+
+/// Record one request of location zone `location` into shared memory.
+/// Same return-value contract as [`record_server`].
+#[cfg(not(test))]
+pub fn record_location(
+    location: &str,
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    request_time: u64,
+) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    update_counters_entry(&shared.locations, location, |c| {
+        c.update(status, bytes_in, bytes_out, request_time)
+    });
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_location(
+    _location: &str,
+    _status: u16,
+    _bytes_in: u64,
+    _bytes_out: u64,
+    _request_time: u64,
+) -> bool {
+    false
+}
+
+/// Record one request of filter group `group` under `key` into shared
+/// memory, folding keys past the group's limit into the overflow key
+/// (see [`admit_filter_key`]).  Same return-value contract as
+/// [`record_server`].
+#[cfg(not(test))]
+pub fn record_filter(
+    group: &str,
+    key: &str,
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    request_time: u64,
+) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    if group.is_empty()
+        || key.is_empty()
+        || group.len() > VTS_MAX_KEY_BYTES
+        || key.len() > VTS_MAX_KEY_BYTES
+    {
+        return true;
+    }
+
+    let mut guard = shared.filters.write();
+    let mut composite = upstream_key_bytes(group, key);
+    if guard.get(composite.as_slice()).is_none() {
+        let known = shared
+            .filter_key_counts
+            .read()
+            .get(group.as_bytes())
+            .copied()
+            .unwrap_or(0);
+        composite = upstream_key_bytes(group, crate::stats::admit_filter_key(key, known as usize));
+    }
+
+    if let Some(entry) = guard.get_mut(composite.as_slice()) {
+        entry.update(status, bytes_in, bytes_out, request_time);
+        return true;
+    }
+
+    let alloc = guard.allocator().clone();
+    let Ok(key) = NgxString::try_from_bytes_in(&composite, alloc) else {
+        return true;
+    };
+    let mut counters = ServerCounters::new();
+    counters.update(status, bytes_in, bytes_out, request_time);
+    if guard.try_insert(key, counters).is_ok() {
+        count_filter_key(&mut shared.filter_key_counts.write(), group);
+    }
+    true
+}
+
+/// Add one key to `group`'s entry in [`VtsShared::filter_key_counts`].
+#[cfg(not(test))]
+fn count_filter_key(counts: &mut KeyCountMap<SlabPool>, group: &str) {
+    if let Some(count) = counts.get_mut(group.as_bytes()) {
+        *count += 1;
+        return;
+    }
+    let alloc = counts.allocator().clone();
+    if let Ok(key) = NgxString::try_from_bytes_in(group.as_bytes(), alloc) {
+        let _ = counts.try_insert(key, 1);
+    }
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_filter(
+    _group: &str,
+    _key: &str,
+    _status: u16,
+    _bytes_in: u64,
+    _bytes_out: u64,
+    _request_time: u64,
+) -> bool {
+    false
+}
+
+/// Count one observation rejected by the FFI plausibility guard.
+/// Returns `false` when no `vts_zone` is configured so the caller can
+/// fall back to a process-local counter.
+#[cfg(not(test))]
+pub fn record_discarded() -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    shared.discarded.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_discarded() -> bool {
+    false
+}
+
+/// Cross-worker total of discarded observations.  Returns `None` when
+/// no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn discarded_observations() -> Option<u64> {
+    Some(shared()?.discarded.load(Ordering::Relaxed))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn discarded_observations() -> Option<u64> {
+    None
+}
+
+/// Count one request whose server name was not valid UTF-8.  Returns
+/// `false` when no `vts_zone` is configured so the caller can fall
+/// back to a process-local counter.
+#[cfg(not(test))]
+pub fn record_non_utf8_name_request() -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    shared
+        .non_utf8_name_requests
+        .fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_non_utf8_name_request() -> bool {
+    false
+}
+
+/// Cross-worker total of requests with a non-UTF-8 server name.
+/// Returns `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn non_utf8_name_requests() -> Option<u64> {
+    Some(shared()?.non_utf8_name_requests.load(Ordering::Relaxed))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn non_utf8_name_requests() -> Option<u64> {
+    None
+}
+
+/// Count one TLS handshake.  Returns `false` when no `vts_zone` is
+/// configured so the caller can fall back to process-local counters.
+#[cfg(not(test))]
+pub fn record_ssl(ok: bool, reused: bool) -> bool {
+    let Some(shared) = shared() else {
+        return false;
+    };
+    shared.ssl.record(ok, reused);
+    true
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn record_ssl(_ok: bool, _reused: bool) -> bool {
+    false
+}
+
+/// Cross-worker TLS handshake totals.  Returns `None` when no
+/// `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn ssl_stats() -> Option<crate::SslStats> {
+    Some(shared()?.ssl.load())
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn ssl_stats() -> Option<crate::SslStats> {
+    None
+}
+
+/// Build the Prometheus-side server map from any iterator of
+/// `(key_bytes, counters)` pairs.  Used by both the production slab path
+/// and the unit tests (with plain heap-allocated maps).
+fn build_server_snapshot<'a, I>(entries: I) -> HashMap<String, VtsServerStats>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a ServerCounters)>,
+{
+    let mut out = HashMap::new();
+    for (key_bytes, counters) in entries {
+        if let Ok(name) = std::str::from_utf8(key_bytes) {
+            out.insert(name.to_string(), (*counters).into_stats());
+        }
+    }
+    out
+}
+
+/// Build the filter-zone map, by group and then key, from any iterator
+/// of `(composite_key_bytes, counters)` pairs.  Malformed keys are
+/// skipped.
+fn build_filter_snapshot<'a, I>(entries: I) -> HashMap<String, HashMap<String, VtsServerStats>>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a ServerCounters)>,
+{
+    let mut out: HashMap<String, HashMap<String, VtsServerStats>> = HashMap::new();
+    for (key_bytes, counters) in entries {
+        let Some((group, key)) = split_upstream_key(key_bytes) else {
+            continue;
+        };
+        let (Ok(group), Ok(key)) = (std::str::from_utf8(group), std::str::from_utf8(key)) else {
+            continue;
+        };
+        out.entry(group.to_string())
+            .or_default()
+            .insert(key.to_string(), (*counters).into_stats());
+    }
+    out
+}
+
+/// Build the Prometheus-side upstream map from any iterator of
+/// `(composite_key_bytes, counters)` pairs.  See [`build_server_snapshot`].
+fn build_upstream_snapshot<'a, I>(entries: I) -> HashMap<String, UpstreamZone>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a UpstreamCounters)>,
+{
+    let mut out: HashMap<String, UpstreamZone> = HashMap::new();
+    for (key_bytes, counters) in entries {
+        let Some((upstream_bytes, server_bytes)) = split_upstream_key(key_bytes) else {
+            continue;
+        };
+        let Ok(upstream) = std::str::from_utf8(upstream_bytes) else {
+            continue;
+        };
+        let Ok(server) = std::str::from_utf8(server_bytes) else {
+            continue;
+        };
+        let zone = out
+            .entry(upstream.to_string())
+            .or_insert_with(|| UpstreamZone::new(upstream));
+        zone.servers
+            .insert(server.to_string(), (*counters).into_stats(server));
+    }
+    out
+}
+
+/// Build the Prometheus-side cache map from any iterator of
+/// `(zone_name_bytes, counters)` pairs.
+fn build_cache_snapshot<'a, I>(entries: I) -> HashMap<String, CacheZoneStats>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a CacheCounters)>,
+{
+    let mut out = HashMap::new();
+    for (key_bytes, counters) in entries {
+        if let Ok(zone) = std::str::from_utf8(key_bytes) {
+            out.insert(zone.to_string(), (*counters).into_stats(zone));
+        }
+    }
+    out
+}
+
+/// Build the cache-zone → upstream → status counters map from any
+/// iterator of `(zone\0upstream bytes, counters)` pairs.
+fn build_cache_upstream_snapshot<'a, I>(
+    entries: I,
+) -> HashMap<String, HashMap<String, VtsCacheStats>>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a CacheCounters)>,
+{
+    let mut out: HashMap<String, HashMap<String, VtsCacheStats>> = HashMap::new();
+    for (key_bytes, counters) in entries {
+        let Some((zone, upstream)) = split_upstream_key(key_bytes) else {
+            continue;
+        };
+        let (Ok(zone), Ok(upstream)) = (std::str::from_utf8(zone), std::str::from_utf8(upstream))
+        else {
+            continue;
+        };
+        out.entry(zone.to_string())
+            .or_default()
+            .insert(upstream.to_string(), (*counters).into_stats(zone).cache);
+    }
+    out
+}
+
+/// Build the upstream-name → queue map from any iterator of
+/// `(upstream_name_bytes, stats)` pairs.
+fn build_queue_snapshot<'a, I>(entries: I) -> HashMap<String, UpstreamQueueStats>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a UpstreamQueueStats)>,
+{
+    let mut out = HashMap::new();
+    for (key_bytes, queue) in entries {
+        if let Ok(upstream) = std::str::from_utf8(key_bytes) {
+            out.insert(upstream.to_string(), *queue);
+        }
+    }
+    out
+}
+
+/// Build the zone-name → cross-tab map from any iterator of
+/// `(zone_name_bytes, counters)` pairs.
+fn build_method_status_snapshot<'a, I>(entries: I) -> HashMap<String, MethodStatusCounters>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a MethodStatusCounters)>,
+{
+    let mut out = HashMap::new();
+    for (key_bytes, counters) in entries {
+        if let Ok(zone) = std::str::from_utf8(key_bytes) {
+            out.insert(zone.to_string(), *counters);
+        }
+    }
+    out
+}
+
+/// Build the zone-name → header-histogram map from any iterator of
+/// `(zone_name_bytes, stats)` pairs.
+fn build_request_header_snapshot<'a, I>(entries: I) -> HashMap<String, RequestHeaderStats>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a RequestHeaderStats)>,
+{
+    let mut out = HashMap::new();
+    for (key_bytes, stats) in entries {
+        if let Ok(zone) = std::str::from_utf8(key_bytes) {
+            out.insert(zone.to_string(), *stats);
+        }
+    }
+    out
+}
+
+/// Build the zone-name → Content-Type breakdown map from any iterator
+/// of `(zone_name_bytes, counters)` pairs.
+fn build_content_type_snapshot<'a, I>(entries: I) -> HashMap<String, ContentTypeCounters>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a ContentTypeCounters)>,
+{
+    let mut out = HashMap::new();
+    for (key_bytes, counters) in entries {
+        if let Ok(zone) = std::str::from_utf8(key_bytes) {
+            out.insert(zone.to_string(), *counters);
+        }
+    }
+    out
+}
+
+/// Build the zone-name → `grpc-status` breakdown map from any iterator
+/// of `(zone_name_bytes, counters)` pairs.
+fn build_grpc_status_snapshot<'a, I>(entries: I) -> HashMap<String, GrpcStatusCounters>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a GrpcStatusCounters)>,
+{
+    let mut out = HashMap::new();
+    for (key_bytes, counters) in entries {
+        if let Ok(zone) = std::str::from_utf8(key_bytes) {
+            out.insert(zone.to_string(), *counters);
+        }
+    }
+    out
+}
+
+/// Materialize all server-zone counters into the format the Prometheus
+/// formatter expects.  Returns `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_servers() -> Option<HashMap<String, VtsServerStats>> {
+    let shared = shared()?;
+    let guard = shared.servers.read();
+    Some(build_server_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Server table standing in for the shared zone's, set by
+    /// [`set_test_servers`].
+    static TEST_SERVERS: std::cell::RefCell<Option<HashMap<String, VtsServerStats>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Test-only: make [`snapshot_servers`] on this thread return `servers`,
+/// as if a `vts_zone` held them; `None` goes back to no zone.
+#[cfg(test)]
+pub fn set_test_servers(servers: Option<HashMap<String, VtsServerStats>>) {
+    TEST_SERVERS.with(|t| *t.borrow_mut() = servers);
+}
+
+/// Test-only stub: `None` unless a test set a table with
+/// [`set_test_servers`].  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_servers() -> Option<HashMap<String, VtsServerStats>> {
+    TEST_SERVERS.with(|t| t.borrow().clone())
+}
+
+/// Materialize upstream counters grouped by upstream name.  Returns
+/// `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_upstreams() -> Option<HashMap<String, UpstreamZone>> {
+    let shared = shared()?;
+    let guard = shared.upstreams.read();
+    Some(build_upstream_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_upstreams() -> Option<HashMap<String, UpstreamZone>> {
+    None
+}
+
+/// Materialize all cache-zone counters into the format the Prometheus
+/// formatter expects.  Returns `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_caches() -> Option<HashMap<String, CacheZoneStats>> {
+    let shared = shared()?;
+    let guard = shared.caches.read();
+    Some(build_cache_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_caches() -> Option<HashMap<String, CacheZoneStats>> {
+    None
+}
+
+/// Materialize the per-upstream cache status counters, by cache zone
+/// and then upstream.  Returns `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_cache_upstreams() -> Option<HashMap<String, HashMap<String, VtsCacheStats>>> {
+    let shared = shared()?;
+    let guard = shared.cache_upstreams.read();
+    Some(build_cache_upstream_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_cache_upstreams() -> Option<HashMap<String, HashMap<String, VtsCacheStats>>> {
+    None
+}
+
+/// Materialize upstream queue state keyed by upstream name.  Returns
+/// `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_upstream_queues() -> Option<HashMap<String, UpstreamQueueStats>> {
+    let shared = shared()?;
+    let guard = shared.queues.read();
+    Some(build_queue_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_upstream_queues() -> Option<HashMap<String, UpstreamQueueStats>> {
+    None
+}
+
+/// Materialize the method × status cross-tabs keyed by server zone.
+/// Returns `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_method_status() -> Option<HashMap<String, MethodStatusCounters>> {
+    let shared = shared()?;
+    let guard = shared.method_status.read();
+    Some(build_method_status_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_method_status() -> Option<HashMap<String, MethodStatusCounters>> {
+    None
+}
+
+/// Materialize the request-header histograms keyed by server zone.
+/// Returns `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_request_headers() -> Option<HashMap<String, RequestHeaderStats>> {
+    let shared = shared()?;
+    let guard = shared.request_headers.read();
+    Some(build_request_header_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_request_headers() -> Option<HashMap<String, RequestHeaderStats>> {
+    None
+}
+
+/// Materialize the Content-Type breakdowns keyed by server zone.
+/// Returns `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_content_types() -> Option<HashMap<String, ContentTypeCounters>> {
+    let shared = shared()?;
+    let guard = shared.content_types.read();
+    Some(build_content_type_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_content_types() -> Option<HashMap<String, ContentTypeCounters>> {
+    None
+}
+
+/// Materialize the `grpc-status` breakdowns keyed by server zone.
+/// Returns `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_grpc_statuses() -> Option<HashMap<String, GrpcStatusCounters>> {
+    let shared = shared()?;
+    let guard = shared.grpc_statuses.read();
+    Some(build_grpc_status_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_grpc_statuses() -> Option<HashMap<String, GrpcStatusCounters>> {
+    None
+}
+
+/// Materialize the distinct-client sketches keyed by server zone.
+/// Returns `None` when no `vts_zone` is configured.
+#[cfg(all(feature = "unique-clients", not(test)))]
+pub fn snapshot_unique_clients() -> Option<HashMap<String, HyperLogLog>> {
+    let shared = shared()?;
+    let guard = shared.unique_clients.read();
+    let mut out = HashMap::new();
+    for (key, sketch) in guard.iter() {
+        if let Ok(zone) = std::str::from_utf8(key.as_bytes()) {
+            out.insert(zone.to_string(), *sketch);
+        }
+    }
+    Some(out)
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(all(feature = "unique-clients", test))]
+pub fn snapshot_unique_clients() -> Option<HashMap<String, HyperLogLog>> {
+    None
+}
+
+/// Materialize all location-zone counters.  Returns `None` when no
+/// `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_locations() -> Option<HashMap<String, VtsServerStats>> {
+    let shared = shared()?;
+    let guard = shared.locations.read();
+    Some(build_server_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_locations() -> Option<HashMap<String, VtsServerStats>> {
+    None
+}
+
+/// Materialize all filter-zone counters, by group and then key.
+/// Returns `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn snapshot_filters() -> Option<HashMap<String, HashMap<String, VtsServerStats>>> {
+    let shared = shared()?;
+    let guard = shared.filters.read();
+    Some(build_filter_snapshot(
+        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
+    ))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn snapshot_filters() -> Option<HashMap<String, HashMap<String, VtsServerStats>>> {
+    None
+}
+
+/// Slab allocator usage of a shared zone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SlabUsage {
+    /// Bytes of the page area the allocator hands out.
+    pub total: u64,
+    pub used: u64,
+    pub free: u64,
+    /// Allocation requests, summed over the size classes.
+    pub reqs: u64,
+    /// Allocation requests that failed for lack of memory.
+    pub fails: u64,
+    /// Entries across the zone's tables.  Filled in by [`slab_usage`];
+    /// [`SlabUsage::read`] only sees the pool and leaves it 0.
+    pub nodes: u64,
+}
+
+impl SlabUsage {
+    /// Read `pool`'s free-page count and per-size-class counters.
+    /// `page_shift` is `ngx_pagesize_shift`; `ngx_slab_init` sets up one
+    /// `stats` slot per size class from `1 << min_shift` up to half a
+    /// page.  Read without the pool mutex: the words may be a moment
+    /// apart, which is fine for a gauge.
+    ///
+    /// # Safety
+    ///
+    /// `pool.stats` must be null or point to `page_shift - min_shift`
+    /// slots, as in any pool initialised by nginx.
+    pub unsafe fn read(pool: &ngx_slab_pool_t, page_shift: usize) -> Self {
+        let total = (pool.end as usize).saturating_sub(pool.start as usize) as u64;
+        let free = ((pool.pfree as u64) << page_shift).min(total);
+        let (mut reqs, mut fails) = (0, 0);
+        if !pool.stats.is_null() {
+            let slots = page_shift.saturating_sub(pool.min_shift);
+            for stat in std::slice::from_raw_parts(pool.stats, slots) {
+                reqs += stat.reqs as u64;
+                fails += stat.fails as u64;
+            }
+        }
+        Self {
+            total,
+            used: total - free,
+            free,
+            reqs,
+            fails,
+            nodes: 0,
+        }
+    }
+
+    /// Whether less than `threshold_percent` of the pool is free.  An
+    /// empty (unknown) pool is never near full.
+    pub fn is_near_full(&self, threshold_percent: u64) -> bool {
+        self.total > 0
+            && u128::from(self.free) * 100 < u128::from(self.total) * u128::from(threshold_percent)
+    }
+}
+
+/// Default `vts_shm_warn_threshold`: the zone is near full once less
+/// than 10% of its pages are free.
+pub const DEFAULT_WARN_THRESHOLD_PERCENT: u64 = 10;
+
+/// Active `vts_shm_warn_threshold` in percent.
+static WARN_THRESHOLD_PERCENT: AtomicU64 = AtomicU64::new(DEFAULT_WARN_THRESHOLD_PERCENT);
+
+/// Set once this worker has been handed the near-full warning, so the
+/// error log gets one line per worker rather than one per request.
+static NEAR_FULL_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Set the near-full threshold in percent (`0` restores
+/// [`DEFAULT_WARN_THRESHOLD_PERCENT`]) and re-arm the warning.
+pub fn set_warn_threshold_percent(percent: u64) {
+    let percent = if percent == 0 {
+        DEFAULT_WARN_THRESHOLD_PERCENT
+    } else {
+        percent.min(100)
+    };
+    WARN_THRESHOLD_PERCENT.store(percent, Ordering::Relaxed);
+    NEAR_FULL_WARNED.store(false, Ordering::Relaxed);
+}
+
+/// Active `vts_shm_warn_threshold` in percent.
+pub fn warn_threshold_percent() -> u64 {
+    WARN_THRESHOLD_PERCENT.load(Ordering::Relaxed)
+}
+
+/// Whether `usage` is past the threshold and the warning has not been
+/// given yet; only the first caller to see it near full gets `true`.
+fn claim_near_full_warning(usage: &SlabUsage) -> bool {
+    usage.is_near_full(warn_threshold_percent()) && !NEAR_FULL_WARNED.swap(true, Ordering::Relaxed)
+}
+
+/// Slab usage of the configured `vts_zone` the first time it is seen
+/// near full, for the one-time warning; `None` otherwise.  Cheap once
+/// the warning was given, as it is checked on every logged request.
+#[cfg(not(test))]
+pub fn near_full_warning() -> Option<SlabUsage> {
+    if NEAR_FULL_WARNED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let pool = SLAB_POOL.load(Ordering::Acquire);
+    if pool.is_null() || !is_configured() {
+        return None;
+    }
+    // SAFETY: as in `slab_usage`.
+    let usage = unsafe { SlabUsage::read(&*pool, ngx_pagesize_shift) };
+    claim_near_full_warning(&usage).then_some(usage)
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn near_full_warning() -> Option<SlabUsage> {
+    None
+}
+
+/// Name and slab usage of the configured `vts_zone`, or `None` when no
+/// zone is configured.
+#[cfg(not(test))]
+pub fn slab_usage() -> Option<(String, SlabUsage)> {
+    let pool = SLAB_POOL.load(Ordering::Acquire);
+    if pool.is_null() || !is_configured() {
+        return None;
+    }
+    let name = ZONE_NAME
+        .lock()
+        .unwrap_or_else(crate::recover_poisoned)
+        .clone();
+    // SAFETY: the pool lives as long as the zone, and nginx sized its
+    // `stats` array for the running page size.
+    let mut usage = unsafe { SlabUsage::read(&*pool, ngx_pagesize_shift) };
+    usage.nodes = shared().map_or(0, VtsShared::node_count);
+    Some((name, usage))
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn slab_usage() -> Option<(String, SlabUsage)> {
+    None
+}
+
+/// Copy the raw server, upstream and cache counters out of the shared
+/// zone for a `vts_state_file`, or `None` when no `vts_zone` is
+/// configured.  Entries whose names are not UTF-8 are skipped.
+#[cfg(not(test))]
+pub fn capture_snapshot() -> Option<VtsSnapshot> {
+    let shared = shared()?;
+    let mut snap = VtsSnapshot::new();
+    for (key, counters) in shared.servers.read().iter() {
+        if let Ok(name) = std::str::from_utf8(key.as_bytes()) {
+            snap.servers.insert(name.to_string(), *counters);
+        }
+    }
+    for (key, counters) in shared.upstreams.read().iter() {
+        let Some((upstream, server)) = split_upstream_key(key.as_bytes()) else {
+            continue;
+        };
+        if let (Ok(upstream), Ok(server)) =
+            (std::str::from_utf8(upstream), std::str::from_utf8(server))
+        {
+            snap.upstreams
+                .insert((upstream.to_string(), server.to_string()), *counters);
+        }
+    }
+    for (key, counters) in shared.caches.read().iter() {
+        if let Ok(name) = std::str::from_utf8(key.as_bytes()) {
+            snap.caches.insert(name.to_string(), *counters);
+        }
+    }
+    Some(snap)
+}
+
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn capture_snapshot() -> Option<VtsSnapshot> {
+    None
 }
 
-/// Record one cache-status observation into shared memory together
-/// with the current `max_size` / `used_size` of the file cache.
-/// Returns `false` when no `vts_zone` is configured so the caller
-/// can fall back to the process-local `CACHE_MANAGER`.  Oversized
-/// zone names and out-of-memory inserts are silently dropped while
-/// reporting `true`.
+/// Add every counter in `snap` to the shared zone (see
+/// [`ServerCounters::merge`] and friends).  Returns `false` when no
+/// `vts_zone` is configured.
 #[cfg(not(test))]
-pub fn record_cache(zone: &str, status: u8, max_size: u64, used_size: u64) -> bool {
+pub fn merge_snapshot(snap: &VtsSnapshot) -> bool {
     let Some(shared) = shared() else {
         return false;
     };
-    if zone.is_empty() || zone.len() > VTS_MAX_KEY_BYTES {
-        return true;
+    for (name, saved) in &snap.servers {
+        update_counters_entry(&shared.servers, name, |c| c.merge(saved));
     }
-
-    let key_bytes = zone.as_bytes();
-    let mut guard = shared.caches.write();
-
-    if let Some(entry) = guard.get_mut(key_bytes) {
-        entry.update(status, max_size, used_size);
-        return true;
+    for ((upstream, server), saved) in &snap.upstreams {
+        update_upstream_entry(upstream, server, |c| c.merge(saved));
+    }
+    for (zone, saved) in &snap.caches {
+        update_cache_entry(zone, |c| c.merge(saved));
     }
-
-    let alloc = guard.allocator().clone();
-    let Ok(key) = NgxString::try_from_bytes_in(key_bytes, alloc) else {
-        return true;
-    };
-    let mut counters = CacheCounters::new();
-    counters.update(status, max_size, used_size);
-    let _ = guard.try_insert(key, counters);
     true
 }
 
 /// Test-only stub.  See [`record_server`].
 #[cfg(test)]
-pub fn record_cache(_zone: &str, _status: u8, _max_size: u64, _used_size: u64) -> bool {
+pub fn merge_snapshot(_snap: &VtsSnapshot) -> bool {
     false
 }
 
-/// Build the Prometheus-side server map from any iterator of
-/// `(key_bytes, counters)` pairs.  Used by both the production slab path
-/// and the unit tests (with plain heap-allocated maps).
-fn build_server_snapshot<'a, I>(entries: I) -> HashMap<String, VtsServerStats>
-where
-    I: IntoIterator<Item = (&'a [u8], &'a ServerCounters)>,
-{
-    let mut out = HashMap::new();
-    for (key_bytes, counters) in entries {
-        if let Ok(name) = std::str::from_utf8(key_bytes) {
-            out.insert(name.to_string(), (*counters).into_stats());
-        }
-    }
-    out
-}
-
-/// Build the Prometheus-side upstream map from any iterator of
-/// `(composite_key_bytes, counters)` pairs.  See [`build_server_snapshot`].
-fn build_upstream_snapshot<'a, I>(entries: I) -> HashMap<String, UpstreamZone>
-where
-    I: IntoIterator<Item = (&'a [u8], &'a UpstreamCounters)>,
-{
-    let mut out: HashMap<String, UpstreamZone> = HashMap::new();
-    for (key_bytes, counters) in entries {
-        let Some((upstream_bytes, server_bytes)) = split_upstream_key(key_bytes) else {
-            continue;
-        };
-        let Ok(upstream) = std::str::from_utf8(upstream_bytes) else {
-            continue;
-        };
-        let Ok(server) = std::str::from_utf8(server_bytes) else {
-            continue;
-        };
-        let zone = out
-            .entry(upstream.to_string())
-            .or_insert_with(|| UpstreamZone::new(upstream));
-        zone.servers
-            .insert(server.to_string(), (*counters).into_stats(server));
-    }
-    out
+/// Claim the one-time merge of a `vts_state_file` into the shared
+/// zone: `Some(true)` for the first caller after the zone was created,
+/// `Some(false)` once it has been claimed (a reload reusing the zone,
+/// a respawned worker), `None` when no `vts_zone` is configured.
+#[cfg(not(test))]
+pub fn claim_state_restore() -> Option<bool> {
+    Some(
+        shared()?
+            .state_restore_pending
+            .swap(false, Ordering::AcqRel),
+    )
 }
 
-/// Build the Prometheus-side cache map from any iterator of
-/// `(zone_name_bytes, counters)` pairs.
-fn build_cache_snapshot<'a, I>(entries: I) -> HashMap<String, CacheZoneStats>
-where
-    I: IntoIterator<Item = (&'a [u8], &'a CacheCounters)>,
-{
-    let mut out = HashMap::new();
-    for (key_bytes, counters) in entries {
-        if let Ok(zone) = std::str::from_utf8(key_bytes) {
-            out.insert(zone.to_string(), (*counters).into_stats(zone));
-        }
-    }
-    out
+/// Test-only stub.  See [`record_server`].
+#[cfg(test)]
+pub fn claim_state_restore() -> Option<bool> {
+    None
 }
 
-/// Materialize all server-zone counters into the format the Prometheus
-/// formatter expects.  Returns `None` when no `vts_zone` is configured.
+/// Zero every server zone's counters (see [`ServerCounters::reset`]),
+/// method × status cross-tabs, request-header histograms, Content-Type
+/// and `grpc-status` breakdowns, distinct-client sketches and
+/// location-zone counters.
+/// Returns the number of zones reset, or `None` when no `vts_zone` is
+/// configured.
 #[cfg(not(test))]
-pub fn snapshot_servers() -> Option<HashMap<String, VtsServerStats>> {
+pub fn reset_servers() -> Option<usize> {
     let shared = shared()?;
-    let guard = shared.servers.read();
-    Some(build_server_snapshot(
-        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
-    ))
+    let mut zones = 0;
+    {
+        let mut guard = shared.servers.write();
+        for (_, counters) in guard.iter_mut() {
+            counters.reset();
+            zones += 1;
+        }
+    }
+    {
+        let mut guard = shared.method_status.write();
+        for (_, counters) in guard.iter_mut() {
+            *counters = MethodStatusCounters::default();
+        }
+    }
+    {
+        let mut guard = shared.request_headers.write();
+        for (_, stats) in guard.iter_mut() {
+            *stats = RequestHeaderStats::default();
+        }
+    }
+    {
+        let mut guard = shared.content_types.write();
+        for (_, counters) in guard.iter_mut() {
+            *counters = ContentTypeCounters::default();
+        }
+    }
+    {
+        let mut guard = shared.grpc_statuses.write();
+        for (_, counters) in guard.iter_mut() {
+            *counters = GrpcStatusCounters::default();
+        }
+    }
+    #[cfg(feature = "unique-clients")]
+    {
+        let mut guard = shared.unique_clients.write();
+        for (_, sketch) in guard.iter_mut() {
+            *sketch = HyperLogLog::new();
+        }
+    }
+    {
+        let mut guard = shared.locations.write();
+        for (_, counters) in guard.iter_mut() {
+            counters.reset();
+        }
+    }
+    let mut guard = shared.filters.write();
+    for (_, counters) in guard.iter_mut() {
+        counters.reset();
+    }
+    Some(zones)
 }
 
 /// Test-only stub.  See [`record_server`].
 #[cfg(test)]
-pub fn snapshot_servers() -> Option<HashMap<String, VtsServerStats>> {
+pub fn reset_servers() -> Option<usize> {
     None
 }
 
-/// Materialize upstream counters grouped by upstream name.  Returns
-/// `None` when no `vts_zone` is configured.
+/// Zero every (upstream, server) counter and the queue wait totals
+/// (queue lengths are live gauges and are kept).  Returns the number of
+/// upstream servers reset, or `None` when no `vts_zone` is configured.
 #[cfg(not(test))]
-pub fn snapshot_upstreams() -> Option<HashMap<String, UpstreamZone>> {
+pub fn reset_upstreams() -> Option<usize> {
     let shared = shared()?;
-    let guard = shared.upstreams.read();
-    Some(build_upstream_snapshot(
-        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
-    ))
+    let mut servers = 0;
+    {
+        let mut guard = shared.upstreams.write();
+        for (_, counters) in guard.iter_mut() {
+            *counters = UpstreamCounters::new();
+            servers += 1;
+        }
+    }
+    let mut guard = shared.queues.write();
+    for (_, queue) in guard.iter_mut() {
+        queue.waits_total = 0;
+    }
+    Some(servers)
 }
 
 /// Test-only stub.  See [`record_server`].
 #[cfg(test)]
-pub fn snapshot_upstreams() -> Option<HashMap<String, UpstreamZone>> {
+pub fn reset_upstreams() -> Option<usize> {
     None
 }
 
-/// Materialize all cache-zone counters into the format the Prometheus
-/// formatter expects.  Returns `None` when no `vts_zone` is configured.
+/// Zero every cache zone's counters (see [`CacheCounters::reset`]).
+/// Returns the number of zones reset, or `None` when no `vts_zone` is
+/// configured.
 #[cfg(not(test))]
-pub fn snapshot_caches() -> Option<HashMap<String, CacheZoneStats>> {
+pub fn reset_caches() -> Option<usize> {
     let shared = shared()?;
-    let guard = shared.caches.read();
-    Some(build_cache_snapshot(
-        guard.iter().map(|(k, v)| (k.as_bytes(), v)),
-    ))
+    let mut guard = shared.caches.write();
+    let mut zones = 0;
+    for (_, counters) in guard.iter_mut() {
+        counters.reset();
+        zones += 1;
+    }
+    for (_, counters) in shared.cache_upstreams.write().iter_mut() {
+        counters.reset();
+    }
+    Some(zones)
 }
 
 /// Test-only stub.  See [`record_server`].
 #[cfg(test)]
-pub fn snapshot_caches() -> Option<HashMap<String, CacheZoneStats>> {
+pub fn reset_caches() -> Option<usize> {
     None
 }
 
+/// Reload half of [`vts_init_shm_zone`]: the `VtsShared` a previous
+/// cycle built in this zone's slab pool, re-attached to `shm_zone`, or
+/// `None` when the pool is fresh and the state has to be built.
+///
+/// The slab pool's `data` field lives in the shared memory itself, so
+/// it survives reload and binary upgrade along with the trees it
+/// points at.  It is the only thing trusted here: nginx's own `data`
+/// argument is the previous cycle's `shm_zone->data` even when the zone
+/// was resized and this memory is new, and following it would leave
+/// the workers on the old mapping, unmapped once that cycle is freed.
+fn reattach_shared(
+    shm_zone: &mut ngx_shm_zone_t,
+    pool: &ngx_slab_pool_t,
+) -> Option<*mut VtsShared> {
+    let existing = pool.data as *mut VtsShared;
+    if existing.is_null() {
+        return None;
+    }
+    shm_zone.data = existing as *mut c_void;
+    Some(existing)
+}
+
 /// Shared-memory zone initialization callback.
 ///
 /// Called by nginx exactly once per cycle (in the master, before workers
-/// fork).  On reload the slab pool's `data` field still points at the
-/// previous cycle's `VtsShared`, so we just re-publish that pointer;
-/// otherwise we allocate two empty `RbTreeMap`s and a fresh `VtsShared`
+/// fork), and the only place the shared state is created.  On reload
+/// the slab pool still holds the previous cycle's `VtsShared`, so
+/// [`reattach_shared`] re-publishes it and every counter carries over;
+/// otherwise we allocate the empty `RbTreeMap`s and a fresh `VtsShared`
 /// from the slab pool itself.
 ///
 /// # Safety
 ///
 /// Invoked by nginx via the function pointer stored in `shm_zone->init`.
 /// `shm_zone` is a valid pointer; `data` is either NULL on initial start
-/// or a pointer carried over from the previous cycle (we re-discover the
-/// shared state from the slab pool either way, so we don't need it).
+/// or a pointer carried over from the previous cycle, deliberately not
+/// used (see [`reattach_shared`]).
 #[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "C" fn vts_init_shm_zone(
@@ -664,19 +2537,21 @@ pub unsafe extern "C" fn vts_init_shm_zone(
         return NGX_ERROR as ngx_int_t;
     }
     let shm_zone_ref = &mut *shm_zone;
+    ZONE_SIZE.store(shm_zone_ref.shm.size, Ordering::Relaxed);
 
     let mut alloc = match SlabPool::from_shm_zone(shm_zone_ref) {
         Some(a) => a,
         None => return NGX_ERROR as ngx_int_t,
     };
+    SLAB_POOL.store(alloc.as_mut() as *mut ngx_slab_pool_t, Ordering::Release);
+    let name = &shm_zone_ref.shm.name;
+    *ZONE_NAME.lock().unwrap_or_else(crate::recover_poisoned) = if name.data.is_null() {
+        String::new()
+    } else {
+        String::from_utf8_lossy(std::slice::from_raw_parts(name.data, name.len)).into_owned()
+    };
 
-    // The slab pool's `data` field persists across reload and binary
-    // upgrade because it lives in the shared memory itself.  Non-null
-    // means a previous cycle (or this same cycle, on reload) already
-    // built the shared state — just re-publish the pointer.
-    let existing = alloc.as_mut().data as *mut VtsShared;
-    if !existing.is_null() {
-        shm_zone_ref.data = existing as *mut c_void;
+    if let Some(existing) = reattach_shared(shm_zone_ref, alloc.as_mut()) {
         VTS_SHARED.store(existing, Ordering::Release);
         return NGX_OK as ngx_int_t;
     }
@@ -695,10 +2570,66 @@ pub unsafe extern "C" fn vts_init_shm_zone(
         Ok(m) => m,
         Err(_) => return NGX_ERROR as ngx_int_t,
     };
+    let queues: QueueMap<SlabPool> = match RbTreeMap::try_new_in(alloc.clone()) {
+        Ok(m) => m,
+        Err(_) => return NGX_ERROR as ngx_int_t,
+    };
+    let method_status: MethodStatusMap<SlabPool> = match RbTreeMap::try_new_in(alloc.clone()) {
+        Ok(m) => m,
+        Err(_) => return NGX_ERROR as ngx_int_t,
+    };
+    let request_headers: RequestHeaderMap<SlabPool> = match RbTreeMap::try_new_in(alloc.clone()) {
+        Ok(m) => m,
+        Err(_) => return NGX_ERROR as ngx_int_t,
+    };
+    let content_types: ContentTypeMap<SlabPool> = match RbTreeMap::try_new_in(alloc.clone()) {
+        Ok(m) => m,
+        Err(_) => return NGX_ERROR as ngx_int_t,
+    };
+    let grpc_statuses: GrpcStatusMap<SlabPool> = match RbTreeMap::try_new_in(alloc.clone()) {
+        Ok(m) => m,
+        Err(_) => return NGX_ERROR as ngx_int_t,
+    };
+    #[cfg(feature = "unique-clients")]
+    let unique_clients: UniqueClientMap<SlabPool> = match RbTreeMap::try_new_in(alloc.clone()) {
+        Ok(m) => m,
+        Err(_) => return NGX_ERROR as ngx_int_t,
+    };
+    let locations: ServerMap<SlabPool> = match RbTreeMap::try_new_in(alloc.clone()) {
+        Ok(m) => m,
+        Err(_) => return NGX_ERROR as ngx_int_t,
+    };
+    let filters: ServerMap<SlabPool> = match RbTreeMap::try_new_in(alloc.clone()) {
+        Ok(m) => m,
+        Err(_) => return NGX_ERROR as ngx_int_t,
+    };
+    let filter_key_counts: KeyCountMap<SlabPool> = match RbTreeMap::try_new_in(alloc.clone()) {
+        Ok(m) => m,
+        Err(_) => return NGX_ERROR as ngx_int_t,
+    };
+    let cache_upstreams: CacheMap<SlabPool> = match RbTreeMap::try_new_in(alloc.clone()) {
+        Ok(m) => m,
+        Err(_) => return NGX_ERROR as ngx_int_t,
+    };
     let shared = VtsShared {
         servers: RwLock::new(servers),
         upstreams: RwLock::new(upstreams),
         caches: RwLock::new(caches),
+        queues: RwLock::new(queues),
+        method_status: RwLock::new(method_status),
+        request_headers: RwLock::new(request_headers),
+        content_types: RwLock::new(content_types),
+        grpc_statuses: RwLock::new(grpc_statuses),
+        #[cfg(feature = "unique-clients")]
+        unique_clients: RwLock::new(unique_clients),
+        locations: RwLock::new(locations),
+        filters: RwLock::new(filters),
+        filter_key_counts: RwLock::new(filter_key_counts),
+        cache_upstreams: RwLock::new(cache_upstreams),
+        discarded: AtomicU64::new(0),
+        non_utf8_name_requests: AtomicU64::new(0),
+        ssl: crate::SslCounters::new(),
+        state_restore_pending: AtomicBool::new(true),
     };
     let shared_ptr: *mut VtsShared = match allocate(shared, &alloc) {
         Ok(p) => p.as_ptr(),
@@ -753,7 +2684,7 @@ mod tests {
     #[test]
     fn server_counters_status_buckets_cover_all_classes() {
         let mut c = ServerCounters::new();
-        for status in [101u16, 204, 304, 404, 503, 600] {
+        for status in [100u16, 204, 304, 404, 503, 600] {
             c.update(status, 0, 0, 0);
         }
         assert_eq!(c.status_1xx, 1);
@@ -765,6 +2696,45 @@ mod tests {
         assert_eq!(c.requests, 6);
     }
 
+    #[test]
+    fn server_counters_keep_rate_limited_out_of_status_classes() {
+        let mut c = ServerCounters::new();
+        c.update(503, 10, 100, 5);
+        c.update_rate_limited(10, 200, 1, 1);
+
+        assert_eq!(c.requests, 2);
+        assert_eq!(c.status_5xx, 1);
+        assert_eq!(c.rate_limited, 1);
+        assert_eq!(c.bytes_out, 300);
+        assert_eq!(c.request_time_min, 1);
+        assert_eq!(c.into_stats().rate_limited, 1);
+    }
+
+    #[test]
+    fn windowed_min_recovers_after_the_window() {
+        const WINDOW: u64 = 60;
+        let start = 1_000 * WINDOW;
+        let mut c = ServerCounters::new();
+        c.roll_min_window(start, WINDOW);
+        c.update(200, 0, 0, 0);
+        for t in start + 1..start + WINDOW {
+            c.roll_min_window(t, WINDOW);
+            c.update(200, 0, 0, 40 + t % 7);
+        }
+        // Still inside the window that saw the 0 ms request.
+        assert_eq!(c.request_time_min, 0);
+
+        for t in start + WINDOW..start + 2 * WINDOW {
+            c.roll_min_window(t, WINDOW);
+            c.update(200, 0, 0, 40 + t % 7);
+        }
+        assert_eq!(c.request_time_min, 40);
+        assert_eq!(c.into_stats().request_times.min, 0.04);
+        // The all-time extremes and totals are untouched by the window.
+        assert_eq!(c.requests, 2 * WINDOW);
+        assert_eq!(c.request_time_max, 46);
+    }
+
     #[test]
     fn server_counters_into_stats_handles_unset_min() {
         let c = ServerCounters::new();
@@ -775,6 +2745,31 @@ mod tests {
         assert_eq!(stats.request_times.avg, 0.0);
     }
 
+    #[test]
+    fn server_counters_track_connection_phases() {
+        let mut c = ServerCounters::new();
+        c.transition_connection(ConnPhase::Idle, ConnPhase::Reading);
+        c.transition_connection(ConnPhase::Idle, ConnPhase::Reading);
+        c.transition_connection(ConnPhase::Reading, ConnPhase::Writing);
+        let conns = c.into_stats().connections;
+        assert_eq!((conns.active, conns.reading, conns.writing), (2, 1, 1));
+
+        // An unbalanced end must not wrap the gauge.
+        c.transition_connection(ConnPhase::Writing, ConnPhase::Idle);
+        c.transition_connection(ConnPhase::Writing, ConnPhase::Idle);
+        assert_eq!((c.conn_reading, c.conn_writing), (1, 0));
+        // Connection gauges never count as requests.
+        assert_eq!(c.requests, 0);
+    }
+
+    #[test]
+    fn conn_phase_from_raw_rejects_unknown_values() {
+        assert_eq!(ConnPhase::from_raw(0), Some(ConnPhase::Idle));
+        assert_eq!(ConnPhase::from_raw(2), Some(ConnPhase::Writing));
+        assert_eq!(ConnPhase::from_raw(3), Some(ConnPhase::Upgraded));
+        assert_eq!(ConnPhase::from_raw(4), None);
+    }
+
     #[test]
     fn upstream_counters_accumulate_correctly() {
         let mut c = UpstreamCounters::new();
@@ -791,6 +2786,13 @@ mod tests {
         assert_eq!(c.request_time_counter, 3);
         assert_eq!(c.response_time_total, 180);
         assert_eq!(c.response_time_counter, 3);
+        assert_eq!(c.no_response, 0);
+
+        // Status 0: nginx got no response; still an attempt.
+        c.update(30000, 30000, 1000, 0, 0);
+        assert_eq!(c.request_counter, 4);
+        assert_eq!(c.no_response, 1);
+        assert_eq!(c.into_stats("s").no_response, 1);
     }
 
     #[test]
@@ -949,12 +2951,22 @@ mod tests {
         // In the test binary the global pointer starts null, so the
         // higher-level callers fall back to VTS_MANAGER.
         assert!(!is_configured());
+        assert!(zone_size().is_none());
         assert!(snapshot_servers().is_none());
         assert!(snapshot_upstreams().is_none());
         assert!(snapshot_caches().is_none());
-        assert!(!record_server("test", 200, 0, 0, 0));
+        assert!(!record_server("test", 200, 0, 0, 0, 1));
+        assert!(!record_server_rate_limited("test", 0, 0, 0, 1));
+        assert!(!record_server_subrequest("test"));
+        assert!(!record_server_connection(
+            "test",
+            ConnPhase::Idle,
+            ConnPhase::Reading
+        ));
         assert!(!record_upstream("u", "s", 0, 0, 0, 0, 200));
-        assert!(!record_cache("zone", 7, 0, 0));
+        assert!(!record_cache("zone", 7, 0, 0, 0));
+        assert!(!record_discarded());
+        assert!(discarded_observations().is_none());
     }
 
     #[test]
@@ -1007,6 +3019,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn cache_counters_count_bytes_served_from_cache_only() {
+        let mut c = CacheCounters::new();
+        for (status, bytes) in [(7, 1000), (7, 2500), (4, 300), (6, 200), (1, 9000), (2, 50)] {
+            c.update(status, 0, 0);
+            c.add_bytes_served(status, bytes);
+        }
+        assert_eq!(c.bytes_served, 4000);
+        assert_eq!(c.into_stats("z").cache.bytes_served, 4000);
+    }
+
     #[test]
     fn cache_counters_into_stats() {
         let mut c = CacheCounters::new();
@@ -1046,6 +3069,28 @@ mod tests {
         assert_eq!(c.hit, 1);
     }
 
+    #[test]
+    fn reset_zeroes_history_but_keeps_live_gauges() {
+        let mut c = CacheCounters::new();
+        c.update(7, 10 * 1024 * 1024, 512 * 1024);
+        c.add_bytes_served(7, 4096);
+        c.add_lock_wait(true);
+        c.reset();
+        assert_eq!(c.hit, 0);
+        assert_eq!(c.bytes_served, 0);
+        assert_eq!(c.lock_waits, 0);
+        assert_eq!(c.max_size, 10 * 1024 * 1024);
+        assert_eq!(c.used_size, 512 * 1024);
+
+        let mut s = ServerCounters::new();
+        s.update(200, 10, 20, 5);
+        s.transition_connection(ConnPhase::Idle, ConnPhase::Writing);
+        s.reset();
+        assert_eq!(s.requests, 0);
+        assert_eq!(s.request_time_min, TIME_MIN_UNSET);
+        assert_eq!(s.conn_writing, 1);
+    }
+
     #[test]
     fn cache_counters_into_stats_carries_size() {
         let mut c = CacheCounters::new();
@@ -1087,4 +3132,191 @@ mod tests {
         let snap = build_cache_snapshot(bad);
         assert!(snap.is_empty());
     }
+
+    #[test]
+    fn build_queue_snapshot_converts_entries() {
+        let mut queue = UpstreamQueueStats::default();
+        queue.update(true);
+        let entries: Vec<(&[u8], &UpstreamQueueStats)> =
+            vec![(b"backend".as_ref(), &queue), (&[0xFF][..], &queue)];
+        let snap = build_queue_snapshot(entries);
+        assert_eq!(snap.len(), 1);
+        assert_eq!(snap["backend"].length, 1);
+        assert_eq!(snap["backend"].waits_total, 1);
+    }
+
+    #[test]
+    fn build_method_status_snapshot_converts_entries() {
+        let mut counters = MethodStatusCounters::default();
+        counters.record(HttpMethod::Get, 200);
+        let entries: Vec<(&[u8], &MethodStatusCounters)> =
+            vec![(b"api.test".as_ref(), &counters), (&[0xFF][..], &counters)];
+        let snap = build_method_status_snapshot(entries);
+        assert_eq!(snap.len(), 1);
+        assert_eq!(snap["api.test"].get(HttpMethod::Get, "2xx"), 1);
+    }
+
+    #[test]
+    fn build_request_header_snapshot_converts_entries() {
+        let mut stats = RequestHeaderStats::default();
+        stats.record(12, 900);
+        let entries: Vec<(&[u8], &RequestHeaderStats)> =
+            vec![(b"api.test".as_ref(), &stats), (&[0xFF][..], &stats)];
+        let snap = build_request_header_snapshot(entries);
+        assert_eq!(snap.len(), 1);
+        assert_eq!(snap["api.test"].bytes_buckets, [0, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(snap["api.test"].count_buckets, [0, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn build_content_type_snapshot_converts_entries() {
+        let mut counters = ContentTypeCounters::default();
+        counters.record(b"image/webp");
+        let entries: Vec<(&[u8], &ContentTypeCounters)> =
+            vec![(b"cdn.test".as_ref(), &counters), (&[0xFF][..], &counters)];
+        let snap = build_content_type_snapshot(entries);
+        assert_eq!(snap.len(), 1);
+        assert_eq!(snap["cdn.test"].counts, [0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn build_filter_snapshot_groups_by_filter() {
+        let mut counters = ServerCounters::new();
+        counters.update(200, 10, 100, 5);
+        let us = upstream_key_bytes("country", "US");
+        let jp = upstream_key_bytes("country", "JP");
+        let asn = upstream_key_bytes("asn", "AS13335");
+        let entries: Vec<(&[u8], &ServerCounters)> = vec![
+            (us.as_slice(), &counters),
+            (jp.as_slice(), &counters),
+            (asn.as_slice(), &counters),
+            (b"no-separator".as_ref(), &counters),
+        ];
+        let snap = build_filter_snapshot(entries);
+        assert_eq!(snap.len(), 2);
+        assert_eq!(snap["country"].len(), 2);
+        assert_eq!(snap["country"]["JP"].requests, 1);
+        assert_eq!(snap["asn"]["AS13335"].bytes_out, 100);
+    }
+
+    #[test]
+    fn build_cache_upstream_snapshot_groups_by_zone() {
+        let mut hits = CacheCounters::new();
+        hits.update(7, 0, 0);
+        hits.update(7, 0, 0);
+        let mut misses = CacheCounters::new();
+        misses.update(1, 0, 0);
+        let api = upstream_key_bytes("shared", "api");
+        let web = upstream_key_bytes("shared", "web");
+        let entries: Vec<(&[u8], &CacheCounters)> = vec![
+            (api.as_slice(), &hits),
+            (web.as_slice(), &misses),
+            (b"no-separator".as_ref(), &hits),
+        ];
+        let snap = build_cache_upstream_snapshot(entries);
+        assert_eq!(snap.len(), 1);
+        assert_eq!(snap["shared"]["api"].hit, 2);
+        assert_eq!(snap["shared"]["web"].miss, 1);
+        assert_eq!(snap["shared"]["web"].hit, 0);
+    }
+
+    #[test]
+    fn build_grpc_status_snapshot_converts_entries() {
+        let mut counters = GrpcStatusCounters::default();
+        counters.record(b"14");
+        let entries: Vec<(&[u8], &GrpcStatusCounters)> =
+            vec![(b"grpc.test".as_ref(), &counters), (&[0xFF][..], &counters)];
+        let snap = build_grpc_status_snapshot(entries);
+        assert_eq!(snap.len(), 1);
+        assert_eq!(snap["grpc.test"].counts[14], 1);
+    }
+
+    #[test]
+    fn reinit_reuses_the_state_already_in_the_pool() {
+        let mut pool: ngx_slab_pool_t = unsafe { std::mem::zeroed() };
+        let mut zone: ngx_shm_zone_t = unsafe { std::mem::zeroed() };
+        let mut stale = 0u8;
+        let stale = &mut stale as *mut u8 as *mut c_void;
+
+        // Fresh (or resized) zone: nginx passes the old cycle's data,
+        // but the pool is empty, so the state must be built anew.
+        zone.data = stale;
+        assert_eq!(reattach_shared(&mut zone, &pool), None);
+        assert_eq!(zone.data, stale);
+
+        // Reload: the pool already holds a `VtsShared`.  Every init call
+        // hands back that same one, whatever nginx passed in.
+        let mut marker = 0u64;
+        let existing = &mut marker as *mut u64 as *mut VtsShared;
+        pool.data = existing as *mut c_void;
+        for _ in 0..2 {
+            assert_eq!(reattach_shared(&mut zone, &pool), Some(existing));
+            assert_eq!(zone.data, existing as *mut c_void);
+        }
+    }
+
+    #[test]
+    fn slab_usage_reads_free_pages_and_size_class_counters() {
+        let mut area = vec![0u8; 64 * 4096];
+        let mut stats: Vec<ngx_slab_stat_t> = (0..9)
+            .map(|i| ngx_slab_stat_t {
+                total: 0,
+                used: 0,
+                reqs: 10 * i,
+                fails: usize::from(i == 8),
+            })
+            .collect();
+        // SAFETY: every field of the pool is a plain integer or pointer.
+        let mut pool: ngx_slab_pool_t = unsafe { std::mem::zeroed() };
+        pool.start = area.as_mut_ptr();
+        pool.end = unsafe { area.as_mut_ptr().add(area.len()) };
+        pool.pfree = 16;
+        pool.min_shift = 3;
+        pool.stats = stats.as_mut_ptr();
+
+        // 4 KiB pages: size classes 8 B .. 2 KiB, i.e. 9 slots.
+        let usage = unsafe { SlabUsage::read(&pool, 12) };
+        assert_eq!(
+            usage,
+            SlabUsage {
+                total: 64 * 4096,
+                used: 48 * 4096,
+                free: 16 * 4096,
+                reqs: 360,
+                fails: 1,
+                nodes: 0,
+            }
+        );
+
+        // A pool without `stats` still reports its pages.
+        pool.stats = std::ptr::null_mut();
+        let usage = unsafe { SlabUsage::read(&pool, 12) };
+        assert_eq!((usage.free, usage.reqs), (16 * 4096, 0));
+    }
+
+    #[test]
+    fn near_full_warning_fires_once_below_the_threshold() {
+        let usage = |free: u64| SlabUsage {
+            total: 1000,
+            used: 1000 - free,
+            free,
+            ..SlabUsage::default()
+        };
+        assert!(!SlabUsage::default().is_near_full(100));
+        assert!(!usage(100).is_near_full(10));
+        assert!(usage(99).is_near_full(10));
+        assert!(usage(499).is_near_full(50));
+        assert!(!usage(500).is_near_full(50));
+
+        set_warn_threshold_percent(0);
+        assert_eq!(warn_threshold_percent(), DEFAULT_WARN_THRESHOLD_PERCENT);
+        assert!(!claim_near_full_warning(&usage(150)));
+        assert!(claim_near_full_warning(&usage(50)));
+        assert!(!claim_near_full_warning(&usage(40)));
+
+        // Reconfiguring re-arms the warning.
+        set_warn_threshold_percent(20);
+        assert!(claim_near_full_warning(&usage(150)));
+        set_warn_threshold_percent(0);
+    }
 }
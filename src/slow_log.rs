@@ -0,0 +1,95 @@
+//! `vts_slow_log_threshold`: flag upstream attempts whose request took
+//! longer than the threshold, so the C side can write one structured
+//! line to the error log for each.
+//!
+//! At most one line per upstream server per second: a server that turns
+//! slow would otherwise log every request it handles.  The state is per
+//! worker process.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+/// Shortest gap between two lines for the same upstream server.
+const LOG_INTERVAL_MS: u64 = 1000;
+
+/// Active `vts_slow_log_threshold` in milliseconds; 0 disables it.
+static THRESHOLD_MS: AtomicU64 = AtomicU64::new(0);
+
+/// When each `(upstream, server)` last got a line, in milliseconds
+/// since [`epoch`].
+static LAST_LOGGED: Mutex<Option<HashMap<(String, String), u64>>> = Mutex::new(None);
+
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+/// Set the threshold in milliseconds (0 = disabled) and forget when
+/// each server was last logged.
+pub fn set_threshold_ms(ms: u64) {
+    THRESHOLD_MS.store(ms, Ordering::Relaxed);
+    *LAST_LOGGED.lock().unwrap_or_else(crate::recover_poisoned) = None;
+}
+
+/// Whether an attempt at `server` of `upstream` whose request took
+/// `request_time_ms` should be logged as slow.
+pub fn check(upstream: &str, server: &str, request_time_ms: u64) -> bool {
+    check_at(
+        upstream,
+        server,
+        request_time_ms,
+        epoch().elapsed().as_millis() as u64,
+    )
+}
+
+fn check_at(upstream: &str, server: &str, request_time_ms: u64, now_ms: u64) -> bool {
+    let threshold = THRESHOLD_MS.load(Ordering::Relaxed);
+    if threshold == 0 || request_time_ms <= threshold {
+        return false;
+    }
+    let mut last_logged = LAST_LOGGED.lock().unwrap_or_else(crate::recover_poisoned);
+    let last_logged = last_logged.get_or_insert_with(HashMap::new);
+    let key = (upstream.to_string(), server.to_string());
+    match last_logged.get(&key) {
+        Some(&at) if now_ms.saturating_sub(at) < LOG_INTERVAL_MS => false,
+        _ => {
+            last_logged.insert(key, now_ms);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_over_threshold_once_per_second_per_server() {
+        set_threshold_ms(500);
+        let observations = [
+            // (server, request time, now) => logged
+            ("10.0.0.1:80", 500, 0, false),
+            ("10.0.0.1:80", 501, 0, true),
+            ("10.0.0.1:80", 900, 10, false),
+            ("10.0.0.2:80", 900, 10, true),
+            ("10.0.0.1:80", 900, 999, false),
+            ("10.0.0.1:80", 100, 1000, false),
+            ("10.0.0.1:80", 900, 1000, true),
+            ("10.0.0.2:80", 900, 2500, true),
+        ];
+        for (server, request_time, now, logged) in observations {
+            assert_eq!(
+                check_at("slow_backend", server, request_time, now),
+                logged,
+                "{server} {request_time}ms at {now}ms"
+            );
+        }
+        // Same address, other upstream: its own budget.
+        assert!(check_at("slow_other", "10.0.0.1:80", 900, 1001));
+
+        set_threshold_ms(0);
+        assert!(!check_at("slow_backend", "10.0.0.3:80", 60_000, 10_000));
+    }
+}
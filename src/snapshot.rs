@@ -0,0 +1,556 @@
+//! Compact binary snapshot of the VTS counters.
+//!
+//! A [`VtsSnapshot`] carries the raw storage-side counters
+//! ([`ServerCounters`], [`UpstreamCounters`], [`CacheCounters`]) plus
+//! the latest connection-state sample, so a consumer that merges
+//! several snapshots (e.g. one per worker, written to a file or pipe)
+//! keeps full fidelity — min/max/bucket values survive the trip
+//! instead of being flattened into the Prometheus-side `f64` views.
+//!
+//! The wire format is little-endian and hand-rolled (no serde):
+//!
+//! ```text
+//! header:      magic "VTSS" | version: u16 | reserved: u16
+//! connections: 6 × u64 (active, reading, writing, waiting, accepted, handled)
+//! servers:     count: u32, then per entry
+//!                name_len: u16 | name | 23 × u64
+//! upstreams:   count: u32, then per entry
+//!                upstream_len: u16 | upstream | server_len: u16 | server
+//!                | 17 × u64 | RESPONSE_TIME_BUCKET_COUNT × u64
+//! caches:      count: u32, then per entry
+//!                name_len: u16 | name | 13 × u64
+//! ```
+//!
+//! The per-zone in-flight gauges (`conn_reading` / `conn_writing` /
+//! `conn_upgraded`) describe the live process rather than history and
+//! are not encoded; they decode as zero.  Likewise the optional `latency-percentiles`
+//! histogram and the rolling error-rate window are not encoded and
+//! decode empty.
+//!
+//! [`SNAPSHOT_VERSION`] must be bumped whenever the layout changes so
+//! a reader can reject snapshots it doesn't understand.  Decoding
+//! never panics: truncated or corrupt input yields a [`SnapshotError`].
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::shm::{CacheCounters, ServerCounters, UpstreamCounters};
+use crate::stats::VtsConnectionStats;
+
+/// Leading magic bytes of every encoded snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"VTSS";
+
+/// Current wire-format version.
+pub const SNAPSHOT_VERSION: u16 = 13;
+
+/// Reasons [`VtsSnapshot::from_bytes`] can reject its input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// Input ended before a complete field could be read.
+    Truncated,
+    /// Input doesn't start with [`SNAPSHOT_MAGIC`].
+    BadMagic,
+    /// Header carries a version this build can't decode.
+    UnsupportedVersion(u16),
+    /// A zone or server name isn't valid UTF-8.
+    InvalidName,
+    /// Bytes left over after the last section.
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::BadMagic => write!(f, "snapshot has bad magic bytes"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {v}")
+            }
+            SnapshotError::InvalidName => write!(f, "snapshot contains a non-UTF-8 name"),
+            SnapshotError::TrailingBytes(n) => {
+                write!(f, "snapshot has {n} trailing bytes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// Point-in-time copy of every counter the module tracks.
+///
+/// Maps are `BTreeMap` so [`VtsSnapshot::to_bytes`] is deterministic:
+/// the same counters always encode to the same bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VtsSnapshot {
+    /// Latest connection-state sample.
+    pub connections: VtsConnectionStats,
+    /// Server-zone counters keyed by zone name.
+    pub servers: BTreeMap<String, ServerCounters>,
+    /// Upstream counters keyed by `(upstream, server)`.
+    pub upstreams: BTreeMap<(String, String), UpstreamCounters>,
+    /// Cache-zone counters keyed by cache zone name.
+    pub caches: BTreeMap<String, CacheCounters>,
+}
+
+impl VtsSnapshot {
+    /// Create an empty snapshot.
+    pub fn new() -> Self {
+        Self {
+            connections: VtsConnectionStats::default(),
+            servers: BTreeMap::new(),
+            upstreams: BTreeMap::new(),
+            caches: BTreeMap::new(),
+        }
+    }
+
+    /// Encode into the versioned little-endian wire format described
+    /// in the module docs.  Names longer than `u16::MAX` bytes cannot
+    /// come from nginx configuration and are skipped.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&SNAPSHOT_MAGIC);
+        out.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+
+        let c = &self.connections;
+        for v in [
+            c.active, c.reading, c.writing, c.waiting, c.accepted, c.handled,
+        ] {
+            put_u64(&mut out, v);
+        }
+
+        let servers: Vec<_> = self
+            .servers
+            .iter()
+            .filter(|(name, _)| name.len() <= u16::MAX as usize)
+            .collect();
+        out.extend_from_slice(&(servers.len() as u32).to_le_bytes());
+        for (name, s) in servers {
+            put_name(&mut out, name);
+            for v in [
+                s.requests,
+                s.bytes_in,
+                s.bytes_out,
+                s.header_bytes_out,
+                s.status_1xx,
+                s.status_2xx,
+                s.status_3xx,
+                s.status_4xx,
+                s.status_5xx,
+                s.request_time_total,
+                s.request_time_max,
+                s.request_time_min,
+                s.rate_limited,
+                s.apdex_satisfied,
+                s.apdex_tolerating,
+                s.apdex_frustrated,
+                s.subrequests,
+                s.client_body_timeouts,
+                s.client_body_malformed,
+                s.status_206,
+                s.status_304,
+                s.upgrades,
+                s.goodput_bytes_out,
+            ] {
+                put_u64(&mut out, v);
+            }
+        }
+
+        let upstreams: Vec<_> = self
+            .upstreams
+            .iter()
+            .filter(|((u, s), _)| u.len() <= u16::MAX as usize && s.len() <= u16::MAX as usize)
+            .collect();
+        out.extend_from_slice(&(upstreams.len() as u32).to_le_bytes());
+        for ((upstream, server), u) in upstreams {
+            put_name(&mut out, upstream);
+            put_name(&mut out, server);
+            for v in [
+                u.request_counter,
+                u.in_bytes,
+                u.out_bytes,
+                u.status_1xx,
+                u.status_2xx,
+                u.status_3xx,
+                u.status_4xx,
+                u.status_5xx,
+                u.no_response,
+                u.request_time_total,
+                u.request_time_counter,
+                u.response_time_total,
+                u.response_time_counter,
+                u.status_206,
+                u.status_304,
+                u.local_time_total,
+                u.local_time_clamped,
+            ] {
+                put_u64(&mut out, v);
+            }
+            for &b in &u.response_buckets {
+                put_u64(&mut out, b);
+            }
+        }
+
+        let caches: Vec<_> = self
+            .caches
+            .iter()
+            .filter(|(name, _)| name.len() <= u16::MAX as usize)
+            .collect();
+        out.extend_from_slice(&(caches.len() as u32).to_le_bytes());
+        for (name, c) in caches {
+            put_name(&mut out, name);
+            for v in [
+                c.miss,
+                c.bypass,
+                c.expired,
+                c.stale,
+                c.updating,
+                c.revalidated,
+                c.hit,
+                c.scarce,
+                c.max_size,
+                c.used_size,
+                c.bytes_served,
+                c.lock_waits,
+                c.lock_timeouts,
+            ] {
+                put_u64(&mut out, v);
+            }
+        }
+
+        out
+    }
+
+    /// Decode a snapshot produced by [`VtsSnapshot::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut r = Reader { buf: bytes };
+
+        if r.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let _reserved = r.u16()?;
+
+        let mut snap = Self::new();
+        snap.connections = VtsConnectionStats {
+            active: r.u64()?,
+            reading: r.u64()?,
+            writing: r.u64()?,
+            waiting: r.u64()?,
+            accepted: r.u64()?,
+            handled: r.u64()?,
+        };
+
+        for _ in 0..r.u32()? {
+            let name = r.name()?;
+            let counters = ServerCounters {
+                requests: r.u64()?,
+                bytes_in: r.u64()?,
+                bytes_out: r.u64()?,
+                header_bytes_out: r.u64()?,
+                status_1xx: r.u64()?,
+                status_2xx: r.u64()?,
+                status_3xx: r.u64()?,
+                status_4xx: r.u64()?,
+                status_5xx: r.u64()?,
+                request_time_total: r.u64()?,
+                request_time_max: r.u64()?,
+                request_time_min: r.u64()?,
+                rate_limited: r.u64()?,
+                apdex_satisfied: r.u64()?,
+                apdex_tolerating: r.u64()?,
+                apdex_frustrated: r.u64()?,
+                subrequests: r.u64()?,
+                client_body_timeouts: r.u64()?,
+                client_body_malformed: r.u64()?,
+                status_206: r.u64()?,
+                status_304: r.u64()?,
+                upgrades: r.u64()?,
+                goodput_bytes_out: r.u64()?,
+                ..ServerCounters::new()
+            };
+            snap.servers.insert(name, counters);
+        }
+
+        for _ in 0..r.u32()? {
+            let upstream = r.name()?;
+            let server = r.name()?;
+            let mut counters = UpstreamCounters::new();
+            counters.request_counter = r.u64()?;
+            counters.in_bytes = r.u64()?;
+            counters.out_bytes = r.u64()?;
+            counters.status_1xx = r.u64()?;
+            counters.status_2xx = r.u64()?;
+            counters.status_3xx = r.u64()?;
+            counters.status_4xx = r.u64()?;
+            counters.status_5xx = r.u64()?;
+            counters.no_response = r.u64()?;
+            counters.request_time_total = r.u64()?;
+            counters.request_time_counter = r.u64()?;
+            counters.response_time_total = r.u64()?;
+            counters.response_time_counter = r.u64()?;
+            counters.status_206 = r.u64()?;
+            counters.status_304 = r.u64()?;
+            counters.local_time_total = r.u64()?;
+            counters.local_time_clamped = r.u64()?;
+            for bucket in counters.response_buckets.iter_mut() {
+                *bucket = r.u64()?;
+            }
+            snap.upstreams.insert((upstream, server), counters);
+        }
+
+        for _ in 0..r.u32()? {
+            let name = r.name()?;
+            let counters = CacheCounters {
+                miss: r.u64()?,
+                bypass: r.u64()?,
+                expired: r.u64()?,
+                stale: r.u64()?,
+                updating: r.u64()?,
+                revalidated: r.u64()?,
+                hit: r.u64()?,
+                scarce: r.u64()?,
+                max_size: r.u64()?,
+                used_size: r.u64()?,
+                bytes_served: r.u64()?,
+                lock_waits: r.u64()?,
+                lock_timeouts: r.u64()?,
+            };
+            snap.caches.insert(name, counters);
+        }
+
+        if !r.buf.is_empty() {
+            return Err(SnapshotError::TrailingBytes(r.buf.len()));
+        }
+        Ok(snap)
+    }
+}
+
+impl Default for VtsSnapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn put_u64(out: &mut Vec<u8>, v: u64) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) {
+    out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    out.extend_from_slice(name.as_bytes());
+}
+
+/// Bounds-checked cursor over the encoded bytes.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.buf.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut a = [0u8; 8];
+        a.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(a))
+    }
+
+    fn name(&mut self) -> Result<String, SnapshotError> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        std::str::from_utf8(bytes)
+            .map(str::to_string)
+            .map_err(|_| SnapshotError::InvalidName)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn populated_snapshot() -> VtsSnapshot {
+        let mut snap = VtsSnapshot::new();
+        snap.connections = VtsConnectionStats {
+            active: 7,
+            reading: 1,
+            writing: 2,
+            waiting: 4,
+            accepted: 1000,
+            handled: 999,
+        };
+
+        let mut s1 = ServerCounters::new();
+        s1.update(200, 100, 1000, 50);
+        s1.update(404, 50, 80, 10);
+        let mut s2 = ServerCounters::new();
+        s2.update(503, 10, 20, 900);
+        s2.update_rate_limited(10, 0, 1, 1);
+        s2.add_subrequest();
+        s2.add_client_body_error(408);
+        snap.servers.insert("example.com".into(), s1);
+        snap.servers.insert("api.example.com".into(), s2);
+        // Never-updated zone keeps the `u64::MAX` min sentinel.
+        snap.servers
+            .insert("idle.example.com".into(), ServerCounters::new());
+
+        let mut u1 = UpstreamCounters::new();
+        u1.update(100, 50, 1000, 500, 200);
+        u1.update(120, 3000, 1200, 600, 502);
+        let mut u2 = UpstreamCounters::new();
+        u2.update(80, 4, 800, 400, 200);
+        u2.update(30000, 30000, 800, 0, 0);
+        snap.upstreams
+            .insert(("backend".into(), "10.0.0.1:80".into()), u1);
+        snap.upstreams
+            .insert(("api".into(), "10.0.1.1:8080".into()), u2);
+
+        let mut c1 = CacheCounters::new();
+        c1.update(7, 10 * 1024 * 1024, 512 * 1024);
+        c1.add_bytes_served(7, 4096);
+        c1.update(1, 10 * 1024 * 1024, 600 * 1024);
+        c1.add_lock_wait(false);
+        c1.add_lock_wait(true);
+        let mut c2 = CacheCounters::new();
+        c2.update(2, 0, 0);
+        snap.caches.insert("static".into(), c1);
+        snap.caches.insert("api_cache".into(), c2);
+        snap
+    }
+
+    #[test]
+    fn empty_snapshot_round_trips() {
+        let snap = VtsSnapshot::new();
+        let bytes = snap.to_bytes();
+        // header (8) + connections (48) + three zero counts (12).
+        assert_eq!(bytes.len(), 8 + 48 + 12);
+        assert_eq!(VtsSnapshot::from_bytes(&bytes).unwrap(), snap);
+    }
+
+    #[test]
+    fn populated_snapshot_round_trips() {
+        let mut snap = populated_snapshot();
+        // The latency histogram and error window are deliberately not
+        // encoded.
+        for counters in snap.upstreams.values_mut() {
+            #[cfg(feature = "latency-percentiles")]
+            {
+                counters.latency = crate::latency::LatencyHistogram::new();
+            }
+            counters.error_window = Default::default();
+        }
+        let decoded = VtsSnapshot::from_bytes(&snap.to_bytes()).unwrap();
+        assert_eq!(decoded, snap);
+
+        let backend = decoded
+            .upstreams
+            .get(&("backend".to_string(), "10.0.0.1:80".to_string()))
+            .unwrap();
+        assert_eq!(backend.request_counter, 2);
+        assert_eq!(backend.status_5xx, 1);
+        assert_eq!(backend.response_buckets[3], 1); // le=50 → {50}
+        assert_eq!(decoded.caches["static"].used_size, 600 * 1024);
+        assert_eq!(
+            decoded.servers["idle.example.com"].request_time_min,
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn encoding_is_deterministic() {
+        assert_eq!(
+            populated_snapshot().to_bytes(),
+            populated_snapshot().to_bytes()
+        );
+    }
+
+    #[test]
+    fn header_carries_magic_and_version() {
+        let bytes = VtsSnapshot::new().to_bytes();
+        assert_eq!(&bytes[..4], b"VTSS");
+        assert_eq!(u16::from_le_bytes([bytes[4], bytes[5]]), SNAPSHOT_VERSION);
+    }
+
+    #[test]
+    fn every_truncation_is_rejected_without_panicking() {
+        let bytes = populated_snapshot().to_bytes();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                VtsSnapshot::from_bytes(&bytes[..len]),
+                Err(SnapshotError::Truncated),
+                "prefix of length {len} should be truncated"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut bytes = VtsSnapshot::new().to_bytes();
+        bytes[0] = b'X';
+        assert_eq!(
+            VtsSnapshot::from_bytes(&bytes),
+            Err(SnapshotError::BadMagic)
+        );
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let mut bytes = VtsSnapshot::new().to_bytes();
+        bytes[4..6].copy_from_slice(&99u16.to_le_bytes());
+        assert_eq!(
+            VtsSnapshot::from_bytes(&bytes),
+            Err(SnapshotError::UnsupportedVersion(99))
+        );
+    }
+
+    #[test]
+    fn corrupt_name_and_trailing_bytes_are_rejected() {
+        let mut snap = VtsSnapshot::new();
+        snap.servers.insert("ab".into(), ServerCounters::new());
+        let mut bytes = snap.to_bytes();
+        // Server name starts right after header + connections + count + len.
+        let name_at = 8 + 48 + 4 + 2;
+        bytes[name_at] = 0xFF;
+        assert_eq!(
+            VtsSnapshot::from_bytes(&bytes),
+            Err(SnapshotError::InvalidName)
+        );
+
+        let mut bytes = VtsSnapshot::new().to_bytes();
+        bytes.extend_from_slice(&[0, 0, 0]);
+        assert_eq!(
+            VtsSnapshot::from_bytes(&bytes),
+            Err(SnapshotError::TrailingBytes(3))
+        );
+    }
+
+    #[test]
+    fn huge_count_with_short_input_is_truncated_not_oom() {
+        let mut bytes = VtsSnapshot::new().to_bytes();
+        let servers_count_at = 8 + 48;
+        bytes[servers_count_at..servers_count_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            VtsSnapshot::from_bytes(&bytes),
+            Err(SnapshotError::Truncated)
+        );
+    }
+}
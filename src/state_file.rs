@@ -0,0 +1,268 @@
+//! `vts_state_file`: keep the counters across a full restart.
+//!
+//! The shared `vts_zone` survives `nginx -s reload` but not a stop and
+//! start.  With a state file configured, the first worker writes a
+//! [`VtsSnapshot`] of the server, upstream and cache counters in
+//! `exit_process` and merges the file back in `init_process`.  With a
+//! `vts_zone` the merge happens once per newly created zone, so a
+//! reload or a respawned worker does not add the same history twice;
+//! without one it goes into that worker's process-local counters.
+//!
+//! Only a full stop and start carries process-local counters over.  On
+//! a reload the new first worker's `init_process` runs while the old
+//! one is still serving, so it loads the file from the previous stop,
+//! and the old worker's counters since then are written after it and
+//! overwritten when the new worker exits.
+//!
+//! A missing file means a first start.  A file that cannot be read or
+//! decoded is ignored (the caller logs a warning) and the counters
+//! start from zero.  Connection gauges, location zones, method × status
+//! cross-tabs and the rolling upstream error window are not persisted.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::shm::{CacheCounters, ServerCounters, UpstreamCounters};
+use crate::snapshot::VtsSnapshot;
+use crate::upstream_stats::{UpstreamServerStats, UpstreamZone};
+
+/// Path from `vts_state_file`, if configured.
+static CONFIGURED_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Set (or, with `None`, clear) the `vts_state_file` path.
+pub fn set_path(path: Option<PathBuf>) {
+    *CONFIGURED_PATH
+        .lock()
+        .unwrap_or_else(crate::recover_poisoned) = path;
+}
+
+fn configured_path() -> Option<PathBuf> {
+    CONFIGURED_PATH
+        .lock()
+        .unwrap_or_else(crate::recover_poisoned)
+        .clone()
+}
+
+/// Write `snap` to `path`.  The bytes go to a temporary file next to it
+/// first and are renamed into place, so a crash mid-write leaves the
+/// previous state file intact.
+pub fn save(path: &Path, snap: &VtsSnapshot) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, snap.to_bytes())?;
+    fs::rename(&tmp, path)
+}
+
+/// Read a snapshot back from `path`.  `Ok(None)` when the file does not
+/// exist; an error message when it cannot be read or decoded.
+pub fn load(path: &Path) -> Result<Option<VtsSnapshot>, String> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("cannot read \"{}\": {e}", path.display())),
+    };
+    VtsSnapshot::from_bytes(&bytes)
+        .map(Some)
+        .map_err(|e| format!("ignoring \"{}\": {e}", path.display()))
+}
+
+/// Snapshot the live counters: the shared zone when configured,
+/// otherwise the process-local managers.
+pub fn capture() -> VtsSnapshot {
+    if let Some(snap) = crate::shm::capture_snapshot() {
+        return snap;
+    }
+    let mut snap = VtsSnapshot::new();
+    {
+        let manager = crate::VTS_MANAGER
+            .read()
+            .unwrap_or_else(crate::recover_poisoned);
+        snap.connections = manager.get_connection_stats().clone();
+        for (name, counters) in &manager.stats {
+            snap.servers.insert(name.clone(), *counters);
+        }
+        for (upstream, zone) in manager.get_all_upstream_zones() {
+            for (server, stats) in &zone.servers {
+                snap.upstreams.insert(
+                    (upstream.clone(), server.clone()),
+                    UpstreamCounters::from_stats(stats),
+                );
+            }
+        }
+    }
+    crate::for_each_cache_zone(|name, stats| {
+        snap.caches
+            .insert(name.to_string(), CacheCounters::from_stats(stats));
+    });
+    snap
+}
+
+/// Add every counter in `snap` to the live counters, in the same store
+/// [`capture`] reads.
+pub fn restore(snap: &VtsSnapshot) {
+    if crate::shm::merge_snapshot(snap) {
+        return;
+    }
+    {
+        let mut manager = crate::VTS_MANAGER
+            .write()
+            .unwrap_or_else(crate::recover_poisoned);
+        for (name, saved) in &snap.servers {
+            manager
+                .stats
+                .entry(name.clone())
+                .or_insert_with(ServerCounters::new)
+                .merge(saved);
+        }
+        for ((upstream, server), saved) in &snap.upstreams {
+            let stats = manager
+                .upstream_zones
+                .entry(upstream.clone())
+                .or_insert_with(|| UpstreamZone::new(upstream))
+                .get_or_create_server(server);
+            let mut merged = UpstreamCounters::from_stats(stats);
+            merged.merge(saved);
+            // Keep the server's configuration, as `reset_counters` does.
+            *stats = UpstreamServerStats {
+                weight: stats.weight,
+                max_fails: stats.max_fails,
+                fail_timeout: stats.fail_timeout,
+                backup: stats.backup,
+                down: stats.down,
+                ..merged.into_stats(server)
+            };
+        }
+    }
+    for (zone, saved) in &snap.caches {
+        crate::CACHE_MANAGER.update_zone(zone, |stats| {
+            let mut merged = CacheCounters::from_stats(stats);
+            merged.merge(saved);
+            *stats = merged.into_stats(zone);
+        });
+    }
+}
+
+/// `init_process` half: merge the configured state file into the live
+/// counters, unless there is none or a `vts_zone` already had it
+/// merged.  An error means the file was ignored.
+pub fn load_configured() -> Result<(), String> {
+    let Some(path) = configured_path() else {
+        return Ok(());
+    };
+    if crate::shm::claim_state_restore() == Some(false) {
+        return Ok(());
+    }
+    if let Some(snap) = load(&path)? {
+        restore(&snap);
+    }
+    Ok(())
+}
+
+/// `exit_process` half: write the live counters to the configured
+/// state file, if any.
+pub fn save_configured() -> Result<(), String> {
+    let Some(path) = configured_path() else {
+        return Ok(());
+    };
+    save(&path, &capture()).map_err(|e| format!("cannot write \"{}\": {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vts-test-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn save_then_load_round_trips() {
+        let mut snap = VtsSnapshot::new();
+        let mut server = ServerCounters::new();
+        server.update(200, 100, 1000, 50);
+        snap.servers.insert("example.com".into(), server);
+        let mut upstream = UpstreamCounters::new();
+        upstream.update(100, 50, 1000, 500, 502);
+        snap.upstreams
+            .insert(("backend".into(), "10.0.0.1:80".into()), upstream);
+
+        let path = temp_path("state.bin");
+        save(&path, &snap).unwrap();
+        let loaded = load(&path).unwrap().expect("state file exists");
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.servers, snap.servers);
+        assert_eq!(
+            loaded.upstreams[&("backend".to_string(), "10.0.0.1:80".to_string())].status_5xx,
+            1
+        );
+    }
+
+    #[test]
+    fn missing_file_is_a_fresh_start_and_corrupt_file_is_ignored() {
+        let path = temp_path("corrupt.bin");
+        assert_eq!(load(&path), Ok(None));
+
+        fs::write(&path, b"not a snapshot").unwrap();
+        let err = load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err.contains("bad magic"), "{err}");
+    }
+
+    #[test]
+    fn reload_loads_the_file_before_the_old_worker_saves_it() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let start_worker = || {
+            *crate::VTS_MANAGER
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                crate::vts_node::VtsStatsManager::new();
+            load_configured().unwrap();
+        };
+        let record = |n| {
+            let mut manager = crate::VTS_MANAGER
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            for _ in 0..n {
+                manager.update_server_stats("example.com", 200, 100, 1000, 50);
+            }
+        };
+        let requests = || {
+            crate::VTS_MANAGER
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .get_all_server_stats()
+                .get("example.com")
+                .map_or(0, |stats| stats.requests)
+        };
+        let path = temp_path("reload.bin");
+        set_path(Some(path.clone()));
+
+        // The old worker counts two requests.
+        start_worker();
+        record(2);
+        let old_worker = capture();
+
+        // Reload: the new worker loads before the old one has saved.
+        start_worker();
+        assert_eq!(requests(), 0);
+        save(&path, &old_worker).unwrap();
+        record(1);
+        save_configured().unwrap();
+
+        // Only the new worker's request makes it past the next start.
+        start_worker();
+        assert_eq!(requests(), 1);
+
+        set_path(None);
+        fs::remove_file(&path).unwrap();
+        *crate::VTS_MANAGER
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+            crate::vts_node::VtsStatsManager::new();
+    }
+}
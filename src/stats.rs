@@ -6,6 +6,9 @@
 //! formatter reads them.  Field shapes match what
 //! `nginx_vts_server_*` metrics need.
 
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
 /// Per-status-class response counters.
 #[derive(Debug, Clone, Default)]
 pub struct VtsResponseStats {
@@ -19,15 +22,17 @@ pub struct VtsResponseStats {
     pub status_4xx: u64,
     /// 5xx responses.
     pub status_5xx: u64,
+    /// 206 Partial Content responses, also counted in `status_2xx`.
+    pub status_206: u64,
+    /// 304 Not Modified responses, also counted in `status_3xx`.
+    pub status_304: u64,
 }
 
 /// Request-time aggregate (in seconds).
 #[derive(Debug, Clone, Default)]
 pub struct VtsRequestTimes {
-    /// Sum of all observed request times.  Populated by the storage
-    /// layers; reserved for a future `..._seconds_sum` Prometheus
-    /// metric and otherwise unread today.
-    #[allow(dead_code)]
+    /// Sum of all observed request times, rendered as
+    /// `nginx_vts_server_request_seconds_sum`.
     pub total: f64,
     /// Minimum observed request time (`0.0` if no requests yet).
     pub min: f64,
@@ -37,6 +42,291 @@ pub struct VtsRequestTimes {
     pub avg: f64,
 }
 
+/// Requests bucketed by request time against the Apdex threshold T
+/// (`vts_apdex_threshold`): at most T, at most 4T, and slower.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VtsApdexStats {
+    /// Request time `<= T`.
+    pub satisfied: u64,
+    /// Request time `> T` and `<= 4T`.
+    pub tolerating: u64,
+    /// Request time `> 4T`.
+    pub frustrated: u64,
+}
+
+impl VtsApdexStats {
+    /// `(satisfied + tolerating / 2) / total`, or `None` before the
+    /// first request (no traffic is not a perfect score).
+    pub fn score(&self) -> Option<f64> {
+        let total = self.satisfied + self.tolerating + self.frustrated;
+        if total == 0 {
+            return None;
+        }
+        Some((self.satisfied as f64 + self.tolerating as f64 / 2.0) / total as f64)
+    }
+}
+
+/// Request methods broken out by `vts_detail_method_status`.  Anything
+/// else (WebDAV, TRACE, unknown) is `Other`, which bounds the series
+/// count per zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpMethod {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Other,
+}
+
+impl HttpMethod {
+    /// Every method, in output order.
+    pub const ALL: [HttpMethod; 8] = [
+        Self::Get,
+        Self::Head,
+        Self::Post,
+        Self::Put,
+        Self::Delete,
+        Self::Patch,
+        Self::Options,
+        Self::Other,
+    ];
+
+    /// Map nginx's `r->method` bit (`NGX_HTTP_GET` etc. from
+    /// `ngx_http_request.h`).
+    pub fn from_ngx(method: u64) -> Self {
+        match method {
+            0x0002 => Self::Get,
+            0x0004 => Self::Head,
+            0x0008 => Self::Post,
+            0x0010 => Self::Put,
+            0x0020 => Self::Delete,
+            0x0200 => Self::Options,
+            0x4000 => Self::Patch,
+            _ => Self::Other,
+        }
+    }
+
+    /// Label value.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Patch => "PATCH",
+            Self::Options => "OPTIONS",
+            Self::Other => "OTHER",
+        }
+    }
+}
+
+/// Status-class labels, indexing the inner arrays of
+/// [`MethodStatusCounters`].
+pub const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+/// Requests of one server zone by method and status class
+/// (`vts_detail_method_status`).  Fixed-size so it can live in the
+/// shared zone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MethodStatusCounters {
+    /// `counts[method][class]`, indexed like [`HttpMethod::ALL`] and
+    /// [`STATUS_CLASSES`].
+    pub counts: [[u64; 5]; 8],
+}
+
+impl MethodStatusCounters {
+    /// Count one response.  Statuses outside `100..=599` are ignored.
+    pub fn record(&mut self, method: HttpMethod, status: u16) {
+        if !(100..=599).contains(&status) {
+            return;
+        }
+        self.counts[method as usize][usize::from(status / 100 - 1)] += 1;
+    }
+
+    /// Count for one method and status class (`"2xx"` etc.).
+    pub fn get(&self, method: HttpMethod, class: &str) -> u64 {
+        STATUS_CLASSES
+            .iter()
+            .position(|c| *c == class)
+            .map_or(0, |i| self.counts[method as usize][i])
+    }
+}
+
+/// Top-level response types broken out by `vts_track_content_type`,
+/// indexing [`ContentTypeCounters::counts`].  Anything else, and
+/// responses without a Content-Type, are `other`.
+pub const CONTENT_TYPES: [&str; 4] = ["application", "image", "text", "other"];
+
+/// Responses of one server zone by the top-level type of their
+/// Content-Type (`vts_track_content_type`).  Fixed-size so it can live
+/// in the shared zone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContentTypeCounters {
+    /// Indexed like [`CONTENT_TYPES`].
+    pub counts: [u64; 4],
+}
+
+impl ContentTypeCounters {
+    /// Index into [`CONTENT_TYPES`] of a `Content-Type` value: the part
+    /// before `/`, compared case-insensitively.
+    pub fn classify(content_type: &[u8]) -> usize {
+        let primary = content_type
+            .split(|&b| b == b'/' || b == b';')
+            .next()
+            .unwrap_or_default()
+            .trim_ascii();
+        CONTENT_TYPES[..3]
+            .iter()
+            .position(|kind| primary.eq_ignore_ascii_case(kind.as_bytes()))
+            .unwrap_or(3)
+    }
+
+    /// Count one response with this `Content-Type` value.
+    pub fn record(&mut self, content_type: &[u8]) {
+        self.counts[Self::classify(content_type)] += 1;
+    }
+}
+
+/// Canonical gRPC status code names, indexed by code, as broken out by
+/// `vts_track_grpc`.
+pub const GRPC_STATUS_NAMES: [&str; 17] = [
+    "OK",
+    "CANCELLED",
+    "UNKNOWN",
+    "INVALID_ARGUMENT",
+    "DEADLINE_EXCEEDED",
+    "NOT_FOUND",
+    "ALREADY_EXISTS",
+    "PERMISSION_DENIED",
+    "RESOURCE_EXHAUSTED",
+    "FAILED_PRECONDITION",
+    "ABORTED",
+    "OUT_OF_RANGE",
+    "UNIMPLEMENTED",
+    "INTERNAL",
+    "UNAVAILABLE",
+    "DATA_LOSS",
+    "UNAUTHENTICATED",
+];
+
+/// Index of `UNKNOWN` in [`GRPC_STATUS_NAMES`], where values outside
+/// the canonical codes are counted.
+const GRPC_STATUS_UNKNOWN: usize = 2;
+
+/// Responses of one server zone by their `grpc-status`
+/// (`vts_track_grpc`), apart from the HTTP status that wraps them.
+/// Fixed-size so it can live in the shared zone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GrpcStatusCounters {
+    /// Indexed by code, like [`GRPC_STATUS_NAMES`].
+    pub counts: [u64; 17],
+}
+
+impl GrpcStatusCounters {
+    /// Index into [`GRPC_STATUS_NAMES`] of a `grpc-status` value, or
+    /// `None` when there is none (not a gRPC response).  Codes that are
+    /// not decimal or not canonical count as `UNKNOWN`, as the gRPC spec
+    /// asks of clients.
+    pub fn classify(grpc_status: &[u8]) -> Option<usize> {
+        let value = grpc_status.trim_ascii();
+        if value.is_empty() {
+            return None;
+        }
+        let code = std::str::from_utf8(value)
+            .ok()
+            .filter(|v| v.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|&code| code < GRPC_STATUS_NAMES.len());
+        Some(code.unwrap_or(GRPC_STATUS_UNKNOWN))
+    }
+
+    /// Count one response with this `grpc-status` value; responses
+    /// without one are ignored.
+    pub fn record(&mut self, grpc_status: &[u8]) {
+        if let Some(code) = Self::classify(grpc_status) {
+            self.counts[code] += 1;
+        }
+    }
+}
+
+/// Cumulative bucket upper bounds for the request-header size
+/// histogram, in bytes.  The top bounds straddle nginx's default
+/// `large_client_header_buffers` (4 × 8k).
+pub const REQUEST_HEADER_BYTES_BUCKETS: [u64; 7] = [512, 1024, 2048, 4096, 8192, 16384, 32768];
+
+/// Cumulative bucket upper bounds for the request-header count
+/// histogram.
+pub const REQUEST_HEADER_COUNT_BUCKETS: [u64; 6] = [4, 8, 16, 32, 64, 128];
+
+/// Request-header size and count histograms of one server zone.
+/// Fixed-size so it can live in the shared zone; buckets are
+/// cumulative like `response_buckets`, `+Inf` being `requests`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestHeaderStats {
+    /// Requests observed.
+    pub requests: u64,
+    /// Requests whose headers took at most
+    /// `REQUEST_HEADER_BYTES_BUCKETS[i]` bytes.
+    pub bytes_buckets: [u64; 7],
+    /// Sum of all header sizes, in bytes.
+    pub bytes_sum: u64,
+    /// Requests with at most `REQUEST_HEADER_COUNT_BUCKETS[i]` headers.
+    pub count_buckets: [u64; 6],
+    /// Sum of all header counts.
+    pub count_sum: u64,
+}
+
+impl RequestHeaderStats {
+    /// Count one request carrying `count` headers totalling `bytes`
+    /// (request line and CRLFs included).
+    pub fn record(&mut self, count: u64, bytes: u64) {
+        self.requests += 1;
+        self.bytes_sum += bytes;
+        self.count_sum += count;
+        for (bucket, &bound) in self
+            .bytes_buckets
+            .iter_mut()
+            .zip(&REQUEST_HEADER_BYTES_BUCKETS)
+        {
+            if bytes <= bound {
+                *bucket += 1;
+            }
+        }
+        for (bucket, &bound) in self
+            .count_buckets
+            .iter_mut()
+            .zip(&REQUEST_HEADER_COUNT_BUCKETS)
+        {
+            if count <= bound {
+                *bucket += 1;
+            }
+        }
+    }
+
+    /// Both histograms as `(family, bounds, buckets, sum)`, family
+    /// names without the `nginx_vts_` prefix.
+    pub fn histograms(&self) -> [(&'static str, &'static [u64], &[u64], u64); 2] {
+        [
+            (
+                "server_request_header_bytes",
+                &REQUEST_HEADER_BYTES_BUCKETS,
+                &self.bytes_buckets,
+                self.bytes_sum,
+            ),
+            (
+                "server_request_headers",
+                &REQUEST_HEADER_COUNT_BUCKETS,
+                &self.count_buckets,
+                self.count_sum,
+            ),
+        ]
+    }
+}
+
 /// Snapshot of one server zone (`server_name` from the matched
 /// server block).  Aggregates everything the formatter needs to
 /// render `nginx_vts_server_*` metrics for a single zone.
@@ -48,15 +338,144 @@ pub struct VtsServerStats {
     pub bytes_in: u64,
     /// Bytes sent to clients.
     pub bytes_out: u64,
+    /// Part of `bytes_out` that was response headers; the rest is
+    /// [`body_bytes_out`](Self::body_bytes_out).
+    pub header_bytes_out: u64,
+    /// Part of `bytes_out` sent with successful (2xx and 3xx)
+    /// responses.
+    pub goodput_bytes_out: u64,
     /// Per-status-class response breakdown.
     pub responses: VtsResponseStats,
+    /// Requests rejected by `limit_req` / `limit_conn` (not part of
+    /// `responses`).
+    pub rate_limited: u64,
     /// Request-time aggregate.
     pub request_times: VtsRequestTimes,
+    /// Apdex buckets for `nginx_vts_server_apdex`.
+    pub apdex: VtsApdexStats,
+    /// Subrequests logged in this zone (not part of `requests`).
+    pub subrequests: u64,
+    /// Requests that failed reading the client body with a 408
+    /// (`client_body_timeout`).
+    pub client_body_timeouts: u64,
+    /// Requests that failed reading the client body with a 400
+    /// (malformed chunked encoding, truncated body, …).
+    pub client_body_malformed: u64,
+    /// 101 Switching Protocols responses (not part of `responses`).
+    pub upgrades: u64,
+    /// In-flight requests currently handled by this zone.
+    pub connections: VtsServerConnections,
+}
+
+impl VtsServerStats {
+    /// Response body bytes: `bytes_out` less the headers.
+    pub fn body_bytes_out(&self) -> u64 {
+        self.bytes_out.saturating_sub(self.header_bytes_out)
+    }
+
+    /// Fold another zone's view into this one, as if both zones had
+    /// been one: counters and gauges add up, the request-time extremes
+    /// widen and the mean is recomputed.
+    pub fn merge(&mut self, other: &Self) {
+        let times = &mut self.request_times;
+        let theirs = &other.request_times;
+        times.min = match (self.requests, other.requests) {
+            (0, _) => theirs.min,
+            (_, 0) => times.min,
+            _ => times.min.min(theirs.min),
+        };
+        times.max = times.max.max(theirs.max);
+        times.total += theirs.total;
+
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.header_bytes_out += other.header_bytes_out;
+        self.goodput_bytes_out += other.goodput_bytes_out;
+        self.responses.status_1xx += other.responses.status_1xx;
+        self.responses.status_2xx += other.responses.status_2xx;
+        self.responses.status_3xx += other.responses.status_3xx;
+        self.responses.status_206 += other.responses.status_206;
+        self.responses.status_304 += other.responses.status_304;
+        self.responses.status_4xx += other.responses.status_4xx;
+        self.responses.status_5xx += other.responses.status_5xx;
+        self.rate_limited += other.rate_limited;
+        self.apdex.satisfied += other.apdex.satisfied;
+        self.apdex.tolerating += other.apdex.tolerating;
+        self.apdex.frustrated += other.apdex.frustrated;
+        self.subrequests += other.subrequests;
+        self.client_body_timeouts += other.client_body_timeouts;
+        self.client_body_malformed += other.client_body_malformed;
+        self.upgrades += other.upgrades;
+        self.connections.active += other.connections.active;
+        self.connections.reading += other.connections.reading;
+        self.connections.writing += other.connections.writing;
+        self.connections.upgraded += other.connections.upgraded;
+
+        times.avg = if self.requests > 0 {
+            times.total / self.requests as f64
+        } else {
+            0.0
+        };
+    }
+}
+
+/// Rename server zones for output (`vts_zone_alias`): each zone listed
+/// in `aliases` is reported under its alias, merged with any other
+/// zone that ends up with the same name.
+pub fn alias_server_zones(
+    zones: impl IntoIterator<Item = (String, VtsServerStats)>,
+    aliases: &HashMap<String, String>,
+) -> HashMap<String, VtsServerStats> {
+    let mut out: HashMap<String, VtsServerStats> = HashMap::new();
+    for (zone, stats) in zones {
+        let name = aliases.get(&zone).cloned().unwrap_or(zone);
+        match out.entry(name) {
+            Entry::Vacant(slot) => {
+                slot.insert(stats);
+            }
+            Entry::Occupied(mut slot) => slot.get_mut().merge(&stats),
+        }
+    }
+    out
+}
+
+/// Distinct keys one `vts_filter_by_variable` group tracks; requests
+/// with any further key are counted under [`FILTER_OTHER_KEY`].
+pub const MAX_FILTER_KEYS: usize = 100;
+
+/// Filter key that collects a group's requests once it is full.
+pub const FILTER_OTHER_KEY: &str = "__other__";
+
+/// The key to count a request under in a filter group that already
+/// has `known` distinct keys, none of them `key`: `key` itself while
+/// the group has room, [`FILTER_OTHER_KEY`] after.
+pub fn admit_filter_key(key: &str, known: usize) -> &str {
+    if known < MAX_FILTER_KEYS {
+        key
+    } else {
+        FILTER_OTHER_KEY
+    }
+}
+
+/// Per-zone in-flight request gauges rendered as
+/// `nginx_vts_server_connections{zone,state}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VtsServerConnections {
+    /// `reading + writing + upgraded`.
+    pub active: u64,
+    /// Accepted by the zone, response header not yet sent.
+    pub reading: u64,
+    /// Response header sent, body still being written.
+    pub writing: u64,
+    /// Switched protocols (101) and still open, rendered apart as
+    /// `nginx_vts_server_websocket_connections{zone}`.
+    pub upgraded: u64,
 }
 
 /// Connection-state snapshot used by the Prometheus
 /// `nginx_vts_connections` series.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct VtsConnectionStats {
     /// Currently active connections.
     pub active: u64,
@@ -71,3 +490,63 @@ pub struct VtsConnectionStats {
     /// Total handled connections.
     pub handled: u64,
 }
+
+/// Phase of a QUIC connection as seen by `nginx_vts_quic_connections`.
+/// The raw values match the `NGX_HTTP_VTS_QUIC_*` constants the QUIC
+/// hooks pass to `vts_track_quic_connection`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuicPhase {
+    /// Not (or no longer) counted.
+    Idle,
+    /// Initial packet accepted, handshake not yet complete.
+    Handshaking,
+    /// Handshake complete.  A migrated connection stays here: it is
+    /// the same connection on a new path.
+    Established,
+}
+
+impl QuicPhase {
+    /// Decode the raw value passed across the FFI boundary.
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        match raw {
+            0 => Some(QuicPhase::Idle),
+            1 => Some(QuicPhase::Handshaking),
+            2 => Some(QuicPhase::Established),
+            _ => None,
+        }
+    }
+}
+
+/// QUIC connection gauges and counters, kept apart from
+/// [`VtsConnectionStats`] because nginx's own connection counters don't
+/// see QUIC the same way (one UDP socket, many connections).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VtsQuicStats {
+    /// Connections in [`QuicPhase::Handshaking`].
+    pub handshaking: u64,
+    /// Connections in [`QuicPhase::Established`].
+    pub established: u64,
+    /// Connections whose 0-RTT early data was accepted.
+    pub zero_rtt: u64,
+}
+
+impl VtsQuicStats {
+    /// Move one connection from `from` to `to`, counting 0-RTT when
+    /// `zero_rtt` is set.  Gauges saturate at zero so a leave without a
+    /// matching enter (e.g. across a reload) can't wrap.
+    pub fn transition(&mut self, from: QuicPhase, to: QuicPhase, zero_rtt: bool) {
+        match from {
+            QuicPhase::Idle => {}
+            QuicPhase::Handshaking => self.handshaking = self.handshaking.saturating_sub(1),
+            QuicPhase::Established => self.established = self.established.saturating_sub(1),
+        }
+        match to {
+            QuicPhase::Idle => {}
+            QuicPhase::Handshaking => self.handshaking += 1,
+            QuicPhase::Established => self.established += 1,
+        }
+        if zero_rtt {
+            self.zero_rtt += 1;
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! `vts_status_rate`: cap how many status pages a worker generates per
+//! second, so an abusive scraper cannot keep it busy formatting.
+//!
+//! A token bucket of `rate` tokens refilled at `rate` per second, kept
+//! in its GCRA form: a single atomic "theoretical arrival time" that
+//! each permitted request pushes `1 / rate` seconds further, so a check
+//! is one compare-and-swap.  The state is per worker process.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+const MICROS_PER_SEC: u64 = 1_000_000;
+
+/// Active `vts_status_rate`; 0 means unlimited.
+static RATE: AtomicU64 = AtomicU64::new(0);
+
+/// Theoretical arrival time, in microseconds since [`epoch`]: the
+/// bucket is full again once the clock passes it.
+static TAT_US: AtomicU64 = AtomicU64::new(0);
+
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+/// Set the limit in status pages per second (0 = unlimited) and refill
+/// the bucket.
+pub fn set_rate(rate: u64) {
+    RATE.store(rate, Ordering::Relaxed);
+    TAT_US.store(0, Ordering::Relaxed);
+}
+
+/// Take a token for one status page.  `Err` carries the seconds to wait
+/// before the next one will be allowed, for `Retry-After`.
+pub fn check() -> Result<(), u64> {
+    check_at(epoch().elapsed().as_micros() as u64)
+}
+
+fn check_at(now_us: u64) -> Result<(), u64> {
+    let rate = RATE.load(Ordering::Relaxed);
+    if rate == 0 {
+        return Ok(());
+    }
+    let interval = (MICROS_PER_SEC / rate).max(1);
+
+    let mut tat = TAT_US.load(Ordering::Relaxed);
+    loop {
+        let next = tat.max(now_us) + interval;
+        // Allowed while the bucket still holds a token, i.e. the new
+        // arrival time is at most one second (`rate` tokens) ahead.
+        if next - now_us > MICROS_PER_SEC {
+            let wait_us = next - MICROS_PER_SEC - now_us;
+            return Err(wait_us.div_ceil(MICROS_PER_SEC).max(1));
+        }
+        match TAT_US.compare_exchange_weak(tat, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return Ok(()),
+            Err(current) => tat = current,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_to_rate_per_second_and_recovers() {
+        set_rate(2);
+        assert_eq!(check_at(0), Ok(()));
+        assert_eq!(check_at(0), Ok(()));
+        assert_eq!(check_at(0), Err(1));
+
+        // Half a second refills one token.
+        assert_eq!(check_at(500_000), Ok(()));
+        assert_eq!(check_at(500_000), Err(1));
+
+        // A full window later the whole burst is back.
+        assert_eq!(check_at(2_000_000), Ok(()));
+        assert_eq!(check_at(2_000_000), Ok(()));
+        assert_eq!(check_at(2_000_000), Err(1));
+
+        set_rate(0);
+        for _ in 0..100 {
+            assert_eq!(check_at(2_000_000), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! `?top=N&by=requests|bytes|errors|avg_time` on a `vts_status`
+//! location: a plain-text list of the busiest server zones, or with
+//! `group=upstream` upstream servers, so finding a hotspot doesn't take
+//! scraping and sorting the whole page.
+
+use std::collections::HashMap;
+
+use crate::stats::VtsServerStats;
+use crate::upstream_stats::{UpstreamServerStats, UpstreamZone};
+use crate::vts_node::VtsStatsManager;
+
+/// Largest `top` accepted; bigger values are clamped to it.
+pub const MAX_TOP: usize = 1000;
+
+/// What a top list is ranked by, busiest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Requests served.
+    Requests,
+    /// Bytes in and out.
+    Bytes,
+    /// 5xx responses; for upstream servers also attempts that got no
+    /// response, as in `nginx_vts_upstream_error_rate`.
+    Errors,
+    /// Mean request time (upstream response time for upstream servers).
+    AvgTime,
+}
+
+impl SortKey {
+    /// Parse the `by` argument.
+    pub fn parse(by: &str) -> Option<Self> {
+        match by {
+            "requests" => Some(Self::Requests),
+            "bytes" => Some(Self::Bytes),
+            "errors" => Some(Self::Errors),
+            "avg_time" => Some(Self::AvgTime),
+            _ => None,
+        }
+    }
+
+    /// The `by` argument naming this key.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Requests => "requests",
+            Self::Bytes => "bytes",
+            Self::Errors => "errors",
+            Self::AvgTime => "avg_time",
+        }
+    }
+
+    fn server_value(self, stats: &VtsServerStats) -> f64 {
+        match self {
+            Self::Requests => stats.requests as f64,
+            Self::Bytes => (stats.bytes_in + stats.bytes_out) as f64,
+            Self::Errors => stats.responses.status_5xx as f64,
+            Self::AvgTime => stats.request_times.avg,
+        }
+    }
+
+    fn upstream_value(self, stats: &UpstreamServerStats) -> f64 {
+        match self {
+            Self::Requests => stats.request_counter as f64,
+            Self::Bytes => (stats.in_bytes + stats.out_bytes) as f64,
+            Self::Errors => (stats.responses.status_5xx + stats.no_response) as f64,
+            Self::AvgTime => stats.avg_response_time() / 1000.0,
+        }
+    }
+}
+
+/// The `n` entries with the highest `value`, highest first; ties are
+/// broken by name so the list is stable.
+fn rank<T>(entries: Vec<(String, T)>, n: usize, value: impl Fn(&T) -> f64) -> Vec<(String, T)> {
+    let mut entries = entries;
+    entries.sort_by(|a, b| {
+        value(&b.1)
+            .total_cmp(&value(&a.1))
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    entries.truncate(n.min(MAX_TOP));
+    entries
+}
+
+/// Zones `vts_expose_zones` leaves off the page are left out here too.
+fn top_servers(
+    zones: HashMap<String, VtsServerStats>,
+    n: usize,
+    key: SortKey,
+) -> Vec<(String, VtsServerStats)> {
+    let entries = zones
+        .into_iter()
+        .filter(|(zone, _)| crate::is_zone_exposed(zone))
+        .collect();
+    rank(entries, n, |s| key.server_value(s))
+}
+
+/// Upstream servers named `upstream/server`.
+fn top_upstreams(
+    zones: &HashMap<String, UpstreamZone>,
+    n: usize,
+    key: SortKey,
+) -> Vec<(String, UpstreamServerStats)> {
+    let entries = zones
+        .values()
+        .flat_map(|zone| {
+            zone.servers
+                .iter()
+                .map(move |(addr, stats)| (format!("{}/{addr}", zone.name), stats.clone()))
+        })
+        .collect();
+    rank(entries, n, |s| key.upstream_value(s))
+}
+
+impl VtsStatsManager {
+    /// The `n` (at most [`MAX_TOP`]) busiest server zones by `key`,
+    /// under their `vts_zone_alias` as on the status page.
+    pub fn top_server_zones(&self, n: usize, key: SortKey) -> Vec<(String, VtsServerStats)> {
+        top_servers(self.aliased(self.get_all_server_stats()), n, key)
+    }
+
+    fn aliased(&self, zones: HashMap<String, VtsServerStats>) -> HashMap<String, VtsServerStats> {
+        crate::stats::alias_server_zones(zones, self.get_zone_aliases())
+    }
+
+    /// The `n` (at most [`MAX_TOP`]) busiest upstream servers by `key`,
+    /// named `upstream/server`.
+    pub fn top_upstream_servers(
+        &self,
+        n: usize,
+        key: SortKey,
+    ) -> Vec<(String, UpstreamServerStats)> {
+        top_upstreams(self.get_all_upstream_zones(), n, key)
+    }
+}
+
+/// Render a top list: a `#` header, then one `name value` line per
+/// entry.
+fn format_top(what: &str, key: SortKey, entries: impl Iterator<Item = (String, f64)>) -> String {
+    let mut out = format!("# top {what} by {}\n", key.name());
+    for (name, value) in entries {
+        match key {
+            SortKey::AvgTime => out.push_str(&format!("{name} {value:.3}\n")),
+            _ => out.push_str(&format!("{name} {value}\n")),
+        }
+    }
+    out
+}
+
+/// The `?top` reply: the `n` busiest server zones (upstream servers
+/// when `upstream`) by `key`, from the shared zone when configured and
+/// the process-local manager otherwise.
+pub fn generate_top(n: usize, key: SortKey, upstream: bool) -> String {
+    let manager = crate::VTS_MANAGER
+        .read()
+        .unwrap_or_else(crate::recover_poisoned);
+    if upstream {
+        let top = match crate::shm::snapshot_upstreams() {
+            Some(zones) => top_upstreams(&zones, n, key),
+            None => manager.top_upstream_servers(n, key),
+        };
+        return format_top(
+            "upstream servers",
+            key,
+            top.into_iter()
+                .map(|(name, stats)| (name, key.upstream_value(&stats))),
+        );
+    }
+    let top = match crate::shm::snapshot_servers() {
+        Some(zones) => top_servers(manager.aliased(zones), n, key),
+        None => manager.top_server_zones(n, key),
+    };
+    format_top(
+        "server zones",
+        key,
+        top.into_iter()
+            .map(|(name, stats)| (name, key.server_value(&stats))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> VtsStatsManager {
+        let mut manager = VtsStatsManager::new();
+        for _ in 0..3 {
+            manager.update_server_stats("busy.test", 200, 10, 100, 5);
+        }
+        manager.update_server_stats("big.test", 200, 10, 50_000, 5);
+        manager.update_server_stats("quiet.test", 503, 10, 100, 5);
+        manager
+    }
+
+    fn names<T>(top: &[(String, T)]) -> Vec<&str> {
+        top.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn top_server_zones_by_requests() {
+        let top = manager().top_server_zones(2, SortKey::Requests);
+        // `big.test` and `quiet.test` tie on one request; names break it.
+        assert_eq!(names(&top), ["busy.test", "big.test"]);
+        assert_eq!(top[0].1.requests, 3);
+    }
+
+    #[test]
+    fn top_server_zones_by_bytes() {
+        let top = manager().top_server_zones(3, SortKey::Bytes);
+        assert_eq!(names(&top), ["big.test", "busy.test", "quiet.test"]);
+    }
+
+    #[test]
+    fn top_larger_than_the_zone_count_lists_every_zone() {
+        let manager = manager();
+        assert_eq!(manager.top_server_zones(50, SortKey::Errors).len(), 3);
+        assert_eq!(
+            names(&manager.top_server_zones(1, SortKey::Errors)),
+            ["quiet.test"]
+        );
+        assert!(manager.top_server_zones(0, SortKey::Requests).is_empty());
+    }
+
+    #[test]
+    fn top_upstream_servers_are_named_by_upstream() {
+        let mut manager = VtsStatsManager::new();
+        manager.update_upstream_stats("backend", "10.0.0.1:80", 10, 5, 100, 200, 200);
+        manager.update_upstream_stats("backend", "10.0.0.2:80", 10, 5, 100, 200, 502);
+        let top = manager.top_upstream_servers(1, SortKey::Errors);
+        assert_eq!(names(&top), ["backend/10.0.0.2:80"]);
+    }
+
+    #[test]
+    fn format_top_prints_one_line_per_entry() {
+        let out = format_top(
+            "server zones",
+            SortKey::AvgTime,
+            [("a.test".to_string(), 0.25)].into_iter(),
+        );
+        assert_eq!(out, "# top server zones by avg_time\na.test 0.250\n");
+    }
+}
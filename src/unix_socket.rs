@@ -0,0 +1,313 @@
+//! `vts_unix_socket`: serve the Prometheus page on a Unix domain socket
+//! so a sidecar can scrape without a `vts_status` location.
+//!
+//! One worker (the first) binds the socket in `init_process` and runs a
+//! background thread answering each connection with a minimal
+//! HTTP/1.0 response; it unbinds in `exit_process`.  The request is
+//! read up to its blank line and otherwise ignored, so
+//! `curl --unix-socket` and a bare newline from `nc -U` both work.
+//!
+//! Rendering walks `ngx_cycle` and other nginx globals, which only the
+//! worker's event thread may touch.  So the page is rendered there by
+//! [`refresh`], from a timer in the C module, and the listener thread
+//! only ever serves the latest copy.
+//!
+//! The listener thread polls its own socket with a short timeout and
+//! checks a stop flag in between.  It is never woken by connecting to
+//! the path: after a reload that path belongs to the new worker.
+
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::prometheus::generate_vts_status_content;
+
+/// Longest request head read before answering anyway.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// Read/write timeout per connection, so a stalled client cannot hold
+/// up the ones behind it.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the listener thread waits for a connection before checking
+/// the stop flag again, in milliseconds.
+const POLL_INTERVAL_MS: libc::c_int = 100;
+
+/// Path from `vts_unix_socket`, if configured.
+static CONFIGURED_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// The listener this process runs, if any.
+static RUNNING: Mutex<Option<Listener>> = Mutex::new(None);
+
+/// Latest page rendered by [`refresh`]; `None` before the first one.
+static PAGE: Mutex<Option<Arc<String>>> = Mutex::new(None);
+
+struct Listener {
+    path: PathBuf,
+    /// `(dev, ino)` of the socket file we bound, so stopping never
+    /// removes a socket a newer worker bound at the same path.
+    file_id: (u64, u64),
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+/// Set (or with `None` clear) the path the next [`start`] binds.
+pub fn set_path(path: Option<PathBuf>) {
+    *CONFIGURED_PATH
+        .lock()
+        .unwrap_or_else(crate::recover_poisoned) = path;
+}
+
+/// Bind the configured path and start serving.  A no-op when no path is
+/// configured or a listener is already running.  Connections get a 503
+/// until the first [`refresh`].
+pub fn start() -> io::Result<()> {
+    let Some(path) = CONFIGURED_PATH
+        .lock()
+        .unwrap_or_else(crate::recover_poisoned)
+        .clone()
+    else {
+        return Ok(());
+    };
+    let mut running = RUNNING.lock().unwrap_or_else(crate::recover_poisoned);
+    if running.is_some() {
+        return Ok(());
+    }
+
+    // A socket left behind by a previous worker would make bind fail.
+    remove_stale_socket(&path)?;
+    let listener = UnixListener::bind(&path)?;
+    // A client that disconnects between poll and accept must not leave
+    // the thread blocked in accept.
+    listener.set_nonblocking(true)?;
+    let meta = std::fs::metadata(&path)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = Arc::clone(&stop);
+        thread::Builder::new()
+            .name("vts-unix-socket".into())
+            .spawn(move || accept_loop(listener, &stop))?
+    };
+    *running = Some(Listener {
+        path,
+        file_id: (meta.dev(), meta.ino()),
+        stop,
+        thread,
+    });
+    Ok(())
+}
+
+/// Render the page the listener serves.  Must run on the nginx event
+/// thread.  Returns whether a listener is running, i.e. whether the
+/// caller should keep refreshing.
+pub fn refresh() -> bool {
+    if RUNNING
+        .lock()
+        .unwrap_or_else(crate::recover_poisoned)
+        .is_none()
+    {
+        return false;
+    }
+    let page = Arc::new(generate_vts_status_content());
+    *PAGE.lock().unwrap_or_else(crate::recover_poisoned) = Some(page);
+    true
+}
+
+/// Stop the listener started by [`start`], if any, and remove its
+/// socket file if it is still the one this process bound.
+pub fn stop() {
+    let Some(listener) = RUNNING
+        .lock()
+        .unwrap_or_else(crate::recover_poisoned)
+        .take()
+    else {
+        return;
+    };
+    // The thread sees the flag within one poll interval.
+    listener.stop.store(true, Ordering::Release);
+    let _ = listener.thread.join();
+    *PAGE.lock().unwrap_or_else(crate::recover_poisoned) = None;
+
+    if let Ok(meta) = std::fs::symlink_metadata(&listener.path) {
+        if (meta.dev(), meta.ino()) == listener.file_id {
+            let _ = std::fs::remove_file(&listener.path);
+        }
+    }
+}
+
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn accept_loop(listener: UnixListener, stop: &AtomicBool) {
+    let mut pollfd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    while !stop.load(Ordering::Acquire) {
+        // SAFETY: one valid `pollfd` for the socket this thread owns.
+        if unsafe { libc::poll(&mut pollfd, 1, POLL_INTERVAL_MS) } <= 0 {
+            continue;
+        }
+        // One bad client must not take the listener down.
+        if let Ok((stream, _)) = listener.accept() {
+            let _ = serve(stream);
+        }
+    }
+}
+
+fn serve(mut stream: UnixStream) -> io::Result<()> {
+    // Accepted sockets inherit O_NONBLOCK on the BSDs.
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    read_request_head(&mut stream)?;
+    stream.write_all(response().as_bytes())
+}
+
+/// Consume the request up to its blank line (or a lone newline for the
+/// line protocol), EOF, or [`MAX_REQUEST_HEAD`] bytes.
+fn read_request_head(stream: &mut impl Read) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 512];
+    while head.len() < MAX_REQUEST_HEAD {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head == b"\n"
+            || head == b"\r\n"
+            || head.ends_with(b"\r\n\r\n")
+            || head.ends_with(b"\n\n")
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The full reply: an HTTP/1.0 header and the latest Prometheus page,
+/// or a 503 before the first [`refresh`].
+fn response() -> String {
+    let page = PAGE.lock().unwrap_or_else(crate::recover_poisoned).clone();
+    let Some(body) = page else {
+        return "HTTP/1.0 503 Service Unavailable\r\n\
+                Content-Length: 0\r\n\
+                Connection: close\r\n\
+                \r\n"
+            .to_string();
+    };
+    format!(
+        "HTTP/1.0 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scrape(path: &Path, request: &[u8]) -> String {
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream.write_all(request).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn serves_status_page_over_unix_socket() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let path = std::env::temp_dir().join(format!("vts-test-{}.sock", std::process::id()));
+        crate::update_server_zone_stats("socket.example.com", 200, 10, 20, 5);
+
+        set_path(Some(path.clone()));
+        start().unwrap();
+        assert!(scrape(&path, b"\n").starts_with("HTTP/1.0 503 "));
+        assert!(refresh());
+
+        let reply = scrape(&path, b"GET /metrics HTTP/1.0\r\nHost: localhost\r\n\r\n");
+        let (head, body) = reply.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert!(body.contains("nginx_vts_server_requests_total{zone=\"socket.example.com\"}"));
+        crate::prometheus::validate_prometheus(body).unwrap();
+
+        // Line protocol: a bare newline is enough.
+        assert!(scrape(&path, b"\n").starts_with("HTTP/1.0 200 OK\r\n"));
+
+        // The listener serves the last rendered page, not a fresh one.
+        crate::update_server_zone_stats("later.example.com", 200, 10, 20, 5);
+        assert!(!scrape(&path, b"\n").contains("zone=\"later.example.com\""));
+        assert!(refresh());
+        assert!(scrape(&path, b"\n").contains("zone=\"later.example.com\""));
+
+        stop();
+        assert!(!refresh());
+        set_path(None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn stop_leaves_a_socket_rebound_by_another_process() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let path =
+            std::env::temp_dir().join(format!("vts-test-rebound-{}.sock", std::process::id()));
+
+        set_path(Some(path.clone()));
+        start().unwrap();
+        // A new worker after reload replaces the socket file.
+        std::fs::remove_file(&path).unwrap();
+        let newer = UnixListener::bind(&path).unwrap();
+
+        // Stopping returns without connecting to, or removing, the
+        // newer socket.
+        stop();
+        set_path(None);
+        assert!(path.exists());
+        newer.set_nonblocking(true).unwrap();
+        assert!(newer.accept().is_err());
+
+        drop(newer);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn start_without_path_is_a_noop() {
+        let _lock = crate::GLOBAL_VTS_TEST_MUTEX
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        set_path(None);
+        start().unwrap();
+        assert!(RUNNING.lock().unwrap().is_none());
+        stop();
+    }
+}
@@ -6,6 +6,12 @@
 
 use std::collections::HashMap;
 
+use crate::shm::UpstreamCounters;
+use crate::stats::VtsResponseStats;
+
+#[cfg(feature = "latency-percentiles")]
+use crate::latency::LatencyHistogram;
+
 /// Cumulative bucket upper bounds (in milliseconds) for the upstream
 /// response-time histogram.  Mirrors the Prometheus client_golang
 /// `DefBuckets` set, just expressed in milliseconds so the on-the-wire
@@ -16,19 +22,97 @@ pub const RESPONSE_TIME_BUCKET_BOUNDS_MS: [u64; 11] =
 /// Number of histogram buckets (excluding `+Inf`, which is implicit).
 pub const RESPONSE_TIME_BUCKET_COUNT: usize = RESPONSE_TIME_BUCKET_BOUNDS_MS.len();
 
-/// Response statistics structure (reused from stats.rs design)
-#[derive(Debug, Clone, Default)]
-pub struct VtsResponseStats {
-    /// 1xx status responses
-    pub status_1xx: u64,
-    /// 2xx status responses  
-    pub status_2xx: u64,
-    /// 3xx status responses
-    pub status_3xx: u64,
-    /// 4xx status responses
-    pub status_4xx: u64,
-    /// 5xx status responses
-    pub status_5xx: u64,
+/// `server` of the entry [`UpstreamZone::limit_servers`] folds the
+/// overflow of an upstream past `vts_upstream_server_limit` into.
+pub const AGGREGATED_SERVER: &str = "__aggregated__";
+
+/// Time a request spent outside the upstream, in milliseconds:
+/// `request_time - upstream_response_time`.  The two are measured
+/// separately, so the upstream time can come out ahead; the result is
+/// then clamped to 0 and the second value is `true`.
+pub fn local_time_ms(request_time: u64, upstream_response_time: u64) -> (u64, bool) {
+    match request_time.checked_sub(upstream_response_time) {
+        Some(local) => (local, false),
+        None => (0, true),
+    }
+}
+
+/// Width of one [`ErrorWindow`] slot, in seconds.
+pub const ERROR_WINDOW_SLOT_SECS: u64 = 5;
+
+/// Number of [`ErrorWindow`] slots: together they cover the last 60
+/// seconds.
+pub const ERROR_WINDOW_SLOTS: usize = 12;
+
+/// Requests and errors seen during one [`ERROR_WINDOW_SLOT_SECS`]-wide
+/// slot, tagged with the slot's number since the epoch so a stale
+/// slot can be recognised and reused.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ErrorSlot {
+    slot: u64,
+    requests: u32,
+    errors: u32,
+}
+
+/// Rolling request/error counts over the last
+/// `ERROR_WINDOW_SLOTS * ERROR_WINDOW_SLOT_SECS` seconds, kept in a
+/// ring of slots so recording and reading are both O(slots) with no
+/// allocation.  Plain data, so it can live in the shared zone too.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorWindow {
+    slots: [ErrorSlot; ERROR_WINDOW_SLOTS],
+}
+
+impl ErrorWindow {
+    /// Count one request finishing at `now` (seconds since the epoch),
+    /// as an error when `error` is set.
+    pub fn record(&mut self, now: u64, error: bool) {
+        let slot = now / ERROR_WINDOW_SLOT_SECS;
+        let entry = &mut self.slots[(slot % ERROR_WINDOW_SLOTS as u64) as usize];
+        if entry.slot != slot {
+            *entry = ErrorSlot {
+                slot,
+                ..ErrorSlot::default()
+            };
+        }
+        entry.requests = entry.requests.saturating_add(1);
+        if error {
+            entry.errors = entry.errors.saturating_add(1);
+        }
+    }
+
+    /// Errors divided by requests over the window ending at `now`, or
+    /// `0.0` when the window saw no requests.
+    pub fn rate(&self, now: u64) -> f64 {
+        let current = now / ERROR_WINDOW_SLOT_SECS;
+        let (requests, errors) = self
+            .slots
+            .iter()
+            .filter(|s| s.slot <= current && current - s.slot < ERROR_WINDOW_SLOTS as u64)
+            .fold((0u64, 0u64), |(r, e), s| {
+                (r + u64::from(s.requests), e + u64::from(s.errors))
+            });
+        if requests == 0 {
+            0.0
+        } else {
+            errors as f64 / requests as f64
+        }
+    }
+}
+
+/// Whether an upstream `status` counts against
+/// [`UpstreamServerStats::error_window`]: a 5xx, or no response at all.
+pub fn is_upstream_error(status: u16) -> bool {
+    status < 100 || (500..=599).contains(&status)
+}
+
+/// Current wall-clock time in whole seconds since the epoch, the clock
+/// [`ErrorWindow`] slots are keyed on.  Wall-clock rather than
+/// monotonic so every worker writing the shared zone agrees.
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
 }
 
 /// Statistics for an individual upstream server
@@ -44,15 +128,20 @@ pub struct UpstreamServerStats {
     /// Total number of requests sent to this server
     pub request_counter: u64,
 
-    /// Total bytes received from this server
+    /// Response bytes received from this server (nginx's
+    /// `$upstream_bytes_received`)
     pub in_bytes: u64,
 
-    /// Total bytes sent to this server
+    /// Request bytes sent to this server (nginx's `$upstream_bytes_sent`)
     pub out_bytes: u64,
 
-    /// Response status code statistics (reusing existing structure)
+    /// Response status code statistics, shared with the server zones
     pub responses: VtsResponseStats,
 
+    /// Attempts with a status below 100 — in practice 0, meaning nginx
+    /// never got a response (connect error, timeout, reset)
+    pub no_response: u64,
+
     /// Total request processing time in milliseconds
     pub request_time_total: u64,
 
@@ -65,11 +154,28 @@ pub struct UpstreamServerStats {
     /// Counter for response time measurements (for average calculation)
     pub response_time_counter: u64,
 
+    /// Total time in milliseconds spent outside the upstream: request
+    /// time minus the response times of all its attempts, summed per
+    /// request on the server of its final attempt
+    pub local_time_total: u64,
+
+    /// Requests whose upstream response time exceeded the request time,
+    /// so their local time was clamped to 0
+    pub local_time_clamped: u64,
+
     /// Cumulative counts of upstream-response-time samples whose value
     /// in milliseconds is `<= RESPONSE_TIME_BUCKET_BOUNDS_MS[i]`.  The
     /// implicit `+Inf` bucket equals `response_time_counter`.
     pub response_buckets: [u64; RESPONSE_TIME_BUCKET_COUNT],
 
+    /// Upstream response-time distribution for percentile output.
+    #[cfg(feature = "latency-percentiles")]
+    pub latency: LatencyHistogram,
+
+    /// Requests and errors (5xx or no response) over the last minute,
+    /// for `nginx_vts_upstream_error_rate`.
+    pub error_window: ErrorWindow,
+
     /// Server weight from nginx configuration
     pub weight: u32,
 
@@ -102,6 +208,32 @@ pub struct UpstreamZone {
     pub servers: HashMap<String, UpstreamServerStats>,
 }
 
+/// Requests waiting for a connection slot to an upstream group (with
+/// `max_conns` or keepalive limits).  Stock nginx does not expose its
+/// queue, so these are fed through `vts_track_upstream_queue`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpstreamQueueStats {
+    /// Requests currently queued.
+    pub length: u64,
+
+    /// Requests that have had to queue, ever.
+    pub waits_total: u64,
+}
+
+impl UpstreamQueueStats {
+    /// Apply one queue event: `waited` is a request joining the queue,
+    /// otherwise a queued request leaving it (slot acquired or given
+    /// up).  An unmatched leave never drives the length below zero.
+    pub fn update(&mut self, waited: bool) {
+        if waited {
+            self.length += 1;
+            self.waits_total += 1;
+        } else {
+            self.length = self.length.saturating_sub(1);
+        }
+    }
+}
+
 impl UpstreamServerStats {
     /// Create new upstream server statistics with default values
     ///
@@ -119,11 +251,17 @@ impl UpstreamServerStats {
             in_bytes: 0,
             out_bytes: 0,
             responses: VtsResponseStats::default(),
+            no_response: 0,
             request_time_total: 0,
             request_time_counter: 0,
             response_time_total: 0,
             response_time_counter: 0,
+            local_time_total: 0,
+            local_time_clamped: 0,
             response_buckets: [0; RESPONSE_TIME_BUCKET_COUNT],
+            #[cfg(feature = "latency-percentiles")]
+            latency: LatencyHistogram::new(),
+            error_window: ErrorWindow::default(),
             weight: 1,
             max_fails: 1,
             fail_timeout: 10,
@@ -132,6 +270,19 @@ impl UpstreamServerStats {
         }
     }
 
+    /// Zero the traffic counters, keeping the server's configuration
+    /// (`weight`, `max_fails`, `fail_timeout`, `backup`, `down`).
+    pub fn reset_counters(&mut self) {
+        *self = Self {
+            weight: self.weight,
+            max_fails: self.max_fails,
+            fail_timeout: self.fail_timeout,
+            backup: self.backup,
+            down: self.down,
+            ..Self::new(&self.server)
+        };
+    }
+
     /// Update response status statistics
     ///
     /// # Arguments
@@ -139,6 +290,7 @@ impl UpstreamServerStats {
     /// * `status_code` - HTTP status code from upstream response
     pub fn update_response_status(&mut self, status_code: u16) {
         match status_code {
+            0..=99 => self.no_response += 1,
             100..=199 => self.responses.status_1xx += 1,
             200..=299 => self.responses.status_2xx += 1,
             300..=399 => self.responses.status_3xx += 1,
@@ -146,6 +298,13 @@ impl UpstreamServerStats {
             500..=599 => self.responses.status_5xx += 1,
             _ => {}
         }
+        match status_code {
+            206 => self.responses.status_206 += 1,
+            304 => self.responses.status_304 += 1,
+            _ => {}
+        }
+        self.error_window
+            .record(now_secs(), is_upstream_error(status_code));
     }
 
     /// Update timing statistics
@@ -169,6 +328,27 @@ impl UpstreamServerStats {
                 self.response_buckets[i] += 1;
             }
         }
+        #[cfg(feature = "latency-percentiles")]
+        self.record_latency_ms(upstream_response_time);
+    }
+
+    /// Add one request's local time: `request_time` minus
+    /// `upstream_response_time`, the summed response times of all its
+    /// attempts.  Called once per request, on its final attempt's
+    /// server, so a retried request is not counted once per attempt.
+    pub fn add_local_time(&mut self, request_time: u64, upstream_response_time: u64) {
+        if request_time > 0 {
+            let (local, clamped) = local_time_ms(request_time, upstream_response_time);
+            self.local_time_total += local;
+            self.local_time_clamped += u64::from(clamped);
+        }
+    }
+
+    /// Record one upstream response-time sample into the percentile
+    /// histogram.
+    #[cfg(feature = "latency-percentiles")]
+    pub fn record_latency_ms(&mut self, ms: u64) {
+        self.latency.record(ms);
     }
 
     /// Get average request processing time
@@ -198,6 +378,20 @@ impl UpstreamServerStats {
             0.0
         }
     }
+
+    /// Circuit-breaker view of the server for
+    /// `nginx_vts_upstream_server_state`: `down` when marked down,
+    /// `degraded` when the error rate over the window ending at `now`
+    /// exceeds `degraded_rate`, `healthy` otherwise.
+    pub fn health_state(&self, now: u64, degraded_rate: f64) -> &'static str {
+        if self.down {
+            "down"
+        } else if self.error_window.rate(now) > degraded_rate {
+            "degraded"
+        } else {
+            "healthy"
+        }
+    }
 }
 
 impl UpstreamZone {
@@ -211,9 +405,15 @@ impl UpstreamZone {
     ///
     /// New UpstreamZone instance with empty servers map
     pub fn new(name: &str) -> Self {
+        Self::with_capacity(name, 0)
+    }
+
+    /// Create a new upstream zone with room for `servers` entries
+    /// before its servers map reallocates
+    pub fn with_capacity(name: &str, servers: usize) -> Self {
         Self {
             name: name.to_string(),
-            servers: HashMap::new(),
+            servers: HashMap::with_capacity(servers),
         }
     }
 
@@ -253,6 +453,39 @@ impl UpstreamZone {
         let total_out = self.servers.values().map(|s| s.out_bytes).sum();
         (total_in, total_out)
     }
+
+    /// This upstream with at most `limit` servers of its own
+    /// (`vts_upstream_server_limit`): the first `limit` by address, with
+    /// the rest summed into one [`AGGREGATED_SERVER`] entry.  Ranking by
+    /// address rather than traffic pins the membership: servers are
+    /// never dropped from the table, so a server only ever moves into
+    /// the aggregate (when a new one sorts before it), never back out,
+    /// and no counter series goes down.  The sum keeps the counters
+    /// and histograms; server configuration and the error-rate window
+    /// are per server and are left at their defaults.  A `limit` of 0
+    /// means no limit.
+    pub fn limit_servers(&self, limit: usize) -> UpstreamZone {
+        if limit == 0 || self.servers.len() <= limit {
+            return self.clone();
+        }
+        let mut ranked: Vec<_> = self.servers.iter().collect();
+        ranked.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+        let mut limited = UpstreamZone::with_capacity(&self.name, limit + 1);
+        let mut overflow = UpstreamCounters::new();
+        for (i, (addr, stats)) in ranked.into_iter().enumerate() {
+            if i < limit {
+                limited.servers.insert(addr.clone(), stats.clone());
+            } else {
+                overflow.merge(&UpstreamCounters::from_stats(stats));
+            }
+        }
+        limited.servers.insert(
+            AGGREGATED_SERVER.to_string(),
+            overflow.into_stats(AGGREGATED_SERVER),
+        );
+        limited
+    }
 }
 
 #[cfg(test)]
@@ -260,6 +493,57 @@ impl UpstreamZone {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_upstream_responses_share_the_server_zone_type() {
+        let mut stats = UpstreamServerStats::new("10.0.0.1:80");
+        stats.update_response_status(206);
+        stats.update_response_status(304);
+        stats.update_response_status(503);
+
+        let responses: crate::stats::VtsResponseStats = stats.responses.clone();
+        assert_eq!(responses.status_2xx, 1);
+        assert_eq!(responses.status_206, 1);
+        assert_eq!(responses.status_3xx, 1);
+        assert_eq!(responses.status_304, 1);
+        assert_eq!(responses.status_5xx, 1);
+
+        let restored = UpstreamCounters::from_stats(&stats).into_stats("10.0.0.1:80");
+        assert_eq!(restored.responses.status_206, 1);
+        assert_eq!(restored.responses.status_304, 1);
+        assert_eq!(restored.responses.status_5xx, 1);
+    }
+
+    #[test]
+    fn test_local_time_is_clamped_when_upstream_time_exceeds_request_time() {
+        assert_eq!(local_time_ms(200, 50), (150, false));
+        assert_eq!(local_time_ms(50, 50), (0, false));
+        assert_eq!(local_time_ms(100, 150), (0, true));
+
+        let mut stats = UpstreamServerStats::new("10.0.0.1:80");
+        stats.add_local_time(200, 50);
+        stats.add_local_time(100, 150);
+        assert_eq!(stats.local_time_total, 150);
+        assert_eq!(stats.local_time_clamped, 1);
+
+        // No request time measured: nothing to split.
+        stats.add_local_time(0, 30);
+        assert_eq!(stats.local_time_total, 150);
+        assert_eq!(stats.local_time_clamped, 1);
+
+        let restored = UpstreamCounters::from_stats(&stats).into_stats("10.0.0.1:80");
+        assert_eq!(restored.local_time_total, 150);
+        assert_eq!(restored.local_time_clamped, 1);
+    }
+
+    #[test]
+    fn test_upstream_zone_with_capacity_matches_new() {
+        let zone = UpstreamZone::with_capacity("backend", 8);
+        assert_eq!(zone.name, "backend");
+        assert!(zone.servers.is_empty());
+        assert!(zone.servers.capacity() >= 8);
+        assert_eq!(UpstreamZone::new("backend").servers.len(), 0);
+    }
+
     #[test]
     fn test_upstream_server_stats_new() {
         let stats = UpstreamServerStats::new("192.168.1.1:80");
@@ -272,6 +556,57 @@ mod tests {
         assert!(!stats.down);
     }
 
+    #[test]
+    fn test_limit_servers_folds_the_last_addresses_into_the_aggregate() {
+        let mut zone = UpstreamZone::new("backend");
+        for (addr, requests) in [("10.0.0.1:80", 3), ("10.0.0.2:80", 1), ("10.0.0.3:80", 5)] {
+            let server = zone.get_or_create_server(addr);
+            server.request_counter = requests;
+            server.in_bytes = requests * 100;
+            server.out_bytes = requests * 200;
+            for _ in 0..requests {
+                server.update_response_status(200);
+            }
+        }
+
+        assert_eq!(zone.limit_servers(0).servers.len(), 3);
+        assert_eq!(zone.limit_servers(3).servers.len(), 3);
+
+        // Traffic doesn't matter: the busiest server is past the limit.
+        let limited = zone.limit_servers(2);
+        let mut addrs: Vec<_> = limited.servers.keys().map(String::as_str).collect();
+        addrs.sort_unstable();
+        assert_eq!(addrs, ["10.0.0.1:80", "10.0.0.2:80", AGGREGATED_SERVER]);
+        let aggregate = &limited.servers[AGGREGATED_SERVER];
+        assert_eq!(aggregate.server, AGGREGATED_SERVER);
+        assert_eq!(aggregate.request_counter, 5);
+        assert_eq!(limited.total_requests(), zone.total_requests());
+        assert_eq!(limited.total_bytes(), zone.total_bytes());
+    }
+
+    #[test]
+    fn test_limit_servers_never_takes_a_server_out_of_the_aggregate() {
+        let mut zone = UpstreamZone::new("backend");
+        for addr in ["10.0.0.2:80", "10.0.0.3:80", "10.0.0.4:80"] {
+            zone.get_or_create_server(addr).request_counter = 1;
+        }
+        let aggregated =
+            |zone: &UpstreamZone| zone.limit_servers(2).servers[AGGREGATED_SERVER].request_counter;
+        assert_eq!(aggregated(&zone), 1);
+
+        // The aggregated server getting busy leaves it there.
+        zone.get_or_create_server("10.0.0.4:80").request_counter = 100;
+        assert_eq!(aggregated(&zone), 100);
+
+        // A new server sorting first pushes one more in; the aggregate
+        // only grows.
+        zone.get_or_create_server("10.0.0.1:80").request_counter = 1;
+        let limited = zone.limit_servers(2);
+        assert!(limited.servers.contains_key("10.0.0.1:80"));
+        assert!(!limited.servers.contains_key("10.0.0.3:80"));
+        assert_eq!(aggregated(&zone), 101);
+    }
+
     #[test]
     fn test_update_response_status() {
         let mut stats = UpstreamServerStats::new("test:80");
@@ -283,6 +618,11 @@ mod tests {
         assert_eq!(stats.responses.status_2xx, 1);
         assert_eq!(stats.responses.status_4xx, 1);
         assert_eq!(stats.responses.status_5xx, 1);
+        assert_eq!(stats.no_response, 0);
+
+        stats.update_response_status(0);
+        stats.update_response_status(99);
+        assert_eq!(stats.no_response, 2);
     }
 
     #[test]
@@ -324,4 +664,40 @@ mod tests {
         assert_eq!(zone.total_requests(), 300);
         assert_eq!(zone.total_bytes(), (3000, 1500));
     }
+
+    #[test]
+    fn test_upstream_queue_stats() {
+        let mut queue = UpstreamQueueStats::default();
+        queue.update(true);
+        queue.update(true);
+        queue.update(false);
+        assert_eq!(queue.length, 1);
+        assert_eq!(queue.waits_total, 2);
+
+        // Leaves without a matching join saturate at zero.
+        queue.update(false);
+        queue.update(false);
+        assert_eq!(queue.length, 0);
+        assert_eq!(queue.waits_total, 2);
+    }
+
+    #[test]
+    fn test_error_window_reflects_only_the_last_minute() {
+        let mut window = ErrorWindow::default();
+        assert_eq!(window.rate(1_000), 0.0);
+
+        // A long healthy history...
+        for _ in 0..1_000 {
+            window.record(1_000, false);
+        }
+        // ...then a burst of errors two minutes later.
+        for status in [502, 503, 0, 200] {
+            window.record(1_120, is_upstream_error(status));
+        }
+        assert_eq!(window.rate(1_120), 0.75);
+
+        // Once the burst ages out the window is empty again.
+        assert_eq!(window.rate(1_200), 0.0);
+        assert!(!is_upstream_error(404));
+    }
 }
@@ -6,10 +6,67 @@
 //! the conversion to the Prometheus-side [`VtsServerStats`] is
 //! single-sourced.
 
-use crate::shm::ServerCounters;
-use crate::stats::{VtsConnectionStats, VtsServerStats};
-use crate::upstream_stats::UpstreamZone;
-use std::collections::HashMap;
+use crate::error::VtsError;
+#[cfg(feature = "unique-clients")]
+use crate::hll::HyperLogLog;
+use crate::shm::{ConnPhase, ServerCounters};
+use crate::stats::{
+    admit_filter_key, alias_server_zones, ContentTypeCounters, GrpcStatusCounters, HttpMethod,
+    MethodStatusCounters, QuicPhase, RequestHeaderStats, VtsConnectionStats, VtsQuicStats,
+    VtsServerStats,
+};
+use crate::upstream_stats::{UpstreamQueueStats, UpstreamServerStats, UpstreamZone};
+use std::collections::{HashMap, HashSet};
+
+/// Most user-defined labels one server zone may carry.
+pub const MAX_ZONE_LABELS: usize = 8;
+
+/// Label names the server families use to break a zone's series down
+/// (`part` is kept for the header/body byte split, `le` is the
+/// request-header histograms' bucket bound, `reason` the client body
+/// error kind, `grpc_status` the gRPC breakdown); a zone label may not
+/// shadow them.
+const RESERVED_ZONE_LABELS: [&str; 10] = [
+    "zone",
+    "direction",
+    "status",
+    "type",
+    "state",
+    "method",
+    "part",
+    "le",
+    "reason",
+    "grpc_status",
+];
+
+/// Check that `name` is a label name a zone may carry: valid in the
+/// exposition format, not reserved, and not a name the server families
+/// already use.
+pub fn check_zone_label_name(name: &str) -> Result<(), VtsError> {
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(VtsError::InvalidLabelName);
+    }
+    if name.starts_with("__") || RESERVED_ZONE_LABELS.contains(&name) {
+        return Err(VtsError::ReservedLabelName);
+    }
+    Ok(())
+}
+
+/// Check that `name` may be added as the next user-defined label of a
+/// zone that already carries `existing`.
+pub fn check_zone_label(existing: &[(String, String)], name: &str) -> Result<(), VtsError> {
+    check_zone_label_name(name)?;
+    if existing.iter().any(|(n, _)| n == name) {
+        return Err(VtsError::DuplicateLabelName);
+    }
+    if existing.len() >= MAX_ZONE_LABELS {
+        return Err(VtsError::TooManyLabels);
+    }
+    Ok(())
+}
 
 /// Process-local VTS statistics manager.
 ///
@@ -17,7 +74,10 @@ use std::collections::HashMap;
 /// higher-level FFI can transparently fall through when no
 /// `vts_zone` is configured (and so unit tests, which never link
 /// the slab allocator, still have somewhere to write).
-#[derive(Debug)]
+///
+/// `Clone` so the status page can copy it out from under
+/// `VTS_MANAGER` and format without holding the lock.
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct VtsStatsManager {
     /// Per server-zone counters keyed by `server_name`.
@@ -26,8 +86,60 @@ pub struct VtsStatsManager {
     /// Per-upstream zone statistics.
     pub upstream_zones: HashMap<String, UpstreamZone>,
 
+    /// Per-upstream connection-slot queue, keyed by upstream name.
+    pub upstream_queues: HashMap<String, UpstreamQueueStats>,
+
+    /// Method × status-class cross-tab per server zone, for zones with
+    /// `vts_detail_method_status` on.
+    pub method_status: HashMap<String, MethodStatusCounters>,
+
+    /// Request-header size and count histograms per server zone.
+    pub request_headers: HashMap<String, RequestHeaderStats>,
+
+    /// Responses by Content-Type top-level type per server zone, for
+    /// zones with `vts_track_content_type` on.
+    pub content_types: HashMap<String, ContentTypeCounters>,
+
+    /// Responses by `grpc-status` per server zone, for zones with
+    /// `vts_track_grpc` on.
+    pub grpc_statuses: HashMap<String, GrpcStatusCounters>,
+
+    /// Distinct client address sketches per server zone, for zones with
+    /// `vts_track_unique_clients` on.
+    #[cfg(feature = "unique-clients")]
+    pub unique_clients: HashMap<String, HyperLogLog>,
+
+    /// Per location-zone counters keyed by `vts_location_zone` name.
+    pub locations: HashMap<String, ServerCounters>,
+
+    /// Filter-zone counters keyed by `vts_filter_by_variable` group,
+    /// then by the variable's value.
+    pub filters: HashMap<String, HashMap<String, ServerCounters>>,
+
+    /// Server addresses listed more than once in an upstream block,
+    /// counted per upstream while seeding from the configuration.
+    pub upstream_duplicate_servers: HashMap<String, u64>,
+
     /// Latest connection-state snapshot.
     pub connections: VtsConnectionStats,
+
+    /// QUIC connection gauges and 0-RTT count (`vts_track_quic_connection`).
+    pub quic: VtsQuicStats,
+
+    /// Server zones whose accounting is paused.  Their stored
+    /// counters are kept, just not updated.
+    pub disabled_zones: HashSet<String>,
+
+    /// User-defined labels (`vts_zone_label`) per server zone, in the
+    /// order they were configured.
+    pub zone_labels: HashMap<String, Vec<(String, String)>>,
+
+    /// Pool name (`vts_upstream_zone`) per upstream group, rendered as
+    /// the `zone` label of its server series.
+    pub upstream_zone_names: HashMap<String, String>,
+
+    /// Output name (`vts_zone_alias`) per stored server zone.
+    pub zone_aliases: HashMap<String, String>,
 }
 
 #[allow(dead_code)]
@@ -37,10 +149,47 @@ impl VtsStatsManager {
         Self {
             stats: HashMap::new(),
             upstream_zones: HashMap::new(),
+            upstream_queues: HashMap::new(),
+            method_status: HashMap::new(),
+            request_headers: HashMap::new(),
+            content_types: HashMap::new(),
+            grpc_statuses: HashMap::new(),
+            #[cfg(feature = "unique-clients")]
+            unique_clients: HashMap::new(),
+            locations: HashMap::new(),
+            filters: HashMap::new(),
+            upstream_duplicate_servers: HashMap::new(),
             connections: VtsConnectionStats::default(),
+            quic: VtsQuicStats::default(),
+            disabled_zones: HashSet::new(),
+            zone_labels: HashMap::new(),
+            upstream_zone_names: HashMap::new(),
+            zone_aliases: HashMap::new(),
         }
     }
 
+    /// Pause (`false`) or resume (`true`) accounting for a server zone
+    /// without discarding what it has accumulated so far.
+    pub fn set_zone_enabled(&mut self, zone: &str, enabled: bool) {
+        if enabled {
+            self.disabled_zones.remove(zone);
+        } else {
+            self.disabled_zones.insert(zone.to_string());
+        }
+    }
+
+    /// Whether `update_server_stats` currently records into `zone`.
+    pub fn is_zone_enabled(&self, zone: &str) -> bool {
+        !self.disabled_zones.contains(zone)
+    }
+
+    /// Paused zones, sorted for stable output.
+    pub fn get_disabled_zones(&self) -> Vec<String> {
+        let mut zones: Vec<String> = self.disabled_zones.iter().cloned().collect();
+        zones.sort();
+        zones
+    }
+
     /// Update statistics for a server zone
     pub fn update_server_stats(
         &mut self,
@@ -50,14 +199,246 @@ impl VtsStatsManager {
         bytes_out: u64,
         request_time: u64,
     ) {
+        self.update_server_stats_weighted(
+            server_name,
+            status,
+            bytes_in,
+            bytes_out,
+            request_time,
+            1,
+        );
+    }
+
+    /// Like [`update_server_stats`], standing for `weight` sampled
+    /// requests (`vts_sample_rate`).
+    ///
+    /// [`update_server_stats`]: VtsStatsManager::update_server_stats
+    pub fn update_server_stats_weighted(
+        &mut self,
+        server_name: &str,
+        status: u16,
+        bytes_in: u64,
+        bytes_out: u64,
+        request_time: u64,
+        weight: u64,
+    ) {
+        if !self.is_zone_enabled(server_name) {
+            return;
+        }
         self.stats
             .entry(server_name.to_string())
             .or_insert_with(ServerCounters::new)
+            .update_weighted(status, bytes_in, bytes_out, request_time, weight);
+    }
+
+    /// Record `weight` requests of `server_name` rejected by
+    /// `limit_req` / `limit_conn` (more than one under
+    /// `vts_sample_rate`).
+    pub fn update_server_rate_limited(
+        &mut self,
+        server_name: &str,
+        bytes_in: u64,
+        bytes_out: u64,
+        request_time: u64,
+        weight: u64,
+    ) {
+        if !self.is_zone_enabled(server_name) {
+            return;
+        }
+        self.stats
+            .entry(server_name.to_string())
+            .or_insert_with(ServerCounters::new)
+            .update_rate_limited(bytes_in, bytes_out, request_time, weight);
+    }
+
+    /// Update statistics for a location zone (`vts_location_zone`).
+    /// Same accounting as [`update_server_stats`], keyed by the
+    /// configured location name instead of the server zone.
+    ///
+    /// [`update_server_stats`]: VtsStatsManager::update_server_stats
+    pub fn update_location_stats(
+        &mut self,
+        location: &str,
+        status: u16,
+        bytes_in: u64,
+        bytes_out: u64,
+        request_time: u64,
+    ) {
+        self.locations
+            .entry(location.to_string())
+            .or_insert_with(ServerCounters::new)
             .update(status, bytes_in, bytes_out, request_time);
     }
 
+    /// Get all location-zone statistics
+    pub fn get_all_location_stats(&self) -> HashMap<String, VtsServerStats> {
+        self.locations
+            .iter()
+            .map(|(location, counters)| (location.clone(), (*counters).into_stats()))
+            .collect()
+    }
+
+    /// Update statistics for key `key` of filter group `group`
+    /// (`vts_filter_by_variable`), with the same accounting as
+    /// [`update_location_stats`].  A group holds at most
+    /// [`MAX_FILTER_KEYS`] keys; later ones share [`FILTER_OTHER_KEY`].
+    ///
+    /// [`update_location_stats`]: VtsStatsManager::update_location_stats
+    /// [`MAX_FILTER_KEYS`]: crate::stats::MAX_FILTER_KEYS
+    /// [`FILTER_OTHER_KEY`]: crate::stats::FILTER_OTHER_KEY
+    pub fn update_filter_stats(
+        &mut self,
+        group: &str,
+        key: &str,
+        status: u16,
+        bytes_in: u64,
+        bytes_out: u64,
+        request_time: u64,
+    ) {
+        let keys = self.filters.entry(group.to_string()).or_default();
+        let key = if keys.contains_key(key) {
+            key
+        } else {
+            admit_filter_key(key, keys.len())
+        };
+        keys.entry(key.to_string())
+            .or_insert_with(ServerCounters::new)
+            .update(status, bytes_in, bytes_out, request_time);
+    }
+
+    /// Get all filter-zone statistics, by group and then key
+    pub fn get_all_filter_stats(&self) -> HashMap<String, HashMap<String, VtsServerStats>> {
+        self.filters
+            .iter()
+            .map(|(group, keys)| {
+                let keys = keys
+                    .iter()
+                    .map(|(key, counters)| (key.clone(), (*counters).into_stats()))
+                    .collect();
+                (group.clone(), keys)
+            })
+            .collect()
+    }
+
+    /// Record one subrequest of `server_name`.
+    pub fn update_server_subrequest(&mut self, server_name: &str) {
+        if !self.is_zone_enabled(server_name) {
+            return;
+        }
+        self.stats
+            .entry(server_name.to_string())
+            .or_insert_with(ServerCounters::new)
+            .add_subrequest();
+    }
+
+    /// Add response bytes of a still-streaming request of
+    /// `server_name` without counting the request itself.
+    pub fn update_server_bytes_out(&mut self, server_name: &str, bytes_out: u64) {
+        if !self.is_zone_enabled(server_name) {
+            return;
+        }
+        self.stats
+            .entry(server_name.to_string())
+            .or_insert_with(ServerCounters::new)
+            .add_bytes_out(bytes_out);
+    }
+
+    /// Record a client body read failure of `server_name` by its final
+    /// status (408 or 400).
+    pub fn update_server_client_body_error(&mut self, server_name: &str, status: u16) {
+        if !self.is_zone_enabled(server_name) {
+            return;
+        }
+        self.stats
+            .entry(server_name.to_string())
+            .or_insert_with(ServerCounters::new)
+            .add_client_body_error(status);
+    }
+
+    /// Mark `header_bytes` of the response bytes already counted for
+    /// `server_name` as headers.
+    pub fn update_server_header_bytes(&mut self, server_name: &str, header_bytes: u64) {
+        self.stats
+            .entry(server_name.to_string())
+            .or_insert_with(ServerCounters::new)
+            .add_header_bytes_out(header_bytes);
+    }
+
+    /// Move one in-flight request of `server_name` between connection
+    /// phases.  Applied even to disabled zones so begin/end pairs stay
+    /// balanced across a pause.
+    pub fn transition_server_connection(
+        &mut self,
+        server_name: &str,
+        from: ConnPhase,
+        to: ConnPhase,
+    ) {
+        self.stats
+            .entry(server_name.to_string())
+            .or_insert_with(ServerCounters::new)
+            .transition_connection(from, to);
+    }
+
+    /// Replace the user-defined labels of a server zone; an empty list
+    /// removes them.  Rejects the whole list if any name is invalid,
+    /// reserved or repeated, or there are more than
+    /// [`MAX_ZONE_LABELS`].
+    pub fn set_zone_labels(
+        &mut self,
+        zone: &str,
+        labels: Vec<(String, String)>,
+    ) -> Result<(), VtsError> {
+        let mut checked: Vec<(String, String)> = Vec::with_capacity(labels.len());
+        for (name, value) in labels {
+            check_zone_label(&checked, &name)?;
+            checked.push((name, value));
+        }
+        if checked.is_empty() {
+            self.zone_labels.remove(zone);
+        } else {
+            self.zone_labels.insert(zone.to_string(), checked);
+        }
+        Ok(())
+    }
+
+    /// User-defined labels per server zone.
+    pub fn get_zone_labels(&self) -> &HashMap<String, Vec<(String, String)>> {
+        &self.zone_labels
+    }
+
+    /// Report server zone `from` as `to`.  Returns `false`, leaving
+    /// the first alias in place, if `from` already has one.
+    pub fn add_zone_alias(&mut self, from: &str, to: &str) -> bool {
+        if self.zone_aliases.contains_key(from) {
+            return false;
+        }
+        self.zone_aliases.insert(from.to_string(), to.to_string());
+        true
+    }
+
+    /// `vts_zone_alias` output name per stored server zone.
+    pub fn get_zone_aliases(&self) -> &HashMap<String, String> {
+        &self.zone_aliases
+    }
+
     // --- Upstream Zone Management ---
 
+    /// Name the pool of an upstream group.  Returns `false`, leaving
+    /// the first name in place, if the group already has one.
+    pub fn set_upstream_zone_name(&mut self, upstream: &str, zone: &str) -> bool {
+        if self.upstream_zone_names.contains_key(upstream) {
+            return false;
+        }
+        self.upstream_zone_names
+            .insert(upstream.to_string(), zone.to_string());
+        true
+    }
+
+    /// `vts_upstream_zone` name per upstream group.
+    pub fn get_upstream_zone_names(&self) -> &HashMap<String, String> {
+        &self.upstream_zone_names
+    }
+
     /// Update upstream statistics
     #[allow(clippy::too_many_arguments)] // Matches nginx API requirements
     pub fn update_upstream_stats(
@@ -89,6 +470,22 @@ impl VtsStatsManager {
         server_stats.update_timing(request_time, upstream_response_time);
     }
 
+    /// Add one request's local time to the server of its final upstream
+    /// attempt; see [`UpstreamServerStats::add_local_time`].
+    pub fn update_upstream_local_time(
+        &mut self,
+        upstream_name: &str,
+        upstream_addr: &str,
+        request_time: u64,
+        upstream_response_time: u64,
+    ) {
+        self.upstream_zones
+            .entry(upstream_name.to_string())
+            .or_insert_with(|| UpstreamZone::new(upstream_name))
+            .get_or_create_server(upstream_addr)
+            .add_local_time(request_time, upstream_response_time);
+    }
+
     /// Get upstream zone statistics
     pub fn get_upstream_zone(&self, upstream_name: &str) -> Option<&UpstreamZone> {
         self.upstream_zones.get(upstream_name)
@@ -111,6 +508,102 @@ impl VtsStatsManager {
             .or_insert_with(|| UpstreamZone::new(upstream_name))
     }
 
+    /// Set a server zone's counters to `counters`, replacing whatever
+    /// was recorded, for seeding test data or migrating totals from
+    /// another exporter.  The zone's in-flight gauges are live state
+    /// and are kept.
+    pub fn load_server_zone(&mut self, server_name: &str, mut counters: ServerCounters) {
+        let entry = self
+            .stats
+            .entry(server_name.to_string())
+            .or_insert_with(ServerCounters::new);
+        counters.conn_reading = entry.conn_reading;
+        counters.conn_writing = entry.conn_writing;
+        counters.conn_upgraded = entry.conn_upgraded;
+        counters.normalize();
+        *entry = counters;
+    }
+
+    /// Set an upstream server's statistics to `stats`, replacing
+    /// whatever was recorded; see [`load_server_zone`](Self::load_server_zone).
+    /// The address is taken from `server_addr`, and the response-time
+    /// buckets are made cumulative and capped at the sample count.
+    pub fn load_upstream_server(
+        &mut self,
+        upstream_name: &str,
+        server_addr: &str,
+        mut stats: UpstreamServerStats,
+    ) {
+        stats.server = server_addr.to_string();
+        let mut floor = 0;
+        for bucket in &mut stats.response_buckets {
+            floor = (*bucket).max(floor).min(stats.response_time_counter);
+            *bucket = floor;
+        }
+        self.get_or_create_upstream_zone(upstream_name)
+            .servers
+            .insert(server_addr.to_string(), stats);
+    }
+
+    /// Register `server_addr` under `upstream_name` with zeroed counters,
+    /// as configured.  An address already listed for that upstream is
+    /// merged into the existing entry and counted as a duplicate;
+    /// returns `true` in that case.
+    pub fn seed_upstream_server(&mut self, upstream_name: &str, server_addr: &str) -> bool {
+        let zone = self.get_or_create_upstream_zone(upstream_name);
+        if zone.servers.contains_key(server_addr) {
+            *self
+                .upstream_duplicate_servers
+                .entry(upstream_name.to_string())
+                .or_default() += 1;
+            return true;
+        }
+        zone.get_or_create_server(server_addr);
+        false
+    }
+
+    /// Mark `server_addr` of `upstream_name` as down, or up again, from
+    /// its nginx peer's `down` flag.
+    pub fn set_upstream_server_down(&mut self, upstream_name: &str, server_addr: &str, down: bool) {
+        self.get_or_create_upstream_zone(upstream_name)
+            .get_or_create_server(server_addr)
+            .down = down;
+    }
+
+    /// Copy the `down` flag of every configured upstream server into
+    /// `zones`, the shared-memory snapshot, whose counters carry no
+    /// peer state.  Servers that have not served a request yet are
+    /// added with zeroed counters, as they are without `vts_zone`.
+    pub fn apply_upstream_peer_state(&self, zones: &mut HashMap<String, UpstreamZone>) {
+        for (name, zone) in &self.upstream_zones {
+            let shared = zones
+                .entry(name.clone())
+                .or_insert_with(|| UpstreamZone::new(name));
+            for (addr, server) in &zone.servers {
+                shared.get_or_create_server(addr).down = server.down;
+            }
+        }
+    }
+
+    /// Duplicate server addresses seen per upstream
+    pub fn get_upstream_duplicate_servers(&self) -> &HashMap<String, u64> {
+        &self.upstream_duplicate_servers
+    }
+
+    /// Record a request joining (`waited`) or leaving an upstream's
+    /// connection-slot queue.
+    pub fn update_upstream_queue(&mut self, upstream_name: &str, waited: bool) {
+        self.upstream_queues
+            .entry(upstream_name.to_string())
+            .or_default()
+            .update(waited);
+    }
+
+    /// Get all upstream queues
+    pub fn get_all_upstream_queues(&self) -> &HashMap<String, UpstreamQueueStats> {
+        &self.upstream_queues
+    }
+
     /// Update connection statistics
     pub fn update_connection_stats(
         &mut self,
@@ -134,6 +627,190 @@ impl VtsStatsManager {
         &self.connections
     }
 
+    /// Number of server zones recorded so far.
+    pub fn server_zone_count(&self) -> usize {
+        self.stats.len()
+    }
+
+    /// Requests summed over every server zone.
+    pub fn total_server_requests(&self) -> u64 {
+        self.stats
+            .values()
+            .map(|counters| counters.requests)
+            .fold(0, u64::saturating_add)
+    }
+
+    /// Move one QUIC connection between phases.
+    pub fn transition_quic_connection(&mut self, from: QuicPhase, to: QuicPhase, zero_rtt: bool) {
+        self.quic.transition(from, to, zero_rtt);
+    }
+
+    /// Get QUIC connection statistics
+    pub fn get_quic_stats(&self) -> &VtsQuicStats {
+        &self.quic
+    }
+
+    /// Visit every server zone, in name order, without building a
+    /// cloned map.  Used on the scrape path; [`get_all_server_stats`]
+    /// remains for callers that need an owned copy.  Zones with a
+    /// `vts_zone_alias` are visited under the alias, merged.
+    ///
+    /// [`get_all_server_stats`]: VtsStatsManager::get_all_server_stats
+    pub fn for_each_server_zone(&self, mut f: impl FnMut(&str, &VtsServerStats)) {
+        if !self.zone_aliases.is_empty() {
+            let zones = self
+                .stats
+                .iter()
+                .map(|(zone, counters)| (zone.clone(), counters.into_stats()));
+            let mut zones: Vec<_> = alias_server_zones(zones, &self.zone_aliases)
+                .into_iter()
+                .collect();
+            zones.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            for (zone, stats) in zones {
+                f(&zone, &stats);
+            }
+            return;
+        }
+        let mut zones: Vec<_> = self.stats.iter().collect();
+        zones.sort_unstable_by_key(|&(zone, _)| zone);
+        for (zone, counters) in zones {
+            f(zone, &counters.into_stats());
+        }
+    }
+
+    /// Count one response in a server zone's method × status cross-tab.
+    pub fn update_method_status(&mut self, server_name: &str, method: HttpMethod, status: u16) {
+        if !self.is_zone_enabled(server_name) {
+            return;
+        }
+        self.method_status
+            .entry(server_name.to_string())
+            .or_default()
+            .record(method, status);
+    }
+
+    /// Get all method × status cross-tabs
+    pub fn get_all_method_status(&self) -> &HashMap<String, MethodStatusCounters> {
+        &self.method_status
+    }
+
+    /// Count one response in a server zone's Content-Type breakdown.
+    pub fn update_content_type(&mut self, server_name: &str, content_type: &[u8]) {
+        if !self.is_zone_enabled(server_name) {
+            return;
+        }
+        self.content_types
+            .entry(server_name.to_string())
+            .or_default()
+            .record(content_type);
+    }
+
+    /// Count one response in a server zone's `grpc-status` breakdown.
+    /// Responses without a `grpc-status` don't create an entry.
+    pub fn update_grpc_status(&mut self, server_name: &str, grpc_status: &[u8]) {
+        if !self.is_zone_enabled(server_name) || GrpcStatusCounters::classify(grpc_status).is_none()
+        {
+            return;
+        }
+        self.grpc_statuses
+            .entry(server_name.to_string())
+            .or_default()
+            .record(grpc_status);
+    }
+
+    /// Get all `grpc-status` breakdowns
+    pub fn get_all_grpc_statuses(&self) -> &HashMap<String, GrpcStatusCounters> {
+        &self.grpc_statuses
+    }
+
+    /// Add a client address to a server zone's distinct-client sketch.
+    #[cfg(feature = "unique-clients")]
+    pub fn update_unique_client(&mut self, server_name: &str, addr: &[u8]) {
+        if !self.is_zone_enabled(server_name) || addr.is_empty() {
+            return;
+        }
+        self.unique_clients
+            .entry(server_name.to_string())
+            .or_default()
+            .insert(addr);
+    }
+
+    /// Get all distinct-client sketches
+    #[cfg(feature = "unique-clients")]
+    pub fn get_all_unique_clients(&self) -> &HashMap<String, HyperLogLog> {
+        &self.unique_clients
+    }
+
+    /// Get all Content-Type breakdowns
+    pub fn get_all_content_types(&self) -> &HashMap<String, ContentTypeCounters> {
+        &self.content_types
+    }
+
+    /// Count the header count and size of one request in a server zone.
+    pub fn update_request_headers(&mut self, server_name: &str, count: u64, bytes: u64) {
+        if !self.is_zone_enabled(server_name) {
+            return;
+        }
+        self.request_headers
+            .entry(server_name.to_string())
+            .or_default()
+            .record(count, bytes);
+    }
+
+    /// Get all request-header histograms
+    pub fn get_all_request_headers(&self) -> &HashMap<String, RequestHeaderStats> {
+        &self.request_headers
+    }
+
+    /// Zero every server, location and filter zone's counters, keeping
+    /// the zones and their in-flight gauges.  Returns the number of server
+    /// zones reset.
+    pub fn reset_server_zones(&mut self) -> usize {
+        for counters in self.stats.values_mut() {
+            counters.reset();
+        }
+        for counters in self.method_status.values_mut() {
+            *counters = MethodStatusCounters::default();
+        }
+        for stats in self.request_headers.values_mut() {
+            *stats = RequestHeaderStats::default();
+        }
+        for counters in self.content_types.values_mut() {
+            *counters = ContentTypeCounters::default();
+        }
+        for counters in self.grpc_statuses.values_mut() {
+            *counters = GrpcStatusCounters::default();
+        }
+        #[cfg(feature = "unique-clients")]
+        for sketch in self.unique_clients.values_mut() {
+            *sketch = HyperLogLog::new();
+        }
+        for counters in self.locations.values_mut() {
+            counters.reset();
+        }
+        for counters in self.filters.values_mut().flat_map(HashMap::values_mut) {
+            counters.reset();
+        }
+        self.stats.len()
+    }
+
+    /// Zero every upstream server's counters and the queue wait totals,
+    /// keeping the configured servers and live queue lengths.  Returns
+    /// the number of upstream servers reset.
+    pub fn reset_upstream_zones(&mut self) -> usize {
+        let mut servers = 0;
+        for zone in self.upstream_zones.values_mut() {
+            for server in zone.servers.values_mut() {
+                server.reset_counters();
+                servers += 1;
+            }
+        }
+        for queue in self.upstream_queues.values_mut() {
+            queue.waits_total = 0;
+        }
+        servers
+    }
+
     /// Get all server statistics in format compatible with PrometheusFormatter
     pub fn get_all_server_stats(&self) -> HashMap<String, VtsServerStats> {
         self.stats
@@ -163,6 +840,208 @@ mod tests {
         assert!(manager.upstream_zones.is_empty());
     }
 
+    #[test]
+    fn loaded_stats_replace_recorded_ones_and_render() {
+        let mut manager = VtsStatsManager::new();
+        manager.update_server_stats("example.com", 500, 1, 1, 1);
+        manager.transition_server_connection("example.com", ConnPhase::Idle, ConnPhase::Reading);
+
+        let mut node = ServerCounters::new();
+        node.requests = 1000;
+        node.bytes_in = 50_000;
+        node.bytes_out = 2_000_000;
+        node.status_2xx = 990;
+        node.status_5xx = 10;
+        node.request_time_total = 40_000;
+        node.request_time_max = 900;
+        node.request_time_min = 2;
+        manager.load_server_zone("example.com", node);
+        manager.load_server_zone("example.com", node);
+
+        let mut upstream = UpstreamServerStats::new("ignored");
+        upstream.request_counter = 1000;
+        upstream.responses.status_2xx = 1000;
+        upstream.response_time_counter = 1000;
+        upstream.response_time_total = 30_000;
+        upstream.response_buckets = [100, 50, 900, 950, 2000, 0, 0, 0, 0, 0, 0];
+        manager.load_upstream_server("backend", "10.0.0.1:80", upstream);
+
+        let formatter = PrometheusFormatter::new();
+        let servers = formatter.format_server_stats(&manager.get_all_server_stats());
+        // Loading twice overwrites rather than adds up.
+        assert!(servers.contains("nginx_vts_server_requests_total{zone=\"example.com\"} 1000\n"));
+        assert!(servers.contains(
+            "nginx_vts_server_responses_total{zone=\"example.com\",status=\"5xx\"} 10\n"
+        ));
+        assert!(servers.contains(
+            "nginx_vts_server_request_seconds{zone=\"example.com\",type=\"avg\"} 0.040000\n"
+        ));
+        assert!(servers
+            .contains("nginx_vts_server_connections{zone=\"example.com\",state=\"reading\"} 1\n"));
+
+        let upstreams = formatter.format_upstream_stats(manager.get_all_upstream_zones());
+        assert!(upstreams.contains(
+            "nginx_vts_upstream_requests_total{upstream=\"backend\",server=\"10.0.0.1:80\"} 1000\n"
+        ));
+        let buckets = &manager.get_all_upstream_zones()["backend"].servers["10.0.0.1:80"];
+        assert_eq!(
+            buckets.response_buckets,
+            [100, 100, 900, 950, 1000, 1000, 1000, 1000, 1000, 1000, 1000]
+        );
+        assert_eq!(buckets.server, "10.0.0.1:80");
+    }
+
+    #[test]
+    fn loaded_server_zone_without_requests_has_no_minimum() {
+        let mut manager = VtsStatsManager::new();
+        let mut node = ServerCounters::new();
+        node.request_time_min = 0;
+        node.header_bytes_out = 10;
+        manager.load_server_zone("idle.example.com", node);
+        let counters = manager.stats["idle.example.com"];
+        assert_eq!(counters, ServerCounters::new());
+    }
+
+    #[test]
+    fn seed_upstream_server_counts_duplicate_addresses() {
+        let mut manager = VtsStatsManager::new();
+        assert!(!manager.seed_upstream_server("backend", "10.0.0.1:80"));
+        assert!(!manager.seed_upstream_server("backend", "10.0.0.2:80"));
+        assert!(manager.seed_upstream_server("backend", "10.0.0.1:80"));
+        // The same address in another upstream is not a duplicate.
+        assert!(!manager.seed_upstream_server("api", "10.0.0.1:80"));
+
+        assert_eq!(
+            manager.get_upstream_zone("backend").unwrap().servers.len(),
+            2
+        );
+        assert_eq!(manager.get_upstream_duplicate_servers()["backend"], 1);
+        assert!(!manager.get_upstream_duplicate_servers().contains_key("api"));
+    }
+
+    #[test]
+    fn peer_state_is_applied_to_the_shared_snapshot() {
+        let mut manager = VtsStatsManager::new();
+        manager.seed_upstream_server("backend", "10.0.0.1:80");
+        manager.seed_upstream_server("backend", "10.0.0.2:80");
+        manager.set_upstream_server_down("backend", "10.0.0.2:80", true);
+
+        // The shared zone has only seen traffic to the first server.
+        let mut shared = HashMap::new();
+        let mut zone = UpstreamZone::new("backend");
+        zone.get_or_create_server("10.0.0.1:80").request_counter = 7;
+        shared.insert("backend".to_string(), zone);
+
+        manager.apply_upstream_peer_state(&mut shared);
+        let servers = &shared["backend"].servers;
+        assert_eq!(servers["10.0.0.1:80"].request_counter, 7);
+        assert!(!servers["10.0.0.1:80"].down);
+        assert!(servers["10.0.0.2:80"].down);
+        assert_eq!(servers["10.0.0.2:80"].request_counter, 0);
+
+        manager.set_upstream_server_down("backend", "10.0.0.2:80", false);
+        manager.apply_upstream_peer_state(&mut shared);
+        assert!(!shared["backend"].servers["10.0.0.2:80"].down);
+    }
+
+    #[test]
+    fn breakdown_label_names_are_reserved() {
+        let mut manager = VtsStatsManager::new();
+        for name in ["part", "le", "reason", "grpc_status"] {
+            assert_eq!(
+                manager.set_zone_labels("a.test", vec![(name.to_string(), "x".to_string())]),
+                Err(VtsError::ReservedLabelName),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn set_zone_labels_validates_names_and_bounds_count() {
+        let mut manager = VtsStatsManager::new();
+        let label = |n: &str| (n.to_string(), "v".to_string());
+
+        assert_eq!(
+            manager.set_zone_labels("a.test", vec![label("tenant"), label("team")]),
+            Ok(())
+        );
+        assert_eq!(manager.get_zone_labels()["a.test"].len(), 2);
+
+        for (bad, why) in [
+            ("1tenant", VtsError::InvalidLabelName),
+            ("ten-ant", VtsError::InvalidLabelName),
+            ("zone", VtsError::ReservedLabelName),
+            ("method", VtsError::ReservedLabelName),
+            ("__name__", VtsError::ReservedLabelName),
+        ] {
+            assert_eq!(
+                manager.set_zone_labels("b.test", vec![label(bad)]),
+                Err(why)
+            );
+        }
+        assert_eq!(
+            manager.set_zone_labels("b.test", vec![label("x"), label("x")]),
+            Err(VtsError::DuplicateLabelName)
+        );
+        let too_many: Vec<_> = (0..=MAX_ZONE_LABELS)
+            .map(|i| label(&format!("l{i}")))
+            .collect();
+        assert_eq!(
+            manager.set_zone_labels("b.test", too_many),
+            Err(VtsError::TooManyLabels)
+        );
+        assert!(!manager.get_zone_labels().contains_key("b.test"));
+
+        assert_eq!(manager.set_zone_labels("a.test", Vec::new()), Ok(()));
+        assert!(manager.get_zone_labels().is_empty());
+    }
+
+    #[test]
+    fn for_each_server_zone_merges_aliased_zones() {
+        let mut manager = VtsStatsManager::new();
+        manager.update_server_stats("legacy.test", 200, 10, 100, 5);
+        manager.update_server_stats("new.test", 500, 20, 200, 15);
+        manager.update_server_stats("other.test", 200, 1, 1, 1);
+        assert!(manager.add_zone_alias("legacy.test", "new.test"));
+        assert!(!manager.add_zone_alias("legacy.test", "elsewhere.test"));
+
+        let mut seen = Vec::new();
+        manager.for_each_server_zone(|zone, stats| seen.push((zone.to_string(), stats.clone())));
+        let names: Vec<_> = seen.iter().map(|(zone, _)| zone.as_str()).collect();
+        assert_eq!(names, ["new.test", "other.test"]);
+
+        let merged = &seen[0].1;
+        assert_eq!(merged.requests, 2);
+        assert_eq!(merged.bytes_in, 30);
+        assert_eq!(merged.bytes_out, 300);
+        assert_eq!(merged.responses.status_2xx, 1);
+        assert_eq!(merged.responses.status_5xx, 1);
+        assert_eq!(merged.request_times.min, 0.005);
+        assert_eq!(merged.request_times.max, 0.015);
+        assert_eq!(merged.request_times.avg, 0.01);
+    }
+
+    #[test]
+    fn for_each_server_zone_visits_each_zone_once() {
+        let mut manager = VtsStatsManager::new();
+        for zone in ["a.test", "b.test", "c.test"] {
+            manager.update_server_stats(zone, 200, 1, 1, 1);
+        }
+        manager.update_server_stats("b.test", 200, 1, 1, 1);
+
+        let mut seen = Vec::new();
+        manager.for_each_server_zone(|zone, stats| seen.push((zone.to_string(), stats.requests)));
+        seen.sort();
+        assert_eq!(
+            seen,
+            vec![
+                ("a.test".to_string(), 1),
+                ("b.test".to_string(), 2),
+                ("c.test".to_string(), 1),
+            ]
+        );
+    }
+
     #[test]
     fn update_server_stats_records_through_server_counters() {
         // Reuses the same counter type as the shm backend, so we get
@@ -184,6 +1063,26 @@ mod tests {
         assert_eq!(s.request_times.max, 0.200);
     }
 
+    #[test]
+    fn disabled_zone_keeps_counters_but_skips_updates() {
+        let mut manager = VtsStatsManager::new();
+        manager.update_server_stats("noisy.test", 200, 100, 1000, 50);
+
+        manager.set_zone_enabled("noisy.test", false);
+        assert!(!manager.is_zone_enabled("noisy.test"));
+        manager.update_server_stats("noisy.test", 500, 100, 1000, 50);
+        let s = &manager.get_all_server_stats()["noisy.test"];
+        assert_eq!(s.requests, 1);
+        assert_eq!(s.responses.status_5xx, 0);
+
+        manager.set_zone_enabled("noisy.test", true);
+        manager.update_server_stats("noisy.test", 500, 100, 1000, 50);
+        let s = &manager.get_all_server_stats()["noisy.test"];
+        assert_eq!(s.requests, 2);
+        assert_eq!(s.responses.status_5xx, 1);
+        assert!(manager.get_disabled_zones().is_empty());
+    }
+
     #[test]
     fn test_complete_upstream_pipeline() {
         let mut manager = VtsStatsManager::new();